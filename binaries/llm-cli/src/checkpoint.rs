@@ -0,0 +1,141 @@
+//! Checkpoints of long generations, so that `llm resume` can continue them after a crash.
+//!
+//! With `llm infer --checkpoint-dir <dir>`, generation pauses every `--checkpoint-every`
+//! tokens to write a checkpoint to `<dir>`: the command line, the text generated so far and
+//! a snapshot of the session, with the state of the random number generator. Each
+//! checkpoint replaces the previous one atomically, so a crash while writing one leaves
+//! the previous one intact. The text is also written to `<dir>/output.txt`, to be read
+//! without `llm`.
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::{self, Context};
+use llm::{InferenceSession, InferenceSnapshot, InferenceSnapshotRef, Model, RngState};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use zstd::stream::{read::Decoder, write::Encoder};
+
+use crate::snapshot;
+
+const CHECKPOINT_FILE: &str = "checkpoint";
+const OUTPUT_FILE: &str = "output.txt";
+
+/// How far a generation has come.
+#[derive(Default)]
+pub struct Progress {
+    /// The text generated so far.
+    pub text: String,
+    /// The number of tokens generated so far.
+    pub generated_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    args: &'a [String],
+    text: &'a str,
+    generated_tokens: usize,
+    finished: bool,
+    snapshot: InferenceSnapshotRef<'a>,
+}
+
+/// A checkpoint read from a checkpoint directory.
+#[derive(Deserialize)]
+pub struct Checkpoint {
+    /// The command line of the generation, including the program name.
+    pub args: Vec<String>,
+    text: String,
+    generated_tokens: usize,
+    /// Whether the generation was complete when the checkpoint was written.
+    pub finished: bool,
+    snapshot: InferenceSnapshot,
+}
+impl Checkpoint {
+    /// Reads the checkpoint in `dir`.
+    pub fn read(dir: &Path) -> eyre::Result<Self> {
+        let path = dir.join(CHECKPOINT_FILE);
+        let file = File::open(llm::long_path(&path))
+            .wrap_err_with(|| format!("Could not open the checkpoint {path:?}"))?;
+        let decoder = Decoder::new(BufReader::new(file))?;
+        snapshot::deserialize_versioned(decoder)
+            .and_then(|checkpoint| checkpoint.ok_or_else(|| eyre::eyre!("it has no format header")))
+            .wrap_err_with(|| format!("Could not read the checkpoint {path:?}"))
+    }
+
+    /// The text generated so far.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Restores the session of the checkpoint, with the random number generator saved with
+    /// it, and returns the progress of the generation.
+    pub fn restore(
+        mut self,
+        model: &dyn Model,
+    ) -> eyre::Result<(InferenceSession, Option<ChaCha12Rng>, Progress)> {
+        let rng = self.snapshot.rng.take().map(|rng| rng.to_rng());
+        let session = InferenceSession::from_snapshot(self.snapshot, model)
+            .wrap_err("Could not restore the session of the checkpoint")?;
+        let progress = Progress {
+            text: self.text,
+            generated_tokens: self.generated_tokens,
+        };
+        Ok((session, rng, progress))
+    }
+}
+
+/// Writes a checkpoint of the generation to `dir`, replacing the previous one.
+pub fn write(
+    dir: &Path,
+    args: &[String],
+    progress: &Progress,
+    finished: bool,
+    session: &mut InferenceSession,
+    rng: &ChaCha12Rng,
+) -> eyre::Result<()> {
+    std::fs::create_dir_all(llm::long_path(dir))
+        .wrap_err_with(|| format!("Could not create the checkpoint directory {dir:?}"))?;
+
+    // SAFETY: the snapshot is dropped before the session is used again.
+    let mut snapshot =
+        unsafe { session.get_snapshot() }.wrap_err("Could not restore the spilled session")?;
+    snapshot.rng = Some(RngState::from(rng));
+    let checkpoint = CheckpointRef {
+        args,
+        text: &progress.text,
+        generated_tokens: progress.generated_tokens,
+        finished,
+        snapshot,
+    };
+    write_atomically(&dir.join(CHECKPOINT_FILE), |file| {
+        let mut encoder = Encoder::new(BufWriter::new(file), 1)?;
+        snapshot::serialize_versioned(&mut encoder, &checkpoint)?;
+        encoder.finish()?.flush()?;
+        Ok(())
+    })?;
+    write_atomically(&dir.join(OUTPUT_FILE), |file| {
+        Ok(file.write_all(progress.text.as_bytes())?)
+    })?;
+    log::info!(
+        "Wrote a checkpoint after {} generated tokens to {dir:?}",
+        progress.generated_tokens
+    );
+    Ok(())
+}
+
+/// Writes `path` through a temporary file, so that it is either replaced whole or not at all.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> eyre::Result<()>,
+) -> eyre::Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut file = File::create(llm::long_path(&temporary))
+        .wrap_err_with(|| format!("Could not create {temporary:?}"))?;
+    write(&mut file)
+        .and_then(|()| Ok(file.sync_all()?))
+        .wrap_err_with(|| format!("Could not write {temporary:?}"))?;
+    std::fs::rename(llm::long_path(&temporary), llm::long_path(path))
+        .wrap_err_with(|| format!("Could not replace {path:?}"))
+}
@@ -5,13 +5,20 @@ use std::{
     sync::Arc,
 };
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use color_eyre::eyre::{self, WrapErr};
 use llm::{
-    ggml_format, ElementType, InferenceParameters, InferenceSessionConfig, InvalidTokenBias,
-    LoadProgress, Model, ModelKVMemoryType, ModelParameters, TokenBias, TokenizerSource,
+    ggml_format,
+    guardrail::Guardrail,
+    samplers::{Dry, Keyframe, ParameterSchedule, Sampler},
+    template::{ExportFormat, PromptTemplate},
+    ContextOverflowPolicy, ContextSize, ElementType, FileTypeFormat, InferenceParameters,
+    InferenceSessionConfig, InvalidTokenBias, KvEviction, LoadProgress, Model, ModelKVMemoryType,
+    ModelParameters, ThreadCount, TokenBias, TokenizerSource,
 };
 use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -24,10 +31,28 @@ pub enum Args {
     /// Measure a model's perplexity for a given prompt.
     Perplexity(Box<Perplexity>),
 
+    /// Compute the embeddings of every text of a JSON Lines or CSV file, and write them to
+    /// another. The files are streamed, so corpora of any size can be embedded.
+    Embed(Box<Embed>),
+
+    /// Find the rows of an embeddings file written by `llm embed` that are the most similar
+    /// to a query, which is embedded with the same model.
+    Search(Box<Search>),
+
+    /// Continue an `llm infer --checkpoint-dir` generation from its last checkpoint, after
+    /// it was interrupted or crashed.
+    Resume(Box<Resume>),
+
     #[command()]
     /// Get information about a GGML model.
     Info(Box<Info>),
 
+    /// Check whether this build of llm can load a model file, and explain why not otherwise.
+    ///
+    /// Only the metadata of the file is read, so this is quick even for large models. Exits
+    /// with a non-zero status if the model cannot be loaded.
+    Doctor(Box<Doctor>),
+
     #[command()]
     /// Dumps the prompt to console and exits, first as a comma-separated list of token IDs
     /// and then as a list of comma-separated string keys and token ID values.
@@ -48,6 +73,33 @@ pub enum Args {
 
     /// Quantize a GGML model to 4-bit.
     Quantize(Box<Quantize>),
+
+    /// Convert a Hugging Face checkpoint (safetensors or PyTorch weights) to a GGJT model,
+    /// quantizing it in the same pass.
+    Convert(Box<Convert>),
+
+    /// Upgrade a model file written by an older version of GGML (GGML, GGMF, or GGJT v1
+    /// and v2) to the current GGJT v3 format, so that it loads quickly and can be memory
+    /// mapped.
+    Migrate(Box<Migrate>),
+
+    #[command(subcommand)]
+    /// Work with chat prompt templates.
+    Template(Template),
+
+    #[command(subcommand)]
+    /// Inspect a model's vocabulary.
+    Vocab(Vocab),
+
+    /// Keep a model loaded and serve `llm infer --remote` requests over a Unix socket,
+    /// so that repeated invocations do not have to load the model again.
+    #[cfg(unix)]
+    Daemon(Box<Daemon>),
+
+    /// Monitor a running `llm daemon`: its sessions, their context occupancy and speed,
+    /// the requests waiting to be served and the memory in use, refreshed until interrupted.
+    #[cfg(unix)]
+    Top(Box<Top>),
 }
 
 #[derive(Parser, Debug)]
@@ -64,6 +116,15 @@ pub struct Infer {
     #[command(flatten)]
     pub prompt: Prompt,
 
+    /// Read the prompt from stdin until EOF, for use in shell pipelines.
+    ///
+    /// Only the inferred tokens are written to stdout, without a trailing newline;
+    /// everything else goes to stderr. The process exits with a non-zero status if
+    /// inference fails. Can be used with `--prompt-file`/`-f`, in which case stdin
+    /// replaces `{{PROMPT}}`.
+    #[arg(long, conflicts_with = "prompt")]
+    pub stdin: bool,
+
     /// Hide the prompt in the generation.
     ///
     /// By default, the prompt tokens will be shown as they are fed to the model.
@@ -95,6 +156,166 @@ pub struct Infer {
     /// things.
     #[arg(long, default_value_t = false)]
     pub stats: bool,
+
+    /// Send the request to the `llm daemon` listening on this socket instead of loading
+    /// the model. The daemon must have the model given with `--model-path` loaded; the
+    /// other model loading options are ignored.
+    #[cfg(unix)]
+    #[arg(
+        long,
+        conflicts_with_all = ["load_session", "save_session", "persist_session", "checkpoint_dir"]
+    )]
+    pub remote: Option<PathBuf>,
+
+    /// Periodically write a checkpoint of the generation to this directory, so that
+    /// `llm resume <dir>` can continue it if it is interrupted.
+    ///
+    /// A checkpoint holds the command line, the text generated so far and the session, and
+    /// replaces the previous one. The text is also written to `output.txt`. Stop sequences
+    /// and runs of low entropy that span a checkpoint are not detected.
+    #[arg(long)]
+    pub checkpoint_dir: Option<PathBuf>,
+
+    /// The number of tokens generated between two checkpoints of `--checkpoint-dir`.
+    #[arg(long, default_value_t = 256, value_parser = clap::value_parser!(u64).range(1..))]
+    pub checkpoint_every: u64,
+
+    /// Filters the generated text with a WASM plugin, as described in the documentation of
+    /// `llm::wasm_plugin`, e.g. to redact it or to stop generation. The plugin runs in a
+    /// sandbox, without access to files or the network. Can be repeated; the filters run in
+    /// order. Not supported with `--remote`.
+    #[cfg(feature = "wasm-plugins")]
+    #[arg(long)]
+    pub wasm_filter: Vec<PathBuf>,
+}
+
+impl Infer {
+    /// Whether the prompt tokens should be written to stdout.
+    pub fn show_prompt(&self) -> bool {
+        !self.hide_prompt && !self.stdin
+    }
+
+    /// Loads the filters of the generated text.
+    pub fn guardrails(&self) -> eyre::Result<Vec<Box<dyn Guardrail>>> {
+        #[allow(unused_mut)]
+        let mut guardrails: Vec<Box<dyn Guardrail>> = vec![];
+        #[cfg(feature = "wasm-plugins")]
+        for path in &self.wasm_filter {
+            let filter = llm::wasm_plugin::WasmFilter::load(path, Default::default())
+                .wrap_err_with(|| format!("Could not load the WASM filter {path:?}"))?;
+            guardrails.push(Box::new(filter));
+        }
+        Ok(guardrails)
+    }
+}
+
+#[cfg(unix)]
+#[derive(Parser, Debug)]
+pub struct Daemon {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    /// The Unix socket to listen on. A stale socket file at this path is replaced.
+    #[arg(long, short = 's')]
+    pub socket: PathBuf,
+
+    /// Whether to use GPU acceleration when available
+    #[arg(long, default_value_t = false)]
+    pub use_gpu: bool,
+
+    /// The kinds of requests to serve, which should match what the model was trained for.
+    /// Other requests are rejected, rather than answered with nonsense.
+    #[arg(long, value_delimiter = ',', default_value = "completion")]
+    pub endpoints: Vec<Endpoint>,
+
+    /// The prompt template used to render chat requests: one of `chatml`, `llama2`,
+    /// `vicuna` or `alpaca`. Required to serve `chat`.
+    #[arg(long)]
+    pub template: Option<PromptTemplate>,
+
+    /// Cache the responses to requests that give a seed, in memory, and replay them when
+    /// the same request is sent again.
+    #[arg(long)]
+    pub cache: bool,
+
+    /// Also keep the cache in this directory, so that it survives restarts. Implies --cache.
+    #[arg(long)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// How long cached responses are kept, in seconds. Kept until evicted by default.
+    #[arg(long)]
+    pub cache_ttl: Option<u64>,
+
+    /// The maximum number of cached responses. The least recently used are evicted.
+    #[arg(long, default_value_t = 1024)]
+    pub cache_max_entries: usize,
+
+    /// The maximum size of the cached responses, in megabytes.
+    #[arg(long, default_value_t = 64)]
+    pub cache_max_mb: usize,
+
+    /// On SIGINT or SIGTERM, how long to let the request being served finish before
+    /// cancelling it, in seconds. A second signal cancels it at once.
+    #[arg(long, default_value_t = 30)]
+    pub shutdown_grace_period: u64,
+}
+#[cfg(unix)]
+impl Daemon {
+    /// The directory and configuration of the response cache, if it is enabled.
+    pub fn cache_config(&self) -> Option<(Option<&Path>, llm::cache::CacheConfig)> {
+        if !self.cache && self.cache_dir.is_none() {
+            return None;
+        }
+        let config = llm::cache::CacheConfig {
+            max_entries: self.cache_max_entries,
+            max_bytes: self.cache_max_mb * 1024 * 1024,
+            ttl: self.cache_ttl.map(std::time::Duration::from_secs),
+        };
+        Some((self.cache_dir.as_deref(), config))
+    }
+}
+
+#[cfg(unix)]
+#[derive(Parser, Debug)]
+pub struct Top {
+    /// The Unix socket the daemon listens on.
+    #[arg(long, short = 's')]
+    pub socket: PathBuf,
+
+    /// How often to refresh, in milliseconds.
+    #[arg(long, default_value_t = 1000)]
+    pub interval: u64,
+
+    /// Print the status once and exit, instead of refreshing the screen.
+    #[arg(long)]
+    pub once: bool,
+}
+
+/// A kind of request served by `llm daemon`.
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    /// Continue a prompt as is. Suits base models.
+    #[default]
+    Completion,
+    /// Reply to a conversation, rendered with the `--template` of the daemon. Suits models
+    /// fine-tuned for chat.
+    Chat,
+    /// Compute the embeddings of a prompt.
+    Embeddings,
+    /// Fill in the text between a prefix and a suffix. Suits code models trained with
+    /// fill-in-the-middle tokens.
+    Infill,
+}
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Completion => write!(f, "completion"),
+            Self::Chat => write!(f, "chat"),
+            Self::Embeddings => write!(f, "embeddings"),
+            Self::Infill => write!(f, "infill"),
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -112,6 +333,128 @@ pub struct Perplexity {
     pub prompt: Prompt,
 }
 
+#[derive(Parser, Debug)]
+pub struct Embed {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    /// The file to read the texts from. Each line of a JSON Lines file is a string or an
+    /// object with a `--text-field` field; a CSV file must have a header row with a
+    /// `--text-field` column.
+    #[arg(long)]
+    pub input: PathBuf,
+
+    /// The file to write the embeddings to, one row per text with its index in the input:
+    /// `{"index": 0, "embedding": [...]}` lines in JSON Lines, or an `index` column followed
+    /// by a column per dimension in CSV.
+    #[arg(long)]
+    pub output: PathBuf,
+
+    /// The format of the input file. Defaults to the format matching its extension.
+    #[arg(long)]
+    pub input_format: Option<EmbedFormat>,
+
+    /// The format of the output file. Defaults to the format matching its extension.
+    #[arg(long)]
+    pub output_format: Option<EmbedFormat>,
+
+    /// The field (JSON Lines) or column (CSV, Parquet) that holds the texts.
+    #[arg(long, default_value = "text")]
+    pub text_field: String,
+
+    /// The number of texts read, embedded and written at a time.
+    #[arg(long, default_value_t = 64)]
+    pub rows_per_batch: usize,
+
+    /// Scale each embedding to a length of 1, so that dot products are cosine similarities.
+    #[arg(long, default_value_t = false)]
+    pub normalize: bool,
+
+    #[command(flatten)]
+    pub generate: Generate,
+}
+
+#[derive(Parser, Debug)]
+pub struct Search {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    /// The embeddings file written by `llm embed` with the same model.
+    #[arg(long)]
+    pub index: PathBuf,
+
+    /// The format of the embeddings file. Defaults to the format matching its extension.
+    #[arg(long)]
+    pub index_format: Option<EmbedFormat>,
+
+    /// The text to search for.
+    #[arg(long)]
+    pub query: String,
+
+    /// The number of rows to return, most similar first.
+    #[arg(short, default_value_t = 10)]
+    pub k: usize,
+
+    /// The file the embeddings were computed from, to show the texts of the rows found.
+    #[arg(long)]
+    pub texts: Option<PathBuf>,
+
+    /// The format of the texts file. Defaults to the format matching its extension.
+    #[arg(long, requires = "texts")]
+    pub texts_format: Option<EmbedFormat>,
+
+    /// The field (JSON Lines) or column (CSV, Parquet) of the texts file that holds the
+    /// texts.
+    #[arg(long, default_value = "text")]
+    pub text_field: String,
+
+    #[command(flatten)]
+    pub generate: Generate,
+}
+
+#[derive(Parser, Debug)]
+pub struct Resume {
+    /// The `--checkpoint-dir` of the generation. The generation continues with the same
+    /// options, and keeps writing checkpoints there; relative paths among its options are
+    /// resolved from the current directory.
+    pub dir: PathBuf,
+}
+
+/// A file format of `llm embed` and `llm search`.
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum EmbedFormat {
+    /// JSON Lines (`.jsonl`, `.ndjson`).
+    Jsonl,
+    /// Comma-separated values with a header row (`.csv`).
+    Csv,
+    /// Apache Parquet (`.parquet`).
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+impl EmbedFormat {
+    /// The format of `path`, given with `format` or guessed from its extension.
+    pub fn resolve(format: Option<Self>, path: &Path) -> eyre::Result<Self> {
+        if let Some(format) = format {
+            return Ok(format);
+        }
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("jsonl" | "ndjson") => Ok(Self::Jsonl),
+            Some("csv") => Ok(Self::Csv),
+            #[cfg(feature = "parquet")]
+            Some("parquet") => Ok(Self::Parquet),
+            #[cfg(not(feature = "parquet"))]
+            Some("parquet") => eyre::bail!(
+                "Parquet files are only supported when llm is built with `--features parquet`; \
+                 convert {path:?} to JSON Lines or CSV"
+            ),
+            _ => eyre::bail!(
+                "Cannot tell the format of {path:?} from its extension; specify it with \
+                 --input-format or --output-format"
+            ),
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 pub struct Info {
     #[command(flatten)]
@@ -124,6 +467,19 @@ pub struct Info {
     /// Show all of the tokens in the tokenizer.
     #[arg(long, short = 'k')]
     pub tokenizer: bool,
+
+    /// Show the device, formats and size of the weights of each layer.
+    ///
+    /// This loads the whole model.
+    #[arg(long)]
+    pub placement: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct Doctor {
+    /// The path to the model file, or to any shard of a sharded model
+    #[arg()]
+    pub model_path: PathBuf,
 }
 
 #[derive(Parser, Debug)]
@@ -138,6 +494,60 @@ pub struct PromptTokens {
     pub prompt: Prompt,
 }
 
+#[derive(Subcommand, Debug)]
+pub enum Template {
+    /// Render a chat prompt with a template, and report how many tokens each part of it
+    /// uses. Warns if the prompt does not fit in the context window.
+    Check(Box<TemplateCheck>),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Vocab {
+    /// Report statistics about the vocabulary of a model: its size, which bytes it cannot
+    /// represent, its longest and duplicated tokens, the embeddings that are unused, and the
+    /// scripts of its tokens. Useful to find mismatches between a model and a tokenizer.
+    Stats(Box<VocabStats>),
+}
+
+#[derive(Parser, Debug)]
+pub struct VocabStats {
+    #[command(flatten)]
+    pub model_and_tokenizer: ModelAndTokenizer,
+
+    /// How many of the longest tokens, and of each other list of tokens, to show.
+    #[arg(long, default_value_t = 10)]
+    pub top: usize,
+
+    /// Token embeddings with an L2 norm at most this large are reported as unused.
+    #[arg(long, default_value_t = 1e-4)]
+    pub unused_norm: f32,
+}
+
+#[derive(Parser, Debug)]
+pub struct TemplateCheck {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    /// The prompt template to use: one of `chatml`, `llama2`, `vicuna` or `alpaca`.
+    #[arg(long)]
+    pub template: PromptTemplate,
+
+    /// The system prompt.
+    #[arg(long)]
+    pub system: Option<String>,
+
+    /// A JSON file containing the messages of the conversation, as an array of
+    /// `{"role": "user", "content": "..."}` objects. The role can be `system`, `user`
+    /// or `assistant`.
+    #[arg(long)]
+    pub messages: Option<PathBuf>,
+
+    /// The number of tokens to leave free for the model's reply. A warning is shown
+    /// if the prompt leaves less room than this in the context window.
+    #[arg(long, default_value_t = 0)]
+    pub reserve: usize,
+}
+
 #[derive(Parser, Debug)]
 pub struct Prompt {
     /// The prompt to feed the generator.
@@ -191,10 +601,36 @@ pub struct Chat {
     #[arg(long, short = 'q')]
     pub message_prompt_prefix_file: Option<PathBuf>,
 
+    /// Write the transcript of the chat to this file after every reply, with the prelude
+    /// as the system prompt.
+    #[arg(long)]
+    pub export: Option<PathBuf>,
+
+    /// The format of the transcript written with `--export`: chatml, openai-json or
+    /// markdown. Defaults to openai-json for `.json` files, markdown for `.md` files and
+    /// chatml otherwise.
+    #[arg(long, requires = "export")]
+    pub export_format: Option<ExportFormat>,
+
     #[command(flatten)]
     pub generate: Generate,
 }
 impl Chat {
+    pub fn export_format(&self) -> ExportFormat {
+        self.export_format.unwrap_or_else(|| {
+            match self
+                .export
+                .as_ref()
+                .and_then(|path| path.extension())
+                .and_then(|extension| extension.to_str())
+            {
+                Some("json") => ExportFormat::OpenAiJson,
+                Some("md") => ExportFormat::Markdown,
+                _ => ExportFormat::ChatMl,
+            }
+        })
+    }
+
     pub fn message_prompt_prefix(&self) -> eyre::Result<String> {
         const MESSAGE_PROMPT_PREFIX_ERROR: &str = concat!(
             "Message prompt prefix must not contain a `{{PROMPT}}` placeholder. ",
@@ -228,16 +664,67 @@ impl Chat {
     }
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 pub struct Generate {
     /// Sets the number of threads to use
     #[arg(long, short = 't')]
     pub num_threads: Option<usize>,
 
+    /// Sets the number of threads to use when generating tokens one at a time.
+    /// Defaults to the value of `--num-threads`.
+    #[arg(long)]
+    pub decode_threads: Option<usize>,
+
+    /// Automatically tune the number of threads used when generating tokens
+    /// one at a time, up to `--num-threads`, based on the measured latency.
+    #[arg(long, conflicts_with = "decode_threads")]
+    pub auto_threads: bool,
+
     /// Sets how many tokens to predict
     #[arg(long, short = 'n')]
     pub num_predict: Option<usize>,
 
+    /// Stops generating once the output reaches this many bytes. The output is cut
+    /// at a character boundary.
+    #[arg(long)]
+    pub max_output_bytes: Option<usize>,
+
+    /// Stops generating once the output reaches this many characters.
+    #[arg(long)]
+    pub max_output_chars: Option<usize>,
+
+    /// Stops generating after this many seconds, counting the time spent feeding the
+    /// prompt. The text generated until then is kept.
+    #[arg(long)]
+    pub timeout: Option<f64>,
+
+    /// Stops generating when the probability of an end-of-text token goes above this, even
+    /// if one would not have been sampled.
+    #[arg(long)]
+    pub stop_eot_probability: Option<f32>,
+
+    /// Stops generating after this many tokens in a row were predicted with an entropy
+    /// below --low-entropy-threshold, e.g. when the model is stuck repeating itself.
+    #[arg(long)]
+    pub stop_low_entropy_steps: Option<usize>,
+
+    /// The entropy, in nats, below which a token counts towards --stop-low-entropy-steps.
+    #[arg(long, default_value_t = 0.1)]
+    #[serde(default = "default_low_entropy_threshold")]
+    pub low_entropy_threshold: f32,
+
+    /// Stops generating when the model generates this sequence of token IDs, given
+    /// as a comma separated list like "32000,13". The tokens of the sequence are
+    /// not output. Can be repeated to stop at any of several sequences.
+    #[arg(long = "stop-tokens", value_parser = parse_token_ids)]
+    #[serde(default)]
+    pub stop_token_sequences: Vec<Vec<llm::TokenId>>,
+
+    /// Forces the response to start with this text, e.g. "Answer: ". It is output
+    /// as part of the response, and generation continues from it.
+    #[arg(long)]
+    pub forced_prefix: Option<String>,
+
     /// How many tokens from the prompt at a time to feed the network. Does not
     /// affect generation.
     #[arg(long, default_value_t = 8)]
@@ -254,6 +741,50 @@ pub struct Generate {
     #[arg(long, default_value_t = 1.30)]
     pub repeat_penalty: f32,
 
+    /// Do not apply the repeat penalty to the tokens of the prompt, so that the model
+    /// is not punished for using words from it.
+    #[arg(long)]
+    pub no_penalize_prompt: bool,
+
+    /// The number of tokens at the end of the prompt that the repeat penalty applies to,
+    /// e.g. to penalize the end of a conversation but not the question. By default, it
+    /// applies to the whole prompt (within `--repeat-last-n`).
+    #[arg(long, conflicts_with = "no_penalize_prompt")]
+    #[serde(default)]
+    pub repeat_prompt_window: Option<usize>,
+
+    /// The DRY ("don't repeat yourself") penalty for a token that extends a verbatim
+    /// repetition of `--dry-allowed-length` tokens, which grows with longer repetitions.
+    /// Unlike `--repeat-penalty`, it spares short repetitions such as braces and
+    /// indentation in code. 0 disables DRY; 0.8 is a common value, with
+    /// `--repeat-penalty 1`.
+    #[arg(long, default_value_t = 0.0)]
+    #[serde(default)]
+    pub dry_multiplier: f32,
+
+    /// How quickly the DRY penalty grows with the length of the repetition.
+    #[arg(long, default_value_t = 1.75)]
+    #[serde(default = "default_dry_base")]
+    pub dry_base: f32,
+
+    /// The length of the repetitions that DRY does not penalize.
+    #[arg(long, default_value_t = 2)]
+    #[serde(default = "default_dry_allowed_length")]
+    pub dry_allowed_length: usize,
+
+    /// Text that DRY repetitions do not extend across: tokens containing it break them.
+    /// Can be repeated; `\n` stands for a line break. Defaults to line breaks, `:`, `"`
+    /// and `*`.
+    #[arg(long = "dry-sequence-breaker")]
+    #[serde(default)]
+    pub dry_sequence_breakers: Vec<String>,
+
+    /// The number of previous tokens that DRY searches for repetitions. 0 searches the
+    /// whole context.
+    #[arg(long, default_value_t = 0)]
+    #[serde(default)]
+    pub dry_last_n: usize,
+
     /// Temperature
     #[arg(long, default_value_t = 0.80)]
     pub temperature: f32,
@@ -267,9 +798,62 @@ pub struct Generate {
     #[arg(long, default_value_t = 0.95)]
     pub top_p: f32,
 
+    /// Typical-p: if set, only the tokens whose surprise is closest to the expected
+    /// surprise are kept for sampling, up to this cumulative probability, before top-p is
+    /// applied. Values like 0.9 reduce repetition in long generations.
+    #[arg(long)]
+    pub typical_p: Option<f32>,
+
+    /// The number of candidate tokens that top-k, top-p and typical-p keep at least,
+    /// however aggressive they are.
+    #[arg(long, default_value_t = 1)]
+    #[serde(default = "default_min_keep")]
+    pub min_keep: usize,
+
+    /// Changes the temperature as the generation goes on, interpolating linearly between
+    /// `<tokens>:<temperature>` keyframes, where `<tokens>` is the number of generated
+    /// tokens, e.g. `0:0.2,200:1.0` to start precise and get more creative.
+    #[arg(
+        long,
+        value_parser = parse_keyframe,
+        value_delimiter = ',',
+        conflicts_with = "typical_p"
+    )]
+    #[serde(default)]
+    pub temperature_schedule: Vec<(usize, f32)>,
+
+    /// Changes top-p as the generation goes on, like `--temperature-schedule`.
+    #[arg(
+        long,
+        value_parser = parse_keyframe,
+        value_delimiter = ',',
+        conflicts_with = "typical_p"
+    )]
+    #[serde(default)]
+    pub top_p_schedule: Vec<(usize, f32)>,
+
+    /// Samples with a plugin: a dynamic library that exports `llm_sampler_sample`, as
+    /// described in the documentation of `llm::sampler_plugin`, instead of top-k and
+    /// top-p. The plugin gets the logits before any other option (e.g. `--temperature`,
+    /// `--token-bias`) is applied, except `--dry-multiplier`.
+    ///
+    /// The plugin runs with the permissions of `llm`; only load plugins you trust. It is
+    /// never sent to or accepted by `llm daemon`, so that clients cannot make the daemon
+    /// load a library.
+    #[cfg(feature = "sampler-plugins")]
+    #[arg(
+        long,
+        conflicts_with_all = ["typical_p", "temperature_schedule", "top_p_schedule"]
+    )]
+    #[serde(skip)]
+    pub sampler_plugin: Option<PathBuf>,
+
     /// Specifies the seed to use during sampling. Note that, depending on
     /// hardware, the same seed may lead to different results on two separate
     /// machines.
+    ///
+    /// Ignored when a session saved with `--save-session` or `--persist-session` is
+    /// loaded, as sampling continues from the state saved with it.
     #[arg(long, default_value = None)]
     pub seed: Option<u64>,
 
@@ -293,7 +877,16 @@ pub struct Generate {
     #[arg(long, default_value = None, value_parser = parse_bias)]
     pub token_bias: Option<TokenBias>,
 
-    /// Prevent the end of stream (EOS/EOD) token from being generated. This will allow the
+    /// Bias a word or other text, of the form `TEXT=BIAS`, without knowing the vocabulary of
+    /// the model: the tokens of the text are biased, with and without a leading space, once
+    /// the tokens before them have been generated. A bias of `-inf` bans the text without
+    /// banning the other words that start the same way. Can be repeated. Biases given with --token-bias take
+    /// precedence.
+    #[arg(long = "text-bias", value_parser = parse_text_bias)]
+    #[serde(default)]
+    pub text_biases: Vec<(String, f32)>,
+
+    /// Prevent the end of stream (EOS/EOD) tokens from being generated. This will allow the
     /// model to generate text until it runs out of context space. Note: The --token-bias
     /// option will override this if specified.
     #[arg(long, default_value_t = false)]
@@ -302,6 +895,50 @@ pub struct Generate {
     /// Whether to use GPU acceleration when available
     #[arg(long, default_value_t = false)]
     pub use_gpu: bool,
+
+    /// The context size of the session (in tokens), if it should be smaller than the
+    /// context size the model is loaded with (`--num-ctx-tokens`). With `llm daemon`, this
+    /// lets each request reserve only the memory it needs.
+    #[arg(long)]
+    pub session_ctx_tokens: Option<usize>,
+
+    /// Evict entries from the key/value memory when it is full, so that generation can go
+    /// past the context size: the `<recent>` most recent tokens are kept, along with the
+    /// `<heavy_hitters>` older tokens that received the most attention. Of the form
+    /// `<recent>,<heavy_hitters>`. Supported by LLaMA, GPT-J, GPT-NeoX and Falcon.
+    #[arg(long, value_parser = parse_kv_eviction)]
+    pub kv_eviction: Option<KvEviction>,
+
+    /// When the context is full, drop the oldest half of the tokens after the first
+    /// `<keep_first_n>`, e.g. those of a system prompt, and go on generating. Supported by
+    /// LLaMA, GPT-J, GPT-NeoX and Falcon.
+    #[arg(long, value_name = "KEEP_FIRST_N", conflicts_with = "kv_eviction")]
+    pub context_shift: Option<usize>,
+
+    /// Keep the beginning-of-sentence tokens at the start of the prompt that duplicate the
+    /// one added before it, e.g. with `--token-escapes`, instead of removing them.
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    pub allow_duplicate_bos: bool,
+
+    /// Replace `{{token:ID}}` in the prompt with the token `ID`, to place exact control
+    /// tokens in it (e.g. `{{token:32001}}`).
+    #[arg(long, default_value_t = false)]
+    pub token_escapes: bool,
+
+    /// Write the computation graph of the first forward pass to this file, for debugging
+    /// model implementations. It is written as JSON if the file name ends with `.json`,
+    /// and as a Graphviz DOT graph otherwise. Not accepted by `llm daemon`.
+    #[cfg(feature = "graph-dump")]
+    #[arg(long)]
+    #[serde(skip)]
+    pub dump_graph: Option<PathBuf>,
+
+    /// If the model produces logits that are NaN or infinite, which stops inference, write
+    /// them to this file for a bug report. Not accepted by `llm daemon`.
+    #[arg(long)]
+    #[serde(skip)]
+    pub dump_non_finite_logits: Option<PathBuf>,
 }
 impl Generate {
     #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
@@ -325,6 +962,20 @@ impl Generate {
             .unwrap_or_else(|| self.autodetect_num_threads())
     }
 
+    pub fn thread_count(&self) -> ThreadCount {
+        let num_threads = self.num_threads();
+        if self.auto_threads {
+            ThreadCount::Auto { max: num_threads }
+        } else if let Some(decode) = self.decode_threads {
+            ThreadCount::PerPhase {
+                prompt: num_threads,
+                decode,
+            }
+        } else {
+            ThreadCount::Fixed(num_threads)
+        }
+    }
+
     pub fn inference_session_config(&self) -> InferenceSessionConfig {
         let mem_typ = if self.no_float16 {
             ModelKVMemoryType::Float32
@@ -335,41 +986,173 @@ impl Generate {
             memory_k_type: mem_typ,
             memory_v_type: mem_typ,
             use_gpu: self.use_gpu,
+            context_size: self.session_ctx_tokens,
+            kv_eviction: self.kv_eviction,
+            context_overflow: self
+                .context_shift
+                .map_or(ContextOverflowPolicy::Fail, |keep_first_n| {
+                    ContextOverflowPolicy::Shift { keep_first_n }
+                }),
+            #[cfg(feature = "graph-dump")]
+            dump_graph: self.dump_graph.clone().map(llm::GraphDump::new),
+            numerical_error_dump: self.dump_non_finite_logits.clone(),
+            allow_duplicate_bos: self.allow_duplicate_bos,
+            ..Default::default()
         }
     }
 
-    pub fn rng(&self) -> rand::rngs::StdRng {
+    pub fn prompt<'a>(&self, text: &'a str) -> llm::Prompt<'a> {
+        if self.token_escapes {
+            llm::Prompt::WithTokenEscapes(text)
+        } else {
+            llm::Prompt::Text(text)
+        }
+    }
+
+    pub fn early_stop(&self) -> llm::EarlyStop {
+        llm::EarlyStop {
+            eot_probability: self.stop_eot_probability,
+            low_entropy_steps: self.stop_low_entropy_steps,
+            low_entropy_threshold: self.low_entropy_threshold,
+        }
+    }
+
+    /// The deadline of a generation that starts now, from --timeout.
+    pub fn deadline(&self) -> Option<std::time::Instant> {
+        self.timeout
+            .map(|timeout| std::time::Instant::now() + std::time::Duration::from_secs_f64(timeout))
+    }
+
+    pub fn rng(&self) -> ChaCha12Rng {
         if let Some(seed) = self.seed {
-            rand::rngs::StdRng::seed_from_u64(seed)
+            ChaCha12Rng::seed_from_u64(seed)
         } else {
-            rand::rngs::StdRng::from_entropy()
+            ChaCha12Rng::from_entropy()
         }
     }
 
-    pub fn inference_parameters(&self, eot: llm::TokenId) -> InferenceParameters {
-        InferenceParameters {
-            n_threads: self.num_threads(),
-            n_batch: self.batch_size,
-            sampler: Arc::new(llm::samplers::TopPTopK {
-                top_k: self.top_k,
-                top_p: self.top_p,
-                repeat_penalty: self.repeat_penalty,
-                temperature: self.temperature,
-                bias_tokens: self.token_bias.clone().unwrap_or_else(|| {
-                    if self.ignore_eos {
-                        TokenBias::new(vec![(eot, -1.0)])
-                    } else {
-                        TokenBias::default()
-                    }
-                }),
-                repetition_penalty_last_n: self.repeat_last_n,
+    pub fn inference_parameters(&self, model: &dyn Model) -> eyre::Result<InferenceParameters> {
+        let stop_tokens = model.stop_token_ids();
+        let mut bias_tokens = self.token_bias.clone().unwrap_or_else(|| {
+            if self.ignore_eos {
+                TokenBias::new(stop_tokens.iter().map(|&id| (id, -1.0)).collect())
+            } else {
+                TokenBias::default()
+            }
+        });
+        for (text, bias) in &self.text_biases {
+            bias_tokens
+                .add_text(model.tokenizer(), text, *bias)
+                .wrap_err_with(|| format!("Could not tokenize the biased text {text:?}"))?;
+        }
+        let base = llm::samplers::TopPTopK {
+            top_k: self.top_k,
+            top_p: self.top_p,
+            repeat_penalty: self.repeat_penalty,
+            temperature: self.temperature,
+            bias_tokens,
+            repetition_penalty_last_n: self.repeat_last_n,
+            penalize_prompt: !self.no_penalize_prompt,
+            repetition_penalty_prompt_window: self.repeat_prompt_window,
+            min_keep: self.min_keep,
+        };
+        let mut schedule = vec![];
+        for &(at, temperature) in &self.temperature_schedule {
+            schedule.push(Keyframe {
+                at,
+                temperature: Some(temperature),
+                top_p: None,
+            });
+        }
+        for &(at, top_p) in &self.top_p_schedule {
+            schedule.push(Keyframe {
+                at,
+                temperature: None,
+                top_p: Some(top_p),
+            });
+        }
+        let mut sampler: Arc<dyn Sampler> = match self.typical_p {
+            Some(typical_p) => Arc::new(llm::samplers::Typical { typical_p, base }),
+            None if !schedule.is_empty() => Arc::new(llm::samplers::Scheduled {
+                base,
+                schedule: ParameterSchedule::Keyframes(schedule),
             }),
+            None => Arc::new(base),
+        };
+        #[cfg(feature = "sampler-plugins")]
+        if let Some(path) = &self.sampler_plugin {
+            // SAFETY: the plugin was chosen by the user, who trusts it. It is only set from
+            // the command line, as it is not deserialized from daemon requests.
+            sampler = Arc::new(unsafe { llm::sampler_plugin::PluginSampler::load(path) }?);
+        }
+        if self.dry_multiplier != 0.0 {
+            let breakers: Vec<String> = if self.dry_sequence_breakers.is_empty() {
+                Dry::DEFAULT_SEQUENCE_BREAKERS.map(String::from).to_vec()
+            } else {
+                self.dry_sequence_breakers
+                    .iter()
+                    .map(|breaker| breaker.replace("\\n", "\n"))
+                    .collect()
+            };
+            sampler = Arc::new(Dry {
+                multiplier: self.dry_multiplier,
+                exponent_base: self.dry_base,
+                allowed_length: self.dry_allowed_length,
+                sequence_breakers: Dry::breaker_tokens(model.tokenizer(), &breakers),
+                last_n: self.dry_last_n,
+                base: sampler,
+            });
         }
+        Ok(InferenceParameters {
+            n_threads: self.thread_count(),
+            n_batch: self.batch_size,
+            sampler,
+        })
     }
 }
 fn parse_bias(s: &str) -> Result<TokenBias, InvalidTokenBias> {
     s.parse()
 }
+fn parse_text_bias(s: &str) -> Result<(String, f32), String> {
+    // The text can contain `=`, but the bias cannot.
+    let (text, bias) = s
+        .rsplit_once('=')
+        .ok_or_else(|| "should be of the form TEXT=BIAS".to_owned())?;
+    if text.is_empty() {
+        return Err("the text is empty".to_owned());
+    }
+    let bias = bias.trim().parse().map_err(|e| format!("{e}"))?;
+    Ok((text.to_owned(), bias))
+}
+fn default_min_keep() -> usize {
+    1
+}
+fn default_low_entropy_threshold() -> f32 {
+    0.1
+}
+fn default_dry_base() -> f32 {
+    1.75
+}
+fn default_dry_allowed_length() -> usize {
+    2
+}
+fn parse_keyframe(s: &str) -> Result<(usize, f32), String> {
+    let invalid = || format!("{s:?} is not of the form <tokens>:<value>");
+    let (at, value) = s.split_once(':').ok_or_else(invalid)?;
+    Ok((
+        at.trim().parse().map_err(|_| invalid())?,
+        value.trim().parse().map_err(|_| invalid())?,
+    ))
+}
+fn parse_token_ids(s: &str) -> Result<Vec<llm::TokenId>, String> {
+    s.split(',')
+        .map(|id| {
+            id.trim()
+                .parse()
+                .map_err(|_| format!("{id:?} is not a token ID"))
+        })
+        .collect()
+}
 
 #[derive(Parser, Debug)]
 pub struct ModelTokenizer {
@@ -450,6 +1233,12 @@ pub struct ModelLoad {
     #[arg(long, default_value_t = 2048)]
     pub num_ctx_tokens: usize,
 
+    /// Chooses the largest context size whose key/value memory fits in this much
+    /// memory (e.g. `512MiB`), instead of using `--num-ctx-tokens`. The chosen size is
+    /// logged.
+    #[arg(long, conflicts_with = "num_ctx_tokens")]
+    pub ctx_memory: Option<bytesize::ByteSize>,
+
     /// Don't use mmap to load the model.
     #[arg(long)]
     pub no_mmap: bool,
@@ -457,20 +1246,97 @@ pub struct ModelLoad {
     /// LoRA adapter to use for the model
     #[arg(long, num_args(0..))]
     pub lora_paths: Option<Vec<PathBuf>>,
+
+    /// The number of layers to offload to the GPU with `--use-gpu`, starting from the
+    /// first. All layers are offloaded if not specified.
+    ///
+    /// Only supported with CUDA (the `cublas` feature) or OpenCL (the `opencl` feature);
+    /// use it to run models that are larger than the memory of the GPU. With OpenCL, the
+    /// GPU can be chosen with the `GGML_OPENCL_PLATFORM` and `GGML_OPENCL_DEVICE`
+    /// environment variables.
+    #[arg(long)]
+    pub gpu_layers: Option<usize>,
+
+    /// Where to store the weights of each layer with `--use-gpu`, instead of offloading the
+    /// first `--gpu-layers`. A comma separated list of `<layers>=<cpu|gpu>`, where
+    /// `<layers>` is a layer or a range of layers like `0-15`, optionally followed by
+    /// `.attention` or `.feed_forward`, or `output=<cpu|gpu>` for the output weights.
+    ///
+    /// For example, `0-31.attention=gpu` only offloads the attention weights of the first
+    /// 32 layers. Layers that are not listed stay on the CPU.
+    #[arg(long, conflicts_with = "gpu_layers")]
+    pub placement: Option<llm::placement::PlacementPolicy>,
+
+    /// The expected SHA-256 of the model file, in hex. The model is hashed before it is
+    /// loaded, and is not loaded if its hash is different.
+    #[arg(long, value_parser = parse_sha256)]
+    pub sha256: Option<[u8; 32]>,
+
+    /// Gives up loading the model after this many seconds, e.g. when a network filesystem
+    /// stalls, reporting the stage that took too long.
+    #[arg(long)]
+    pub load_timeout: Option<f64>,
+
+    /// Replaces the tensors whose names match a glob with those of another model file,
+    /// given as `<glob>=<path>`, e.g. `output.weight=repaired-head.bin`. `*` matches any
+    /// text. Can be repeated; later overrides take precedence.
+    #[arg(long = "tensor-override", value_parser = parse_tensor_override)]
+    pub tensor_overrides: Vec<(String, PathBuf)>,
+}
+fn parse_kv_eviction(s: &str) -> Result<KvEviction, String> {
+    let invalid = || format!("{s:?} is not of the form <recent>,<heavy_hitters>");
+    let (recent, heavy_hitters) = s.split_once(',').ok_or_else(invalid)?;
+    Ok(KvEviction {
+        recent: recent.trim().parse().map_err(|_| invalid())?,
+        heavy_hitters: heavy_hitters.trim().parse().map_err(|_| invalid())?,
+    })
+}
+fn parse_tensor_override(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((pattern, path)) if !pattern.is_empty() && !path.is_empty() => {
+            Ok((pattern.to_owned(), PathBuf::from(path)))
+        }
+        _ => Err(format!("{s:?} is not of the form <glob>=<path>")),
+    }
+}
+fn parse_sha256(s: &str) -> Result<[u8; 32], String> {
+    let invalid = || format!("{s:?} is not a SHA-256 in hex");
+    if s.len() != 64 || !s.is_ascii() {
+        return Err(invalid());
+    }
+    let mut hash = [0; 32];
+    for (byte, hex) in hash.iter_mut().zip(s.as_bytes().chunks(2)) {
+        let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(hex, 16).map_err(|_| invalid())?;
+    }
+    Ok(hash)
 }
 impl ModelLoad {
     pub fn load(&self, use_gpu: bool) -> eyre::Result<Box<dyn Model>> {
         let params = ModelParameters {
             prefer_mmap: !self.no_mmap,
-            context_size: self.num_ctx_tokens,
+            context_size: match self.ctx_memory {
+                Some(memory) => ContextSize::Auto {
+                    max_memory_bytes: memory.as_u64() as usize,
+                },
+                None => ContextSize::Fixed(self.num_ctx_tokens),
+            },
             lora_adapters: self.lora_paths.clone(),
             use_gpu,
+            gpu_layers: self.gpu_layers,
+            placement: self.placement.clone(),
+            expected_sha256: self.sha256,
+            load_timeout: self.load_timeout.map(std::time::Duration::from_secs_f64),
+            tensor_overrides: self.tensor_overrides.clone(),
+            ..Default::default()
         };
 
-        let mut sp = Some(spinoff::Spinner::new(
+        // The spinner is a diagnostic, so it must not end up in piped output.
+        let mut sp = Some(spinoff::Spinner::new_with_stream(
             spinoff::spinners::Dots2,
             "Loading model...",
             None,
+            spinoff::Streams::Stderr,
         ));
         let now = std::time::Instant::now();
         let mut prev_load_time = now;
@@ -491,6 +1357,17 @@ impl ModelLoad {
             tokenizer_source,
             params,
             |progress| match progress {
+                LoadProgress::Verifying { bytes, total_bytes } => {
+                    if prev_load_time.elapsed().as_millis() > 500 || bytes == total_bytes {
+                        if let Some(sp) = sp.as_mut() {
+                            sp.update_text(format!(
+                                "Verifying model ({}%)",
+                                bytes * 100 / total_bytes.max(1)
+                            ));
+                        };
+                        prev_load_time = std::time::Instant::now();
+                    }
+                }
                 LoadProgress::HyperparametersLoaded => {
                     if let Some(sp) = sp.as_mut() {
                         sp.update_text("Loaded hyperparameters")
@@ -500,15 +1377,25 @@ impl ModelLoad {
                     "ggml ctx size = {}",
                     bytesize::to_string(bytes as u64, false)
                 ),
+                LoadProgress::GpuSelected { platform, device } => {
+                    log::info!("Offloading to the GPU {device} ({platform})")
+                }
                 LoadProgress::LoraApplied { name, source } => {
                     if let Some(sp) = sp.as_mut() {
                         sp.update_text(format!(
                             "Patched tensor {} via LoRA from '{}'",
                             name,
-                            source.file_name().unwrap().to_str().unwrap()
+                            source.file_name().unwrap_or_default().to_string_lossy()
                         ));
                     }
                 }
+                LoadProgress::ShardLoaded {
+                    index, shard_count, ..
+                } => {
+                    if let Some(sp) = sp.as_mut() {
+                        sp.update_text(format!("Read shard {}/{shard_count}", index + 1));
+                    };
+                }
                 LoadProgress::TensorLoaded {
                     current_tensor,
                     tensor_count,
@@ -584,7 +1471,7 @@ pub struct Quantize {
     #[arg()]
     pub source: PathBuf,
 
-    /// The path to save the quantized model to
+    /// The path to save the quantized model to. Nothing is written with `--dry-run`.
     #[arg()]
     pub destination: PathBuf,
 
@@ -600,6 +1487,11 @@ pub struct Quantize {
 
     /// The format to convert to
     pub target: QuantizationTarget,
+
+    /// Only read the tensor metadata, and print the type and projected size of each
+    /// tensor and the memory needed, without quantizing the model.
+    #[arg(long)]
+    pub dry_run: bool,
 }
 
 #[derive(Parser, Debug, ValueEnum, Clone, Copy)]
@@ -651,3 +1543,141 @@ impl From<QuantizationTarget> for ElementType {
         }
     }
 }
+
+#[derive(Parser, Debug)]
+pub struct Migrate {
+    /// The architecture of the model. If not specified, it is recognized from the
+    /// tensors of the model.
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the model to migrate
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the migrated model to
+    #[arg()]
+    pub destination: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct Convert {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the directory of the checkpoint, containing `config.json`,
+    /// `tokenizer.json` and the `.safetensors` or `pytorch_model*.bin` weights
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the converted model to
+    #[arg()]
+    pub destination: PathBuf,
+
+    /// The format to convert to.
+    ///
+    /// The weights are read from the checkpoint and quantized one tensor at a time,
+    /// so no intermediate f16 model is written.
+    #[arg(long, short = 'q', default_value_t = ConvertTarget::F16)]
+    pub quantize: ConvertTarget,
+
+    /// Pad or truncate the token embeddings and output weights if the checkpoint has
+    /// a different number of rows than its tokenizer has tokens. New rows are the mean
+    /// of the existing rows.
+    #[arg(long)]
+    pub resize_vocabulary: Option<ResizeVocabulary>,
+
+    /// Use the Hugging Face tokenizer at this path instead of the checkpoint's, and move
+    /// the rows of the token embeddings and output weights to the IDs of their tokens in it.
+    #[arg(long, conflicts_with = "resize_vocabulary")]
+    pub remap_vocabulary: Option<PathBuf>,
+}
+impl Convert {
+    pub fn convert_parameters(&self) -> llm::convert::ConvertParameters {
+        use llm::convert::VocabularyAdjustment;
+
+        llm::convert::ConvertParameters {
+            format: self.quantize.into(),
+            vocabulary_adjustment: match (&self.remap_vocabulary, self.resize_vocabulary) {
+                (Some(path), _) => VocabularyAdjustment::Remap(path.clone()),
+                (None, Some(ResizeVocabulary::Pad)) => VocabularyAdjustment::Pad,
+                (None, Some(ResizeVocabulary::Truncate)) => VocabularyAdjustment::Truncate,
+                (None, None) => VocabularyAdjustment::None,
+            },
+        }
+    }
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum ResizeVocabulary {
+    /// Add rows for the tokens that have none.
+    Pad,
+    /// Drop the rows after the last token.
+    Truncate,
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+#[clap(rename_all = "snake_case")]
+#[allow(non_camel_case_types)]
+pub enum ConvertTarget {
+    /// 32-bit floats.
+    F32,
+    /// 16-bit floats.
+    F16,
+    /// Quantized 4-bit (type 0).
+    Q4_0,
+    /// Quantized 4-bit (type 1).
+    Q4_1,
+    /// Quantized 5-bit (type 0).
+    Q5_0,
+    /// Quantized 5-bit (type 1).
+    Q5_1,
+    /// Quantized 8-bit (type 0).
+    Q8_0,
+    /// k-quant 2-bit.
+    Q2_K,
+    /// k-quant 3-bit, small.
+    Q3_K_S,
+    /// k-quant 3-bit, medium.
+    Q3_K_M,
+    /// k-quant 3-bit, large.
+    Q3_K_L,
+    /// k-quant 4-bit, small.
+    Q4_K_S,
+    /// k-quant 4-bit, medium.
+    Q4_K_M,
+    /// k-quant 5-bit, small.
+    Q5_K_S,
+    /// k-quant 5-bit, medium.
+    Q5_K_M,
+    /// k-quant 6-bit.
+    Q6_K,
+}
+impl fmt::Display for ConvertTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no values are skipped");
+        write!(f, "{}", value.get_name())
+    }
+}
+impl From<ConvertTarget> for FileTypeFormat {
+    fn from(t: ConvertTarget) -> Self {
+        match t {
+            ConvertTarget::F32 => FileTypeFormat::F32,
+            ConvertTarget::F16 => FileTypeFormat::MostlyF16,
+            ConvertTarget::Q4_0 => FileTypeFormat::MostlyQ4_0,
+            ConvertTarget::Q4_1 => FileTypeFormat::MostlyQ4_1,
+            ConvertTarget::Q5_0 => FileTypeFormat::MostlyQ5_0,
+            ConvertTarget::Q5_1 => FileTypeFormat::MostlyQ5_1,
+            ConvertTarget::Q8_0 => FileTypeFormat::MostlyQ8_0,
+            ConvertTarget::Q2_K => FileTypeFormat::MostlyQ2_K,
+            ConvertTarget::Q3_K_S => FileTypeFormat::MostlyQ3_K_S,
+            ConvertTarget::Q3_K_M => FileTypeFormat::MostlyQ3_K_M,
+            ConvertTarget::Q3_K_L => FileTypeFormat::MostlyQ3_K_L,
+            ConvertTarget::Q4_K_S => FileTypeFormat::MostlyQ4_K_S,
+            ConvertTarget::Q4_K_M => FileTypeFormat::MostlyQ4_K_M,
+            ConvertTarget::Q5_K_S => FileTypeFormat::MostlyQ5_K_S,
+            ConvertTarget::Q5_K_M => FileTypeFormat::MostlyQ5_K_M,
+            ConvertTarget::Q6_K => FileTypeFormat::MostlyQ6_K,
+        }
+    }
+}
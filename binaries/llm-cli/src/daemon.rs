@@ -0,0 +1,1039 @@
+//! `llm daemon` keeps a model loaded and serves `llm infer --remote` requests over a
+//! Unix socket.
+//!
+//! The protocol is one JSON [Request] per connection, written on a single line by the
+//! client, followed by a stream of JSON [Response]s, one per line, from the daemon.
+//!
+//! A daemon only serves the kinds of requests ([cli_args::Endpoint]) it was started with,
+//! as a base model would answer a chat request with nonsense, and the other way around.
+//!
+//! The daemon echoes the seed it used, a fingerprint of the model and the generation
+//! parameters in [Metadata], so that a client can reproduce a result exactly by sending the
+//! same request with the same seed.
+//!
+//! With `--cache` or `--cache-dir`, the responses to requests that give a seed are cached, as
+//! they are deterministic, and replayed when the same request is sent again.
+//!
+//! A connection can send a [StatusRequest] instead, which is answered at once with the
+//! [Status] of the daemon, even while a request is being served. `llm top` displays it.
+//!
+//! On SIGINT or SIGTERM, the daemon removes its socket and rejects the requests it has not
+//! started serving, lets the request being served finish for up to its grace period, and
+//! exits. See [Shutdown].
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{self, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{cli_args, cli_args::Endpoint, util};
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    /// The model the client expects the daemon to have loaded, resolved by the client.
+    model_path: PathBuf,
+    #[serde(default)]
+    endpoint: Endpoint,
+    /// The prompt of completion and embeddings requests, or the text before the gap of
+    /// infill requests.
+    #[serde(default)]
+    prompt: String,
+    /// The text after the gap of infill requests.
+    #[serde(default)]
+    suffix: String,
+    /// The system prompt of chat requests.
+    #[serde(default)]
+    system: Option<String>,
+    /// The conversation to reply to, for chat requests.
+    #[serde(default)]
+    messages: Vec<llm::template::Message>,
+    generate: cli_args::Generate,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+enum Response {
+    PromptToken(String),
+    InferredToken(String),
+    /// The embeddings of an embeddings request.
+    Embeddings(Vec<f32>),
+    /// Inference stopped early, but the tokens sent so far are still valid.
+    Warning(String),
+    /// The request failed. This is the last response.
+    Error(String),
+    /// The request succeeded. This is the last response.
+    Finished {
+        stats: String,
+        metadata: Box<Metadata>,
+    },
+}
+
+/// What a client needs to reproduce a response.
+#[derive(Serialize, Deserialize, Clone)]
+struct Metadata {
+    /// The seed that was used for sampling: the one from the request, or a random one.
+    seed: u64,
+    /// The SHA-256 of the model file, in hex.
+    model_sha256: String,
+    /// The generation parameters, including the sampler configuration, with `seed` set.
+    generate: cli_args::Generate,
+}
+
+/// Asks the daemon for its [Status]: `{"status":true}`.
+#[derive(Serialize, Deserialize)]
+struct StatusRequest {
+    status: bool,
+}
+
+/// What the daemon is doing, sent in answer to a [StatusRequest].
+#[derive(Serialize, Deserialize)]
+struct Status {
+    model_path: PathBuf,
+    endpoints: Vec<Endpoint>,
+    uptime: Duration,
+    /// The number of requests served since the daemon started, including failed ones.
+    served: u64,
+    /// The number of requests waiting for the ones being served.
+    queued: usize,
+    /// The sessions of the requests being served.
+    sessions: Vec<SessionStatus>,
+    memory: llm::memory::MemoryUsage,
+}
+
+/// A session of a request being served.
+#[derive(Serialize, Deserialize, Clone)]
+struct SessionStatus {
+    endpoint: Endpoint,
+    /// The number of tokens in the context window, counted as they are fed or inferred.
+    context_tokens: usize,
+    context_size: usize,
+    generated_tokens: usize,
+    /// The generation speed, from the first inferred token on.
+    tokens_per_second: f64,
+    elapsed: Duration,
+}
+
+/// Tracks what the daemon is doing, to answer [StatusRequest]s.
+struct Monitor {
+    started: Instant,
+    state: Mutex<MonitorState>,
+}
+#[derive(Default)]
+struct MonitorState {
+    served: u64,
+    queued: usize,
+    session: Option<LiveSession>,
+}
+struct LiveSession {
+    endpoint: Endpoint,
+    started: Instant,
+    /// When the first token was inferred.
+    generation_started: Option<Instant>,
+    context_tokens: usize,
+    context_size: usize,
+    generated_tokens: usize,
+}
+impl Monitor {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            state: Default::default(),
+        }
+    }
+
+    fn update(&self, update: impl FnOnce(&mut MonitorState)) {
+        update(&mut self.state.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn status(&self, model_path: &Path, endpoints: &[Endpoint]) -> Status {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let sessions = state.session.iter().map(|session| {
+            let generating = session
+                .generation_started
+                .map_or(0.0, |started| started.elapsed().as_secs_f64());
+            SessionStatus {
+                endpoint: session.endpoint,
+                context_tokens: session.context_tokens,
+                context_size: session.context_size,
+                generated_tokens: session.generated_tokens,
+                tokens_per_second: if generating > 0.0 {
+                    session.generated_tokens as f64 / generating
+                } else {
+                    0.0
+                },
+                elapsed: session.started.elapsed(),
+            }
+        });
+        Status {
+            model_path: model_path.to_owned(),
+            endpoints: endpoints.to_vec(),
+            uptime: self.started.elapsed(),
+            served: state.served,
+            queued: state.queued,
+            sessions: sessions.collect(),
+            memory: llm::memory::usage(),
+        }
+    }
+}
+
+/// What the connection thread sends to the thread that serves requests.
+enum Message {
+    /// The first line of a connection, and the connection.
+    Request(String, UnixStream),
+    /// The daemon received SIGINT or SIGTERM.
+    Shutdown,
+}
+
+/// Shuts the daemon down gracefully. Once started, no more requests are served, and the one
+/// being served is cancelled when the grace period is over, or at once on a second signal.
+struct Shutdown {
+    grace_period: Duration,
+    started: AtomicBool,
+    /// Whether the grace period is over. Requests are cancelled as soon as they start.
+    expired: AtomicBool,
+    /// The cancellation token of the request being served.
+    current: Mutex<Option<llm::CancellationToken>>,
+}
+impl Shutdown {
+    fn new(grace_period: Duration) -> Self {
+        Self {
+            grace_period,
+            started: AtomicBool::new(false),
+            expired: AtomicBool::new(false),
+            current: Mutex::new(None),
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    /// Starts the shutdown and its grace period, or ends the grace period if the shutdown
+    /// had already started. Returns whether it started the shutdown.
+    fn start(self: &Arc<Self>) -> bool {
+        if self.started.swap(true, Ordering::SeqCst) {
+            self.expire();
+            return false;
+        }
+        let shutdown = self.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(shutdown.grace_period);
+            shutdown.expire();
+        });
+        true
+    }
+
+    /// Ends the grace period, cancelling the request being served.
+    fn expire(&self) {
+        self.expired.store(true, Ordering::SeqCst);
+        if let Some(token) = &*self.current.lock().unwrap_or_else(|e| e.into_inner()) {
+            token.cancel();
+        }
+    }
+
+    /// Sets the cancellation token of the request being served, which is cancelled at once
+    /// if the grace period is over.
+    fn watch(&self, token: Option<llm::CancellationToken>) {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(token) = &token {
+            if self.expired.load(Ordering::SeqCst) {
+                token.cancel();
+            }
+        }
+        *current = token;
+    }
+}
+
+/// What the daemon serves, checked when it starts.
+struct Endpoints {
+    served: Vec<Endpoint>,
+    template: Option<llm::template::PromptTemplate>,
+    /// The fill-in-the-middle tokens: prefix, suffix and middle.
+    infill_tokens: Option<[llm::TokenId; 3]>,
+}
+
+pub fn serve(args: &cli_args::Daemon) -> eyre::Result<()> {
+    let model = args.model_load.load(args.use_gpu)?;
+    let endpoints = Endpoints {
+        served: args.endpoints.clone(),
+        template: args.template,
+        infill_tokens: infill_tokens(model.tokenizer()),
+    };
+    if endpoints.served.contains(&Endpoint::Chat) && endpoints.template.is_none() {
+        eyre::bail!("Serving chat requests needs the --template the model was fine-tuned with");
+    }
+    if endpoints.served.contains(&Endpoint::Infill) && endpoints.infill_tokens.is_none() {
+        eyre::bail!(
+            "The model cannot serve infill requests, as it has no fill-in-the-middle tokens \
+            (<PRE>, <SUF> and <MID>, or <fim_prefix>, <fim_suffix> and <fim_middle>)"
+        );
+    }
+    let model_path = canonical(&args.model_load.model_and_tokenizer.model_path);
+    log::info!("Computing the fingerprint of {model_path:?}");
+    let model_sha256: String = llm::model_sha256(&model_path)
+        .wrap_err_with(|| format!("Could not read {model_path:?} to fingerprint it"))?
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    log::info!("Model SHA-256: {model_sha256}");
+    let cache = args
+        .cache_config()
+        .map(|(directory, config)| match directory {
+            Some(directory) => llm::cache::GenerationCache::open(directory, config)
+                .wrap_err_with(|| format!("Could not open the cache directory {directory:?}")),
+            None => Ok(llm::cache::GenerationCache::in_memory(config)),
+        });
+    let cache = cache.transpose()?;
+
+    if args.socket.exists() {
+        std::fs::remove_file(&args.socket)
+            .wrap_err_with(|| format!("Could not remove the stale socket {:?}", args.socket))?;
+    }
+    let listener = UnixListener::bind(&args.socket)
+        .wrap_err_with(|| format!("Could not listen on {:?}", args.socket))?;
+    log::info!("Listening on {:?}", args.socket);
+
+    // Connections are accepted on another thread, which answers status requests at once
+    // and queues the others.
+    let monitor = Arc::new(Monitor::new());
+    let shutdown = Arc::new(Shutdown::new(Duration::from_secs(
+        args.shutdown_grace_period,
+    )));
+    let (sender, receiver) = mpsc::channel();
+    {
+        let shutdown = shutdown.clone();
+        let sender = sender.clone();
+        let socket = args.socket.clone();
+        ctrlc::set_handler(move || {
+            if shutdown.start() {
+                log::info!(
+                    "Shutting down; the request being served has {:?} to finish \
+                    (send the signal again to cancel it)",
+                    shutdown.grace_period
+                );
+                // New clients cannot connect once the socket is gone.
+                remove_socket(&socket);
+                let _ = sender.send(Message::Shutdown);
+            } else {
+                log::info!("Cancelling the request being served");
+            }
+        })
+        .wrap_err("Could not handle SIGINT and SIGTERM")?;
+    }
+    {
+        let monitor = monitor.clone();
+        let shutdown = shutdown.clone();
+        let model_path = model_path.clone();
+        let served = endpoints.served.clone();
+        std::thread::spawn(move || {
+            accept(listener, &monitor, &shutdown, &model_path, &served, sender);
+        });
+    }
+
+    // Requests are served one at a time: they would compete for the same CPU cores anyway.
+    for message in &receiver {
+        let Message::Request(line, stream) = message else {
+            break;
+        };
+        monitor.update(|state| state.queued -= 1);
+        if shutdown.is_started() {
+            reject(&stream);
+            continue;
+        }
+        if let Err(err) = handle(
+            model.as_ref(),
+            &model_path,
+            &model_sha256,
+            &endpoints,
+            &monitor,
+            &shutdown,
+            cache.as_ref(),
+            &line,
+            stream,
+        ) {
+            log::warn!("Request failed: {err}");
+        }
+        monitor.update(|state| {
+            state.served += 1;
+            state.session = None;
+        });
+    }
+
+    // The requests queued while the last one was served.
+    for message in receiver.try_iter() {
+        if let Message::Request(_, stream) = message {
+            reject(&stream);
+        }
+    }
+    remove_socket(&args.socket);
+    log::info!("Shut down");
+    Ok(())
+}
+
+/// Answers a request that arrived after the shutdown started.
+fn reject(stream: &UnixStream) {
+    let mut writer = stream;
+    let response = Response::Error("The daemon is shutting down".to_owned());
+    if let Err(err) = serde_json::to_writer(&mut writer, &response)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writer.write_all(b"\n"))
+    {
+        log::warn!("Could not reject a request: {err}");
+    }
+}
+
+fn remove_socket(socket: &Path) {
+    match std::fs::remove_file(socket) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            log::warn!("Could not remove the socket {socket:?}: {err}")
+        }
+        _ => {}
+    }
+}
+
+/// Accepts connections, answers status requests, and sends the first line of the others to
+/// be served.
+fn accept(
+    listener: UnixListener,
+    monitor: &Monitor,
+    shutdown: &Shutdown,
+    model_path: &Path,
+    endpoints: &[Endpoint],
+    sender: mpsc::Sender<Message>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("Could not accept a connection: {err}");
+                continue;
+            }
+        };
+        // A client that never sends its request must not stop the daemon from accepting others.
+        let mut line = String::new();
+        let read = stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .and_then(|()| BufReader::new(&stream).read_line(&mut line));
+        if let Err(err) = read {
+            log::warn!("Could not read a request: {err}");
+            continue;
+        }
+
+        if let Ok(StatusRequest { status: true }) = serde_json::from_str(&line) {
+            let status = monitor.status(model_path, endpoints);
+            let mut writer = &stream;
+            if let Err(err) = serde_json::to_writer(&mut writer, &status)
+                .map_err(std::io::Error::from)
+                .and_then(|()| writer.write_all(b"\n"))
+            {
+                log::warn!("Could not send the status: {err}");
+            }
+            continue;
+        }
+
+        if shutdown.is_started() {
+            reject(&stream);
+            continue;
+        }
+        monitor.update(|state| state.queued += 1);
+        if sender.send(Message::Request(line, stream)).is_err() {
+            return;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle(
+    model: &dyn llm::Model,
+    model_path: &Path,
+    model_sha256: &str,
+    endpoints: &Endpoints,
+    monitor: &Monitor,
+    shutdown: &Shutdown,
+    cache: Option<&llm::cache::GenerationCache<Vec<Response>>>,
+    line: &str,
+    stream: UnixStream,
+) -> eyre::Result<()> {
+    let mut writer = &stream;
+    let mut send = |response: Response| -> std::io::Result<()> {
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")
+    };
+
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => {
+            send(Response::Error(format!("Invalid request: {err}")))?;
+            return Ok(());
+        }
+    };
+    if request.model_path != model_path {
+        send(Response::Error(format!(
+            "The daemon has {model_path:?} loaded, not {:?}",
+            request.model_path
+        )))?;
+        return Ok(());
+    }
+    if let Err(error) = check_endpoint(&request, endpoints) {
+        send(Response::Error(error))?;
+        return Ok(());
+    }
+    log::info!(
+        "Serving a {} request with a {}-byte prompt",
+        request.endpoint,
+        request.prompt.len()
+    );
+
+    // Seeded requests are deterministic, so their responses can be cached.
+    let cache = cache.filter(|_| request.generate.seed.is_some());
+    let key = match cache {
+        Some(_) => Some(llm::cache::CacheKey::new(
+            model_sha256,
+            &request.prompt,
+            &request,
+        )?),
+        None => None,
+    };
+    if let (Some(cache), Some(key)) = (cache, key) {
+        if let Some(responses) = cache.get(&key) {
+            log::info!("Answering from the cache");
+            for response in responses {
+                send(response)?;
+            }
+            return Ok(());
+        }
+    }
+
+    let mut responses = vec![];
+    let mut record = |response: Response| {
+        if key.is_some() {
+            responses.push(response.clone());
+        }
+        send(response)
+    };
+    let cancellation_token = llm::CancellationToken::new();
+    watch_disconnection(&stream, cancellation_token.clone())?;
+    shutdown.watch(Some(cancellation_token.clone()));
+    let result = respond(
+        model,
+        model_sha256,
+        endpoints,
+        monitor,
+        shutdown,
+        request,
+        &cancellation_token,
+        &mut record,
+    );
+    shutdown.watch(None);
+    // Stops the watcher, whose token is not used anymore.
+    let _ = stream.shutdown(std::net::Shutdown::Read);
+    result?;
+    // Only complete responses are cached, not errors.
+    if let (Some(cache), Some(key), Some(Response::Finished { .. })) =
+        (cache, key, responses.last())
+    {
+        cache.insert(key, responses);
+    }
+
+    Ok(())
+}
+
+/// Cancels `token` when the client disconnects, so that its generation stops at once
+/// instead of when the next token cannot be sent. Clients send nothing after their
+/// request, so a read that returns means that the connection was closed.
+fn watch_disconnection(stream: &UnixStream, token: llm::CancellationToken) -> std::io::Result<()> {
+    let mut stream = stream.try_clone()?;
+    stream.set_read_timeout(None)?;
+    std::thread::spawn(move || {
+        let _ = stream.read(&mut [0; 1]);
+        token.cancel();
+    });
+    Ok(())
+}
+
+/// Serves a request that was checked by [handle].
+#[allow(clippy::too_many_arguments)]
+fn respond(
+    model: &dyn llm::Model,
+    model_sha256: &str,
+    endpoints: &Endpoints,
+    monitor: &Monitor,
+    shutdown: &Shutdown,
+    request: Request,
+    cancellation_token: &llm::CancellationToken,
+    send: &mut dyn FnMut(Response) -> std::io::Result<()>,
+) -> eyre::Result<()> {
+    // Requests without a seed get a random one, which is echoed so that they can be repeated.
+    let mut generate = request.generate;
+    let seed = *generate.seed.get_or_insert_with(rand::random);
+    let parameters = match generate.inference_parameters(model) {
+        Ok(parameters) => parameters,
+        Err(err) => {
+            send(Response::Error(format!("{err:#}")))?;
+            return Ok(());
+        }
+    };
+    let mut session = match model.try_start_session(generate.inference_session_config()) {
+        Ok(session) => session,
+        Err(err) => {
+            send(Response::Error(format!("Could not start a session: {err}")))?;
+            return Ok(());
+        }
+    };
+    monitor.update(|state| {
+        state.session = Some(LiveSession {
+            endpoint: request.endpoint,
+            started: Instant::now(),
+            generation_started: None,
+            context_tokens: 0,
+            context_size: session.context_size(),
+            generated_tokens: 0,
+        })
+    });
+    let metadata = Box::new(Metadata {
+        seed,
+        model_sha256: model_sha256.to_string(),
+        generate: generate.clone(),
+    });
+
+    let chat_prompt;
+    let infill_prompt;
+    let prompt = match request.endpoint {
+        Endpoint::Completion => generate.prompt(&request.prompt),
+        Endpoint::Chat => {
+            let template = endpoints.template.expect("checked when the daemon started");
+            chat_prompt = template
+                .render(request.system.as_deref(), &request.messages)
+                .text();
+            llm::Prompt::Text(&chat_prompt)
+        }
+        Endpoint::Infill => {
+            let [prefix, suffix, middle] = endpoints
+                .infill_tokens
+                .expect("checked when the daemon started");
+            let tokenize = |text: &str| -> Result<Vec<llm::TokenId>, llm::TokenizationError> {
+                Ok(model
+                    .tokenizer()
+                    .tokenize(text, false)?
+                    .into_iter()
+                    .map(|(_, id)| id)
+                    .collect())
+            };
+            match (tokenize(&request.prompt), tokenize(&request.suffix)) {
+                (Ok(before), Ok(after)) => {
+                    infill_prompt = std::iter::once(prefix)
+                        .chain(before)
+                        .chain([suffix])
+                        .chain(after)
+                        .chain([middle])
+                        .collect::<Vec<_>>();
+                    llm::Prompt::Tokens(&infill_prompt)
+                }
+                (Err(err), _) | (_, Err(err)) => {
+                    send(Response::Error(format!(
+                        "A tokenization-related failure occurred: {err}"
+                    )))?;
+                    return Ok(());
+                }
+            }
+        }
+        Endpoint::Embeddings => {
+            let tokens = match generate
+                .prompt(&request.prompt)
+                .to_tokens(model.tokenizer(), true)
+            {
+                Ok(tokens) => tokens,
+                Err(err) => {
+                    send(Response::Error(format!(
+                        "A tokenization-related failure occurred: {err}"
+                    )))?;
+                    return Ok(());
+                }
+            };
+            let mut output_request = llm::OutputRequest {
+                all_logits: None,
+                embeddings: Some(vec![]),
+                logits_callback: None,
+            };
+            monitor.update(|state| {
+                if let Some(live) = &mut state.session {
+                    live.context_tokens = tokens.len();
+                }
+            });
+            model.evaluate(&mut session, &parameters, &tokens, &mut output_request);
+            send(Response::Embeddings(output_request.embeddings.unwrap()))?;
+            send(Response::Finished {
+                stats: String::new(),
+                metadata,
+            })?;
+            return Ok(());
+        }
+    };
+
+    let res = session.infer::<std::io::Error>(
+        model,
+        &mut generate.rng(),
+        &llm::InferenceRequest {
+            prompt,
+            parameters: &parameters,
+            play_back_previous_tokens: false,
+            maximum_token_count: generate.num_predict,
+            maximum_output_bytes: generate.max_output_bytes,
+            maximum_output_chars: generate.max_output_chars,
+            stop_token_sequences: &generate.stop_token_sequences,
+            guardrails: &[],
+            forced_prefix: generate.forced_prefix.as_deref(),
+            logprobs: None,
+            early_stop: generate.early_stop(),
+            cancellation_token: Some(cancellation_token),
+            deadline: generate.deadline(),
+        },
+        &mut Default::default(),
+        |r| {
+            match r {
+                llm::InferenceResponse::PromptToken(t) => {
+                    monitor.update(|state| {
+                        if let Some(live) = &mut state.session {
+                            live.context_tokens += 1;
+                        }
+                    });
+                    send(Response::PromptToken(t))?
+                }
+                llm::InferenceResponse::InferredToken(t) => {
+                    monitor.update(|state| {
+                        if let Some(live) = &mut state.session {
+                            live.generation_started.get_or_insert_with(Instant::now);
+                            live.context_tokens += 1;
+                            live.generated_tokens += 1;
+                        }
+                    });
+                    send(Response::InferredToken(t))?
+                }
+                _ => {}
+            }
+            Ok(llm::InferenceFeedback::Continue)
+        },
+    );
+
+    match res {
+        Ok(stats) => send(Response::Finished {
+            stats: stats.to_string(),
+            metadata,
+        })?,
+        Err(llm::InferenceError::ContextFull) => {
+            send(Response::Warning(
+                "Context window full, stopping inference.".to_string(),
+            ))?;
+            send(Response::Finished {
+                stats: String::new(),
+                metadata,
+            })?
+        }
+        Err(llm::InferenceError::TokenizationFailed(err)) => send(Response::Error(format!(
+            "A tokenization-related failure occurred: {err}"
+        )))?,
+        Err(err @ llm::InferenceError::NumericalError { .. }) => {
+            send(Response::Error(err.to_string()))?
+        }
+        // The client went away, so there is nobody to tell.
+        Err(llm::InferenceError::UserCallback(err)) => eyre::bail!(err),
+        // Not a complete response, so it is not cached.
+        Err(llm::InferenceError::Cancelled) if shutdown.is_started() => send(Response::Error(
+            "The daemon shut down before finishing the request".to_owned(),
+        ))?,
+        Err(llm::InferenceError::Cancelled) => {
+            log::info!("The client disconnected; cancelled the generation")
+        }
+        Err(llm::InferenceError::EndOfText) | Err(llm::InferenceError::RestoreFailed(_)) => {
+            unreachable!("cannot fail")
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns an error for the client if the daemon does not serve `request`, or if the
+/// request does not have what its endpoint needs.
+fn check_endpoint(request: &Request, endpoints: &Endpoints) -> Result<(), String> {
+    let served = &endpoints.served;
+    if !served.contains(&request.endpoint) {
+        let list = served
+            .iter()
+            .map(|endpoint| endpoint.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let hint = match request.endpoint {
+            Endpoint::Completion => {
+                "its model expects prompts in its chat format, and would continue a raw \
+                prompt poorly"
+            }
+            Endpoint::Chat => {
+                "its model is not declared to be a chat model. Send the conversation as a \
+                completion prompt, or restart the daemon with `--endpoints chat --template \
+                <template>` if the model was fine-tuned for chat"
+            }
+            Endpoint::Embeddings => "restart it with `--endpoints embeddings` to serve them",
+            Endpoint::Infill => {
+                "restart it with `--endpoints infill` if its model was trained to fill in \
+                the middle"
+            }
+        };
+        return Err(format!(
+            "This daemon serves {list} requests, not {} requests: {hint}",
+            request.endpoint
+        ));
+    }
+
+    match request.endpoint {
+        Endpoint::Chat if request.messages.is_empty() => {
+            Err("Chat requests need messages to reply to".to_owned())
+        }
+        Endpoint::Chat => Ok(()),
+        _ if !request.messages.is_empty() || request.system.is_some() => Err(format!(
+            "Requests to the {} endpoint take a prompt, not messages; send a chat request instead",
+            request.endpoint
+        )),
+        Endpoint::Infill => Ok(()),
+        _ if !request.suffix.is_empty() => Err(format!(
+            "Requests to the {} endpoint do not take a suffix; send an infill request instead",
+            request.endpoint
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the fill-in-the-middle tokens of the vocabulary: prefix, suffix and middle.
+fn infill_tokens(tokenizer: &llm::Tokenizer) -> Option<[llm::TokenId; 3]> {
+    [
+        ["<PRE>", "<SUF>", "<MID>"],
+        ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    ]
+    .into_iter()
+    .find_map(|names| {
+        let [prefix, suffix, middle] = names.map(|name| tokenizer.id(name.as_bytes()));
+        Some([prefix?, suffix?, middle?])
+    })
+}
+
+pub fn infer_remote(socket: &Path, args: &cli_args::Infer, prompt: String) -> eyre::Result<()> {
+    let mut stream = UnixStream::connect(socket)
+        .wrap_err_with(|| format!("Could not connect to the daemon at {socket:?}"))?;
+    let request = Request {
+        model_path: canonical(&args.model_load.model_and_tokenizer.model_path),
+        endpoint: Endpoint::Completion,
+        prompt,
+        suffix: String::new(),
+        system: None,
+        messages: vec![],
+        generate: args.generate.clone(),
+    };
+    serde_json::to_writer(&mut stream, &request)?;
+    stream.write_all(b"\n")?;
+
+    for line in BufReader::new(stream).lines() {
+        match serde_json::from_str(&line?)? {
+            Response::PromptToken(t) if args.show_prompt() => util::print_token(t),
+            Response::PromptToken(_) => {}
+            Response::InferredToken(t) => util::print_token(t),
+            Response::Embeddings(_) => eyre::bail!("The daemon sent embeddings for a completion"),
+            Response::Warning(warning) => log::warn!("{warning}"),
+            Response::Error(error) => {
+                if !args.stdin {
+                    println!();
+                }
+                eyre::bail!("The daemon could not complete the request: {error}");
+            }
+            Response::Finished { stats, metadata } if args.stdin => {
+                if args.stats {
+                    if !stats.is_empty() {
+                        eprintln!("{stats}");
+                    }
+                    eprintln!("{metadata}");
+                }
+                return Ok(());
+            }
+            Response::Finished { stats, metadata } => {
+                println!();
+                if args.stats {
+                    println!();
+                    if !stats.is_empty() {
+                        println!("{stats}");
+                    }
+                    println!("{metadata}");
+                    println!();
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    if !args.stdin {
+        println!();
+    }
+    eyre::bail!("The daemon closed the connection before finishing the request")
+}
+
+pub fn top(args: &cli_args::Top) -> eyre::Result<()> {
+    let mut connected = false;
+    loop {
+        match status(&args.socket) {
+            Ok(status) if args.once => {
+                print!("{status}");
+                return Ok(());
+            }
+            Ok(status) => {
+                connected = true;
+                // Clear the screen and move the cursor to its top-left corner.
+                print!("\x1b[2J\x1b[H{status}");
+            }
+            // The daemon may be restarting, so only the first connection has to succeed.
+            Err(err) if connected => {
+                println!(
+                    "\x1b[2J\x1b[HCould not reach the daemon at {:?}: {err:#}",
+                    args.socket
+                )
+            }
+            Err(err) => return Err(err),
+        }
+        std::io::stdout().flush()?;
+        std::thread::sleep(Duration::from_millis(args.interval));
+    }
+}
+
+fn status(socket: &Path) -> eyre::Result<Status> {
+    let mut stream = UnixStream::connect(socket)
+        .wrap_err_with(|| format!("Could not connect to the daemon at {socket:?}"))?;
+    serde_json::to_writer(&mut stream, &StatusRequest { status: true })?;
+    stream.write_all(b"\n")?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(&line).wrap_err("The daemon sent an invalid status")
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let size = |bytes: usize| bytesize::ByteSize(bytes as u64).to_string_as(true);
+        let uptime = self.uptime.as_secs();
+        let endpoints = self
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(f, "model: {:?} ({endpoints})", self.model_path)?;
+        writeln!(
+            f,
+            "up {}h {:02}m {:02}s, {} requests served, {} queued",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60,
+            self.served,
+            self.queued
+        )?;
+        writeln!(
+            f,
+            "memory: {} (weights {}, KV cache {}, scratch {})",
+            size(self.memory.total()),
+            size(self.memory.model_weights),
+            size(self.memory.kv_cache),
+            size(self.memory.scratch)
+        )?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<12} {:>20} {:>10} {:>9} {:>9}",
+            "ENDPOINT", "CONTEXT", "GENERATED", "TOKENS/S", "ELAPSED"
+        )?;
+        for session in &self.sessions {
+            let context = format!(
+                "{}/{} ({:.0}%)",
+                session.context_tokens,
+                session.context_size,
+                100.0 * session.context_tokens as f64 / session.context_size.max(1) as f64
+            );
+            writeln!(
+                f,
+                "{:<12} {context:>20} {:>10} {:>9.1} {:>8.1}s",
+                session.endpoint.to_string(),
+                session.generated_tokens,
+                session.tokens_per_second,
+                session.elapsed.as_secs_f64()
+            )?;
+        }
+        if self.sessions.is_empty() {
+            writeln!(f, "(idle)")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Metadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "seed: {}\nmodel_sha256: {}",
+            self.seed, self.model_sha256
+        )
+    }
+}
+
+/// Model paths are compared after resolving them, as the daemon and client may have been
+/// started from different directories.
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_cancels_the_request_after_the_grace_period() {
+        let shutdown = Arc::new(Shutdown::new(Duration::from_millis(50)));
+        let token = llm::CancellationToken::new();
+        shutdown.watch(Some(token.clone()));
+        assert!(shutdown.start());
+        assert!(shutdown.is_started());
+        assert!(!token.is_cancelled());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !token.is_cancelled() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(token.is_cancelled());
+
+        // Requests that start after the grace period are cancelled at once.
+        let late = llm::CancellationToken::new();
+        shutdown.watch(Some(late.clone()));
+        assert!(late.is_cancelled());
+    }
+
+    #[test]
+    fn a_second_signal_cancels_the_request_at_once() {
+        let shutdown = Arc::new(Shutdown::new(Duration::from_secs(3600)));
+        let token = llm::CancellationToken::new();
+        shutdown.watch(Some(token.clone()));
+        assert!(shutdown.start());
+        assert!(!token.is_cancelled());
+        assert!(!shutdown.start());
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn finished_requests_are_not_cancelled() {
+        let shutdown = Arc::new(Shutdown::new(Duration::ZERO));
+        let token = llm::CancellationToken::new();
+        shutdown.watch(Some(token.clone()));
+        shutdown.watch(None);
+        shutdown.start();
+        shutdown.expire();
+        assert!(!token.is_cancelled());
+    }
+}
@@ -0,0 +1,553 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::{self, Context, ContextCompat};
+
+use crate::cli_args::{Embed, EmbedFormat, Generate, Search};
+
+#[cfg(feature = "parquet")]
+mod parquet_file;
+
+pub fn embed(args: &Embed) -> eyre::Result<()> {
+    let input_format = EmbedFormat::resolve(args.input_format, &args.input)?;
+    let output_format = EmbedFormat::resolve(args.output_format, &args.output)?;
+    let mut reader = TextReader::open(input_format, &args.input, &args.text_field)?;
+    let mut writer = EmbeddingWriter::create(output_format, &args.output)?;
+
+    let model = args.model_load.load(args.generate.use_gpu)?;
+    let embedder = Embedder::new(model.as_ref(), &args.generate)?;
+
+    let rows_per_batch = args.rows_per_batch.max(1);
+    let mut index = 0;
+    loop {
+        let texts = reader.next_batch(rows_per_batch)?;
+        if texts.is_empty() {
+            break;
+        }
+        for text in texts {
+            let mut embedding = embedder
+                .embed(&text)
+                .wrap_err_with(|| format!("Could not embed row {index}"))?;
+            if args.normalize {
+                normalize(&mut embedding);
+            }
+            writer.write(index, &embedding)?;
+            index += 1;
+        }
+        writer.flush()?;
+        log::info!("Embedded {index} rows");
+    }
+    writer.finish()
+}
+
+pub fn search(args: &Search) -> eyre::Result<()> {
+    let index_format = EmbedFormat::resolve(args.index_format, &args.index)?;
+    let mut reader = EmbeddingReader::open(index_format, &args.index)?;
+
+    let model = args.model_load.load(args.generate.use_gpu)?;
+    let mut query = Embedder::new(model.as_ref(), &args.generate)?
+        .embed(&args.query)
+        .wrap_err("Could not embed the query")?;
+    normalize(&mut query);
+
+    // The best rows so far, best first.
+    let mut best: Vec<(f32, usize)> = Vec::with_capacity(args.k + 1);
+    while let Some((index, embedding)) = reader.next_embedding()? {
+        if embedding.len() != query.len() {
+            eyre::bail!(
+                "Row {index} has {} dimensions, but the model's embeddings have {}; was the \
+                 index made with another model?",
+                embedding.len(),
+                query.len()
+            );
+        }
+        let score = cosine_similarity(&query, &embedding);
+        let position = best.partition_point(|&(other, _)| other >= score);
+        if position < args.k {
+            best.insert(position, (score, index));
+            best.truncate(args.k);
+        }
+    }
+
+    let texts = match &args.texts {
+        Some(path) => {
+            let format = EmbedFormat::resolve(args.texts_format, path)?;
+            let mut wanted: Vec<usize> = best.iter().map(|&(_, index)| index).collect();
+            wanted.sort_unstable();
+            TextReader::open(format, path, &args.text_field)?.read_rows(&wanted)?
+        }
+        None => vec![],
+    };
+    for (rank, (score, index)) in best.into_iter().enumerate() {
+        print!("{}. row {index} ({score:.4})", rank + 1);
+        match texts.iter().find(|(row, _)| *row == index) {
+            Some((_, text)) => println!(": {text}"),
+            None => println!(),
+        }
+    }
+
+    Ok(())
+}
+
+/// Computes the embeddings of texts with a model.
+struct Embedder<'a> {
+    model: &'a dyn llm::Model,
+    generate: &'a Generate,
+    session_config: llm::InferenceSessionConfig,
+    parameters: llm::InferenceParameters,
+}
+impl<'a> Embedder<'a> {
+    fn new(model: &'a dyn llm::Model, generate: &'a Generate) -> eyre::Result<Self> {
+        Ok(Self {
+            model,
+            generate,
+            session_config: generate.inference_session_config(),
+            parameters: generate.inference_parameters(model)?,
+        })
+    }
+
+    fn embed(&self, text: &str) -> eyre::Result<Vec<f32>> {
+        let model = self.model;
+        let tokens = self
+            .generate
+            .prompt(text)
+            .to_tokens(model.tokenizer(), true)?;
+        if tokens.is_empty() {
+            eyre::bail!("The text has no tokens");
+        }
+        if tokens.len() > model.context_size() {
+            eyre::bail!(
+                "The text has {} tokens, more than the context size of {}",
+                tokens.len(),
+                model.context_size()
+            );
+        }
+
+        // Each text gets a session of its own, only as large as it needs.
+        let mut session = model.try_start_session(llm::InferenceSessionConfig {
+            context_size: Some(tokens.len()),
+            ..self.session_config.clone()
+        })?;
+        let mut output_request = llm::OutputRequest {
+            embeddings: Some(vec![]),
+            ..Default::default()
+        };
+        model.evaluate(&mut session, &self.parameters, &tokens, &mut output_request);
+        output_request
+            .embeddings
+            .wrap_err("The model did not return embeddings")
+    }
+}
+
+/// Scales `embedding` to a length of 1, unless it is all zeros.
+fn normalize(embedding: &mut [f32]) {
+    let length = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if length > 0.0 {
+        for x in embedding {
+            *x /= length;
+        }
+    }
+}
+
+/// The cosine similarity of `query`, which is normalized, and `embedding`.
+fn cosine_similarity(query: &[f32], embedding: &[f32]) -> f32 {
+    let length = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if length == 0.0 {
+        return 0.0;
+    }
+    query.iter().zip(embedding).map(|(a, b)| a * b).sum::<f32>() / length
+}
+
+/// Reads the texts of an input file, a batch at a time.
+struct TextReader {
+    source: TextSource,
+    text_field: String,
+}
+enum TextSource {
+    Jsonl(LineReader),
+    Csv {
+        reader: csv::Reader<File>,
+        record: csv::StringRecord,
+        /// The column of the texts.
+        column: usize,
+    },
+    #[cfg(feature = "parquet")]
+    Parquet(parquet_file::TextReader),
+}
+impl TextReader {
+    fn open(format: EmbedFormat, path: &Path, text_field: &str) -> eyre::Result<Self> {
+        let source = match format {
+            EmbedFormat::Jsonl => TextSource::Jsonl(LineReader::open(path)?),
+            EmbedFormat::Csv => {
+                let mut reader = open_csv(path)?;
+                let column = reader
+                    .headers()
+                    .wrap_err_with(|| format!("Could not read the header row of {path:?}"))?
+                    .iter()
+                    .position(|name| name == text_field)
+                    .wrap_err_with(|| format!("{path:?} has no `{text_field}` column"))?;
+                TextSource::Csv {
+                    reader,
+                    record: csv::StringRecord::new(),
+                    column,
+                }
+            }
+            #[cfg(feature = "parquet")]
+            EmbedFormat::Parquet => {
+                TextSource::Parquet(parquet_file::TextReader::open(path, text_field)?)
+            }
+        };
+        Ok(Self {
+            source,
+            text_field: text_field.to_owned(),
+        })
+    }
+
+    /// Reads up to `n` texts, returning fewer only at the end of the file.
+    fn next_batch(&mut self, n: usize) -> eyre::Result<Vec<String>> {
+        let mut texts = Vec::with_capacity(n);
+        while texts.len() < n {
+            match self.next_text()? {
+                Some(text) => texts.push(text),
+                None => break,
+            }
+        }
+        Ok(texts)
+    }
+
+    /// Reads the texts of the rows at `indices`, which are sorted, along with their index.
+    fn read_rows(&mut self, indices: &[usize]) -> eyre::Result<Vec<(usize, String)>> {
+        let mut texts = vec![];
+        let mut index = 0;
+        for &wanted in indices {
+            while let Some(text) = self.next_text()? {
+                index += 1;
+                if index - 1 == wanted {
+                    texts.push((wanted, text));
+                    break;
+                }
+            }
+        }
+        Ok(texts)
+    }
+
+    fn next_text(&mut self) -> eyre::Result<Option<String>> {
+        match &mut self.source {
+            TextSource::Jsonl(reader) => {
+                let Some(value) = reader.read_json()? else {
+                    return Ok(None);
+                };
+                let text = match &value {
+                    serde_json::Value::String(text) => Some(text),
+                    serde_json::Value::Object(object) => match object.get(&self.text_field) {
+                        Some(serde_json::Value::String(text)) => Some(text),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match text {
+                    Some(text) => Ok(Some(text.clone())),
+                    None => eyre::bail!(
+                        "Line {} is neither a string nor an object with a `{}` string",
+                        reader.line,
+                        self.text_field
+                    ),
+                }
+            }
+            TextSource::Csv {
+                reader,
+                record,
+                column,
+            } => {
+                if !reader.read_record(record)? {
+                    return Ok(None);
+                }
+                match record.get(*column) {
+                    Some(text) => Ok(Some(text.to_owned())),
+                    None => eyre::bail!(
+                        "Line {} has no `{}` column",
+                        csv_line(record),
+                        self.text_field
+                    ),
+                }
+            }
+            #[cfg(feature = "parquet")]
+            TextSource::Parquet(reader) => reader.next_text(),
+        }
+    }
+}
+
+/// Reads the embeddings written by [EmbeddingWriter], a row at a time.
+enum EmbeddingReader {
+    Jsonl(LineReader),
+    Csv {
+        reader: csv::Reader<File>,
+        record: csv::StringRecord,
+    },
+    #[cfg(feature = "parquet")]
+    Parquet(parquet_file::EmbeddingReader),
+}
+impl EmbeddingReader {
+    fn open(format: EmbedFormat, path: &Path) -> eyre::Result<Self> {
+        Ok(match format {
+            EmbedFormat::Jsonl => Self::Jsonl(LineReader::open(path)?),
+            EmbedFormat::Csv => Self::Csv {
+                reader: open_csv(path)?,
+                record: csv::StringRecord::new(),
+            },
+            #[cfg(feature = "parquet")]
+            EmbedFormat::Parquet => Self::Parquet(parquet_file::EmbeddingReader::open(path)?),
+        })
+    }
+
+    fn next_embedding(&mut self) -> eyre::Result<Option<(usize, Vec<f32>)>> {
+        let row = match self {
+            Self::Jsonl(reader) => {
+                #[derive(serde::Deserialize)]
+                struct Row {
+                    index: usize,
+                    embedding: Vec<f32>,
+                }
+                let line = reader.line + 1;
+                match reader.read_json()? {
+                    Some(value) => {
+                        let row: Row = serde_json::from_value(value).wrap_err_with(|| {
+                            format!("Line {line} is not an object with `index` and `embedding`")
+                        })?;
+                        Some((row.index, row.embedding))
+                    }
+                    None => None,
+                }
+            }
+            Self::Csv { reader, record } => {
+                if !reader.read_record(record)? {
+                    return Ok(None);
+                }
+                let mut fields = record.iter().map(|field| field.trim());
+                let index = fields.next().unwrap_or_default().parse().ok();
+                let embedding: Option<Vec<f32>> = fields.map(|field| field.parse().ok()).collect();
+                match index.zip(embedding) {
+                    Some(row) => Some(row),
+                    None => eyre::bail!(
+                        "Line {} is not an index followed by numbers",
+                        csv_line(record)
+                    ),
+                }
+            }
+            #[cfg(feature = "parquet")]
+            Self::Parquet(reader) => reader.next_embedding()?,
+        };
+        Ok(row)
+    }
+}
+
+/// Opens a CSV file with a header row. Quoted fields may span several lines.
+fn open_csv(path: &Path) -> eyre::Result<csv::Reader<File>> {
+    let file = File::open(path).wrap_err_with(|| format!("Could not open {path:?}"))?;
+    Ok(csv::Reader::from_reader(file))
+}
+
+/// The line a CSV record starts at, for error messages.
+fn csv_line(record: &csv::StringRecord) -> u64 {
+    record.position().map_or(0, csv::Position::line)
+}
+
+/// Reads the lines of a JSON Lines file, keeping count of them for error messages.
+struct LineReader {
+    reader: BufReader<File>,
+    line: usize,
+}
+impl LineReader {
+    fn open(path: &Path) -> eyre::Result<Self> {
+        let file = File::open(path).wrap_err_with(|| format!("Could not open {path:?}"))?;
+        Ok(Self {
+            reader: BufReader::new(file),
+            line: 0,
+        })
+    }
+
+    /// Reads the JSON value of the next non-empty line.
+    fn read_json(&mut self) -> eyre::Result<Option<serde_json::Value>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        serde_json::from_str(&line)
+            .wrap_err_with(|| format!("Line {} is not valid JSON", self.line))
+    }
+}
+
+/// Writes embeddings to an output file.
+///
+/// The CSV and Parquet writers are boxed, as they are much larger than the JSON Lines one.
+enum EmbeddingWriter {
+    Jsonl(BufWriter<File>),
+    Csv {
+        writer: Box<csv::Writer<File>>,
+        wrote_header: bool,
+    },
+    #[cfg(feature = "parquet")]
+    Parquet(Box<parquet_file::EmbeddingWriter>),
+}
+impl EmbeddingWriter {
+    fn create(format: EmbedFormat, path: &Path) -> eyre::Result<Self> {
+        let file = File::create(path).wrap_err_with(|| format!("Could not create {path:?}"))?;
+        Ok(match format {
+            EmbedFormat::Jsonl => Self::Jsonl(BufWriter::new(file)),
+            EmbedFormat::Csv => Self::Csv {
+                writer: Box::new(csv::Writer::from_writer(file)),
+                wrote_header: false,
+            },
+            #[cfg(feature = "parquet")]
+            EmbedFormat::Parquet => {
+                Self::Parquet(Box::new(parquet_file::EmbeddingWriter::new(file)))
+            }
+        })
+    }
+
+    fn write(&mut self, index: usize, embedding: &[f32]) -> eyre::Result<()> {
+        match self {
+            Self::Jsonl(writer) => {
+                #[derive(serde::Serialize)]
+                struct Row<'a> {
+                    index: usize,
+                    embedding: &'a [f32],
+                }
+                serde_json::to_writer(&mut *writer, &Row { index, embedding })?;
+                writeln!(writer)?;
+            }
+            Self::Csv {
+                writer,
+                wrote_header,
+            } => {
+                if !*wrote_header {
+                    let dimensions = (0..embedding.len()).map(|d| format!("embedding_{d}"));
+                    writer.write_record(std::iter::once("index".to_owned()).chain(dimensions))?;
+                    *wrote_header = true;
+                }
+                writer.write_field(index.to_string())?;
+                for x in embedding {
+                    writer.write_field(x.to_string())?;
+                }
+                writer.write_record(None::<&[u8]>)?;
+            }
+            #[cfg(feature = "parquet")]
+            Self::Parquet(writer) => writer.write(index, embedding)?,
+        }
+        Ok(())
+    }
+
+    /// Writes out the rows written so far.
+    fn flush(&mut self) -> eyre::Result<()> {
+        match self {
+            Self::Jsonl(writer) => writer.flush()?,
+            Self::Csv { writer, .. } => writer.flush()?,
+            #[cfg(feature = "parquet")]
+            Self::Parquet(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+
+    /// Finishes the file.
+    fn finish(mut self) -> eyre::Result<()> {
+        self.flush()?;
+        #[cfg(feature = "parquet")]
+        if let Self::Parquet(writer) = self {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A file in the temporary directory, removed when dropped.
+    struct TempFile(PathBuf);
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("llm-embed-{}-{name}", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn read_texts(format: EmbedFormat, file: &TempFile) -> eyre::Result<Vec<String>> {
+        TextReader::open(format, &file.0, "text")?.next_batch(16)
+    }
+
+    #[test]
+    fn csv_texts_can_span_lines() {
+        let file = TempFile::new(
+            "multiline.csv",
+            "id,text\n1,\"first\nline, with \"\"quotes\"\"\"\n\n2,second\n",
+        );
+        assert_eq!(
+            read_texts(EmbedFormat::Csv, &file).unwrap(),
+            ["first\nline, with \"quotes\"", "second"]
+        );
+
+        let file = TempFile::new("unknown-column.csv", "id,body\n1,text\n");
+        assert!(read_texts(EmbedFormat::Csv, &file).is_err());
+    }
+
+    #[test]
+    fn jsonl_texts_are_strings_or_fields() {
+        let file = TempFile::new(
+            "texts.jsonl",
+            "\"plain\"\n\n{\"text\": \"field\", \"id\": 1}\n",
+        );
+        assert_eq!(
+            read_texts(EmbedFormat::Jsonl, &file).unwrap(),
+            ["plain", "field"]
+        );
+
+        let file = TempFile::new("no-text.jsonl", "{\"body\": \"text\"}\n");
+        assert!(read_texts(EmbedFormat::Jsonl, &file).is_err());
+    }
+
+    #[test]
+    fn embeddings_round_trip() {
+        let rows = [(0, vec![0.5, -1.0, 2.25]), (1, vec![0.0, 1e-3, -7.5])];
+        let formats = [
+            EmbedFormat::Jsonl,
+            EmbedFormat::Csv,
+            #[cfg(feature = "parquet")]
+            EmbedFormat::Parquet,
+        ];
+        for format in formats {
+            let file = TempFile::new(&format!("embeddings-{format:?}"), "");
+            let mut writer = EmbeddingWriter::create(format, &file.0).unwrap();
+            for (index, embedding) in &rows {
+                writer.write(*index, embedding).unwrap();
+            }
+            writer.finish().unwrap();
+
+            let mut reader = EmbeddingReader::open(format, &file.0).unwrap();
+            let mut read = vec![];
+            while let Some(row) = reader.next_embedding().unwrap() {
+                read.push(row);
+            }
+            assert_eq!(read, rows, "{format:?}");
+        }
+    }
+}
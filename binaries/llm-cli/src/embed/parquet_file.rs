@@ -0,0 +1,210 @@
+//! Parquet files for `llm embed` and `llm search`.
+//!
+//! Embeddings are written as an `index` column of `u64`s and an `embedding` column of
+//! fixed-size lists of `f32`s, in row groups of [ROW_GROUP_SIZE] rows, so that neither
+//! writing nor reading holds the whole file in memory.
+use std::{fs::File, path::Path, sync::Arc};
+
+use arrow_array::{
+    cast::AsArray,
+    types::{Float32Type, UInt64Type},
+    Array, ArrayRef, FixedSizeListArray, Float32Array, RecordBatch, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema};
+use color_eyre::eyre::{self, Context, ContextCompat};
+use parquet::{
+    arrow::{
+        arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder},
+        ArrowWriter, ProjectionMask,
+    },
+    file::properties::WriterProperties,
+};
+
+/// The number of rows of each row group of the embeddings written.
+const ROW_GROUP_SIZE: usize = 8192;
+
+/// Reads the texts of a column of a Parquet file.
+pub struct TextReader {
+    batches: ParquetRecordBatchReader,
+    batch: Option<RecordBatch>,
+    /// The next row of `batch`.
+    row: usize,
+    text_field: String,
+}
+impl TextReader {
+    pub fn open(path: &Path, text_field: &str) -> eyre::Result<Self> {
+        let builder = open(path)?;
+        let column = builder
+            .schema()
+            .index_of(text_field)
+            .ok()
+            .wrap_err_with(|| format!("{path:?} has no `{text_field}` column"))?;
+        let projection = ProjectionMask::roots(builder.parquet_schema(), [column]);
+        Ok(Self {
+            batches: builder.with_projection(projection).build()?,
+            batch: None,
+            row: 0,
+            text_field: text_field.to_owned(),
+        })
+    }
+
+    pub fn next_text(&mut self) -> eyre::Result<Option<String>> {
+        let Some(batch) = next_row(&mut self.batches, &mut self.batch, &mut self.row)? else {
+            return Ok(None);
+        };
+        let column = batch.column(0);
+        let texts = column
+            .as_string_opt::<i32>()
+            .wrap_err_with(|| format!("The `{}` column is not a string column", self.text_field))?;
+        if texts.is_null(self.row) {
+            eyre::bail!("A row has no `{}`", self.text_field);
+        }
+        let text = texts.value(self.row).to_owned();
+        self.row += 1;
+        Ok(Some(text))
+    }
+}
+
+/// Reads the embeddings written by [EmbeddingWriter].
+pub struct EmbeddingReader {
+    batches: ParquetRecordBatchReader,
+    batch: Option<RecordBatch>,
+    /// The next row of `batch`.
+    row: usize,
+}
+impl EmbeddingReader {
+    pub fn open(path: &Path) -> eyre::Result<Self> {
+        Ok(Self {
+            batches: open(path)?.build()?,
+            batch: None,
+            row: 0,
+        })
+    }
+
+    pub fn next_embedding(&mut self) -> eyre::Result<Option<(usize, Vec<f32>)>> {
+        let Some(batch) = next_row(&mut self.batches, &mut self.batch, &mut self.row)? else {
+            return Ok(None);
+        };
+        let indices = batch
+            .column_by_name("index")
+            .and_then(|column| column.as_primitive_opt::<UInt64Type>())
+            .wrap_err("The file has no `index` column of unsigned integers")?;
+        let embeddings = batch
+            .column_by_name("embedding")
+            .and_then(|column| column.as_fixed_size_list_opt())
+            .wrap_err("The file has no `embedding` column of fixed-size lists")?;
+        let values = embeddings.value(self.row);
+        let values = values
+            .as_primitive_opt::<Float32Type>()
+            .wrap_err("The embeddings are not `f32`s")?;
+        let row = (indices.value(self.row) as usize, values.values().to_vec());
+        self.row += 1;
+        Ok(Some(row))
+    }
+}
+
+/// Writes embeddings to a Parquet file, a row group at a time.
+pub struct EmbeddingWriter {
+    file: Option<File>,
+    /// Created with the first rows, as the schema depends on the number of dimensions.
+    writer: Option<ArrowWriter<File>>,
+    indices: Vec<u64>,
+    values: Vec<f32>,
+    dimensions: usize,
+}
+impl EmbeddingWriter {
+    pub fn new(file: File) -> Self {
+        Self {
+            file: Some(file),
+            writer: None,
+            indices: vec![],
+            values: vec![],
+            dimensions: 0,
+        }
+    }
+
+    pub fn write(&mut self, index: usize, embedding: &[f32]) -> eyre::Result<()> {
+        if self.indices.is_empty() && self.writer.is_none() {
+            self.dimensions = embedding.len();
+        } else if embedding.len() != self.dimensions {
+            eyre::bail!(
+                "Row {index} has {} dimensions, but the previous rows have {}",
+                embedding.len(),
+                self.dimensions
+            );
+        }
+        self.indices.push(index as u64);
+        self.values.extend_from_slice(embedding);
+        Ok(())
+    }
+
+    /// Writes the buffered rows to the current row group.
+    pub fn flush(&mut self) -> eyre::Result<()> {
+        if self.indices.is_empty() {
+            return Ok(());
+        }
+        let item = Arc::new(Field::new("item", DataType::Float32, false));
+        let size = i32::try_from(self.dimensions).wrap_err("The embeddings are too large")?;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("index", DataType::UInt64, false),
+            Field::new(
+                "embedding",
+                DataType::FixedSizeList(item.clone(), size),
+                false,
+            ),
+        ]));
+        let indices: ArrayRef = Arc::new(UInt64Array::from(std::mem::take(&mut self.indices)));
+        let values = Arc::new(Float32Array::from(std::mem::take(&mut self.values)));
+        let embeddings: ArrayRef = Arc::new(FixedSizeListArray::try_new(item, size, values, None)?);
+        let batch = RecordBatch::try_new(schema.clone(), vec![indices, embeddings])?;
+
+        if self.writer.is_none() {
+            let properties = WriterProperties::builder()
+                .set_max_row_group_size(ROW_GROUP_SIZE)
+                .build();
+            let file = self.file.take().expect("the file is taken once");
+            self.writer = Some(ArrowWriter::try_new(file, schema, Some(properties))?);
+        }
+        self.writer.as_mut().expect("created above").write(&batch)?;
+        Ok(())
+    }
+
+    /// Writes the footer of the file, without which it cannot be read.
+    pub fn finish(mut self) -> eyre::Result<()> {
+        self.flush()?;
+        if let Some(writer) = self.writer {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> eyre::Result<ParquetRecordBatchReaderBuilder<File>> {
+    let file = File::open(path).wrap_err_with(|| format!("Could not open {path:?}"))?;
+    ParquetRecordBatchReaderBuilder::try_new(file)
+        .wrap_err_with(|| format!("{path:?} is not a Parquet file"))
+}
+
+/// Moves on to the next batch of `batches` once `row` is past the end of `batch`. Returns
+/// the batch that holds the next row, if any.
+fn next_row<'a>(
+    batches: &mut ParquetRecordBatchReader,
+    batch: &'a mut Option<RecordBatch>,
+    row: &mut usize,
+) -> eyre::Result<Option<&'a RecordBatch>> {
+    // `Option::is_none_or` is newer than the Rust version of the release builds.
+    #[allow(clippy::unnecessary_map_or)]
+    while batch
+        .as_ref()
+        .map_or(true, |batch| *row >= batch.num_rows())
+    {
+        match batches.next() {
+            Some(next) => {
+                *batch = Some(next?);
+                *row = 0;
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(batch.as_ref())
+}
@@ -8,6 +8,8 @@ use rustyline::{
     Cmd, Completer, Helper, Highlighter, Hinter, KeyCode, KeyEvent, Modifiers,
 };
 
+use llm::template::{Conversation, PromptTemplate, Role};
+
 use crate::{
     cli_args::{Chat, Repl},
     snapshot, util,
@@ -26,7 +28,7 @@ pub fn repl(
     let template = prompt_file.contents()?;
 
     let model = model.as_ref();
-    let mut session = create_session(model, inference_session_config);
+    let mut session = create_session(model, &inference_session_config);
     readline_loop(|raw_line| {
         let line = raw_line.replace("\\\n", "\n");
 
@@ -44,6 +46,15 @@ pub fn repl(
                 parameters: &parameters,
                 play_back_previous_tokens: false,
                 maximum_token_count: generate.num_predict,
+                maximum_output_bytes: generate.max_output_bytes,
+                maximum_output_chars: generate.max_output_chars,
+                stop_token_sequences: &generate.stop_token_sequences,
+                guardrails: &[],
+                forced_prefix: generate.forced_prefix.as_deref(),
+                logprobs: None,
+                early_stop: generate.early_stop(),
+                cancellation_token: None,
+                deadline: generate.deadline(),
             },
             &mut Default::default(),
             |r| {
@@ -57,7 +68,7 @@ pub fn repl(
         if !session_ends_with_newline(&session) {
             println!();
         }
-        session = create_session(model, inference_session_config);
+        session = create_session(model, &inference_session_config);
 
         Ok(())
     })
@@ -76,14 +87,20 @@ pub fn chat(args: &Chat) -> eyre::Result<()> {
 
     let prelude_prompt = std::fs::read_to_string(prelude_prompt_file)?;
     let message_prompt_prefix = args.message_prompt_prefix()?;
+    let export_format = args.export_format();
+    // The template is not used: the prompts are formatted by the prelude and the prefix.
+    let mut conversation = Conversation::new(
+        PromptTemplate::ChatMl,
+        Some(prelude_prompt.trim().to_owned()).filter(|prelude| !prelude.is_empty()),
+    );
 
     let model = model.as_ref();
-    let mut session = create_session(model, inference_session_config);
+    let mut session = create_session(model, &inference_session_config);
     feed_prompt_with_spinner(model, &mut session, &parameters, prelude_prompt)?;
 
     readline_loop(|raw_line| {
+        let line = raw_line.replace("\\\n", "\n");
         let prompt = {
-            let line = raw_line.replace("\\\n", "\n");
             let mut prompt = format!("{message_prompt_prefix}{line}");
             // Add a newline to the end of the prompt if it doesn't end with one
             if !prompt.ends_with('\n') {
@@ -92,6 +109,7 @@ pub fn chat(args: &Chat) -> eyre::Result<()> {
             prompt
         };
 
+        let mut reply = String::new();
         session.infer::<Infallible>(
             model,
             &mut rng,
@@ -100,15 +118,33 @@ pub fn chat(args: &Chat) -> eyre::Result<()> {
                 parameters: &parameters,
                 play_back_previous_tokens: false,
                 maximum_token_count: generate.num_predict,
+                maximum_output_bytes: generate.max_output_bytes,
+                maximum_output_chars: generate.max_output_chars,
+                stop_token_sequences: &generate.stop_token_sequences,
+                guardrails: &[],
+                forced_prefix: generate.forced_prefix.as_deref(),
+                logprobs: None,
+                early_stop: generate.early_stop(),
+                cancellation_token: None,
+                deadline: generate.deadline(),
             },
             &mut Default::default(),
-            llm::conversation_inference_callback(&message_prompt_prefix, util::print_token),
+            llm::conversation_inference_callback(&message_prompt_prefix, |token| {
+                reply.push_str(&token);
+                util::print_token(token);
+            }),
         )?;
 
         if !session_ends_with_newline(&session) {
             println!();
         }
 
+        if let Some(path) = &args.export {
+            conversation.push(Role::User, line);
+            conversation.push(Role::Assistant, reply.trim());
+            std::fs::write(path, conversation.export(export_format))?;
+        }
+
         Ok(())
     })
 }
@@ -120,12 +156,12 @@ fn initialize_common_state(
     llm::InferenceSessionConfig,
     llm::InferenceParameters,
     Box<dyn llm::Model>,
-    rand::rngs::StdRng,
+    rand_chacha::ChaCha12Rng,
 )> {
     let model = model_load.load(generate.use_gpu)?;
     Ok((
         generate.inference_session_config(),
-        generate.inference_parameters(model.eot_token_id()),
+        generate.inference_parameters(model.as_ref())?,
         model,
         generate.rng(),
     ))
@@ -158,9 +194,9 @@ fn feed_prompt_with_spinner(
 
 fn create_session(
     model: &dyn llm::Model,
-    inference_session_config: llm::InferenceSessionConfig,
+    inference_session_config: &llm::InferenceSessionConfig,
 ) -> llm::InferenceSession {
-    snapshot::read_or_create_session(model, None, None, inference_session_config).0
+    snapshot::read_or_create_session(model, None, None, inference_session_config.clone()).0
 }
 
 fn session_ends_with_newline(session: &llm::InferenceSession) -> bool {
@@ -1,14 +1,19 @@
 use std::{
     convert::Infallible,
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{BufReader, BufWriter, Write},
 };
 
 use clap::Parser;
 use cli_args::Args;
 use color_eyre::eyre::{self, Context, ContextCompat};
+use rand_chacha::ChaCha12Rng;
 
+mod checkpoint;
 mod cli_args;
+#[cfg(unix)]
+mod daemon;
+mod embed;
 mod interactive;
 mod snapshot;
 mod util;
@@ -24,53 +29,130 @@ fn main() -> eyre::Result<()> {
     match args {
         Args::Infer(args) => infer(&args),
         Args::Perplexity(args) => perplexity(&args),
+        Args::Embed(args) => embed::embed(&args),
+        Args::Search(args) => embed::search(&args),
+        Args::Resume(args) => resume(&args),
         Args::Info(args) => info(&args),
+        Args::Doctor(args) => doctor(&args),
         Args::PromptTokens(args) => prompt_tokens(&args),
         Args::Repl(args) => interactive::repl(&args),
         Args::Chat(args) => interactive::chat(&args),
         Args::Quantize(args) => quantize(&args),
+        Args::Convert(args) => convert(&args),
+        Args::Migrate(args) => migrate(&args),
+        Args::Template(cli_args::Template::Check(args)) => template_check(&args),
+        Args::Vocab(cli_args::Vocab::Stats(args)) => vocab_stats(&args),
+        #[cfg(unix)]
+        Args::Daemon(args) => daemon::serve(&args),
+        #[cfg(unix)]
+        Args::Top(args) => daemon::top(&args),
     }
 }
 
 fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
-    let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?;
+    let stdin_prompt = if args.stdin {
+        let prompt = std::io::read_to_string(std::io::stdin())
+            .wrap_err("Could not read the prompt from stdin")?;
+        if prompt.is_empty() {
+            eyre::bail!("No prompt was provided on stdin");
+        }
+        Some(prompt)
+    } else {
+        None
+    };
+    let prompt = load_prompt_file_with_prompt(
+        &args.prompt_file,
+        stdin_prompt.as_deref().or(args.prompt.as_deref()),
+    )?;
+    let guardrails = args.guardrails()?;
+    #[cfg(unix)]
+    if let Some(socket) = &args.remote {
+        if !guardrails.is_empty() {
+            eyre::bail!("Filters of the generated text are not supported with --remote");
+        }
+        #[cfg(feature = "sampler-plugins")]
+        if args.generate.sampler_plugin.is_some() {
+            eyre::bail!("Sampler plugins are not supported with --remote");
+        }
+        return daemon::infer_remote(socket, args, prompt);
+    }
+    let guardrails: Vec<_> = guardrails.iter().map(|g| g.as_ref()).collect();
     let inference_session_config = args.generate.inference_session_config();
     let model = args.model_load.load(args.generate.use_gpu)?;
 
-    let (mut session, session_loaded) = snapshot::read_or_create_session(
+    let (mut session, session_loaded, saved_rng) = snapshot::read_or_create_session(
         model.as_ref(),
         args.persist_session.as_deref(),
         args.load_session.as_deref(),
         inference_session_config,
     );
-    let parameters = args.generate.inference_parameters(model.eot_token_id());
+    let parameters = args.generate.inference_parameters(model.as_ref())?;
 
-    let mut rng = args.generate.rng();
+    // Continuing with the saved generator samples the same tokens as an uninterrupted run.
+    let mut rng = saved_rng.unwrap_or_else(|| args.generate.rng());
+    if args.checkpoint_dir.is_some() {
+        let command_line: Vec<String> = std::env::args().collect();
+        let res = infer_with_checkpoints(
+            args,
+            &command_line,
+            model.as_ref(),
+            &mut session,
+            &mut rng,
+            Some((&prompt, session_loaded)),
+            Default::default(),
+        )?;
+        return finish_inference(args, res, session, &rng);
+    }
     let res = session.infer::<Infallible>(
         model.as_ref(),
         &mut rng,
         &llm::InferenceRequest {
-            prompt: prompt.as_str().into(),
+            prompt: args.generate.prompt(&prompt),
             parameters: &parameters,
             play_back_previous_tokens: session_loaded,
             maximum_token_count: args.generate.num_predict,
+            maximum_output_bytes: args.generate.max_output_bytes,
+            maximum_output_chars: args.generate.max_output_chars,
+            stop_token_sequences: &args.generate.stop_token_sequences,
+            guardrails: &guardrails,
+            forced_prefix: args.generate.forced_prefix.as_deref(),
+            logprobs: None,
+            early_stop: args.generate.early_stop(),
+            cancellation_token: None,
+            deadline: args.generate.deadline(),
         },
         // OutputRequest
         &mut Default::default(),
         |r| {
             match r {
-                llm::InferenceResponse::PromptToken(t) if !args.hide_prompt => util::print_token(t),
+                llm::InferenceResponse::PromptToken(t) if args.show_prompt() => {
+                    util::print_token(t)
+                }
                 llm::InferenceResponse::InferredToken(t) => util::print_token(t),
                 _ => {}
             }
             Ok(llm::InferenceFeedback::Continue)
         },
     );
-    println!();
+    finish_inference(args, res, session, &rng)
+}
+
+/// Reports the result of `llm infer` and saves its session, if requested.
+fn finish_inference(
+    args: &cli_args::Infer,
+    res: Result<llm::InferenceStats, llm::InferenceError>,
+    session: llm::InferenceSession,
+    rng: &ChaCha12Rng,
+) -> eyre::Result<()> {
+    if !args.stdin {
+        println!();
+    }
 
     match res {
         Ok(stats) => {
-            if args.stats {
+            if args.stats && args.stdin {
+                eprintln!("{}", stats);
+            } else if args.stats {
                 println!();
                 println!("{}", stats);
                 println!();
@@ -79,29 +161,192 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
         Err(llm::InferenceError::ContextFull) => {
             log::warn!("Context window full, stopping inference.")
         }
+        Err(llm::InferenceError::TokenizationFailed(err)) if args.stdin => {
+            eyre::bail!("A tokenization-related failure occurred: {}", err);
+        }
         Err(llm::InferenceError::TokenizationFailed(err)) => {
             log::error!("A tokenization-related failure occurred: {}", err);
         }
-        Err(llm::InferenceError::UserCallback(_)) | Err(llm::InferenceError::EndOfText) => {
-            unreachable!("cannot fail")
+        Err(err @ llm::InferenceError::NumericalError { .. }) => {
+            eyre::bail!("{err}; the model or its quantization may be broken");
         }
+        Err(llm::InferenceError::UserCallback(_))
+        | Err(llm::InferenceError::EndOfText)
+        | Err(llm::InferenceError::RestoreFailed(_))
+        | Err(llm::InferenceError::Cancelled) => unreachable!("cannot fail"),
     }
 
     if let Some(session_path) = args.save_session.as_ref().or(args.persist_session.as_ref()) {
         // Write the memory to the cache file
-        snapshot::write_session(session, session_path);
+        snapshot::write_session(session, rng, session_path);
     }
 
     Ok(())
 }
 
+/// Generates in chunks of `--checkpoint-every` tokens, writing a checkpoint to
+/// `--checkpoint-dir` after each. `prompt` is the prompt and whether the session was loaded,
+/// or `None` when resuming from a checkpoint, whose prompt is already in the session.
+fn infer_with_checkpoints(
+    args: &cli_args::Infer,
+    command_line: &[String],
+    model: &dyn llm::Model,
+    session: &mut llm::InferenceSession,
+    rng: &mut ChaCha12Rng,
+    mut prompt: Option<(&str, bool)>,
+    mut progress: checkpoint::Progress,
+) -> eyre::Result<Result<llm::InferenceStats, llm::InferenceError>> {
+    let dir = args
+        .checkpoint_dir
+        .as_deref()
+        .wrap_err("a checkpoint directory is required")?;
+    let parameters = args.generate.inference_parameters(model)?;
+    let generate = &args.generate;
+    let guardrails = args.guardrails()?;
+    let guardrails: Vec<_> = guardrails.iter().map(|g| g.as_ref()).collect();
+
+    let mut stats = llm::InferenceStats::default();
+    let deadline = generate.deadline();
+    loop {
+        let remaining = generate
+            .num_predict
+            .map(|n| n.saturating_sub(progress.generated_tokens));
+        let chunk = remaining.map_or(args.checkpoint_every as usize, |remaining| {
+            remaining.min(args.checkpoint_every as usize)
+        });
+        let (text, play_back) = prompt.take().unwrap_or(("", false));
+        let res = session.infer::<Infallible>(
+            model,
+            rng,
+            &llm::InferenceRequest {
+                prompt: generate.prompt(text),
+                parameters: &parameters,
+                play_back_previous_tokens: play_back,
+                maximum_token_count: Some(chunk),
+                maximum_output_bytes: generate
+                    .max_output_bytes
+                    .map(|n| n.saturating_sub(progress.text.len())),
+                maximum_output_chars: generate
+                    .max_output_chars
+                    .map(|n| n.saturating_sub(progress.text.chars().count())),
+                stop_token_sequences: &generate.stop_token_sequences,
+                guardrails: &guardrails,
+                // The prefix is part of the text of the first checkpoint.
+                forced_prefix: if progress.generated_tokens == 0 && !text.is_empty() {
+                    generate.forced_prefix.as_deref()
+                } else {
+                    None
+                },
+                logprobs: None,
+                early_stop: generate.early_stop(),
+                cancellation_token: None,
+                deadline,
+            },
+            &mut Default::default(),
+            |r| {
+                match r {
+                    llm::InferenceResponse::PromptToken(t) if args.show_prompt() => {
+                        util::print_token(t)
+                    }
+                    llm::InferenceResponse::InferredToken(t) => {
+                        progress.text.push_str(&t);
+                        util::print_token(t)
+                    }
+                    _ => {}
+                }
+                Ok(llm::InferenceFeedback::Continue)
+            },
+        );
+
+        let chunk_stats = match res {
+            Ok(chunk_stats) => chunk_stats,
+            Err(err) => {
+                // The generation cannot continue from a full context, so it is finished.
+                if matches!(err, llm::InferenceError::ContextFull) {
+                    checkpoint::write(dir, command_line, &progress, true, session, rng)?;
+                }
+                return Ok(Err(err));
+            }
+        };
+        progress.generated_tokens += chunk_stats.predict_tokens;
+        if stats.prompt_tokens == 0 {
+            stats.prompt_tokens = chunk_stats.prompt_tokens;
+        }
+        stats.feed_prompt_duration += chunk_stats.feed_prompt_duration;
+        stats.predict_duration += chunk_stats.predict_duration;
+        stats.predict_tokens += chunk_stats.predict_tokens;
+        stats.resource_usage = add_resource_usage(stats.resource_usage, chunk_stats.resource_usage);
+        stats.stop_reason = chunk_stats.stop_reason;
+
+        // `Option::is_some_and` is newer than the Rust version of the release builds.
+        #[allow(clippy::unnecessary_map_or)]
+        let finished = chunk_stats.stop_reason != llm::StopReason::MaximumTokens
+            || remaining.map_or(false, |remaining| remaining <= chunk);
+        checkpoint::write(dir, command_line, &progress, finished, session, rng)?;
+        if finished {
+            return Ok(Ok(stats));
+        }
+    }
+}
+
+/// Sums the resources used by two chunks of a generation.
+fn add_resource_usage(a: llm::ResourceUsage, b: llm::ResourceUsage) -> llm::ResourceUsage {
+    fn add<T: std::ops::Add<Output = T>>(a: Option<T>, b: Option<T>) -> Option<T> {
+        Some(a? + b?)
+    }
+    llm::ResourceUsage {
+        peak_rss_bytes: b.peak_rss_bytes.max(a.peak_rss_bytes),
+        user_cpu_time: add(a.user_cpu_time, b.user_cpu_time),
+        system_cpu_time: add(a.system_cpu_time, b.system_cpu_time),
+        energy_joules: add(a.energy_joules, b.energy_joules),
+    }
+}
+
+fn resume(args: &cli_args::Resume) -> eyre::Result<()> {
+    let checkpoint = checkpoint::Checkpoint::read(&args.dir)?;
+    let command_line = checkpoint.args.clone();
+    let Args::Infer(mut infer_args) = Args::try_parse_from(&command_line)
+        .wrap_err_with(|| format!("Could not parse the command line of {:?}", args.dir))?
+    else {
+        eyre::bail!("{:?} is not a checkpoint of `llm infer`", args.dir);
+    };
+    infer_args.checkpoint_dir = Some(args.dir.clone());
+
+    if checkpoint.finished {
+        log::info!("The generation in {:?} is already finished", args.dir);
+        println!("{}", checkpoint.text());
+        return Ok(());
+    }
+
+    let model = infer_args.model_load.load(infer_args.generate.use_gpu)?;
+    let (mut session, rng, progress) = checkpoint.restore(model.as_ref())?;
+    let mut rng = rng.unwrap_or_else(|| infer_args.generate.rng());
+    log::info!(
+        "Resuming after {} generated tokens from {:?}",
+        progress.generated_tokens,
+        args.dir
+    );
+    util::print_token(progress.text.clone());
+
+    let res = infer_with_checkpoints(
+        &infer_args,
+        &command_line,
+        model.as_ref(),
+        &mut session,
+        &mut rng,
+        None,
+        progress,
+    )?;
+    finish_inference(&infer_args, res, session, &rng)
+}
+
 fn perplexity(args: &cli_args::Perplexity) -> eyre::Result<()> {
     let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?;
     let inference_session_config = args.generate.inference_session_config();
     let model = args.model_load.load(args.generate.use_gpu)?;
-    let (mut session, _) =
+    let (mut session, _, _) =
         snapshot::read_or_create_session(model.as_ref(), None, None, inference_session_config);
-    let parameters = args.generate.inference_parameters(model.eot_token_id());
+    let parameters = args.generate.inference_parameters(model.as_ref())?;
 
     session.perplexity(
         model.as_ref(),
@@ -136,6 +381,15 @@ fn info(args: &cli_args::Info) -> eyre::Result<()> {
             log::info!("Container type: {:?}", loader.container_type);
             log::info!("Hyperparameters: {:?}", loader.hyperparameters);
             log::info!("Tokenizer vocabulary size: {}", loader.tokenizer.len());
+            let tied = M::embedding_tensors().are_tied(|name| loader.tensors.contains_key(name));
+            log::info!(
+                "Output weights: {}",
+                if tied {
+                    "tied to the token embeddings"
+                } else {
+                    "separate from the token embeddings"
+                }
+            );
 
             if args.tokenizer {
                 log::info!("Tokens:");
@@ -151,6 +405,37 @@ fn info(args: &cli_args::Info) -> eyre::Result<()> {
                 }
             }
 
+            if args.placement {
+                let model = llm::load::<M>(
+                    model_path,
+                    args.model_and_tokenizer.to_source()?,
+                    Default::default(),
+                    |_| {},
+                )?;
+                let report = model.placement_report();
+                log::info!("Placement:");
+                for layer in &report.layers {
+                    log::info!(
+                        "- {:>6} {:<4} {:>10} {}",
+                        layer
+                            .layer
+                            .map_or_else(|| "-".to_string(), |l| l.to_string()),
+                        layer.device,
+                        bytesize::to_string(layer.bytes as u64, false),
+                        layer
+                            .element_types
+                            .iter()
+                            .map(|(t, _)| t.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+                log::info!(
+                    "Total: {}",
+                    bytesize::to_string(report.bytes() as u64, false)
+                );
+            }
+
             fn utf8_or_array(token: &[u8]) -> String {
                 std::str::from_utf8(token)
                     .map(|s| s.to_owned())
@@ -168,6 +453,54 @@ fn info(args: &cli_args::Info) -> eyre::Result<()> {
         .visit(&mut InfoVisitor(args))
 }
 
+fn doctor(args: &cli_args::Doctor) -> eyre::Result<()> {
+    let report = llm::check_compatibility(&args.model_path)?;
+
+    println!("Model: {}", args.model_path.display());
+    if let Some(container_type) = report.container_type {
+        println!("Container: {container_type:?}");
+    }
+    if let Some(quantization_version) = report.quantization_version {
+        println!("Quantization version: {quantization_version}");
+    }
+    match report.architecture {
+        Some(architecture) => println!("Architecture: {architecture}"),
+        None => println!("Architecture: not recognized"),
+    }
+    if let Some(file_type) = report.file_type {
+        println!("File type: {}", file_type.format);
+    }
+    if report.container_type.is_some() {
+        let element_types = report
+            .element_types
+            .iter()
+            .map(|(element_type, count)| format!("{element_type}: {count}"))
+            .collect::<Vec<_>>();
+        println!(
+            "Tensors: {} ({})",
+            report.tensor_count,
+            element_types.join(", ")
+        );
+    }
+    println!();
+
+    if !report.is_compatible() {
+        println!("This build of llm cannot load the model:");
+        for issue in &report.issues {
+            println!("- {issue}");
+        }
+        std::process::exit(1);
+    }
+    println!("This build of llm can load the model.");
+    if !report.notes.is_empty() {
+        println!("However:");
+        for note in &report.notes {
+            println!("- {note}");
+        }
+    }
+    Ok(())
+}
+
 fn prompt_tokens(args: &cli_args::PromptTokens) -> eyre::Result<()> {
     let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?;
     let model = args.model_load.load(false)?;
@@ -197,15 +530,176 @@ fn prompt_tokens(args: &cli_args::PromptTokens) -> eyre::Result<()> {
     Ok(())
 }
 
-fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
-    use llm::QuantizeProgress;
+fn template_check(args: &cli_args::TemplateCheck) -> eyre::Result<()> {
+    let messages: Vec<llm::template::Message> = match &args.messages {
+        Some(path) => {
+            let file = File::open(path)
+                .wrap_err_with(|| format!("Could not open messages file {path:?}"))?;
+            serde_json::from_reader(BufReader::new(file))
+                .wrap_err_with(|| format!("Could not parse messages file {path:?}"))?
+        }
+        None => vec![],
+    };
+    let prompt = args.template.render(args.system.as_deref(), &messages);
+
+    let model = args.model_load.load(false)?;
+    let tokenizer = model.tokenizer();
+    let context_size = model.context_size();
+
+    println!("{}", prompt.text());
+    println!();
+
+    // Sections are tokenized separately, so their counts may not add up exactly to the
+    // total if tokens merge across section boundaries.
+    let mut rows = vec![];
+    for (i, section) in prompt.sections.iter().enumerate() {
+        let tokens = tokenizer.tokenize(&section.text, i == 0)?.len();
+        rows.push((section.label.as_str(), tokens));
+    }
+    let total = tokenizer.tokenize(&prompt.text(), true)?.len();
+    rows.push(("total", total));
+    rows.push(("context size", context_size));
+
+    let width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    for (label, tokens) in rows {
+        println!("{label:<width$}  {tokens:>7}");
+    }
+    println!();
+
+    let required = total + args.reserve;
+    if required > context_size {
+        log::warn!(
+            "The prompt needs {required} tokens ({total} for the prompt and {} reserved for the reply), \
+             which exceeds the context size of {context_size} tokens by {}",
+            args.reserve,
+            required - context_size
+        );
+    } else {
+        log::info!(
+            "{} tokens of the context remain for the reply",
+            context_size - total
+        );
+    }
+
+    Ok(())
+}
+
+fn vocab_stats(args: &cli_args::VocabStats) -> eyre::Result<()> {
+    struct VocabStatsVisitor<'a>(&'a cli_args::VocabStats);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for VocabStatsVisitor<'_> {
+        fn visit<M: llm::KnownModel + 'static>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let model_path = &args.model_and_tokenizer.model_path;
+            let tokenizer = args.model_and_tokenizer.to_source()?.retrieve(model_path)?;
+            let mut reader = BufReader::new(File::open(model_path)?);
+            let mut loader: llm::Loader<M::Hyperparameters, _> =
+                llm::Loader::new(tokenizer, |_| {});
+            llm::ggml_format::load(&mut reader, &mut loader)?;
+
+            let stats = llm::vocab::VocabStats::new(&loader.tokenizer, args.top);
+            let top = args.top;
+            let show = |token: &[u8]| format!("{:?}", String::from_utf8_lossy(token));
+
+            println!("Vocabulary size: {}", stats.size);
+            let n_vocab = llm::Hyperparameters::n_vocabulary(&loader.hyperparameters);
+            if n_vocab != stats.size {
+                println!("  The model's hyperparameters specify {n_vocab} tokens");
+            }
+
+            println!(
+                "Byte coverage: {}/256 bytes have a token",
+                256 - stats.missing_bytes.len()
+            );
+            if !stats.missing_bytes.is_empty() {
+                let missing: Vec<_> = stats
+                    .missing_bytes
+                    .iter()
+                    .take(top)
+                    .map(|b| format!("0x{b:02X}"))
+                    .collect();
+                println!("  Missing: {}", missing.join(", "));
+            }
+
+            println!("Longest tokens:");
+            for (id, token) in &stats.longest {
+                println!("  {id:>7}  {:>3} bytes  {}", token.len(), show(token));
+            }
+
+            println!("Duplicated tokens: {}", stats.duplicates.len());
+            for (token, ids) in stats.duplicates.iter().take(top) {
+                println!("  {}: {ids:?}", show(token));
+            }
+            if !stats.empty.is_empty() {
+                println!("Empty tokens: {}", stats.empty.len());
+            }
 
+            let embeddings_name = M::embedding_tensors().input;
+            match loader.tensors.get(embeddings_name) {
+                Some(tensor) => match tensor.read_f32(&mut reader)? {
+                    Some(embeddings) => {
+                        let n_embd = tensor.dims()[0];
+                        let rows = embeddings.len() / n_embd;
+                        println!("Embedding rows: {rows}");
+                        if rows != stats.size {
+                            println!(
+                                "  The tokenizer has {} tokens; the model and tokenizer may not match",
+                                stats.size
+                            );
+                        }
+                        let unused = llm::vocab::unused_embedding_rows(
+                            &embeddings,
+                            n_embd,
+                            args.unused_norm,
+                        );
+                        println!("Unused embedding rows: {}", unused.len());
+                        for id in unused.iter().take(top) {
+                            let token = loader.tokenizer.token(*id as usize);
+                            println!("  {id:>7}  {}", show(&token));
+                        }
+                    }
+                    None => println!(
+                        "Embedding rows: cannot read `{embeddings_name}` of type {}",
+                        tensor.element_type
+                    ),
+                },
+                None => println!("Embedding rows: the model has no `{embeddings_name}` tensor"),
+            }
+
+            println!("Scripts:");
+            for (script, count) in &stats.scripts {
+                println!(
+                    "  {:<14} {count:>7} ({:.1}%)",
+                    script.to_string(),
+                    *count as f64 / stats.size as f64 * 100.0
+                );
+            }
+
+            Ok(())
+        }
+    }
+
+    args.model_and_tokenizer
+        .architecture
+        .model_architecture
+        .wrap_err("a model architecture is required at present")?
+        .visit(&mut VocabStatsVisitor(args))
+}
+
+fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
     struct QuantizeVisitor<'a>(&'a cli_args::Quantize);
     impl llm::ModelArchitectureVisitor<eyre::Result<()>> for QuantizeVisitor<'_> {
         fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
             let args = self.0;
 
             let mut source: BufReader<File> = BufReader::new(std::fs::File::open(&args.source)?);
+            if args.dry_run {
+                let report = llm::quantize_dry_run::<M, _>(&mut source, args.target.into())
+                    .wrap_err("failed to plan the quantization")?;
+                print_quantize_plan(&report);
+                return Ok(());
+            }
+
             let mut destination: BufWriter<File> =
                 BufWriter::new(std::fs::File::create(&args.destination)?);
             let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&args.source)?;
@@ -216,38 +710,10 @@ fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
                 tokenizer,
                 args.container_type.into(),
                 args.target.into(),
-                |progress| match progress {
-                    QuantizeProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
-                    QuantizeProgress::TensorLoading {
-                        name,
-                        dims,
-                        element_type,
-                        n_elements,
-                    } => log::info!(
-                        "Loading tensor `{name}` ({n_elements} ({dims:?}) {element_type} elements)"
-                    ),
-                    QuantizeProgress::TensorQuantizing { name } => log::info!("Quantizing tensor `{name}`"),
-                    QuantizeProgress::TensorQuantized {
-                        name,
-                        original_size,
-                        reduced_size,
-                        history,
-                    } => log::info!(
-                    "Quantized tensor `{name}` from {original_size} to {reduced_size} bytes ({history:?})"
-                ),
-                    QuantizeProgress::TensorSkipped { name, size } => {
-                        log::info!("Skipped tensor `{name}` ({size} bytes)")
-                    }
-                    QuantizeProgress::Finished {
-                        original_size,
-                        reduced_size,
-                        history,
-                    } => log::info!(
-                        "Finished quantization from {original_size} to {reduced_size} bytes ({history:?})"
-                    ),
-                },
+                log_quantize_progress,
             )
-            .wrap_err("failed to quantize model")
+            .wrap_err("failed to quantize model")?;
+            Ok(())
         }
     }
 
@@ -257,6 +723,186 @@ fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
         .visit(&mut QuantizeVisitor(args))
 }
 
+fn convert(args: &cli_args::Convert) -> eyre::Result<()> {
+    struct ConvertVisitor<'a>(&'a cli_args::Convert);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for ConvertVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let mut destination: BufWriter<File> =
+                BufWriter::new(std::fs::File::create(&args.destination)?);
+
+            llm::convert::convert_hf::<M, _>(
+                &args.source,
+                &mut destination,
+                args.convert_parameters(),
+                log_quantize_progress,
+            )
+            .wrap_err("failed to convert model")?;
+            Ok(())
+        }
+    }
+
+    let architecture = match args.architecture.model_architecture {
+        Some(architecture) => architecture,
+        None => {
+            let config = llm::convert::HfConfig::load(&args.source.join("config.json"))?;
+            config
+                .architectures()
+                .into_iter()
+                .find_map(|name| {
+                    name.strip_suffix("ForCausalLM")
+                        .unwrap_or(name)
+                        .parse::<llm::ModelArchitecture>()
+                        .ok()
+                })
+                .wrap_err(
+                    "could not determine the architecture from config.json; specify it with -a",
+                )?
+        }
+    };
+    architecture.visit(&mut ConvertVisitor(args))
+}
+
+fn migrate(args: &cli_args::Migrate) -> eyre::Result<()> {
+    struct MigrateVisitor<'a>(&'a cli_args::Migrate);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for MigrateVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let mut source = BufReader::new(std::fs::File::open(&args.source)?);
+            let mut destination = BufWriter::new(std::fs::File::create(&args.destination)?);
+            llm::migrate::<M, _, _>(&mut source, &mut destination, |progress| match progress {
+                llm::MigrateProgress::HyperparametersLoaded => {
+                    log::info!("Loaded hyperparameters")
+                }
+                llm::MigrateProgress::TensorMigrated {
+                    name,
+                    upgraded: true,
+                    ..
+                } => log::info!("Upgraded tensor `{name}`"),
+                llm::MigrateProgress::TensorMigrated {
+                    name,
+                    requantized: true,
+                    ..
+                } => log::info!("Requantized tensor `{name}` to q8_0"),
+                llm::MigrateProgress::TensorMigrated { .. } => {}
+                llm::MigrateProgress::Finished {
+                    upgraded_tensors,
+                    requantized_tensors,
+                    tensor_count,
+                } => log::info!(
+                    "Finished migration: {upgraded_tensors} of {tensor_count} tensors upgraded, \
+                     {requantized_tensors} requantized"
+                ),
+            })
+            .wrap_err("failed to migrate model")?;
+            destination.flush()?;
+            Ok(())
+        }
+    }
+
+    if args.destination.exists()
+        && args.destination.canonicalize()? == args.source.canonicalize()?
+    {
+        eyre::bail!("the model must be migrated to a different file");
+    }
+    let architecture = match args.architecture.model_architecture {
+        Some(architecture) => architecture,
+        None => {
+            let report = llm::check_compatibility(&args.source)?;
+            match report.architecture {
+                Some(architecture) => architecture,
+                None => eyre::bail!(
+                    "could not determine the architecture of the model ({}); specify it with -a",
+                    report
+                        .issues
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                ),
+            }
+        }
+    };
+    architecture.visit(&mut MigrateVisitor(args))?;
+    println!("Migrated the model to {}", args.destination.display());
+    Ok(())
+}
+
+fn print_quantize_plan(report: &llm::QuantizeReport) {
+    let size = |bytes: usize| bytesize::to_string(bytes as u64, false);
+    for tensor in &report.tensors {
+        println!(
+            "{}: {} -> {}, {} -> {}",
+            tensor.name,
+            tensor.original_element_type,
+            tensor.element_type,
+            size(tensor.original_size),
+            size(tensor.reduced_size)
+        );
+    }
+    println!();
+    println!(
+        "Projected size: {} (from {})",
+        size(report.reduced_size),
+        size(report.original_size)
+    );
+    println!(
+        "Memory needed to quantize: {} (for the largest tensor)",
+        size(report.peak_memory())
+    );
+    println!(
+        "Memory needed to load the quantized model without mmap: {}",
+        size(report.reduced_size)
+    );
+}
+
+fn log_quantize_progress(progress: llm::QuantizeProgress) {
+    use llm::QuantizeProgress;
+
+    match progress {
+        QuantizeProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
+        QuantizeProgress::TensorLoading {
+            name,
+            dims,
+            element_type,
+            n_elements,
+        } => {
+            log::info!("Loading tensor `{name}` ({n_elements} ({dims:?}) {element_type} elements)")
+        }
+        QuantizeProgress::TensorQuantizing { name } => log::info!("Quantizing tensor `{name}`"),
+        QuantizeProgress::TensorQuantized {
+            name,
+            original_size,
+            reduced_size,
+            histogram,
+        } => log::info!(
+            "Quantized tensor `{name}` from {original_size} to {reduced_size} bytes ({:?})",
+            histogram.frequencies()
+        ),
+        QuantizeProgress::TensorSkipped { name, size } => {
+            log::info!("Skipped tensor `{name}` ({size} bytes)")
+        }
+        QuantizeProgress::VocabularyAdjusted { changes } => log::warn!(
+            "Adjusted the vocabulary from {} to {} rows: {} moved, {} added, {} removed",
+            changes.original_size,
+            changes.size,
+            changes.moved,
+            changes.added,
+            changes.removed
+        ),
+        QuantizeProgress::Finished {
+            original_size,
+            reduced_size,
+            histogram,
+        } => log::info!(
+            "Finished quantization from {original_size} to {reduced_size} bytes ({:?})",
+            histogram.frequencies()
+        ),
+    }
+}
+
 fn load_prompt_file_with_prompt(
     prompt_file: &cli_args::PromptFile,
     prompt: Option<&str>,
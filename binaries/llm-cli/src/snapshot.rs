@@ -1,11 +1,18 @@
 use std::{
+    cmp::Ordering,
     error::Error,
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{BufReader, BufWriter, Read, Write},
     path::Path,
 };
 
-use llm::{InferenceSession, InferenceSessionConfig, Model};
+use color_eyre::eyre;
+use llm::{
+    InferenceSession, InferenceSessionConfig, InferenceSnapshot, Model, ModelKVMemoryType,
+    RngState, TokenId,
+};
+use rand_chacha::ChaCha12Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use zstd::{
     stream::{read::Decoder, write::Encoder},
@@ -14,50 +21,157 @@ use zstd::{
 
 const SNAPSHOT_COMPRESSION_LEVEL: CompressionLevel = 1;
 
-/// Read or create a session
+/// Written at the start of session and checkpoint files, followed by
+/// [InferenceSnapshot::FORMAT_VERSION], so that files written by other versions of `llm`
+/// are recognized. The session files of `llm` 0.1 start with the snapshot directly.
+const MAGIC: [u8; 4] = *b"llms";
+
+/// Serializes `value`, a snapshot or a value that holds one, after a header with the
+/// format version of snapshots.
+pub fn serialize_versioned(mut writer: impl Write, value: &impl Serialize) -> eyre::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&InferenceSnapshot::FORMAT_VERSION.to_le_bytes())?;
+    bincode::serialize_into(writer, value)?;
+    Ok(())
+}
+
+/// Deserializes a value written by [serialize_versioned]. Returns `None` if the data has
+/// no header, and fails if it was written with another format version.
+pub fn deserialize_versioned<T: DeserializeOwned>(
+    mut reader: impl Read,
+) -> eyre::Result<Option<T>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Ok(None);
+    }
+    let mut version = [0; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    let current = InferenceSnapshot::FORMAT_VERSION;
+    match version.cmp(&current) {
+        Ordering::Equal => Ok(Some(bincode::deserialize_from(reader)?)),
+        Ordering::Greater => eyre::bail!(
+            "it was written by a newer version of llm (format {version}; this version reads format {current})"
+        ),
+        Ordering::Less => eyre::bail!(
+            "it was written by an older version of llm (format {version}; this version reads format {current})"
+        ),
+    }
+}
+
+/// A snapshot written by `llm` 0.1 (format version 0).
+#[derive(Deserialize)]
+struct LegacySnapshot {
+    npast: usize,
+    config: LegacyConfig,
+    tokens: Vec<TokenId>,
+    last_logits: Vec<f32>,
+    #[serde(with = "serde_bytes")]
+    memory_k: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    memory_v: Vec<u8>,
+}
+#[derive(Deserialize)]
+struct LegacyConfig {
+    memory_k_type: ModelKVMemoryType,
+    memory_v_type: ModelKVMemoryType,
+    use_gpu: bool,
+}
+impl From<LegacySnapshot> for InferenceSnapshot {
+    fn from(legacy: LegacySnapshot) -> Self {
+        InferenceSnapshot {
+            npast: legacy.npast,
+            n_evicted: 0,
+            config: InferenceSessionConfig {
+                memory_k_type: legacy.config.memory_k_type,
+                memory_v_type: legacy.config.memory_v_type,
+                use_gpu: legacy.config.use_gpu,
+                ..Default::default()
+            },
+            tokens: legacy.tokens,
+            last_logits: legacy.last_logits,
+            memory_k: legacy.memory_k,
+            memory_v: legacy.memory_v,
+            sampler_state: Default::default(),
+            rng: None,
+        }
+    }
+}
+
+/// Reads the snapshot of the session file at `path`, migrating the files of `llm` 0.1.
+fn read_snapshot(path: &Path) -> eyre::Result<InferenceSnapshot> {
+    let open = || -> eyre::Result<_> {
+        let file = File::open(llm::long_path(path))?;
+        Ok(Decoder::new(BufReader::new(file))?)
+    };
+    if let Some(snapshot) = deserialize_versioned(open()?)? {
+        return Ok(snapshot);
+    }
+    let legacy: LegacySnapshot = bincode::deserialize_from(open()?).map_err(|_| {
+        eyre::eyre!(
+            "it has no format header and is not a session of llm 0.1; it was probably written \
+             by a development version of llm, and must be deleted"
+        )
+    })?;
+    log::info!("Migrating the session in {path:?} from the format of llm 0.1");
+    Ok(legacy.into())
+}
+
+/// Read or create a session. Also returns whether the session was loaded, and the random
+/// number generator saved with it, if any.
 pub fn read_or_create_session(
     model: &dyn Model,
     persist_session: Option<&Path>,
     load_session: Option<&Path>,
     inference_session_config: InferenceSessionConfig,
-) -> (InferenceSession, bool) {
-    fn load(model: &dyn Model, path: &Path) -> InferenceSession {
-        let file = unwrap_or_exit(File::open(path), || format!("Could not open file {path:?}"));
-        let decoder = unwrap_or_exit(Decoder::new(BufReader::new(file)), || {
-            format!("Could not create decoder for {path:?}")
-        });
-        let snapshot = unwrap_or_exit(bincode::deserialize_from(decoder), || {
-            format!("Could not deserialize inference session from {path:?}")
-        });
+) -> (InferenceSession, bool, Option<ChaCha12Rng>) {
+    fn load(model: &dyn Model, path: &Path) -> (InferenceSession, bool, Option<ChaCha12Rng>) {
+        let mut snapshot = match read_snapshot(path) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                log::error!("Could not read the inference session from {path:?}: {err:#}");
+                std::process::exit(1);
+            }
+        };
+        let rng = snapshot.rng.take().map(|rng| rng.to_rng());
         let session = unwrap_or_exit(InferenceSession::from_snapshot(snapshot, model), || {
             format!("Could not convert snapshot from {path:?} to session")
         });
         log::info!("Loaded inference session from {path:?}");
-        session
+        (session, true, rng)
     }
 
     match (persist_session, load_session) {
-        (Some(path), _) if path.exists() => (load(model, path), true),
-        (_, Some(path)) => (load(model, path), true),
-        _ => (model.start_session(inference_session_config), false),
+        (Some(path), _) if llm::long_path(path).exists() => load(model, path),
+        (_, Some(path)) => load(model, path),
+        _ => {
+            let session = unwrap_or_exit(model.try_start_session(inference_session_config), || {
+                "Could not start the inference session".to_string()
+            });
+            (session, false, None)
+        }
     }
 }
 
-/// Write the session
-pub fn write_session(mut session: InferenceSession, path: &Path) {
+/// Write the session, with the state of the random number generator used to sample from it
+pub fn write_session(mut session: InferenceSession, rng: &ChaCha12Rng, path: &Path) {
     // SAFETY: the session is consumed here, so nothing else can access it.
-    let snapshot = unsafe { session.get_snapshot() };
-    let file = unwrap_or_exit(File::create(path), || {
+    let mut snapshot = unwrap_or_exit(unsafe { session.get_snapshot() }, || {
+        "Could not restore the spilled session".to_string()
+    });
+    snapshot.rng = Some(RngState::from(rng));
+    let file = unwrap_or_exit(File::create(llm::long_path(path)), || {
         format!("Could not create file {path:?}")
     });
     let encoder = unwrap_or_exit(
         Encoder::new(BufWriter::new(file), SNAPSHOT_COMPRESSION_LEVEL),
         || format!("Could not create encoder for {path:?}"),
     );
-    unwrap_or_exit(
-        bincode::serialize_into(encoder.auto_finish(), &snapshot),
-        || format!("Could not serialize inference session to {path:?}"),
-    );
+    if let Err(err) = serialize_versioned(encoder.auto_finish(), &snapshot) {
+        log::error!("Could not serialize inference session to {path:?}. Error: {err:#}");
+        std::process::exit(1);
+    }
     log::info!("Successfully wrote session to {path:?}");
 }
 
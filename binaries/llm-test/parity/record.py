@@ -0,0 +1,65 @@
+"""Records the outputs of a Hugging Face model for the `Parity` test case of `llm-test`.
+
+Usage:
+
+    python record.py <model directory or Hub ID> <output.json> [prompt]
+
+The model is run in float32 on the CPU. The output contains the prompt's token IDs, the
+output of every decoder layer (before the final norm) and the logits of every token, each
+flattened in the same order as `llm` stores them.
+
+Requires `torch` and `transformers`.
+"""
+
+import json
+import sys
+
+import torch
+from transformers import AutoModelForCausalLM, AutoTokenizer
+
+
+def decoder_layers(model):
+    """Returns the list of decoder layers, whatever the architecture calls it."""
+    n_layer = model.config.num_hidden_layers
+    for module in model.modules():
+        if isinstance(module, torch.nn.ModuleList) and len(module) == n_layer:
+            return module
+    raise ValueError("could not find the decoder layers of the model")
+
+
+def main():
+    if len(sys.argv) < 3:
+        sys.exit(__doc__)
+    model_path, output_path = sys.argv[1], sys.argv[2]
+    prompt = sys.argv[3] if len(sys.argv) > 3 else "When a llama rides a crab,"
+
+    tokenizer = AutoTokenizer.from_pretrained(model_path)
+    model = AutoModelForCausalLM.from_pretrained(model_path, torch_dtype=torch.float32)
+    model.eval()
+
+    layers = []
+
+    def record(_module, _inputs, output):
+        hidden = output[0] if isinstance(output, tuple) else output
+        layers.append(hidden[0].flatten().tolist())
+
+    for layer in decoder_layers(model):
+        layer.register_forward_hook(record)
+
+    tokens = tokenizer(prompt, return_tensors="pt").input_ids
+    with torch.no_grad():
+        logits = model(tokens).logits
+
+    with open(output_path, "w") as f:
+        json.dump(
+            {
+                "tokens": tokens[0].tolist(),
+                "layers": layers,
+                "logits": logits[0].flatten().tolist(),
+            },
+            f,
+        )
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,132 @@
+//! Tests that feeding a prompt in one batch gives the same logits as feeding it token by
+//! token:
+//!
+//! *   [llm::InferenceSession::feed_prompt()] with [llm::InferenceParameters::n_batch]
+//!
+//! Batches of 32 tokens or more go through the matrix-matrix kernels, which run on the GPU
+//! with `--gpu` and the `cublas` or `clblast` feature, while single tokens go through the
+//! matrix-vector kernels. Metal still computes prompts on the CPU.
+//!
+//! See [crate::TestCase::Batch].
+
+use std::convert::Infallible;
+
+use llm::{InferenceFeedback, InferenceParameters, Model, OutputRequest};
+use serde::Serialize;
+
+use crate::{ModelConfig, TestCaseReport, TestCaseReportInner, TestCaseReportMeta};
+
+/// The number of tokens from which `ggml` multiplies matrices on the GPU.
+const GPU_BATCH_SIZE: usize = 32;
+
+/// Tests that the logits after `input` do not depend on the batch size, to within
+/// `tolerance` of the largest logit.
+pub(crate) fn can_batch(
+    model: &dyn Model,
+    model_config: &ModelConfig,
+    input: &str,
+    tolerance: f32,
+) -> TestCaseReport {
+    let mut report = BatchReport {
+        gpu: model_config.gpu,
+        ..Default::default()
+    };
+
+    let tokens = match model.tokenizer().tokenize(input, true) {
+        Ok(tokens) => tokens.len(),
+        Err(err) => return report.failure(&err.to_string()),
+    };
+    report.tokens = tokens;
+    if tokens < GPU_BATCH_SIZE {
+        log::warn!(
+            "The input of the batch test has {tokens} tokens, fewer than the \
+            {GPU_BATCH_SIZE} for which the matrix-matrix kernels are used"
+        );
+    }
+
+    let batched = match last_logits(model, model_config, input, tokens) {
+        Ok(logits) => logits,
+        Err(err) => return report.failure(&err.to_string()),
+    };
+    let sequential = match last_logits(model, model_config, input, 1) {
+        Ok(logits) => logits,
+        Err(err) => return report.failure(&err.to_string()),
+    };
+
+    if batched.iter().chain(&sequential).any(|v| !v.is_finite()) {
+        return report.failure("The logits are not finite.");
+    }
+    let scale = sequential.iter().fold(0.0f32, |max, v| max.max(v.abs()));
+    report.difference = batched
+        .iter()
+        .zip(&sequential)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0.0, f32::max)
+        / scale.max(f32::EPSILON);
+    if report.difference > tolerance {
+        let difference = report.difference;
+        return report.failure(&format!(
+            "The logits of a batch of {tokens} tokens differ from those of single tokens \
+            by {difference} of the largest logit, more than the tolerance of {tolerance}"
+        ));
+    }
+
+    log::info!("`can_batch` test passed!");
+    report.success()
+}
+
+/// Feeds `input` in batches of `n_batch` tokens to a new session, and returns the logits
+/// of its last token.
+fn last_logits(
+    model: &dyn Model,
+    model_config: &ModelConfig,
+    input: &str,
+    n_batch: usize,
+) -> Result<Vec<f32>, llm::InferenceError> {
+    let mut session = model.start_session(Default::default());
+    let mut output = OutputRequest {
+        all_logits: Some(vec![]),
+        ..Default::default()
+    };
+    session.feed_prompt(
+        model,
+        &InferenceParameters {
+            n_threads: model_config.threads.into(),
+            n_batch,
+            ..Default::default()
+        },
+        input,
+        &mut output,
+        |_| Ok::<_, Infallible>(InferenceFeedback::Continue),
+    )?;
+    let logits = output.all_logits.unwrap_or_default();
+    let n_vocab = model.tokenizer().len();
+    Ok(logits[logits.len().saturating_sub(n_vocab)..].to_vec())
+}
+
+#[derive(Serialize, Default)]
+pub struct BatchReport {
+    /// Whether the model was loaded on the GPU.
+    gpu: bool,
+    tokens: usize,
+    /// The largest difference between the logits, relative to the largest logit.
+    difference: f32,
+}
+
+impl BatchReport {
+    fn failure(self, msg: &str) -> TestCaseReport {
+        TestCaseReport {
+            meta: TestCaseReportMeta::Error {
+                error: msg.to_owned(),
+            },
+            report: TestCaseReportInner::Batch(self),
+        }
+    }
+
+    fn success(self) -> TestCaseReport {
+        TestCaseReport {
+            meta: TestCaseReportMeta::Success,
+            report: TestCaseReportInner::Batch(self),
+        }
+    }
+}
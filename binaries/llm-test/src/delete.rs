@@ -1,6 +1,8 @@
 //! Tests the model's token manipulation APIs:
 //!
 //! *   [llm::InferenceSession::feed_prompt()]
+//! *   [llm::InferenceSession::rewind()]
+//! *   [llm::InferenceSession::speculate()]
 //!
 //! See [crate::TestCase::Tokens].
 
@@ -44,14 +46,25 @@ pub(crate) fn can_delete(model: &impl Model) -> TestCaseReport {
         return report.failure("Second run of model did not return logits.");
     };
 
-    // Compare the logits
-    for (idx, (&original, redone)) in original_logits.iter().zip(redone_logits).enumerate() {
-        if original > redone + f32::EPSILON || original < redone - f32::EPSILON {
-            return report.failure(&format!(
-                "Expected logits to be the same after delete, but differed at {idx}, \
-                expected {original}, but was {redone}."
-            ));
-        }
+    if let Err(msg) = compare_logits(&original_logits, &redone_logits, "delete") {
+        return report.failure(&msg);
+    }
+
+    // Rewind, speculate a wrong guess, then add the actual token. Verify logits are the same.
+    if let Err(err) = session.rewind(model, 1) {
+        return report.failure(&err.to_string());
+    }
+    if let Err(err) = session.speculate(model, &Default::default(), " in the", None) {
+        return report.failure(&err.to_string());
+    }
+    if let Err(err) = feed_prompt(" ", &mut session, model, &mut output) {
+        return report.failure(&err.to_string());
+    }
+    let Some(speculated_logits) = output.all_logits.clone() else {
+        return report.failure("Run of model after speculation did not return logits.");
+    };
+    if let Err(msg) = compare_logits(&original_logits, &speculated_logits, "speculation") {
+        return report.failure(&msg);
     }
 
     log::info!("`can_delete` test passed!");
@@ -67,6 +80,18 @@ fn feed_prompt(
     session.feed_prompt(model, &Default::default(), prompt, output, always_continue)
 }
 
+fn compare_logits(original: &[f32], redone: &[f32], operation: &str) -> Result<(), String> {
+    for (idx, (&original, &redone)) in original.iter().zip(redone).enumerate() {
+        if original > redone + f32::EPSILON || original < redone - f32::EPSILON {
+            return Err(format!(
+                "Expected logits to be the same after {operation}, but differed at {idx}, \
+                expected {original}, but was {redone}."
+            ));
+        }
+    }
+    Ok(())
+}
+
 fn always_continue(_: &[u8]) -> Result<InferenceFeedback, Infallible> {
     Ok(InferenceFeedback::Continue)
 }
@@ -70,12 +70,21 @@ fn run_inference(
         &llm::InferenceRequest {
             prompt: input.into(),
             parameters: &llm::InferenceParameters {
-                n_threads: model_config.threads,
+                n_threads: model_config.threads.into(),
                 n_batch: 1,
                 sampler: Arc::new(DeterministicSampler),
             },
             play_back_previous_tokens: false,
             maximum_token_count: Some(maximum_token_count),
+            maximum_output_bytes: None,
+            maximum_output_chars: None,
+            stop_token_sequences: &[],
+            guardrails: &[],
+            forced_prefix: None,
+            logprobs: None,
+            early_stop: Default::default(),
+            cancellation_token: None,
+            deadline: None,
         },
         &mut Default::default(),
         |r| match r {
@@ -1,8 +1,11 @@
 //! Test runner for all LLMs.
 
+mod batch;
 mod common;
 mod delete;
 mod inference;
+#[cfg(feature = "parity")]
+mod parity;
 mod tokens;
 
 use anyhow::Context;
@@ -37,6 +40,11 @@ struct Cli {
     #[clap(short, long)]
     threads: Option<usize>,
 
+    /// Load the models on the GPU, to test the `cublas`, `clblast` or `metal` backend the
+    /// test runner was built with. The results are saved as `<architecture>-gpu.json`.
+    #[clap(long)]
+    gpu: bool,
+
     /// The model architecture to test. If not specified, all architectures will be tested.
     architecture: Option<String>,
 }
@@ -75,6 +83,7 @@ async fn main() -> anyhow::Result<()> {
     let model_config = ModelConfig {
         mmap: !args.no_mmap,
         threads: args.threads.unwrap_or(2),
+        gpu: args.gpu,
     };
 
     // Test models
@@ -108,6 +117,7 @@ async fn main() -> anyhow::Result<()> {
 struct ModelConfig {
     mmap: bool,
     threads: usize,
+    gpu: bool,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -130,6 +140,23 @@ enum TestCase {
         output: usize,
     },
     Delete {},
+    /// Compares the logits after `input` fed in one batch with those after feeding it token
+    /// by token. With `--gpu`, this covers the batched matrix multiplications of the GPU.
+    Batch {
+        /// At least 32 tokens, for the matrix-matrix kernels to be used.
+        input: String,
+        /// The largest difference allowed, relative to the largest logit.
+        tolerance: f32,
+    },
+    /// Compares the output of every layer, and the logits, with a reference
+    /// implementation. See `parity/record.py` for how to record the reference.
+    #[cfg(feature = "parity")]
+    Parity {
+        /// The path to the recorded reference.
+        reference: PathBuf,
+        /// The largest difference allowed, relative to the largest value of the reference.
+        tolerance: f32,
+    },
 }
 
 #[derive(Serialize)]
@@ -161,6 +188,9 @@ pub enum TestCaseReportInner {
     },
     Tokens(tokens::TokensReport),
     Delete(delete::DeleteReport),
+    Batch(batch::BatchReport),
+    #[cfg(feature = "parity")]
+    Parity(parity::ParityReport),
 }
 
 async fn test_model(
@@ -212,6 +242,7 @@ async fn test_model(
                     llm::TokenizerSource::Embedded,
                     llm::ModelParameters {
                         prefer_mmap: model_config.mmap,
+                        use_gpu: model_config.gpu,
                         ..Default::default()
                     },
                     |progress| {
@@ -230,6 +261,7 @@ async fn test_model(
                     Ok(m) => m,
                     Err(err) => {
                         write_report(
+                            model_config,
                             test_config,
                             results_dir,
                             &Report::LoadFail {
@@ -284,6 +316,18 @@ async fn test_model(
                     TestCase::Delete {} => {
                         test_case_reports.push(delete::can_delete(&model));
                     }
+                    TestCase::Batch { input, tolerance } => test_case_reports
+                        .push(batch::can_batch(&model, model_config, input, *tolerance)),
+                    #[cfg(feature = "parity")]
+                    TestCase::Parity {
+                        reference,
+                        tolerance,
+                    } => test_case_reports.push(parity::matches_reference(
+                        &model,
+                        model_config,
+                        reference,
+                        *tolerance,
+                    )?),
                 }
             }
             let first_error: Option<String> =
@@ -297,6 +341,7 @@ async fn test_model(
             // Save the results
             // Serialize the report to a JSON string
             write_report(
+                model_config,
                 test_config,
                 results_dir,
                 &Report::LoadSuccess {
@@ -328,12 +373,14 @@ async fn test_model(
 }
 
 fn write_report(
+    model_config: &ModelConfig,
     test_config: &TestConfig,
     results_dir: &Path,
     report: &Report,
 ) -> anyhow::Result<()> {
     let json_report = serde_json::to_string_pretty(&report)?;
-    let report_path = results_dir.join(format!("{}.json", test_config.architecture));
+    let suffix = if model_config.gpu { "-gpu" } else { "" };
+    let report_path = results_dir.join(format!("{}{suffix}.json", test_config.architecture));
     fs::write(report_path, json_report)?;
     Ok(())
 }
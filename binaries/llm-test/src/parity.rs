@@ -0,0 +1,164 @@
+//! Compares the model against a reference implementation, layer by layer, using outputs
+//! recorded with `binaries/llm-test/parity/record.py`.
+//!
+//! See [crate::TestCase::Parity].
+
+use std::{fs, path::Path};
+
+use llm::{InferenceSessionConfig, OutputRequest, TokenId};
+use serde::{Deserialize, Serialize};
+
+use crate::{ModelConfig, TestCaseReport, TestCaseReportInner, TestCaseReportMeta};
+
+/// The outputs of the reference implementation for a sequence of tokens.
+#[derive(Deserialize)]
+struct Reference {
+    /// The tokens that were evaluated, in one batch.
+    tokens: Vec<TokenId>,
+    /// The output of each layer, before the final norm, as `n_tokens * n_embd` values.
+    layers: Vec<Vec<f32>>,
+    /// The logits for every token, as `n_tokens * n_vocab` values.
+    logits: Vec<f32>,
+}
+
+pub(crate) fn matches_reference(
+    model: &dyn llm::Model,
+    model_config: &ModelConfig,
+    reference_path: &Path,
+    tolerance: f32,
+) -> anyhow::Result<TestCaseReport> {
+    let reference: Reference = serde_json::from_str(&fs::read_to_string(reference_path)?)?;
+
+    let mut session = model.start_session(InferenceSessionConfig {
+        capture_layer_outputs: true,
+        ..Default::default()
+    });
+    let mut output_request = OutputRequest {
+        all_logits: Some(vec![]),
+        ..Default::default()
+    };
+    model.evaluate(
+        &mut session,
+        &llm::InferenceParameters {
+            n_threads: model_config.threads.into(),
+            ..Default::default()
+        },
+        &reference.tokens,
+        &mut output_request,
+    );
+
+    let layer_outputs = session.layer_outputs();
+    let mut report = ParityReport {
+        layers: vec![],
+        logits: None,
+        first_divergent_layer: None,
+    };
+    if layer_outputs.len() != reference.layers.len() {
+        return Ok(report.failure(format!(
+            "The model has {} layers, but the reference has {}.",
+            layer_outputs.len(),
+            reference.layers.len()
+        )));
+    }
+
+    for (layer, (actual, expected)) in layer_outputs.iter().zip(&reference.layers).enumerate() {
+        let Some(difference) = Difference::new(actual, expected) else {
+            return Ok(report.failure(format!(
+                "Layer {layer} has {} values, but the reference has {}.",
+                actual.len(),
+                expected.len()
+            )));
+        };
+        if difference.relative > tolerance && report.first_divergent_layer.is_none() {
+            report.first_divergent_layer = Some(layer);
+        }
+        log::info!(
+            "layer {layer}: max abs diff {:.6}, relative {:.6}",
+            difference.max_absolute,
+            difference.relative
+        );
+        report.layers.push(difference);
+    }
+
+    let all_logits = output_request.all_logits.unwrap_or_default();
+    report.logits = Difference::new(&all_logits, &reference.logits);
+    let Some(logits) = report.logits else {
+        return Ok(report.failure(format!(
+            "The model produced {} logits, but the reference has {}.",
+            all_logits.len(),
+            reference.logits.len()
+        )));
+    };
+
+    if let Some(layer) = report.first_divergent_layer {
+        let difference = report.layers[layer].relative;
+        return Ok(report.failure(format!(
+            "Layer {layer} diverges from the reference (relative difference {difference} > {tolerance})."
+        )));
+    }
+    if logits.relative > tolerance {
+        return Ok(report.failure(format!(
+            "The logits diverge from the reference (relative difference {} > {tolerance}).",
+            logits.relative
+        )));
+    }
+
+    log::info!("`matches_reference` test passed!");
+    Ok(report.success())
+}
+
+#[derive(Serialize)]
+pub struct ParityReport {
+    layers: Vec<Difference>,
+    logits: Option<Difference>,
+    first_divergent_layer: Option<usize>,
+}
+impl ParityReport {
+    fn failure(self, error: String) -> TestCaseReport {
+        TestCaseReport {
+            meta: TestCaseReportMeta::Error { error },
+            report: TestCaseReportInner::Parity(self),
+        }
+    }
+
+    fn success(self) -> TestCaseReport {
+        TestCaseReport {
+            meta: TestCaseReportMeta::Success,
+            report: TestCaseReportInner::Parity(self),
+        }
+    }
+}
+
+/// How far a tensor is from the reference.
+#[derive(Serialize, Clone, Copy)]
+struct Difference {
+    /// The largest absolute difference between two values.
+    max_absolute: f32,
+    /// [Self::max_absolute] divided by the largest absolute value of the reference.
+    relative: f32,
+}
+impl Difference {
+    fn new(actual: &[f32], expected: &[f32]) -> Option<Self> {
+        if actual.len() != expected.len() {
+            return None;
+        }
+
+        let max_absolute = actual
+            .iter()
+            .zip(expected)
+            .map(|(a, e)| (a - e).abs())
+            // NaNs are the most common symptom of a broken implementation, so they must not
+            // be ignored by `f32::max`.
+            .map(|d| if d.is_nan() { f32::INFINITY } else { d })
+            .fold(0.0, f32::max);
+        let scale = expected.iter().map(|e| e.abs()).fold(0.0, f32::max);
+        Some(Self {
+            max_absolute,
+            relative: if scale > 0.0 {
+                max_absolute / scale
+            } else {
+                max_absolute
+            },
+        })
+    }
+}
@@ -0,0 +1,90 @@
+//! Offloading tensors to an accelerator, such as a GPU.
+//!
+//! With the `cublas` feature (CUDA) or the `clblast` feature (OpenCL, through CLBlast), the
+//! weights of a model can be copied to the GPU with [Context::offload](crate::Context::offload).
+//! The operations that use them are then computed on the GPU, while the rest of the graph
+//! stays on the CPU. Metal does not need this, as it shares the memory of the CPU.
+//!
+//! With OpenCL, the GPU is chosen when `ggml` is first initialized: the first GPU of the
+//! first platform that has one, unless the `GGML_OPENCL_PLATFORM` and `GGML_OPENCL_DEVICE`
+//! environment variables name another (by number, or by part of its name). The large
+//! matrix multiplications of prompts use CLBlast even for tensors that are not offloaded.
+use crate::sys;
+
+/// Where the data of a tensor is stored, and where the operations that use it are computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// In main memory, for the CPU.
+    #[default]
+    Cpu,
+    /// On the GPU.
+    Gpu,
+}
+impl From<Backend> for sys::ggml_backend {
+    fn from(backend: Backend) -> Self {
+        match backend {
+            Backend::Cpu => sys::ggml_backend_GGML_BACKEND_CPU,
+            Backend::Gpu => sys::ggml_backend_GGML_BACKEND_GPU,
+        }
+    }
+}
+impl TryFrom<sys::ggml_backend> for Backend {
+    type Error = ();
+    fn try_from(backend: sys::ggml_backend) -> Result<Self, Self::Error> {
+        match backend {
+            sys::ggml_backend_GGML_BACKEND_CPU => Ok(Backend::Cpu),
+            // Tensors split across several GPUs are still on the GPU.
+            sys::ggml_backend_GGML_BACKEND_GPU | sys::ggml_backend_GGML_BACKEND_GPU_SPLIT => {
+                Ok(Backend::Gpu)
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether this build of `ggml` can offload tensors to the GPU, which requires the
+/// `cublas` or the `clblast` feature.
+pub fn can_offload() -> bool {
+    cfg!(any(feature = "cublas", feature = "clblast"))
+}
+
+/// A GPU that tensors are offloaded to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuDevice {
+    /// The name of the platform of the device (e.g. the OpenCL platform).
+    pub platform: String,
+    /// The name of the device.
+    pub name: String,
+}
+
+/// Returns the GPU that tensors are offloaded to, once a [Context](crate::Context) has been
+/// created. This is only known with OpenCL; it is `None` in other builds.
+pub fn gpu_device() -> Option<GpuDevice> {
+    #[cfg(all(feature = "clblast", not(feature = "cublas")))]
+    {
+        extern "C" {
+            // Added to ggml-opencl.cpp by the build script of ggml-sys.
+            fn ggml_cl_selected_platform() -> *const std::os::raw::c_char;
+            fn ggml_cl_selected_device() -> *const std::os::raw::c_char;
+        }
+        // SAFETY: the names are null-terminated strings in static buffers, which are only
+        // written when ggml is initialized.
+        let (platform, name) = unsafe {
+            (
+                std::ffi::CStr::from_ptr(ggml_cl_selected_platform()),
+                std::ffi::CStr::from_ptr(ggml_cl_selected_device()),
+            )
+        };
+        if name.to_bytes().is_empty() {
+            return None;
+        }
+        Some(GpuDevice {
+            platform: platform.to_string_lossy().into_owned(),
+            name: name.to_string_lossy().into_owned(),
+        })
+    }
+    #[cfg(not(all(feature = "clblast", not(feature = "cublas"))))]
+    {
+        None
+    }
+}
@@ -13,11 +13,15 @@ pub struct Context {
     /// with it if the underlying context has been deallocated.
     pub ptr: Arc<NonNull<sys::ggml_context>>,
 
-    /// Memory mapping information
-    pub mmap: Option<Mmap>,
+    /// The memory mapped files that the tensors point into, if any.
+    pub mmaps: Vec<Mmap>,
 
     /// Backing buffer (in case we own it)
     pub buffer: Option<Buffer>,
+
+    /// The tensors whose data has been copied to the GPU, which is freed with the context.
+    #[cfg_attr(not(any(feature = "cublas", feature = "clblast")), allow(dead_code))]
+    offloaded_tensors: Vec<Tensor>,
 }
 
 impl Context {
@@ -33,16 +37,23 @@ impl Context {
 
         Self {
             ptr: Arc::new(NonNull::new(raw).expect("Should not be null")),
-            mmap: None,
+            mmaps: vec![],
             buffer: Some(buffer),
+            offloaded_tensors: vec![],
         }
     }
 
     /// Creates a new [Context] with the memory mapped file provided
     pub fn init_mmap(mmap: Mmap) -> Self {
+        Self::init_mmaps(vec![mmap])
+    }
+
+    /// Creates a new [Context] with the memory mapped files provided, for models split
+    /// across several files.
+    pub fn init_mmaps(mmaps: Vec<Mmap>) -> Self {
         let raw = unsafe {
             sys::ggml_init(sys::ggml_init_params {
-                mem_size: mmap.len(),
+                mem_size: mmaps.iter().map(|mmap| mmap.len()).sum(),
                 mem_buffer: std::ptr::null_mut(),
                 no_alloc: true, // We are mmapping so ggml does not need to allocate any memory for us
             })
@@ -50,8 +61,9 @@ impl Context {
 
         Self {
             ptr: Arc::new(NonNull::new(raw).expect("Should not be null")),
-            mmap: Some(mmap),
+            mmaps,
             buffer: None,
+            offloaded_tensors: vec![],
         }
     }
 
@@ -68,8 +80,9 @@ impl Context {
 
         Self {
             ptr: Arc::new(NonNull::new(raw).expect("Should not be null")),
-            mmap: None,
+            mmaps: vec![],
             buffer: None,
+            offloaded_tensors: vec![],
         }
     }
 
@@ -82,9 +95,16 @@ impl Context {
     }
 
     /// Creates a new 1D tensor.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `typ` is a type that `ggml` cannot compute with (see [Type::is_file_only]).
+    /// This applies to all of the `new_tensor` functions; use [Context::try_new_tensor] for
+    /// types read from model files.
     pub fn new_tensor_1d(&self, typ: Type, ne0: usize) -> Tensor {
-        let raw =
-            unsafe { sys::ggml_new_tensor_1d(self.ptr.as_ptr(), typ.into(), usize_to_i64(ne0)) };
+        let raw = unsafe {
+            sys::ggml_new_tensor_1d(self.ptr.as_ptr(), tensor_type(typ), usize_to_i64(ne0))
+        };
         self.new_tensor_raw(raw)
     }
 
@@ -93,7 +113,7 @@ impl Context {
         let raw = unsafe {
             sys::ggml_new_tensor_2d(
                 self.ptr.as_ptr(),
-                typ.into(),
+                tensor_type(typ),
                 usize_to_i64(ne0),
                 usize_to_i64(ne1),
             )
@@ -106,7 +126,7 @@ impl Context {
         let raw = unsafe {
             sys::ggml_new_tensor_3d(
                 self.ptr.as_ptr(),
-                typ.into(),
+                tensor_type(typ),
                 usize_to_i64(ne0),
                 usize_to_i64(ne1),
                 usize_to_i64(ne2),
@@ -115,6 +135,34 @@ impl Context {
         self.new_tensor_raw(raw)
     }
 
+    /// Creates a new tensor with the dimensions `ne`, or returns an error if `typ` is a type
+    /// that `ggml` cannot compute with (see [Type::is_file_only]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ne` has no dimensions or more than [sys::GGML_MAX_DIMS].
+    pub fn try_new_tensor(&self, typ: Type, ne: &[usize]) -> Result<Tensor, FileOnlyTypeError> {
+        assert!(
+            (1..=sys::GGML_MAX_DIMS as usize).contains(&ne.len()),
+            "ggml tensors have between 1 and {} dimensions, not {}",
+            sys::GGML_MAX_DIMS,
+            ne.len()
+        );
+        if typ.is_file_only() {
+            return Err(FileOnlyTypeError(typ));
+        }
+        let ne: Vec<i64> = ne.iter().copied().map(usize_to_i64).collect();
+        let raw = unsafe {
+            sys::ggml_new_tensor(
+                self.ptr.as_ptr(),
+                typ.into(),
+                usize_to_i32(ne.len()),
+                ne.as_ptr(),
+            )
+        };
+        Ok(self.new_tensor_raw(raw))
+    }
+
     /// Creates a new 1D tensor with the specified value.
     pub fn new_f32(&self, x: f32) -> Tensor {
         let raw = unsafe { sys::ggml_new_f32(self.ptr.as_ptr(), x) };
@@ -473,13 +521,71 @@ impl Context {
         let tensor = unsafe { sys::ggml_gelu(self.ptr.as_ptr(), a.ptr.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
+
+    /// Copies the data of `tensor` to the GPU, so that the operations that use it are
+    /// computed there. The memory on the GPU is freed when the context is dropped.
+    ///
+    /// Returns whether the tensor was offloaded: without the `cublas` or `clblast` feature
+    /// (see [accelerator::can_offload](crate::accelerator::can_offload)), this does nothing.
+    ///
+    /// # Safety
+    ///
+    /// `tensor` must belong to this context, and its data must have been written. The data
+    /// must not be modified afterwards, as the copy on the GPU would not be updated.
+    pub unsafe fn offload(&mut self, tensor: &Tensor) -> bool {
+        #[cfg(any(feature = "cublas", feature = "clblast"))]
+        {
+            if tensor.backend() == crate::accelerator::Backend::Gpu {
+                return true;
+            }
+            let raw = tensor.ptr.as_ptr();
+            (*raw).backend = crate::accelerator::Backend::Gpu.into();
+            // The build script of ggml-sys prefers CUDA if both are enabled.
+            #[cfg(feature = "cublas")]
+            sys::cuda::ggml_cuda_transform_tensor((*raw).data, raw);
+            #[cfg(not(feature = "cublas"))]
+            sys::opencl::ggml_cl_transform_tensor((*raw).data, raw);
+            self.offloaded_tensors.push(tensor.share());
+            true
+        }
+        #[cfg(not(any(feature = "cublas", feature = "clblast")))]
+        {
+            let _ = tensor;
+            false
+        }
+    }
 }
 
 impl Drop for Context {
     fn drop(&mut self) {
+        #[cfg(feature = "cublas")]
+        for tensor in &self.offloaded_tensors {
+            // SAFETY: the tensors were offloaded by this context, which is still alive.
+            unsafe { sys::cuda::ggml_cuda_free_data(tensor.ptr.as_ptr()) };
+        }
+        #[cfg(all(feature = "clblast", not(feature = "cublas")))]
+        for tensor in &self.offloaded_tensors {
+            // SAFETY: as above.
+            unsafe { sys::opencl::ggml_cl_free_data(tensor.ptr.as_ptr()) };
+        }
+
         // SAFETY: The only non-weak copy of ptr is no longer accessible after this drop call.
         unsafe {
             sys::ggml_free(self.ptr.as_ptr());
         }
     }
 }
+
+/// The error returned by [Context::try_new_tensor] for a type that `ggml` cannot compute
+/// with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("ggml cannot create {0} tensors; convert the data to a supported type first")]
+pub struct FileOnlyTypeError(pub Type);
+
+/// Returns the `ggml` type for a new tensor of type `typ`.
+fn tensor_type(typ: Type) -> sys::ggml_type {
+    if typ.is_file_only() {
+        panic!("{}", FileOnlyTypeError(typ));
+    }
+    typ.into()
+}
@@ -83,6 +83,9 @@ pub struct TensorLoadInfo {
     pub element_type: ElementType,
     /// start of tensor - start of file
     pub start_offset: u64,
+    /// The quantization version of the file, which determines the layout of quantized
+    /// tensors. See [legacy](crate::legacy).
+    pub quantization_version: u32,
 }
 impl TensorLoadInfo {
     /// Get the dimensions of the tensor.
@@ -90,9 +93,17 @@ impl TensorLoadInfo {
         &self.dims[0..self.n_dims]
     }
 
-    /// Calculate the size of the tensor's values in bytes.
+    /// Calculate the size of the tensor's values in bytes, as stored in the file.
     pub fn calc_size(&self) -> usize {
-        data_size(self.element_type, self.dims().iter().product())
+        let n_elements: usize = self.dims().iter().product();
+        crate::legacy::type_size(self.element_type, self.quantization_version) * n_elements
+            / crate::blck_size(self.element_type)
+    }
+
+    /// Returns whether the tensor is stored in a legacy layout, and must be
+    /// [upgraded](crate::legacy::upgrade) before it is used.
+    pub fn is_legacy(&self) -> bool {
+        crate::legacy::is_legacy(self.element_type, self.quantization_version)
     }
 
     /// Calculates the absolute size in bytes of the tensor's data, given the mmap flag.
@@ -110,12 +121,24 @@ impl TensorLoadInfo {
     ///
     /// Do not use this if loading with `mmap`.
     pub fn read_data<R: BufRead + Seek>(&self, reader: &mut R) -> std::io::Result<Vec<u8>> {
-        let n_bytes = self.n_elements * crate::type_size(self.element_type);
-        let mut data = vec![0; n_bytes];
+        let mut data = vec![0; self.calc_size()];
         reader.seek(SeekFrom::Start(self.start_offset))?;
         reader.read_exact(&mut data)?;
         Ok(data)
     }
+
+    /// Reads the tensor's values from the given reader as `f32`s, upgrading and dequantizing
+    /// them as necessary. Returns `None` if the values cannot be converted (see
+    /// [dequantize](crate::dequantize)).
+    ///
+    /// The behaviour is undefined if the reader does not correspond to this info.
+    pub fn read_f32<R: BufRead + Seek>(&self, reader: &mut R) -> std::io::Result<Option<Vec<f32>>> {
+        let mut data = self.read_data(reader)?;
+        if self.is_legacy() {
+            data = crate::legacy::upgrade(self.element_type, self.quantization_version, &data);
+        }
+        Ok(crate::dequantize(self.element_type, &data))
+    }
 }
 
 /// Returns the size occupied by a tensor's data in bytes given the element type and number of elements.
@@ -139,6 +162,9 @@ pub fn tensor_size(element_type: ElementType, n_elements: usize) -> usize {
 pub struct PartialHyperparameters {
     /// The number of tokens in the model's embedded vocabulary.
     pub n_vocab: usize,
+    /// The quantization version of the tensors, which determines their layout.
+    /// See [legacy](crate::legacy).
+    pub quantization_version: u32,
 }
 
 /// A handler for loading a GGML model.
@@ -200,9 +226,11 @@ pub fn load<E: Error, R: BufRead + Seek>(
 
     // Load tensor data
     match container_type {
-        ContainerType::Ggmf(_) | ContainerType::Ggml => load_weights(reader, handler, false),
+        ContainerType::Ggmf(_) | ContainerType::Ggml => {
+            load_weights(reader, handler, false, hparams.quantization_version)
+        }
         ContainerType::Ggjt(_version) | ContainerType::Ggla(_version) => {
-            load_weights(reader, handler, true)
+            load_weights(reader, handler, true, hparams.quantization_version)
         }
     }
 }
@@ -211,10 +239,14 @@ pub fn load<E: Error, R: BufRead + Seek>(
 ///
 /// `align`
 /// align to 4 bytes before reading tensor weights
+///
+/// `quantization_version`
+/// the quantization version of the tensors, which determines their size
 fn load_weights<E: Error, R: BufRead + Seek>(
     reader: &mut R,
     handler: &mut impl LoadHandler<E>,
     align: bool,
+    quantization_version: u32,
 ) -> Result<(), LoadError<E>> {
     while has_data_left(reader)? {
         // load tensor header
@@ -269,6 +301,7 @@ fn load_weights<E: Error, R: BufRead + Seek>(
             n_elements,
             element_type: ftype,
             start_offset: offset_aligned,
+            quantization_version,
         };
         let n_bytes = tensor_info.calc_size();
         handler
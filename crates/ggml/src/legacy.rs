@@ -0,0 +1,147 @@
+//! The layouts of the quantized types in files written by older versions of `ggml`.
+//!
+//! The layout of a quantized type is determined by the quantization version of the file
+//! (see [QNT_VERSION](crate::QNT_VERSION)):
+//!
+//! - Version 0 (GGJT v1 and older containers) stores the scales of `q4_0`, `q4_1` and `q8_0`
+//!   as `f32`s, and interleaves the values of a block: the low and high nibbles of each byte
+//!   are consecutive values.
+//! - Version 1 (GGJT v2) stores the first half of a block in the low nibbles and the second
+//!   half in the high nibbles, like the current layout, but still has `f32` scales.
+//! - Version 2 (GGJT v3) is the current layout, with `f16` scales.
+//!
+//! The values themselves were quantized the same way in every version, so a tensor is
+//! [upgraded](upgrade) to the current layout without requantizing it; only the scales lose
+//! precision, as they are rounded to `f16`.
+//!
+//! Files of version 0 can also hold [Type::Q4_2] and [Type::Q4_3], which were removed from
+//! `ggml` altogether. They cannot be upgraded, only [dequantized](dequantize_q4_2).
+use crate::{f16_from_le_bytes, Type};
+
+/// The number of elements in a block of the types with a legacy layout.
+const QK: usize = 32;
+/// The number of elements in a block of [Type::Q4_2] and [Type::Q4_3].
+pub(crate) const QK4_2: usize = 16;
+
+/// Returns whether tensors of type `t` are stored in a different layout in files with
+/// `quantization_version` than in current files.
+pub fn is_legacy(t: Type, quantization_version: u32) -> bool {
+    match t {
+        Type::Q4_0 | Type::Q4_1 | Type::Q8_0 => quantization_version < 2,
+        Type::Q5_0 | Type::Q5_1 => quantization_version < 1,
+        _ => false,
+    }
+}
+
+/// The size of a block of `t` in bytes, in files with `quantization_version`.
+pub fn type_size(t: Type, quantization_version: u32) -> usize {
+    if !is_legacy(t, quantization_version) {
+        return crate::type_size(t);
+    }
+    match t {
+        // f32 d, 16 bytes of nibbles
+        Type::Q4_0 => 4 + QK / 2,
+        // f32 d, f32 m, 16 bytes of nibbles
+        Type::Q4_1 => 4 + 4 + QK / 2,
+        // f32 d, 32 bytes
+        Type::Q8_0 => 4 + QK,
+        // Only the order of the values changed.
+        _ => crate::type_size(t),
+    }
+}
+
+/// Converts `data`, which holds blocks of `t` from a file with `quantization_version`, to
+/// the current layout of `t`.
+///
+/// # Panics
+///
+/// Panics if `t` does not have a legacy layout in that version (see [is_legacy]).
+pub fn upgrade(t: Type, quantization_version: u32, data: &[u8]) -> Vec<u8> {
+    assert!(
+        is_legacy(t, quantization_version),
+        "{t} has no legacy layout in quantization version {quantization_version}"
+    );
+    let interleaved = quantization_version == 0;
+    let mut output =
+        Vec::with_capacity(data.len() / type_size(t, quantization_version) * crate::type_size(t));
+    for block in data.chunks_exact(type_size(t, quantization_version)) {
+        match t {
+            Type::Q4_0 => {
+                output.extend(f32_to_f16_bytes(&block[0..4]));
+                output.extend(nibbles(&block[4..], interleaved));
+            }
+            Type::Q4_1 => {
+                output.extend(f32_to_f16_bytes(&block[0..4]));
+                output.extend(f32_to_f16_bytes(&block[4..8]));
+                output.extend(nibbles(&block[8..], interleaved));
+            }
+            Type::Q8_0 => {
+                output.extend(f32_to_f16_bytes(&block[0..4]));
+                output.extend_from_slice(&block[4..]);
+            }
+            // The high bits in `qh` are already in the order of the values; the scales are
+            // the `f16`s before them.
+            Type::Q5_0 => {
+                output.extend_from_slice(&block[..6]);
+                output.extend(nibbles(&block[6..], interleaved));
+            }
+            Type::Q5_1 => {
+                output.extend_from_slice(&block[..8]);
+                output.extend(nibbles(&block[8..], interleaved));
+            }
+            _ => unreachable!("{t} has no legacy layout"),
+        }
+    }
+    output
+}
+
+fn f32_to_f16_bytes(bytes: &[u8]) -> [u8; 2] {
+    let value = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    half::f16::from_f32(value).to_le_bytes()
+}
+
+/// Reorders the 4-bit values of a block so that the first half is in the low nibbles and
+/// the second half in the high nibbles, if they are `interleaved`.
+fn nibbles(qs: &[u8], interleaved: bool) -> Vec<u8> {
+    if !interleaved {
+        return qs.to_vec();
+    }
+    let values: Vec<u8> = qs.iter().flat_map(|q| [q & 0xf, q >> 4]).collect();
+    let (low, high) = values.split_at(values.len() / 2);
+    low.iter().zip(high).map(|(l, h)| l | (h << 4)).collect()
+}
+
+/// Dequantizes `src`, which holds blocks of [Type::Q4_2], into `f32`s.
+///
+/// A block is an `f16` scale followed by the interleaved 4-bit values, which are offset by 8.
+pub fn dequantize_q4_2(src: &[u8]) -> Vec<f32> {
+    let mut output = Vec::with_capacity(src.len() / crate::type_size(Type::Q4_2) * QK4_2);
+    for block in src.chunks_exact(crate::type_size(Type::Q4_2)) {
+        let d = f16_from_le_bytes(&block[0..2]);
+        output.extend(
+            block[2..]
+                .iter()
+                .flat_map(|q| [q & 0xf, q >> 4])
+                .map(|q| (f32::from(q) - 8.0) * d),
+        );
+    }
+    output
+}
+
+/// Dequantizes `src`, which holds blocks of [Type::Q4_3], into `f32`s.
+///
+/// A block is an `f16` scale and an `f16` minimum followed by the interleaved 4-bit values.
+pub fn dequantize_q4_3(src: &[u8]) -> Vec<f32> {
+    let mut output = Vec::with_capacity(src.len() / crate::type_size(Type::Q4_3) * QK4_2);
+    for block in src.chunks_exact(crate::type_size(Type::Q4_3)) {
+        let d = f16_from_le_bytes(&block[0..2]);
+        let m = f16_from_le_bytes(&block[2..4]);
+        output.extend(
+            block[4..]
+                .iter()
+                .flat_map(|q| [q & 0xf, q >> 4])
+                .map(|q| f32::from(q) * d + m),
+        );
+    }
+    output
+}
@@ -15,10 +15,12 @@ use std::{
 mod context;
 mod tensor;
 
+pub mod accelerator;
 pub mod format;
+pub mod legacy;
 pub mod util;
 
-pub use context::Context;
+pub use context::{Context, FileOnlyTypeError};
 pub use tensor::Tensor;
 
 pub use ggml_sys as sys;
@@ -122,6 +124,9 @@ pub const QNT_VERSION: u32 = sys::GGML_QNT_VERSION;
 /// The factor by which to divide `ftype` to determine the current quantization version.
 pub const QNT_VERSION_FACTOR: u32 = sys::GGML_QNT_VERSION_FACTOR;
 
+/// The number of elements in a block of the k-quant types (e.g. [Type::Q4_K]).
+pub const QK_K: usize = sys::QK_K as usize;
+
 /// The size of a `ggml` object.
 pub const OBJECT_SIZE: usize = sys::GGML_OBJECT_SIZE;
 
@@ -162,7 +167,32 @@ pub enum Type {
     F16,
     /// Float 32-bit.
     F32,
+    /// Brain float 16-bit.
+    ///
+    /// `ggml` cannot compute with this type, so it only appears in model files. Tensors of
+    /// this type must be converted to [Type::F16] or [Type::F32] before they are used.
+    BF16,
+    /// Quantized 4-bit (type 2), in blocks of 16. Removed from `ggml` before quantization
+    /// version 1.
+    ///
+    /// Like [Type::BF16], this type only appears in model files. See
+    /// [legacy::dequantize_q4_2].
+    #[allow(non_camel_case_types)]
+    Q4_2,
+    /// Quantized 4-bit (type 3), in blocks of 16. Removed from `ggml` before quantization
+    /// version 1.
+    ///
+    /// Like [Type::BF16], this type only appears in model files. See
+    /// [legacy::dequantize_q4_3].
+    #[allow(non_camel_case_types)]
+    Q4_3,
 }
+/// The ID of [Type::BF16] in model files, from later versions of `ggml`.
+const GGML_TYPE_BF16: sys::ggml_type = 30;
+/// The ID of [Type::Q4_2] in model files, from earlier versions of `ggml`.
+const GGML_TYPE_Q4_2: sys::ggml_type = 4;
+/// The ID of [Type::Q4_3] in model files, from earlier versions of `ggml`.
+const GGML_TYPE_Q4_3: sys::ggml_type = 5;
 impl From<Type> for sys::ggml_type {
     fn from(t: Type) -> Self {
         match t {
@@ -180,6 +210,9 @@ impl From<Type> for sys::ggml_type {
             Type::I32 => sys::ggml_type_GGML_TYPE_I32,
             Type::F16 => sys::ggml_type_GGML_TYPE_F16,
             Type::F32 => sys::ggml_type_GGML_TYPE_F32,
+            Type::BF16 => GGML_TYPE_BF16,
+            Type::Q4_2 => GGML_TYPE_Q4_2,
+            Type::Q4_3 => GGML_TYPE_Q4_3,
         }
     }
 }
@@ -201,6 +234,9 @@ impl TryFrom<sys::ggml_type> for Type {
             sys::ggml_type_GGML_TYPE_I32 => Ok(Type::I32),
             sys::ggml_type_GGML_TYPE_F16 => Ok(Type::F16),
             sys::ggml_type_GGML_TYPE_F32 => Ok(Type::F32),
+            GGML_TYPE_BF16 => Ok(Type::BF16),
+            GGML_TYPE_Q4_2 => Ok(Type::Q4_2),
+            GGML_TYPE_Q4_3 => Ok(Type::Q4_3),
 
             _ => Err(()),
         }
@@ -223,6 +259,9 @@ impl std::fmt::Display for Type {
             Type::I32 => write!(f, "i32"),
             Type::F16 => write!(f, "f16"),
             Type::F32 => write!(f, "f32"),
+            Type::BF16 => write!(f, "bf16"),
+            Type::Q4_2 => write!(f, "q4_2"),
+            Type::Q4_3 => write!(f, "q4_3"),
         }
     }
 }
@@ -244,8 +283,17 @@ impl Type {
             Type::I32 => false,
             Type::F16 => false,
             Type::F32 => false,
+            Type::BF16 => false,
+            Type::Q4_2 => true,
+            Type::Q4_3 => true,
         }
     }
+
+    /// Returns whether this type only appears in model files, as this version of `ggml`
+    /// cannot compute with it. Tensors of these types must be converted when loaded.
+    pub fn is_file_only(&self) -> bool {
+        matches!(self, Type::BF16 | Type::Q4_2 | Type::Q4_3)
+    }
 }
 
 /// A buffer of memory that can be used as a scratch buffer for a [Context].
@@ -307,21 +355,115 @@ impl ComputationGraph {
     pub fn build_forward_expand(&mut self, tensor: &Tensor) {
         unsafe { sys::ggml_build_forward_expand(&mut self.inner, tensor.ptr.as_ptr()) }
     }
+
+    /// Describes every tensor in this graph: first the leaves (inputs and weights), then the
+    /// computed tensors in the order they are evaluated.
+    ///
+    /// # Safety
+    ///
+    /// The contexts owning the tensors of the graph must still be alive.
+    pub unsafe fn nodes(&self) -> Vec<GraphNode> {
+        let leafs = &self.inner.leafs[..i32_to_usize(self.inner.n_leafs)];
+        let nodes = &self.inner.nodes[..i32_to_usize(self.inner.n_nodes)];
+        let indices: std::collections::HashMap<*mut sys::ggml_tensor, usize> = leafs
+            .iter()
+            .chain(nodes)
+            .enumerate()
+            .map(|(i, t)| (*t, i))
+            .collect();
+
+        leafs
+            .iter()
+            .chain(nodes)
+            .map(|&t| {
+                let t = &*t;
+                let shape: Vec<usize> = t.ne[..i32_to_usize(t.n_dims)]
+                    .iter()
+                    .map(|&n| i64_to_usize(n))
+                    .collect();
+                let inputs = [t.src0, t.src1]
+                    .into_iter()
+                    .chain(t.opt)
+                    .filter_map(|src| indices.get(&src).copied())
+                    .collect();
+                let elements: u64 = t.ne.iter().map(|&n| n as u64).product();
+                let flops = match t.op {
+                    sys::ggml_op_GGML_OP_MUL_MAT => 2 * (*t.src0).ne[0] as u64 * elements,
+                    sys::ggml_op_GGML_OP_NONE
+                    | sys::ggml_op_GGML_OP_DUP
+                    | sys::ggml_op_GGML_OP_CPY
+                    | sys::ggml_op_GGML_OP_CONT
+                    | sys::ggml_op_GGML_OP_RESHAPE
+                    | sys::ggml_op_GGML_OP_VIEW
+                    | sys::ggml_op_GGML_OP_PERMUTE
+                    | sys::ggml_op_GGML_OP_TRANSPOSE
+                    | sys::ggml_op_GGML_OP_GET_ROWS
+                    | sys::ggml_op_GGML_OP_REPEAT => 0,
+                    _ => elements,
+                };
+
+                GraphNode {
+                    name: std::ffi::CStr::from_ptr(t.name.as_ptr())
+                        .to_string_lossy()
+                        .into_owned(),
+                    op: std::ffi::CStr::from_ptr(sys::ggml_op_name(t.op))
+                        .to_string_lossy()
+                        .into_owned(),
+                    element_type: Type::try_from(t.type_).ok(),
+                    shape,
+                    inputs,
+                    flops,
+                }
+            })
+            .collect()
+    }
+}
+
+/// A tensor in a [ComputationGraph]. See [ComputationGraph::nodes].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphNode {
+    /// The name of the tensor, or an empty string if it was not named.
+    pub name: String,
+    /// The operation that computes the tensor (e.g. `MUL_MAT`), or `NONE` for leaves.
+    pub op: String,
+    /// The element type of the tensor, or `None` if it is not supported by this crate.
+    pub element_type: Option<Type>,
+    /// The number of elements in each dimension of the tensor.
+    pub shape: Vec<usize>,
+    /// The indices of the nodes the operation reads from.
+    pub inputs: Vec<usize>,
+    /// An estimate of the floating-point operations needed to compute the tensor: two per
+    /// multiply-add for matrix multiplications, none for operations that only move data,
+    /// and one per element for everything else.
+    pub flops: u64,
 }
 
 /// The size of `t` as bytes.
 pub fn type_size(t: Type) -> usize {
-    unsafe { sys::ggml_type_size(t.into()) }
+    match t {
+        // Unknown to this version of ggml.
+        Type::BF16 => 2,
+        Type::Q4_2 => 2 + legacy::QK4_2 / 2,
+        Type::Q4_3 => 2 + 2 + legacy::QK4_2 / 2,
+        _ => unsafe { sys::ggml_type_size(t.into()) },
+    }
 }
 
 /// [type_size]/[blck_size] as float.
 pub fn type_sizef(x: Type) -> f64 {
-    (unsafe { sys::ggml_type_sizef(x.into()) }) as f64
+    match x {
+        Type::BF16 | Type::Q4_2 | Type::Q4_3 => type_size(x) as f64 / blck_size(x) as f64,
+        _ => (unsafe { sys::ggml_type_sizef(x.into()) }) as f64,
+    }
 }
 
 /// The size of a block for `t`. Only relevant for quantized types.
 pub fn blck_size(t: Type) -> usize {
-    i32_to_usize(unsafe { sys::ggml_blck_size(t.into()) })
+    match t {
+        Type::BF16 => 1,
+        Type::Q4_2 | Type::Q4_3 => legacy::QK4_2,
+        _ => i32_to_usize(unsafe { sys::ggml_blck_size(t.into()) }),
+    }
 }
 
 fn usize_to_i32(val: usize) -> i32 {
@@ -388,6 +530,91 @@ pub fn quantize_q8_0(src: &[f32], n_elements: usize, n_elements_0: usize) -> Qua
     quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q8_0)
 }
 
+/// Quantizes `src` into `dst` using `q2_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src` and a multiple of [QK_K].
+pub fn quantize_q2_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q2_K)
+}
+
+/// Quantizes `src` into `dst` using `q3_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src` and a multiple of [QK_K].
+pub fn quantize_q3_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q3_K)
+}
+
+/// Quantizes `src` into `dst` using `q4_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src` and a multiple of [QK_K].
+pub fn quantize_q4_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q4_K)
+}
+
+/// Quantizes `src` into `dst` using `q5_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src` and a multiple of [QK_K].
+pub fn quantize_q5_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q5_K)
+}
+
+/// Quantizes `src` into `dst` using `q6_K` quantization.
+///
+/// You must ensure that `src.len() == n_elements`, and `n_elements_0`
+/// is the first dimension of `src` and a multiple of [QK_K].
+pub fn quantize_q6_k(src: &[f32], n_elements: usize, n_elements_0: usize) -> QuantizationResult {
+    quantize_impl(src, n_elements, n_elements_0, sys::ggml_quantize_q6_K)
+}
+
+/// Dequantizes `src`, which holds little-endian blocks of `t`, into `f32`s.
+///
+/// Returns `None` if `t` cannot be converted to `f32`s, like [Type::I32] and [Type::Q8_1],
+/// which `ggml` only uses for intermediate values.
+pub fn dequantize(t: Type, src: &[u8]) -> Option<Vec<f32>> {
+    let output = match t {
+        Type::F32 => src
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        Type::F16 => src.chunks_exact(2).map(f16_from_le_bytes).collect(),
+        Type::BF16 => src
+            .chunks_exact(2)
+            .map(|b| half::bf16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(),
+        Type::Q4_2 => legacy::dequantize_q4_2(src),
+        Type::Q4_3 => legacy::dequantize_q4_3(src),
+        _ => {
+            // `ggml` fills the lookup tables that the dequantization functions use when the
+            // first context is created.
+            static INIT_TABLES: std::sync::Once = std::sync::Once::new();
+            INIT_TABLES.call_once(|| drop(Context::init(1024, false)));
+
+            let fns =
+                unsafe { sys::ggml_internal_get_quantize_fn(sys::ggml_type::from(t) as usize) };
+            let dequantize_row = fns.dequantize_row_q?;
+            let n_elements = src.len() / type_size(t) * blck_size(t);
+            let mut output = vec![0.0; n_elements];
+            unsafe {
+                dequantize_row(
+                    src.as_ptr().cast(),
+                    output.as_mut_ptr(),
+                    usize_to_i32(n_elements),
+                )
+            };
+            output
+        }
+    };
+    Some(output)
+}
+
+fn f16_from_le_bytes(bytes: &[u8]) -> f32 {
+    half::f16::from_le_bytes([bytes[0], bytes[1]]).to_f32()
+}
+
 fn quantize_impl(
     src: &[f32],
     n_elements: usize,
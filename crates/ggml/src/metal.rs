@@ -49,28 +49,33 @@ impl MetalContext {
             unsafe {
                 let raw_context = from_context.ptr.as_ptr();
 
-                let (data_ptr, data_size): (*mut c_void, usize) =
-                    if let Some(ref mmap) = from_context.mmap {
+                let buffers: Vec<(*mut c_void, usize)> = if from_context.mmaps.is_empty() {
+                    vec![(
+                        ggml_sys::ggml_get_mem_buffer(raw_context),
+                        ggml_sys::ggml_get_mem_size(raw_context),
+                    )]
+                } else {
+                    from_context
+                        .mmaps
+                        .iter()
                         // This is a bit naughty...
-                        (mmap.as_ptr().cast_mut().cast(), mmap.len())
-                    } else {
-                        (
-                            ggml_sys::ggml_get_mem_buffer(raw_context),
-                            ggml_sys::ggml_get_mem_size(raw_context),
-                        )
-                    };
+                        .map(|mmap| (mmap.as_ptr().cast_mut().cast(), mmap.len()))
+                        .collect()
+                };
 
                 let max_size = ggml_sys::ggml_get_max_tensor_size(raw_context);
-                assert!(
-                    metal::ggml_metal_add_buffer(
-                        self.ptr.as_ptr(),
-                        "wt\0".as_ptr().cast(), // FIXME provide an actual name
-                        data_ptr,
-                        data_size,
-                        max_size
-                    ),
-                    "Could not add weight buffer to metal context"
-                );
+                for (data_ptr, data_size) in buffers {
+                    assert!(
+                        metal::ggml_metal_add_buffer(
+                            self.ptr.as_ptr(),
+                            "wt\0".as_ptr().cast(), // FIXME provide an actual name
+                            data_ptr,
+                            data_size,
+                            max_size
+                        ),
+                        "Could not add weight buffer to metal context"
+                    );
+                }
             }
         }
     }
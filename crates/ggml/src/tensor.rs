@@ -1,6 +1,6 @@
 use std::{os::raw::c_void, ptr::NonNull, sync::Weak};
 
-use crate::{i64_to_usize, sys, Type};
+use crate::{accelerator::Backend, i64_to_usize, sys, Type};
 
 /// Tensors are owned by the context. A tensor is alive as long as the
 /// underlying context it was created with is alive.
@@ -23,6 +23,12 @@ impl Tensor {
         }
     }
 
+    /// Returns the underlying `ggml_tensor` pointer. As shared copies of a tensor
+    /// (see [Tensor::share]) have the same pointer, this can be used to identify tensors.
+    pub fn as_ptr(&self) -> *mut sys::ggml_tensor {
+        self.ptr.as_ptr()
+    }
+
     fn with_alive_ctx<U>(&self, mut f: impl FnMut() -> U) -> U {
         if let Some(_ctx) = self.ctx.upgrade() {
             f()
@@ -95,6 +101,11 @@ impl Tensor {
         self.with_alive_ctx(|| unsafe { *self.ptr.as_ptr() }.type_.try_into().unwrap())
     }
 
+    /// Where the data of the tensor is stored. See [Context::offload](crate::Context::offload).
+    pub fn backend(&self) -> Backend {
+        self.with_alive_ctx(|| unsafe { *self.ptr.as_ptr() }.backend.try_into().unwrap())
+    }
+
     /// The size of the element type in bytes.
     pub fn element_size(&self) -> usize {
         self.with_alive_ctx(|| unsafe { sys::ggml_element_size(self.ptr.as_ptr()) })
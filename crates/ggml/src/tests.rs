@@ -57,6 +57,33 @@ fn can_roundtrip_loader_and_saver_ggjt_v3() {
     roundtrip_test(format::SaveContainerType::GgjtV3, tokenizer).unwrap();
 }
 
+#[test]
+fn legacy_blocks_are_upgraded_to_the_current_layout() {
+    let values: Vec<u8> = (0..32).map(|i| (i * 7 % 16) as u8).collect();
+    let mut block = 0.5f32.to_le_bytes().to_vec();
+    block.extend(values.chunks_exact(2).map(|v| v[0] | (v[1] << 4)));
+    assert_eq!(legacy::type_size(Type::Q4_0, 0), block.len());
+
+    let mut expected = half::f16::from_f32(0.5).to_le_bytes().to_vec();
+    expected.extend((0..16).map(|i| values[i] | (values[i + 16] << 4)));
+    assert_eq!(legacy::upgrade(Type::Q4_0, 0, &block), expected);
+    assert_eq!(expected.len(), type_size(Type::Q4_0));
+
+    // Version 1 only changed the scales.
+    assert!(!legacy::is_legacy(Type::Q5_0, 1));
+    assert!(!legacy::is_legacy(Type::Q4_0, QNT_VERSION));
+
+    // The removed types can only be dequantized.
+    let mut block = half::f16::from_f32(0.5).to_le_bytes().to_vec();
+    block.extend(values[..16].chunks_exact(2).map(|v| v[0] | (v[1] << 4)));
+    assert_eq!(block.len(), type_size(Type::Q4_2));
+    let expected: Vec<f32> = values[..16]
+        .iter()
+        .map(|&v| (f32::from(v) - 8.0) * 0.5)
+        .collect();
+    assert_eq!(legacy::dequantize_q4_2(&block), expected);
+}
+
 fn roundtrip_test(
     save_container_type: format::SaveContainerType,
     tokenizer: Vec<(Vec<u8>, f32)>,
@@ -198,6 +225,7 @@ impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
                 .tokenizer_size
                 .try_into()
                 .unwrap(),
+            quantization_version: crate::QNT_VERSION,
         })
     }
 
@@ -214,3 +242,37 @@ impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
         Ok(())
     }
 }
+
+#[test]
+fn can_dequantize() {
+    let src: Vec<f32> = (0..QK_K).map(|i| (i as f32 - 128.0) / 64.0).collect();
+    for (t, quantized) in [
+        (Type::Q8_0, quantize_q8_0(&src, src.len(), src.len()).output),
+        (Type::Q6_K, quantize_q6_k(&src, src.len(), src.len()).output),
+    ] {
+        let output = dequantize(t, &quantized).unwrap();
+        assert_eq!(output.len(), src.len());
+        for (a, b) in src.iter().zip(&output) {
+            assert!((a - b).abs() < 0.05, "{t}: {a} != {b}");
+        }
+    }
+
+    let f16: Vec<u8> = [1.5f32, -2.0]
+        .iter()
+        .flat_map(|v| half::f16::from_f32(*v).to_le_bytes())
+        .collect();
+    assert_eq!(dequantize(Type::F16, &f16), Some(vec![1.5, -2.0]));
+    assert_eq!(dequantize(Type::I32, &[0; 4]), None);
+}
+
+#[test]
+fn file_only_tensors_cannot_be_created() {
+    let context = Context::init(1024 * 1024, true);
+    let tensor = context.try_new_tensor(Type::F16, &[4, 2]).unwrap();
+    assert_eq!(tensor.get_ne()[..2], [4, 2]);
+    assert_eq!(tensor.get_type(), Type::F16);
+    assert_eq!(
+        context.try_new_tensor(Type::BF16, &[4, 2]).err(),
+        Some(FileOnlyTypeError(Type::BF16))
+    );
+}
@@ -23,13 +23,17 @@ fn main() {
     let is_release = env::var("PROFILE").unwrap() == "release";
     let compiler = build.get_compiler();
 
+    // Use the target OS rather than `cfg!` here, as `cfg!` reflects the host when
+    // cross-compiling (e.g. building for Android or iOS from macOS).
+    let is_apple_target = matches!(target_os.as_str(), "macos" | "ios");
+
     // Enable accelerators
     let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR is not defined"));
     if cfg_cublas() && !cfg!(target_os = "macos") {
         enable_cublas(build, &out_dir);
     } else if cfg_clblast() {
-        enable_clblast(build);
-    } else if cfg!(target_os = "macos") {
+        enable_clblast(build, &out_dir);
+    } else if is_apple_target {
         if cfg_metal() {
             enable_metal(build, &out_dir);
         } else {
@@ -90,6 +94,29 @@ fn main() {
                 build.flag("-pthread");
             }
         }
+        "wasm32" => {
+            // ggml has WebAssembly SIMD kernels, which need the target to enable `simd128`
+            // (e.g. with `RUSTFLAGS="-C target-feature=+simd128"`).
+            let target_features = env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+            if target_features
+                .split(',')
+                .any(|feature| feature == "simd128")
+            {
+                build.flag("-msimd128");
+            }
+            if target_features
+                .split(',')
+                .any(|feature| feature == "atomics")
+            {
+                build.flag("-pthread");
+            }
+            // ggml measures time with `clock()`, which WASI only provides through an
+            // emulation library.
+            if target_os == "wasi" {
+                build.define("_WASI_EMULATED_PROCESS_CLOCKS", None);
+                println!("cargo:rustc-link-lib=wasi-emulated-process-clocks");
+            }
+        }
         _ => {}
     }
 
@@ -162,7 +189,9 @@ fn lib_path(prefix: &str) -> String {
     .to_string()
 }
 
-fn enable_clblast(build: &mut cc::Build) {
+fn enable_clblast(build: &mut cc::Build, out_dir: &Path) {
+    const GGML_OPENCL_PATH: &str = "llama-cpp/ggml-opencl.cpp";
+
     println!("cargo:rustc-link-lib=clblast");
     println!("cargo:rustc-link-lib=OpenCL");
 
@@ -171,7 +200,47 @@ fn enable_clblast(build: &mut cc::Build) {
         println!(r"cargo:rustc-link-lib=dylib=stdc++");
     }
 
-    build.file("llama-cpp/ggml-opencl.cpp");
+    // HACK: patch ggml-opencl.cpp so that it keeps the names of the platform and device
+    // that it selects, which it otherwise only prints, so that they can be reported.
+    let ggml_opencl_path = {
+        let ggml_opencl =
+            std::fs::read_to_string(GGML_OPENCL_PATH).expect("Could not read ggml-opencl.cpp");
+
+        let init_needle = "void ggml_cl_init(void) {";
+        let selection_needle = "    device = default_device->id;\n";
+        if !ggml_opencl.contains(init_needle) || !ggml_opencl.contains(selection_needle) {
+            panic!("ggml-opencl.cpp does not contain the needles to be replaced; the patching logic needs to be reinvestigated. Contact a `llm` developer!");
+        }
+
+        let ggml_opencl = ggml_opencl
+            .replace(
+                init_needle,
+                &format!(
+                    r#"static char ggml_cl_platform_name[128] = "";
+static char ggml_cl_device_name[128] = "";
+extern "C" const char * ggml_cl_selected_platform(void) {{ return ggml_cl_platform_name; }}
+extern "C" const char * ggml_cl_selected_device(void) {{ return ggml_cl_device_name; }}
+
+{init_needle}"#
+                ),
+            )
+            .replace(
+                selection_needle,
+                &format!(
+                    r#"{selection_needle}    snprintf(ggml_cl_platform_name, sizeof(ggml_cl_platform_name), "%s", default_device->platform->name);
+    snprintf(ggml_cl_device_name, sizeof(ggml_cl_device_name), "%s", default_device->name);
+"#
+                ),
+            );
+
+        let patched_ggml_opencl_path = out_dir.join("ggml-opencl.cpp");
+        std::fs::write(&patched_ggml_opencl_path, ggml_opencl)
+            .expect("Could not write temporary patched ggml-opencl.cpp");
+
+        patched_ggml_opencl_path
+    };
+
+    build.file(ggml_opencl_path);
     build.flag("-DGGML_USE_CLBLAST");
 
     let clblast_include_path = include_path("CLBLAST");
@@ -0,0 +1,195 @@
+//! Benchmarks the GGML kernels that dominate inference, on graphs built like those of the
+//! LLaMA model with random weights: appending to the key/value memory, and evaluating a
+//! whole layer. The sizes are those of a small model, so that a run stays short.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use llm_base::ggml::{self, ComputationGraph, Context, Tensor};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+const N_EMBD: usize = 512;
+const N_HEAD: usize = 8;
+const N_FF: usize = 1376;
+const N_CTX: usize = 512;
+/// The number of tokens already in the memory when the batch is evaluated.
+const N_PAST: usize = 256;
+const BATCH_SIZES: [usize; 3] = [1, 8, 32];
+
+fn n_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Returns a tensor of `ne0 x ne1` random values in `[-0.1, 0.1)`.
+fn random_tensor(ctx: &Context, rng: &mut impl Rng, ne0: usize, ne1: usize) -> Tensor {
+    let mut tensor = ctx.new_tensor_2d(ggml::Type::F32, ne0, ne1);
+    let values: Vec<f32> = (0..ne0 * ne1).map(|_| rng.gen_range(-0.1..0.1)).collect();
+    // SAFETY: the tensor was just allocated with room for the values, and is not used yet.
+    unsafe { tensor.write_data(bytemuck::cast_slice(&values)) };
+    tensor
+}
+
+/// Returns the zeroed key and value memories of one layer.
+fn kv_memory(ctx: &Context) -> (Tensor, Tensor) {
+    let mut memory_k = ctx.new_tensor_1d(ggml::Type::F16, N_EMBD * N_CTX);
+    let mut memory_v = ctx.new_tensor_1d(ggml::Type::F16, N_EMBD * N_CTX);
+    memory_k.zero_data();
+    memory_v.zero_data();
+    (memory_k, memory_v)
+}
+
+/// Adds to `graph` the copy of `k` and `v`, `[N_EMBD, n_batch]`, to the memories at
+/// [N_PAST], with the values transposed.
+fn append_kv(
+    ctx: &Context,
+    graph: &mut ComputationGraph,
+    (memory_k, memory_v): (&Tensor, &Tensor),
+    k: &Tensor,
+    v: &Tensor,
+    n_batch: usize,
+) {
+    let k_view = ctx.op_view_1d(
+        memory_k,
+        n_batch * N_EMBD,
+        memory_k.element_size() * N_EMBD * N_PAST,
+    );
+    let v_view = ctx.op_view_2d(
+        memory_v,
+        (n_batch, N_EMBD),
+        N_CTX * memory_v.element_size(),
+        N_PAST * memory_v.element_size(),
+    );
+    graph.build_forward_expand(&ctx.op_cpy(k, &k_view));
+    graph.build_forward_expand(&ctx.op_cpy(&ctx.op_transpose(v), &v_view));
+}
+
+fn kv_append(c: &mut Criterion) {
+    let mut rng = ChaCha12Rng::seed_from_u64(0);
+    let mut group = c.benchmark_group("kv_append");
+    for n_batch in BATCH_SIZES {
+        let ctx = Context::init(16 * 1024 * 1024, true);
+        let (memory_k, memory_v) = kv_memory(&ctx);
+        let k = random_tensor(&ctx, &mut rng, N_EMBD, n_batch);
+        let v = random_tensor(&ctx, &mut rng, N_EMBD, n_batch);
+        let mut graph = ComputationGraph::new(n_threads());
+        append_kv(&ctx, &mut graph, (&memory_k, &memory_v), &k, &v, n_batch);
+
+        group.throughput(Throughput::Elements(n_batch as u64));
+        group.bench_function(BenchmarkId::from_parameter(n_batch), |b| {
+            b.iter(|| ctx.graph_compute(&mut graph))
+        });
+    }
+    group.finish();
+}
+
+/// The weights of a LLaMA layer.
+struct Layer {
+    attention_norm: Tensor,
+    wq: Tensor,
+    wk: Tensor,
+    wv: Tensor,
+    wo: Tensor,
+    ffn_norm: Tensor,
+    w1: Tensor,
+    w2: Tensor,
+    w3: Tensor,
+}
+impl Layer {
+    fn random(ctx: &Context, rng: &mut impl Rng) -> Self {
+        Self {
+            attention_norm: random_tensor(ctx, rng, N_EMBD, 1),
+            wq: random_tensor(ctx, rng, N_EMBD, N_EMBD),
+            wk: random_tensor(ctx, rng, N_EMBD, N_EMBD),
+            wv: random_tensor(ctx, rng, N_EMBD, N_EMBD),
+            wo: random_tensor(ctx, rng, N_EMBD, N_EMBD),
+            ffn_norm: random_tensor(ctx, rng, N_EMBD, 1),
+            w1: random_tensor(ctx, rng, N_EMBD, N_FF),
+            w2: random_tensor(ctx, rng, N_FF, N_EMBD),
+            w3: random_tensor(ctx, rng, N_EMBD, N_FF),
+        }
+    }
+
+    /// Adds the evaluation of the layer for `input`, `[N_EMBD, n_batch]`, to `graph`.
+    fn build(
+        &self,
+        ctx: &Context,
+        graph: &mut ComputationGraph,
+        (memory_k, memory_v): (&Tensor, &Tensor),
+        input: &Tensor,
+        n_batch: usize,
+    ) {
+        let head_dim = N_EMBD / N_HEAD;
+        let n_kv = N_PAST + n_batch;
+
+        // Self-attention.
+        let current = ctx.op_mul(&ctx.op_rms_norm(input), &self.attention_norm);
+        let rope = |weight: &Tensor| {
+            ctx.op_rope_inplace(
+                &ctx.op_reshape_3d(&ctx.op_mul_mat(weight, &current), head_dim, N_HEAD, n_batch),
+                N_PAST,
+                head_dim,
+                0,
+            )
+        };
+        let q = rope(&self.wq);
+        let k = rope(&self.wk);
+        let v = ctx.op_mul_mat(&self.wv, &current);
+        append_kv(ctx, graph, (memory_k, memory_v), &k, &v, n_batch);
+
+        let keys = ctx.op_permute(
+            &ctx.op_reshape_3d(
+                &ctx.op_view_1d(memory_k, n_kv * N_EMBD, 0),
+                head_dim,
+                N_HEAD,
+                n_kv,
+            ),
+            (0, 2, 1, 3),
+        );
+        let scores = ctx.op_mul_mat(&keys, &ctx.op_permute(&q, (0, 2, 1, 3)));
+        let scale = ctx.new_f32(1.0 / (head_dim as f32).sqrt());
+        let weights = ctx.op_soft_max_inplace(
+            &ctx.op_diag_mask_inf_inplace(&ctx.op_scale_inplace(&scores, &scale), N_PAST),
+        );
+        let values = ctx.op_view_3d(
+            memory_v,
+            (n_kv, head_dim, N_HEAD),
+            (
+                N_CTX * memory_v.element_size(),
+                N_CTX * memory_v.element_size() * head_dim,
+            ),
+            0,
+        );
+        let attention = ctx.op_cpy(
+            &ctx.op_permute(&ctx.op_mul_mat(&values, &weights), (0, 2, 1, 3)),
+            &ctx.new_tensor_2d(ggml::Type::F32, N_EMBD, n_batch),
+        );
+        let feed_forward_input = ctx.op_add(&ctx.op_mul_mat(&self.wo, &attention), input);
+
+        // Feed-forward network.
+        let current = ctx.op_mul(&ctx.op_rms_norm(&feed_forward_input), &self.ffn_norm);
+        let gate = ctx.op_mul_mat(&self.w3, &current);
+        let current = ctx.op_mul(&ctx.op_silu(&ctx.op_mul_mat(&self.w1, &current)), &gate);
+        let output = ctx.op_add(&ctx.op_mul_mat(&self.w2, &current), &feed_forward_input);
+        graph.build_forward_expand(&output);
+    }
+}
+
+fn layer(c: &mut Criterion) {
+    let mut rng = ChaCha12Rng::seed_from_u64(0);
+    let mut group = c.benchmark_group("layer");
+    for n_batch in BATCH_SIZES {
+        let ctx = Context::init(64 * 1024 * 1024, true);
+        let layer = Layer::random(&ctx, &mut rng);
+        let (memory_k, memory_v) = kv_memory(&ctx);
+        let input = random_tensor(&ctx, &mut rng, N_EMBD, n_batch);
+        let mut graph = ComputationGraph::new(n_threads());
+        layer.build(&ctx, &mut graph, (&memory_k, &memory_v), &input, n_batch);
+
+        group.throughput(Throughput::Elements(n_batch as u64));
+        group.bench_function(BenchmarkId::from_parameter(n_batch), |b| {
+            b.iter(|| ctx.graph_compute(&mut graph))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, kv_append, layer);
+criterion_main!(benches);
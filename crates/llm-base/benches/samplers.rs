@@ -0,0 +1,50 @@
+//! Benchmarks the samplers on synthetic logits, as if for one token of a model with a
+//! LLaMA-sized vocabulary.
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use llm_base::{
+    samplers::{Dry, Mirostat2, TopPTopK, Typical},
+    Sampler, SamplerState, TokenId,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+const N_VOCAB: usize = 32000;
+const N_PREVIOUS: usize = 512;
+
+fn samplers(c: &mut Criterion) {
+    let mut rng = ChaCha12Rng::seed_from_u64(0);
+    let logits: Vec<f32> = (0..N_VOCAB).map(|_| rng.gen_range(-10.0..10.0)).collect();
+    // Repetitive, so that the repetition penalties have work to do.
+    let previous_tokens: Vec<TokenId> = (0..N_PREVIOUS).map(|i| (i % 64 * 37) as TokenId).collect();
+
+    let samplers: Vec<(&str, Arc<dyn Sampler>)> = vec![
+        ("top_p_top_k", Arc::new(TopPTopK::default())),
+        ("typical", Arc::new(Typical::default())),
+        ("mirostat2", Arc::new(Mirostat2::default())),
+        (
+            "dry",
+            Arc::new(Dry {
+                multiplier: 0.8,
+                exponent_base: 1.75,
+                allowed_length: 2,
+                sequence_breakers: Default::default(),
+                last_n: 0,
+                base: Arc::new(TopPTopK::default()),
+            }),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("sample");
+    for (name, sampler) in samplers {
+        let mut state = SamplerState::default();
+        group.bench_function(name, |b| {
+            b.iter(|| sampler.sample_with_state(&mut state, &previous_tokens, &logits, &mut rng))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, samplers);
+criterion_main!(benches);
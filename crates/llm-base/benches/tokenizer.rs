@@ -0,0 +1,68 @@
+//! Benchmarks the embedded tokenizer with a synthetic vocabulary, so that no model is needed.
+use std::collections::HashSet;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use llm_base::{EmbeddedTokenizer, TokenId, Tokenizer};
+
+const TEXT: &str = "The quick brown fox jumps over the lazy dog. Meanwhile, the tokenizer \
+    splits every sentence into the longest pieces of its vocabulary, one position at a time, \
+    and the benchmark measures how long that takes for prompts of various lengths. ";
+
+/// Returns a tokenizer with the special tokens, every byte, and the pieces of the words of
+/// [TEXT] of up to 8 bytes, like the vocabulary of a SentencePiece model.
+fn tokenizer() -> Tokenizer {
+    let mut pieces: Vec<Vec<u8>> = ["<unk>", "<s>", "</s>"]
+        .iter()
+        .map(|token| token.as_bytes().to_vec())
+        .chain((0..=u8::MAX).map(|byte| vec![byte]))
+        .collect();
+    let mut seen: HashSet<Vec<u8>> = pieces.iter().cloned().collect();
+    for word in TEXT.split_inclusive(' ') {
+        let word = word.as_bytes();
+        for start in 0..word.len() {
+            for end in start + 2..=word.len().min(start + 8) {
+                if seen.insert(word[start..end].to_vec()) {
+                    pieces.push(word[start..end].to_vec());
+                }
+            }
+        }
+    }
+
+    let mut tokenizer = EmbeddedTokenizer::default();
+    for (id, piece) in pieces.into_iter().enumerate() {
+        tokenizer.push_token(id as TokenId, piece, 0.0);
+    }
+    tokenizer.into()
+}
+
+fn tokenize(c: &mut Criterion) {
+    let tokenizer = tokenizer();
+    let mut group = c.benchmark_group("tokenize");
+    for repeats in [1, 16, 128] {
+        let text = TEXT.repeat(repeats);
+        group.throughput(Throughput::Bytes(text.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(text.len()), &text, |b, text| {
+            b.iter(|| tokenizer.tokenize(text, true).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let tokenizer = tokenizer();
+    let tokens: Vec<TokenId> = tokenizer
+        .tokenize(&TEXT.repeat(128), true)
+        .unwrap()
+        .into_iter()
+        .map(|(_, id)| id)
+        .collect();
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(tokens.len() as u64));
+    group.bench_function(BenchmarkId::from_parameter(tokens.len()), |b| {
+        b.iter(|| tokenizer.decode(tokens.clone(), true))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, tokenize, decode);
+criterion_main!(benches);
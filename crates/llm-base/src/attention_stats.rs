@@ -0,0 +1,98 @@
+//! Statistics of the attention of each head of the model, for interpretability tools.
+//!
+//! An [AttentionObserver] added to a session with
+//! [InferenceSession::add_attention_observer](crate::InferenceSession::add_attention_observer)
+//! is given, after each evaluation, the [HeadAttentionStats] of every head of every layer for
+//! the last token that was evaluated. The statistics are computed from the attention weights
+//! after the softmax, so they work the same for quantized models. Models that do not record
+//! their attention weights report none: LLaMA, GPT-J, GPT-NeoX and Falcon do.
+//!
+//! This module is only available with the `attention-stats` feature.
+use crate::TokenId;
+
+/// Statistics of the attention of one head for one token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadAttentionStats {
+    /// The index of the layer of the head.
+    pub layer: usize,
+    /// The index of the head in its layer.
+    pub head: usize,
+    /// The entropy of the attention weights, in nats. It is 0 when the head attends to a
+    /// single position, and `ln(n)` when it attends equally to all `n` positions.
+    pub entropy: f32,
+    /// The position in the key/value memory that the head attends to the most. Positions
+    /// before [InferenceSession::n_past](crate::InferenceSession::n_past) hold the
+    /// [tokens](crate::InferenceSession::tokens) of the session, and the following ones
+    /// the tokens that were evaluated.
+    pub top_source: usize,
+    /// The attention weight of [Self::top_source].
+    pub top_weight: f32,
+}
+
+/// Receives the [HeadAttentionStats] of the model after each evaluation. See the
+/// [module documentation](self).
+///
+/// It is implemented for closures taking the same arguments as [Self::observe].
+pub trait AttentionObserver: Send {
+    /// Receives the statistics of every head, layer by layer, for the last of the
+    /// `input_tokens` that were just evaluated.
+    fn observe(&mut self, input_tokens: &[TokenId], stats: &[HeadAttentionStats]);
+}
+impl<F: FnMut(&[TokenId], &[HeadAttentionStats]) + Send> AttentionObserver for F {
+    fn observe(&mut self, input_tokens: &[TokenId], stats: &[HeadAttentionStats]) {
+        self(input_tokens, stats)
+    }
+}
+
+/// Computes the statistics of the heads of `layer`, whose attention weights are `weights`:
+/// one row of `n_kv` weights per head.
+pub(crate) fn head_stats(layer: usize, weights: &[f32], n_kv: usize) -> Vec<HeadAttentionStats> {
+    weights
+        .chunks_exact(n_kv)
+        .enumerate()
+        .map(|(head, row)| {
+            let (top_source, top_weight) =
+                row.iter()
+                    .copied()
+                    .enumerate()
+                    .fold((0, f32::NEG_INFINITY), |top, (i, weight)| {
+                        if weight > top.1 {
+                            (i, weight)
+                        } else {
+                            top
+                        }
+                    });
+            let entropy = -row
+                .iter()
+                .filter(|&&weight| weight > 0.0)
+                .map(|&weight| weight * weight.ln())
+                .sum::<f32>();
+            HeadAttentionStats {
+                layer,
+                head,
+                entropy,
+                top_source,
+                top_weight,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entropy_and_top_source_of_each_head() {
+        let stats = head_stats(3, &[0.0, 1.0, 0.0, 0.25, 0.25, 0.5], 3);
+        assert_eq!(stats.len(), 2);
+
+        assert_eq!((stats[0].layer, stats[0].head), (3, 0));
+        assert_eq!(stats[0].entropy, 0.0);
+        assert_eq!((stats[0].top_source, stats[0].top_weight), (1, 1.0));
+
+        assert_eq!((stats[1].layer, stats[1].head), (3, 1));
+        assert!((stats[1].entropy - 1.5 * 2f32.ln()).abs() < 1e-6);
+        assert_eq!((stats[1].top_source, stats[1].top_weight), (2, 0.5));
+    }
+}
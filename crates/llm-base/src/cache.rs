@@ -0,0 +1,453 @@
+//! Caching the results of deterministic generation requests.
+//!
+//! Evaluation suites often run the same prompts with the same parameters over and over. When
+//! the sampling is seeded, the result only depends on the model, the prompt and the
+//! parameters, so a [GenerationCache] can return it without running the model again. It is
+//! consulted with [GenerationCache::get_or_insert_with] before inference, and holds any
+//! serializable result, such as the generated text.
+//!
+//! The cache is kept in memory, and optionally in a directory as well, one JSON file per
+//! entry, so that results survive restarts and can be shared between processes. Entries
+//! expire after the [time to live](CacheConfig::ttl) of the cache, and the least recently used
+//! ones are evicted when there are too many, or when they take too much space.
+//!
+//! The cache is best-effort: entries that cannot be read or written are logged and treated as
+//! missing. The cache is not available on WebAssembly, which has no clock.
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Identifies a generation request: the model, the prompt and the parameters it was run with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey([u8; 32]);
+impl CacheKey {
+    /// Creates the key of a request. `model_fingerprint` identifies the model, such as the
+    /// SHA-256 of its file, and `parameters` everything else the result depends on: the
+    /// sampler configuration, the seed and the limits on the output.
+    ///
+    /// [InferenceParameters](crate::InferenceParameters) cannot be serialized, as samplers
+    /// are trait objects, so `parameters` is usually the configuration they are built from.
+    pub fn new(
+        model_fingerprint: &str,
+        prompt: &str,
+        parameters: &impl Serialize,
+    ) -> Result<Self, serde_json::Error> {
+        let parameters = serde_json::to_vec(parameters)?;
+        let mut hasher = Sha256::new();
+        // Each part is prefixed with its length, so that no two requests hash the same
+        // sequence of bytes.
+        for part in [model_fingerprint.as_bytes(), prompt.as_bytes(), &parameters] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        Ok(Self(hasher.finalize().into()))
+    }
+}
+impl Display for CacheKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Configuration for a [GenerationCache].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// The maximum number of entries, in memory and in the directory.
+    pub max_entries: usize,
+    /// The maximum size of the entries, in bytes of JSON, in memory and in the directory.
+    pub max_bytes: usize,
+    /// How long entries are kept, or `None` to keep them until they are evicted.
+    pub ttl: Option<Duration>,
+}
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 1024,
+            max_bytes: 64 * 1024 * 1024,
+            ttl: None,
+        }
+    }
+}
+
+/// A cache of the results of generation requests. See the [module documentation](self).
+pub struct GenerationCache<V> {
+    config: CacheConfig,
+    directory: Option<PathBuf>,
+    state: Mutex<State<V>>,
+}
+
+struct State<V> {
+    entries: HashMap<CacheKey, Entry<V>>,
+    bytes: usize,
+    /// Incremented on every use, to find the least recently used entry.
+    clock: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    created: SystemTime,
+    bytes: usize,
+    used: u64,
+}
+
+/// An entry as stored in the directory.
+#[derive(Serialize, Deserialize)]
+struct StoredEntry<V> {
+    /// When the entry was created, in seconds since the Unix epoch.
+    created: u64,
+    value: V,
+}
+
+impl<V: Clone + Serialize + DeserializeOwned> GenerationCache<V> {
+    /// Creates a cache that is only kept in memory.
+    pub fn in_memory(config: CacheConfig) -> Self {
+        Self {
+            config,
+            directory: None,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                bytes: 0,
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Creates a cache that is also kept in `directory`, which is created if needed. The
+    /// entries already in the directory are used.
+    pub fn open(directory: impl Into<PathBuf>, config: CacheConfig) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory: Some(directory),
+            ..Self::in_memory(config)
+        })
+    }
+
+    /// Returns the cached result of `key`, if it has not expired.
+    pub fn get(&self, key: &CacheKey) -> Option<V> {
+        let mut state = self.lock();
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some(entry) = state.entries.get_mut(key) {
+            if !self.expired(entry.created) {
+                entry.used = clock;
+                return Some(entry.value.clone());
+            }
+            state.remove(key);
+            self.remove_file(key);
+            return None;
+        }
+
+        let path = self.path(key)?;
+        let stored: StoredEntry<V> = match fs::read(&path) {
+            Ok(json) => match serde_json::from_slice(&json) {
+                Ok(stored) => stored,
+                Err(err) => {
+                    log::warn!("Ignoring the unreadable cache entry {path:?}: {err}");
+                    return None;
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                log::warn!("Could not read the cache entry {path:?}: {err}");
+                return None;
+            }
+        };
+        let created = SystemTime::UNIX_EPOCH + Duration::from_secs(stored.created);
+        if self.expired(created) {
+            self.remove_file(key);
+            return None;
+        }
+        let bytes = serde_json::to_vec(&stored).map_or(0, |json| json.len());
+        let value = stored.value.clone();
+        state.insert(*key, stored.value, created, bytes);
+        self.evict_memory(&mut state);
+        Some(value)
+    }
+
+    /// Caches `value` as the result of `key`, replacing any previous one, and evicts the least
+    /// recently used entries if the cache is over its limits.
+    pub fn insert(&self, key: CacheKey, value: V) {
+        let created = SystemTime::now();
+        let stored = StoredEntry {
+            created: created
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            value,
+        };
+        let json = match serde_json::to_vec(&stored) {
+            Ok(json) => json,
+            Err(err) => {
+                log::warn!("Could not serialize the cache entry {key}: {err}");
+                return;
+            }
+        };
+
+        let mut state = self.lock();
+        if let Some(path) = self.path(&key) {
+            // Written to a temporary file first, so that readers never see half an entry.
+            let temporary = path.with_extension("tmp");
+            if let Err(err) =
+                fs::write(&temporary, &json).and_then(|()| fs::rename(&temporary, &path))
+            {
+                log::warn!("Could not write the cache entry {path:?}: {err}");
+            }
+            self.evict_directory();
+        }
+        state.insert(key, stored.value, created, json.len());
+        self.evict_memory(&mut state);
+    }
+
+    /// Returns the cached result of `key`, or computes it with `generate` and caches it.
+    /// Errors are returned as is, and not cached.
+    pub fn get_or_insert_with<E>(
+        &self,
+        key: CacheKey,
+        generate: impl FnOnce() -> Result<V, E>,
+    ) -> Result<V, E> {
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+        let value = generate()?;
+        self.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Removes every entry, from memory and from the directory.
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        state.entries.clear();
+        state.bytes = 0;
+        for (path, _, _) in self.stored_entries() {
+            if let Err(err) = fs::remove_file(&path) {
+                log::warn!("Could not remove the cache entry {path:?}: {err}");
+            }
+        }
+    }
+
+    /// The number of entries in memory.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Whether there are no entries in memory.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State<V>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn expired(&self, created: SystemTime) -> bool {
+        self.config.ttl.map_or(false, |ttl| {
+            created.elapsed().map_or(false, |elapsed| elapsed > ttl)
+        })
+    }
+
+    fn path(&self, key: &CacheKey) -> Option<PathBuf> {
+        Some(self.directory.as_ref()?.join(format!("{key}.json")))
+    }
+
+    fn remove_file(&self, key: &CacheKey) {
+        if let Some(path) = self.path(key) {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    log::warn!("Could not remove the cache entry {path:?}: {err}")
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn evict_memory(&self, state: &mut State<V>) {
+        while state.entries.len() > self.config.max_entries || state.bytes > self.config.max_bytes {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            state.remove(&oldest);
+        }
+    }
+
+    /// Removes the expired entries from the directory, then the oldest ones while it is over
+    /// the limits. The directory is listed every time, as other processes may share it.
+    fn evict_directory(&self) {
+        let mut entries = self.stored_entries();
+        entries.retain(|(path, created, _)| {
+            if !self.expired(*created) {
+                return true;
+            }
+            let _ = fs::remove_file(path);
+            false
+        });
+        entries.sort_by_key(|(_, created, _)| *created);
+
+        let mut bytes: u64 = entries.iter().map(|(_, _, bytes)| bytes).sum();
+        let mut count = entries.len();
+        for (path, _, size) in entries {
+            if count <= self.config.max_entries && bytes <= self.config.max_bytes as u64 {
+                break;
+            }
+            if let Err(err) = fs::remove_file(&path) {
+                log::warn!("Could not remove the cache entry {path:?}: {err}");
+            }
+            count -= 1;
+            bytes -= size;
+        }
+    }
+
+    /// The files of the directory with their modification time and size.
+    fn stored_entries(&self) -> Vec<(PathBuf, SystemTime, u64)> {
+        let Some(directory) = &self.directory else {
+            return vec![];
+        };
+        let read_dir = match fs::read_dir(directory) {
+            Ok(read_dir) => read_dir,
+            Err(err) => {
+                log::warn!("Could not list the cache directory {directory:?}: {err}");
+                return vec![];
+            }
+        };
+        read_dir
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let path = entry.path();
+                if !is_entry_file(&path) {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                Some((path, metadata.modified().ok()?, metadata.len()))
+            })
+            .collect()
+    }
+}
+
+impl<V> State<V> {
+    fn insert(&mut self, key: CacheKey, value: V, created: SystemTime, bytes: usize) {
+        self.remove(&key);
+        self.clock += 1;
+        self.bytes += bytes;
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                created,
+                bytes,
+                used: self.clock,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.bytes;
+        }
+    }
+}
+
+/// Whether `path` is named like an entry: the hex of a [CacheKey], with a `json` extension.
+fn is_entry_file(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |extension| extension == "json")
+        && path.file_stem().map_or(false, |stem| {
+            stem.len() == 64
+                && stem
+                    .to_string_lossy()
+                    .chars()
+                    .all(|c| c.is_ascii_hexdigit())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(prompt: &str) -> CacheKey {
+        CacheKey::new("model", prompt, &("seed", 1)).unwrap()
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let cache = GenerationCache::in_memory(CacheConfig {
+            max_entries: 2,
+            ..Default::default()
+        });
+        assert_ne!(key("a"), CacheKey::new("model", "a", &("seed", 2)).unwrap());
+
+        cache.insert(key("a"), "A".to_owned());
+        cache.insert(key("b"), "B".to_owned());
+        assert_eq!(cache.get(&key("a")).as_deref(), Some("A"));
+        cache.insert(key("c"), "C".to_owned());
+        assert_eq!(cache.get(&key("b")), None);
+        assert_eq!(cache.get(&key("a")).as_deref(), Some("A"));
+        assert_eq!(cache.get(&key("c")).as_deref(), Some("C"));
+
+        let mut generated = 0;
+        let mut generate = || -> Result<String, ()> {
+            generated += 1;
+            Ok("D".to_owned())
+        };
+        assert_eq!(
+            cache.get_or_insert_with(key("d"), &mut generate),
+            Ok("D".into())
+        );
+        assert_eq!(
+            cache.get_or_insert_with(key("d"), &mut generate),
+            Ok("D".into())
+        );
+        assert_eq!(generated, 1);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn entries_persist_in_the_directory_until_they_expire() {
+        let directory = std::env::temp_dir().join(format!("llm-cache-{}", std::process::id()));
+        let config = CacheConfig {
+            max_entries: 2,
+            ..Default::default()
+        };
+        {
+            let cache = GenerationCache::open(&directory, config).unwrap();
+            cache.clear();
+            cache.insert(key("a"), vec![1, 2]);
+            cache.insert(key("b"), vec![3]);
+            cache.insert(key("c"), vec![4]);
+        }
+
+        let cache = GenerationCache::<Vec<u32>>::open(&directory, config).unwrap();
+        assert_eq!(cache.stored_entries().len(), 2);
+        assert_eq!(cache.get(&key("c")), Some(vec![4]));
+        assert_eq!(cache.len(), 1);
+
+        let expiring = GenerationCache::<Vec<u32>>::open(
+            &directory,
+            CacheConfig {
+                ttl: Some(Duration::ZERO),
+                ..config
+            },
+        )
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(expiring.get(&key("c")), None);
+        assert_eq!(cache.get(&key("c")), Some(vec![4]));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
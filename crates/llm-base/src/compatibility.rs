@@ -0,0 +1,433 @@
+//! Checking whether a model file can be loaded, without loading it.
+//!
+//! GGML files do not record the architecture of their model, so a file is probed with the
+//! hyperparameters of an architecture and recognized by its tensor names
+//! ([KnownModel::distinctive_tensors]). Only the metadata of the file is read.
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{BufRead, Seek, SeekFrom},
+};
+
+use ggml::format::{FormatMagic, LoadError as FormatLoadError};
+
+use crate::{
+    loader::quantization_version, ContainerType, ElementType, FileType, Hyperparameters,
+    KnownModel, LoadError, Loader, Tokenizer,
+};
+
+/// The magic number of GGUF files, the successor of the GGML formats.
+const FILE_MAGIC_GGUF: u32 = 0x46554747;
+
+/// Something about a model file that prevents this build of `llm` from loading it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompatibilityIssue {
+    /// The file is not a GGML model file.
+    UnknownFormat {
+        /// The magic number at the start of the file.
+        magic: u32,
+    },
+    /// The file is in the GGUF format, which is not supported yet.
+    Gguf,
+    /// The file is in a version of its container format that is not supported, usually
+    /// because it is newer than this build.
+    UnsupportedContainerVersion {
+        /// The container format and version of the file.
+        container_type: ContainerType,
+    },
+    /// The file is a LoRA adapter, not a model.
+    LoraAdapter,
+    /// The `ftype` hyperparameter is not a known file type.
+    UnsupportedFileType {
+        /// The value of the hyperparameter.
+        ftype: i32,
+    },
+    /// The file has a tensor in a type that is not supported, such as a newer quantization
+    /// type.
+    UnsupportedElementType {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The type of the tensor, as stored in the file.
+        ftype: u32,
+    },
+    /// The quantized tensors of the file use a newer quantization format than this build
+    /// supports. Older formats are upgraded when the file is loaded.
+    UnsupportedQuantizationVersion {
+        /// The quantization version of the file.
+        version: u32,
+    },
+    /// The tensors of the file do not match any of the supported architectures.
+    UnknownArchitecture,
+    /// The file ends before all of its metadata or tensors, usually because it was not
+    /// downloaded or copied completely.
+    Truncated,
+    /// The file could not be read, because it is corrupt.
+    Unreadable {
+        /// Why the file could not be read.
+        reason: String,
+    },
+}
+impl CompatibilityIssue {
+    /// Whether the issue is with the container of the file, rather than with the model in it:
+    /// such issues are found whichever architecture the file is probed with.
+    pub fn is_format_issue(&self) -> bool {
+        matches!(
+            self,
+            Self::UnknownFormat { .. }
+                | Self::Gguf
+                | Self::UnsupportedContainerVersion { .. }
+                | Self::LoraAdapter
+        )
+    }
+}
+impl fmt::Display for CompatibilityIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownFormat { magic } => write!(
+                f,
+                "the file is not a GGML model (magic number {}); check that it is a model \
+                 file, and that it was downloaded completely",
+                FormatMagic(*magic)
+            ),
+            Self::Gguf => write!(
+                f,
+                "the file is in the GGUF format, which is not supported yet; use a GGJT \
+                 (GGML v3) version of the model instead. The IQ quantizations, which are \
+                 only published as GGUF, are not supported either"
+            ),
+            Self::UnsupportedContainerVersion { container_type } => write!(
+                f,
+                "the file is in the {container_type:?} format, which this version of llm does \
+                 not support; use a file in the GGJT v3 format, or update llm"
+            ),
+            Self::LoraAdapter => write!(
+                f,
+                "the file is a LoRA adapter; load it with a base model through \
+                 ModelParameters::lora_adapters"
+            ),
+            Self::UnsupportedFileType { ftype } => write!(
+                f,
+                "the file type {ftype} is not supported; the file may have been written by a \
+                 newer converter, so update llm or requantize the model"
+            ),
+            Self::UnsupportedElementType { tensor_name, ftype } => match iq_type_name(*ftype) {
+                Some(name) => write!(
+                    f,
+                    "the tensor `{tensor_name}` has the {name} type of the IQ quantizations, \
+                     which llm cannot load; use a k-quantized (such as Q4_K) version of the model"
+                ),
+                None => write!(
+                    f,
+                    "the tensor `{tensor_name}` has the unsupported type {ftype}; update llm, \
+                     or requantize the model with `llm quantize`"
+                ),
+            },
+            Self::UnsupportedQuantizationVersion { version } => write!(
+                f,
+                "the model uses quantization version {version}, but only versions up to {} \
+                 are supported; update llm, or requantize the model from its f16 version",
+                ggml::QNT_VERSION
+            ),
+            Self::UnknownArchitecture => write!(
+                f,
+                "the tensors of the file do not match any supported architecture; the \
+                 architecture may not be supported, or its feature may be disabled"
+            ),
+            Self::Truncated => write!(
+                f,
+                "the file ends early; check that it was downloaded or copied completely"
+            ),
+            Self::Unreadable { reason } => write!(
+                f,
+                "the file could not be read ({reason}); it may be corrupt"
+            ),
+        }
+    }
+}
+
+/// Something about a model file that does not prevent it from loading, but makes loading it
+/// slower or use more memory.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompatibilityNote {
+    /// The container of the file does not support memory mapping, so the whole model is
+    /// read into memory.
+    NoMmap {
+        /// The container format and version of the file.
+        container_type: ContainerType,
+    },
+    /// Some quantized tensors are in the layout of an older quantization version, and are
+    /// upgraded every time they are loaded (see [ggml::legacy]).
+    LegacyQuantization {
+        /// The quantization version of the file.
+        quantization_version: u32,
+    },
+    /// Some tensors are in types that `ggml` cannot compute with, and are converted every
+    /// time they are loaded.
+    ConvertedTensors {
+        /// The types of the converted tensors.
+        element_types: Vec<ElementType>,
+    },
+}
+impl fmt::Display for CompatibilityNote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoMmap { container_type } => write!(
+                f,
+                "the {container_type:?} format cannot be memory mapped, so the whole model is \
+                 read into memory; run `llm migrate` to rewrite it as GGJT v3"
+            ),
+            Self::LegacyQuantization {
+                quantization_version,
+            } => write!(
+                f,
+                "the tensors are in the layout of quantization version {quantization_version}, \
+                 and are upgraded every time the model is loaded; run `llm migrate` to upgrade \
+                 them once"
+            ),
+            Self::ConvertedTensors { element_types } => write!(
+                f,
+                "the {} tensors are converted when the model is loaded, so it cannot be memory \
+                 mapped",
+                element_types
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// What was found by probing a model file with [probe].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbedModelFile {
+    /// The container format and version of the file.
+    pub container_type: ContainerType,
+    /// The file type recorded in the hyperparameters, if the architecture has one.
+    pub file_type: Option<FileType>,
+    /// The quantization version of the file, which determines the layout of its quantized
+    /// tensors.
+    pub quantization_version: u32,
+    /// The number of tensors of each type, in no particular order.
+    pub element_types: Vec<(ElementType, usize)>,
+    /// The number of tensors in the file.
+    pub tensor_count: usize,
+    /// Whether the file has all of the distinctive tensors of the architecture it was
+    /// probed with.
+    pub recognized: bool,
+    /// The issues that would prevent the file from loading as that architecture.
+    pub issues: Vec<CompatibilityIssue>,
+    /// What would make loading the file slower, if it can be loaded.
+    pub notes: Vec<CompatibilityNote>,
+}
+
+/// Reads the metadata of the model file in `reader` with the hyperparameters of `M`.
+///
+/// Returns the issue that stopped the file from being read as `M`, if there is one. If it is
+/// a [format issue](CompatibilityIssue::is_format_issue), probing the file as another
+/// architecture gives the same result.
+pub fn probe<M: KnownModel, R: BufRead + Seek>(
+    reader: &mut R,
+) -> Result<ProbedModelFile, CompatibilityIssue> {
+    probe_shards::<M, R>(std::slice::from_mut(reader))
+}
+
+/// Like [probe], for a model split across `shards`. The hyperparameters are read from the
+/// first shard, and the tensors of all shards are taken together.
+pub fn probe_shards<M: KnownModel, R: BufRead + Seek>(
+    shards: &mut [R],
+) -> Result<ProbedModelFile, CompatibilityIssue> {
+    let unreadable = |err: std::io::Error| CompatibilityIssue::Unreadable {
+        reason: err.to_string(),
+    };
+
+    let mut first: Option<(ContainerType, M::Hyperparameters)> = None;
+    let mut tensors = HashMap::new();
+    for reader in shards {
+        // LoRA adapters have different hyperparameters, so they are told apart before
+        // reading them.
+        reader.seek(SeekFrom::Start(0)).map_err(unreadable)?;
+        if ggml::util::read_u32(reader).map_err(unreadable)? == ggml::FILE_MAGIC_GGLA {
+            return Err(CompatibilityIssue::LoraAdapter);
+        }
+        reader.seek(SeekFrom::Start(0)).map_err(unreadable)?;
+
+        let mut loader = Loader::<M::Hyperparameters, _>::new(Tokenizer::empty_embedded(), |_| {});
+        if let Err(err) = ggml::format::load(reader, &mut loader) {
+            return Err(issue_from_format_error(err));
+        }
+
+        // The data of the tensors is skipped rather than read, so the end of the file is
+        // only noticed if it cuts off a header.
+        let file_size = reader.seek(SeekFrom::End(0)).map_err(unreadable)?;
+        if loader
+            .tensors
+            .values()
+            .any(|t| t.start_offset + t.calc_size() as u64 > file_size)
+        {
+            return Err(CompatibilityIssue::Truncated);
+        }
+
+        tensors.extend(loader.tensors);
+        first.get_or_insert((loader.container_type, loader.hyperparameters));
+    }
+    let Some((container_type, hyperparameters)) = first else {
+        return Err(CompatibilityIssue::Unreadable {
+            reason: "there are no shards".to_owned(),
+        });
+    };
+
+    let distinctive_tensors = M::distinctive_tensors();
+    let recognized = !distinctive_tensors.is_empty()
+        && distinctive_tensors
+            .iter()
+            .all(|name| tensors.contains_key(*name));
+
+    let mut element_types: Vec<(ElementType, usize)> = vec![];
+    for tensor in tensors.values() {
+        match element_types
+            .iter_mut()
+            .find(|(ty, _)| *ty == tensor.element_type)
+        {
+            Some((_, count)) => *count += 1,
+            None => element_types.push((tensor.element_type, 1)),
+        }
+    }
+
+    let file_type = hyperparameters.file_type();
+    let mut issues = vec![];
+    let version = quantization_version(file_type, container_type);
+    if version > ggml::QNT_VERSION && tensors.values().any(|t| t.element_type.is_quantized()) {
+        issues.push(CompatibilityIssue::UnsupportedQuantizationVersion { version });
+    }
+
+    let mut notes = vec![];
+    if !container_type.support_mmap() {
+        notes.push(CompatibilityNote::NoMmap { container_type });
+    }
+    if tensors.values().any(|t| t.is_legacy()) {
+        notes.push(CompatibilityNote::LegacyQuantization {
+            quantization_version: version,
+        });
+    }
+    let converted: Vec<ElementType> = element_types
+        .iter()
+        .map(|(ty, _)| *ty)
+        .filter(ElementType::is_file_only)
+        .collect();
+    if !converted.is_empty() {
+        notes.push(CompatibilityNote::ConvertedTensors {
+            element_types: converted,
+        });
+    }
+
+    Ok(ProbedModelFile {
+        container_type,
+        file_type,
+        quantization_version: version,
+        element_types,
+        tensor_count: tensors.len(),
+        recognized,
+        issues,
+        notes,
+    })
+}
+
+fn issue_from_format_error(err: FormatLoadError<LoadError>) -> CompatibilityIssue {
+    match err {
+        FormatLoadError::InvalidMagic(FormatMagic(FILE_MAGIC_GGUF)) => CompatibilityIssue::Gguf,
+        FormatLoadError::InvalidMagic(FormatMagic(magic)) => {
+            CompatibilityIssue::UnknownFormat { magic }
+        }
+        FormatLoadError::InvalidFormatVersion(container_type) => {
+            CompatibilityIssue::UnsupportedContainerVersion { container_type }
+        }
+        FormatLoadError::UnsupportedElementType { tensor_name, ftype } => {
+            CompatibilityIssue::UnsupportedElementType { tensor_name, ftype }
+        }
+        FormatLoadError::ImplementationError(LoadError::UnsupportedFileType(ftype)) => {
+            CompatibilityIssue::UnsupportedFileType { ftype }
+        }
+        err if ends_early(&err) => CompatibilityIssue::Truncated,
+        err => CompatibilityIssue::Unreadable {
+            reason: error_chain(&err),
+        },
+    }
+}
+
+/// Whether `err` was caused by reaching the end of the file.
+fn ends_early(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            if err.kind() == std::io::ErrorKind::UnexpectedEof {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// The name of the IQ quantization type with the ID `ftype` in the files of later versions
+/// of `ggml`. These types are not supported: they have no dequantization kernels here.
+fn iq_type_name(ftype: u32) -> Option<&'static str> {
+    Some(match ftype {
+        16 => "IQ2_XXS",
+        17 => "IQ2_XS",
+        18 => "IQ3_XXS",
+        19 => "IQ1_S",
+        20 => "IQ4_NL",
+        21 => "IQ3_S",
+        22 => "IQ2_S",
+        23 => "IQ4_XS",
+        29 => "IQ1_M",
+        _ => return None,
+    })
+}
+
+/// `err` followed by its sources, as the errors of the loader are not descriptive on their own.
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut reason = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        reason += &format!(": {err}");
+        source = err.source();
+    }
+    reason
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gguf_files_are_recognized_by_their_magic() {
+        assert_eq!(
+            issue_from_format_error(FormatLoadError::InvalidMagic(FormatMagic(
+                u32::from_le_bytes(*b"GGUF")
+            ))),
+            CompatibilityIssue::Gguf
+        );
+        assert!(matches!(
+            issue_from_format_error(FormatLoadError::InvalidMagic(FormatMagic(0))),
+            CompatibilityIssue::UnknownFormat { magic: 0 }
+        ));
+    }
+
+    #[test]
+    fn iq_types_are_named_as_unsupported() {
+        let issue = CompatibilityIssue::UnsupportedElementType {
+            tensor_name: "output.weight".to_owned(),
+            ftype: 20,
+        };
+        assert!(issue
+            .to_string()
+            .contains("IQ4_NL type of the IQ quantizations"));
+        let issue = CompatibilityIssue::UnsupportedElementType {
+            tensor_name: "output.weight".to_owned(),
+            ftype: 99,
+        };
+        assert!(issue.to_string().contains("unsupported type 99"));
+    }
+}
@@ -0,0 +1,1118 @@
+//! Constraints on the text that is generated, enforced by masking the tokens that would
+//! break them before sampling.
+//!
+//! A [Constraint] follows the bytes of the generated text. [Constrained] wraps a [Sampler]
+//! so that it only samples the tokens whose text the constraint accepts, and only ends the
+//! text once the constraint is satisfied. [JsonSchema] is a constraint that only accepts
+//! JSON documents that are valid against a [JSON Schema](https://json-schema.org/), so that
+//! tool calls and other structured output can be parsed without retrying.
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use llm_base::{constraint::{Constrained, JsonSchema}, samplers::TopPTopK, Model};
+//! # fn example(model: &dyn Model) -> Result<(), Box<dyn std::error::Error>> {
+//! let schema = JsonSchema::parse(r#"{
+//!     "type": "object",
+//!     "properties": { "city": { "type": "string" }, "days": { "type": "integer" } },
+//!     "required": ["city", "days"]
+//! }"#)?;
+//! let sampler = Constrained::new(
+//!     schema,
+//!     model.tokenizer(),
+//!     &model.stop_token_ids(),
+//!     Arc::new(TopPTopK::default()),
+//! );
+//! // Use `Arc::new(sampler)` as the `sampler` of the `InferenceParameters`.
+//! # Ok(())
+//! # }
+//! ```
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{Sampler, SamplerState, TokenId, Tokenizer};
+
+/// A rule that the generated text must follow, checked one piece of text at a time.
+pub trait Constraint: Debug + Send + Sync {
+    /// What the constraint remembers of the text so far.
+    type State: Clone;
+
+    /// The state before any text has been generated.
+    fn start(&self) -> Self::State;
+
+    /// Advances `state` past `bytes`. Returns `false` if the text can no longer be
+    /// completed into text that satisfies the constraint, in which case `state` is invalid.
+    fn push(&self, state: &mut Self::State, bytes: &[u8]) -> bool;
+
+    /// Whether the text so far satisfies the constraint, so that generation can end.
+    fn is_complete(&self, state: &Self::State) -> bool;
+}
+
+/// A [Sampler] that only samples tokens that keep the generated text within a [Constraint].
+///
+/// Before each token, the text generated since the prompt is replayed through the
+/// constraint, and every token whose text it does not accept is masked out before `sampler`
+/// samples from the remaining ones. The stop tokens are only allowed once the constraint is
+/// complete, and are the only tokens allowed then if nothing else may follow.
+///
+/// The generated text is only guaranteed to satisfy the constraint if generation ends with
+/// a stop token, rather than by running out of tokens or context. If the text already
+/// breaks the constraint, because it was generated without it, tokens are not masked.
+#[derive(Debug)]
+pub struct Constrained<C> {
+    constraint: C,
+    /// The text of each token, indexed by ID.
+    tokens: Vec<Vec<u8>>,
+    stop_tokens: Vec<TokenId>,
+    sampler: Arc<dyn Sampler>,
+}
+impl<C: Constraint> Constrained<C> {
+    /// Constrains `sampler` with `constraint`. The text of the tokens is taken from
+    /// `tokenizer`, and generation can only end with one of `stop_tokens`, which are usually
+    /// the [stop tokens](crate::Model::stop_token_ids) of the model.
+    pub fn new(
+        constraint: C,
+        tokenizer: &Tokenizer,
+        stop_tokens: &[TokenId],
+        sampler: Arc<dyn Sampler>,
+    ) -> Self {
+        Self {
+            constraint,
+            tokens: (0..tokenizer.len()).map(|id| tokenizer.token(id)).collect(),
+            stop_tokens: stop_tokens.to_vec(),
+            sampler,
+        }
+    }
+
+    /// The constraint that is enforced.
+    pub fn constraint(&self) -> &C {
+        &self.constraint
+    }
+
+    fn token(&self, id: TokenId) -> Option<&[u8]> {
+        self.tokens
+            .get(usize::try_from(id).ok()?)
+            .map(Vec::as_slice)
+    }
+}
+impl<C: Constraint> Sampler for Constrained<C> {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        self.sample_with_state(&mut SamplerState::default(), previous_tokens, logits, rng)
+    }
+
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let generated = &previous_tokens[state.generation_start().min(previous_tokens.len())..];
+        let mut text = self.constraint.start();
+        let valid = generated.iter().all(|&id| {
+            self.token(id)
+                .map_or(false, |bytes| self.constraint.push(&mut text, bytes))
+        });
+        if !valid {
+            return self
+                .sampler
+                .sample_with_state(state, previous_tokens, logits, rng);
+        }
+
+        let complete = self.constraint.is_complete(&text);
+        let mut allowed_any = false;
+        let masked: Vec<f32> = logits
+            .iter()
+            .enumerate()
+            .map(|(id, &logit)| {
+                let id = id as TokenId;
+                let allowed = if self.stop_tokens.contains(&id) {
+                    complete
+                } else {
+                    // Tokens without text would let generation go on without progress.
+                    self.token(id).map_or(false, |bytes| {
+                        !bytes.is_empty() && self.constraint.push(&mut text.clone(), bytes)
+                    })
+                };
+                allowed_any |= allowed;
+                if allowed {
+                    logit
+                } else {
+                    f32::NEG_INFINITY
+                }
+            })
+            .collect();
+
+        match self.stop_tokens.first() {
+            // No token can continue the text: the vocabulary cannot spell what must follow.
+            Some(&stop_token) if !allowed_any => stop_token,
+            _ if !allowed_any => {
+                self.sampler
+                    .sample_with_state(state, previous_tokens, logits, rng)
+            }
+            _ => self
+                .sampler
+                .sample_with_state(state, previous_tokens, &masked, rng),
+        }
+    }
+}
+
+/// Returned by [JsonSchema::new] when a schema cannot be used as a constraint.
+#[derive(Error, Debug)]
+pub enum JsonSchemaError {
+    /// The schema is not valid JSON.
+    #[error("the schema is not valid JSON")]
+    InvalidJson(#[from] serde_json::Error),
+    /// The schema uses a feature that cannot be enforced while generating.
+    #[error("the schema at {path:?} is not supported: {reason}")]
+    Unsupported {
+        /// The JSON pointer to the unsupported schema.
+        path: String,
+        /// Why it is not supported.
+        reason: String,
+    },
+    /// A `$ref` does not point to a schema in the same document.
+    #[error("the reference {reference:?} at {path:?} could not be resolved")]
+    UnresolvedReference {
+        /// The JSON pointer to the schema with the reference.
+        path: String,
+        /// The reference.
+        reference: String,
+    },
+}
+
+/// A [Constraint] that only accepts a JSON document that is valid against a JSON Schema.
+///
+/// The schema is compiled when the constraint is created. The supported keywords are `type`
+/// (including lists of types), `properties`, `required`, `additionalProperties`, `items`,
+/// `minItems`, `maxItems`, `minLength`, `maxLength`, `enum`, `const`, `anyOf`, `oneOf`
+/// (treated as `anyOf`), `allOf` with a single schema, and `$ref` to schemas in the same
+/// document, such as `#/$defs/...`. Other keywords, such as `pattern`, `format` and
+/// `minimum`, are not enforced.
+///
+/// To keep the output useful, objects only get the properties listed in `properties` unless
+/// `additionalProperties` is `true` or a schema, or no properties are listed. `integer`s are
+/// generated without a fraction or exponent. A document can have some whitespace between
+/// its tokens, but not after its end, so that generation stops once it is complete.
+#[derive(Debug, Clone)]
+pub struct JsonSchema {
+    nodes: Arc<[Node]>,
+    root: usize,
+}
+impl JsonSchema {
+    /// Compiles `schema`.
+    pub fn new(schema: &Value) -> Result<Self, JsonSchemaError> {
+        let mut compiler = Compiler {
+            document: schema,
+            nodes: vec![],
+            references: HashMap::new(),
+            any: None,
+        };
+        let root = compiler.compile(schema, "")?;
+        Ok(Self {
+            nodes: compiler.nodes.into(),
+            root,
+        })
+    }
+
+    /// Parses and compiles the JSON Schema in `schema`.
+    pub fn parse(schema: &str) -> Result<Self, JsonSchemaError> {
+        Self::new(&serde_json::from_str(schema)?)
+    }
+}
+impl Constraint for JsonSchema {
+    type State = JsonSchemaState;
+
+    fn start(&self) -> JsonSchemaState {
+        JsonSchemaState {
+            stacks: vec![Stack {
+                frames: vec![Frame::Value(self.root)],
+                whitespace: 0,
+            }],
+        }
+    }
+
+    fn push(&self, state: &mut JsonSchemaState, bytes: &[u8]) -> bool {
+        for &byte in bytes {
+            let mut stacks = Vec::with_capacity(state.stacks.len());
+            for stack in state.stacks.drain(..) {
+                step(&self.nodes, stack, byte, 0, &mut stacks);
+            }
+            // Alternatives that have converged do not need to be followed separately.
+            for stack in stacks {
+                if !state.stacks.contains(&stack) {
+                    state.stacks.push(stack);
+                }
+            }
+            if state.stacks.is_empty() {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn is_complete(&self, state: &JsonSchemaState) -> bool {
+        state
+            .stacks
+            .iter()
+            .any(|stack| match stack.frames.as_slice() {
+                [] => true,
+                // A number at the top level has nothing after it to end it.
+                [Frame::Number { state, .. }] => state.is_complete(),
+                _ => false,
+            })
+    }
+}
+
+/// The state of a [JsonSchema] constraint: where the text so far is in the document, for
+/// each of the alternatives of the schema that it matches.
+#[derive(Debug, Clone)]
+pub struct JsonSchemaState {
+    stacks: Vec<Stack>,
+}
+
+/// The most whitespace allowed in a row, so that generation cannot get stuck on it.
+const MAX_WHITESPACE: usize = 32;
+/// The longest number allowed, for the same reason.
+const MAX_NUMBER_LENGTH: usize = 24;
+/// The deepest `anyOf` nesting that is followed, to stop on schemas that refer to
+/// themselves without ever matching anything.
+const MAX_ALTERNATIVE_DEPTH: usize = 32;
+
+#[derive(Debug)]
+enum Node {
+    /// Nothing is valid (the `false` schema).
+    Never,
+    /// One of several schemas.
+    AnyOf(Vec<usize>),
+    /// Exactly this JSON text.
+    Literal(Vec<u8>),
+    Number {
+        integer: bool,
+    },
+    String {
+        min_length: usize,
+        max_length: Option<usize>,
+    },
+    Array {
+        items: usize,
+        min_items: usize,
+        max_items: Option<usize>,
+    },
+    Object {
+        /// The name of each property, as it is written between the quotes, and its schema.
+        properties: Vec<(Vec<u8>, usize)>,
+        /// A bit for each required property.
+        required: u128,
+        /// The schema of the other properties, if they are allowed.
+        additional: Option<usize>,
+    },
+}
+
+struct Compiler<'a> {
+    document: &'a Value,
+    nodes: Vec<Node>,
+    /// The node of each reference that was compiled.
+    references: HashMap<String, usize>,
+    /// The node that accepts any value.
+    any: Option<usize>,
+}
+impl Compiler<'_> {
+    fn push(&mut self, node: Node) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn compile(&mut self, schema: &Value, path: &str) -> Result<usize, JsonSchemaError> {
+        let object = match schema {
+            Value::Bool(true) => return Ok(self.any()),
+            Value::Bool(false) => return Ok(self.push(Node::Never)),
+            Value::Object(object) => object,
+            _ => return Err(unsupported(path, "a schema must be an object or a boolean")),
+        };
+
+        if let Some(reference) = object.get("$ref") {
+            return self.reference(reference, path);
+        }
+        if let Some(value) = object.get("const") {
+            return Ok(self.literal(value));
+        }
+        if let Some(values) = object.get("enum") {
+            let Value::Array(values) = values else {
+                return Err(unsupported(path, "`enum` must be an array"));
+            };
+            let alternatives = values.iter().map(|value| self.literal(value)).collect();
+            return Ok(self.push(Node::AnyOf(alternatives)));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = object.get(keyword) {
+                let Value::Array(schemas) = schemas else {
+                    return Err(unsupported(path, &format!("`{keyword}` must be an array")));
+                };
+                let alternatives = schemas
+                    .iter()
+                    .enumerate()
+                    .map(|(i, schema)| self.compile(schema, &format!("{path}/{keyword}/{i}")))
+                    .collect::<Result<_, _>>()?;
+                return Ok(self.push(Node::AnyOf(alternatives)));
+            }
+        }
+        if let Some(schemas) = object.get("allOf") {
+            return match schemas {
+                Value::Array(schemas) if schemas.len() == 1 => {
+                    self.compile(&schemas[0], &format!("{path}/allOf/0"))
+                }
+                _ => Err(unsupported(path, "`allOf` can only have one schema")),
+            };
+        }
+
+        let types = match object.get("type") {
+            Some(Value::String(name)) => vec![name.as_str()],
+            Some(Value::Array(names)) => names
+                .iter()
+                .map(|name| {
+                    name.as_str()
+                        .ok_or_else(|| unsupported(path, "`type` must be a string or strings"))
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err(unsupported(path, "`type` must be a string or strings")),
+            None if object.contains_key("properties") => vec!["object"],
+            None if object.contains_key("items") => vec!["array"],
+            None => return Ok(self.any()),
+        };
+        let mut alternatives = types
+            .into_iter()
+            .map(|name| self.compile_type(name, object, path))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(if alternatives.len() == 1 {
+            alternatives.remove(0)
+        } else {
+            self.push(Node::AnyOf(alternatives))
+        })
+    }
+
+    fn compile_type(
+        &mut self,
+        name: &str,
+        schema: &serde_json::Map<String, Value>,
+        path: &str,
+    ) -> Result<usize, JsonSchemaError> {
+        let count = |keyword: &str| -> Result<Option<usize>, JsonSchemaError> {
+            match schema.get(keyword) {
+                None => Ok(None),
+                Some(value) => value
+                    .as_u64()
+                    .and_then(|count| usize::try_from(count).ok())
+                    .map(Some)
+                    .ok_or_else(|| {
+                        unsupported(path, &format!("`{keyword}` must be a non-negative integer"))
+                    }),
+            }
+        };
+
+        let node = match name {
+            "null" => return Ok(self.literal(&Value::Null)),
+            "boolean" => {
+                let alternatives = vec![
+                    self.literal(&Value::Bool(true)),
+                    self.literal(&Value::Bool(false)),
+                ];
+                Node::AnyOf(alternatives)
+            }
+            "integer" => Node::Number { integer: true },
+            "number" => Node::Number { integer: false },
+            "string" => Node::String {
+                min_length: count("minLength")?.unwrap_or(0),
+                max_length: count("maxLength")?,
+            },
+            "array" => {
+                let items = match schema.get("items") {
+                    None => self.any(),
+                    Some(Value::Array(_)) => {
+                        return Err(unsupported(path, "`items` must be a single schema"))
+                    }
+                    Some(items) => self.compile(items, &format!("{path}/items"))?,
+                };
+                Node::Array {
+                    items,
+                    min_items: count("minItems")?.unwrap_or(0),
+                    max_items: count("maxItems")?,
+                }
+            }
+            "object" => self.compile_object(schema, path)?,
+            _ => return Err(unsupported(path, &format!("unknown type {name:?}"))),
+        };
+        Ok(self.push(node))
+    }
+
+    fn compile_object(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        path: &str,
+    ) -> Result<Node, JsonSchemaError> {
+        let mut properties = vec![];
+        if let Some(declared) = schema.get("properties") {
+            let Value::Object(declared) = declared else {
+                return Err(unsupported(path, "`properties` must be an object"));
+            };
+            for (name, property) in declared {
+                let node = self.compile(property, &format!("{path}/properties/{name}"))?;
+                properties.push((name.clone(), node));
+            }
+        }
+
+        let mut required = 0;
+        if let Some(names) = schema.get("required") {
+            let Value::Array(names) = names else {
+                return Err(unsupported(path, "`required` must be an array"));
+            };
+            for name in names {
+                let Value::String(name) = name else {
+                    return Err(unsupported(path, "`required` must list property names"));
+                };
+                let index = match properties.iter().position(|(n, _)| n == name) {
+                    Some(index) => index,
+                    None => {
+                        properties.push((name.clone(), self.any()));
+                        properties.len() - 1
+                    }
+                };
+                if index < 128 {
+                    required |= 1 << index;
+                }
+            }
+        }
+        if properties.len() > 128 {
+            return Err(unsupported(path, "objects can have at most 128 properties"));
+        }
+
+        let additional = match schema.get("additionalProperties") {
+            Some(Value::Bool(false)) => None,
+            Some(Value::Bool(true)) => Some(self.any()),
+            Some(additional) => {
+                Some(self.compile(additional, &format!("{path}/additionalProperties"))?)
+            }
+            None if properties.is_empty() => Some(self.any()),
+            None => None,
+        };
+
+        Ok(Node::Object {
+            properties: properties
+                .into_iter()
+                .map(|(name, node)| (escaped(&name), node))
+                .collect(),
+            required,
+            additional,
+        })
+    }
+
+    fn reference(&mut self, reference: &Value, path: &str) -> Result<usize, JsonSchemaError> {
+        let unresolved = || JsonSchemaError::UnresolvedReference {
+            path: path.to_owned(),
+            reference: reference.to_string(),
+        };
+        let reference = reference.as_str().ok_or_else(unresolved)?;
+        if let Some(&node) = self.references.get(reference) {
+            return Ok(node);
+        }
+        let pointer = reference.strip_prefix('#').ok_or_else(unresolved)?;
+        let target = self.document.pointer(pointer).ok_or_else(unresolved)?;
+
+        // The node is reserved before the target is compiled, as it may refer to itself.
+        let node = self.push(Node::Never);
+        self.references.insert(reference.to_owned(), node);
+        let target = self.compile(target, pointer)?;
+        self.nodes[node] = Node::AnyOf(vec![target]);
+        Ok(node)
+    }
+
+    fn literal(&mut self, value: &Value) -> usize {
+        self.push(Node::Literal(value.to_string().into_bytes()))
+    }
+
+    fn any(&mut self) -> usize {
+        if let Some(any) = self.any {
+            return any;
+        }
+        let any = self.push(Node::Never);
+        self.any = Some(any);
+        let alternatives = vec![
+            self.push(Node::Object {
+                properties: vec![],
+                required: 0,
+                additional: Some(any),
+            }),
+            self.push(Node::Array {
+                items: any,
+                min_items: 0,
+                max_items: None,
+            }),
+            self.push(Node::String {
+                min_length: 0,
+                max_length: None,
+            }),
+            self.push(Node::Number { integer: false }),
+            self.literal(&Value::Null),
+            self.literal(&Value::Bool(true)),
+            self.literal(&Value::Bool(false)),
+        ];
+        self.nodes[any] = Node::AnyOf(alternatives);
+        any
+    }
+}
+
+fn unsupported(path: &str, reason: &str) -> JsonSchemaError {
+    JsonSchemaError::Unsupported {
+        path: path.to_owned(),
+        reason: reason.to_owned(),
+    }
+}
+
+/// `name` as it is written between the quotes of a JSON string.
+fn escaped(name: &str) -> Vec<u8> {
+    let quoted = Value::String(name.to_owned()).to_string().into_bytes();
+    quoted[1..quoted.len() - 1].to_vec()
+}
+
+/// Where one alternative of the text is in the document, from the outermost value in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stack {
+    frames: Vec<Frame>,
+    /// The whitespace in a row up to now.
+    whitespace: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Frame {
+    /// Before a value of the node.
+    Value(usize),
+    /// Within a literal, after `matched` of its bytes.
+    Literal { node: usize, matched: usize },
+    Number {
+        integer: bool,
+        state: NumberState,
+        length: usize,
+    },
+    /// Within a string, after its opening quote.
+    String {
+        node: usize,
+        length: usize,
+        escape: Escape,
+    },
+    Array {
+        node: usize,
+        count: usize,
+        state: ArrayState,
+    },
+    Object {
+        node: usize,
+        seen: u128,
+        state: ObjectState,
+    },
+    /// Within the key of the object below, after its opening quote.
+    Key {
+        node: usize,
+        key: Vec<u8>,
+        escape: Escape,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NumberState {
+    Minus,
+    Zero,
+    Integer,
+    Dot,
+    Fraction,
+    Exponent,
+    ExponentSign,
+    ExponentDigits,
+}
+impl NumberState {
+    fn is_complete(self) -> bool {
+        matches!(
+            self,
+            Self::Zero | Self::Integer | Self::Fraction | Self::ExponentDigits
+        )
+    }
+
+    fn next(self, byte: u8, integer: bool) -> Option<Self> {
+        Some(match (self, byte) {
+            (Self::Minus, b'0') => Self::Zero,
+            (Self::Minus | Self::Integer, b'0'..=b'9') => Self::Integer,
+            (Self::Zero | Self::Integer, b'.') if !integer => Self::Dot,
+            (Self::Dot | Self::Fraction, b'0'..=b'9') => Self::Fraction,
+            (Self::Zero | Self::Integer | Self::Fraction, b'e' | b'E') if !integer => {
+                Self::Exponent
+            }
+            (Self::Exponent, b'+' | b'-') => Self::ExponentSign,
+            (Self::Exponent | Self::ExponentSign | Self::ExponentDigits, b'0'..=b'9') => {
+                Self::ExponentDigits
+            }
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArrayState {
+    /// After `[`.
+    Start,
+    /// After an item.
+    AfterItem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ObjectState {
+    /// After `{`.
+    Start,
+    /// After a key, before the `:` and the value of the node.
+    Colon(usize),
+    /// After a value.
+    AfterValue,
+    /// After `,`.
+    NeedKey,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Backslash,
+    /// Within a `\u` escape, with this many hex digits left.
+    Unicode(u8),
+}
+
+enum StringByte {
+    Invalid,
+    /// The closing quote.
+    End,
+    /// A byte of the string, which starts a new character if `true`.
+    Content(bool),
+}
+
+fn string_byte(escape: &mut Escape, byte: u8) -> StringByte {
+    match *escape {
+        Escape::None => match byte {
+            b'"' => StringByte::End,
+            b'\\' => {
+                *escape = Escape::Backslash;
+                StringByte::Content(true)
+            }
+            0..=0x1f => StringByte::Invalid,
+            // UTF-8 continuation bytes.
+            0x80..=0xbf => StringByte::Content(false),
+            _ => StringByte::Content(true),
+        },
+        Escape::Backslash => match byte {
+            b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => {
+                *escape = Escape::None;
+                StringByte::Content(false)
+            }
+            b'u' => {
+                *escape = Escape::Unicode(4);
+                StringByte::Content(false)
+            }
+            _ => StringByte::Invalid,
+        },
+        Escape::Unicode(left) if byte.is_ascii_hexdigit() => {
+            *escape = if left == 1 {
+                Escape::None
+            } else {
+                Escape::Unicode(left - 1)
+            };
+            StringByte::Content(false)
+        }
+        Escape::Unicode(_) => StringByte::Invalid,
+    }
+}
+
+/// Advances `stack` past `byte`, adding the stacks of the alternatives that accept it to
+/// `out`.
+fn step(nodes: &[Node], mut stack: Stack, byte: u8, depth: usize, out: &mut Vec<Stack>) {
+    let whitespace = matches!(byte, b' ' | b'\t' | b'\n' | b'\r');
+    let skip_whitespace = |mut stack: Stack, out: &mut Vec<Stack>| {
+        if stack.whitespace < MAX_WHITESPACE {
+            stack.whitespace += 1;
+            out.push(stack);
+        }
+    };
+    if !whitespace {
+        stack.whitespace = 0;
+    }
+
+    // Nothing may follow the end of the document.
+    let Some(top) = stack.frames.last_mut() else {
+        return;
+    };
+    match top {
+        Frame::Value(_) if whitespace => skip_whitespace(stack, out),
+        &mut Frame::Value(node) => {
+            stack.frames.pop();
+            let frame = match &nodes[node] {
+                Node::Never => return,
+                Node::AnyOf(alternatives) => {
+                    if depth < MAX_ALTERNATIVE_DEPTH {
+                        for &alternative in alternatives {
+                            let mut stack = stack.clone();
+                            stack.frames.push(Frame::Value(alternative));
+                            step(nodes, stack, byte, depth + 1, out);
+                        }
+                    }
+                    return;
+                }
+                Node::Literal(literal) if literal[0] == byte => Frame::Literal { node, matched: 0 },
+                &Node::Number { integer } => {
+                    let state = match byte {
+                        b'-' => NumberState::Minus,
+                        b'0' => NumberState::Zero,
+                        b'1'..=b'9' => NumberState::Integer,
+                        _ => return,
+                    };
+                    stack.frames.push(Frame::Number {
+                        integer,
+                        state,
+                        length: 1,
+                    });
+                    out.push(stack);
+                    return;
+                }
+                Node::String { .. } if byte == b'"' => Frame::String {
+                    node,
+                    length: 0,
+                    escape: Escape::None,
+                },
+                Node::Array { .. } if byte == b'[' => Frame::Array {
+                    node,
+                    count: 0,
+                    state: ArrayState::Start,
+                },
+                Node::Object { .. } if byte == b'{' => Frame::Object {
+                    node,
+                    seen: 0,
+                    state: ObjectState::Start,
+                },
+                _ => return,
+            };
+            let literal = matches!(frame, Frame::Literal { .. });
+            stack.frames.push(frame);
+            if literal {
+                step(nodes, stack, byte, depth, out);
+            } else {
+                out.push(stack);
+            }
+        }
+
+        Frame::Literal { node, matched } => {
+            let Node::Literal(literal) = &nodes[*node] else {
+                unreachable!("literal frames are only made for literals");
+            };
+            if literal[*matched] != byte {
+                return;
+            }
+            *matched += 1;
+            if *matched == literal.len() {
+                stack.frames.pop();
+            }
+            out.push(stack);
+        }
+
+        Frame::Number {
+            integer,
+            state,
+            length,
+        } => match state.next(byte, *integer) {
+            Some(next) if *length < MAX_NUMBER_LENGTH => {
+                *state = next;
+                *length += 1;
+                out.push(stack);
+            }
+            Some(_) => {}
+            // The number ends at the first byte that is not part of it, which belongs to
+            // what contains it.
+            None if state.is_complete() => {
+                stack.frames.pop();
+                step(nodes, stack, byte, depth, out);
+            }
+            None => {}
+        },
+
+        Frame::String {
+            node,
+            length,
+            escape,
+        } => {
+            let &Node::String {
+                min_length,
+                max_length,
+            } = &nodes[*node]
+            else {
+                unreachable!("string frames are only made for strings");
+            };
+            match string_byte(escape, byte) {
+                StringByte::Invalid => {}
+                StringByte::End if *length >= min_length => {
+                    stack.frames.pop();
+                    out.push(stack);
+                }
+                StringByte::End => {}
+                StringByte::Content(true) if max_length.map_or(false, |max| *length >= max) => {}
+                StringByte::Content(new_character) => {
+                    *length += usize::from(new_character);
+                    out.push(stack);
+                }
+            }
+        }
+
+        Frame::Array { .. } if whitespace => skip_whitespace(stack, out),
+        Frame::Array { node, count, state } => {
+            let &Node::Array {
+                items,
+                min_items,
+                max_items,
+            } = &nodes[*node]
+            else {
+                unreachable!("array frames are only made for arrays");
+            };
+            let room = max_items.map_or(true, |max| *count < max);
+            match (*state, byte) {
+                (_, b']') if *count >= min_items => {
+                    stack.frames.pop();
+                    out.push(stack);
+                }
+                (ArrayState::AfterItem, b',') if room => {
+                    *count += 1;
+                    stack.frames.push(Frame::Value(items));
+                    out.push(stack);
+                }
+                (ArrayState::Start, _) if room => {
+                    *count += 1;
+                    *state = ArrayState::AfterItem;
+                    stack.frames.push(Frame::Value(items));
+                    step(nodes, stack, byte, depth, out);
+                }
+                _ => {}
+            }
+        }
+
+        Frame::Object { .. } if whitespace => skip_whitespace(stack, out),
+        Frame::Object { node, seen, state } => {
+            let Node::Object {
+                properties,
+                required,
+                additional,
+            } = &nodes[*node]
+            else {
+                unreachable!("object frames are only made for objects");
+            };
+            // A key is only started if it can be finished.
+            let more_keys = additional.is_some()
+                || (0..properties.len()).any(|index| *seen & (1 << index) == 0);
+            match (*state, byte) {
+                (ObjectState::Start | ObjectState::AfterValue, b'}') if required & !*seen == 0 => {
+                    stack.frames.pop();
+                    out.push(stack);
+                }
+                (ObjectState::Start | ObjectState::NeedKey, b'"') if more_keys => {
+                    let node = *node;
+                    stack.frames.push(Frame::Key {
+                        node,
+                        key: vec![],
+                        escape: Escape::None,
+                    });
+                    out.push(stack);
+                }
+                (ObjectState::AfterValue, b',') if more_keys => {
+                    *state = ObjectState::NeedKey;
+                    out.push(stack);
+                }
+                (ObjectState::Colon(value), b':') => {
+                    *state = ObjectState::AfterValue;
+                    stack.frames.push(Frame::Value(value));
+                    out.push(stack);
+                }
+                _ => {}
+            }
+        }
+
+        Frame::Key { node, .. } => {
+            let node = *node;
+            let Node::Object {
+                properties,
+                additional,
+                ..
+            } = &nodes[node]
+            else {
+                unreachable!("key frames are only made for objects");
+            };
+            let index = stack.frames.len() - 2;
+            let Frame::Object { seen, .. } = stack.frames[index] else {
+                unreachable!("key frames are always above their object");
+            };
+            let Frame::Key { key, escape, .. } = stack.frames.last_mut().unwrap() else {
+                unreachable!();
+            };
+            let unseen = |index: usize| seen & (1 << index) == 0;
+            match string_byte(escape, byte) {
+                StringByte::Invalid => {}
+                StringByte::End => {
+                    let declared = properties.iter().position(|(name, _)| name == key);
+                    let (bit, value) = match (declared, additional) {
+                        (Some(index), _) if unseen(index) => (1 << index, properties[index].1),
+                        (None, Some(additional)) => (0, *additional),
+                        _ => return,
+                    };
+                    stack.frames.pop();
+                    stack.frames[index] = Frame::Object {
+                        node,
+                        seen: seen | bit,
+                        state: ObjectState::Colon(value),
+                    };
+                    out.push(stack);
+                }
+                StringByte::Content(_) => {
+                    key.push(byte);
+                    let possible = additional.is_some()
+                        || properties
+                            .iter()
+                            .enumerate()
+                            .any(|(index, (name, _))| unseen(index) && name.starts_with(key));
+                    if possible {
+                        out.push(stack);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::{samplers::TopPTopK, tokenizer::EmbeddedTokenizer};
+
+    /// Whether `text` can be continued into a valid document, and whether it is one.
+    fn check(schema: &JsonSchema, text: &str) -> (bool, bool) {
+        let mut state = schema.start();
+        let valid = schema.push(&mut state, text.as_bytes());
+        (valid, valid && schema.is_complete(&state))
+    }
+
+    #[test]
+    fn documents_are_checked_against_the_schema() {
+        let schema = JsonSchema::parse(
+            r##"{
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string", "maxLength": 5 },
+                    "age": { "type": "integer" },
+                    "tags": { "type": "array", "items": { "enum": ["a", "b"] }, "maxItems": 2 },
+                    "pet": { "$ref": "#/$defs/pet" }
+                },
+                "required": ["name", "age"],
+                "$defs": { "pet": { "type": ["string", "null"] } }
+            }"##,
+        )
+        .unwrap();
+
+        let complete = (true, true);
+        let partial = (true, false);
+        let invalid = (false, false);
+        assert_eq!(check(&schema, r#"{"name": "Bob", "age": 42}"#), complete);
+        assert_eq!(
+            check(
+                &schema,
+                r#"{ "age": -1, "name": "", "tags": ["a","b"], "pet": null }"#
+            ),
+            complete
+        );
+        assert_eq!(check(&schema, r#"{"name": "Bob", "pet": "Rex""#), partial);
+        assert_eq!(check(&schema, r#"{"name": "Bob"}"#), invalid);
+        assert_eq!(check(&schema, r#"{"name": "Bobby!"#), invalid);
+        assert_eq!(check(&schema, r#"{"age": 4.5"#), invalid);
+        assert_eq!(check(&schema, r#"{"nam"#), partial);
+        assert_eq!(check(&schema, r#"{"colour"#), invalid);
+        assert_eq!(check(&schema, r#"{"age": 1, "age""#), invalid);
+        assert_eq!(check(&schema, r#"{"tags": ["a", "c"#), invalid);
+        assert_eq!(check(&schema, r#"{"tags": ["a", "b","#), invalid);
+        assert_eq!(check(&schema, r#"{"name": "Bob", "age": 42} "#), invalid);
+
+        let any = JsonSchema::parse("{}").unwrap();
+        assert_eq!(check(&any, r#"[1, {"a": [true, "é\n"]}, null]"#), complete);
+        assert_eq!(check(&any, "-12.5e+3"), complete);
+        assert_eq!(check(&any, "01"), invalid);
+    }
+
+    #[test]
+    fn unsupported_schemas_are_rejected() {
+        assert!(matches!(
+            JsonSchema::parse(r##"{"type": "object", "properties": {"a": {"$ref": "#/nope"}}}"##),
+            Err(JsonSchemaError::UnresolvedReference { .. })
+        ));
+        assert!(matches!(
+            JsonSchema::parse(r#"{"allOf": [{"type": "string"}, {"maxLength": 2}]}"#),
+            Err(JsonSchemaError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn constrained_sampling_generates_valid_json() {
+        let vocabulary = [
+            "</s>", "{", "}", "\"", "name", "\":", " \"", "Bob", "\",", "age", "1", "2", ",", " ",
+            "\n", "hello", "\"}", "[", "null", "Sure!", ":",
+        ];
+        let mut tokenizer = EmbeddedTokenizer::default();
+        for (id, token) in vocabulary.iter().enumerate() {
+            tokenizer.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+        }
+        let schema = JsonSchema::parse(
+            r#"{
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string", "maxLength": 10 },
+                    "age": { "type": "integer" }
+                },
+                "required": ["name", "age"],
+                "additionalProperties": false
+            }"#,
+        )
+        .unwrap();
+        let sampler = Constrained::new(
+            schema.clone(),
+            &Tokenizer::Embedded(tokenizer),
+            &[0],
+            Arc::new(TopPTopK {
+                top_k: 1,
+                ..Default::default()
+            }),
+        );
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let mut state = SamplerState::default();
+            let mut tokens = vec![];
+            let mut text = String::new();
+            loop {
+                // Random logits, so that the mask alone decides what is valid.
+                let logits: Vec<f32> = (0..vocabulary.len()).map(|_| rng.gen()).collect();
+                let token = sampler.sample_with_state(&mut state, &tokens, &logits, &mut rng);
+                if token == 0 {
+                    break;
+                }
+                tokens.push(token);
+                text.push_str(vocabulary[token as usize]);
+                assert!(tokens.len() < 200, "generation did not end: {text}");
+            }
+            let value: Value = serde_json::from_str(&text).unwrap();
+            assert!(value["name"].is_string() && value["age"].is_i64(), "{text}");
+            assert_eq!(check(&schema, &text), (true, true));
+        }
+    }
+}
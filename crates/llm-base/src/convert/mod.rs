@@ -0,0 +1,1006 @@
+//! Conversion of Hugging Face checkpoints to GGML models.
+//!
+//! A checkpoint is a directory containing a `config.json`, a `tokenizer.json` and the
+//! weights in one or more `*.safetensors` files or, failing that, PyTorch
+//! `pytorch_model*.bin` files. [convert_hf] streams the weights out of the files one tensor
+//! at a time, quantizing each as it is written, so the conversion needs no more disk space
+//! than the output model.
+//!
+//! Architectures opt in to conversion by returning an [HfConverter] from
+//! [KnownModel::hf_converter].
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Seek, Write},
+    num::TryFromIntError,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use ggml::format::{SaveContainerType, SaveError, SaveHandler, TensorSaveInfo};
+use half::{bf16, f16};
+use memmap2::Mmap;
+use regex::Regex;
+use thiserror::Error;
+
+use crate::{
+    model::HyperparametersWriteError, quantize::quantize_data, util, FileType, FileTypeFormat,
+    Hyperparameters, KnownModel, QuantizationHistogram, QuantizeProgress, QuantizeReport,
+    TensorQuantizeStats, Tokenizer, TokenizerLoadError, TokenizerSource,
+};
+
+mod pytorch;
+
+/// Converts the parts of a Hugging Face checkpoint that are specific to an architecture.
+pub trait HfConverter<H: Hyperparameters> {
+    /// Creates the hyperparameters of the model from its `config.json`, with the given
+    /// vocabulary size and file type.
+    ///
+    /// `n_vocab` is the size of the vocabulary embedded in the model, which differs from
+    /// the `vocab_size` of the checkpoint if the vocabulary was adjusted with a
+    /// [VocabularyAdjustment].
+    fn hyperparameters(
+        &self,
+        config: &HfConfig,
+        n_vocab: usize,
+        file_type: FileType,
+    ) -> Result<H, ConvertError>;
+
+    /// Returns the name in the GGML model of the tensor named `hf_name` in the checkpoint,
+    /// or `None` if the tensor is not used by the model and should be dropped.
+    fn tensor_name(&self, hf_name: &str) -> Option<String>;
+
+    /// Returns the names (in the GGML model) of the tensors that have one row per token,
+    /// such as the token embeddings. These are the tensors changed by a
+    /// [VocabularyAdjustment].
+    fn vocabulary_tensors(&self) -> &[&str];
+
+    /// Rearranges the tensor named `name` (in the GGML model) to the layout the model
+    /// expects, if that differs from the layout in the checkpoint.
+    fn transform(
+        &self,
+        hyperparameters: &H,
+        name: &str,
+        tensor: &mut HfTensor,
+    ) -> Result<(), ConvertError> {
+        let _ = (hyperparameters, name, tensor);
+        Ok(())
+    }
+
+    /// Returns the vocabulary to embed in the GGML model.
+    ///
+    /// By default, this is [sentencepiece_vocabulary].
+    fn vocabulary(&self, tokenizer: &Tokenizer) -> Vec<(Vec<u8>, f32)> {
+        sentencepiece_vocabulary(tokenizer)
+    }
+}
+
+/// The `config.json` of a Hugging Face checkpoint.
+#[derive(Clone, Debug)]
+pub struct HfConfig {
+    path: PathBuf,
+    value: serde_json::Value,
+}
+impl HfConfig {
+    /// Reads the configuration at `path`.
+    pub fn load(path: &Path) -> Result<Self, ConvertError> {
+        let contents =
+            std::fs::read(util::long_path(path)).map_err(|source| ConvertError::ReadFailed {
+                source,
+                path: path.to_owned(),
+            })?;
+        let value = serde_json::from_slice(&contents).map_err(|source| {
+            ConvertError::InvalidConfigFile {
+                source,
+                path: path.to_owned(),
+            }
+        })?;
+        Ok(Self {
+            path: path.to_owned(),
+            value,
+        })
+    }
+
+    /// Returns the integer `key`, or an error if it is missing or not an integer.
+    pub fn usize(&self, key: &str) -> Result<usize, ConvertError> {
+        self.optional_usize(key)?
+            .ok_or_else(|| self.invalid_key(key))
+    }
+
+    /// Returns the integer `key`, or `None` if it is missing or null.
+    pub fn optional_usize(&self, key: &str) -> Result<Option<usize>, ConvertError> {
+        match self.value.get(key) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => value
+                .as_u64()
+                .and_then(|v| usize::try_from(v).ok())
+                .map(Some)
+                .ok_or_else(|| self.invalid_key(key)),
+        }
+    }
+
+    /// Returns the boolean `key`, or `None` if it is missing or null.
+    pub fn optional_bool(&self, key: &str) -> Result<Option<bool>, ConvertError> {
+        match self.value.get(key) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => value
+                .as_bool()
+                .map(Some)
+                .ok_or_else(|| self.invalid_key(key)),
+        }
+    }
+
+    /// Returns the names of the Hugging Face model classes the checkpoint is for, such as
+    /// `LlamaForCausalLM`.
+    pub fn architectures(&self) -> Vec<&str> {
+        self.value
+            .get("architectures")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v.as_str())
+            .collect()
+    }
+
+    fn invalid_key(&self, key: &str) -> ConvertError {
+        ConvertError::InvalidConfig {
+            key: key.to_owned(),
+            path: self.path.clone(),
+        }
+    }
+}
+
+/// Parameters for [convert_hf].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConvertParameters {
+    /// The format of the tensors of the converted model.
+    pub format: FileTypeFormat,
+    /// How to handle a vocabulary that does not match the vocabulary tensors.
+    pub vocabulary_adjustment: VocabularyAdjustment,
+}
+
+/// How [convert_hf] reconciles a checkpoint whose vocabulary tensors (see
+/// [HfConverter::vocabulary_tensors]) have a different number of rows than its tokenizer
+/// has tokens, as happens with fine-tunes that add or remove tokens.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum VocabularyAdjustment {
+    /// Fail with [ConvertError::VocabularySizeMismatch].
+    #[default]
+    None,
+    /// Add rows for the tokens that have none. Each new row is the mean of the existing
+    /// rows.
+    Pad,
+    /// Drop the rows after the last token.
+    Truncate,
+    /// Use the Hugging Face tokenizer file at this path instead of the checkpoint's, and
+    /// move each row to the ID its token has in that tokenizer. Tokens that the checkpoint
+    /// has no row for get new rows, as with [Pad](Self::Pad), and the rows of tokens that
+    /// the tokenizer does not have are dropped.
+    Remap(PathBuf),
+}
+
+/// The changes made to the vocabulary tensors by a [VocabularyAdjustment].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VocabularyChanges {
+    /// The number of rows in the checkpoint.
+    pub original_size: usize,
+    /// The number of rows in the converted model.
+    pub size: usize,
+    /// The number of rows that were kept, but moved to a different token ID.
+    pub moved: usize,
+    /// The number of rows that were added.
+    pub added: usize,
+    /// The number of rows that were dropped.
+    pub removed: usize,
+}
+impl VocabularyChanges {
+    fn new(original_size: usize, rows: &[Option<usize>]) -> Self {
+        let kept = rows
+            .iter()
+            .flatten()
+            .collect::<std::collections::HashSet<_>>();
+        Self {
+            original_size,
+            size: rows.len(),
+            moved: rows
+                .iter()
+                .enumerate()
+                .filter(|(i, row)| row.map_or(false, |row| row != *i))
+                .count(),
+            added: rows.iter().filter(|row| row.is_none()).count(),
+            removed: original_size - kept.len(),
+        }
+    }
+}
+
+/// A tensor read from a checkpoint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HfTensor {
+    /// The shape of the tensor, outermost dimension first (as in PyTorch).
+    pub shape: Vec<usize>,
+    /// The elements of the tensor, in row-major order.
+    pub data: Vec<f32>,
+}
+
+/// Returns the vocabulary of a SentencePiece-style `tokenizer` in the form embedded in
+/// GGML models: the `▁` word boundary marker is replaced with a space, and byte fallback
+/// tokens (`<0x0A>`) are replaced with the byte they represent.
+///
+/// Hugging Face tokenizers do not expose scores, so all tokens are given a score of 0.
+pub fn sentencepiece_vocabulary(tokenizer: &Tokenizer) -> Vec<(Vec<u8>, f32)> {
+    match tokenizer {
+        Tokenizer::Embedded(tokenizer) => tokenizer.iter().collect(),
+        Tokenizer::HuggingFace(tokenizer) => {
+            let tokenizer = &tokenizer.tokenizer;
+            (0..tokenizer.get_vocab_size(true))
+                .map(|id| {
+                    let piece = tokenizer.id_to_token(id as u32).unwrap_or_default();
+                    let byte = piece
+                        .strip_prefix("<0x")
+                        .and_then(|p| p.strip_suffix('>'))
+                        .filter(|hex| hex.len() == 2)
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                    let bytes = match byte {
+                        Some(byte) => vec![byte],
+                        None => piece.replace('\u{2581}', " ").into_bytes(),
+                    };
+                    (bytes, 0.0)
+                })
+                .collect()
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while converting a checkpoint.
+pub enum ConvertError {
+    #[error("could not read {path:?}")]
+    /// A file of the checkpoint could not be read.
+    ReadFailed {
+        /// The original error.
+        source: std::io::Error,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error, such as a failure to write the converted model.
+    Io(#[from] std::io::Error),
+    #[error("invalid integer conversion")]
+    /// One of the integers encountered could not be converted to a more appropriate type.
+    InvalidIntegerConversion(#[from] TryFromIntError),
+    #[error("{path:?} is not a valid model configuration")]
+    /// The `config.json` of the checkpoint is not valid JSON.
+    InvalidConfigFile {
+        /// The original error.
+        source: serde_json::Error,
+        /// The path of the configuration.
+        path: PathBuf,
+    },
+    #[error("`{key}` is missing from {path:?} or has an invalid value")]
+    /// A value required for conversion is missing from the `config.json` or is invalid.
+    InvalidConfig {
+        /// The key of the value.
+        key: String,
+        /// The path of the configuration.
+        path: PathBuf,
+    },
+    #[error("the model configuration is not supported: {reason}")]
+    /// The checkpoint uses a variant of the architecture that the model does not support.
+    UnsupportedConfig {
+        /// What is not supported.
+        reason: String,
+    },
+    #[error("no .safetensors or pytorch_model*.bin files were found in {path:?}")]
+    /// The checkpoint has no weights in the safetensors or PyTorch formats.
+    NoSafetensors {
+        /// The directory of the checkpoint.
+        path: PathBuf,
+    },
+    #[error("{path:?} is not a valid safetensors file: {reason}")]
+    /// A safetensors file is corrupt.
+    InvalidSafetensors {
+        /// The path of the file.
+        path: PathBuf,
+        /// What is wrong with the file.
+        reason: String,
+    },
+    #[error("{path:?} is not a valid PyTorch checkpoint: {reason}")]
+    /// A PyTorch checkpoint is corrupt, or uses features that cannot be read.
+    InvalidPytorch {
+        /// The path of the file.
+        path: PathBuf,
+        /// What is wrong with the file.
+        reason: String,
+    },
+    #[error("the tensor {tensor_name} has the unsupported type {dtype}")]
+    /// A tensor has an element type that cannot be converted.
+    UnsupportedDtype {
+        /// The name of the tensor in the checkpoint.
+        tensor_name: String,
+        /// The type of the tensor, as safetensors names it (`F32`), or as PyTorch names its
+        /// storage (`LongStorage`).
+        dtype: String,
+    },
+    #[error("the model architecture does not support conversion from Hugging Face checkpoints")]
+    /// The model architecture has no [HfConverter].
+    UnsupportedArchitecture,
+    #[error("cannot convert to {format}")]
+    /// The requested format cannot be produced by conversion.
+    UnsupportedFormat {
+        /// The requested format.
+        format: FileTypeFormat,
+    },
+    #[error("could not load the tokenizer")]
+    /// The `tokenizer.json` of the checkpoint could not be loaded.
+    TokenizerLoad(#[from] TokenizerLoadError),
+    #[error("the tokenizer has {tokenizer} tokens, but the model has {model}")]
+    /// The size of the vocabulary of the tokenizer does not match the vocabulary tensors
+    /// of the model, and the [VocabularyAdjustment] does not allow the difference.
+    VocabularySizeMismatch {
+        /// The number of tokens in the tokenizer.
+        tokenizer: usize,
+        /// The number of rows in the vocabulary tensors of the model.
+        model: usize,
+    },
+    #[error("invariant broken: {invariant}")]
+    /// An invariant was broken.
+    InvariantBroken {
+        /// The invariant that was broken.
+        invariant: String,
+    },
+    #[error("an error was encountered while writing the hyperparameters")]
+    /// An error was encountered while writing the hyperparameters.
+    HyperparametersWriteError(#[source] HyperparametersWriteError),
+}
+impl ConvertError {
+    fn from_format_error(value: SaveError<ConvertError>) -> Self {
+        match value {
+            SaveError::Io(io) => ConvertError::Io(io),
+            SaveError::InvalidIntegerConversion(e) => ConvertError::InvalidIntegerConversion(e),
+            SaveError::ImplementationError(e) => e,
+            SaveError::InvariantBroken(invariant) => ConvertError::InvariantBroken { invariant },
+            SaveError::VocabularyScoringNotSupported => unreachable!("GGJT supports scores"),
+        }
+    }
+}
+
+/// Converts the Hugging Face checkpoint in the directory `source` to a GGJT model, and
+/// writes it to `writer`.
+///
+/// Tensors are read, converted and written one at a time, so the whole checkpoint is
+/// never held in memory. Progress is reported with the same events as
+/// [quantize](crate::quantize()), and [QuantizeProgress::VocabularyAdjusted] if the
+/// vocabulary tensors were changed. Like [quantize](crate::quantize()), returns a report
+/// of what happened to each tensor.
+pub fn convert_hf<M: KnownModel, W: Write + Seek>(
+    source: &Path,
+    writer: &mut W,
+    params: ConvertParameters,
+    progress_callback: impl Fn(QuantizeProgress),
+) -> Result<QuantizeReport, ConvertError> {
+    let ConvertParameters {
+        format,
+        vocabulary_adjustment,
+    } = params;
+    let converter = M::hf_converter().ok_or(ConvertError::UnsupportedArchitecture)?;
+    if matches!(
+        format,
+        FileTypeFormat::MostlyQ4_1SomeF16
+            | FileTypeFormat::MostlyBF16
+            | FileTypeFormat::MostlyQ4_2
+            | FileTypeFormat::MostlyQ4_3
+    ) {
+        return Err(ConvertError::UnsupportedFormat { format });
+    }
+
+    let config = HfConfig::load(&source.join("config.json"))?;
+    let checkpoint = Checkpoint::open(source)?;
+    let mut tensors = HashMap::new();
+    let mut tensor_names = vec![];
+    for (hf_name, tensor) in &checkpoint.tensors {
+        if let Some(name) = converter.tensor_name(hf_name) {
+            tensor_names.push(name.clone());
+            tensors.insert(name, (hf_name.as_str(), tensor));
+        }
+    }
+
+    let tokenizer = TokenizerSource::HuggingFaceTokenizerFile(source.join("tokenizer.json"))
+        .retrieve(source)?;
+    let mut vocabulary = converter.vocabulary(&tokenizer);
+    let original_rows = converter
+        .vocabulary_tensors()
+        .iter()
+        .find_map(|name| tensors.get(*name))
+        .map_or(vocabulary.len(), |(_, tensor)| tensor.shape[0]);
+
+    let vocabulary_rows = match vocabulary_adjustment {
+        VocabularyAdjustment::Remap(path) => {
+            let target = converter
+                .vocabulary(&TokenizerSource::HuggingFaceTokenizerFile(path).retrieve(source)?);
+            let rows = remap_rows(&vocabulary, &target, original_rows);
+            vocabulary = target;
+            Some(rows)
+        }
+        _ if original_rows == vocabulary.len() => None,
+        VocabularyAdjustment::Pad if original_rows < vocabulary.len() => Some(
+            (0..vocabulary.len())
+                .map(|i| (i < original_rows).then_some(i))
+                .collect(),
+        ),
+        VocabularyAdjustment::Truncate if original_rows > vocabulary.len() => {
+            Some((0..vocabulary.len()).map(Some).collect())
+        }
+        _ => {
+            return Err(ConvertError::VocabularySizeMismatch {
+                tokenizer: vocabulary.len(),
+                model: original_rows,
+            })
+        }
+    };
+    if let Some(rows) = &vocabulary_rows {
+        progress_callback(QuantizeProgress::VocabularyAdjusted {
+            changes: VocabularyChanges::new(original_rows, rows),
+        });
+    }
+
+    let quantization_version = match format {
+        FileTypeFormat::F32 | FileTypeFormat::MostlyF16 => 0,
+        _ => ggml::QNT_VERSION,
+    };
+    let hyperparameters = converter.hyperparameters(
+        &config,
+        vocabulary.len(),
+        FileType {
+            format,
+            quantization_version,
+        },
+    )?;
+    progress_callback(QuantizeProgress::HyperparametersLoaded);
+
+    let to_quantize = M::quantize_tensors();
+    let to_skip = M::skip_quantize_tensors();
+    let mut saver = ConvertSaver {
+        converter: converter.as_ref(),
+        hyperparameters: &hyperparameters,
+        checkpoint: &checkpoint,
+        tensors: &tensors,
+        positions: layer_positions(&tensor_names),
+        vocabulary_rows: vocabulary_rows.map(|rows| (original_rows, rows)),
+        format,
+        to_quantize: &to_quantize,
+        to_skip: &to_skip,
+        progress_callback: &progress_callback,
+
+        report: QuantizeReport::default(),
+    };
+    ggml::format::save(
+        writer,
+        &mut saver,
+        SaveContainerType::GgjtV3,
+        &vocabulary,
+        &tensor_names,
+    )
+    .map_err(ConvertError::from_format_error)?;
+
+    progress_callback(saver.report.finished());
+
+    Ok(saver.report)
+}
+
+struct ConvertSaver<'a, H: Hyperparameters, F: Fn(QuantizeProgress)> {
+    // Input
+    converter: &'a dyn HfConverter<H>,
+    hyperparameters: &'a H,
+    checkpoint: &'a Checkpoint,
+    tensors: &'a HashMap<String, (&'a str, &'a CheckpointTensor)>,
+    positions: HashMap<String, (usize, usize)>,
+    /// The number of rows of the vocabulary tensors in the checkpoint, and the row each
+    /// row of the converted tensors is copied from (or `None` for new rows).
+    vocabulary_rows: Option<(usize, Vec<Option<usize>>)>,
+    format: FileTypeFormat,
+    to_quantize: &'a [Regex],
+    to_skip: &'a [Regex],
+    progress_callback: &'a F,
+
+    // Output
+    report: QuantizeReport,
+}
+impl<H: Hyperparameters, F: Fn(QuantizeProgress)> SaveHandler<ConvertError>
+    for ConvertSaver<'_, H, F>
+{
+    fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), ConvertError> {
+        self.hyperparameters
+            .write_ggml(writer)
+            .map_err(ConvertError::HyperparametersWriteError)
+    }
+
+    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, ConvertError> {
+        let (hf_name, info) = self.tensors[tensor_name];
+        let n_dims = info.shape.len();
+        if !(1..=2).contains(&n_dims) {
+            return Err(ConvertError::InvariantBroken {
+                invariant: format!("{hf_name} has 1 or 2 dimensions"),
+            });
+        }
+        let original_size = info.data.len();
+
+        let original_element_type = match info.dtype.as_str() {
+            "F16" => ggml::Type::F16,
+            _ => ggml::Type::F32,
+        };
+        (self.progress_callback)(QuantizeProgress::TensorLoading {
+            name: tensor_name,
+            dims: ggml_dims(&info.shape),
+            element_type: original_element_type,
+            n_elements: info.shape.iter().product(),
+        });
+
+        let mut tensor = self.checkpoint.read(hf_name, info)?;
+        self.converter
+            .transform(self.hyperparameters, tensor_name, &mut tensor)?;
+        if let Some((original_rows, rows)) = &self.vocabulary_rows {
+            if self.converter.vocabulary_tensors().contains(&tensor_name) {
+                if tensor.shape[0] != *original_rows {
+                    return Err(ConvertError::InvariantBroken {
+                        invariant: format!("{hf_name} has {original_rows} rows"),
+                    });
+                }
+                select_rows(&mut tensor, rows);
+            }
+        }
+        let dims = ggml_dims(&tensor.shape);
+        let n_elements = dims[0] * dims[1];
+
+        let quantize = self.to_quantize.iter().any(|re| re.is_match(tensor_name))
+            && !self.to_skip.iter().any(|re| re.is_match(tensor_name));
+        let element_type = element_type(
+            self.format,
+            tensor_name,
+            n_dims,
+            dims[0],
+            quantize,
+            self.positions.get(tensor_name).copied(),
+        );
+
+        let mut histogram = None;
+        let data = match element_type {
+            ggml::Type::F32 => tensor.data.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ggml::Type::F16 => tensor
+                .data
+                .iter()
+                .flat_map(|v| f16::from_f32(*v).to_le_bytes())
+                .collect(),
+            _ => {
+                (self.progress_callback)(QuantizeProgress::TensorQuantizing { name: tensor_name });
+                let result = quantize_data(element_type, &tensor.data, n_elements, dims[0]);
+                let tensor_histogram = QuantizationHistogram::from_ggml(&result.history);
+
+                (self.progress_callback)(QuantizeProgress::TensorQuantized {
+                    name: tensor_name,
+                    original_size,
+                    reduced_size: result.output.len(),
+                    histogram: tensor_histogram.clone(),
+                });
+                histogram = Some(tensor_histogram);
+                result.output
+            }
+        };
+        if matches!(element_type, ggml::Type::F32 | ggml::Type::F16) {
+            (self.progress_callback)(QuantizeProgress::TensorSkipped {
+                name: tensor_name,
+                size: data.len(),
+            });
+        }
+        self.report.record(TensorQuantizeStats {
+            name: tensor_name.to_owned(),
+            original_element_type,
+            element_type,
+            n_elements,
+            original_size,
+            reduced_size: data.len(),
+            histogram,
+        });
+
+        Ok(TensorSaveInfo {
+            n_dims,
+            dims,
+            element_type,
+            data,
+        })
+    }
+}
+
+/// Returns the dimensions of a tensor with the given `shape` as GGML lists them, innermost
+/// first.
+fn ggml_dims(shape: &[usize]) -> [usize; 2] {
+    match *shape {
+        [cols] => [cols, 1],
+        [rows, cols] => [cols, rows],
+        _ => unreachable!("tensors have 1 or 2 dimensions"),
+    }
+}
+
+/// Returns the row of the checkpoint each token of `target` should use: the row of the
+/// same token in `source`, or `None` if `source` does not have the token or the checkpoint
+/// has no row for it.
+fn remap_rows(
+    source: &[(Vec<u8>, f32)],
+    target: &[(Vec<u8>, f32)],
+    original_rows: usize,
+) -> Vec<Option<usize>> {
+    let mut source_ids = HashMap::new();
+    for (id, (token, _)) in source.iter().enumerate().take(original_rows) {
+        source_ids.entry(token.as_slice()).or_insert(id);
+    }
+    target
+        .iter()
+        .map(|(token, _)| source_ids.get(token.as_slice()).copied())
+        .collect()
+}
+
+/// Rebuilds the rows of `tensor` from `rows`: each row is copied from the given row of
+/// the original tensor, or is the mean of all of its rows if `None`.
+fn select_rows(tensor: &mut HfTensor, rows: &[Option<usize>]) {
+    let original_rows = tensor.shape[0];
+    let cols = tensor.data.len() / original_rows.max(1);
+
+    let mut mean = vec![0.0; cols];
+    for row in tensor.data.chunks_exact(cols) {
+        for (mean, value) in mean.iter_mut().zip(row) {
+            *mean += value / original_rows as f32;
+        }
+    }
+
+    tensor.data = rows
+        .iter()
+        .flat_map(|row| match row {
+            Some(row) => &tensor.data[row * cols..(row + 1) * cols],
+            None => &mean[..],
+        })
+        .copied()
+        .collect();
+    tensor.shape[0] = rows.len();
+}
+
+/// Chooses the element type of a tensor with `n_dims` dimensions and rows of `row_size`
+/// elements in a model of the given `format`.
+///
+/// `position` is the index of the tensor among the tensors with the same role in other
+/// layers, and the number of those tensors; the k-quant mixes (e.g. `Q4_K_M`) use more
+/// bits for some of the attention value and feed-forward output tensors.
+fn element_type(
+    format: FileTypeFormat,
+    name: &str,
+    n_dims: usize,
+    row_size: usize,
+    quantize: bool,
+    position: Option<(usize, usize)>,
+) -> ggml::Type {
+    use ggml::Type as T;
+    use FileTypeFormat as F;
+
+    if n_dims == 1 || format == F::F32 {
+        return T::F32;
+    }
+    if !quantize || format == F::MostlyF16 {
+        return T::F16;
+    }
+
+    let use_more_bits = position.map_or(false, |(i, n)| {
+        i < n / 8 || i >= 7 * n / 8 || (i - n / 8) % 3 == 2
+    });
+    let feeds_residual = name.ends_with("attention.wv.weight")
+        || name.ends_with("feed_forward.w2.weight")
+        || name.ends_with("attention.wo.weight");
+    let base = match format {
+        F::MostlyQ4_0 => T::Q4_0,
+        F::MostlyQ4_1 => T::Q4_1,
+        F::MostlyQ5_0 => T::Q5_0,
+        F::MostlyQ5_1 => T::Q5_1,
+        F::MostlyQ8_0 => T::Q8_0,
+        _ if name == "output.weight" => T::Q6_K,
+        F::MostlyQ2_K | F::MostlyQ3_K_M if feeds_residual => T::Q4_K,
+        F::MostlyQ3_K_L if feeds_residual => T::Q5_K,
+        F::MostlyQ4_K_M | F::MostlyQ5_K_M if use_more_bits => T::Q6_K,
+        F::MostlyQ2_K => T::Q2_K,
+        F::MostlyQ3_K_S | F::MostlyQ3_K_M | F::MostlyQ3_K_L => T::Q3_K,
+        F::MostlyQ4_K_S | F::MostlyQ4_K_M => T::Q4_K,
+        F::MostlyQ5_K_S | F::MostlyQ5_K_M => T::Q5_K,
+        F::MostlyQ6_K => T::Q6_K,
+        F::F32
+        | F::MostlyF16
+        | F::MostlyQ4_1SomeF16
+        | F::MostlyBF16
+        | F::MostlyQ4_2
+        | F::MostlyQ4_3 => {
+            unreachable!("handled by the caller")
+        }
+    };
+
+    // k-quants work on blocks of `QK_K` elements, so narrower rows fall back to the
+    // closest legacy type.
+    match base {
+        T::Q2_K | T::Q3_K if row_size % ggml::QK_K != 0 => T::Q4_0,
+        T::Q4_K if row_size % ggml::QK_K != 0 => T::Q5_0,
+        T::Q5_K if row_size % ggml::QK_K != 0 => T::Q5_1,
+        T::Q6_K if row_size % ggml::QK_K != 0 => T::Q8_0,
+        base => base,
+    }
+}
+
+/// Returns the position of each attention value and feed-forward output tensor among the
+/// tensors with the same role, ordered by layer, and the number of those tensors.
+fn layer_positions(tensor_names: &[String]) -> HashMap<String, (usize, usize)> {
+    let mut positions = HashMap::new();
+    for role in ["attention.wv.weight", "feed_forward.w2.weight"] {
+        let mut layers: Vec<(usize, &String)> = tensor_names
+            .iter()
+            .filter(|name| name.ends_with(role))
+            .filter_map(|name| {
+                let layer = name.strip_prefix("layers.")?.split('.').next()?;
+                Some((layer.parse().ok()?, name))
+            })
+            .collect();
+        layers.sort();
+
+        let count = layers.len();
+        for (i, (_, name)) in layers.into_iter().enumerate() {
+            positions.insert(name.clone(), (i, count));
+        }
+    }
+    positions
+}
+
+struct CheckpointTensor {
+    file: usize,
+    dtype: String,
+    shape: Vec<usize>,
+    /// The range of the tensor's data in its file.
+    data: Range<usize>,
+}
+
+/// The memory-mapped weight files of a checkpoint.
+struct Checkpoint {
+    files: Vec<(PathBuf, Mmap)>,
+    /// The tensors in the order they are stored.
+    tensors: Vec<(String, CheckpointTensor)>,
+}
+impl Checkpoint {
+    /// Opens the safetensors files in `dir`, or its PyTorch files if it has none.
+    fn open(dir: &Path) -> Result<Self, ConvertError> {
+        let read_failed = |source, path: &Path| ConvertError::ReadFailed {
+            source,
+            path: path.to_owned(),
+        };
+
+        let mut paths = vec![];
+        let mut pytorch_paths = vec![];
+        for entry in std::fs::read_dir(util::long_path(dir)).map_err(|e| read_failed(e, dir))? {
+            let path = entry.map_err(|e| read_failed(e, dir))?.path();
+            let is_pytorch = path.extension().map_or(false, |ext| ext == "bin")
+                && path.file_name().map_or(false, |name| {
+                    name.to_string_lossy().starts_with("pytorch_model")
+                });
+            if path.extension().map_or(false, |ext| ext == "safetensors") {
+                paths.push(path);
+            } else if is_pytorch {
+                pytorch_paths.push(path);
+            }
+        }
+        let pytorch = paths.is_empty();
+        if pytorch {
+            paths = pytorch_paths;
+        }
+        if paths.is_empty() {
+            return Err(ConvertError::NoSafetensors {
+                path: dir.to_owned(),
+            });
+        }
+        // Shards are numbered, so this keeps the layers in order.
+        paths.sort();
+
+        let mut files = vec![];
+        let mut tensors = vec![];
+        for (file, path) in paths.into_iter().enumerate() {
+            let handle = File::open(util::long_path(&path)).map_err(|e| read_failed(e, &path))?;
+            let mmap = unsafe { Mmap::map(&handle) }.map_err(|e| read_failed(e, &path))?;
+
+            let mut header = if pytorch {
+                pytorch::parse_checkpoint(&mmap).map_err(|reason| ConvertError::InvalidPytorch {
+                    path: path.clone(),
+                    reason,
+                })?
+            } else {
+                parse_header(&mmap).map_err(|reason| ConvertError::InvalidSafetensors {
+                    path: path.clone(),
+                    reason,
+                })?
+            };
+            header.sort_by_key(|(_, tensor)| tensor.data.start);
+            tensors.extend(
+                header
+                    .into_iter()
+                    .map(|(name, tensor)| (name, CheckpointTensor { file, ..tensor })),
+            );
+            files.push((path, mmap));
+        }
+
+        Ok(Self { files, tensors })
+    }
+
+    fn read(&self, name: &str, tensor: &CheckpointTensor) -> Result<HfTensor, ConvertError> {
+        let bytes = &self.files[tensor.file].1[tensor.data.clone()];
+        let data = match tensor.dtype.as_str() {
+            "F32" => bytes
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+            "F16" => bytes
+                .chunks_exact(2)
+                .map(|chunk| f16::from_le_bytes(chunk.try_into().unwrap()).to_f32())
+                .collect(),
+            "BF16" => bytes
+                .chunks_exact(2)
+                .map(|chunk| bf16::from_le_bytes(chunk.try_into().unwrap()).to_f32())
+                .collect(),
+            dtype => {
+                return Err(ConvertError::UnsupportedDtype {
+                    tensor_name: name.to_owned(),
+                    dtype: dtype.to_owned(),
+                })
+            }
+        };
+        Ok(HfTensor {
+            shape: tensor.shape.clone(),
+            data,
+        })
+    }
+}
+
+/// Parses the header of the safetensors file `bytes`, returning its tensors with their
+/// data ranges relative to the start of the file.
+fn parse_header(bytes: &[u8]) -> Result<Vec<(String, CheckpointTensor)>, String> {
+    #[derive(serde::Deserialize)]
+    struct Entry {
+        dtype: String,
+        shape: Vec<usize>,
+        data_offsets: [usize; 2],
+    }
+
+    let header_len = bytes
+        .get(..8)
+        .map(|len| u64::from_le_bytes(len.try_into().unwrap()))
+        .ok_or("the file is too short")?;
+    let data_start = usize::try_from(header_len)
+        .ok()
+        .and_then(|len| len.checked_add(8))
+        .filter(|&start| start <= bytes.len())
+        .ok_or("the header is longer than the file")?;
+    let header: HashMap<String, serde_json::Value> =
+        serde_json::from_slice(&bytes[8..data_start]).map_err(|e| e.to_string())?;
+
+    let data_len = bytes.len() - data_start;
+    let mut tensors = vec![];
+    for (name, value) in header {
+        if name == "__metadata__" {
+            continue;
+        }
+        let Entry {
+            dtype,
+            shape,
+            data_offsets: [start, end],
+        } = serde_json::from_value(value).map_err(|e| format!("{name}: {e}"))?;
+
+        if start > end || end > data_len {
+            return Err(format!("{name}: the data is out of bounds"));
+        }
+        let element_size = match dtype.as_str() {
+            "F32" => Some(4),
+            "F16" | "BF16" => Some(2),
+            _ => None,
+        };
+        if let Some(element_size) = element_size {
+            if shape.iter().product::<usize>() * element_size != end - start {
+                return Err(format!("{name}: the data does not match the shape"));
+            }
+        }
+
+        tensors.push((
+            name,
+            CheckpointTensor {
+                file: 0,
+                dtype,
+                shape,
+                data: data_start + start..data_start + end,
+            },
+        ));
+    }
+    Ok(tensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safetensors_header_is_validated() {
+        let file = |header: &str, data_len: usize| {
+            let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+            bytes.extend(header.as_bytes());
+            bytes.resize(bytes.len() + data_len, 0);
+            bytes
+        };
+
+        let header = r#"{"__metadata__":{"format":"pt"},"a":{"dtype":"F16","shape":[2,3],"data_offsets":[0,12]}}"#;
+        let tensors = parse_header(&file(header, 12)).unwrap();
+        assert_eq!(tensors.len(), 1);
+        assert_eq!(tensors[0].0, "a");
+        assert_eq!(tensors[0].1.shape, [2, 3]);
+        assert_eq!(tensors[0].1.data, 8 + header.len()..8 + header.len() + 12);
+
+        assert!(parse_header(&file(header, 11)).is_err());
+        let header = r#"{"a":{"dtype":"F32","shape":[2,3],"data_offsets":[0,12]}}"#;
+        assert!(parse_header(&file(header, 12)).is_err());
+    }
+
+    #[test]
+    fn vocabulary_rows_are_remapped() {
+        let vocabulary = |tokens: &[&str]| -> Vec<(Vec<u8>, f32)> {
+            tokens
+                .iter()
+                .map(|t| (t.as_bytes().to_vec(), 0.0))
+                .collect()
+        };
+        let rows = remap_rows(
+            &vocabulary(&["a", "b", "c"]),
+            &vocabulary(&["c", "a", "d"]),
+            3,
+        );
+        assert_eq!(rows, [Some(2), Some(0), None]);
+
+        let changes = VocabularyChanges::new(3, &rows);
+        assert_eq!((changes.moved, changes.added, changes.removed), (2, 1, 1));
+
+        let mut tensor = HfTensor {
+            shape: vec![3, 2],
+            data: vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        };
+        select_rows(&mut tensor, &rows);
+        assert_eq!(tensor.shape, [3, 2]);
+        assert_eq!(tensor.data, [5.0, 6.0, 1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn k_quant_mixes_match_llama_cpp() {
+        use ggml::Type as T;
+        use FileTypeFormat as F;
+        let ty = |format, name, row_size, position| {
+            element_type(format, name, 2, row_size, true, position)
+        };
+
+        assert_eq!(
+            element_type(F::MostlyQ4_K_M, "norm.weight", 1, 4096, true, None),
+            T::F32
+        );
+        assert_eq!(
+            element_type(F::MostlyQ4_K_M, "x.weight", 2, 4096, false, None),
+            T::F16
+        );
+        assert_eq!(ty(F::MostlyQ4_K_M, "output.weight", 4096, None), T::Q6_K);
+        assert_eq!(
+            ty(F::MostlyQ4_K_M, "layers.0.attention.wq.weight", 4096, None),
+            T::Q4_K
+        );
+
+        // Of 32 layers, the first and last 4 and every third in between use more bits.
+        let wv = "layers.0.attention.wv.weight";
+        assert_eq!(ty(F::MostlyQ4_K_M, wv, 4096, Some((0, 32))), T::Q6_K);
+        assert_eq!(ty(F::MostlyQ4_K_M, wv, 4096, Some((4, 32))), T::Q4_K);
+        assert_eq!(ty(F::MostlyQ4_K_M, wv, 4096, Some((6, 32))), T::Q6_K);
+        assert_eq!(ty(F::MostlyQ4_K_S, wv, 4096, Some((0, 32))), T::Q4_K);
+        assert_eq!(ty(F::MostlyQ3_K_L, wv, 4096, Some((4, 32))), T::Q5_K);
+
+        // Rows that are not a multiple of the k-quant block size fall back.
+        assert_eq!(ty(F::MostlyQ4_K_S, "x.weight", 4000, None), T::Q5_0);
+    }
+}
@@ -0,0 +1,447 @@
+//! Reading of PyTorch checkpoints (`pytorch_model*.bin`).
+//!
+//! Since PyTorch 1.6, `torch.save` writes a zip archive containing the pickled state dict
+//! (`<archive>/data.pkl`) and the data of each storage, uncompressed, in
+//! `<archive>/data/<key>`. The pickle is interpreted just far enough to find the storage,
+//! offset and shape of each tensor; the data is then read in place, like safetensors.
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+};
+
+use super::CheckpointTensor;
+
+/// Returns the tensors of the PyTorch checkpoint `bytes`, with their data ranges relative to
+/// the start of the file.
+pub(super) fn parse_checkpoint(bytes: &[u8]) -> Result<Vec<(String, CheckpointTensor)>, String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(|e| {
+        format!("{e} (checkpoints saved in the legacy format of PyTorch 1.5 and earlier are not supported)")
+    })?;
+    let pickle_name = archive
+        .file_names()
+        .find(|name| name.ends_with("data.pkl"))
+        .ok_or("the archive has no data.pkl")?
+        .to_owned();
+    let prefix = pickle_name.strip_suffix("data.pkl").unwrap_or_default();
+
+    let mut pickle = vec![];
+    archive
+        .by_name(&pickle_name)
+        .and_then(|mut file| Ok(file.read_to_end(&mut pickle)?))
+        .map_err(|e| format!("could not read {pickle_name}: {e}"))?;
+    let Value::Dict(state_dict) = unpickle(&pickle)? else {
+        return Err("the checkpoint is not a state dict".to_string());
+    };
+
+    let mut tensors = vec![];
+    for (name, value) in state_dict {
+        // Other values, such as nested dicts or version numbers, are not weights.
+        let (Value::String(name), Value::Tensor(tensor)) = (name, value) else {
+            continue;
+        };
+
+        let Some(element_size) = element_size(&tensor.dtype) else {
+            tensors.push((
+                name,
+                CheckpointTensor {
+                    file: 0,
+                    dtype: tensor.dtype,
+                    shape: tensor.shape,
+                    data: 0..0,
+                },
+            ));
+            continue;
+        };
+        let mut contiguous_stride = vec![1; tensor.shape.len()];
+        for i in (0..tensor.shape.len().saturating_sub(1)).rev() {
+            contiguous_stride[i] = contiguous_stride[i + 1] * tensor.shape[i + 1];
+        }
+        if tensor.stride != contiguous_stride {
+            return Err(format!("{name}: the tensor is not contiguous"));
+        }
+
+        let storage_name = format!("{prefix}data/{}", tensor.storage);
+        let storage = archive
+            .by_name(&storage_name)
+            .map_err(|e| format!("{name}: could not find {storage_name}: {e}"))?;
+        if storage.compression() != zip::CompressionMethod::Stored {
+            return Err(format!("{name}: the storage is compressed"));
+        }
+        let storage_start = usize::try_from(storage.data_start()).map_err(|e| e.to_string())?;
+        let storage_len = usize::try_from(storage.size()).map_err(|e| e.to_string())?;
+
+        let n_elements: usize = tensor.shape.iter().product();
+        let start = tensor.offset * element_size;
+        let end = start + n_elements * element_size;
+        if end > storage_len || storage_start + storage_len > bytes.len() {
+            return Err(format!("{name}: the data is out of bounds"));
+        }
+        tensors.push((
+            name,
+            CheckpointTensor {
+                file: 0,
+                dtype: tensor.dtype,
+                shape: tensor.shape,
+                data: storage_start + start..storage_start + end,
+            },
+        ));
+    }
+    Ok(tensors)
+}
+
+/// Returns the size of an element of the given safetensors-style `dtype`.
+fn element_size(dtype: &str) -> Option<usize> {
+    match dtype {
+        "F32" => Some(4),
+        "F16" | "BF16" => Some(2),
+        _ => None,
+    }
+}
+
+/// A tensor, as rebuilt by `torch._utils._rebuild_tensor_v2`.
+#[derive(Clone, Debug, PartialEq)]
+struct TensorRef {
+    /// The element type, named like safetensors names it.
+    dtype: String,
+    /// The key of the storage the tensor is in.
+    storage: String,
+    /// The index of the first element of the tensor in the storage.
+    offset: usize,
+    shape: Vec<usize>,
+    stride: Vec<usize>,
+}
+
+/// The subset of Python values that a state dict is made of.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Dict(Vec<(Value, Value)>),
+    /// A class or function, by module and name.
+    Global(String, String),
+    /// A storage, loaded from a persistent ID: its element type and key.
+    Storage(String, String),
+    Tensor(TensorRef),
+    /// Any other object. It is carried along, but its contents are not known.
+    Object,
+}
+
+/// Interprets the pickle `bytes`, returning the value it describes.
+///
+/// Only the opcodes written by `torch.save` are supported, and only the classes and functions
+/// used to rebuild tensors and state dicts are understood; anything else becomes
+/// [Value::Object].
+fn unpickle(bytes: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let mut take = |n: usize| reader.take(n);
+
+    let mut stack: Vec<Value> = vec![];
+    let mut marks: Vec<usize> = vec![];
+    let mut memo: HashMap<usize, Value> = HashMap::new();
+    let pop = |stack: &mut Vec<Value>| stack.pop().ok_or("the pickle stack is empty".to_string());
+    let pop_mark = |stack: &mut Vec<Value>, marks: &mut Vec<usize>| -> Result<Vec<Value>, String> {
+        let mark = marks.pop().ok_or("the pickle has no mark")?;
+        if mark > stack.len() {
+            return Err("the pickle mark is invalid".to_string());
+        }
+        Ok(stack.split_off(mark))
+    };
+
+    loop {
+        let opcode = take(1)?[0];
+        match opcode {
+            // PROTO
+            0x80 => {
+                take(1)?;
+            }
+            // FRAME
+            0x95 => {
+                take(8)?;
+            }
+            // STOP
+            b'.' => return pop(&mut stack),
+            b'(' => marks.push(stack.len()),
+            b'N' => stack.push(Value::None),
+            0x88 => stack.push(Value::Bool(true)),
+            0x89 => stack.push(Value::Bool(false)),
+            // BININT, BININT1, BININT2
+            b'J' => stack.push(Value::Int(
+                i32::from_le_bytes(take(4)?.try_into().unwrap()).into(),
+            )),
+            b'K' => stack.push(Value::Int(take(1)?[0].into())),
+            b'M' => stack.push(Value::Int(
+                u16::from_le_bytes(take(2)?.try_into().unwrap()).into(),
+            )),
+            // LONG1
+            0x8a => {
+                let n = take(1)?[0] as usize;
+                let data = take(n)?;
+                if n > 8 {
+                    return Err("the pickle has an integer that is too large".to_string());
+                }
+                let fill = if data.last().map_or(false, |b| b & 0x80 != 0) {
+                    0xff
+                } else {
+                    0
+                };
+                let mut le = [fill; 8];
+                le[..n].copy_from_slice(data);
+                stack.push(Value::Int(i64::from_le_bytes(le)));
+            }
+            // BINFLOAT
+            b'G' => stack.push(Value::Float(f64::from_be_bytes(
+                take(8)?.try_into().unwrap(),
+            ))),
+            // SHORT_BINUNICODE, BINUNICODE, BINUNICODE8, SHORT_BINSTRING, BINSTRING
+            0x8c | b'X' | 0x8d | b'U' | b'T' => {
+                let n = match opcode {
+                    0x8c | b'U' => take(1)?[0] as usize,
+                    0x8d => usize::try_from(u64::from_le_bytes(take(8)?.try_into().unwrap()))
+                        .map_err(|e| e.to_string())?,
+                    _ => u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize,
+                };
+                stack.push(Value::String(
+                    String::from_utf8_lossy(take(n)?).into_owned(),
+                ));
+            }
+            // SHORT_BINBYTES, BINBYTES
+            b'C' | b'B' => {
+                let n = match opcode {
+                    b'C' => take(1)?[0] as usize,
+                    _ => u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize,
+                };
+                take(n)?;
+                stack.push(Value::Object);
+            }
+            b')' => stack.push(Value::Tuple(vec![])),
+            b']' => stack.push(Value::List(vec![])),
+            b'}' => stack.push(Value::Dict(vec![])),
+            b't' => {
+                let items = pop_mark(&mut stack, &mut marks)?;
+                stack.push(Value::Tuple(items));
+            }
+            // TUPLE1, TUPLE2, TUPLE3
+            0x85..=0x87 => {
+                let n = (opcode - 0x84) as usize;
+                if stack.len() < n {
+                    return Err("the pickle stack is empty".to_string());
+                }
+                let items = stack.split_off(stack.len() - n);
+                stack.push(Value::Tuple(items));
+            }
+            // APPEND, APPENDS
+            b'a' | b'e' => {
+                let items = match opcode {
+                    b'a' => vec![pop(&mut stack)?],
+                    _ => pop_mark(&mut stack, &mut marks)?,
+                };
+                if let Some(Value::List(list)) = stack.last_mut() {
+                    list.extend(items);
+                }
+            }
+            // SETITEM, SETITEMS
+            b's' | b'u' => {
+                let items = match opcode {
+                    b's' => {
+                        let value = pop(&mut stack)?;
+                        vec![pop(&mut stack)?, value]
+                    }
+                    _ => pop_mark(&mut stack, &mut marks)?,
+                };
+                if let Some(Value::Dict(dict)) = stack.last_mut() {
+                    let mut items = items.into_iter();
+                    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                        dict.push((key, value));
+                    }
+                }
+            }
+            // BINPUT, LONG_BINPUT, MEMOIZE
+            b'q' | b'r' | 0x94 => {
+                let index = match opcode {
+                    b'q' => take(1)?[0] as usize,
+                    b'r' => u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize,
+                    _ => memo.len(),
+                };
+                let value = stack.last().ok_or("the pickle stack is empty")?.clone();
+                memo.insert(index, value);
+            }
+            // BINGET, LONG_BINGET
+            b'h' | b'j' => {
+                let index = match opcode {
+                    b'h' => take(1)?[0] as usize,
+                    _ => u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize,
+                };
+                let value = memo
+                    .get(&index)
+                    .ok_or("the pickle refers to an unknown memo")?
+                    .clone();
+                stack.push(value);
+            }
+            // GLOBAL
+            b'c' => {
+                let mut line = || -> Result<String, String> {
+                    let mut line = vec![];
+                    loop {
+                        match take(1)?[0] {
+                            b'\n' => return Ok(String::from_utf8_lossy(&line).into_owned()),
+                            c => line.push(c),
+                        }
+                    }
+                };
+                let module = line()?;
+                let name = line()?;
+                stack.push(Value::Global(module, name));
+            }
+            // STACK_GLOBAL
+            0x93 => {
+                let name = pop(&mut stack)?;
+                let module = pop(&mut stack)?;
+                let (Value::String(module), Value::String(name)) = (module, name) else {
+                    return Err("the pickle has an invalid global".to_string());
+                };
+                stack.push(Value::Global(module, name));
+            }
+            // BINPERSID
+            b'Q' => {
+                let pid = pop(&mut stack)?;
+                stack.push(persistent_load(pid)?);
+            }
+            // REDUCE, NEWOBJ
+            b'R' | 0x81 => {
+                let args = pop(&mut stack)?;
+                let callable = pop(&mut stack)?;
+                stack.push(call(callable, args)?);
+            }
+            // BUILD: the state of the object is not needed.
+            b'b' => {
+                pop(&mut stack)?;
+            }
+            _ => {
+                return Err(format!(
+                    "the pickle has the unsupported opcode {opcode:#04x}"
+                ))
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let data = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or("the pickle ended unexpectedly")?;
+        self.pos += n;
+        Ok(data)
+    }
+}
+
+/// Loads the object referred to by the persistent ID `pid`. PyTorch uses these for storages:
+/// `('storage', storage_type, key, location, numel)`.
+fn persistent_load(pid: Value) -> Result<Value, String> {
+    match pid {
+        Value::Tuple(items) => match items.as_slice() {
+            [Value::String(kind), Value::Global(_, storage_type), Value::String(key), ..]
+                if kind == "storage" =>
+            {
+                let dtype = match storage_type.as_str() {
+                    "FloatStorage" => "F32",
+                    "HalfStorage" => "F16",
+                    "BFloat16Storage" => "BF16",
+                    other => other,
+                };
+                Ok(Value::Storage(dtype.to_string(), key.clone()))
+            }
+            _ => Err("the pickle has an unsupported persistent ID".to_string()),
+        },
+        _ => Err("the pickle has an unsupported persistent ID".to_string()),
+    }
+}
+
+/// Calls `callable` with `args`, for the few callables needed to rebuild a state dict.
+fn call(callable: Value, args: Value) -> Result<Value, String> {
+    let Value::Global(module, name) = callable else {
+        return Ok(Value::Object);
+    };
+    let args = match args {
+        Value::Tuple(args) => args,
+        _ => return Ok(Value::Object),
+    };
+    let usizes = |value: &Value| -> Result<Vec<usize>, String> {
+        match value {
+            Value::Tuple(items) => items
+                .iter()
+                .map(|item| match item {
+                    Value::Int(i) => usize::try_from(*i).map_err(|e| e.to_string()),
+                    _ => Err("a tensor has an invalid shape".to_string()),
+                })
+                .collect(),
+            _ => Err("a tensor has an invalid shape".to_string()),
+        }
+    };
+
+    Ok(match (module.as_str(), name.as_str()) {
+        ("collections", "OrderedDict") => Value::Dict(vec![]),
+        ("torch._utils", "_rebuild_tensor_v2") => match args.as_slice() {
+            [Value::Storage(dtype, storage), Value::Int(offset), shape, stride, ..] => {
+                Value::Tensor(TensorRef {
+                    dtype: dtype.clone(),
+                    storage: storage.clone(),
+                    offset: usize::try_from(*offset).map_err(|e| e.to_string())?,
+                    shape: usizes(shape)?,
+                    stride: usizes(stride)?,
+                })
+            }
+            _ => return Err("a tensor could not be rebuilt".to_string()),
+        },
+        ("torch._utils", "_rebuild_parameter") => match args.into_iter().next() {
+            Some(tensor @ Value::Tensor(_)) => tensor,
+            _ => return Err("a parameter could not be rebuilt".to_string()),
+        },
+        _ => Value::Object,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_dicts_are_unpickled() {
+        // `pickle.dumps` (protocol 2) of the state dict {"a.weight": <2x3 f16 tensor at offset 4
+        // of storage "0">}, as written by `torch.save`.
+        let mut pickle = vec![0x80, 2];
+        pickle.extend(b"ccollections\nOrderedDict\nq\x00)Rq\x01(");
+        pickle.extend(b"X\x08\x00\x00\x00a.weightq\x02");
+        pickle.extend(b"ctorch._utils\n_rebuild_tensor_v2\nq\x03");
+        pickle.extend(b"((X\x07\x00\x00\x00storageq\x04ctorch\nHalfStorage\nq\x05X\x01\x00\x00\x000q\x06X\x03\x00\x00\x00cpuq\x07K\x0atq\x08Q");
+        pickle.extend(b"K\x04K\x02K\x03\x86q\x09K\x03K\x01\x86q\x0a\x89h\x00)Rq\x0btq\x0cRq\x0d");
+        pickle.extend(b"u}q\x0eX\x09\x00\x00\x00_metadataq\x0fh\x00)Rsb.");
+
+        let Value::Dict(state_dict) = unpickle(&pickle).unwrap() else {
+            panic!("not a dict");
+        };
+        assert_eq!(
+            state_dict,
+            [(
+                Value::String("a.weight".to_string()),
+                Value::Tensor(TensorRef {
+                    dtype: "F16".to_string(),
+                    storage: "0".to_string(),
+                    offset: 4,
+                    shape: vec![2, 3],
+                    stride: vec![3, 1],
+                })
+            )]
+        );
+    }
+}
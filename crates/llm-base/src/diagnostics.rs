@@ -0,0 +1,201 @@
+//! Structured reporting of notable events that are not errors, such as a tensor falling
+//! back to another tensor or memory mapping being disabled.
+//!
+//! Events are sent to the [Diagnostics] callback in [ModelParameters](crate::ModelParameters).
+//! By default, they are forwarded to the [log] crate.
+use std::{fmt::Display, sync::Arc};
+
+/// How important a [Diagnostic] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DiagnosticLevel {
+    /// Something the user may want to know about, but that does not need attention.
+    Info,
+    /// Something that is likely to be a mistake, or that may affect performance or output.
+    Warning,
+}
+
+/// A notable event reported through [Diagnostics].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// The model was loaded without memory mapping, even though it was preferred.
+    MmapDisabled {
+        /// Why memory mapping could not be used.
+        reason: MmapDisabledReason,
+    },
+    /// A tensor was missing from the model file, and another tensor is used in its place.
+    TensorFallback {
+        /// The name of the missing tensor.
+        tensor_name: String,
+        /// The name of the tensor used instead.
+        fallback_name: String,
+    },
+    /// The model file uses the layouts of an older quantization version, so its tensors are
+    /// upgraded as they are loaded. Migrating the file once avoids this.
+    LegacyQuantization {
+        /// The quantization version of the file.
+        quantization_version: u32,
+    },
+    /// Layers were to be offloaded to the GPU, but this build cannot offload them, so they
+    /// stay on the CPU.
+    GpuOffloadUnavailable,
+    /// The context size of the model was chosen to fit a memory budget. See
+    /// [ContextSize::Auto](crate::ContextSize::Auto).
+    ContextSizeChosen {
+        /// The chosen context size, in tokens.
+        context_size: usize,
+        /// The memory budget of the key/value memory, in bytes.
+        max_memory_bytes: usize,
+    },
+    /// A model was dropped while its weights were still referenced elsewhere (e.g. by a
+    /// session that outlives it), so they will not be freed until those references are.
+    WeightsStillReferenced {
+        /// The number of other references.
+        references: usize,
+    },
+    /// A prompt fed to a session started with beginning-of-sentence tokens that duplicated
+    /// one already before them, which hurts the output of some models, so they were removed.
+    /// See [InferenceSessionConfig::allow_duplicate_bos](crate::InferenceSessionConfig::allow_duplicate_bos).
+    DuplicateBosRemoved {
+        /// The number of tokens that were removed.
+        removed: usize,
+    },
+}
+impl Diagnostic {
+    /// How important this diagnostic is.
+    pub fn level(&self) -> DiagnosticLevel {
+        match self {
+            Self::MmapDisabled { .. }
+            | Self::TensorFallback { .. }
+            | Self::ContextSizeChosen { .. } => DiagnosticLevel::Info,
+            Self::LegacyQuantization { .. }
+            | Self::GpuOffloadUnavailable
+            | Self::WeightsStillReferenced { .. }
+            | Self::DuplicateBosRemoved { .. } => DiagnosticLevel::Warning,
+        }
+    }
+}
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MmapDisabled { reason } => {
+                write!(f, "memory mapping is disabled because {reason}")
+            }
+            Self::TensorFallback {
+                tensor_name,
+                fallback_name,
+            } => write!(
+                f,
+                "the tensor `{tensor_name}` is missing; using `{fallback_name}` instead"
+            ),
+            Self::LegacyQuantization {
+                quantization_version,
+            } => write!(
+                f,
+                "the model uses the legacy quantization version {quantization_version}, so it \
+                 is upgraded while loading and cannot be memory mapped; migrate it to the \
+                 current version to load it faster"
+            ),
+            Self::GpuOffloadUnavailable => write!(
+                f,
+                "layers cannot be offloaded to the GPU, as llm was built without the cublas \
+                 or opencl feature; they are evaluated on the CPU"
+            ),
+            Self::ContextSizeChosen {
+                context_size,
+                max_memory_bytes,
+            } => write!(
+                f,
+                "using a context size of {context_size} tokens, the most that fits in \
+                 {max_memory_bytes} bytes of key/value memory"
+            ),
+            Self::WeightsStillReferenced { references } => write!(
+                f,
+                "model dropped while its weights are still referenced {references} more time(s); \
+                 they will not be freed until those references are dropped"
+            ),
+            Self::DuplicateBosRemoved { removed } => write!(
+                f,
+                "removed {removed} duplicate beginning-of-sentence token(s) from the start of \
+                 the prompt"
+            ),
+        }
+    }
+}
+
+/// Why memory mapping was disabled. See [Diagnostic::MmapDisabled].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmapDisabledReason {
+    /// The model file's container format does not support memory mapping.
+    UnsupportedContainer,
+    /// LoRA adapters are applied to the weights, so they must be loaded into memory.
+    LoraAdapters,
+    /// Some tensors are replaced with those of other files, with
+    /// [ModelParameters::tensor_overrides](crate::ModelParameters::tensor_overrides).
+    TensorOverrides,
+    /// The model file has `bf16` tensors, which must be converted when they are loaded.
+    Bf16Tensors,
+    /// The model is loaded from a reader rather than a file.
+    Reader,
+    /// The model file uses the layouts of an older quantization version, which are
+    /// upgraded when they are loaded. See [Diagnostic::LegacyQuantization].
+    LegacyQuantization,
+    /// The target, such as WebAssembly, does not support memory mapping.
+    UnsupportedTarget,
+}
+impl Display for MmapDisabledReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedContainer => {
+                write!(f, "the container format does not support it")
+            }
+            Self::LoraAdapters => write!(f, "LoRA adapters are applied to the weights"),
+            Self::TensorOverrides => write!(f, "some tensors are overridden by other files"),
+            Self::Bf16Tensors => write!(f, "the model has bf16 tensors, which must be converted"),
+            Self::Reader => write!(f, "the model is loaded from a reader, not a file"),
+            Self::LegacyQuantization => {
+                write!(f, "the model is in a legacy quantization format")
+            }
+            Self::UnsupportedTarget => write!(f, "the target does not support it"),
+        }
+    }
+}
+
+/// A callback that receives [Diagnostic]s.
+///
+/// The default forwards each diagnostic to the [log] crate at the matching level.
+#[derive(Clone)]
+pub struct Diagnostics(Arc<dyn Fn(&Diagnostic) + Send + Sync>);
+impl Diagnostics {
+    /// Creates a callback that calls `callback` with each diagnostic.
+    pub fn new(callback: impl Fn(&Diagnostic) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    /// Creates a callback that forwards each diagnostic to the [log] crate.
+    pub fn log() -> Self {
+        Self::new(|diagnostic| match diagnostic.level() {
+            DiagnosticLevel::Info => log::info!("{diagnostic}"),
+            DiagnosticLevel::Warning => log::warn!("{diagnostic}"),
+        })
+    }
+
+    /// Creates a callback that ignores all diagnostics.
+    pub fn ignore() -> Self {
+        Self::new(|_| {})
+    }
+
+    /// Reports `diagnostic`.
+    pub fn emit(&self, diagnostic: Diagnostic) {
+        (self.0)(&diagnostic)
+    }
+}
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::log()
+    }
+}
+impl std::fmt::Debug for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Diagnostics").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,421 @@
+//! Stable codes for the errors returned by this crate.
+//!
+//! The error types of this crate are rich enums that change as the crate evolves, and their
+//! [Display] output is meant for humans. Layers that hand errors to other languages or over
+//! the network (FFI bindings, servers) should use [ErrorCode] instead, which maps every error
+//! to one of a small set of numbered codes that will not change meaning between releases.
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::{
+    constraint::JsonSchemaError, convert::ConvertError, judge::JudgeError,
+    memory::MemoryLimitExceeded, pipelines::SummarizeError, template::UnknownPromptTemplateError,
+    text::ChunkError, ChooseError, InferenceError, LoadError, QuantizeError, RewindError,
+    SequenceError, SessionLoraError, SnapshotError, SpeculationError, SpillError,
+    TokenizationError, TokenizerLoadError,
+};
+
+/// A stable code for a class of error.
+///
+/// Codes are grouped by the operation that most commonly produces them: 1xx for loading,
+/// 2xx for tokenization, 3xx for inference, 4xx for snapshots, 5xx for quantization and
+/// 9xx for errors that can occur anywhere. Existing codes are never renumbered or reused.
+/// When serialized, the code is represented by its [name](ErrorCode::name).
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+#[repr(u32)]
+pub enum ErrorCode {
+    /// A file that was expected to exist does not.
+    FileNotFound = 100,
+    /// Reading or writing a file failed.
+    Io = 101,
+    /// The model file is corrupt or is not a model file.
+    InvalidModelFile = 102,
+    /// The model file uses a format, version or element type this crate does not support.
+    UnsupportedModelFormat = 103,
+    /// The tokenizer could not be loaded.
+    TokenizerLoadFailed = 104,
+    /// The model architecture was not given and could not be determined.
+    MissingModelArchitecture = 105,
+    /// A model file could not be downloaded.
+    DownloadFailed = 106,
+    /// A model file does not have the expected checksum.
+    ChecksumMismatch = 107,
+    /// Loading a model took longer than its timeout.
+    TimedOut = 108,
+
+    /// The text could not be tokenized.
+    TokenizationFailed = 200,
+    /// A token ID does not belong to the model's vocabulary.
+    InvalidTokenId = 201,
+
+    /// The context window of the session is full.
+    ContextFull = 300,
+    /// The model ended the text.
+    EndOfText = 301,
+    /// A callback provided by the caller returned an error.
+    CallbackFailed = 302,
+    /// The operation would exceed the [memory limit](crate::memory::set_limit).
+    MemoryLimitExceeded = 303,
+    /// The model architecture does not support the operation.
+    UnsupportedOperation = 304,
+    /// The operation was abandoned because its runtime was shut down.
+    ShutDown = 305,
+    /// The model produced non-finite values.
+    NumericalError = 306,
+
+    /// A snapshot does not match the session it is being loaded into.
+    SnapshotMismatch = 400,
+
+    /// The requested quantization target is not supported.
+    InvalidQuantizationTarget = 500,
+
+    /// An argument provided by the caller is invalid.
+    InvalidArgument = 900,
+    /// The operation was cancelled by the caller.
+    Cancelled = 901,
+    /// An internal invariant was broken. This is a bug.
+    Internal = 999,
+}
+impl ErrorCode {
+    /// The numeric value of the code.
+    pub fn as_u32(self) -> u32 {
+        self as u32
+    }
+
+    /// The name of the code, in `snake_case`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::FileNotFound => "file_not_found",
+            Self::Io => "io",
+            Self::InvalidModelFile => "invalid_model_file",
+            Self::UnsupportedModelFormat => "unsupported_model_format",
+            Self::TokenizerLoadFailed => "tokenizer_load_failed",
+            Self::MissingModelArchitecture => "missing_model_architecture",
+            Self::DownloadFailed => "download_failed",
+            Self::ChecksumMismatch => "checksum_mismatch",
+            Self::TimedOut => "timed_out",
+            Self::TokenizationFailed => "tokenization_failed",
+            Self::InvalidTokenId => "invalid_token_id",
+            Self::ContextFull => "context_full",
+            Self::EndOfText => "end_of_text",
+            Self::CallbackFailed => "callback_failed",
+            Self::MemoryLimitExceeded => "memory_limit_exceeded",
+            Self::UnsupportedOperation => "unsupported_operation",
+            Self::ShutDown => "shut_down",
+            Self::NumericalError => "numerical_error",
+            Self::SnapshotMismatch => "snapshot_mismatch",
+            Self::InvalidQuantizationTarget => "invalid_quantization_target",
+            Self::InvalidArgument => "invalid_argument",
+            Self::Cancelled => "cancelled",
+            Self::Internal => "internal",
+        }
+    }
+}
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl LoadError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::FileDoesNotExist { .. } | Self::NoParentPath { .. } => ErrorCode::FileNotFound,
+            Self::OpenFileFailed { .. } | Self::ReadExactFailed { .. } | Self::Io(_) => {
+                ErrorCode::Io
+            }
+            Self::InvalidUtf8(_)
+            | Self::InvalidIntegerConversion(_)
+            | Self::InvalidMagic { .. }
+            | Self::HyperparametersF16Invalid { .. }
+            | Self::UnknownTensor { .. }
+            | Self::TensorWrongSize { .. }
+            | Self::ShardMismatch { .. }
+            | Self::AttentionHeadsMismatch { .. }
+            | Self::InvalidRotaryDimensions { .. }
+            | Self::TensorOverrideShapeMismatch { .. }
+            | Self::ModelNotCreated { .. } => ErrorCode::InvalidModelFile,
+            Self::UnsupportedFileType(_)
+            | Self::InvalidFormatVersion { .. }
+            | Self::UnsupportedElementType { .. }
+            | Self::TensorOutOfRange { .. }
+            | Self::UnsupportedQuantizationVersion { .. }
+            | Self::MultipartNotSupported { .. } => ErrorCode::UnsupportedModelFormat,
+            Self::TokenizerLoadFail { .. } | Self::VocabularyMismatch { .. } => {
+                ErrorCode::TokenizerLoadFailed
+            }
+            Self::MissingModelArchitecture { .. } => ErrorCode::MissingModelArchitecture,
+            Self::ChecksumMismatch { .. } => ErrorCode::ChecksumMismatch,
+            Self::Cancelled => ErrorCode::Cancelled,
+            Self::TimedOut { .. } => ErrorCode::TimedOut,
+            Self::TensorOverrideUnmatched { .. } => ErrorCode::InvalidArgument,
+            Self::InvariantBroken { .. } => ErrorCode::Internal,
+        }
+    }
+}
+
+#[cfg(feature = "hf-hub")]
+impl crate::hf_hub::DownloadError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidName { .. } => ErrorCode::InvalidArgument,
+            Self::Http(_) | Self::UnexpectedStatus { .. } | Self::Interrupted { .. } => {
+                ErrorCode::DownloadFailed
+            }
+            Self::Io { .. } => ErrorCode::Io,
+        }
+    }
+}
+
+#[cfg(feature = "hf-hub")]
+impl crate::hf_hub::HfLoadError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Download(e) => e.code(),
+            Self::Load(e) => e.code(),
+        }
+    }
+}
+
+impl TokenizerLoadError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::TokenizerLoadFailed
+    }
+}
+
+impl TokenizationError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::TokenizationFailed { .. } => ErrorCode::TokenizationFailed,
+            Self::InvalidTokenId(_) => ErrorCode::InvalidTokenId,
+        }
+    }
+}
+
+impl InferenceError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::TokenizationFailed(e) => e.code(),
+            Self::ContextFull => ErrorCode::ContextFull,
+            Self::EndOfText => ErrorCode::EndOfText,
+            Self::UserCallback(_) => ErrorCode::CallbackFailed,
+            Self::RestoreFailed(e) => e.code(),
+            Self::NumericalError { .. } => ErrorCode::NumericalError,
+            Self::Cancelled => ErrorCode::Cancelled,
+        }
+    }
+}
+
+impl SpeculationError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::UnsupportedArchitecture | Self::Eviction => ErrorCode::UnsupportedOperation,
+            Self::Inference(e) => e.code(),
+        }
+    }
+}
+
+impl RewindError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::NotEnoughTokens => ErrorCode::InvalidArgument,
+            Self::UnsupportedArchitecture => ErrorCode::UnsupportedOperation,
+        }
+    }
+}
+
+impl ChooseError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::NoOptions | Self::EmptyOption { .. } | Self::EmptyPrompt => {
+                ErrorCode::InvalidArgument
+            }
+            Self::UnsupportedArchitecture => ErrorCode::UnsupportedOperation,
+            Self::Inference(e) => e.code(),
+        }
+    }
+}
+
+impl SnapshotError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::IO(_) => ErrorCode::Io,
+            Self::MemorySizeMismatch { .. } => ErrorCode::SnapshotMismatch,
+            Self::MemoryLimitExceeded(e) => e.code(),
+        }
+    }
+}
+
+impl SpillError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Io(_) => ErrorCode::Io,
+            Self::MemoryLimitExceeded(e) => e.code(),
+            Self::Unsupported => ErrorCode::UnsupportedOperation,
+        }
+    }
+}
+
+impl SequenceError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::UnknownSequence(_) | Self::ActiveSequence => ErrorCode::InvalidArgument,
+            Self::MemoryLimitExceeded(e) => e.code(),
+            Self::Spill(e) => e.code(),
+            Self::Unsupported => ErrorCode::UnsupportedOperation,
+        }
+    }
+}
+
+impl QuantizeError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Load(e) => e.code(),
+            Self::Io(_) | Self::CreateFileFailed { .. } => ErrorCode::Io,
+            Self::HyperparametersWriteError(_) => ErrorCode::Io,
+            Self::InvalidUtf8(_) | Self::InvalidIntegerConversion(_) => ErrorCode::InvalidModelFile,
+            Self::UnsupportedElementType { .. } | Self::VocabularyScoringNotSupported => {
+                ErrorCode::UnsupportedModelFormat
+            }
+            Self::InvalidQuantizationTarget { .. } => ErrorCode::InvalidQuantizationTarget,
+            Self::InvariantBroken { .. } => ErrorCode::Internal,
+        }
+    }
+}
+
+impl ConvertError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::ReadFailed { source, .. } if source.kind() == std::io::ErrorKind::NotFound => {
+                ErrorCode::FileNotFound
+            }
+            Self::NoSafetensors { .. } => ErrorCode::FileNotFound,
+            Self::ReadFailed { .. } | Self::Io(_) | Self::HyperparametersWriteError(_) => {
+                ErrorCode::Io
+            }
+            Self::InvalidIntegerConversion(_)
+            | Self::InvalidConfigFile { .. }
+            | Self::InvalidConfig { .. }
+            | Self::InvalidSafetensors { .. }
+            | Self::InvalidPytorch { .. }
+            | Self::VocabularySizeMismatch { .. } => ErrorCode::InvalidModelFile,
+            Self::UnsupportedConfig { .. } | Self::UnsupportedDtype { .. } => {
+                ErrorCode::UnsupportedModelFormat
+            }
+            Self::UnsupportedArchitecture => ErrorCode::UnsupportedOperation,
+            Self::UnsupportedFormat { .. } => ErrorCode::InvalidQuantizationTarget,
+            Self::TokenizerLoad(e) => e.code(),
+            Self::InvariantBroken { .. } => ErrorCode::Internal,
+        }
+    }
+}
+
+impl MemoryLimitExceeded {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::MemoryLimitExceeded
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl crate::runtime::RuntimeError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Inference(e) => e.code(),
+            Self::MemoryLimitExceeded(e) => e.code(),
+            Self::ShutDown => ErrorCode::ShutDown,
+        }
+    }
+}
+
+impl SessionLoraError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::InvalidArgument
+    }
+}
+
+impl ChunkError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::OverlapTooLarge { .. } => ErrorCode::InvalidArgument,
+            Self::Tokenization(e) => e.code(),
+        }
+    }
+}
+
+impl SummarizeError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::ContextTooSmall { .. } => ErrorCode::InvalidArgument,
+            Self::MemoryLimitExceeded(e) => e.code(),
+            Self::Tokenization(e) => e.code(),
+            Self::Inference(e) => e.code(),
+        }
+    }
+}
+
+impl JudgeError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::MissingCandidate | Self::InvalidScale { .. } => ErrorCode::InvalidArgument,
+            Self::MemoryLimitExceeded(e) => e.code(),
+            Self::Tokenization(e) => e.code(),
+            Self::Inference(e) => e.code(),
+            Self::Choose(e) => e.code(),
+            Self::Rewind(e) => e.code(),
+        }
+    }
+}
+
+impl JsonSchemaError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::InvalidArgument
+    }
+}
+
+impl UnknownPromptTemplateError {
+    /// The [ErrorCode] for this error.
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::InvalidArgument
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_errors_use_the_code_of_their_cause() {
+        let err = InferenceError::TokenizationFailed(TokenizationError::InvalidTokenId(7));
+        assert_eq!(err.code(), ErrorCode::InvalidTokenId);
+        assert_eq!(err.code().as_u32(), 201);
+
+        let err = QuantizeError::Load(LoadError::FileDoesNotExist {
+            path: "model.bin".into(),
+        });
+        assert_eq!(err.code(), ErrorCode::FileNotFound);
+        assert_eq!(err.code().to_string(), "file_not_found");
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use ggml::GraphNode;
+use serde::Serialize;
+
+/// Where and how to write the computation graph of a session. See
+/// [InferenceSessionConfig::dump_graph](crate::InferenceSessionConfig::dump_graph).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphDump {
+    /// The file to write the graph to. It is overwritten if it exists.
+    pub path: PathBuf,
+    /// The format to write the graph in.
+    pub format: GraphDumpFormat,
+}
+impl GraphDump {
+    /// Writes the graph to `path`, as JSON if its extension is `json`, and as DOT otherwise.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("json") => GraphDumpFormat::Json,
+            _ => GraphDumpFormat::Dot,
+        };
+        Self { path, format }
+    }
+
+    pub(crate) fn write(&self, nodes: &[GraphNode]) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(std::fs::File::create(&self.path)?);
+        match self.format {
+            GraphDumpFormat::Dot => write_dot(&mut writer, nodes)?,
+            GraphDumpFormat::Json => {
+                let nodes: Vec<_> = nodes.iter().map(JsonNode::from).collect();
+                serde_json::to_writer_pretty(&mut writer, &nodes)?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// The format of a [GraphDump].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphDumpFormat {
+    /// A Graphviz DOT graph, with one box per tensor labelled with its name, operation,
+    /// shape, element type and estimated FLOPs.
+    Dot,
+    /// A JSON array with one object per tensor, in evaluation order. Inputs refer to the
+    /// index of the tensor in the array.
+    Json,
+}
+
+#[derive(Serialize)]
+struct JsonNode<'a> {
+    name: &'a str,
+    op: &'a str,
+    element_type: Option<String>,
+    shape: &'a [usize],
+    inputs: &'a [usize],
+    flops: u64,
+}
+impl<'a> From<&'a GraphNode> for JsonNode<'a> {
+    fn from(node: &'a GraphNode) -> Self {
+        Self {
+            name: &node.name,
+            op: &node.op,
+            element_type: node.element_type.map(|t| t.to_string()),
+            shape: &node.shape,
+            inputs: &node.inputs,
+            flops: node.flops,
+        }
+    }
+}
+
+fn write_dot(writer: &mut impl Write, nodes: &[GraphNode]) -> std::io::Result<()> {
+    let total_flops: u64 = nodes.iter().map(|n| n.flops).sum();
+    writeln!(writer, "digraph G {{")?;
+    writeln!(
+        writer,
+        "  label=\"{} nodes, {} FLOPs\";",
+        nodes.len(),
+        total_flops
+    )?;
+    writeln!(writer, "  node [shape=record];")?;
+    for (i, node) in nodes.iter().enumerate() {
+        let element_type = node
+            .element_type
+            .map_or_else(|| "?".to_string(), |t| t.to_string());
+        let shape = node
+            .shape
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>()
+            .join(" x ");
+        writeln!(
+            writer,
+            "  n{i} [label=\"{{{} | {} | [{shape}] {element_type} | {} FLOPs}}\"{}];",
+            escape(&node.name),
+            node.op,
+            node.flops,
+            if node.inputs.is_empty() {
+                ", style=filled, fillcolor=lightgrey"
+            } else {
+                ""
+            }
+        )?;
+        for input in &node.inputs {
+            writeln!(writer, "  n{input} -> n{i};")?;
+        }
+    }
+    writeln!(writer, "}}")
+}
+
+/// Escapes the characters that have a meaning in DOT record labels.
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '"' | '\\' | '{' | '}' | '|' | '<' | '>') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot_output_links_nodes_to_their_inputs() {
+        let node = |name: &str, op: &str, inputs: Vec<usize>, flops| GraphNode {
+            name: name.to_string(),
+            op: op.to_string(),
+            element_type: Some(ggml::Type::F32),
+            shape: vec![4, 2],
+            inputs,
+            flops,
+        };
+        let nodes = [
+            node("a|b", "NONE", vec![], 0),
+            node("c", "NONE", vec![], 0),
+            node("out", "MUL_MAT", vec![0, 1], 64),
+        ];
+
+        let mut dot = vec![];
+        write_dot(&mut dot, &nodes).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+
+        assert!(dot.contains("label=\"3 nodes, 64 FLOPs\""));
+        assert!(dot.contains("n0 [label=\"{a\\|b | NONE | [4 x 2] f32 | 0 FLOPs}\""));
+        assert!(dot.contains("n0 -> n2;\n  n1 -> n2;"));
+    }
+}
@@ -0,0 +1,251 @@
+//! Policies enforced on the generated text as it is generated, such as content filters.
+//!
+//! A [Guardrail] is given the text of each generated token by
+//! [InferenceSession::infer](crate::InferenceSession::infer), before the callback, through
+//! [InferenceRequest::guardrails](crate::InferenceRequest::guardrails). It decides what the
+//! callback receives: it can pass the text on, redact parts of it, hold it back until more
+//! text shows whether it needs to act on it, or halt generation. [PatternGuardrail] acts on
+//! regular expressions or keywords.
+use std::fmt::Debug;
+
+use regex::{Regex, RegexBuilder};
+
+/// A policy enforced on the generated text. See the [module documentation](self).
+pub trait Guardrail: Debug + Send + Sync {
+    /// Checks `pending`, the text generated since the previous check, after the text that
+    /// was held back then. `end` is `true` once generation has ended, in which case no text
+    /// can be held back: this is the last check.
+    fn check(&self, pending: &str, end: bool) -> GuardrailVerdict;
+}
+
+/// What a [Guardrail] decided about the text it checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuardrailVerdict {
+    /// Pass `text` on, and hold back the last `held` bytes of the checked text, to be
+    /// checked again with the text that follows.
+    ///
+    /// `text` is usually the checked text without the held bytes, with any redactions.
+    Pass {
+        /// The text to pass on.
+        text: String,
+        /// The number of bytes at the end of the checked text to hold back. It is rounded
+        /// down to a character boundary, and ignored at the end of generation.
+        held: usize,
+    },
+    /// Pass `text` on, then stop generation with
+    /// [StopReason::Guardrail](crate::StopReason::Guardrail). The rest of the checked text is
+    /// dropped.
+    Halt {
+        /// The text to pass on before stopping, usually the text before the violation.
+        text: String,
+    },
+}
+
+/// Runs the [Guardrail]s of a request in order, each on the text passed on by the previous.
+pub(crate) struct GuardrailChain<'a> {
+    guardrails: &'a [&'a dyn Guardrail],
+    held: Vec<String>,
+}
+impl<'a> GuardrailChain<'a> {
+    pub(crate) fn new(guardrails: &'a [&'a dyn Guardrail]) -> Self {
+        Self {
+            guardrails,
+            held: vec![String::new(); guardrails.len()],
+        }
+    }
+
+    /// Checks `text`, returning the text to pass on and whether generation must stop.
+    pub(crate) fn push(&mut self, mut text: String, mut end: bool) -> (String, bool) {
+        let mut halted = false;
+        for (guardrail, held) in self.guardrails.iter().zip(&mut self.held) {
+            let mut pending = std::mem::take(held);
+            pending.push_str(&text);
+            match guardrail.check(&pending, end) {
+                GuardrailVerdict::Pass {
+                    text: passed,
+                    held: held_bytes,
+                } => {
+                    if !end {
+                        let mut start = pending.len().saturating_sub(held_bytes);
+                        while !pending.is_char_boundary(start) {
+                            start += 1;
+                        }
+                        *held = pending[start..].to_owned();
+                    }
+                    text = passed;
+                }
+                // The text passed on before stopping is the last that the following
+                // guardrails check.
+                GuardrailVerdict::Halt { text: passed } => {
+                    text = passed;
+                    halted = true;
+                    end = true;
+                }
+            }
+        }
+        (text, halted)
+    }
+}
+
+/// What a [PatternGuardrail] does with a match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternAction {
+    /// Replace the match with this text.
+    Redact(String),
+    /// Stop generation before the match.
+    Halt,
+}
+
+/// A [Guardrail] that redacts the matches of a regular expression, or halts generation at
+/// the first one.
+///
+/// As a match can be split across tokens, the last `window` bytes of the text are held back
+/// until more text arrives, so that matches are found as long as they are at most `window`
+/// bytes long. This delays the text by as much.
+#[derive(Debug, Clone)]
+pub struct PatternGuardrail {
+    pattern: Regex,
+    action: PatternAction,
+    window: usize,
+}
+impl PatternGuardrail {
+    /// The number of bytes held back by [Self::new] and [Self::keywords]: the longest
+    /// match that is guaranteed to be found.
+    pub const DEFAULT_WINDOW: usize = 64;
+
+    /// Acts on the matches of `pattern`.
+    pub fn new(pattern: Regex, action: PatternAction) -> Self {
+        Self {
+            pattern,
+            action,
+            window: Self::DEFAULT_WINDOW,
+        }
+    }
+
+    /// Acts on the whole-word occurrences of any of `keywords`, ignoring case. The window is
+    /// made long enough for the longest keyword.
+    pub fn keywords<S: AsRef<str>>(keywords: &[S], action: PatternAction) -> Self {
+        let alternatives = keywords
+            .iter()
+            .map(|keyword| regex::escape(keyword.as_ref()))
+            .collect::<Vec<_>>();
+        let pattern = if alternatives.is_empty() {
+            // Matches nothing.
+            "[^\\s\\S]".to_owned()
+        } else {
+            format!(r"\b(?:{})\b", alternatives.join("|"))
+        };
+        let pattern = RegexBuilder::new(&pattern)
+            .case_insensitive(true)
+            .build()
+            .expect("escaped keywords form a valid pattern");
+        // One more byte, to see where the word ends.
+        let longest = keywords
+            .iter()
+            .map(|keyword| keyword.as_ref().len() + 1)
+            .max()
+            .unwrap_or(0);
+        Self {
+            pattern,
+            action,
+            window: longest.max(Self::DEFAULT_WINDOW),
+        }
+    }
+
+    /// Sets the number of bytes that are held back. See [PatternGuardrail].
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+}
+impl Guardrail for PatternGuardrail {
+    fn check(&self, pending: &str, end: bool) -> GuardrailVerdict {
+        let mut cut = if end {
+            pending.len()
+        } else {
+            pending.len().saturating_sub(self.window)
+        };
+        let mut text = String::new();
+        let mut last = 0;
+        for found in self.pattern.find_iter(pending) {
+            // A match that reaches into the held text may still grow.
+            if found.end() > cut {
+                cut = cut.min(found.start());
+                break;
+            }
+            text.push_str(&pending[last..found.start()]);
+            match &self.action {
+                PatternAction::Halt => return GuardrailVerdict::Halt { text },
+                PatternAction::Redact(replacement) => text.push_str(replacement),
+            }
+            last = found.end();
+        }
+        let cut = (0..=cut)
+            .rev()
+            .find(|&i| pending.is_char_boundary(i))
+            .unwrap_or(0)
+            .max(last);
+        text.push_str(&pending[last..cut]);
+        GuardrailVerdict::Pass {
+            text,
+            held: pending.len() - cut,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(guardrails: &[&dyn Guardrail], pieces: &[&str]) -> (String, bool) {
+        let mut chain = GuardrailChain::new(guardrails);
+        let mut output = String::new();
+        for piece in pieces {
+            let (text, halted) = chain.push(piece.to_string(), false);
+            output.push_str(&text);
+            if halted {
+                return (output, true);
+            }
+        }
+        let (text, halted) = chain.push(String::new(), true);
+        output.push_str(&text);
+        (output, halted)
+    }
+
+    #[test]
+    fn matches_split_across_tokens_are_redacted() {
+        let guardrail = PatternGuardrail::new(
+            Regex::new(r"\d{3}-\d{4}").unwrap(),
+            PatternAction::Redact("[number]".to_owned()),
+        )
+        .with_window(8);
+        assert_eq!(
+            run(
+                &[&guardrail],
+                &["Call 55", "5-12", "34 or 555-", "0000 now"]
+            ),
+            ("Call [number] or [number] now".to_owned(), false)
+        );
+    }
+
+    #[test]
+    fn keywords_halt_generation_before_them() {
+        let guardrail = PatternGuardrail::keywords(&["secret", "password"], PatternAction::Halt);
+        assert_eq!(
+            run(
+                &[&guardrail],
+                &["The pass", "words are", " a PASS", "WORD: hunter2"]
+            ),
+            ("The passwords are a ".to_owned(), true)
+        );
+
+        let redact = PatternGuardrail::keywords(&["darn"], PatternAction::Redact("***".into()));
+        assert_eq!(
+            run(
+                &[&redact, &guardrail],
+                &["Darn, ", "it is darning. My secret is out"]
+            ),
+            ("***, it is darning. My ".to_owned(), true)
+        );
+    }
+}
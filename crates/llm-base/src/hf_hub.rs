@@ -0,0 +1,275 @@
+//! Downloads model files from the [Hugging Face Hub](https://huggingface.co).
+//!
+//! Files are stored in a cache directory, and are only downloaded once. Interrupted downloads
+//! are resumed from where they stopped.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::LoadError;
+
+/// A file in a Hugging Face Hub repository.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HfFile {
+    /// The ID of the repository, e.g. `rustformers/open-llama-ggml`.
+    pub repo_id: String,
+    /// The path of the file within the repository.
+    pub filename: String,
+    /// The branch, tag or commit to download the file from.
+    ///
+    /// The file is cached by revision, so a file downloaded from a branch is not updated
+    /// when the branch changes. Use a commit hash to make this explicit.
+    pub revision: String,
+    /// The access token to use for private or gated repositories.
+    pub auth_token: Option<String>,
+}
+impl HfFile {
+    /// The file `filename` on the `main` branch of `repo_id`.
+    pub fn new(repo_id: impl Into<String>, filename: impl Into<String>) -> Self {
+        Self {
+            repo_id: repo_id.into(),
+            filename: filename.into(),
+            revision: "main".to_string(),
+            auth_token: None,
+        }
+    }
+
+    /// The URL to download the file from.
+    pub fn url(&self) -> String {
+        format!(
+            "https://huggingface.co/{}/resolve/{}/{}",
+            self.repo_id, self.revision, self.filename
+        )
+    }
+
+    /// Where the file is stored within `cache_dir`.
+    pub fn cache_path(&self, cache_dir: &Path) -> Result<PathBuf, DownloadError> {
+        let mut path = cache_dir.to_owned();
+        for part in [&self.repo_id, &self.revision, &self.filename] {
+            // Each part must stay within its directory.
+            if part
+                .split('/')
+                .any(|c| c.is_empty() || c == "." || c == ".." || c.contains('\\'))
+            {
+                return Err(DownloadError::InvalidName { name: part.clone() });
+            }
+            path.push(part);
+        }
+        Ok(path)
+    }
+}
+
+/// The directory that files are cached in by default: `$LLM_CACHE` if it is set, and
+/// `llm` in the user's cache directory otherwise.
+pub fn default_cache_dir() -> PathBuf {
+    if let Some(path) = std::env::var_os("LLM_CACHE") {
+        PathBuf::from(path)
+    } else {
+        dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("llm")
+    }
+}
+
+/// Each variant represents a step within the process of downloading a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DownloadProgress {
+    /// The file is already in the cache; nothing will be downloaded.
+    Cached {
+        /// Where the file is stored.
+        path: PathBuf,
+    },
+    /// The download has started.
+    Started {
+        /// The size of the file, if the server reported it.
+        total_bytes: Option<u64>,
+        /// How many bytes were already downloaded by an earlier, interrupted attempt.
+        resumed_from: u64,
+    },
+    /// Part of the file has been downloaded.
+    Downloaded {
+        /// How many bytes have been downloaded so far, including earlier attempts.
+        bytes: u64,
+        /// The size of the file, if the server reported it.
+        total_bytes: Option<u64>,
+    },
+    /// The download has completed.
+    Finished {
+        /// Where the file is stored.
+        path: PathBuf,
+    },
+}
+
+/// Downloads `file` into `cache_dir`, unless it is already there, and returns its path.
+///
+/// The file is written to a `.part` file next to its final path while it downloads, so an
+/// interrupted download is resumed by the next call.
+pub fn download(
+    file: &HfFile,
+    cache_dir: &Path,
+    mut progress_callback: impl FnMut(DownloadProgress),
+) -> Result<PathBuf, DownloadError> {
+    let path = file.cache_path(cache_dir)?;
+    if path.is_file() {
+        progress_callback(DownloadProgress::Cached { path: path.clone() });
+        return Ok(path);
+    }
+
+    let mut part_path = path.clone().into_os_string();
+    part_path.push(".part");
+    let part_path = PathBuf::from(part_path);
+    let io_error = |path: &Path| {
+        let path = path.to_owned();
+        move |source| DownloadError::Io { path, source }
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(io_error(parent))?;
+    }
+
+    let url = file.url();
+    let mut resumed_from = fs::metadata(&part_path).map_or(0, |m| m.len());
+    let client = reqwest::blocking::Client::builder()
+        // Model files take far longer than the default timeout to download.
+        .timeout(None)
+        .build()?;
+    let mut request = client.get(&url);
+    if resumed_from > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={resumed_from}-"));
+    }
+    if let Some(token) = &file.auth_token {
+        request = request.bearer_auth(token);
+    }
+    let mut response = request.send()?;
+
+    let mut part = match response.status() {
+        reqwest::StatusCode::PARTIAL_CONTENT => OpenOptions::new()
+            .append(true)
+            .open(&part_path)
+            .map_err(io_error(&part_path))?,
+        // The server ignored the range, so start over.
+        reqwest::StatusCode::OK => {
+            resumed_from = 0;
+            File::create(&part_path).map_err(io_error(&part_path))?
+        }
+        // The earlier attempt had downloaded the whole file.
+        reqwest::StatusCode::RANGE_NOT_SATISFIABLE if resumed_from > 0 => {
+            fs::rename(&part_path, &path).map_err(io_error(&path))?;
+            progress_callback(DownloadProgress::Finished { path: path.clone() });
+            return Ok(path);
+        }
+        status => {
+            return Err(DownloadError::UnexpectedStatus {
+                url,
+                status: status.as_u16(),
+            })
+        }
+    };
+
+    let total_bytes = response.content_length().map(|n| n + resumed_from);
+    progress_callback(DownloadProgress::Started {
+        total_bytes,
+        resumed_from,
+    });
+    let mut bytes = resumed_from;
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let n = response
+            .read(&mut buffer)
+            .map_err(|source| DownloadError::Interrupted {
+                url: url.clone(),
+                source,
+            })?;
+        if n == 0 {
+            break;
+        }
+        part.write_all(&buffer[..n]).map_err(io_error(&part_path))?;
+        bytes += n as u64;
+        progress_callback(DownloadProgress::Downloaded { bytes, total_bytes });
+    }
+    part.sync_all().map_err(io_error(&part_path))?;
+    drop(part);
+
+    if matches!(total_bytes, Some(total) if bytes != total) {
+        return Err(DownloadError::Interrupted {
+            url,
+            source: std::io::ErrorKind::UnexpectedEof.into(),
+        });
+    }
+    fs::rename(&part_path, &path).map_err(io_error(&path))?;
+    progress_callback(DownloadProgress::Finished { path: path.clone() });
+
+    Ok(path)
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while downloading a file from the Hugging Face Hub.
+pub enum DownloadError {
+    /// The repository ID, revision or filename would escape the cache directory.
+    #[error("{name:?} is not a valid repository ID, revision or filename")]
+    InvalidName {
+        /// The invalid name.
+        name: String,
+    },
+    /// The HTTP request failed.
+    #[error("the HTTP request failed")]
+    Http(#[from] reqwest::Error),
+    /// The server did not return the file.
+    #[error("{url} returned HTTP status {status}")]
+    UnexpectedStatus {
+        /// The URL of the file.
+        url: String,
+        /// The HTTP status code.
+        status: u16,
+    },
+    /// The connection was lost while downloading. Downloading again resumes from there.
+    #[error("the download of {url} was interrupted")]
+    Interrupted {
+        /// The URL of the file.
+        url: String,
+        /// The underlying error.
+        source: std::io::Error,
+    },
+    /// Reading or writing the cache failed.
+    #[error("could not write to {path:?}")]
+    Io {
+        /// The path that failed.
+        path: PathBuf,
+        /// The underlying error.
+        source: std::io::Error,
+    },
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while downloading and loading a model from the Hugging Face Hub.
+pub enum HfLoadError {
+    /// The model could not be downloaded.
+    #[error("could not download the model")]
+    Download(#[from] DownloadError),
+    /// The model was downloaded, but could not be loaded.
+    #[error("could not load the model")]
+    Load(#[from] LoadError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_paths_stay_within_the_cache_directory() {
+        let mut file = HfFile::new("org/model", "ggml/model-q4_0.bin");
+        assert_eq!(
+            file.cache_path(Path::new("cache")).unwrap(),
+            Path::new("cache/org/model/main/ggml/model-q4_0.bin")
+        );
+
+        file.filename = "../../model.bin".to_string();
+        assert!(matches!(
+            file.cache_path(Path::new("cache")),
+            Err(DownloadError::InvalidName { .. })
+        ));
+    }
+}
@@ -1,14 +1,38 @@
 use ggml::{Buffer, ComputationGraph, Context, Tensor};
+use once_cell::sync::Lazy;
+use partial_sort::PartialSort;
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
 use serde::Serialize;
-use std::{fmt::Display, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 use thiserror::Error;
 
 #[cfg(feature = "metal")]
 use ggml::metal::MetalContext;
 
+#[cfg(feature = "attention-stats")]
+use crate::attention_stats::{self, AttentionObserver};
+#[cfg(feature = "graph-dump")]
+use crate::graph_dump::GraphDump;
 use crate::{
-    mulf, util, InferenceParameters, Model, OutputRequest, Prompt, TokenId, TokenUtf8Buffer,
-    TokenizationError,
+    cancellation::CancellationToken,
+    diagnostics::{Diagnostic, Diagnostics},
+    guardrail::{Guardrail, GuardrailChain},
+    memory::{self, MemoryKind, MemoryLimitExceeded, Reservation},
+    mulf,
+    resource_usage::ResourceSnapshot,
+    threading::ThreadTuner,
+    util, InferenceParameters, Model, OutputRequest, Prompt, ResourceUsage, SamplerState,
+    SessionLora, SessionLoraError, TokenId, TokenUtf8Buffer, TokenizationError,
 };
 
 // The size of a scratch buffer used for inference. This is used for temporary
@@ -78,10 +102,30 @@ pub struct InferenceSession {
     // All decoded tokens generated by this inference session
     pub(crate) decoded_tokens: Vec<u8>,
 
+    // The number of tokens at the end of `tokens` that were fed by `speculate`.
+    speculated: usize,
+
+    // Whether `last_logits` belong to a token that was rewound, so that the last token
+    // must be evaluated again before the next one is sampled.
+    logits_stale: bool,
+
     /// The logits that were last predicted by the network. Zeroed out otherwise.
     #[doc(hidden)]
     pub last_logits: Vec<f32>,
 
+    /// The state kept by the sampler between tokens.
+    sampler_state: SamplerState,
+
+    /// The parameters that [Self::infer] generates with, which can be adjusted while it runs.
+    sampler_handle: SamplerHandle,
+
+    /// Applied to the logits before each token is sampled.
+    logits_processors: Vec<Box<dyn LogitsProcessor>>,
+
+    /// Given the attention statistics after each evaluation.
+    #[cfg(feature = "attention-stats")]
+    attention_observers: Vec<Box<dyn AttentionObserver>>,
+
     #[cfg(feature = "metal")]
     metal_context: Option<MetalContext>,
 
@@ -89,7 +133,100 @@ pub struct InferenceSession {
 
     n_embd: usize,
 
+    n_layer: usize,
+
+    /// The number of tokens the session can hold.
+    n_ctx: usize,
+
+    /// The number of tokens that were evicted from the key/value memory by
+    /// [InferenceSessionConfig::kv_eviction].
+    n_evicted: usize,
+
+    /// The attention each position of the key/value memory has received, summed over
+    /// heads and layers, if [InferenceSessionConfig::kv_eviction] is set.
+    attention_scores: Vec<f32>,
+
+    /// Copies of the attention weights of the last query of each layer, recorded during
+    /// the last evaluation with [BuildContext::record_attention]. They live in `ctx0`.
+    attention_weights: Vec<Tensor>,
+
     scratch: ScratchBuffers,
+
+    thread_tuner: ThreadTuner,
+
+    lora: Option<BoundLora>,
+
+    #[cfg(feature = "graph-dump")]
+    graph_dumped: bool,
+
+    /// Copies of the layer outputs recorded during the last evaluation, if
+    /// [InferenceSessionConfig::capture_layer_outputs] is set. They live in `ctx0`.
+    layer_outputs: Vec<Tensor>,
+
+    /// Where the key/value memory is while the session is [spilled](Self::spill).
+    spilled: Option<SpilledMemory>,
+
+    memory_reservation: Reservation,
+
+    /// The sequence whose state and key/value entries are the ones above.
+    sequence: SequenceId,
+
+    /// The other sequences of the session, set aside by [Self::switch_sequence].
+    parked_sequences: BTreeMap<SequenceId, ParkedSequence>,
+
+    /// The identifier of the next sequence to be created.
+    next_sequence: u32,
+}
+
+/// A sequence of an [InferenceSession] that is not the active one.
+struct ParkedSequence {
+    tokens: Vec<TokenId>,
+    decoded_tokens: Vec<u8>,
+    n_past: usize,
+    n_evicted: usize,
+    speculated: usize,
+    logits_stale: bool,
+    last_logits: Vec<f32>,
+    sampler_state: SamplerState,
+    attention_scores: Vec<f32>,
+    /// The key/value memory of the sequence. Empty sequences get one when they are first
+    /// switched to.
+    slot: Option<KvSlot>,
+}
+
+/// The key/value memory of a sequence that is not the active one: tensors like
+/// [InferenceSession::memory_k] and [InferenceSession::memory_v], with room for the whole
+/// context, which are swapped with those of the session when the sequence is switched to.
+struct KvSlot {
+    context: Arc<ggml::Context>,
+    memory_k: Tensor,
+    memory_v: Tensor,
+    _reservation: Reservation,
+}
+
+/// The key/value memory of an [InferenceSession] that was [spilled](InferenceSession::spill)
+/// to a file. The file is removed when this is dropped.
+struct SpilledMemory {
+    path: PathBuf,
+    /// The number of elements of `memory_k` and `memory_v`.
+    n_elements: usize,
+    /// The size of the evaluation buffer.
+    eval_size: usize,
+    /// The memory that was reserved for the session.
+    reservation: Vec<(MemoryKind, usize)>,
+}
+impl Drop for SpilledMemory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A [SessionLora] that has been matched against the tensors of a model.
+struct BoundLora {
+    lora: Arc<SessionLora>,
+    /// The transposed `A` and the `B` matrices, keyed by the pointer of the model
+    /// tensor they patch.
+    targets: HashMap<usize, (Tensor, Tensor)>,
 }
 
 pub struct BuildContext<'session> {
@@ -98,20 +235,107 @@ pub struct BuildContext<'session> {
     pub memory_k: &'session Tensor,
     pub memory_v: &'session Tensor,
     pub scratch: &'session mut ScratchBuffers,
+    lora: Option<&'session BoundLora>,
+    scratch_index: Option<usize>,
+    layer_outputs: Option<&'session mut Vec<Tensor>>,
+    attention_weights: Option<&'session mut Vec<Tensor>>,
 }
 
 impl<'session> BuildContext<'session> {
     pub fn use_scratch(&mut self, idx: Option<usize>) {
+        self.scratch_index = idx;
         self.ctx0.use_scratch(match idx {
             None => None,
             Some(idx) => Some(&mut self.scratch[idx]),
         })
     }
+
+    /// Records `output` as the output of the next layer of the model, for
+    /// [InferenceSession::layer_outputs], and returns the tensor to feed to the next layer.
+    ///
+    /// Call this once per layer, in order. Unless
+    /// [InferenceSessionConfig::capture_layer_outputs] is set, this returns `output` as is.
+    #[must_use]
+    pub fn record_layer_output(&mut self, output: &Tensor) -> Tensor {
+        let Some(layer_outputs) = self.layer_outputs.as_mut() else {
+            return output.share();
+        };
+
+        // The output is usually in a scratch buffer that later layers overwrite, so copy it
+        // to the evaluation buffer. The next layer reads the copy, which makes sure that the
+        // copy is computed before the scratch buffer is reused.
+        self.ctx0.use_scratch(None);
+        let ne0 = output.get_ne()[0] as usize;
+        let copy = self.ctx0.op_cpy(
+            output,
+            &self
+                .ctx0
+                .new_tensor_2d(ggml::Type::F32, ne0, output.nelements() / ne0),
+        );
+        layer_outputs.push(copy.share());
+        self.use_scratch(self.scratch_index);
+        copy
+    }
+
+    /// Records the attention weights of a layer, `[n_past + n_batch, n_batch, n_head]` after
+    /// the softmax, to score the entries of the key/value memory for
+    /// [InferenceSessionConfig::kv_eviction] and for the attention statistics of the
+    /// `attention-stats` feature.
+    ///
+    /// Call this once per layer, right after the softmax. Only the weights of the last query
+    /// of the batch are kept; unless they are needed, this does nothing.
+    pub fn record_attention(&mut self, graph: &mut ComputationGraph, weights: &Tensor) {
+        let Some(attention_weights) = self.attention_weights.as_mut() else {
+            return;
+        };
+
+        // The weights are usually in a scratch buffer that later layers overwrite, so copy
+        // them to the evaluation buffer, and add the copy to the graph now so that it is
+        // computed before the scratch buffer is reused.
+        self.ctx0.use_scratch(None);
+        let [n_kv, n_batch, n_head, _] = weights.get_ne().map(|n| n as usize);
+        let nb = weights.get_nb();
+        let last_query =
+            self.ctx0
+                .op_view_2d(weights, (n_kv, n_head), nb[2], (n_batch - 1) * nb[1]);
+        let copy = self.ctx0.op_cpy(
+            &last_query,
+            &self.ctx0.new_tensor_2d(ggml::Type::F32, n_kv, n_head),
+        );
+        graph.build_forward_expand(&copy);
+        attention_weights.push(copy);
+        self.use_scratch(self.scratch_index);
+    }
+
+    /// Multiplies the model weight `weight` by `input`, adding the contribution of the
+    /// session's LoRA adapter (see [InferenceSession::set_lora]) if it patches `weight`.
+    pub fn mul_mat(&self, weight: &Tensor, input: &Tensor) -> Tensor {
+        let ctx0 = self.ctx0;
+        let output = ctx0.op_mul_mat(weight, input);
+        let Some(lora) = self.lora else {
+            return output;
+        };
+        let Some((a_t, b)) = lora.targets.get(&(weight.as_ptr() as usize)) else {
+            return output;
+        };
+
+        // LoRA formula: y = Wx + B(Ax)*s
+        let mut delta = ctx0.op_mul_mat(b, &ctx0.op_mul_mat(a_t, input));
+        let scaling = lora.lora.scaling();
+        if scaling != 1.0 {
+            delta = ctx0.op_scale(&delta, &ctx0.new_f32(scaling));
+        }
+        ctx0.op_add(&output, &delta)
+    }
 }
 
 unsafe impl Send for InferenceSession {}
 impl InferenceSession {
     /// Create a new InferenceSession
+    ///
+    /// # Panics
+    /// Panics if a [memory limit](crate::memory::set_limit) is set and the session would
+    /// exceed it. Use [InferenceSession::try_new] to handle this case.
     pub fn new(
         config: InferenceSessionConfig,
         n_ctx: usize,
@@ -119,36 +343,26 @@ impl InferenceSession {
         n_embd: usize,
         n_vocab: usize,
     ) -> InferenceSession {
-        let ctx_size = {
-            let mut ctx_size = 0;
-            ctx_size += mulf!(
-                n_ctx,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(config.memory_k_type.into())
-            ); // memory_k
-            ctx_size += mulf!(
-                n_ctx,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(config.memory_v_type.into())
-            ); // memory_v
-            ctx_size += (5 + 10 * n_layer) * 256; // object overhead
-
-            ctx_size
-        };
-
-        let session_ctx = Arc::new(ggml::Context::init(ctx_size, true));
-
-        // Initialize key + value memory tensors
-        let n_mem = n_layer * n_ctx;
-        let n_elements = n_embd * n_mem;
-        let memory_k = session_ctx.new_tensor_1d(config.memory_k_type.into(), n_elements);
-        let memory_v = session_ctx.new_tensor_1d(config.memory_v_type.into(), n_elements);
-        ggml::set_name(&memory_k, "memory_k");
-        ggml::set_name(&memory_v, "memory_v");
+        Self::try_new(config, n_ctx, n_layer, n_embd, n_vocab)
+            .unwrap_or_else(|err| panic!("failed to start session: {err}"))
+    }
 
-        let scratch = scratch_buffers();
+    /// Create a new InferenceSession, or return an error if the memory it needs would
+    /// exceed the [memory limit](crate::memory::set_limit).
+    ///
+    /// `n_ctx` is the context size of the model, which is the largest the session can have:
+    /// [InferenceSessionConfig::context_size] can only make it smaller.
+    pub fn try_new(
+        config: InferenceSessionConfig,
+        n_ctx: usize,
+        n_layer: usize,
+        n_embd: usize,
+        n_vocab: usize,
+    ) -> Result<InferenceSession, MemoryLimitExceeded> {
+        let n_ctx = config
+            .context_size
+            .map_or(n_ctx, |context_size| context_size.min(n_ctx));
+        let ctx_size = kv_cache_size(&config, n_ctx, n_layer, n_embd);
 
         // Allocate buffer for storing intermediate values during evaluation (ctx0 backing)
         // For the first run, we need to guess a maximum buffer size so we can measure
@@ -166,6 +380,25 @@ impl InferenceSession {
             buf_size_mb * 1024 * 1024
         };
 
+        // Check the memory limit before allocating anything.
+        let memory_reservation = memory::reserve(&[
+            (MemoryKind::KvCache, ctx_size),
+            // The two scratch buffers and the evaluation buffer.
+            (MemoryKind::Scratch, 2 * SCRATCH_SIZE + buf_size),
+        ])?;
+
+        let session_ctx = Arc::new(ggml::Context::init(ctx_size, true));
+
+        // Initialize key + value memory tensors
+        let n_mem = n_layer * n_ctx;
+        let n_elements = n_embd * n_mem;
+        let memory_k = session_ctx.new_tensor_1d(config.memory_k_type.into(), n_elements);
+        let memory_v = session_ctx.new_tensor_1d(config.memory_v_type.into(), n_elements);
+        ggml::set_name(&memory_k, "memory_k");
+        ggml::set_name(&memory_v, "memory_v");
+
+        let scratch = scratch_buffers();
+
         let eval = Buffer::new(buf_size);
         let ctx0 = ggml::Context::init_buffer(eval);
 
@@ -186,7 +419,13 @@ impl InferenceSession {
             }
         };
 
-        InferenceSession {
+        let attention_scores = if config.kv_eviction.is_some() {
+            vec![0.0; n_ctx]
+        } else {
+            vec![]
+        };
+
+        Ok(InferenceSession {
             _session_ctx: session_ctx,
             _memory_size: ctx_size,
             config,
@@ -196,16 +435,123 @@ impl InferenceSession {
             mem_per_token: 0,
             tokens: vec![],
             decoded_tokens: vec![],
+            speculated: 0,
+            logits_stale: false,
             last_logits: vec![0.0; n_vocab],
+            sampler_state: SamplerState::default(),
+            sampler_handle: SamplerHandle::default(),
+            logits_processors: vec![],
+            #[cfg(feature = "attention-stats")]
+            attention_observers: vec![],
             #[cfg(feature = "metal")]
             metal_context,
             ctx0,
             n_embd,
+            n_layer,
+            n_ctx,
+            n_evicted: 0,
+            attention_scores,
+            attention_weights: vec![],
             scratch,
+            thread_tuner: ThreadTuner::default(),
+            lora: None,
+            #[cfg(feature = "graph-dump")]
+            graph_dumped: false,
+            layer_outputs: vec![],
+            spilled: None,
+            memory_reservation,
+            sequence: SequenceId(0),
+            parked_sequences: BTreeMap::new(),
+            next_sequence: 1,
+        })
+    }
+
+    /// Applies `lora` to all evaluations of this session from now on, replacing any
+    /// previously applied adapter. `None` removes the adapter.
+    ///
+    /// The model's weights are not modified, so other sessions of the same `model` are
+    /// unaffected. Tokens already in the session's memory are not re-evaluated, so this is
+    /// usually done before feeding the prompt.
+    pub fn set_lora(
+        &mut self,
+        model: &dyn Model,
+        lora: Option<Arc<SessionLora>>,
+    ) -> Result<(), SessionLoraError> {
+        let Some(lora) = lora else {
+            self.lora = None;
+            return Ok(());
+        };
+
+        let mut targets = HashMap::with_capacity(lora.tensors.len());
+        for (name, (a_t, b)) in &lora.tensors {
+            let weight = model
+                .tensor(name)
+                .ok_or_else(|| SessionLoraError::UnknownTensor {
+                    tensor_name: name.clone(),
+                })?;
+
+            // The weight is `[n_in, n_out]`, `A^T` is `[n_in, r]` and `B` is `[r, n_out]`.
+            let [n_in, n_out, ..] = weight.get_ne();
+            let [a_in, a_rank, ..] = a_t.get_ne();
+            let [b_rank, b_out, ..] = b.get_ne();
+            if a_in != n_in || b_out != n_out || a_rank != b_rank {
+                return Err(SessionLoraError::ShapeMismatch {
+                    tensor_name: name.clone(),
+                });
+            }
+
+            targets.insert(weight.as_ptr() as usize, (a_t.share(), b.share()));
         }
+
+        self.lora = Some(BoundLora { lora, targets });
+        Ok(())
+    }
+
+    /// The LoRA adapter applied with [InferenceSession::set_lora], if any.
+    pub fn lora(&self) -> Option<&Arc<SessionLora>> {
+        self.lora.as_ref().map(|bound| &bound.lora)
+    }
+
+    /// Drops the session, returning whether its memory (including the key/value cache)
+    /// was actually freed.
+    pub fn close(self) -> bool {
+        let context = Arc::downgrade(&self._session_ctx);
+        drop(self);
+        context.strong_count() == 0
+    }
+
+    /// Returns the output of each layer of the model for the tokens of the last evaluation,
+    /// in order, each with `n_batch * n_embd` elements.
+    ///
+    /// This is empty unless [InferenceSessionConfig::capture_layer_outputs] is set.
+    pub fn layer_outputs(&self) -> Vec<Vec<f32>> {
+        self.layer_outputs
+            .iter()
+            .map(|output| {
+                let mut data = vec![0.0; output.nelements()];
+                // SAFETY: the copies are f32 tensors in ctx0, which is not reset until the
+                // next evaluation.
+                unsafe { output.read_data(0, bytemuck::cast_slice_mut(&mut data)) };
+                data
+            })
+            .collect()
+    }
+
+    /// Selects the number of threads to use to evaluate `n_tokens` tokens, according to
+    /// [InferenceParameters::n_threads].
+    ///
+    /// This should be called by [Model::evaluate] implementations before [Self::compute].
+    pub fn thread_count(&mut self, params: &InferenceParameters, n_tokens: usize) -> usize {
+        self.thread_tuner.select(params.n_threads, n_tokens)
     }
 
     /// Compute a model (possibly building a graph in the provided closure when called for the first time and/or when parameters have)
+    ///
+    /// # Panics
+    ///
+    /// Panics if the session is [spilled](Self::spill) and cannot be restored. The methods
+    /// of the session restore it before evaluating, returning an error if that fails;
+    /// callers of [Model::evaluate] should call [Self::restore] first.
     pub fn compute<F>(
         &mut self,
         #[allow(unused_variables)] model_context: Arc<Context>,
@@ -215,18 +561,31 @@ impl InferenceSession {
     where
         F: FnOnce(BuildContext) -> (ComputationGraph, GraphOutputs),
     {
+        self.restore()
+            .unwrap_or_else(|err| panic!("failed to restore the spilled session: {err}"));
+
         // Build a graph
+        self.layer_outputs.clear();
+        self.attention_weights.clear();
         self.ctx0 = ggml::Context::init_buffer(self.ctx0.buffer.take().unwrap());
         let ctx0 = &self.ctx0;
         let mut embd = ctx0.new_tensor_1d(ggml::Type::I32, input_tokens.len());
         ggml::set_name(&embd, "embd");
 
+        let record_attention = self.config.kv_eviction.is_some() || self.observes_attention();
         let bc = BuildContext {
             ctx0,
             embd: &embd,
             memory_k: &self.memory_k,
             memory_v: &self.memory_v,
             scratch: &mut self.scratch,
+            lora: self.lora.as_ref(),
+            scratch_index: None,
+            layer_outputs: self
+                .config
+                .capture_layer_outputs
+                .then_some(&mut self.layer_outputs),
+            attention_weights: record_attention.then_some(&mut self.attention_weights),
         };
         let (mut built_gf, built_result) = builder(bc);
 
@@ -235,6 +594,9 @@ impl InferenceSession {
         {
             if let Some(ref mut metal_context) = self.metal_context {
                 metal_context.add_context(model_context);
+                if let Some(lora) = &self.lora {
+                    metal_context.add_context(lora.lora.context.clone());
+                }
             }
         }
 
@@ -244,6 +606,28 @@ impl InferenceSession {
         // Compute the graph
         built_gf.build_forward_expand(&built_result.result);
 
+        #[cfg(feature = "graph-dump")]
+        if let Some(dump) = self
+            .config
+            .dump_graph
+            .as_ref()
+            .filter(|_| !self.graph_dumped)
+        {
+            self.graph_dumped = true;
+            // SAFETY: the tensors of the graph belong to ctx0, the session context and the
+            // model context, all of which are alive.
+            let nodes = unsafe { built_gf.nodes() };
+            match dump.write(&nodes) {
+                Ok(()) => log::info!("wrote the computation graph to {}", dump.path.display()),
+                Err(e) => log::warn!(
+                    "failed to write the computation graph to {}: {e}",
+                    dump.path.display()
+                ),
+            }
+        }
+
+        let compute_start = std::time::Instant::now();
+
         #[cfg(feature = "metal")]
         {
             // FIXME can only process one token at a time currently
@@ -263,12 +647,45 @@ impl InferenceSession {
         {
             ctx0.graph_compute(&mut built_gf);
         }
+        self.thread_tuner.record(compute_start.elapsed());
 
         // Adjust the required memory per token if we didn't know that already
         if self.mem_per_token == 0 {
             self.mem_per_token = ctx0.used_mem() / self.n_embd;
         }
 
+        // Score the entries of the memory by the attention they received.
+        let n_kv = self.n_past + input_tokens.len();
+        #[cfg(feature = "attention-stats")]
+        let mut layer_weights = vec![];
+        for weights in &self.attention_weights {
+            let mut data = vec![0.0f32; weights.nelements()];
+            // SAFETY: the copies are f32 tensors in ctx0, which was just computed.
+            unsafe { weights.read_data(0, bytemuck::cast_slice_mut(&mut data)) };
+            if !self.attention_scores.is_empty() {
+                for head in data.chunks_exact(n_kv) {
+                    for (score, weight) in self.attention_scores.iter_mut().zip(head) {
+                        *score += weight;
+                    }
+                }
+            }
+            #[cfg(feature = "attention-stats")]
+            if self.observes_attention() {
+                layer_weights.push(data);
+            }
+        }
+        #[cfg(feature = "attention-stats")]
+        if !layer_weights.is_empty() {
+            let stats: Vec<_> = layer_weights
+                .iter()
+                .enumerate()
+                .flat_map(|(layer, data)| attention_stats::head_stats(layer, data, n_kv))
+                .collect();
+            for observer in &mut self.attention_observers {
+                observer.observe(input_tokens, &stats);
+            }
+        }
+
         // Adjust n_past to new length.
         self.n_past += input_tokens.len();
 
@@ -286,21 +703,177 @@ impl InferenceSession {
         params: &InferenceParameters,
         prompt: P,
         output_request: &mut OutputRequest,
-        mut callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+        callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+    ) -> Result<(), InferenceError> {
+        self.feed_prompt_cancellable(model, params, prompt, output_request, None, callback)
+    }
+
+    /// Like [Self::feed_prompt], but fails with [InferenceError::Cancelled] when
+    /// `cancellation_token` is cancelled.
+    ///
+    /// The token is checked before each batch of [InferenceParameters::n_batch] tokens is
+    /// evaluated, so a smaller batch size makes cancellation quicker. The batches that
+    /// were evaluated before the cancellation stay in the session.
+    pub fn feed_prompt_cancellable<
+        'a,
+        E: std::error::Error + Send + Sync + 'static,
+        P: Into<Prompt<'a>>,
+    >(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        prompt: P,
+        output_request: &mut OutputRequest,
+        cancellation_token: Option<&CancellationToken>,
+        callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
     ) -> Result<(), InferenceError> {
-        let beginning_of_sentence = self.n_past == 0;
+        self.feed_prompt_inner(
+            model,
+            params,
+            prompt.into(),
+            output_request,
+            FeedLimits {
+                cancellation_token,
+                deadline: None,
+            },
+            false,
+            callback,
+        )?;
+        Ok(())
+    }
+
+    /// Feeds `prompt`, a guess of the next prompt, e.g. the usual next turn of a scripted
+    /// chat, so that it is processed while the session would otherwise be idle.
+    ///
+    /// The guess is committed or discarded by the next call to [Self::feed_prompt] or
+    /// [Self::infer]: the longest prefix of their prompt that matches it is kept and not
+    /// evaluated again, and the rest of the guess is rewound. When the guess was right, the
+    /// prompt is processed at once. The callback of the prompt is not called for the tokens
+    /// that were kept. Calling this again extends the guess.
+    ///
+    /// Pass a `cancellation_token` to stop speculating when the actual prompt arrives;
+    /// the part of the guess that was fed before is still used. A
+    /// [snapshot](Self::get_snapshot) taken before the guess is resolved keeps it as if it
+    /// was part of the prompt.
+    ///
+    /// This requires a model that [supports rewinding](Model::supports_rewind), and a
+    /// session without [InferenceSessionConfig::kv_eviction] or
+    /// [InferenceSessionConfig::context_overflow].
+    pub fn speculate<'a, P: Into<Prompt<'a>>>(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        prompt: P,
+        cancellation_token: Option<&CancellationToken>,
+    ) -> Result<(), SpeculationError> {
+        if !model.supports_rewind() {
+            return Err(SpeculationError::UnsupportedArchitecture);
+        }
+        if self.config.kv_eviction.is_some()
+            || self.config.context_overflow != ContextOverflowPolicy::Fail
+        {
+            return Err(SpeculationError::Eviction);
+        }
+        let n_past = self.n_past;
+        let result = self.feed_prompt_inner(
+            model,
+            params,
+            prompt.into(),
+            &mut OutputRequest::default(),
+            FeedLimits {
+                cancellation_token,
+                deadline: None,
+            },
+            true,
+            |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+        );
+        self.speculated += self.n_past - n_past;
+        result?;
+        Ok(())
+    }
+
+    /// The number of tokens fed by [Self::speculate] that are not resolved yet.
+    pub fn speculated_tokens(&self) -> usize {
+        self.speculated
+    }
+
+    /// Feeds `prompt`. Returns `false` if [FeedLimits::deadline] passed before all of it
+    /// was fed.
+    #[allow(clippy::too_many_arguments)]
+    fn feed_prompt_inner<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        prompt: Prompt,
+        output_request: &mut OutputRequest,
+        limits: FeedLimits,
+        speculative: bool,
+        mut callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+    ) -> Result<bool, InferenceError> {
+        self.restore()?;
+        // A speculative guess is resolved against the actual prompt, which follows the
+        // tokens that came before the guess.
+        let base = if speculative {
+            self.n_past
+        } else {
+            self.n_past - self.speculated
+        };
+        let beginning_of_sentence = base == 0;
 
         let vocab = model.tokenizer();
-        let prompt_tokens = prompt.into().to_tokens(vocab, beginning_of_sentence)?;
+        let mut prompt_tokens = prompt.to_tokens(vocab, beginning_of_sentence)?;
+        // The BOS token is the one the tokenizer adds, as not every model reports it.
+        let bos = vocab
+            .tokenize("", true)?
+            .first()
+            .map(|&(_, token)| token)
+            .or_else(|| model.bot_token_id());
+        if let (false, Some(bos)) = (self.config.allow_duplicate_bos, bos) {
+            let previous = self.tokens[..base].last().copied();
+            let removed = remove_duplicate_bos(&mut prompt_tokens, previous, bos);
+            if removed > 0 {
+                let diagnostic = Diagnostic::DuplicateBosRemoved { removed };
+                match model.diagnostics() {
+                    Some(diagnostics) => diagnostics.emit(diagnostic),
+                    None => Diagnostics::default().emit(diagnostic),
+                }
+            }
+        }
+        if !speculative && self.speculated > 0 {
+            prompt_tokens = self.resolve_speculation(model, prompt_tokens);
+        }
 
-        if self.n_past + prompt_tokens.len() >= model.context_size() {
+        // With eviction, the prompt can be longer than the context.
+        if !self.frees_memory(model) && self.n_past + prompt_tokens.len() >= self.context_size() {
             return Err(InferenceError::ContextFull);
         }
 
         for batch in prompt_tokens.chunks(params.n_batch) {
+            if limits
+                .cancellation_token
+                .map_or(false, CancellationToken::is_cancelled)
+            {
+                return Err(InferenceError::Cancelled);
+            }
+            if limits
+                .deadline
+                .map_or(false, |deadline| Instant::now() >= deadline)
+            {
+                self.sampler_state.set_generation_start(self.tokens.len());
+                return Ok(false);
+            }
+            self.make_room(model, batch.len())?;
             model.evaluate(self, params, batch, output_request);
+            self.logits_stale = false;
+            self.check_logits(batch)?;
+            if let Some(logits_callback) = &output_request.logits_callback {
+                logits_callback.call(batch, &self.last_logits);
+            }
+            // The whole batch was evaluated, so all of its tokens are recorded even once the
+            // callback halts, to keep `tokens` in step with `n_past`.
+            let mut halted = false;
             for &tk in batch {
-                let should_call_callback = Some(tk) != model.bot_token_id();
+                let should_call_callback = !halted && Some(tk) != model.bot_token_id();
 
                 let mut token = match model.tokenizer() {
                     crate::Tokenizer::Embedded(_) => model.tokenizer().token(tk as usize).to_vec(),
@@ -319,7 +892,8 @@ impl InferenceSession {
                         Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
                         Ok(f) => match f {
                             InferenceFeedback::Continue => (),
-                            InferenceFeedback::Halt => break,
+                            InferenceFeedback::Pause(duration) => std::thread::sleep(duration),
+                            InferenceFeedback::Halt => halted = true,
                         },
                     }
                 }
@@ -329,11 +903,51 @@ impl InferenceSession {
                 self.decoded_tokens.append(&mut token);
             }
         }
+        self.sampler_state.set_generation_start(self.tokens.len());
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// Keeps the speculated tokens that start `prompt_tokens`, and rewinds the others.
+    /// Returns the tokens of the prompt that remain to be fed.
+    fn resolve_speculation(
+        &mut self,
+        model: &dyn Model,
+        mut prompt_tokens: Vec<TokenId>,
+    ) -> Vec<TokenId> {
+        let base = self.n_past - self.speculated;
+        let kept = self.tokens[base..self.n_past]
+            .iter()
+            .zip(&prompt_tokens)
+            .take_while(|(speculated, actual)| speculated == actual)
+            .count();
+        let mut keep = base + kept;
+        prompt_tokens.drain(..kept);
+        // The logits are those of the last speculated token, so the last kept token is
+        // evaluated again if there is nothing else to evaluate.
+        if prompt_tokens.is_empty() && keep < self.n_past && keep > 0 {
+            keep -= 1;
+            prompt_tokens.push(self.tokens[keep]);
+        }
+        let discarded = self.n_past - keep;
+        if discarded > 0 {
+            self.remove_last_tokens(model, discarded);
+        }
+        // Without tokens to evaluate again, the logits of the guess are replaced with
+        // those of a new session.
+        if keep == 0 && prompt_tokens.is_empty() {
+            self.last_logits.fill(0.0);
+        }
+        self.speculated = 0;
+        prompt_tokens
     }
 
-    /// Removes `num` tokens from the end of the buffer. Roughly the inverse of `feed_prompt`.
+    /// Removes `num` tokens from the end of the buffer and of the key/value memory, and
+    /// returns them. Roughly the inverse of `feed_prompt`.
+    ///
+    /// This lets a prompt be fed once and continued in several ways: the next token is
+    /// predicted from the tokens that remain, as the last of them is evaluated again before
+    /// it is sampled. At least one token must remain.
     pub fn rewind(&mut self, model: &dyn Model, num: usize) -> Result<Vec<TokenId>, RewindError> {
         if !model.supports_rewind() {
             return Err(RewindError::UnsupportedArchitecture);
@@ -343,6 +957,67 @@ impl InferenceSession {
             return Err(RewindError::NotEnoughTokens);
         }
 
+        self.speculated = self.speculated.saturating_sub(num);
+        self.logits_stale |= num > 0;
+        Ok(self.remove_last_tokens(model, num))
+    }
+
+    /// Keeps the first `len` tokens of the key/value memory ([Self::n_past] of them) and
+    /// removes the others, e.g. to go back to the end of a prompt of `len` tokens. See
+    /// [Self::rewind].
+    ///
+    /// A `len` of 0 clears the session, which is then like a new one, with the default
+    /// logits and sampler state. This works with every model, as nothing has to be
+    /// evaluated again.
+    pub fn truncate_to(
+        &mut self,
+        model: &dyn Model,
+        len: usize,
+    ) -> Result<Vec<TokenId>, RewindError> {
+        if len == 0 {
+            return Ok(self.clear());
+        }
+        if len >= self.n_past && model.supports_rewind() {
+            return Ok(vec![]);
+        }
+        self.rewind(model, self.n_past - len.min(self.n_past))
+    }
+
+    /// Removes all of the tokens of the session, and returns them.
+    fn clear(&mut self) -> Vec<TokenId> {
+        self.decoded_tokens.clear();
+        self.n_past = 0;
+        self.n_evicted = 0;
+        self.speculated = 0;
+        self.logits_stale = false;
+        self.last_logits.fill(0.0);
+        self.attention_scores.fill(0.0);
+        self.sampler_state = SamplerState::default();
+        self.layer_outputs.clear();
+        self.attention_weights.clear();
+        std::mem::take(&mut self.tokens)
+    }
+
+    /// Evaluates the last token again if it was [rewound](Self::rewind) to, so that
+    /// [Self::last_logits] are its predictions.
+    fn refresh_logits(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+    ) -> Result<(), InferenceError> {
+        if !self.logits_stale {
+            return Ok(());
+        }
+        let last = self.tokens[self.n_past - 1];
+        // The entry of the key/value memory is overwritten with the same values.
+        self.n_past -= 1;
+        model.evaluate(self, params, &[last], &mut OutputRequest::default());
+        self.logits_stale = false;
+        self.check_logits(&[last])
+    }
+
+    /// Removes the last `num` of the `n_past` tokens, which must exist.
+    fn remove_last_tokens(&mut self, model: &dyn Model, num: usize) -> Vec<TokenId> {
         // Remove the tokens from self.tokens.
         let token_start = self.n_past - num;
         let deleted_tokens: Vec<_> = self.tokens.drain(token_start..).collect();
@@ -356,8 +1031,151 @@ impl InferenceSession {
 
         // Decrement the n_past tokens counter.
         self.n_past -= num;
+        if let Some(scores) = self
+            .attention_scores
+            .get_mut(token_start..token_start + num)
+        {
+            scores.fill(0.0);
+        }
+        let generation_start = self.sampler_state.generation_start();
+        self.sampler_state
+            .set_generation_start(generation_start.min(self.tokens.len()));
+
+        deleted_tokens
+    }
+
+    /// Whether the session frees entries of the key/value memory when it is full, with
+    /// [InferenceSessionConfig::kv_eviction] or [InferenceSessionConfig::context_overflow].
+    fn frees_memory(&self, model: &dyn Model) -> bool {
+        model.kv_layout().is_some()
+            && (self.config.kv_eviction.is_some()
+                || self.config.context_overflow != ContextOverflowPolicy::Fail)
+    }
+
+    /// Makes room for `n_tokens` more tokens in the key/value memory, evicting entries if
+    /// [InferenceSessionConfig::kv_eviction] or [InferenceSessionConfig::context_overflow]
+    /// is set and the model supports it.
+    fn make_room(&mut self, model: &dyn Model, n_tokens: usize) -> Result<(), InferenceError> {
+        let context_size = self.context_size();
+        if self.n_past + n_tokens < context_size {
+            return Ok(());
+        }
+        let Some(layout) = model.kv_layout() else {
+            return Err(InferenceError::ContextFull);
+        };
+        let keep = match (self.config.kv_eviction, self.config.context_overflow) {
+            (Some(eviction), _) => {
+                if eviction.recent + eviction.heavy_hitters + n_tokens >= context_size {
+                    return Err(InferenceError::ContextFull);
+                }
+                positions_to_keep(&self.attention_scores[..self.n_past], eviction)
+            }
+            (None, ContextOverflowPolicy::Shift { keep_first_n }) => {
+                if keep_first_n + n_tokens >= context_size {
+                    return Err(InferenceError::ContextFull);
+                }
+                positions_to_shift(self.n_past, keep_first_n, context_size - n_tokens)
+            }
+            (None, ContextOverflowPolicy::Fail) => return Err(InferenceError::ContextFull),
+        };
+        self.compact_memory(layout, &keep);
+
+        for (new, &old) in keep.iter().enumerate() {
+            if let Some(&score) = self.attention_scores.get(old) {
+                self.attention_scores[new] = score;
+            }
+            self.tokens[new] = self.tokens[old];
+        }
+        if let Some(freed) = self.attention_scores.get_mut(keep.len()..) {
+            freed.fill(0.0);
+        }
+        self.tokens.truncate(keep.len());
+        let generation_start = self.sampler_state.generation_start();
+        self.sampler_state
+            .set_generation_start(keep.iter().filter(|&&i| i < generation_start).count());
+        self.decoded_tokens = match model.tokenizer() {
+            crate::Tokenizer::Embedded(_) => self
+                .tokens
+                .iter()
+                .flat_map(|&id| model.tokenizer().token(id as usize))
+                .collect(),
+            crate::Tokenizer::HuggingFace(_) => model.tokenizer().decode(self.tokens.clone(), true),
+        };
+
+        log::debug!(
+            "evicted {} entries from the key/value memory",
+            self.n_past - keep.len()
+        );
+        self.n_evicted += self.n_past - keep.len();
+        self.n_past = keep.len();
+        Ok(())
+    }
+
+    /// Moves the entries of the key/value memory at the positions `keep`, in increasing
+    /// order, to the start of the memory.
+    fn compact_memory(&mut self, layout: KvLayout, keep: &[usize]) {
+        let (n_layer, n_ctx) = (self.n_layer, self.n_ctx);
+        for (memory, transposed) in [
+            (&mut self.memory_k, false),
+            (&mut self.memory_v, layout.transposed_values),
+        ] {
+            let (rows, row_size, entry_size) =
+                kv_rows(layout, transposed, n_layer, n_ctx, memory.element_size());
+            // SAFETY: the session has exclusive access to its memory, and no graph is being
+            // computed.
+            let bytes = unsafe { memory_bytes(memory) };
+            for row in bytes.chunks_exact_mut(row_size).take(rows) {
+                for (new, &old) in keep.iter().enumerate() {
+                    row.copy_within(old * entry_size..(old + 1) * entry_size, new * entry_size);
+                }
+            }
+        }
+    }
 
-        Ok(deleted_tokens)
+    /// Fails with [InferenceError::NumericalError] if the logits of the evaluation of
+    /// `input_tokens` are not all finite, writing them to
+    /// [InferenceSessionConfig::numerical_error_dump] if it is set.
+    fn check_logits(&self, input_tokens: &[TokenId]) -> Result<(), InferenceError> {
+        if self.last_logits.iter().all(|logit| logit.is_finite()) {
+            return Ok(());
+        }
+
+        let step = self.position() - 1;
+        let layer_hint = self
+            .layer_outputs()
+            .iter()
+            .position(|output| output.iter().any(|value| !value.is_finite()));
+        if let Some(path) = &self.config.numerical_error_dump {
+            let dump = || -> std::io::Result<()> {
+                let mut writer = BufWriter::new(File::create(util::long_path(path))?);
+                writeln!(writer, "# step: {step}")?;
+                match layer_hint {
+                    Some(layer) => writeln!(writer, "# first non-finite layer output: {layer}")?,
+                    None => writeln!(writer, "# first non-finite layer output: unknown")?,
+                }
+                writeln!(writer, "# input tokens: {input_tokens:?}")?;
+                for logit in &self.last_logits {
+                    writeln!(writer, "{logit}")?;
+                }
+                writer.flush()
+            };
+            match dump() {
+                Ok(()) => log::info!("wrote the non-finite logits to {}", path.display()),
+                Err(e) => log::warn!(
+                    "failed to write the non-finite logits to {}: {e}",
+                    path.display()
+                ),
+            }
+        }
+        Err(InferenceError::NumericalError { step, layer_hint })
+    }
+
+    /// The position in the sequence of the next token to be evaluated, for positional
+    /// encodings. This is [Self::n_past] plus the number of tokens evicted from the
+    /// key/value memory by [InferenceSessionConfig::kv_eviction] or
+    /// [InferenceSessionConfig::context_overflow].
+    pub fn position(&self) -> usize {
+        self.n_past + self.n_evicted
     }
 
     /// Infer the next token for this session.
@@ -368,20 +1186,38 @@ impl InferenceSession {
         output_request: &mut OutputRequest,
         rng: &mut impl rand::Rng,
     ) -> Result<Vec<u8>, InferenceError> {
-        if self.n_past + 1 >= model.context_size() {
-            return Err(InferenceError::ContextFull);
-        }
+        self.restore()?;
+        self.refresh_logits(model, params)?;
+        self.make_room(model, 1)?;
 
-        let next_token = params.sampler.sample(&self.tokens, &self.last_logits, rng);
+        let processed_logits;
+        let logits = if self.logits_processors.is_empty() {
+            &self.last_logits
+        } else {
+            let mut logits = self.last_logits.clone();
+            for processor in &mut self.logits_processors {
+                processor.process(&self.tokens, &mut logits);
+            }
+            processed_logits = logits;
+            &processed_logits
+        };
+        let next_token =
+            params
+                .sampler
+                .sample_with_state(&mut self.sampler_state, &self.tokens, logits, rng);
 
         // Update the tokens for this session
         self.tokens.push(next_token);
 
         // Then, evaluate the network again to compute the new last_logits
         model.evaluate(self, params, &[next_token], output_request);
+        self.check_logits(&[next_token])?;
+        if let Some(logits_callback) = &output_request.logits_callback {
+            logits_callback.call(&[next_token], &self.last_logits);
+        }
 
         // Return the next token
-        if next_token as TokenId == model.eot_token_id() {
+        if model.stop_token_ids().contains(&next_token) {
             Err(InferenceError::EndOfText)
         } else {
             let res = match model.tokenizer() {
@@ -403,8 +1239,9 @@ impl InferenceSession {
     /// Generate text by using the provided [Model] to evaluate the `prompt`.
     ///
     /// The `callback` is called with each new token until an end-of-text (EOT)
-    /// token is encountered or the maximum number of tokens have been
-    /// generated (specified by [InferenceRequest::maximum_token_count]).
+    /// token is encountered, the maximum number of tokens have been
+    /// generated (specified by [InferenceRequest::maximum_token_count]) or the
+    /// output has reached its maximum length. [InferenceStats::stop_reason] tells which.
     ///
     /// This is a wrapper around [Self::feed_prompt] and [Self::infer_next_token].
     pub fn infer<E: std::error::Error + Send + Sync + 'static>(
@@ -434,52 +1271,264 @@ impl InferenceSession {
 
         let mut stats = InferenceStats::default();
         let start_at = std::time::SystemTime::now();
+        let start_resources = ResourceSnapshot::now();
 
         let parameters = request.parameters;
+        self.sampler_handle.set(parameters.clone());
+
+        // The forced prefix is fed after the prompt, and passed to the callback as if the
+        // model had generated it. A text prompt is tokenized together with the prefix, so
+        // that the tokens at the boundary are those the model would see in the whole text.
+        let mut prompt = request.prompt;
+        let mut forced = request
+            .forced_prefix
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| (Prompt::Text(prefix), prefix));
+        let joined_tokens;
+        if let (Prompt::Text(text), Some((_, prefix))) = (prompt, forced) {
+            let tokenizer = model.tokenizer();
+            let mut tokens = tokenizer
+                .tokenize("", self.n_past == self.speculated)?
+                .into_iter()
+                .map(|(_, id)| id)
+                .collect::<Vec<_>>();
+            let mut prompt_len = tokens.len();
+            for (id, range) in tokenizer.tokenize_with_offsets(&format!("{text}{prefix}"))? {
+                tokens.push(id);
+                // A token that spans the boundary belongs to the prompt.
+                if range.start < text.len() {
+                    prompt_len = tokens.len();
+                }
+            }
+            joined_tokens = tokens;
+            let (prompt_tokens, prefix_tokens) = joined_tokens.split_at(prompt_len);
+            prompt = Prompt::Tokens(prompt_tokens);
+            forced = Some((Prompt::Tokens(prefix_tokens), prefix));
+        }
 
         // Feed the initial prompt through the transformer, to update its
         // context window with new data, if necessary.
-        if !request.prompt.is_empty() {
-            self.feed_prompt(
+        let limits = FeedLimits {
+            cancellation_token: request.cancellation_token,
+            deadline: request.deadline,
+        };
+        // An empty prompt still discards a speculative guess.
+        let mut fed = true;
+        if !prompt.is_empty() || self.speculated > 0 {
+            fed = self.feed_prompt_inner(
                 model,
                 parameters,
-                request.prompt,
+                prompt,
                 output_request,
+                limits,
+                false,
                 feed_prompt_callback(&mut callback),
             )?;
         }
         stats.feed_prompt_duration = start_at.elapsed().unwrap();
-        stats.prompt_tokens = self.n_past;
+        stats.prompt_tokens = self.position();
+        if let Some((prefix_tokens, _)) = forced.filter(|(tokens, _)| fed && !tokens.is_empty()) {
+            let generation_start = self.tokens.len();
+            fed = self.feed_prompt_inner(
+                model,
+                parameters,
+                prefix_tokens,
+                output_request,
+                limits,
+                false,
+                |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+            )?;
+            // The prefix is part of the response, e.g. for the repetition penalty.
+            self.sampler_state.set_generation_start(generation_start);
+        }
+        if !fed {
+            // Nothing was generated, and a partly fed prefix is not output.
+            stats.stop_reason = StopReason::Deadline;
+            stats.predict_duration = start_at.elapsed().unwrap();
+            stats.resource_usage = start_resources.elapsed();
+            return Ok(stats);
+        }
 
         // After the prompt is consumed, sample tokens by repeatedly calling
         // `infer_next_token`. We generate tokens until the model returns an
         // EndOfText token, or we run out of space in the context window,
         // or we reach the specified limit.
         let mut tokens_processed = 0;
+        // Only the logits callback applies to the generated tokens, so that the outputs
+        // requested for the prompt are kept.
+        let mut generation_output = OutputRequest {
+            logits_callback: output_request.logits_callback.clone(),
+            ..Default::default()
+        };
         let mut token_utf8_buf = TokenUtf8Buffer::new();
-        while tokens_processed < maximum_token_count {
-            let token = match self.infer_next_token(model, parameters, &mut Default::default(), rng)
-            {
-                Ok(token) => token,
-                Err(InferenceError::EndOfText) => break,
-                Err(e) => return Err(e),
-            };
+        let mut output_budget = OutputBudget {
+            bytes: request.maximum_output_bytes.unwrap_or(usize::MAX),
+            chars: request.maximum_output_chars.unwrap_or(usize::MAX),
+        };
+        let mut stop_tokens = StopTokenMatcher::new(request.stop_token_sequences);
+        let early_stop = request.early_stop;
+        let eot_tokens = match early_stop.eot_probability {
+            Some(_) => model.stop_token_ids(),
+            None => vec![],
+        };
+        let mut low_entropy_steps = 0;
+        let mut guardrails = GuardrailChain::new(request.guardrails);
+        // Passes the text of a generated token to the callback, returning why generation
+        // must stop, if it must. `end` flushes the text held back by the guardrails. The
+        // logprobs of a newly generated token are passed first.
+        let mut emit = |token: &[u8],
+                        logprobs: Option<TokenLogprobs>,
+                        end: bool|
+         -> Result<Option<StopReason>, InferenceError> {
+            if let Some(logprobs) = logprobs {
+                match callback(InferenceResponse::Logprobs(logprobs)) {
+                    Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
+                    Ok(InferenceFeedback::Continue) => (),
+                    Ok(InferenceFeedback::Pause(duration)) => std::thread::sleep(duration),
+                    Ok(InferenceFeedback::Halt) => return Ok(Some(StopReason::Halted)),
+                }
+            }
+            if token.is_empty() && !end {
+                return Ok(None);
+            }
 
-            // Buffer the token until it's valid UTF-8, then call the callback.
-            if let Some(tokens) = token_utf8_buf.push(&token) {
+            // Buffer the token until it's valid UTF-8, then check it against the guardrails
+            // and call the callback.
+            let tokens = match token_utf8_buf.push(token) {
+                Some(tokens) => tokens,
+                None if end => String::new(),
+                None => return Ok(None),
+            };
+            let (mut tokens, violated) = guardrails.push(tokens, end);
+            let exhausted = output_budget.take(&mut tokens);
+            if !tokens.is_empty() {
                 match callback(InferenceResponse::InferredToken(tokens)) {
                     Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
-                    Ok(f) => match f {
-                        InferenceFeedback::Continue => (),
-                        InferenceFeedback::Halt => break,
-                    },
+                    Ok(InferenceFeedback::Continue) => (),
+                    Ok(InferenceFeedback::Pause(duration)) => std::thread::sleep(duration),
+                    Ok(InferenceFeedback::Halt) => return Ok(Some(StopReason::Halted)),
+                }
+            }
+            if violated {
+                return Ok(Some(StopReason::Guardrail));
+            }
+            Ok(exhausted.then_some(StopReason::MaximumOutput))
+        };
+        stats.stop_reason = StopReason::MaximumTokens;
+        if let Some((_, prefix)) = forced {
+            if let Some(stop_reason) = emit(prefix.as_bytes(), None, false)? {
+                stats.stop_reason = stop_reason;
+            }
+        }
+        'generation: while stats.stop_reason == StopReason::MaximumTokens
+            && tokens_processed < maximum_token_count
+        {
+            if request
+                .cancellation_token
+                .map_or(false, CancellationToken::is_cancelled)
+            {
+                return Err(InferenceError::Cancelled);
+            }
+            if request
+                .deadline
+                .map_or(false, |deadline| Instant::now() >= deadline)
+            {
+                stats.stop_reason = StopReason::Deadline;
+                break;
+            }
+            // The callback may have adjusted the parameters since the previous token.
+            let parameters = self.sampler_handle.get();
+            let logits = request.logprobs.map(|_| self.last_logits.clone());
+            if early_stop != EarlyStop::default() && !self.last_logits.is_empty() {
+                let (eot_probability, entropy) =
+                    EarlyStop::statistics(&self.last_logits, &eot_tokens);
+                if early_stop
+                    .eot_probability
+                    .map_or(false, |threshold| eot_probability > threshold)
+                {
+                    stats.stop_reason = StopReason::EndOfTextProbability;
+                    break;
+                }
+                if entropy < early_stop.low_entropy_threshold {
+                    low_entropy_steps += 1;
+                } else {
+                    low_entropy_steps = 0;
+                }
+            }
+            let token = match self.infer_next_token(model, &parameters, &mut generation_output, rng)
+            {
+                Ok(token) => token,
+                Err(InferenceError::EndOfText) => {
+                    stats.stop_reason = StopReason::EndOfText;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let token_id = *self.tokens.last().expect("a token was just generated");
+            if let (Some(logits), Some(top)) = (logits, request.logprobs) {
+                let logprobs = TokenLogprobs::new(&logits, token_id, top);
+                if let Some(stop_reason) = emit(&[], Some(logprobs), false)? {
+                    stats.stop_reason = stop_reason;
+                    break;
                 }
             }
+            let (released, stopped) = stop_tokens.push(token_id, token);
+            for token in released {
+                if let Some(stop_reason) = emit(&token, None, false)? {
+                    stats.stop_reason = stop_reason;
+                    break 'generation;
+                }
+            }
+            if stopped {
+                stats.stop_reason = StopReason::StopTokens;
+                break;
+            }
 
             tokens_processed += 1;
+            if early_stop
+                .low_entropy_steps
+                .map_or(false, |steps| low_entropy_steps >= steps)
+            {
+                stats.stop_reason = StopReason::LowEntropy;
+                break;
+            }
+        }
+        // Tokens held back because they could have started a stop sequence are output
+        // after all if generation ended without one.
+        if matches!(
+            stats.stop_reason,
+            StopReason::MaximumTokens
+                | StopReason::EndOfText
+                | StopReason::EndOfTextProbability
+                | StopReason::LowEntropy
+                | StopReason::Deadline
+        ) {
+            for token in stop_tokens.finish() {
+                if let Some(stop_reason) = emit(&token, None, false)? {
+                    stats.stop_reason = stop_reason;
+                    break;
+                }
+            }
+        }
+        // Likewise for the text held back by the guardrails.
+        if matches!(
+            stats.stop_reason,
+            StopReason::MaximumTokens
+                | StopReason::EndOfText
+                | StopReason::StopTokens
+                | StopReason::EndOfTextProbability
+                | StopReason::LowEntropy
+                | StopReason::Deadline
+        ) && !request.guardrails.is_empty()
+        {
+            if let Some(stop_reason) = emit(&[], None, true)? {
+                stats.stop_reason = stop_reason;
+            }
         }
         stats.predict_duration = start_at.elapsed().unwrap();
-        stats.predict_tokens = self.n_past;
+        stats.predict_tokens = self.position();
+        stats.resource_usage = start_resources.elapsed();
 
         Ok(stats)
     }
@@ -495,7 +1544,8 @@ impl InferenceSession {
         parameters: &InferenceParameters,
         prompt: P,
         mut perplexity_callback: impl FnMut(usize, f32),
-    ) -> Result<(), TokenizationError> {
+    ) -> Result<(), InferenceError> {
+        self.restore()?;
         // Implementation based on perplexity example of llama.cpp:
         // https://github.com/ggerganov/llama.cpp/blob/2d5db48371052087a83974abda3767d1aedec598/examples/perplexity/perplexity.cpp#L24
         let mut tokens = prompt.into().to_tokens(model.tokenizer(), true)?;
@@ -503,7 +1553,7 @@ impl InferenceSession {
         let mut count = 0;
 
         // TODO: make this handle <n_ctx tokens
-        let n_ctx = model.context_size();
+        let n_ctx = self.context_size();
         let n_chunk = tokens.len() / n_ctx;
         let n_vocab = model.tokenizer().len();
         let n_batch = parameters.n_batch;
@@ -563,6 +1613,112 @@ impl InferenceSession {
         Ok(())
     }
 
+    /// Feeds `prompt` to the model, then picks the most likely of `options` to follow it,
+    /// without generating any text.
+    ///
+    /// Each option is scored by the total log-likelihood of its tokens following the
+    /// prompt, so the model can only ever answer with one of the options. Note that the
+    /// options are tokenized on their own, so they should include any leading whitespace
+    /// (e.g. `" yes"` after `"Answer:"`). Longer options have lower likelihoods, so
+    /// options of similar length work best.
+    ///
+    /// The prompt remains in the session afterwards, but the options do not. The session
+    /// must not be empty once the prompt is fed, as the first token of each option is
+    /// predicted from the last token before it.
+    pub fn choose<'a, P: Into<Prompt<'a>>>(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        prompt: P,
+        options: &[&str],
+    ) -> Result<Choice, ChooseError> {
+        if options.is_empty() {
+            return Err(ChooseError::NoOptions);
+        }
+        if !model.supports_rewind() {
+            return Err(ChooseError::UnsupportedArchitecture);
+        }
+        let option_tokens = options
+            .iter()
+            .enumerate()
+            .map(|(index, option)| {
+                let tokens = Prompt::from(*option).to_tokens(model.tokenizer(), false)?;
+                if tokens.is_empty() {
+                    return Err(ChooseError::EmptyOption { index });
+                }
+                Ok(tokens)
+            })
+            .collect::<Result<Vec<_>, ChooseError>>()?;
+
+        self.feed_prompt(model, params, prompt, &mut Default::default(), |_| {
+            Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+        })?;
+        if self.n_past == 0 {
+            return Err(ChooseError::EmptyPrompt);
+        }
+        self.refresh_logits(model, params)?;
+        let n_past = self.n_past;
+        let prompt_logits = self.last_logits.clone();
+        // Evaluating an option only extends the key/value memory past the prompt, but
+        // everything it updates is put back before the next option is evaluated.
+        let n_tokens = self.tokens.len();
+        let n_decoded = self.decoded_tokens.len();
+        let attention_scores = self.attention_scores.clone();
+
+        let longest = option_tokens.iter().map(|t| t.len()).max().unwrap_or(0);
+        if n_past + longest >= self.context_size() {
+            return Err(InferenceError::ContextFull.into());
+        }
+
+        let n_vocab = model.tokenizer().len();
+        let log_likelihoods = option_tokens
+            .iter()
+            .map(|tokens| {
+                // The logits after the prompt predict the first token, and the logits after
+                // each token of the option predict the next one.
+                let mut logits = prompt_logits.clone();
+                if tokens.len() > 1 {
+                    let mut output_request = OutputRequest {
+                        all_logits: Some(vec![]),
+                        ..Default::default()
+                    };
+                    model.evaluate(
+                        self,
+                        params,
+                        &tokens[..tokens.len() - 1],
+                        &mut output_request,
+                    );
+                    logits.extend(output_request.all_logits.unwrap());
+                    self.n_past = n_past;
+                    self.tokens.truncate(n_tokens);
+                    self.decoded_tokens.truncate(n_decoded);
+                    self.attention_scores.copy_from_slice(&attention_scores);
+                }
+
+                tokens
+                    .iter()
+                    .zip(logits.chunks(n_vocab))
+                    .map(|(&token, logits)| util::softmax(logits)[token as usize].ln())
+                    .sum()
+            })
+            .collect::<Vec<f32>>();
+        self.last_logits = prompt_logits;
+
+        let probabilities = util::softmax(&log_likelihoods);
+        let index = probabilities
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index)
+            .unwrap();
+
+        Ok(Choice {
+            index,
+            probabilities,
+            log_likelihoods,
+        })
+    }
+
     /// Obtains a serializable snapshot of the current inference status. This
     /// can be used to cache the state of the model and store them into a file.
     ///
@@ -571,7 +1727,10 @@ impl InferenceSession {
     /// This function provides raw access to the underlying memory owned by the
     /// ggml context. While the provided `InferenceSnapshotRef` object is alive,
     /// no other methods for this model object should be called.
-    pub unsafe fn get_snapshot(&mut self) -> InferenceSnapshotRef<'_> {
+    ///
+    /// If the session is [spilled](Self::spill), it is restored first, which can fail.
+    pub unsafe fn get_snapshot(&mut self) -> Result<InferenceSnapshotRef<'_>, SpillError> {
+        self.restore()?;
         let memory_k = unsafe {
             std::slice::from_raw_parts(self.memory_k.data() as *mut u8, self.memory_k.nbytes())
         };
@@ -579,14 +1738,17 @@ impl InferenceSession {
             std::slice::from_raw_parts(self.memory_v.data() as *mut u8, self.memory_v.nbytes())
         };
 
-        InferenceSnapshotRef {
+        Ok(InferenceSnapshotRef {
             npast: self.n_past,
-            config: self.config,
+            n_evicted: self.n_evicted,
+            config: self.config.clone(),
             tokens: self.tokens.clone(),
             logits: self.last_logits.clone(),
             memory_k,
             memory_v,
-        }
+            sampler_state: self.sampler_state.clone(),
+            rng: None,
+        })
     }
 
     /// Creates an [InferenceSession] from a snapshot.
@@ -594,7 +1756,7 @@ impl InferenceSession {
         snapshot: InferenceSnapshot,
         model: &dyn Model,
     ) -> Result<Self, SnapshotError> {
-        let mut session = model.start_session(snapshot.config);
+        let mut session = model.try_start_session(snapshot.config)?;
 
         if session.memory_k.nbytes() != snapshot.memory_k.len()
             || session.memory_v.nbytes() != snapshot.memory_v.len()
@@ -614,12 +1776,364 @@ impl InferenceSession {
         }
 
         session.n_past = snapshot.npast;
+        session.n_evicted = snapshot.n_evicted;
         session.tokens = snapshot.tokens;
         session.last_logits = snapshot.last_logits;
+        session.sampler_state = snapshot.sampler_state;
 
         Ok(session)
     }
 
+    /// Writes the key/value memory of this session to a new file at `path`, and frees it
+    /// along with the scratch and evaluation buffers, so that an idle session (e.g. of a
+    /// conversation that is waiting for its user) holds almost no memory.
+    ///
+    /// The memory is restored from the file the next time the session is used, or with
+    /// [Self::restore], after which the file is removed; it is also removed if the session
+    /// is dropped while spilled. Restoring fails if it would exceed the
+    /// [memory limit](crate::memory::set_limit). Does nothing if the session is already
+    /// spilled.
+    pub fn spill(&mut self, path: impl Into<PathBuf>) -> Result<(), SpillError> {
+        if self.spilled.is_some() {
+            return Ok(());
+        }
+        #[cfg(feature = "metal")]
+        if self.metal_context.is_some() {
+            return Err(SpillError::Unsupported);
+        }
+
+        let spilled = SpilledMemory {
+            path: path.into(),
+            n_elements: self.memory_k.nelements(),
+            eval_size: self.ctx0.buffer.as_ref().map_or(0, Buffer::size),
+            reservation: self.memory_reservation.allocations().to_vec(),
+        };
+        let mut writer = BufWriter::new(File::create(util::long_path(&spilled.path))?);
+        // SAFETY: the tensors are not used by anything else while the session is borrowed.
+        unsafe {
+            writer.write_all(memory_bytes(&mut self.memory_k))?;
+            writer.write_all(memory_bytes(&mut self.memory_v))?;
+        }
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+
+        // Replace everything that holds memory with placeholders until it is restored.
+        let placeholder = Arc::new(ggml::Context::init(1024, false));
+        self.memory_k = placeholder.new_tensor_1d(self.config.memory_k_type.into(), 0);
+        self.memory_v = placeholder.new_tensor_1d(self.config.memory_v_type.into(), 0);
+        self._session_ctx = placeholder;
+        self.layer_outputs.clear();
+        self.attention_weights.clear();
+        self.ctx0 = ggml::Context::init(1024, false);
+        self.scratch = [Buffer::new(1), Buffer::new(1)];
+        self.memory_reservation = Reservation::default();
+        self.spilled = Some(spilled);
+        Ok(())
+    }
+
+    /// Restores the key/value memory of a session that was [spilled](Self::spill), and
+    /// removes the file it was written to. Does nothing if the session is not spilled.
+    ///
+    /// This is done automatically the next time the session is used.
+    pub fn restore(&mut self) -> Result<(), SpillError> {
+        let Some(spilled) = self.spilled.as_ref() else {
+            return Ok(());
+        };
+
+        let memory_reservation = memory::reserve(&spilled.reservation)?;
+        let mut reader = BufReader::new(File::open(util::long_path(&spilled.path))?);
+        let session_ctx = Arc::new(ggml::Context::init(self._memory_size, true));
+        let mut memory_k =
+            session_ctx.new_tensor_1d(self.config.memory_k_type.into(), spilled.n_elements);
+        let mut memory_v =
+            session_ctx.new_tensor_1d(self.config.memory_v_type.into(), spilled.n_elements);
+        ggml::set_name(&memory_k, "memory_k");
+        ggml::set_name(&memory_v, "memory_v");
+        // SAFETY: the tensors were just created, so nothing else refers to them.
+        unsafe {
+            reader.read_exact(memory_bytes(&mut memory_k))?;
+            reader.read_exact(memory_bytes(&mut memory_v))?;
+        }
+
+        self._session_ctx = session_ctx;
+        self.memory_k = memory_k;
+        self.memory_v = memory_v;
+        self.ctx0 = ggml::Context::init_buffer(Buffer::new(spilled.eval_size));
+        self.scratch = scratch_buffers();
+        self.memory_reservation = memory_reservation;
+        self.spilled = None;
+        Ok(())
+    }
+
+    /// Whether the key/value memory of this session is [spilled](Self::spill) to a file.
+    pub fn is_spilled(&self) -> bool {
+        self.spilled.is_some()
+    }
+
+    /// The sequence that the session feeds and infers with. A session starts with a single
+    /// sequence.
+    ///
+    /// Each sequence of a session is an independent conversation, with its own tokens,
+    /// sampler state and key/value memory, so that several can be served with one
+    /// session and one copy of the model weights. Each sequence that has been used has a
+    /// key/value memory as large as that of the session, which counts towards the
+    /// [memory limit](crate::memory::set_limit). Only the active sequence is evaluated:
+    /// [Self::switch_sequence] makes another one active without copying its entries, but
+    /// the sequences are evaluated one at a time, not batched together. Snapshots cover
+    /// the active sequence only.
+    pub fn sequence(&self) -> SequenceId {
+        self.sequence
+    }
+
+    /// All sequences of the session, in the order they were created.
+    pub fn sequences(&self) -> Vec<SequenceId> {
+        let mut sequences: Vec<_> = self.parked_sequences.keys().copied().collect();
+        let index = sequences.partition_point(|&id| id < self.sequence);
+        sequences.insert(index, self.sequence);
+        sequences
+    }
+
+    /// Adds an empty sequence to the session, without switching to it.
+    pub fn create_sequence(&mut self) -> SequenceId {
+        let id = self.new_sequence_id();
+        let sequence = ParkedSequence {
+            tokens: vec![],
+            decoded_tokens: vec![],
+            n_past: 0,
+            n_evicted: 0,
+            speculated: 0,
+            logits_stale: false,
+            last_logits: vec![0.0; self.last_logits.len()],
+            sampler_state: SamplerState::default(),
+            attention_scores: vec![0.0; self.attention_scores.len()],
+            slot: None,
+        };
+        self.parked_sequences.insert(id, sequence);
+        id
+    }
+
+    /// Adds a copy of the sequence `id` to the session, without switching to it, e.g. to
+    /// continue a shared system prompt in several conversations without feeding it again.
+    ///
+    /// The copy gets a key/value memory of its own, into which the entries of `id` are
+    /// copied, so forking takes time in proportion to the number of tokens of `id`.
+    pub fn fork_sequence(
+        &mut self,
+        model: &dyn Model,
+        id: SequenceId,
+    ) -> Result<SequenceId, SequenceError> {
+        let sequence = if id == self.sequence {
+            self.check_sequences_supported()?;
+            let slot = self.copy_kv_slot(model, [&self.memory_k, &self.memory_v], self.n_past)?;
+            ParkedSequence {
+                tokens: self.tokens.clone(),
+                decoded_tokens: self.decoded_tokens.clone(),
+                n_past: self.n_past,
+                n_evicted: self.n_evicted,
+                speculated: self.speculated,
+                logits_stale: self.logits_stale,
+                last_logits: self.last_logits.clone(),
+                sampler_state: self.sampler_state.clone(),
+                attention_scores: self.attention_scores.clone(),
+                slot: Some(slot),
+            }
+        } else {
+            let parked = self
+                .parked_sequences
+                .get(&id)
+                .ok_or(SequenceError::UnknownSequence(id))?;
+            let slot = match &parked.slot {
+                Some(slot) => Some(self.copy_kv_slot(
+                    model,
+                    [&slot.memory_k, &slot.memory_v],
+                    parked.n_past,
+                )?),
+                None => None,
+            };
+            ParkedSequence {
+                tokens: parked.tokens.clone(),
+                decoded_tokens: parked.decoded_tokens.clone(),
+                n_past: parked.n_past,
+                n_evicted: parked.n_evicted,
+                speculated: parked.speculated,
+                logits_stale: parked.logits_stale,
+                last_logits: parked.last_logits.clone(),
+                sampler_state: parked.sampler_state.clone(),
+                attention_scores: parked.attention_scores.clone(),
+                slot,
+            }
+        };
+        let id = self.new_sequence_id();
+        self.parked_sequences.insert(id, sequence);
+        Ok(id)
+    }
+
+    /// Removes the sequence `id` from the session, freeing its memory. The active sequence
+    /// cannot be freed; switch to another one first.
+    pub fn free_sequence(&mut self, id: SequenceId) -> Result<(), SequenceError> {
+        if id == self.sequence {
+            return Err(SequenceError::ActiveSequence);
+        }
+        self.parked_sequences
+            .remove(&id)
+            .map(drop)
+            .ok_or(SequenceError::UnknownSequence(id))
+    }
+
+    /// Makes `id` the sequence that the session feeds and infers with, setting the active
+    /// one aside. Does nothing if `id` is already active.
+    ///
+    /// The key/value memories of the two sequences are swapped rather than copied, so
+    /// switching takes the same short time whatever the number of tokens they hold. The
+    /// first switch to an empty sequence allocates its key/value memory, which fails with
+    /// [SequenceError::MemoryLimitExceeded] if that would exceed the memory limit.
+    pub fn switch_sequence(
+        &mut self,
+        _model: &dyn Model,
+        id: SequenceId,
+    ) -> Result<(), SequenceError> {
+        if id == self.sequence {
+            return Ok(());
+        }
+        let new_slot = match self.parked_sequences.get(&id) {
+            None => return Err(SequenceError::UnknownSequence(id)),
+            Some(sequence) if sequence.slot.is_none() => {
+                self.check_sequences_supported()?;
+                Some(self.new_kv_slot()?)
+            }
+            Some(_) => {
+                self.check_sequences_supported()?;
+                None
+            }
+        };
+        let mut sequence = self.parked_sequences.remove(&id).unwrap();
+        let slot = sequence.slot.take().or(new_slot).unwrap();
+
+        let slot = KvSlot {
+            context: std::mem::replace(&mut self._session_ctx, slot.context),
+            memory_k: std::mem::replace(&mut self.memory_k, slot.memory_k),
+            memory_v: std::mem::replace(&mut self.memory_v, slot.memory_v),
+            _reservation: slot._reservation,
+        };
+        let parked = ParkedSequence {
+            tokens: std::mem::replace(&mut self.tokens, sequence.tokens),
+            decoded_tokens: std::mem::replace(&mut self.decoded_tokens, sequence.decoded_tokens),
+            n_past: std::mem::replace(&mut self.n_past, sequence.n_past),
+            n_evicted: std::mem::replace(&mut self.n_evicted, sequence.n_evicted),
+            speculated: std::mem::replace(&mut self.speculated, sequence.speculated),
+            logits_stale: std::mem::replace(&mut self.logits_stale, sequence.logits_stale),
+            last_logits: std::mem::replace(&mut self.last_logits, sequence.last_logits),
+            sampler_state: std::mem::replace(&mut self.sampler_state, sequence.sampler_state),
+            attention_scores: std::mem::replace(
+                &mut self.attention_scores,
+                sequence.attention_scores,
+            ),
+            slot: Some(slot),
+        };
+        self.parked_sequences.insert(self.sequence, parked);
+        self.sequence = id;
+        self.layer_outputs.clear();
+        self.attention_weights.clear();
+        Ok(())
+    }
+
+    fn new_sequence_id(&mut self) -> SequenceId {
+        let id = SequenceId(self.next_sequence);
+        self.next_sequence += 1;
+        id
+    }
+
+    /// Fails if the key/value memory of the session cannot be set aside for another
+    /// sequence. Restores it first if it was [spilled](Self::spill).
+    fn check_sequences_supported(&mut self) -> Result<(), SequenceError> {
+        self.restore()?;
+        #[cfg(feature = "metal")]
+        if self.metal_context.is_some() {
+            return Err(SequenceError::Unsupported);
+        }
+        Ok(())
+    }
+
+    /// Allocates a key/value memory for a sequence, like the one of the session.
+    fn new_kv_slot(&self) -> Result<KvSlot, MemoryLimitExceeded> {
+        let reservation = memory::reserve(&[(MemoryKind::KvCache, self._memory_size)])?;
+        let context = Arc::new(ggml::Context::init(self._memory_size, true));
+        let n_elements = self.memory_k.nelements();
+        let memory_k = context.new_tensor_1d(self.config.memory_k_type.into(), n_elements);
+        let memory_v = context.new_tensor_1d(self.config.memory_v_type.into(), n_elements);
+        ggml::set_name(&memory_k, "memory_k");
+        ggml::set_name(&memory_v, "memory_v");
+        Ok(KvSlot {
+            context,
+            memory_k,
+            memory_v,
+            _reservation: reservation,
+        })
+    }
+
+    /// Allocates a key/value memory for a sequence, holding a copy of the first `n_past`
+    /// positions of `memory`, the key and value memories of another sequence.
+    fn copy_kv_slot(
+        &self,
+        model: &dyn Model,
+        memory: [&Tensor; 2],
+        n_past: usize,
+    ) -> Result<KvSlot, MemoryLimitExceeded> {
+        let mut slot = self.new_kv_slot()?;
+        let ranges = self.kv_entry_ranges(model, n_past);
+        for ((from, to), ranges) in memory
+            .into_iter()
+            .zip([&mut slot.memory_k, &mut slot.memory_v])
+            .zip(ranges)
+        {
+            // SAFETY: `to` was just created, and `from` is not written to while the session
+            // is borrowed.
+            let to = unsafe { memory_bytes(to) };
+            for range in ranges {
+                // SAFETY: the ranges are within both memories, which have the same size.
+                unsafe { from.read_data(range.start, &mut to[range]) };
+            }
+        }
+        Ok(slot)
+    }
+
+    /// The byte ranges of the key and value memories that hold the first `n_past`
+    /// positions, or the whole memories if the model does not report its
+    /// [layout](crate::KnownModel::kv_layout).
+    fn kv_entry_ranges(&self, model: &dyn Model, n_past: usize) -> [Vec<Range<usize>>; 2] {
+        if n_past == 0 {
+            return [vec![], vec![]];
+        }
+        let Some(layout) = model.kv_layout() else {
+            return [&self.memory_k, &self.memory_v]
+                .map(|memory| std::iter::once(0..memory.nbytes()).collect());
+        };
+        [
+            (&self.memory_k, false),
+            (&self.memory_v, layout.transposed_values),
+        ]
+        .map(|(memory, transposed)| {
+            let (rows, row_size, entry_size) = kv_rows(
+                layout,
+                transposed,
+                self.n_layer,
+                self.n_ctx,
+                memory.element_size(),
+            );
+            (0..rows)
+                .map(|row| row * row_size..row * row_size + n_past * entry_size)
+                .collect()
+        })
+    }
+
+    /// The number of tokens the session can hold: the context size of the model, or
+    /// [InferenceSessionConfig::context_size] if it is smaller.
+    pub fn context_size(&self) -> usize {
+        self.n_ctx
+    }
+
     /// All tokens generated by this inference session
     pub fn tokens(&self) -> &[TokenId] {
         self.tokens.as_ref()
@@ -629,6 +2143,153 @@ impl InferenceSession {
     pub fn decoded_tokens(&self) -> &[u8] {
         self.decoded_tokens.as_ref()
     }
+
+    /// The state that the sampler has kept since the start of this session.
+    pub fn sampler_state(&self) -> &SamplerState {
+        &self.sampler_state
+    }
+
+    /// Mutable access to the state of the sampler, e.g. to
+    /// [clear](SamplerState::clear) it before an unrelated request.
+    pub fn sampler_state_mut(&mut self) -> &mut SamplerState {
+        &mut self.sampler_state
+    }
+
+    /// Returns a handle to the parameters that [Self::infer] generates with, which the
+    /// callback of [Self::infer] can use to adjust them between tokens.
+    pub fn sampler_handle(&self) -> SamplerHandle {
+        self.sampler_handle.clone()
+    }
+
+    /// Adds a [LogitsProcessor], which is applied to the logits before every token that
+    /// this session samples, after the processors added before it and whatever the
+    /// sampler is.
+    pub fn add_logits_processor(&mut self, processor: impl LogitsProcessor + 'static) {
+        self.logits_processors.push(Box::new(processor));
+    }
+
+    /// Removes the [LogitsProcessor]s of this session.
+    pub fn clear_logits_processors(&mut self) {
+        self.logits_processors.clear();
+    }
+
+    /// Adds an [AttentionObserver], which is given the attention statistics of the model
+    /// after each evaluation of this session. Recording them slows evaluation down a little.
+    #[cfg(feature = "attention-stats")]
+    pub fn add_attention_observer(&mut self, observer: impl AttentionObserver + 'static) {
+        self.attention_observers.push(Box::new(observer));
+    }
+
+    /// Removes the [AttentionObserver]s of this session.
+    #[cfg(feature = "attention-stats")]
+    pub fn clear_attention_observers(&mut self) {
+        self.attention_observers.clear();
+    }
+
+    /// Whether the attention weights are recorded for [AttentionObserver]s.
+    fn observes_attention(&self) -> bool {
+        #[cfg(feature = "attention-stats")]
+        return !self.attention_observers.is_empty();
+        #[cfg(not(feature = "attention-stats"))]
+        false
+    }
+}
+
+/// Adjusts the logits predicted by the model before each token is sampled, independently
+/// of the [Sampler](crate::Sampler): for example, to forbid tokens, to embed a watermark,
+/// or to record the distributions for research. Added to a session with
+/// [InferenceSession::add_logits_processor].
+///
+/// It is implemented for closures taking the same arguments as [Self::process].
+pub trait LogitsProcessor: Send {
+    /// Adjusts `logits`, which has one entry per token of the vocabulary. `tokens` are the
+    /// tokens of the session so far, the prompt included.
+    ///
+    /// The changes only apply to the token being sampled: the session keeps the logits of
+    /// the model in [InferenceSession::last_logits].
+    fn process(&mut self, tokens: &[TokenId], logits: &mut [f32]);
+}
+impl<F: FnMut(&[TokenId], &mut [f32]) + Send> LogitsProcessor for F {
+    fn process(&mut self, tokens: &[TokenId], logits: &mut [f32]) {
+        self(tokens, logits)
+    }
+}
+
+/// A handle to the [InferenceParameters] that an [InferenceSession] generates with,
+/// obtained with [InferenceSession::sampler_handle].
+///
+/// [InferenceSession::infer] starts with the parameters of its request, and reads them from
+/// the handle again before each token, so the callback can change them while it generates:
+/// for example, to lower the temperature once the output enters a code block.
+///
+/// The handle holds the whole [InferenceParameters], whose
+/// [sampler](InferenceParameters::sampler) is a shared `Arc<dyn Sampler>` that cannot be
+/// modified in place: to change one of its settings, replace it with a sampler that has
+/// the new setting, as in [Self::update]. The [SamplerState] of the session is kept when
+/// the sampler is replaced.
+///
+/// Clones of the handle share the same parameters.
+#[derive(Clone, Debug, Default)]
+pub struct SamplerHandle(Arc<Mutex<InferenceParameters>>);
+impl SamplerHandle {
+    /// Returns the parameters that the next token will be generated with.
+    pub fn get(&self) -> InferenceParameters {
+        self.lock().clone()
+    }
+
+    /// Generates the following tokens with `parameters`.
+    pub fn set(&self, parameters: InferenceParameters) {
+        *self.lock() = parameters;
+    }
+
+    /// Adjusts the parameters that the following tokens will be generated with.
+    ///
+    /// The sampler is shared, so it is replaced rather than modified:
+    ///
+    /// ```
+    /// # use std::sync::Arc;
+    /// # let handle = llm_base::SamplerHandle::default();
+    /// handle.update(|parameters| {
+    ///     parameters.sampler = Arc::new(llm_base::samplers::TopPTopK {
+    ///         temperature: 0.2,
+    ///         ..Default::default()
+    ///     });
+    /// });
+    /// ```
+    pub fn update(&self, update: impl FnOnce(&mut InferenceParameters)) {
+        update(&mut self.lock());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, InferenceParameters> {
+        // The parameters are always valid, even if a thread panicked while holding the lock.
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Returns the number of rows of a key/value memory with `layout`, the size of a row and
+/// the size of the entry of each position in a row, all in bytes.
+fn kv_rows(
+    layout: KvLayout,
+    transposed: bool,
+    n_layer: usize,
+    n_ctx: usize,
+    element_size: usize,
+) -> (usize, usize, usize) {
+    // Each block is either `width` rows of `n_ctx` elements, one per position, or
+    // `n_ctx` rows of `width` elements.
+    let width = layout.width;
+    if transposed {
+        (n_layer * width, n_ctx * element_size, element_size)
+    } else {
+        (n_layer, n_ctx * width * element_size, width * element_size)
+    }
+}
+
+/// The memory of `tensor`, which must not be used elsewhere while the slice is alive.
+unsafe fn memory_bytes(tensor: &mut Tensor) -> &mut [u8] {
+    std::slice::from_raw_parts_mut(tensor.data() as *mut u8, tensor.nbytes())
 }
 
 fn get_newly_decoded_portion_huggingface(
@@ -664,6 +2325,72 @@ pub enum InferenceError {
     #[error("the user-specified callback returned an error")]
     /// The user-specified callback returned an error.
     UserCallback(Box<dyn std::error::Error + Send + Sync>),
+    #[error("the spilled session memory could not be restored")]
+    /// The session was [spilled](InferenceSession::spill), and its memory could not be
+    /// restored.
+    RestoreFailed(#[from] SpillError),
+    #[error("the model produced non-finite logits at step {step}{}", layer_hint_suffix(.layer_hint))]
+    /// The model produced logits that are NaN or infinite, which usually comes from a broken
+    /// quantization or a bug in the model's implementation.
+    ///
+    /// Set [InferenceSessionConfig::numerical_error_dump] to write the logits to a file for a
+    /// bug report, and [InferenceSessionConfig::capture_layer_outputs] to find the layer.
+    NumericalError {
+        /// The position in the sequence of the token whose logits are not finite.
+        step: usize,
+        /// The first layer whose output is not finite, if the layer outputs were
+        /// [captured](InferenceSessionConfig::capture_layer_outputs).
+        layer_hint: Option<usize>,
+    },
+    #[error("inference was cancelled")]
+    /// [InferenceRequest::cancellation_token] was cancelled. The tokens evaluated until then
+    /// stay in the session.
+    Cancelled,
+}
+fn layer_hint_suffix(layer_hint: &Option<usize>) -> String {
+    layer_hint.map_or(String::new(), |layer| {
+        format!(", starting at layer {layer}")
+    })
+}
+
+/// The result of [InferenceSession::choose].
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Choice {
+    /// The index of the most likely option.
+    pub index: usize,
+    /// The probability of each option, normalized so that they sum to one.
+    pub probabilities: Vec<f32>,
+    /// The log-likelihood of each option following the prompt.
+    pub log_likelihoods: Vec<f32>,
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered by [InferenceSession::choose].
+pub enum ChooseError {
+    /// No options were given.
+    #[error("at least one option is required")]
+    NoOptions,
+    /// An option tokenized to nothing, so it cannot be scored.
+    #[error("option {index} is empty")]
+    EmptyOption {
+        /// The index of the option.
+        index: usize,
+    },
+    /// The session is empty once the prompt is fed, so there is nothing to predict the
+    /// first token of an option from.
+    #[error("the prompt is empty, so the options cannot be scored")]
+    EmptyPrompt,
+    /// The model architecture cannot discard the options after scoring them.
+    #[error("model architecture does not support rewinding, which is required to score options")]
+    UnsupportedArchitecture,
+    /// Inference failed.
+    #[error("inference failed")]
+    Inference(#[from] InferenceError),
+}
+impl From<TokenizationError> for ChooseError {
+    fn from(e: TokenizationError) -> Self {
+        Self::Inference(e.into())
+    }
 }
 
 #[derive(Error, Debug)]
@@ -678,6 +2405,22 @@ pub enum RewindError {
     UnsupportedArchitecture,
 }
 
+#[derive(Error, Debug)]
+/// Errors encountered by [InferenceSession::speculate].
+pub enum SpeculationError {
+    /// The model cannot rewind a wrong guess.
+    #[error("model architecture does not support rewinding a speculative prompt")]
+    UnsupportedArchitecture,
+    /// The session evicts key/value entries, with [InferenceSessionConfig::kv_eviction] or
+    /// [InferenceSessionConfig::context_overflow], which would mix the guess with the
+    /// tokens before it.
+    #[error("speculative prompts are not supported with key/value eviction")]
+    Eviction,
+    /// Feeding the guess failed.
+    #[error("feeding the speculative prompt failed")]
+    Inference(#[from] InferenceError),
+}
+
 #[derive(Error, Debug)]
 /// Errors encountered during the snapshot process.
 pub enum SnapshotError {
@@ -692,6 +2435,71 @@ pub enum SnapshotError {
         /// The size of the session memory in snapshot.
         input_size: usize,
     },
+    /// Starting the session would exceed the [memory limit](crate::memory::set_limit).
+    #[error("starting the session would exceed the memory limit")]
+    MemoryLimitExceeded(#[from] MemoryLimitExceeded),
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while managing the [sequences](InferenceSession::sequence) of a
+/// session.
+pub enum SequenceError {
+    /// The session has no sequence with this identifier.
+    #[error("the session has no sequence {0}")]
+    UnknownSequence(SequenceId),
+    /// The active sequence cannot be freed.
+    #[error("the active sequence cannot be freed")]
+    ActiveSequence,
+    /// Allocating the key/value memory of a sequence would exceed the
+    /// [memory limit](crate::memory::set_limit).
+    #[error("the key/value memory of the sequence would exceed the memory limit")]
+    MemoryLimitExceeded(#[from] MemoryLimitExceeded),
+    /// The session was [spilled](InferenceSession::spill) and could not be restored.
+    #[error("the spilled session could not be restored")]
+    Spill(#[from] SpillError),
+    /// The session memory is used by the GPU, so it cannot be swapped.
+    #[error("the sequences of a session that uses the GPU cannot be switched")]
+    Unsupported,
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered by [InferenceSession::spill] and [InferenceSession::restore].
+pub enum SpillError {
+    /// Writing or reading the file failed.
+    #[error("I/O error while spilling or restoring the session memory")]
+    Io(#[from] std::io::Error),
+    /// Restoring the memory would exceed the [memory limit](crate::memory::set_limit).
+    #[error("restoring the session memory would exceed the memory limit")]
+    MemoryLimitExceeded(#[from] MemoryLimitExceeded),
+    /// The session memory is used by the GPU, so it cannot be spilled.
+    #[error("the memory of a session that uses the GPU cannot be spilled")]
+    Unsupported,
+}
+
+/// The size in bytes of the key/value memory of a session with `config` and a context
+/// size of `n_ctx`, for a model with `n_layer` layers of `n_embd` elements.
+pub(crate) fn kv_cache_size(
+    config: &InferenceSessionConfig,
+    n_ctx: usize,
+    n_layer: usize,
+    n_embd: usize,
+) -> usize {
+    let mut ctx_size = 0;
+    ctx_size += mulf!(
+        n_ctx,
+        n_layer,
+        n_embd,
+        ggml::type_sizef(config.memory_k_type.into())
+    ); // memory_k
+    ctx_size += mulf!(
+        n_ctx,
+        n_layer,
+        n_embd,
+        ggml::type_sizef(config.memory_v_type.into())
+    ); // memory_v
+    ctx_size += (5 + 10 * n_layer) * 256; // object overhead
+
+    ctx_size
 }
 
 #[derive(serde::Serialize, Clone, PartialEq)]
@@ -701,10 +2509,15 @@ pub enum SnapshotError {
 /// If serializing, ensure that your serializer is binary-efficient.
 /// This type contains a large array of bytes; traditional textual serializers
 /// are likely to serialize this as an array of numbers at extreme cost.
-// Keep in sync with [InferenceSession] and [InferenceSnapshot].
+// Keep in sync with [InferenceSession] and [InferenceSnapshot], and increment
+// [InferenceSnapshot::FORMAT_VERSION] when the fields change.
 pub struct InferenceSnapshotRef<'a> {
     /// How many tokens have been stored in the memory so far.
     pub npast: usize,
+    /// How many tokens have been evicted from the memory by
+    /// [InferenceSessionConfig::kv_eviction].
+    #[serde(default)]
+    pub n_evicted: usize,
     /// Parameters associated with the saved inference session.
     pub config: InferenceSessionConfig,
     /// All tokens generated by this inference session.
@@ -717,6 +2530,11 @@ pub struct InferenceSnapshotRef<'a> {
     /// The contents of the 'value' memory tensor.
     #[serde(with = "serde_bytes")]
     pub memory_v: &'a [u8],
+    /// The state kept by the sampler between tokens.
+    pub sampler_state: SamplerState,
+    /// The state of the random number generator used for sampling, if the caller set it.
+    /// [InferenceSession::get_snapshot] leaves this empty, as the session does not own it.
+    pub rng: Option<RngState>,
 }
 impl InferenceSnapshotRef<'_> {
     /// Creates an owned [InferenceSnapshot] from this [InferenceSnapshotRef].
@@ -725,11 +2543,14 @@ impl InferenceSnapshotRef<'_> {
     pub fn to_owned(&self) -> InferenceSnapshot {
         InferenceSnapshot {
             npast: self.npast,
-            config: self.config,
+            n_evicted: self.n_evicted,
+            config: self.config.clone(),
             tokens: self.tokens.clone(),
             last_logits: self.logits.clone(),
             memory_k: self.memory_k.to_vec(),
             memory_v: self.memory_v.to_vec(),
+            sampler_state: self.sampler_state.clone(),
+            rng: self.rng.clone(),
         }
     }
 }
@@ -737,10 +2558,15 @@ impl InferenceSnapshotRef<'_> {
 /// A serializable snapshot of the inference process. Can be restored by calling
 /// [InferenceSession::from_snapshot].
 #[derive(serde::Deserialize, Clone, PartialEq)]
-// Keep in sync with [InferenceSession] and [InferenceSnapshotRef].
+// Keep in sync with [InferenceSession] and [InferenceSnapshotRef], and increment
+// [InferenceSnapshot::FORMAT_VERSION] when the fields change.
 pub struct InferenceSnapshot {
     /// How many tokens have been stored in the memory so far.
     pub npast: usize,
+    /// How many tokens have been evicted from the memory by
+    /// [InferenceSessionConfig::kv_eviction].
+    #[serde(default)]
+    pub n_evicted: usize,
     /// Parameters associated with the saved inference session.
     pub config: InferenceSessionConfig,
     /// All tokens generated by this inference session.
@@ -753,9 +2579,57 @@ pub struct InferenceSnapshot {
     /// The contents of the 'value' memory tensor.
     #[serde(with = "serde_bytes")]
     pub memory_v: Vec<u8>,
+    /// The state kept by the sampler between tokens.
+    pub sampler_state: SamplerState,
+    /// The state of the random number generator used for sampling, if it was saved.
+    ///
+    /// [InferenceSession::from_snapshot] does not use this: take it out beforehand and
+    /// continue sampling with [RngState::to_rng] to reproduce an uninterrupted run.
+    pub rng: Option<RngState>,
+}
+impl InferenceSnapshot {
+    /// The version of the layout of [InferenceSnapshot] and [InferenceSnapshotRef],
+    /// including [InferenceSessionConfig], [SamplerState] and [RngState]. It is incremented
+    /// whenever their fields change, which breaks compact formats such as bincode.
+    ///
+    /// Applications that store snapshots should store it with them, so that they can tell
+    /// snapshots of other versions apart instead of misreading them. Version 0 is the
+    /// layout of `llm` 0.1, which had no version.
+    pub const FORMAT_VERSION: u32 = 2;
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+/// The state of the random number generator used for sampling, which can be saved in an
+/// [InferenceSnapshot] so that a restored session samples the same tokens as an
+/// uninterrupted one.
+///
+/// Only [ChaCha12Rng] can be captured. It is the algorithm behind `rand::rngs::StdRng`, and
+/// produces the same numbers for the same seed.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RngState {
+    seed: [u8; 32],
+    stream: u64,
+    word_pos: u128,
+}
+impl RngState {
+    /// Creates a random number generator that continues from this state.
+    pub fn to_rng(&self) -> ChaCha12Rng {
+        let mut rng = ChaCha12Rng::from_seed(self.seed);
+        rng.set_stream(self.stream);
+        rng.set_word_pos(self.word_pos);
+        rng
+    }
+}
+impl From<&ChaCha12Rng> for RngState {
+    fn from(rng: &ChaCha12Rng) -> Self {
+        Self {
+            seed: rng.get_seed(),
+            stream: rng.get_stream(),
+            word_pos: rng.get_word_pos(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 /// Configuration for an inference session.
 ///
 /// This is specified at the time of creation of an [InferenceSession],
@@ -769,6 +2643,67 @@ pub struct InferenceSessionConfig {
 
     /// Whether to use GPU acceleration
     pub use_gpu: bool,
+
+    /// The number of tokens the session can hold. If `None`, or larger than the context
+    /// size the model was loaded with ([ModelParameters::context_size](crate::ModelParameters::context_size)), the context size
+    /// of the model is used.
+    ///
+    /// The memory of the session grows with its context size, so a model loaded with a
+    /// large context size can serve short sessions without reserving memory for the
+    /// longest ones.
+    #[serde(default)]
+    pub context_size: Option<usize>,
+
+    /// If set, the computation graph of the first forward pass of the session (usually the
+    /// prompt) is written to a file before it is computed, for debugging model implementations.
+    ///
+    /// This is not saved in snapshots. Requires the `graph-dump` feature.
+    #[cfg(feature = "graph-dump")]
+    #[serde(skip)]
+    pub dump_graph: Option<GraphDump>,
+
+    /// Whether to keep the output of every layer of the model, so that it can be read with
+    /// [InferenceSession::layer_outputs] after each evaluation. This costs memory and time,
+    /// and is meant for comparing model implementations against a reference. It is not
+    /// supported when the graph is computed on the GPU.
+    ///
+    /// This is not saved in snapshots.
+    #[serde(skip)]
+    pub capture_layer_outputs: bool,
+
+    /// If set, the logits are written to this file, one per line, when they are not all
+    /// finite, for a bug report. See [InferenceError::NumericalError].
+    ///
+    /// This is not saved in snapshots.
+    #[serde(skip)]
+    pub numerical_error_dump: Option<PathBuf>,
+
+    /// If set, entries are evicted from the key/value memory when it is full, so that
+    /// generation can go on past the context size instead of failing with
+    /// [InferenceError::ContextFull]. See [KvEviction].
+    ///
+    /// This is ignored by models that do not report their
+    /// [memory layout](crate::KnownModel::kv_layout).
+    #[serde(default)]
+    pub kv_eviction: Option<KvEviction>,
+
+    /// What to do when the key/value memory is full, e.g. to let a chat go on past the
+    /// context size. See [ContextOverflowPolicy]. [Self::kv_eviction] takes precedence
+    /// when both are set.
+    ///
+    /// This is ignored by models that do not report their
+    /// [memory layout](crate::KnownModel::kv_layout).
+    #[serde(default)]
+    pub context_overflow: ContextOverflowPolicy,
+
+    /// Whether to keep the beginning-of-sentence tokens at the start of a prompt that
+    /// duplicate the one before them, e.g. when a prompt with its own BOS token is fed at
+    /// the start of the session, which adds one. By default, they are removed, as a double
+    /// BOS hurts the output of some models, and
+    /// [Diagnostic::DuplicateBosRemoved](crate::diagnostics::Diagnostic::DuplicateBosRemoved)
+    /// is reported to the diagnostics of the model.
+    #[serde(default)]
+    pub allow_duplicate_bos: bool,
 }
 impl Default for InferenceSessionConfig {
     fn default() -> Self {
@@ -776,10 +2711,118 @@ impl Default for InferenceSessionConfig {
             memory_k_type: ModelKVMemoryType::Float16,
             memory_v_type: ModelKVMemoryType::Float16,
             use_gpu: false,
+            context_size: None,
+            #[cfg(feature = "graph-dump")]
+            dump_graph: None,
+            capture_layer_outputs: false,
+            numerical_error_dump: None,
+            kv_eviction: None,
+            context_overflow: ContextOverflowPolicy::Fail,
+            allow_duplicate_bos: false,
         }
     }
 }
 
+/// A policy for evicting entries from the key/value memory of a session when it is full,
+/// which approximates the heavy-hitter oracle (H2O): most of the attention goes to a few
+/// tokens, so keeping those and the most recent ones bounds the memory with little loss of
+/// quality.
+///
+/// When the memory is full, it is compacted to the `recent` most recent tokens and the
+/// `heavy_hitters` older tokens that have received the most attention. The attention is
+/// summed over heads and layers, for each generated token and for the last token of each
+/// prompt batch. Evicted tokens are also removed from [InferenceSession::tokens].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct KvEviction {
+    /// The number of most recent tokens that are always kept.
+    pub recent: usize,
+    /// The number of older tokens that are kept for the attention they received.
+    pub heavy_hitters: usize,
+}
+
+/// What an [InferenceSession] does when its key/value memory is full. See
+/// [InferenceSessionConfig::context_overflow].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ContextOverflowPolicy {
+    /// Fail with [InferenceError::ContextFull].
+    #[default]
+    Fail,
+    /// Drop the oldest half of the tokens after the first `keep_first_n`, e.g. to keep a
+    /// system prompt, by shifting the entries after them to the front of the memory, and
+    /// go on. Like [InferenceSessionConfig::kv_eviction], the dropped tokens are also
+    /// removed from [InferenceSession::tokens].
+    Shift {
+        /// The number of tokens at the start of the session that are never dropped.
+        keep_first_n: usize,
+    },
+}
+
+/// Identifies one of the independent [sequences](InferenceSession::sequence) of an
+/// [InferenceSession].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SequenceId(u32);
+impl Display for SequenceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How a model lays out its key/value memory, so that [InferenceSessionConfig::kv_eviction]
+/// and [InferenceSessionConfig::context_overflow] can move its entries.
+///
+/// Both memories hold `n_layer` blocks, one per layer, of `n_ctx` positions of `width`
+/// elements each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KvLayout {
+    /// The number of elements stored for each position and layer, in each memory.
+    pub width: usize,
+    /// Whether the value memory is transposed, with each block stored as `width` rows of
+    /// `n_ctx` positions.
+    pub transposed_values: bool,
+}
+
+/// Removes the `bos` tokens at the start of `tokens` that duplicate the one before them,
+/// which is `previous` for the first, returning how many were removed.
+fn remove_duplicate_bos(
+    tokens: &mut Vec<TokenId>,
+    previous: Option<TokenId>,
+    bos: TokenId,
+) -> usize {
+    let leading = tokens.iter().take_while(|&&token| token == bos).count();
+    let removed = if previous == Some(bos) {
+        leading
+    } else {
+        leading.saturating_sub(1)
+    };
+    tokens.drain(..removed);
+    removed
+}
+
+/// Returns the positions of the memory that `eviction` keeps, in increasing order, given the
+/// attention score of each position.
+fn positions_to_keep(scores: &[f32], eviction: KvEviction) -> Vec<usize> {
+    let recent_start = scores.len().saturating_sub(eviction.recent);
+    let mut keep: Vec<usize> = (0..recent_start).collect();
+    // The highest scores first, and the earliest positions among equal scores.
+    keep.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]).then(a.cmp(&b)));
+    keep.truncate(eviction.heavy_hitters);
+    keep.sort_unstable();
+    keep.extend(recent_start..scores.len());
+    keep
+}
+
+/// Returns the positions of a memory of `n_past` entries that
+/// [ContextOverflowPolicy::Shift] keeps, so that fewer than `limit` remain: the first
+/// `keep_first_n`, and the newest of the others after dropping at least half of them.
+fn positions_to_shift(n_past: usize, keep_first_n: usize, limit: usize) -> Vec<usize> {
+    let keep_first_n = keep_first_n.min(n_past);
+    let rest = n_past - keep_first_n;
+    let discard = (rest / 2).max((n_past + 1).saturating_sub(limit)).min(rest);
+    (0..keep_first_n)
+        .chain(keep_first_n + discard..n_past)
+        .collect()
+}
+
 #[derive(Debug, Clone, Copy)]
 /// Settings specific to [InferenceSession::infer].
 pub struct InferenceRequest<'a> {
@@ -795,6 +2838,251 @@ pub struct InferenceRequest<'a> {
     pub play_back_previous_tokens: bool,
     /// The maximum number of tokens to generate.
     pub maximum_token_count: Option<usize>,
+    /// The maximum number of bytes of text to generate.
+    ///
+    /// The text is cut at a character boundary, so the output may be slightly shorter. The
+    /// token that crossed the limit is still part of the session, even if it was cut.
+    pub maximum_output_bytes: Option<usize>,
+    /// The maximum number of characters (Unicode scalar values) of text to generate. See
+    /// [Self::maximum_output_bytes].
+    pub maximum_output_chars: Option<usize>,
+    /// Sequences of token IDs that stop generation when the model generates one of them,
+    /// e.g. the tokens of a multi-token end marker like `<|im_end|>` in some vocabularies.
+    ///
+    /// They are matched against the generated tokens, not their text, so they are
+    /// independent of how the tokens decode. Tokens that could start a sequence are held
+    /// back until it is clear whether they do, and the tokens of a matched sequence are not
+    /// passed to the callback.
+    pub stop_token_sequences: &'a [Vec<TokenId>],
+    /// Policies enforced on the generated text before it is passed to the callback, in
+    /// order. They can redact it or halt generation, and may hold text back until they can
+    /// tell whether to act on it. See [crate::guardrail].
+    pub guardrails: &'a [&'a dyn Guardrail],
+    /// Text that the response is forced to start with, e.g. `"Answer: "` to steer the
+    /// format of the answer. It is fed to the model after the prompt and passed to the
+    /// callback as generated text, then generation continues from it.
+    ///
+    /// With a text prompt, the prompt and the prefix are tokenized together, so the tokens
+    /// at the boundary are the same as if the prefix was part of the prompt.
+    pub forced_prefix: Option<&'a str>,
+    /// If set, the callback receives the logprob of each generated token, along with this
+    /// many of the most likely tokens and their logprobs, as
+    /// [InferenceResponse::Logprobs] before the text of the token.
+    pub logprobs: Option<usize>,
+    /// Conditions that stop generation early, based on the distribution the model predicts
+    /// for each token. See [EarlyStop].
+    pub early_stop: EarlyStop,
+    /// If set, inference fails with [InferenceError::Cancelled] once the token is
+    /// cancelled, e.g. from another thread when the client of a server disconnects. It is
+    /// checked before each generated token and each batch of the prompt, without waiting
+    /// for the callback.
+    pub cancellation_token: Option<&'a CancellationToken>,
+    /// If set, generation stops with [StopReason::Deadline] once this instant has passed,
+    /// for services with a latency target, as the time per token varies with the hardware.
+    /// The text generated until then is output as usual, and [InferenceStats] describes it.
+    ///
+    /// It is checked before each generated token and each batch of the prompt. If the
+    /// prompt could not be fed in time, the batches that were fed stay in the session and
+    /// nothing is generated.
+    pub deadline: Option<Instant>,
+}
+impl Default for InferenceRequest<'_> {
+    /// An empty prompt with the [default parameters](InferenceParameters::default), and
+    /// no limits, stop sequences or guardrails. Set the fields you need and use
+    /// `..Default::default()` for the others.
+    fn default() -> Self {
+        static PARAMETERS: Lazy<InferenceParameters> = Lazy::new(Default::default);
+        Self {
+            prompt: Prompt::default(),
+            parameters: &PARAMETERS,
+            play_back_previous_tokens: false,
+            maximum_token_count: None,
+            maximum_output_bytes: None,
+            maximum_output_chars: None,
+            stop_token_sequences: &[],
+            guardrails: &[],
+            forced_prefix: None,
+            logprobs: None,
+            early_stop: EarlyStop::default(),
+            cancellation_token: None,
+            deadline: None,
+        }
+    }
+}
+
+/// What interrupts [InferenceSession::feed_prompt_inner].
+#[derive(Clone, Copy)]
+struct FeedLimits<'a> {
+    /// Fails with [InferenceError::Cancelled] once cancelled.
+    cancellation_token: Option<&'a CancellationToken>,
+    /// Stops feeding once passed.
+    deadline: Option<Instant>,
+}
+
+/// Conditions on the distribution of the next token that stop
+/// [InferenceSession::infer] early, to get shorter outputs without a hard limit on the
+/// number of tokens. They are checked on the distribution of the model, before the
+/// sampler is applied.
+///
+/// The [default](Default) never stops early.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EarlyStop {
+    /// Stop with [StopReason::EndOfTextProbability] when the probability of the
+    /// [end-of-text tokens](Model::stop_token_ids) is above this, even if one would not
+    /// have been sampled.
+    pub eot_probability: Option<f32>,
+    /// Stop with [StopReason::LowEntropy] after this many consecutive tokens were generated
+    /// from distributions whose entropy is below [Self::low_entropy_threshold], e.g. when
+    /// the model is stuck repeating itself.
+    pub low_entropy_steps: Option<usize>,
+    /// The entropy, in nats, below which a distribution counts towards
+    /// [Self::low_entropy_steps].
+    pub low_entropy_threshold: f32,
+}
+impl EarlyStop {
+    /// Returns the probability of `stop_tokens` and the entropy, in nats, of the
+    /// distribution of `logits`.
+    fn statistics(logits: &[f32], stop_tokens: &[TokenId]) -> (f32, f32) {
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum = logits.iter().map(|logit| (logit - max).exp()).sum::<f32>();
+        let log_sum = max + sum.ln();
+        let entropy = -logits
+            .iter()
+            .map(|logit| logit - log_sum)
+            .filter(|logprob| logprob.is_finite())
+            .map(|logprob| logprob.exp() * logprob)
+            .sum::<f32>();
+        let eot_probability = stop_tokens
+            .iter()
+            .filter_map(|&id| logits.get(id as usize))
+            .map(|logit| (logit - log_sum).exp())
+            .sum();
+        (eot_probability, entropy)
+    }
+}
+
+/// Matches the tokens generated by [InferenceSession::infer] against
+/// [InferenceRequest::stop_token_sequences].
+struct StopTokenMatcher<'a> {
+    sequences: &'a [Vec<TokenId>],
+    /// The tokens that may be the start of a sequence, with their bytes.
+    held: Vec<(TokenId, Vec<u8>)>,
+}
+impl<'a> StopTokenMatcher<'a> {
+    fn new(sequences: &'a [Vec<TokenId>]) -> Self {
+        Self {
+            sequences,
+            held: vec![],
+        }
+    }
+
+    /// Adds a generated token. Returns the bytes of the tokens that can no longer be part
+    /// of a sequence, in order, and whether a sequence was generated.
+    fn push(&mut self, token: TokenId, bytes: Vec<u8>) -> (Vec<Vec<u8>>, bool) {
+        self.held.push((token, bytes));
+        let ids: Vec<TokenId> = self.held.iter().map(|(id, _)| *id).collect();
+
+        let matched = self
+            .sequences
+            .iter()
+            .filter(|sequence| !sequence.is_empty() && ids.ends_with(sequence))
+            .map(Vec::len)
+            .max();
+        // Keep the longest run of tokens at the end that starts a sequence.
+        let keep = match matched {
+            Some(len) => len,
+            None => (1..=ids.len())
+                .rev()
+                .find(|&n| {
+                    let tail = &ids[ids.len() - n..];
+                    self.sequences
+                        .iter()
+                        .any(|sequence| sequence.len() > n && sequence.starts_with(tail))
+                })
+                .unwrap_or(0),
+        };
+
+        let released = self
+            .held
+            .drain(..ids.len() - keep)
+            .map(|(_, bytes)| bytes)
+            .collect();
+        if matched.is_some() {
+            self.held.clear();
+        }
+        (released, matched.is_some())
+    }
+
+    /// Returns the bytes of the tokens that are still held.
+    fn finish(&mut self) -> Vec<Vec<u8>> {
+        self.held.drain(..).map(|(_, bytes)| bytes).collect()
+    }
+}
+
+/// The remaining length of the output of [InferenceSession::infer].
+struct OutputBudget {
+    bytes: usize,
+    chars: usize,
+}
+impl OutputBudget {
+    /// Cuts `text` to what remains of the budget and deducts it. Returns whether the budget
+    /// is exhausted, in which case generation must stop.
+    fn take(&mut self, text: &mut String) -> bool {
+        let mut end = 0;
+        let mut chars = 0;
+        for c in text.chars() {
+            if end + c.len_utf8() > self.bytes || chars == self.chars {
+                break;
+            }
+            end += c.len_utf8();
+            chars += 1;
+        }
+        let exhausted = end < text.len() || end == self.bytes || chars == self.chars;
+        text.truncate(end);
+        self.bytes -= end;
+        self.chars -= chars;
+        exhausted
+    }
+}
+
+/// Why [InferenceSession::infer] stopped generating.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The model produced an end-of-text token, one of [Model::stop_token_ids].
+    EndOfText,
+    /// [InferenceRequest::maximum_token_count] tokens were generated.
+    MaximumTokens,
+    /// The output reached [InferenceRequest::maximum_output_bytes] or
+    /// [InferenceRequest::maximum_output_chars].
+    MaximumOutput,
+    /// The callback returned [InferenceFeedback::Halt].
+    Halted,
+    /// The model generated one of [InferenceRequest::stop_token_sequences].
+    StopTokens,
+    /// One of [InferenceRequest::guardrails] halted generation.
+    Guardrail,
+    /// The probability of an end-of-text token went above
+    /// [EarlyStop::eot_probability].
+    EndOfTextProbability,
+    /// [EarlyStop::low_entropy_steps] tokens in a row were generated with a low entropy.
+    LowEntropy,
+    /// [InferenceRequest::deadline] passed.
+    Deadline,
+}
+impl Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            StopReason::EndOfText => "end_of_text",
+            StopReason::MaximumTokens => "maximum_tokens",
+            StopReason::MaximumOutput => "maximum_output",
+            StopReason::Halted => "halted",
+            StopReason::StopTokens => "stop_tokens",
+            StopReason::Guardrail => "guardrail",
+            StopReason::EndOfTextProbability => "end_of_text_probability",
+            StopReason::LowEntropy => "low_entropy",
+            StopReason::Deadline => "deadline",
+        })
+    }
 }
 
 /// Statistics about the inference process.
@@ -802,12 +3090,17 @@ pub struct InferenceRequest<'a> {
 pub struct InferenceStats {
     /// How long it took to feed the prompt.
     pub feed_prompt_duration: std::time::Duration,
-    /// How many tokens the prompt was.
+    /// How many tokens the prompt was. If the [deadline](InferenceRequest::deadline)
+    /// passed while it was fed, only the tokens that were fed in time are counted.
     pub prompt_tokens: usize,
     /// How long it took to predict new tokens.
     pub predict_duration: std::time::Duration,
     /// The number of predicted tokens.
     pub predict_tokens: usize,
+    /// The resources used by the process during inference.
+    pub resource_usage: ResourceUsage,
+    /// Why generation stopped.
+    pub stop_reason: StopReason,
 }
 impl Default for InferenceStats {
     fn default() -> Self {
@@ -816,6 +3109,8 @@ impl Default for InferenceStats {
             prompt_tokens: 0,
             predict_duration: std::time::Duration::from_secs(0),
             predict_tokens: 0,
+            resource_usage: ResourceUsage::default(),
+            stop_reason: StopReason::EndOfText,
         }
     }
 }
@@ -826,6 +3121,8 @@ impl Display for InferenceStats {
             prompt_tokens,
             predict_duration,
             predict_tokens,
+            resource_usage,
+            stop_reason,
         } = *self;
 
         let feed_prompt_duration = feed_prompt_duration.as_millis();
@@ -840,7 +3137,39 @@ impl Display for InferenceStats {
         writeln!(f, "prompt_tokens: {}", prompt_tokens)?;
         writeln!(f, "predict_duration: {}ms", predict_duration)?;
         writeln!(f, "predict_tokens: {}", predict_tokens)?;
-        write!(f, "per_token_duration: {:.3}ms", per_token_duration)
+        writeln!(f, "per_token_duration: {:.3}ms", per_token_duration)?;
+        write!(f, "stop_reason: {stop_reason}")?;
+
+        let ResourceUsage {
+            peak_rss_bytes,
+            user_cpu_time,
+            system_cpu_time,
+            energy_joules,
+        } = resource_usage;
+        if let Some(peak_rss_bytes) = peak_rss_bytes {
+            write!(
+                f,
+                "\npeak_rss: {:.1}MiB",
+                peak_rss_bytes as f64 / (1024.0 * 1024.0)
+            )?;
+        }
+        if let Some(user_cpu_time) = user_cpu_time {
+            write!(f, "\nuser_cpu_time: {}ms", user_cpu_time.as_millis())?;
+        }
+        if let Some(system_cpu_time) = system_cpu_time {
+            write!(f, "\nsystem_cpu_time: {}ms", system_cpu_time.as_millis())?;
+        }
+        if let Some(energy_joules) = energy_joules {
+            write!(f, "\nenergy: {:.2}J", energy_joules)?;
+            if predict_tokens > 0 {
+                write!(
+                    f,
+                    "\nenergy_per_token: {:.3}J",
+                    energy_joules / predict_tokens as f64
+                )?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -872,6 +3201,52 @@ pub enum InferenceResponse {
     InferredToken(String),
     /// The inference session has generated an end-of-text token
     EotToken,
+    /// The logprobs of a generated token, sent before its text if
+    /// [InferenceRequest::logprobs] is set. The text can come later, or not at all, if it
+    /// is held back by stop sequences or guardrails.
+    Logprobs(TokenLogprobs),
+}
+
+/// The logprobs of a generated token and of the most likely alternatives, as sent in
+/// [InferenceResponse::Logprobs].
+///
+/// They are the natural logarithms of the probabilities predicted by the model, before the
+/// sampler (e.g. the temperature) and the [LogitsProcessor]s are applied.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenLogprobs {
+    /// The generated token.
+    pub token: TokenId,
+    /// The logprob of [Self::token].
+    pub logprob: f32,
+    /// The most likely tokens and their logprobs, most likely first. The generated token is
+    /// among them if it is likely enough.
+    pub top: Vec<(TokenId, f32)>,
+}
+impl TokenLogprobs {
+    /// Computes the logprobs of `token` and of the `top` most likely tokens from `logits`.
+    pub fn new(logits: &[f32], token: TokenId, top: usize) -> Self {
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let log_sum = max
+            + logits
+                .iter()
+                .map(|logit| (logit - max).exp())
+                .sum::<f32>()
+                .ln();
+
+        let mut ids: Vec<usize> = (0..logits.len()).collect();
+        let top = top.min(ids.len());
+        ids.partial_sort(top, |&a, &b| logits[b].total_cmp(&logits[a]));
+        Self {
+            token,
+            logprob: logits
+                .get(token as usize)
+                .map_or(f32::NEG_INFINITY, |logit| logit - log_sum),
+            top: ids[..top]
+                .iter()
+                .map(|&id| (id as TokenId, logits[id] - log_sum))
+                .collect(),
+        }
+    }
 }
 
 /// Feedback from a caller to [InferenceSession::infer], sent as the return
@@ -881,6 +3256,11 @@ pub enum InferenceFeedback {
     Continue,
     /// Halt inference
     Halt,
+    /// Wait for the given duration, then continue inference.
+    ///
+    /// This lets a consumer that is slower than generation, such as text-to-speech, pace it
+    /// instead of buffering the tokens it cannot keep up with.
+    Pause(std::time::Duration),
 }
 
 /// Adapt an [InferenceResponse] callback so that it can be used in a call to
@@ -931,3 +3311,839 @@ pub fn conversation_inference_callback<'a, E: std::error::Error + Send + Sync +
         _ => Ok(InferenceFeedback::Continue),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, time::Duration};
+
+    use super::*;
+    use crate::testing::MockModel;
+
+    #[test]
+    fn output_budget_cuts_at_character_boundaries() {
+        let mut budget = OutputBudget { bytes: 5, chars: 3 };
+        let mut text = "ab".to_string();
+        assert!(!budget.take(&mut text));
+        assert_eq!(text, "ab");
+
+        // "é" takes two bytes, which would go over the byte budget.
+        let mut text = "cé".to_string();
+        assert!(budget.take(&mut text));
+        assert_eq!(text, "c");
+
+        let mut budget = OutputBudget {
+            bytes: usize::MAX,
+            chars: 2,
+        };
+        let mut text = "éé".to_string();
+        assert!(budget.take(&mut text));
+        assert_eq!(text, "éé");
+    }
+
+    #[test]
+    fn stop_token_sequences_hold_back_their_prefixes() {
+        let sequences = [vec![1, 2, 3], vec![5]];
+        let mut matcher = StopTokenMatcher::new(&sequences);
+        let bytes = |b: &[u8]| b.to_vec();
+
+        assert_eq!(matcher.push(1, bytes(b"a")), (vec![], false));
+        assert_eq!(matcher.push(2, bytes(b"b")), (vec![], false));
+        // `1 2 1` cannot be completed, but the last `1` can still start a sequence.
+        assert_eq!(
+            matcher.push(1, bytes(b"c")),
+            (vec![bytes(b"a"), bytes(b"b")], false)
+        );
+        assert_eq!(
+            matcher.push(4, bytes(b"d")),
+            (vec![bytes(b"c"), bytes(b"d")], false)
+        );
+        assert_eq!(matcher.push(1, bytes(b"e")), (vec![], false));
+        assert_eq!(matcher.finish(), vec![bytes(b"e")]);
+
+        assert_eq!(matcher.push(1, bytes(b"f")), (vec![], false));
+        assert_eq!(matcher.push(2, bytes(b"g")), (vec![], false));
+        assert_eq!(matcher.push(3, bytes(b"h")), (vec![], true));
+        assert_eq!(matcher.push(6, bytes(b"i")), (vec![bytes(b"i")], false));
+        assert_eq!(matcher.push(5, bytes(b"j")), (vec![], true));
+    }
+
+    #[test]
+    fn early_stop_statistics_of_the_distribution() {
+        let ln4 = 4f32.ln();
+        let (eot_probability, entropy) = EarlyStop::statistics(&[0.0; 4], &[1, 3]);
+        assert!((eot_probability - 0.5).abs() < 1e-6);
+        assert!((entropy - ln4).abs() < 1e-6);
+
+        let (eot_probability, entropy) =
+            EarlyStop::statistics(&[0.0, f32::NEG_INFINITY, 0.0, 20.0], &[3]);
+        assert!(eot_probability > 0.99);
+        assert!(entropy < 0.01);
+    }
+
+    #[test]
+    fn logprobs_of_the_token_and_the_top_alternatives() {
+        let logits = [2f32.ln(), 1f32.ln(), 5f32.ln(), 2f32.ln()];
+        let logprobs = TokenLogprobs::new(&logits, 1, 2);
+        assert_eq!(logprobs.token, 1);
+        assert!((logprobs.logprob - 0.1f32.ln()).abs() < 1e-6);
+        assert_eq!(logprobs.top.len(), 2);
+        assert_eq!(logprobs.top[0].0, 2);
+        assert!((logprobs.top[0].1 - 0.5f32.ln()).abs() < 1e-6);
+        assert!((logprobs.top[1].1 - 0.2f32.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn duplicate_bos_tokens_are_removed() {
+        let mut tokens = vec![1, 1, 1, 5, 1];
+        assert_eq!(remove_duplicate_bos(&mut tokens, None, 1), 2);
+        assert_eq!(tokens, [1, 5, 1]);
+        assert_eq!(remove_duplicate_bos(&mut tokens, Some(7), 1), 0);
+        assert_eq!(remove_duplicate_bos(&mut tokens, Some(1), 1), 1);
+        assert_eq!(tokens, [5, 1]);
+    }
+
+    #[test]
+    fn eviction_keeps_recent_tokens_and_heavy_hitters() {
+        let scores = [5.0, 0.1, 2.0, 0.3, 2.0, 0.2, 9.0, 0.0];
+        let eviction = KvEviction {
+            recent: 2,
+            heavy_hitters: 3,
+        };
+        assert_eq!(positions_to_keep(&scores, eviction), [0, 2, 4, 6, 7]);
+
+        // Fewer tokens than are kept.
+        let eviction = KvEviction {
+            recent: 4,
+            heavy_hitters: 8,
+        };
+        assert_eq!(
+            positions_to_keep(&scores[..6], eviction),
+            [0, 1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn shift_keeps_the_first_tokens_and_the_newest_half() {
+        assert_eq!(positions_to_shift(10, 2, 10), [0, 1, 6, 7, 8, 9]);
+        // More is dropped to fit what comes next.
+        assert_eq!(positions_to_shift(10, 2, 5), [0, 1, 8, 9]);
+        // Fewer tokens than are kept.
+        assert_eq!(positions_to_shift(3, 4, 3), [0, 1, 2]);
+    }
+
+    #[test]
+    fn auto_context_size_fits_the_memory_budget() {
+        let config = InferenceSessionConfig::default();
+        let budget = kv_cache_size(&config, 1000, 32, 4096) + 1;
+        let context_size = crate::ContextSize::Auto {
+            max_memory_bytes: budget,
+        };
+        assert_eq!(context_size.tokens(32, 4096), 1000);
+
+        let context_size = crate::ContextSize::Auto {
+            max_memory_bytes: budget - 2,
+        };
+        assert_eq!(context_size.tokens(32, 4096), 999);
+        assert_eq!(crate::ContextSize::Fixed(12).tokens(32, 4096), 12);
+    }
+
+    /// A model that answers "Hello" with ", world", and a session that was fed "Hello" and
+    /// whose key/value memory holds a recognizable pattern.
+    fn spillable_session() -> (MockModel, InferenceSession) {
+        let model = MockModel::new(&["Hello", ",", " world"]).with_response(", world");
+        let mut session = model.start_session(Default::default());
+        session
+            .feed_prompt(
+                &model,
+                &Default::default(),
+                "Hello",
+                &mut Default::default(),
+                |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+            )
+            .unwrap();
+        for memory in [&mut session.memory_k, &mut session.memory_v] {
+            // SAFETY: nothing else uses the memory.
+            for (i, byte) in unsafe { memory_bytes(memory) }.iter_mut().enumerate() {
+                *byte = (i % 251) as u8;
+            }
+        }
+        (model, session)
+    }
+
+    fn spill_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("llm-spill-{name}-{}.bin", std::process::id()))
+    }
+
+    #[test]
+    fn spilled_sessions_continue_identically() {
+        let (model, mut session) = spillable_session();
+        // SAFETY: nothing else uses the memory.
+        let memory = unsafe {
+            [&mut session.memory_k, &mut session.memory_v]
+                .map(|memory| memory_bytes(memory).to_vec())
+        };
+
+        let path = spill_path("round-trip");
+        session.spill(&path).unwrap();
+        assert!(session.is_spilled());
+        assert!(path.exists());
+
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+        let token = session
+            .infer_next_token(
+                &model,
+                &Default::default(),
+                &mut Default::default(),
+                &mut rng,
+            )
+            .unwrap();
+        assert_eq!(token, b",");
+        assert!(!session.is_spilled());
+        assert!(!path.exists());
+        // SAFETY: nothing else uses the memory.
+        let restored = unsafe {
+            [&mut session.memory_k, &mut session.memory_v]
+                .map(|memory| memory_bytes(memory).to_vec())
+        };
+        assert_eq!(restored, memory);
+    }
+
+    #[test]
+    fn sequences_keep_their_own_memory() {
+        let (model, mut session) = spillable_session();
+        let first = session.sequence();
+        // SAFETY: nothing else uses the memory.
+        let memory = |session: &mut InferenceSession| unsafe {
+            [&mut session.memory_k, &mut session.memory_v]
+                .map(|memory| memory_bytes(memory).to_vec())
+        };
+        // SAFETY: the pointer is only compared.
+        let address = |session: &mut InferenceSession| unsafe { session.memory_k.data() };
+        let pattern = memory(&mut session);
+        let data = address(&mut session);
+
+        let forked = session.fork_sequence(&model, first).unwrap();
+        let second = session.create_sequence();
+        session.switch_sequence(&model, second).unwrap();
+        assert_ne!(address(&mut session), data);
+        for memory in [&mut session.memory_k, &mut session.memory_v] {
+            // SAFETY: nothing else uses the memory.
+            unsafe { memory_bytes(memory) }.fill(7);
+        }
+
+        // Switching swaps the memories instead of copying them.
+        session.switch_sequence(&model, first).unwrap();
+        assert_eq!(address(&mut session), data);
+        assert_eq!(memory(&mut session), pattern);
+
+        session.switch_sequence(&model, forked).unwrap();
+        assert_ne!(address(&mut session), data);
+        assert_eq!(memory(&mut session), pattern);
+        session.switch_sequence(&model, second).unwrap();
+        assert!(memory(&mut session).iter().flatten().all(|&byte| byte == 7));
+    }
+
+    #[test]
+    fn failed_restores_are_errors() {
+        let (model, mut session) = spillable_session();
+        let path = spill_path("failed");
+        session.spill(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+        let result = session.infer_next_token(
+            &model,
+            &Default::default(),
+            &mut Default::default(),
+            &mut rng,
+        );
+        assert!(matches!(
+            result,
+            Err(InferenceError::RestoreFailed(SpillError::Io(_)))
+        ));
+        // SAFETY: the snapshot is not taken.
+        assert!(matches!(
+            unsafe { session.get_snapshot() },
+            Err(SpillError::Io(_))
+        ));
+        assert!(session.is_spilled());
+    }
+
+    fn model() -> MockModel {
+        MockModel::new(&["Hello", ",", " world", "!", " "]).with_response(", world!")
+    }
+
+    /// Runs inference on `prompt`, returning the generated text and the result.
+    fn infer(
+        model: &MockModel,
+        session: &mut InferenceSession,
+        prompt: &str,
+        maximum_token_count: Option<usize>,
+    ) -> (String, Result<StopReason, InferenceError>) {
+        let mut output = String::new();
+        let result = session.infer::<Infallible>(
+            model,
+            &mut ChaCha12Rng::seed_from_u64(0),
+            &InferenceRequest {
+                prompt: prompt.into(),
+                maximum_token_count,
+                ..Default::default()
+            },
+            &mut Default::default(),
+            |response| {
+                if let InferenceResponse::InferredToken(token) = response {
+                    output.push_str(&token);
+                }
+                Ok(InferenceFeedback::Continue)
+            },
+        );
+        (output, result.map(|stats| stats.stop_reason))
+    }
+
+    /// Feeds `prompt`, returning the text of the tokens that were evaluated.
+    fn feed<'a>(
+        model: &MockModel,
+        session: &mut InferenceSession,
+        prompt: impl Into<Prompt<'a>>,
+    ) -> String {
+        let mut fed = vec![];
+        session
+            .feed_prompt::<Infallible, _>(
+                model,
+                &Default::default(),
+                prompt,
+                &mut Default::default(),
+                |token| {
+                    fed.extend_from_slice(token);
+                    Ok(InferenceFeedback::Continue)
+                },
+            )
+            .unwrap();
+        String::from_utf8(fed).unwrap()
+    }
+
+    #[test]
+    fn halting_mid_prompt_keeps_the_evaluated_tokens() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let mut reported = vec![];
+        session
+            .feed_prompt::<Infallible, _>(
+                &model,
+                &Default::default(),
+                "Hello, world",
+                &mut Default::default(),
+                |token| {
+                    reported.push(String::from_utf8(token.to_vec()).unwrap());
+                    Ok(match token {
+                        b"," => InferenceFeedback::Halt,
+                        _ => InferenceFeedback::Continue,
+                    })
+                },
+            )
+            .unwrap();
+        assert_eq!(reported, ["Hello", ","]);
+        // The batch was evaluated whole, so its tokens stay in the session.
+        assert_eq!(session.n_past, 4);
+        assert_eq!(session.tokens().len(), 4);
+
+        assert_eq!(feed(&model, &mut session, "!"), "!");
+        assert_eq!(session.tokens().len(), 5);
+        // The session ends with the whole response, so the model is done.
+        let (output, result) = infer(&model, &mut session, "", None);
+        assert_eq!(output, "");
+        assert_eq!(result.unwrap(), StopReason::EndOfText);
+    }
+
+    fn speculate(model: &MockModel, session: &mut InferenceSession, prompt: &str) {
+        session
+            .speculate(model, &Default::default(), prompt, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn non_finite_logits_are_dumped() {
+        let path = std::env::temp_dir().join(format!("llm-nan-{}.txt", std::process::id()));
+        let model = model().with_non_finite_logits_at(2);
+        let mut session = model.start_session(InferenceSessionConfig {
+            numerical_error_dump: Some(path.clone()),
+            ..Default::default()
+        });
+        let (_, result) = infer(&model, &mut session, "Hello", None);
+        assert!(matches!(
+            result,
+            Err(InferenceError::NumericalError { step: 2, .. })
+        ));
+
+        let dump = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(dump.starts_with("# step: 2\n"));
+        assert!(dump.lines().any(|line| line == "NaN"));
+    }
+
+    #[test]
+    fn truncating_continues_from_the_prompt() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let (output, _) = infer(&model, &mut session, "Hello", Some(2));
+        assert_eq!(output, ", world");
+
+        // The beginning of text and "Hello" are kept, and the response is generated again
+        // from the predictions for "Hello".
+        let removed = session.truncate_to(&model, 2).unwrap();
+        assert_eq!(removed.len(), 2);
+        assert_eq!(session.n_past, 2);
+        let (output, _) = infer(&model, &mut session, "", Some(2));
+        assert_eq!(output, ", world");
+
+        assert!(session.truncate_to(&model, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncating_to_nothing_clears_the_session() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let (output, _) = infer(&model, &mut session, "Hello", Some(2));
+        assert_eq!(output, ", world");
+
+        let removed = session.truncate_to(&model, 0).unwrap();
+        assert_eq!(removed.len(), 4);
+        assert_eq!(session.n_past, 0);
+        assert!(session.tokens().is_empty());
+        assert!(session.decoded_tokens.is_empty());
+        assert!(session.last_logits.iter().all(|&logit| logit == 0.0));
+
+        // The session continues like a new one.
+        let (output, _) = infer(&model, &mut session, "Hello", Some(2));
+        assert_eq!(output, ", world");
+        assert!(session.truncate_to(&model, 0).is_ok());
+        assert!(session.truncate_to(&model, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sequences_are_independent() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let first = session.sequence();
+        let (output, _) = infer(&model, &mut session, "Hello", Some(1));
+        assert_eq!(output, ",");
+
+        let forked = session.fork_sequence(&model, first).unwrap();
+        let second = session.create_sequence();
+        session.switch_sequence(&model, second).unwrap();
+        assert!(session.tokens().is_empty());
+        let (output, _) = infer(&model, &mut session, "Hello", Some(2));
+        assert_eq!(output, ", world");
+
+        // Both copies of the first sequence go on from where it was left.
+        for id in [first, forked] {
+            session.switch_sequence(&model, id).unwrap();
+            let (output, _) = infer(&model, &mut session, "", Some(1));
+            assert_eq!(output, " world");
+        }
+
+        assert_eq!(session.sequences(), vec![first, forked, second]);
+        assert!(matches!(
+            session.free_sequence(forked),
+            Err(SequenceError::ActiveSequence)
+        ));
+        session.free_sequence(second).unwrap();
+        assert!(matches!(
+            session.switch_sequence(&model, second),
+            Err(SequenceError::UnknownSequence(_))
+        ));
+    }
+
+    #[test]
+    fn speculation_that_matches_is_kept() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        speculate(&model, &mut session, "Hello");
+        assert_eq!(session.speculated_tokens(), 2);
+
+        assert_eq!(feed(&model, &mut session, "Hello"), "");
+        assert_eq!(session.speculated_tokens(), 0);
+        assert_eq!(session.n_past, 2);
+        let (output, _) = infer(&model, &mut session, "", None);
+        assert_eq!(output, ", world!");
+    }
+
+    #[test]
+    fn speculation_is_kept_up_to_the_first_difference() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        speculate(&model, &mut session, "Hello, world");
+        assert_eq!(feed(&model, &mut session, "Hello!"), "!");
+        assert_eq!(session.decoded_tokens(), b"<s>Hello!");
+        assert_eq!(session.n_past, 3);
+    }
+
+    #[test]
+    fn speculation_that_misses_is_rewound() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        speculate(&model, &mut session, "Hello");
+        // Only the beginning of text matches.
+        assert_eq!(feed(&model, &mut session, "!"), "!");
+        assert_eq!(session.decoded_tokens(), b"<s>!");
+        assert_eq!(session.n_past, 2);
+    }
+
+    #[test]
+    fn speculation_before_an_empty_prompt_is_discarded() {
+        let model = model();
+        let fresh = model.start_session(Default::default());
+        let mut session = model.start_session(Default::default());
+        speculate(&model, &mut session, "Hello");
+
+        // Not even the beginning of text is kept, so no token is evaluated again.
+        assert_eq!(feed(&model, &mut session, &[][..]), "");
+        assert_eq!(session.speculated_tokens(), 0);
+        assert!(session.tokens().is_empty());
+        // The predictions of the guess are not used for the next token.
+        assert_eq!(session.last_logits, fresh.last_logits);
+    }
+
+    #[test]
+    fn cancelled_speculation_keeps_the_earlier_guess() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        speculate(&model, &mut session, "Hello");
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = session.speculate(&model, &Default::default(), ",", Some(&token));
+        assert!(matches!(
+            result,
+            Err(SpeculationError::Inference(InferenceError::Cancelled))
+        ));
+        assert_eq!(session.speculated_tokens(), 2);
+
+        assert_eq!(feed(&model, &mut session, "Hello,"), ",");
+        assert_eq!(session.n_past, 3);
+    }
+
+    #[test]
+    fn choose_scores_each_option_after_the_prompt() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let options = [", world!", "!", " world"];
+        let choice = session
+            .choose(&model, &Default::default(), "Hello", &options)
+            .unwrap();
+        assert_eq!(choice.index, 0);
+        // Only the option that follows the scripted response is likely.
+        assert!(choice.log_likelihoods[0] > -1.0);
+        assert!(choice.log_likelihoods[1] < -50.0);
+        assert!(choice.log_likelihoods[2] < -50.0);
+        assert_eq!(session.decoded_tokens(), b"<s>Hello");
+        assert_eq!(session.n_past, 2);
+
+        // The options do not affect each other.
+        let mut reversed = options;
+        reversed.reverse();
+        let mut session = model.start_session(Default::default());
+        let choice_reversed = session
+            .choose(&model, &Default::default(), "Hello", &reversed)
+            .unwrap();
+        let mut log_likelihoods = choice_reversed.log_likelihoods;
+        log_likelihoods.reverse();
+        assert_eq!(log_likelihoods, choice.log_likelihoods);
+
+        // The session goes on from the prompt.
+        let (output, _) = infer(&model, &mut session, "", None);
+        assert_eq!(output, ", world!");
+    }
+
+    #[test]
+    fn choose_needs_a_prompt() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let result = session.choose(&model, &Default::default(), &[][..], &["Hello"]);
+        assert!(matches!(result, Err(ChooseError::EmptyPrompt)));
+    }
+
+    #[test]
+    fn cancellation_stops_between_tokens() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let token = CancellationToken::new();
+        let mut output = String::new();
+        let result = session.infer::<Infallible>(
+            &model,
+            &mut ChaCha12Rng::seed_from_u64(0),
+            &InferenceRequest {
+                prompt: "Hello".into(),
+                cancellation_token: Some(&token),
+                ..Default::default()
+            },
+            &mut Default::default(),
+            |response| {
+                if let InferenceResponse::InferredToken(text) = response {
+                    output.push_str(&text);
+                    token.cancel();
+                }
+                Ok(InferenceFeedback::Continue)
+            },
+        );
+        assert!(matches!(result, Err(InferenceError::Cancelled)));
+        assert_eq!(output, ",");
+    }
+
+    #[test]
+    fn cancellation_stops_between_prompt_batches() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let token = CancellationToken::new();
+        let parameters = InferenceParameters {
+            n_batch: 1,
+            ..Default::default()
+        };
+        let result = session.infer::<Infallible>(
+            &model,
+            &mut ChaCha12Rng::seed_from_u64(0),
+            &InferenceRequest {
+                prompt: "Hello, world".into(),
+                parameters: &parameters,
+                cancellation_token: Some(&token),
+                ..Default::default()
+            },
+            &mut Default::default(),
+            |response| {
+                assert!(matches!(response, InferenceResponse::PromptToken(_)));
+                token.cancel();
+                Ok(InferenceFeedback::Continue)
+            },
+        );
+        assert!(matches!(result, Err(InferenceError::Cancelled)));
+        // The beginning of text and "Hello" were fed before the cancellation was noticed.
+        assert_eq!(session.n_past, 2);
+    }
+
+    /// Runs inference on `prompt` until `deadline`, calling `wait` with each response
+    /// before it is recorded. Returns the generated text and the statistics.
+    fn infer_until(
+        model: &MockModel,
+        session: &mut InferenceSession,
+        prompt: &str,
+        parameters: &InferenceParameters,
+        deadline: Instant,
+        mut wait: impl FnMut(&InferenceResponse),
+    ) -> (String, InferenceStats) {
+        let mut output = String::new();
+        let stats = session
+            .infer::<Infallible>(
+                model,
+                &mut ChaCha12Rng::seed_from_u64(0),
+                &InferenceRequest {
+                    prompt: prompt.into(),
+                    parameters,
+                    deadline: Some(deadline),
+                    ..Default::default()
+                },
+                &mut Default::default(),
+                |response| {
+                    wait(&response);
+                    if let InferenceResponse::InferredToken(token) = response {
+                        output.push_str(&token);
+                    }
+                    Ok(InferenceFeedback::Continue)
+                },
+            )
+            .unwrap();
+        (output, stats)
+    }
+
+    fn sleep_until(deadline: Instant) {
+        std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+
+    #[test]
+    fn past_deadline_stops_before_the_prompt() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let deadline = Instant::now();
+        let (output, stats) = infer_until(
+            &model,
+            &mut session,
+            "Hello",
+            &Default::default(),
+            deadline,
+            |_| {},
+        );
+        assert_eq!(output, "");
+        assert_eq!(stats.stop_reason, StopReason::Deadline);
+        assert_eq!(stats.prompt_tokens, 0);
+        assert_eq!(session.n_past, 0);
+    }
+
+    #[test]
+    fn deadline_while_feeding_reports_the_fed_tokens() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let parameters = InferenceParameters {
+            n_batch: 1,
+            ..Default::default()
+        };
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let (output, stats) = infer_until(
+            &model,
+            &mut session,
+            "Hello, world",
+            &parameters,
+            deadline,
+            |_| sleep_until(deadline),
+        );
+        assert_eq!(output, "");
+        assert_eq!(stats.stop_reason, StopReason::Deadline);
+        // The beginning of text and "Hello" were fed in time, and stay in the session.
+        assert_eq!(stats.prompt_tokens, 2);
+        assert_eq!(session.n_past, 2);
+        assert_eq!(stats.predict_tokens, 0);
+    }
+
+    #[test]
+    fn deadline_while_generating_keeps_the_output() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let (output, stats) = infer_until(
+            &model,
+            &mut session,
+            "Hello",
+            &Default::default(),
+            deadline,
+            |response| {
+                if matches!(response, InferenceResponse::InferredToken(_)) {
+                    sleep_until(deadline);
+                }
+            },
+        );
+        assert_eq!(output, ",");
+        assert_eq!(stats.stop_reason, StopReason::Deadline);
+        assert_eq!(stats.prompt_tokens, 2);
+        assert_eq!(session.tokens().len(), 3);
+    }
+
+    /// A sampler that always samples the same token.
+    #[derive(Debug)]
+    struct Always(TokenId);
+    impl crate::Sampler for Always {
+        fn sample(&self, _: &[TokenId], _: &[f32], _: &mut dyn rand::RngCore) -> TokenId {
+            self.0
+        }
+    }
+
+    #[test]
+    fn sampler_changes_from_the_callback_apply_to_the_next_token() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let handle = session.sampler_handle();
+        let exclamation = model.tokenizer().id(b"!").unwrap();
+        let mut output = String::new();
+        session
+            .infer::<Infallible>(
+                &model,
+                &mut ChaCha12Rng::seed_from_u64(0),
+                &InferenceRequest {
+                    prompt: "Hello".into(),
+                    maximum_token_count: Some(3),
+                    ..Default::default()
+                },
+                &mut Default::default(),
+                |response| {
+                    if let InferenceResponse::InferredToken(token) = response {
+                        output.push_str(&token);
+                        handle.update(|parameters| {
+                            parameters.sampler = std::sync::Arc::new(Always(exclamation));
+                        });
+                    }
+                    Ok(InferenceFeedback::Continue)
+                },
+            )
+            .unwrap();
+        // The model predicts ", world!", but every token after the first is replaced.
+        assert_eq!(output, ",!!");
+    }
+
+    /// Samples the given tokens in turn, counting them in its custom sampler state.
+    #[derive(Debug)]
+    struct InTurn(Vec<TokenId>);
+    impl crate::Sampler for InTurn {
+        fn sample(
+            &self,
+            previous_tokens: &[TokenId],
+            logits: &[f32],
+            rng: &mut dyn rand::RngCore,
+        ) -> TokenId {
+            self.sample_with_state(&mut SamplerState::default(), previous_tokens, logits, rng)
+        }
+
+        fn sample_with_state(
+            &self,
+            state: &mut SamplerState,
+            _: &[TokenId],
+            _: &[f32],
+            _: &mut dyn rand::RngCore,
+        ) -> TokenId {
+            let count: usize = state
+                .custom("in-turn")
+                .map_or(0, |state| serde_json::from_slice(state).unwrap());
+            state.set_custom("in-turn", serde_json::to_vec(&(count + 1)).unwrap());
+            self.0[count % self.0.len()]
+        }
+    }
+
+    #[test]
+    fn custom_sampler_state_is_kept_in_snapshots() {
+        let model = model();
+        let tokenizer = model.tokenizer();
+        let sampler = InTurn(
+            [",", " world", "!"]
+                .iter()
+                .map(|token| tokenizer.id(token.as_bytes()).unwrap())
+                .collect(),
+        );
+        let parameters = InferenceParameters {
+            sampler: std::sync::Arc::new(sampler),
+            ..Default::default()
+        };
+        let infer = |session: &mut InferenceSession, prompt: &str| {
+            let mut output = String::new();
+            session
+                .infer::<Infallible>(
+                    &model,
+                    &mut ChaCha12Rng::seed_from_u64(0),
+                    &InferenceRequest {
+                        prompt: prompt.into(),
+                        parameters: &parameters,
+                        maximum_token_count: Some(2),
+                        ..Default::default()
+                    },
+                    &mut Default::default(),
+                    |response| {
+                        if let InferenceResponse::InferredToken(token) = response {
+                            output.push_str(&token);
+                        }
+                        Ok(InferenceFeedback::Continue)
+                    },
+                )
+                .unwrap();
+            output
+        };
+
+        let mut session = model.start_session(Default::default());
+        assert_eq!(infer(&mut session, "Hello"), ", world");
+
+        // SAFETY: the snapshot is copied before the session is used again.
+        let mut snapshot = unsafe { session.get_snapshot() }.unwrap().to_owned();
+        let json = serde_json::to_vec(&snapshot.sampler_state).unwrap();
+        snapshot.sampler_state = serde_json::from_slice(&json).unwrap();
+        assert_eq!(snapshot.sampler_state.custom("in-turn"), Some(&b"2"[..]));
+        let mut restored = InferenceSession::from_snapshot(snapshot, &model).unwrap();
+        assert_eq!(infer(&mut restored, ""), "!,");
+
+        restored.sampler_state_mut().clear();
+        assert_eq!(restored.sampler_state().custom("in-turn"), None);
+    }
+}
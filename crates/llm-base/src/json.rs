@@ -0,0 +1,552 @@
+//! Incremental parsing of JSON as it is generated, so that structured output can be shown
+//! progressively rather than once the whole document has been generated.
+//!
+//! [JsonStreamParser] is fed the text of each token, and returns the [JsonEvent]s that the
+//! text completes: objects and arrays starting and ending, keys, and values, with strings
+//! split into fragments. [inference_callback] adapts a callback for
+//! [InferenceSession::infer](crate::InferenceSession::infer) so that it receives the events
+//! of each generated token along with the token.
+use std::mem;
+
+use thiserror::Error;
+
+use crate::{InferenceFeedback, InferenceResponse};
+
+/// A part of a JSON document, reported by [JsonStreamParser] as soon as it is complete.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JsonEvent {
+    /// An object started (`{`).
+    ObjectStart,
+    /// The innermost object ended (`}`).
+    ObjectEnd,
+    /// An array started (`[`).
+    ArrayStart,
+    /// The innermost array ended (`]`).
+    ArrayEnd,
+    /// The key of the next value of the innermost object, unescaped.
+    Key(String),
+    /// A string value started.
+    StringStart,
+    /// The next part of the current string value, unescaped. A string value may have any
+    /// number of fragments, each with the text that was parsed since the last one.
+    StringFragment(String),
+    /// The current string value ended.
+    StringEnd,
+    /// A number, as written in the document.
+    Number(String),
+    /// `true` or `false`.
+    Bool(bool),
+    /// `null`.
+    Null,
+}
+
+/// Returned by [JsonStreamParser] when the text is not valid JSON.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid JSON at byte {offset}: {reason}")]
+pub struct JsonStreamError {
+    /// The offset of the invalid character in all of the text that was pushed.
+    pub offset: usize,
+    /// What was wrong.
+    pub reason: &'static str,
+}
+
+/// Parses a JSON document that arrives in pieces, such as the tokens generated by a model.
+///
+/// Whitespace before and after the document is ignored. Once the parser has returned an
+/// error, it returns the same error for everything that is pushed afterwards.
+#[derive(Debug, Default)]
+pub struct JsonStreamParser {
+    containers: Vec<Container>,
+    expect: Expect,
+    scalar: Scalar,
+    offset: usize,
+    error: Option<JsonStreamError>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Container {
+    Object,
+    Array,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Expect {
+    #[default]
+    Value,
+    /// A value, or the end of an array that was just started.
+    ValueOrEnd,
+    Key,
+    /// A key, or the end of an object that was just started.
+    KeyOrEnd,
+    Colon,
+    CommaOrEnd,
+    /// The document is complete.
+    Done,
+}
+
+/// A value that is being parsed.
+#[derive(Debug, Default)]
+enum Scalar {
+    #[default]
+    None,
+    String {
+        key: bool,
+        text: String,
+        escape: Option<Escape>,
+        /// The first half of a surrogate pair, whose second half must follow.
+        high_surrogate: Option<u16>,
+    },
+    Number(String),
+    Literal(String),
+}
+
+#[derive(Debug)]
+enum Escape {
+    /// After a backslash.
+    Start,
+    /// The hex digits of a `\u` escape so far.
+    Unicode(String),
+}
+
+const UNPAIRED_SURROGATE: &str = "unpaired surrogate in a `\\u` escape";
+
+impl JsonStreamParser {
+    /// Creates a parser for a new document.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses the next part of the document, and returns the events it completes.
+    ///
+    /// A number at the end of `text` is only reported once the character after it is
+    /// pushed, or by [Self::finish], as it may continue in the next part.
+    pub fn push(&mut self, text: &str) -> Result<Vec<JsonEvent>, JsonStreamError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+
+        let mut events = vec![];
+        for c in text.chars() {
+            if let Err(reason) = self.push_char(c, &mut events) {
+                let error = JsonStreamError {
+                    offset: self.offset,
+                    reason,
+                };
+                self.error = Some(error.clone());
+                return Err(error);
+            }
+            self.offset += c.len_utf8();
+        }
+
+        // Report what has been parsed of a string value so far.
+        if let Scalar::String {
+            key: false, text, ..
+        } = &mut self.scalar
+        {
+            if !text.is_empty() {
+                events.push(JsonEvent::StringFragment(mem::take(text)));
+            }
+        }
+        Ok(events)
+    }
+
+    /// Ends the document, reporting a number at its end, and returns an error if the
+    /// document is incomplete.
+    pub fn finish(&mut self) -> Result<Vec<JsonEvent>, JsonStreamError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+
+        let mut events = vec![];
+        let mut result = Ok(());
+        if matches!(self.scalar, Scalar::Number(_)) {
+            result = self.end_number(&mut events);
+        }
+        if result.is_ok() && !self.is_complete() {
+            result = Err("the document is incomplete");
+        }
+        match result {
+            Ok(()) => Ok(events),
+            Err(reason) => {
+                let error = JsonStreamError {
+                    offset: self.offset,
+                    reason,
+                };
+                self.error = Some(error.clone());
+                Err(error)
+            }
+        }
+    }
+
+    /// Whether a whole document has been parsed. A number on its own is only complete once
+    /// it is followed by whitespace, or after [Self::finish].
+    pub fn is_complete(&self) -> bool {
+        self.expect == Expect::Done
+    }
+
+    /// The depth of the innermost object or array that is being parsed, or 0 outside of them.
+    pub fn depth(&self) -> usize {
+        self.containers.len()
+    }
+
+    fn push_char(&mut self, c: char, events: &mut Vec<JsonEvent>) -> Result<(), &'static str> {
+        match &mut self.scalar {
+            Scalar::None => {}
+            Scalar::String { .. } => return self.push_string_char(c, events),
+            Scalar::Number(number) => {
+                if matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E') {
+                    number.push(c);
+                    return Ok(());
+                }
+                // The number has ended; `c` is handled below.
+                self.end_number(events)?;
+            }
+            Scalar::Literal(literal) => {
+                literal.push(c);
+                let event = match literal.as_str() {
+                    "true" => JsonEvent::Bool(true),
+                    "false" => JsonEvent::Bool(false),
+                    "null" => JsonEvent::Null,
+                    partial
+                        if ["true", "false", "null"]
+                            .iter()
+                            .any(|w| w.starts_with(partial)) =>
+                    {
+                        return Ok(())
+                    }
+                    _ => return Err("invalid literal"),
+                };
+                events.push(event);
+                self.scalar = Scalar::None;
+                self.end_value();
+                return Ok(());
+            }
+        }
+
+        if matches!(c, ' ' | '\t' | '\n' | '\r') {
+            return Ok(());
+        }
+        match self.expect {
+            Expect::Done => Err("unexpected text after the end of the document"),
+            Expect::Colon if c == ':' => {
+                self.expect = Expect::Value;
+                Ok(())
+            }
+            Expect::Colon => Err("expected `:`"),
+            Expect::CommaOrEnd => match (c, self.containers.last()) {
+                (',', Some(Container::Object)) => {
+                    self.expect = Expect::Key;
+                    Ok(())
+                }
+                (',', Some(Container::Array)) => {
+                    self.expect = Expect::Value;
+                    Ok(())
+                }
+                ('}', Some(Container::Object)) | (']', Some(Container::Array)) => {
+                    self.end_container(events);
+                    Ok(())
+                }
+                _ => Err("expected `,` or the end of the object or array"),
+            },
+            Expect::Key | Expect::KeyOrEnd => match c {
+                '"' => {
+                    self.start_string(true);
+                    Ok(())
+                }
+                '}' if self.expect == Expect::KeyOrEnd => {
+                    self.end_container(events);
+                    Ok(())
+                }
+                _ => Err("expected a key"),
+            },
+            Expect::Value | Expect::ValueOrEnd => {
+                match c {
+                    ']' if self.expect == Expect::ValueOrEnd => self.end_container(events),
+                    '{' => {
+                        self.containers.push(Container::Object);
+                        self.expect = Expect::KeyOrEnd;
+                        events.push(JsonEvent::ObjectStart);
+                    }
+                    '[' => {
+                        self.containers.push(Container::Array);
+                        self.expect = Expect::ValueOrEnd;
+                        events.push(JsonEvent::ArrayStart);
+                    }
+                    '"' => {
+                        self.start_string(false);
+                        events.push(JsonEvent::StringStart);
+                    }
+                    '-' | '0'..='9' => self.scalar = Scalar::Number(c.to_string()),
+                    't' | 'f' | 'n' => self.scalar = Scalar::Literal(c.to_string()),
+                    _ => return Err("expected a value"),
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn push_string_char(
+        &mut self,
+        c: char,
+        events: &mut Vec<JsonEvent>,
+    ) -> Result<(), &'static str> {
+        let Scalar::String {
+            key,
+            text,
+            escape,
+            high_surrogate,
+        } = &mut self.scalar
+        else {
+            unreachable!("not in a string");
+        };
+
+        match escape.take() {
+            None => match c {
+                _ if high_surrogate.is_some() && c != '\\' => return Err(UNPAIRED_SURROGATE),
+                '"' => {
+                    let key = *key;
+                    let text = mem::take(text);
+                    self.scalar = Scalar::None;
+                    if key {
+                        events.push(JsonEvent::Key(text));
+                        self.expect = Expect::Colon;
+                    } else {
+                        if !text.is_empty() {
+                            events.push(JsonEvent::StringFragment(text));
+                        }
+                        events.push(JsonEvent::StringEnd);
+                        self.end_value();
+                    }
+                }
+                '\\' => *escape = Some(Escape::Start),
+                '\0'..='\x1f' => return Err("control character in a string"),
+                c => text.push(c),
+            },
+            Some(Escape::Start) => {
+                let unescaped = match c {
+                    _ if high_surrogate.is_some() && c != 'u' => return Err(UNPAIRED_SURROGATE),
+                    '"' | '\\' | '/' => c,
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'u' => {
+                        *escape = Some(Escape::Unicode(String::new()));
+                        return Ok(());
+                    }
+                    _ => return Err("invalid escape"),
+                };
+                text.push(unescaped);
+            }
+            Some(Escape::Unicode(mut hex)) => {
+                if !c.is_ascii_hexdigit() {
+                    return Err("invalid `\\u` escape");
+                }
+                hex.push(c);
+                if hex.len() < 4 {
+                    *escape = Some(Escape::Unicode(hex));
+                    return Ok(());
+                }
+
+                let unit = u16::from_str_radix(&hex, 16).expect("the escape is valid hex");
+                match (high_surrogate.take(), unit) {
+                    (None, 0xD800..=0xDBFF) => *high_surrogate = Some(unit),
+                    (None, 0xDC00..=0xDFFF) => return Err(UNPAIRED_SURROGATE),
+                    (None, unit) => {
+                        text.push(char::from_u32(unit.into()).expect("not a surrogate"));
+                    }
+                    (Some(high), 0xDC00..=0xDFFF) => text.extend(
+                        char::decode_utf16([high, unit])
+                            .map(|c| c.expect("the surrogates are paired")),
+                    ),
+                    (Some(_), _) => return Err(UNPAIRED_SURROGATE),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn start_string(&mut self, key: bool) {
+        self.scalar = Scalar::String {
+            key,
+            text: String::new(),
+            escape: None,
+            high_surrogate: None,
+        };
+    }
+
+    fn end_number(&mut self, events: &mut Vec<JsonEvent>) -> Result<(), &'static str> {
+        let Scalar::Number(number) = mem::take(&mut self.scalar) else {
+            unreachable!("not in a number");
+        };
+        if !is_number(&number) {
+            return Err("invalid number");
+        }
+        events.push(JsonEvent::Number(number));
+        self.end_value();
+        Ok(())
+    }
+
+    fn end_container(&mut self, events: &mut Vec<JsonEvent>) {
+        events.push(match self.containers.pop() {
+            Some(Container::Object) => JsonEvent::ObjectEnd,
+            Some(Container::Array) => JsonEvent::ArrayEnd,
+            None => unreachable!("not in a container"),
+        });
+        self.end_value();
+    }
+
+    fn end_value(&mut self) {
+        self.expect = if self.containers.is_empty() {
+            Expect::Done
+        } else {
+            Expect::CommaOrEnd
+        };
+    }
+}
+
+/// Whether `number` follows the JSON grammar for numbers.
+fn is_number(number: &str) -> bool {
+    let digits = |s: &str| s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+
+    let rest = number.strip_prefix('-').unwrap_or(number);
+    let rest = match digits(rest) {
+        0 => return false,
+        n if n > 1 && rest.starts_with('0') => return false,
+        n => &rest[n..],
+    };
+    let rest = match rest.strip_prefix('.') {
+        Some(fraction) => match digits(fraction) {
+            0 => return false,
+            n => &fraction[n..],
+        },
+        None => rest,
+    };
+    let rest = match rest.strip_prefix(['e', 'E']) {
+        Some(exponent) => {
+            let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+            match digits(exponent) {
+                0 => return false,
+                n => &exponent[n..],
+            }
+        }
+        None => rest,
+    };
+    rest.is_empty()
+}
+
+/// Adapts `callback` to receive the [JsonEvent]s of each generated token along with the
+/// token, for use with [InferenceSession::infer](crate::InferenceSession::infer) when the
+/// model generates JSON (e.g. when its output is constrained to JSON).
+///
+/// Other responses are passed on with no events, except for the end of text, which ends the
+/// document with [JsonStreamParser::finish]. If the generated text is not valid JSON, the
+/// callback receives the error instead of events. Inference is halted once the document is
+/// complete.
+pub fn inference_callback<'a, E: std::error::Error + Send + Sync + 'static>(
+    mut callback: impl FnMut(
+            InferenceResponse,
+            Result<Vec<JsonEvent>, JsonStreamError>,
+        ) -> Result<InferenceFeedback, E>
+        + 'a,
+) -> impl FnMut(InferenceResponse) -> Result<InferenceFeedback, E> + 'a {
+    let mut parser = JsonStreamParser::new();
+    move |response| {
+        let events = match &response {
+            InferenceResponse::InferredToken(token) => parser.push(token),
+            InferenceResponse::EotToken => parser.finish(),
+            _ => Ok(vec![]),
+        };
+        match callback(response, events)? {
+            InferenceFeedback::Continue if parser.is_complete() => Ok(InferenceFeedback::Halt),
+            feedback => Ok(feedback),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(pieces: &[&str]) -> Result<Vec<JsonEvent>, JsonStreamError> {
+        let mut parser = JsonStreamParser::new();
+        let mut events = vec![];
+        for piece in pieces {
+            events.extend(parser.push(piece)?);
+        }
+        events.extend(parser.finish()?);
+        Ok(events)
+    }
+
+    #[test]
+    fn events_are_reported_as_the_document_arrives() {
+        use JsonEvent::*;
+
+        let mut parser = JsonStreamParser::new();
+        assert_eq!(parser.push("{\"na").unwrap(), vec![ObjectStart]);
+        assert_eq!(
+            parser.push("me\": \"Ad").unwrap(),
+            vec![Key("name".into()), StringStart, StringFragment("Ad".into())]
+        );
+        assert_eq!(
+            parser.push("a\\n\", \"age\": 3").unwrap(),
+            vec![StringFragment("a\n".into()), StringEnd, Key("age".into())]
+        );
+        assert_eq!(parser.depth(), 1);
+        assert_eq!(
+            parser.push("6, \"tags\": [true, null]}").unwrap(),
+            vec![
+                Number("36".into()),
+                Key("tags".into()),
+                ArrayStart,
+                Bool(true),
+                Null,
+                ArrayEnd,
+                ObjectEnd
+            ]
+        );
+        assert!(parser.is_complete());
+        assert!(parser.push(" x").is_err());
+    }
+
+    #[test]
+    fn escapes_can_be_split_across_pieces() {
+        use JsonEvent::*;
+
+        assert_eq!(
+            parse(&["\"\\", "u00e9\\u", "d83d\\ude00", "\""]).unwrap(),
+            vec![
+                StringStart,
+                StringFragment("é".into()),
+                StringFragment("😀".into()),
+                StringEnd
+            ]
+        );
+        assert_eq!(
+            parse(&["-1.5e", "+3"]).unwrap(),
+            vec![Number("-1.5e+3".into())]
+        );
+        assert_eq!(parse(&["[]"]).unwrap(), vec![ArrayStart, ArrayEnd]);
+    }
+
+    #[test]
+    fn invalid_documents_are_rejected() {
+        for pieces in [
+            &["{\"a\" 1}"][..],
+            &["[1,]"],
+            &["[01]"],
+            &["\"\\ud83d\""],
+            &["nul", "k"],
+            &["{\"a\": 1"],
+            &["1 2"],
+        ] {
+            assert!(parse(pieces).is_err(), "{pieces:?} should be invalid");
+        }
+
+        let mut parser = JsonStreamParser::new();
+        let error = parser.push("[1, }").unwrap_err();
+        assert_eq!(error.offset, 4);
+        assert_eq!(parser.push("]"), Err(error));
+    }
+}
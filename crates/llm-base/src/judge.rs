@@ -0,0 +1,269 @@
+//! Scoring text with a model acting as a judge.
+//!
+//! The judge is given a rubric and a candidate in a prompt, and must answer with a score on
+//! a fixed scale. The score is not generated freely: each possible score is ranked with
+//! [InferenceSession::choose](crate::InferenceSession::choose), so the judge always answers
+//! with a valid score. It then explains its score in a short rationale.
+use std::convert::Infallible;
+
+use rand::SeedableRng;
+use thiserror::Error;
+
+use crate::{
+    memory::MemoryLimitExceeded, ChooseError, InferenceError, InferenceFeedback,
+    InferenceParameters, InferenceRequest, InferenceResponse, InferenceSessionConfig, Model,
+    OutputRequest, RewindError, TokenizationError,
+};
+
+/// The placeholder that is replaced with the rubric in [JudgeOptions::prompt].
+pub const RUBRIC_PLACEHOLDER: &str = "{rubric}";
+/// The placeholder that is replaced with the candidate in [JudgeOptions::prompt].
+pub const CANDIDATE_PLACEHOLDER: &str = "{candidate}";
+/// The placeholder that is replaced with the lowest score in [JudgeOptions::prompt].
+pub const MIN_SCORE_PLACEHOLDER: &str = "{min_score}";
+/// The placeholder that is replaced with the highest score in [JudgeOptions::prompt].
+pub const MAX_SCORE_PLACEHOLDER: &str = "{max_score}";
+
+/// Options for [score] and [score_batch].
+#[derive(Clone, Debug)]
+pub struct JudgeOptions {
+    /// The prompt given to the judge, which must end where the score is expected.
+    ///
+    /// [RUBRIC_PLACEHOLDER], [CANDIDATE_PLACEHOLDER], [MIN_SCORE_PLACEHOLDER] and
+    /// [MAX_SCORE_PLACEHOLDER] are replaced with their values. The candidate must come
+    /// after the rubric, as everything before it is shared between the candidates of a batch.
+    pub prompt: String,
+    /// The text fed after the score, after which the rationale is generated.
+    pub rationale_prompt: String,
+    /// The lowest possible score.
+    pub min_score: u32,
+    /// The highest possible score.
+    ///
+    /// Every score is ranked by the likelihood of all of its tokens, so scales on which
+    /// some scores have more digits than others (e.g. 1 to 10) favour the shorter ones.
+    pub max_score: u32,
+    /// The maximum number of tokens generated for the rationale. The rationale also ends
+    /// at the first blank line.
+    pub max_rationale_tokens: usize,
+    /// The parameters used for scoring and generation.
+    pub parameters: InferenceParameters,
+    /// The configuration of the session used for judging.
+    pub session_config: InferenceSessionConfig,
+    /// The seed for the random number generator. If not specified, a random seed is used.
+    pub seed: Option<u64>,
+}
+impl Default for JudgeOptions {
+    fn default() -> Self {
+        Self {
+            prompt: "You are an impartial judge. Rate the response below according to the \
+                rubric, on a scale from {min_score} (worst) to {max_score} (best).\n\n\
+                Rubric:\n{rubric}\n\nResponse:\n{candidate}\n\nScore:"
+                .to_owned(),
+            rationale_prompt: "\nRationale:".to_owned(),
+            min_score: 1,
+            max_score: 5,
+            max_rationale_tokens: 128,
+            parameters: Default::default(),
+            session_config: Default::default(),
+            seed: None,
+        }
+    }
+}
+
+/// The verdict of the judge on a candidate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Judgement {
+    /// The most likely score.
+    pub score: u32,
+    /// The probability of each score, from [JudgeOptions::min_score] to
+    /// [JudgeOptions::max_score].
+    pub probabilities: Vec<f32>,
+    /// The mean of the scores weighted by their probabilities, which distinguishes
+    /// candidates that share the same most likely score.
+    pub expected_score: f32,
+    /// The explanation the judge gave for [Self::score].
+    pub rationale: String,
+}
+
+/// Errors encountered by [score] and [score_batch].
+#[derive(Error, Debug)]
+pub enum JudgeError {
+    /// The prompt does not contain [CANDIDATE_PLACEHOLDER].
+    #[error("the judge prompt does not contain {CANDIDATE_PLACEHOLDER}")]
+    MissingCandidate,
+    /// The lowest score is higher than the highest score.
+    #[error("the lowest score ({min_score}) is higher than the highest score ({max_score})")]
+    InvalidScale {
+        /// The lowest score.
+        min_score: u32,
+        /// The highest score.
+        max_score: u32,
+    },
+    /// The session for the judge could not be started.
+    #[error("the session could not be started")]
+    MemoryLimitExceeded(#[from] MemoryLimitExceeded),
+    /// Tokenization failed.
+    #[error("tokenization failed")]
+    Tokenization(#[from] TokenizationError),
+    /// Inference failed.
+    #[error("inference failed")]
+    Inference(#[from] InferenceError),
+    /// The scores could not be ranked.
+    #[error("the scores could not be ranked")]
+    Choose(#[from] ChooseError),
+    /// The session could not be rewound to judge the next candidate.
+    #[error("the session could not be rewound")]
+    Rewind(#[from] RewindError),
+}
+
+/// Asks `model` to score `candidate` according to `rubric`.
+///
+/// The model must support rewinding, which is required to rank the scores.
+pub fn score(
+    model: &dyn Model,
+    rubric: &str,
+    candidate: &str,
+    options: &JudgeOptions,
+) -> Result<Judgement, JudgeError> {
+    Ok(score_batch(model, rubric, &[candidate], options)?
+        .pop()
+        .expect("one judgement per candidate"))
+}
+
+/// Asks `model` to score each of `candidates` according to `rubric`.
+///
+/// The part of the prompt before the candidate, including the rubric, is only evaluated
+/// once: the session is rewound to it after each candidate.
+pub fn score_batch(
+    model: &dyn Model,
+    rubric: &str,
+    candidates: &[&str],
+    options: &JudgeOptions,
+) -> Result<Vec<Judgement>, JudgeError> {
+    let JudgeOptions {
+        min_score,
+        max_score,
+        ..
+    } = *options;
+    if min_score > max_score {
+        return Err(JudgeError::InvalidScale {
+            min_score,
+            max_score,
+        });
+    }
+    let (prefix, suffix) = split_prompt(options, rubric)?;
+    let scores: Vec<String> = (min_score..=max_score).map(|s| format!(" {s}")).collect();
+    let scores: Vec<&str> = scores.iter().map(String::as_str).collect();
+
+    let mut rng = match options.seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+    let parameters = &options.parameters;
+    let mut session = model.try_start_session(options.session_config.clone())?;
+    session.feed_prompt(
+        model,
+        parameters,
+        prefix.as_str(),
+        &mut OutputRequest::default(),
+        |_| Ok::<_, Infallible>(InferenceFeedback::Continue),
+    )?;
+    let shared_tokens = session.n_past;
+
+    let mut judgements = vec![];
+    for candidate in candidates {
+        let prompt = format!("{candidate}{suffix}");
+        let choice = session.choose(model, parameters, prompt.as_str(), &scores)?;
+
+        let mut rationale = String::new();
+        let rationale_prompt = format!("{}{}", scores[choice.index], options.rationale_prompt);
+        session.infer::<Infallible>(
+            model,
+            &mut rng,
+            &InferenceRequest {
+                prompt: rationale_prompt.as_str().into(),
+                parameters,
+                play_back_previous_tokens: false,
+                maximum_token_count: Some(options.max_rationale_tokens),
+                maximum_output_bytes: None,
+                maximum_output_chars: None,
+                stop_token_sequences: &[],
+                guardrails: &[],
+                forced_prefix: None,
+                logprobs: None,
+                early_stop: Default::default(),
+                cancellation_token: None,
+                deadline: None,
+            },
+            &mut Default::default(),
+            |response| {
+                if let InferenceResponse::InferredToken(token) = response {
+                    rationale.push_str(&token);
+                    if rationale.trim_start().contains("\n\n") {
+                        return Ok(InferenceFeedback::Halt);
+                    }
+                }
+                Ok(InferenceFeedback::Continue)
+            },
+        )?;
+        let rationale = rationale.trim_start();
+        let rationale = rationale.split("\n\n").next().unwrap_or_default();
+
+        judgements.push(Judgement {
+            score: min_score + choice.index as u32,
+            expected_score: choice
+                .probabilities
+                .iter()
+                .enumerate()
+                .map(|(i, p)| (min_score + i as u32) as f32 * p)
+                .sum(),
+            probabilities: choice.probabilities,
+            rationale: rationale.trim_end().to_owned(),
+        });
+
+        session.rewind(model, session.n_past - shared_tokens)?;
+    }
+
+    Ok(judgements)
+}
+
+/// Fills in the placeholders of the prompt, and splits it into the parts before and after
+/// the candidate.
+fn split_prompt(options: &JudgeOptions, rubric: &str) -> Result<(String, String), JudgeError> {
+    let fill = |text: &str| {
+        text.replace(MIN_SCORE_PLACEHOLDER, &options.min_score.to_string())
+            .replace(MAX_SCORE_PLACEHOLDER, &options.max_score.to_string())
+            .replace(RUBRIC_PLACEHOLDER, rubric)
+    };
+    let (prefix, suffix) = options
+        .prompt
+        .split_once(CANDIDATE_PLACEHOLDER)
+        .ok_or(JudgeError::MissingCandidate)?;
+    Ok((fill(prefix), fill(suffix)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prompt_is_split_at_the_candidate() {
+        let options = JudgeOptions {
+            prompt: "Rate {min_score}-{max_score}: {rubric}\n{candidate}\nScore:".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            split_prompt(&options, "Be polite").unwrap(),
+            ("Rate 1-5: Be polite\n".to_owned(), "\nScore:".to_owned())
+        );
+
+        let options = JudgeOptions {
+            prompt: "{rubric}".to_owned(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            split_prompt(&options, ""),
+            Err(JudgeError::MissingCandidate)
+        ));
+    }
+}
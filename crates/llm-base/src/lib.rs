@@ -7,42 +7,92 @@
 //! As a user, you probably want to use the [llm](https://crates.io/crates/llm) crate instead.
 #![deny(missing_docs)]
 
+mod error_code;
+#[cfg(feature = "graph-dump")]
+mod graph_dump;
 mod inference_session;
 mod loader;
 mod lora;
+mod migrate;
 mod quantize;
+mod resource_usage;
+mod threading;
 mod tokenizer;
 
+#[cfg(feature = "attention-stats")]
+pub mod attention_stats;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cache;
+pub mod cancellation;
+pub mod compatibility;
+pub mod constraint;
+pub mod convert;
+pub mod diagnostics;
+pub mod guardrail;
+#[cfg(feature = "hf-hub")]
+pub mod hf_hub;
+pub mod json;
+pub mod judge;
+pub mod memory;
 pub mod model;
+pub mod pipelines;
+pub mod placement;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod runtime;
+#[cfg(feature = "sampler-plugins")]
+pub mod sampler_plugin;
 pub mod samplers;
+pub mod template;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod text;
 pub mod util;
+pub mod vocab;
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm_plugin;
 
 use std::sync::Arc;
 
 pub use ggml;
 pub use ggml::Type as ElementType;
 
+pub use cancellation::CancellationToken;
+pub use error_code::ErrorCode;
+#[cfg(feature = "graph-dump")]
+pub use graph_dump::{GraphDump, GraphDumpFormat};
 pub use inference_session::{
-    conversation_inference_callback, feed_prompt_callback, GraphOutputs, InferenceError,
-    InferenceFeedback, InferenceRequest, InferenceResponse, InferenceSession,
-    InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef, InferenceStats,
-    ModelKVMemoryType, RewindError, SnapshotError,
+    conversation_inference_callback, feed_prompt_callback, Choice, ChooseError,
+    ContextOverflowPolicy, EarlyStop, GraphOutputs, InferenceError, InferenceFeedback,
+    InferenceRequest, InferenceResponse, InferenceSession, InferenceSessionConfig,
+    InferenceSnapshot, InferenceSnapshotRef, InferenceStats, KvEviction, KvLayout, LogitsProcessor,
+    ModelKVMemoryType, RewindError, RngState, SamplerHandle, SequenceError, SequenceId,
+    SnapshotError, SpeculationError, SpillError, StopReason, TokenLogprobs,
 };
 pub use loader::{
-    load, load_progress_callback_stdout, ContainerType, FileType, FileTypeFormat, FormatMagic,
-    LoadError, LoadProgress, Loader, TensorLoader,
+    load, load_from_reader, load_progress_callback_stdout, validate_attention, ContainerType,
+    FileType, FileTypeFormat, FormatMagic, LoadError, LoadProgress, LoadStage, Loader,
+    TensorLoader, READER_PATH,
 };
-pub use lora::{LoraAdapter, LoraParameters};
+pub use lora::{LoraAdapter, LoraParameters, SessionLora, SessionLoraError};
 pub use memmap2::Mmap;
-pub use model::{Hyperparameters, KnownModel, Model, ModelParameters, OutputRequest};
-pub use quantize::{quantize, QuantizeError, QuantizeProgress};
+pub use migrate::{migrate, MigrateProgress};
+pub use model::{
+    ArchitectureInfo, ContextSize, EmbeddingTensors, Hyperparameters, KnownModel, LogitsCallback,
+    Model, ModelParameters, OutputRequest,
+};
+pub use quantize::{
+    quantize, quantize_dry_run, QuantizationHistogram, QuantizeError, QuantizeProgress,
+    QuantizeReport, TensorQuantizeStats,
+};
 pub use regex::Regex;
-pub use samplers::Sampler;
+pub use resource_usage::ResourceUsage;
+pub use samplers::{Sampler, SamplerState};
+pub use threading::ThreadCount;
 pub use tokenizer::{
-    InvalidTokenBias, Prompt, TokenBias, TokenId, TokenizationError, Tokenizer, TokenizerLoadError,
-    TokenizerSource,
+    EmbeddedTokenizer, InvalidTokenBias, Prompt, TokenBias, TokenId, TokenizationError, Tokenizer,
+    TokenizerLoadError, TokenizerSource, END_TOKENS,
 };
-pub use util::TokenUtf8Buffer;
+pub use util::{long_path, model_sha256, TokenUtf8Buffer};
 
 #[derive(Clone, Debug)]
 /// The parameters for text generation.
@@ -62,9 +112,12 @@ pub struct InferenceParameters {
     /// Apple Silicon and modern Intel processors have "performance" and "efficiency" cores,
     /// and you may want to only use the performance cores.
     ///
+    /// Decoding a single token is often faster with fewer threads than prompt processing;
+    /// see [ThreadCount::PerPhase] and [ThreadCount::Auto] to use different counts for each.
+    ///
     /// A reasonable default value is 8, as most modern high-performance computers have
     /// 8 physical cores. Adjust to your needs.
-    pub n_threads: usize,
+    pub n_threads: ThreadCount,
     /// Controls batch/chunk size for prompt ingestion in [InferenceSession::feed_prompt].
     ///
     /// This is the number of tokens that will be ingested at once. This is useful for
@@ -93,7 +146,7 @@ unsafe impl Sync for InferenceParameters {}
 impl Default for InferenceParameters {
     fn default() -> Self {
         Self {
-            n_threads: 8,
+            n_threads: ThreadCount::default(),
             n_batch: 8,
             sampler: Arc::new(samplers::TopPTopK::default()),
         }
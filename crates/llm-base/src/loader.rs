@@ -5,11 +5,15 @@ use std::{
     fs::File,
     io::{BufRead, BufReader, Read, Seek, SeekFrom},
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    util, Hyperparameters, KnownModel, LoraAdapter, LoraParameters, ModelParameters, TokenId,
-    Tokenizer, TokenizerLoadError, TokenizerSource,
+    diagnostics::{Diagnostic, MmapDisabledReason},
+    memory::{self, MemoryKind},
+    placement::{layer_index, Device, PlacementPolicy},
+    util, CancellationToken, Hyperparameters, KnownModel, LoraAdapter, LoraParameters,
+    ModelParameters, TokenId, Tokenizer, TokenizerLoadError, TokenizerSource,
 };
 pub use ggml::{format::FormatMagic, ContainerType};
 use ggml::{
@@ -17,6 +21,8 @@ use ggml::{
     Context,
 };
 use memmap2::Mmap;
+use regex::Regex;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
 #[derive(Debug, PartialEq, Clone, Copy, Eq, Default)]
@@ -93,7 +99,25 @@ pub enum FileTypeFormat {
     MostlyQ5_K_M,
     /// The tensors are stored using the `Q6_K` quantization scheme.
     MostlyQ6_K,
+    /// All tensors are mostly stored as `bf16`, except for the 1D tensors (32-bit).
+    ///
+    /// The `bf16` tensors are converted to `f16` when loaded.
+    MostlyBF16,
+    /// All tensors are mostly stored as `Q4_2`, except for the 1D tensors (32-bit).
+    ///
+    /// The tensors are converted to `Q8_0` when loaded.
+    MostlyQ4_2,
+    /// All tensors are mostly stored as `Q4_3`, except for the 1D tensors (32-bit).
+    ///
+    /// The tensors are converted to `Q8_0` when loaded.
+    MostlyQ4_3,
 }
+/// The ID of [FileTypeFormat::MostlyBF16], from later versions of `llama.cpp`.
+const LLAMA_FTYPE_MOSTLY_BF16: ggml::sys::llama::llama_ftype = 32;
+/// The ID of [FileTypeFormat::MostlyQ4_2], from earlier versions of `llama.cpp`.
+const LLAMA_FTYPE_MOSTLY_Q4_2: ggml::sys::llama::llama_ftype = 5;
+/// The ID of [FileTypeFormat::MostlyQ4_3], from earlier versions of `llama.cpp`.
+const LLAMA_FTYPE_MOSTLY_Q4_3: ggml::sys::llama::llama_ftype = 6;
 impl TryFrom<ggml::sys::llama::llama_ftype> for FileTypeFormat {
     type Error = ();
 
@@ -117,6 +141,9 @@ impl TryFrom<ggml::sys::llama::llama_ftype> for FileTypeFormat {
             LLAMA_FTYPE_MOSTLY_Q5_K_S => Ok(FileTypeFormat::MostlyQ5_K_S),
             LLAMA_FTYPE_MOSTLY_Q5_K_M => Ok(FileTypeFormat::MostlyQ5_K_M),
             LLAMA_FTYPE_MOSTLY_Q6_K => Ok(FileTypeFormat::MostlyQ6_K),
+            LLAMA_FTYPE_MOSTLY_BF16 => Ok(FileTypeFormat::MostlyBF16),
+            LLAMA_FTYPE_MOSTLY_Q4_2 => Ok(FileTypeFormat::MostlyQ4_2),
+            LLAMA_FTYPE_MOSTLY_Q4_3 => Ok(FileTypeFormat::MostlyQ4_3),
             _ => Err(()),
         }
     }
@@ -142,6 +169,9 @@ impl From<FileTypeFormat> for ggml::sys::llama::llama_ftype {
             FileTypeFormat::MostlyQ5_K_S => LLAMA_FTYPE_MOSTLY_Q5_K_S,
             FileTypeFormat::MostlyQ5_K_M => LLAMA_FTYPE_MOSTLY_Q5_K_M,
             FileTypeFormat::MostlyQ6_K => LLAMA_FTYPE_MOSTLY_Q6_K,
+            FileTypeFormat::MostlyBF16 => LLAMA_FTYPE_MOSTLY_BF16,
+            FileTypeFormat::MostlyQ4_2 => LLAMA_FTYPE_MOSTLY_Q4_2,
+            FileTypeFormat::MostlyQ4_3 => LLAMA_FTYPE_MOSTLY_Q4_3,
         }
     }
 }
@@ -168,6 +198,9 @@ impl Display for FileTypeFormat {
                 FileTypeFormat::MostlyQ5_K_S => "q5_K_S",
                 FileTypeFormat::MostlyQ5_K_M => "q5_K_M",
                 FileTypeFormat::MostlyQ6_K => "q6_k",
+                FileTypeFormat::MostlyBF16 => "bf16",
+                FileTypeFormat::MostlyQ4_2 => "q4_2",
+                FileTypeFormat::MostlyQ4_3 => "q4_3",
             }
         )
     }
@@ -177,6 +210,14 @@ impl Display for FileTypeFormat {
 /// These can be used to report progress to the user.
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum LoadProgress {
+    /// Part of the model has been hashed to compare it with
+    /// [ModelParameters::expected_sha256]. Only reported if it is set, before anything else.
+    Verifying {
+        /// The number of bytes hashed so far.
+        bytes: u64,
+        /// The size of the model.
+        total_bytes: u64,
+    },
     /// The hyperparameters have been loaded from the model.
     HyperparametersLoaded,
     /// The context has been created.
@@ -184,6 +225,14 @@ pub enum LoadProgress {
         /// The size of the context.
         bytes: usize,
     },
+    /// The GPU that layers are offloaded to (see [ModelParameters::gpu_layers]) has been
+    /// selected. Only reported if it is known, which is currently only the case with OpenCL.
+    GpuSelected {
+        /// The name of the platform of the GPU.
+        platform: String,
+        /// The name of the GPU.
+        device: String,
+    },
     /// A tensor was patched with a LoRA.
     LoraApplied {
         /// The name of the patched tensor.
@@ -191,18 +240,28 @@ pub enum LoadProgress {
         /// LoRA file the patch was applied from.
         source: PathBuf,
     },
-    /// A tensor from the current part has been loaded.
+    /// The header of a shard of a sharded model has been read. Only reported for sharded
+    /// models; the tensors of all the shards are then loaded and counted together.
+    ShardLoaded {
+        /// The path of the shard.
+        path: PathBuf,
+        /// The index of the shard (0-indexed).
+        index: usize,
+        /// The number of shards.
+        shard_count: usize,
+    },
+    /// A tensor has been loaded.
     TensorLoaded {
         /// The current tensor (0-indexed).
         current_tensor: usize,
         /// The number of total tensors.
         tensor_count: usize,
     },
-    /// A model part has finished fully loading.
+    /// The model has finished fully loading.
     Loaded {
-        /// The number of bytes in the part.
+        /// The number of bytes in the model files.
         file_size: u64,
-        /// The number of tensors in the part.
+        /// The number of tensors in the model.
         tensor_count: usize,
     },
 }
@@ -298,6 +357,22 @@ pub enum LoadError {
         /// The path that failed.
         path: PathBuf,
     },
+    /// The tensor `tensor_name` has values that do not fit in the type it is converted to
+    /// when it is loaded.
+    #[error(
+        "the {element_type} tensor `{tensor_name}` in {path:?} has values outside the range \
+         of {loaded_type}; convert the model with `llm quantize` first"
+    )]
+    TensorOutOfRange {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The element type of the tensor in the file.
+        element_type: ggml::Type,
+        /// The element type the tensor would have been converted to.
+        loaded_type: ggml::Type,
+        /// The path that failed.
+        path: PathBuf,
+    },
     /// An invariant was broken.
     ///
     /// This error is not relevant unless `loader2` is being used.
@@ -320,12 +395,53 @@ pub enum LoadError {
     },
     /// Multiple parts of the model were found.
     ///
-    /// Multi-part models are not supported. Please convert the model to a single part.
+    /// Multi-part models in the old `llama.cpp` format are not supported. Please convert the
+    /// model to a single part.
     #[error("multipart models are not supported")]
     MultipartNotSupported {
         /// The paths that were found.
         paths: Vec<PathBuf>,
     },
+    /// The quantized tensors of the model are in a newer quantization format than this
+    /// version of `llm` supports.
+    #[error(
+        "the model {path:?} uses quantization version {quantization_version}, which is newer than this version of llm supports"
+    )]
+    UnsupportedQuantizationVersion {
+        /// The path of the model.
+        path: PathBuf,
+        /// The quantization version of the model.
+        quantization_version: u32,
+    },
+    /// The model does not have the SHA-256 in [ModelParameters::expected_sha256]: it is
+    /// corrupt, or not the expected model.
+    #[error("the SHA-256 of {path:?} is {actual}, not {expected}")]
+    ChecksumMismatch {
+        /// The path of the model, or of its first shard.
+        path: PathBuf,
+        /// The expected SHA-256, in hex.
+        expected: String,
+        /// The actual SHA-256, in hex.
+        actual: String,
+    },
+    /// Loading was cancelled with [ModelParameters::cancellation_token].
+    #[error("loading was cancelled")]
+    Cancelled,
+    /// Loading took longer than [ModelParameters::load_timeout].
+    #[error("loading timed out after {elapsed:?} while {stage}")]
+    TimedOut {
+        /// The stage that was in progress when the timeout was noticed.
+        stage: LoadStage,
+        /// How long loading had taken.
+        elapsed: Duration,
+    },
+    /// A shard of a sharded model has different hyperparameters or a different container
+    /// type than the first shard.
+    #[error("the shard {path:?} does not belong to the same model as the first shard")]
+    ShardMismatch {
+        /// The path of the shard.
+        path: PathBuf,
+    },
     /// The tokenizer could not be loaded.
     #[error("could not load tokenizer {path:?}: {error}")]
     TokenizerLoadFail {
@@ -335,6 +451,72 @@ pub enum LoadError {
         /// The error that occurred.
         error: Box<dyn Error + Send + Sync>,
     },
+    /// The embedding size of the model is not divisible by its number of attention heads.
+    #[error(
+        "the embedding size {n_embd} of {path:?} is not divisible by its {n_head} attention heads; \
+         the hyperparameters of the file are corrupt, or it was converted for another architecture"
+    )]
+    AttentionHeadsMismatch {
+        /// The path that failed.
+        path: PathBuf,
+        /// The embedding size.
+        n_embd: usize,
+        /// The number of attention heads.
+        n_head: usize,
+    },
+    /// The number of dimensions rotated by the rotary position embeddings does not fit the
+    /// attention heads.
+    #[error(
+        "{path:?} rotates {n_rot} dimensions of attention heads of {head_size}, but it should \
+         rotate an even number of them, at most {head_size}; the hyperparameters of the file \
+         are corrupt, or it was converted with an outdated script"
+    )]
+    InvalidRotaryDimensions {
+        /// The path that failed.
+        path: PathBuf,
+        /// The number of rotated dimensions.
+        n_rot: usize,
+        /// The number of dimensions of each attention head.
+        head_size: usize,
+    },
+    /// The tokenizer has tokens that the model has no embeddings for.
+    #[error(
+        "the tokenizer has {tokenizer_len} tokens, but {path:?} only has embeddings for \
+         {n_vocab}; use the tokenizer the model was trained with, or its embedded vocabulary"
+    )]
+    VocabularyMismatch {
+        /// The path that failed.
+        path: PathBuf,
+        /// The number of tokens the model has embeddings for.
+        n_vocab: usize,
+        /// The number of tokens of the tokenizer.
+        tokenizer_len: usize,
+    },
+    /// A tensor of a [tensor override](ModelParameters::tensor_overrides) file does not
+    /// have the shape of the tensor it replaces.
+    #[error(
+        "the tensor `{tensor_name}` in {path:?} has the shape {actual:?}, but the tensor it \
+         overrides has the shape {expected:?}"
+    )]
+    TensorOverrideShapeMismatch {
+        /// The path of the override file.
+        path: PathBuf,
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The shape of the tensor of the model.
+        expected: Vec<usize>,
+        /// The shape of the tensor of the override file.
+        actual: Vec<usize>,
+    },
+    /// No tensor of a [tensor override](ModelParameters::tensor_overrides) file matches its
+    /// pattern.
+    #[error("no tensor in {path:?} matches the override pattern {pattern:?}")]
+    TensorOverrideUnmatched {
+        /// The path of the override file.
+        path: PathBuf,
+        /// The pattern.
+        pattern: String,
+    },
     /// There is insufficient information to guess the model architecture from the provided file.
     ///
     /// A model architecture must be provided to load the model.
@@ -392,10 +574,42 @@ impl LoadError {
     }
 }
 
+/// Checks that the `n_head` attention heads of the model at `path` split its embedding of
+/// `n_embd` dimensions evenly and, for models with rotary position embeddings, that the
+/// `n_rot` rotated dimensions fit in each head. Used by the implementations of
+/// [Hyperparameters::validate].
+pub fn validate_attention(
+    path: &Path,
+    n_embd: usize,
+    n_head: usize,
+    n_rot: Option<usize>,
+) -> Result<(), LoadError> {
+    if n_head == 0 || n_embd % n_head != 0 {
+        return Err(LoadError::AttentionHeadsMismatch {
+            path: path.to_owned(),
+            n_embd,
+            n_head,
+        });
+    }
+    let head_size = n_embd / n_head;
+    match n_rot {
+        Some(n_rot) if n_rot == 0 || n_rot % 2 != 0 || n_rot > head_size => {
+            Err(LoadError::InvalidRotaryDimensions {
+                path: path.to_owned(),
+                n_rot,
+                head_size,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Used by models to fetch tensors from a loader.
 pub trait TensorLoader<E: std::error::Error> {
     /// Gets a tensor from the loader.
     fn load(&mut self, name: &str) -> Result<ggml::Tensor, E>;
+    /// Returns whether the model file has a tensor named `name`.
+    fn contains(&self, name: &str) -> bool;
     /// Finish loading the model, and extract all of the state from the loader.
     fn finish(self) -> (Context, HashMap<String, ggml::Tensor>);
 }
@@ -403,8 +617,12 @@ pub trait TensorLoader<E: std::error::Error> {
 /// Load a GGML model from the `path` and configure it per the `params`. The status
 /// of the loading process will be reported through `load_progress_callback`.
 ///
-/// Note that the model must be a single-part model, and the model in `path`
-/// *must* match the architecture of `M`.
+/// If `path` is one of the shards of a sharded model (see [util::shard_paths]), all of the
+/// shards are loaded together. Each shard is a complete model file holding some of the
+/// tensors, and all of them must have the same hyperparameters. Models split into parts
+/// in the old `llama.cpp` format (`model.bin`, `model.bin.1`, ...) are not supported.
+///
+/// The model in `path` *must* match the architecture of `M`.
 ///
 /// # Panics
 ///
@@ -431,56 +649,273 @@ pub fn load<M: KnownModel>(
         return Err(LoadError::MultipartNotSupported { paths });
     }
 
-    let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
-        source: e,
-        path: path.to_owned(),
-    })?;
-    let mut reader = BufReader::new(&file);
+    let shards = util::shard_paths(path)
+        .unwrap_or_else(|| vec![path.to_owned()])
+        .into_iter()
+        .map(|path| {
+            if !util::long_path(&path).exists() {
+                return Err(LoadError::FileDoesNotExist { path });
+            }
+            match File::open(util::long_path(&path)) {
+                Ok(file) => Ok(Shard { file, path }),
+                Err(source) => Err(LoadError::OpenFileFailed { source, path }),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let tokenizer = tokenizer_source.retrieve(path)?;
+
+    load_internal(shards, true, tokenizer, params, load_progress_callback)
+}
 
+/// The path that errors refer to when loading from a reader with [load_from_reader].
+pub const READER_PATH: &str = "<reader>";
+
+/// Load a GGML model from `reader` and configure it per the `params`, like [load].
+///
+/// This allows loading models that are not files on disk, such as models in memory
+/// (through [std::io::Cursor]), embedded in the executable or decrypted on the fly.
+/// The model is read into memory, as memory mapping requires a file: if
+/// [ModelParameters::prefer_mmap] is set, a [Diagnostic::MmapDisabled] is emitted.
+/// Errors refer to the model by [READER_PATH].
+///
+/// # Panics
+///
+/// - If the model does not match the architecture of `M`, as with [load].
+pub fn load_from_reader<M: KnownModel, R: Read + Seek>(
+    reader: R,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<M, LoadError> {
+    let path = Path::new(READER_PATH);
     let tokenizer = tokenizer_source.retrieve(path)?;
-    let mut loader = Loader::new(tokenizer, load_progress_callback);
 
-    ggml::format::load(&mut reader, &mut loader)
-        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+    let shard = Shard {
+        file: reader,
+        path: path.to_owned(),
+    };
+    load_internal(
+        vec![shard],
+        false,
+        tokenizer,
+        params,
+        load_progress_callback,
+    )
+}
+
+/// Whether models can be memory mapped on this target. WebAssembly has no memory mapping,
+/// so models are always read into memory there.
+const SUPPORTS_MMAP: bool = cfg!(not(target_arch = "wasm32"));
+
+/// A file holding some or all of the tensors of a model.
+struct Shard<R> {
+    file: R,
+    path: PathBuf,
+}
+
+/// Loads a model from `shards`, with the hyperparameters and vocabulary of the first.
+/// The shards are only opened again by path to memory map them, which is not attempted
+/// unless `mappable` is set.
+fn load_internal<M: KnownModel, R: Read + Seek>(
+    mut shards: Vec<Shard<R>>,
+    mappable: bool,
+    tokenizer: Tokenizer,
+    params: ModelParameters,
+    mut load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<M, LoadError> {
+    let guard = LoadGuard::new(&params);
+
+    if let Some(expected) = params.expected_sha256 {
+        verify_sha256(&mut shards, expected, &guard, &mut load_progress_callback)?;
+    }
+
+    let mut loader = Loader::<M::Hyperparameters, _>::new(tokenizer, &mut load_progress_callback);
+    let first = &mut shards[0];
+    ggml::format::load(&mut BufReader::new(&mut first.file), &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, first.path.clone()))?;
+    guard.check(LoadStage::ReadingMetadata)?;
 
     let Loader {
         hyperparameters,
         tokenizer,
-        tensors,
+        mut tensors,
         mut load_progress_callback,
         container_type,
         ..
     } = loader;
 
-    let quantization_version = (&hyperparameters as &M::Hyperparameters)
-        .file_type()
-        .map(|ft| ft.quantization_version)
-        .unwrap_or_default();
-    let quantization_version = if quantization_version == 0 {
-        // HACK: I think llama.cpp does not actually write the quantization version correctly,
-        // so we need to guess it from the container type.
-        if container_type == ggml::ContainerType::Ggjt(2) {
-            1
-        } else if container_type == ggml::ContainerType::Ggjt(3) {
-            2
-        } else {
-            quantization_version
+    hyperparameters.validate(&shards[0].path)?;
+    // A tokenizer may have fewer tokens than the model has embeddings, as vocabularies are
+    // often padded, but tokens without an embedding would read past the end of the table.
+    let n_vocab = hyperparameters.n_vocabulary();
+    if tokenizer.len() > n_vocab {
+        return Err(LoadError::VocabularyMismatch {
+            path: shards[0].path.clone(),
+            n_vocab,
+            tokenizer_len: tokenizer.len(),
+        });
+    }
+
+    // The tensors of the other shards are added to those of the first, and each tensor is
+    // read from the shard it was found in.
+    let mut tensor_sources: HashMap<String, TensorSource> = tensors
+        .keys()
+        .map(|name| (name.clone(), TensorSource::Shard(0)))
+        .collect();
+    let shard_count = shards.len();
+    for (index, shard) in shards.iter_mut().enumerate() {
+        if index > 0 {
+            let mut shard_loader =
+                Loader::<M::Hyperparameters, _>::new(Tokenizer::empty_embedded(), |_| {});
+            ggml::format::load(&mut BufReader::new(&mut shard.file), &mut shard_loader)
+                .map_err(|err| LoadError::from_format_error(err, shard.path.clone()))?;
+            guard.check(LoadStage::ReadingMetadata)?;
+            if shard_loader.hyperparameters != hyperparameters
+                || shard_loader.container_type != container_type
+            {
+                return Err(LoadError::ShardMismatch {
+                    path: shard.path.clone(),
+                });
+            }
+            for (name, info) in shard_loader.tensors {
+                if tensor_sources
+                    .insert(name.clone(), TensorSource::Shard(index))
+                    .is_some()
+                {
+                    return Err(LoadError::InvariantBroken {
+                        path: Some(shard.path.clone()),
+                        invariant: format!("the tensor {name} should only be in one shard"),
+                    });
+                }
+                tensors.insert(name, info);
+            }
         }
-    } else {
-        quantization_version
-    };
+        if shard_count > 1 {
+            (load_progress_callback)(LoadProgress::ShardLoaded {
+                path: shard.path.clone(),
+                index,
+                shard_count,
+            });
+        }
+    }
 
-    // TODO: this is temporary while we figure out how to handle this
-    if tensors.values().any(|t| t.element_type.is_quantized()) {
-        assert_eq!(quantization_version, 2, "quantization version must be 2");
+    // Tensors that match an override are read from its file instead.
+    let mut overrides = vec![];
+    for (pattern, path) in &params.tensor_overrides {
+        let matcher = glob_regex(pattern);
+        let mut file =
+            File::open(util::long_path(path)).map_err(|e| LoadError::OpenFileFailed {
+                source: e,
+                path: path.to_owned(),
+            })?;
+        let mut override_loader =
+            Loader::<M::Hyperparameters, _>::new(Tokenizer::empty_embedded(), |_| {});
+        ggml::format::load(&mut BufReader::new(&mut file), &mut override_loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+        guard.check(LoadStage::ReadingMetadata)?;
+
+        let mut matched = false;
+        for (name, info) in override_loader.tensors {
+            if !matcher.is_match(&name) {
+                continue;
+            }
+            let Some(original) = tensors.get(&name) else {
+                return Err(LoadError::UnknownTensor {
+                    tensor_name: name,
+                    path: path.to_owned(),
+                });
+            };
+            if original.dims() != info.dims() {
+                return Err(LoadError::TensorOverrideShapeMismatch {
+                    path: path.to_owned(),
+                    tensor_name: name,
+                    expected: original.dims().to_vec(),
+                    actual: info.dims().to_vec(),
+                });
+            }
+            matched = true;
+            tensor_sources.insert(name.clone(), TensorSource::Override(overrides.len()));
+            tensors.insert(name, info);
+        }
+        if !matched {
+            return Err(LoadError::TensorOverrideUnmatched {
+                path: path.to_owned(),
+                pattern: pattern.to_owned(),
+            });
+        }
+        overrides.push(Shard {
+            file,
+            path: path.to_owned(),
+        });
+    }
+
+    let quantization_version = quantization_version(
+        (&hyperparameters as &M::Hyperparameters).file_type(),
+        container_type,
+    );
+
+    if quantization_version > ggml::QNT_VERSION
+        && tensors.values().any(|t| t.element_type.is_quantized())
+    {
+        return Err(LoadError::UnsupportedQuantizationVersion {
+            path: shards[0].path.clone(),
+            quantization_version,
+        });
+    }
+    // Tensors in the layouts of older versions are upgraded as they are loaded.
+    let legacy = tensors.values().any(|t| t.is_legacy());
+    if legacy {
+        params.diagnostics.emit(Diagnostic::LegacyQuantization {
+            quantization_version,
+        });
     }
 
-    let use_mmap =
-        params.prefer_mmap && container_type.support_mmap() && params.lora_adapters.is_none();
+    let needs_conversion = legacy || tensors.values().any(|t| t.element_type.is_file_only());
+    let use_mmap = params.prefer_mmap
+        && SUPPORTS_MMAP
+        && mappable
+        && container_type.support_mmap()
+        && params.lora_adapters.is_none()
+        && overrides.is_empty()
+        && !needs_conversion;
+    if params.prefer_mmap && !use_mmap {
+        let reason = if !SUPPORTS_MMAP {
+            MmapDisabledReason::UnsupportedTarget
+        } else if !mappable {
+            MmapDisabledReason::Reader
+        } else if !container_type.support_mmap() {
+            MmapDisabledReason::UnsupportedContainer
+        } else if params.lora_adapters.is_some() {
+            MmapDisabledReason::LoraAdapters
+        } else if !overrides.is_empty() {
+            MmapDisabledReason::TensorOverrides
+        } else if legacy
+            || tensors
+                .values()
+                .any(|t| matches!(t.element_type, ggml::Type::Q4_2 | ggml::Type::Q4_3))
+        {
+            // The `Q4_2` and `Q4_3` types of old files are converted when they are loaded.
+            MmapDisabledReason::LegacyQuantization
+        } else {
+            MmapDisabledReason::Bf16Tensors
+        };
+        params.diagnostics.emit(Diagnostic::MmapDisabled { reason });
+    }
 
     let ctx_size = tensors
         .values()
-        .map(|ti| ti.calc_absolute_size(use_mmap))
+        .map(|ti| {
+            if ti.element_type.is_file_only() {
+                ggml::format::tensor_size(
+                    loaded_element_type(ti.element_type, ti.n_dims),
+                    ti.n_elements,
+                )
+            } else if ti.is_legacy() {
+                ggml::format::tensor_size(ti.element_type, ti.n_elements)
+            } else {
+                ti.calc_absolute_size(use_mmap)
+            }
+        })
         .sum::<usize>();
 
     let mut lora_adapters: Option<Vec<LoraAdapter>> = None;
@@ -489,9 +924,11 @@ pub fn load<M: KnownModel>(
             .iter()
             .map(|lora_path| {
                 // Read the LoRA file
-                let lora_file = File::open(lora_path).map_err(|e| LoadError::OpenFileFailed {
-                    source: e,
-                    path: lora_path.to_owned(),
+                let lora_file = File::open(util::long_path(lora_path)).map_err(|e| {
+                    LoadError::OpenFileFailed {
+                        source: e,
+                        path: lora_path.to_owned(),
+                    }
                 })?;
                 let mut lora_reader = BufReader::new(&lora_file);
                 // TODO: Consider updating the progress callback to report the progress of the LoRA file.
@@ -521,25 +958,66 @@ pub fn load<M: KnownModel>(
         lora_adapters = Some(adapters?);
     }
 
+    guard.check(LoadStage::ReadingMetadata)?;
     (load_progress_callback)(LoadProgress::ContextSize { bytes: ctx_size });
     let (context, file_size) = if use_mmap {
-        let file = File::open(path)?;
-        unsafe {
-            let mmap = Mmap::map(&file)?;
-            let file_size = mmap.len() as u64;
-            (Context::init_mmap(mmap), file_size)
+        let mmaps = shards
+            .iter()
+            .map(|shard| unsafe { Mmap::map(&File::open(util::long_path(&shard.path))?) })
+            .collect::<Result<Vec<_>, _>>()?;
+        guard.check(LoadStage::Mapping)?;
+        let file_size = mmaps.iter().map(|mmap| mmap.len() as u64).sum();
+        (Context::init_mmaps(mmaps), file_size)
+    } else {
+        let mut file_size = 0;
+        for shard in &mut shards {
+            file_size += shard.file.seek(SeekFrom::End(0))?;
         }
+        (Context::init(ctx_size, true), file_size)
+    };
+    let weights_size = if use_mmap {
+        file_size as usize
     } else {
-        (Context::init(ctx_size, true), file.metadata()?.len())
+        ctx_size
     };
+    memory::track(&context, MemoryKind::ModelWeights, weights_size);
+
+    // Metal uses the whole model on the GPU without offloading it.
+    let placement = match (&params.placement, params.gpu_layers) {
+        _ if !params.use_gpu || cfg!(feature = "metal") => PlacementPolicy::default(),
+        (placement, gpu_layers) if ggml::accelerator::can_offload() => match placement {
+            Some(placement) => placement.clone(),
+            None => PlacementPolicy::first_layers(gpu_layers.unwrap_or_else(|| {
+                let last_layer = tensors.keys().filter_map(|name| layer_index(name)).max();
+                last_layer.map_or(0, |layer| layer + 1)
+            })),
+        },
+        (None, None) => PlacementPolicy::default(),
+        _ => {
+            params.diagnostics.emit(Diagnostic::GpuOffloadUnavailable);
+            PlacementPolicy::default()
+        }
+    };
+    if placement != PlacementPolicy::default() {
+        if let Some(device) = ggml::accelerator::gpu_device() {
+            load_progress_callback(LoadProgress::GpuSelected {
+                platform: device.platform,
+                device: device.name,
+            });
+        }
+    }
 
     let tensors_len = tensors.len();
     let tl = MmapCompatibleLoader {
-        path: path.to_owned(),
-        file,
+        shards,
+        overrides,
+        tensor_sources,
         tensors,
         context,
         lora_adapters,
+        placement,
+        output_tensor: M::embedding_tensors().output,
+        guard,
         load_progress_callback: &mut load_progress_callback,
         loaded_tensors: Default::default(),
     };
@@ -554,6 +1032,124 @@ pub fn load<M: KnownModel>(
     Ok(model)
 }
 
+/// A stage of loading a model, reported by [LoadError::TimedOut].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadStage {
+    /// Hashing the model files to check [ModelParameters::expected_sha256].
+    Verifying,
+    /// Reading the hyperparameters, the vocabulary and the tensor index of the model files.
+    ReadingMetadata,
+    /// Memory mapping the model files.
+    Mapping,
+    /// Reading the tensors, or setting them up in the memory-mapped files.
+    LoadingTensors,
+}
+impl Display for LoadStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LoadStage::Verifying => "verifying the checksum",
+            LoadStage::ReadingMetadata => "reading the metadata",
+            LoadStage::Mapping => "memory mapping the model",
+            LoadStage::LoadingTensors => "loading the tensors",
+        })
+    }
+}
+
+/// Stops loading when [ModelParameters::cancellation_token] is cancelled, or when
+/// [ModelParameters::load_timeout] has passed.
+struct LoadGuard {
+    cancellation_token: Option<CancellationToken>,
+    /// When loading started, and how long it may take.
+    deadline: Option<(Instant, Duration)>,
+}
+impl LoadGuard {
+    fn new(params: &ModelParameters) -> Self {
+        Self {
+            cancellation_token: params.cancellation_token.clone(),
+            // The clock is only read with a timeout, as it is not available on every target.
+            deadline: params.load_timeout.map(|timeout| (Instant::now(), timeout)),
+        }
+    }
+
+    /// Fails if loading was cancelled or took too long, while in `stage`.
+    fn check(&self, stage: LoadStage) -> Result<(), LoadError> {
+        if let Some(token) = &self.cancellation_token {
+            if token.is_cancelled() {
+                return Err(LoadError::Cancelled);
+            }
+        }
+        if let Some((started, timeout)) = self.deadline {
+            let elapsed = started.elapsed();
+            if elapsed > timeout {
+                return Err(LoadError::TimedOut { stage, elapsed });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hashes `shards`, one after the other, and compares the hash with `expected`. The shards
+/// are left at their start.
+fn verify_sha256<R: Read + Seek>(
+    shards: &mut [Shard<R>],
+    expected: [u8; 32],
+    guard: &LoadGuard,
+    load_progress_callback: &mut impl FnMut(LoadProgress),
+) -> Result<(), LoadError> {
+    let mut total_bytes = 0;
+    for shard in shards.iter_mut() {
+        total_bytes += shard.file.seek(SeekFrom::End(0))?;
+        shard.file.seek(SeekFrom::Start(0))?;
+    }
+
+    let mut hasher = Sha256::new();
+    let mut bytes = 0;
+    load_progress_callback(LoadProgress::Verifying { bytes, total_bytes });
+    for shard in shards.iter_mut() {
+        guard.check(LoadStage::Verifying)?;
+        util::hash_reader(&mut hasher, &mut shard.file, |n| {
+            bytes += n;
+            load_progress_callback(LoadProgress::Verifying { bytes, total_bytes });
+            guard.check(LoadStage::Verifying)
+        })?;
+        shard.file.seek(SeekFrom::Start(0))?;
+    }
+
+    let actual: [u8; 32] = hasher.finalize().into();
+    if actual != expected {
+        let hex = |hash: [u8; 32]| hash.iter().map(|b| format!("{b:02x}")).collect();
+        return Err(LoadError::ChecksumMismatch {
+            path: shards[0].path.clone(),
+            expected: hex(expected),
+            actual: hex(actual),
+        });
+    }
+    Ok(())
+}
+
+/// The quantization version of the tensors of a model with `file_type` and `container_type`.
+pub(crate) fn quantization_version(
+    file_type: Option<FileType>,
+    container_type: ContainerType,
+) -> u32 {
+    let quantization_version = file_type
+        .map(|ft| ft.quantization_version)
+        .unwrap_or_default();
+    if quantization_version == 0 {
+        // HACK: I think llama.cpp does not actually write the quantization version correctly,
+        // so we need to guess it from the container type.
+        if container_type == ggml::ContainerType::Ggjt(2) {
+            1
+        } else if container_type == ggml::ContainerType::Ggjt(3) {
+            2
+        } else {
+            quantization_version
+        }
+    } else {
+        quantization_version
+    }
+}
+
 /// A GGML format loader for LLMs.
 pub struct Loader<Hp: Hyperparameters, F: FnMut(LoadProgress)> {
     // Input
@@ -613,6 +1209,11 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> ggml::format::LoadHandler<Load
         let hyperparameters = Hp::read_ggml(reader)?;
         let partial = PartialHyperparameters {
             n_vocab: hyperparameters.n_vocabulary(),
+            // Files without a file type (e.g. LoRA adapters) are assumed to be current.
+            quantization_version: match hyperparameters.file_type() {
+                Some(file_type) => quantization_version(Some(file_type), self.container_type),
+                None => ggml::QNT_VERSION,
+            },
         };
         self.hyperparameters = hyperparameters;
         (self.load_progress_callback)(LoadProgress::HyperparametersLoaded);
@@ -626,30 +1227,78 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> ggml::format::LoadHandler<Load
     }
 }
 
-struct MmapCompatibleLoader<'a> {
-    path: PathBuf,
-    file: File,
+/// Where a tensor is read from by a [MmapCompatibleLoader].
+#[derive(Clone, Copy, Debug)]
+enum TensorSource {
+    /// The shard with this index.
+    Shard(usize),
+    /// The tensor override file with this index.
+    Override(usize),
+}
+
+/// Matches the names that the glob `pattern` matches: `*` matches any text, and `?` any
+/// character.
+fn glob_regex(pattern: &str) -> Regex {
+    let mut regex = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).expect("escaped globs form a valid pattern")
+}
+
+struct MmapCompatibleLoader<'a, R: Read + Seek> {
+    shards: Vec<Shard<R>>,
+    /// The files of [ModelParameters::tensor_overrides] that replace some tensors.
+    overrides: Vec<Shard<File>>,
+    /// Where each tensor is read from.
+    tensor_sources: HashMap<String, TensorSource>,
     tensors: HashMap<String, TensorLoadInfo>,
     context: Context,
     lora_adapters: Option<Vec<LoraAdapter>>,
+    /// Where to store the weights of each layer.
+    placement: PlacementPolicy,
+    /// The name of the output weights, which are placed separately from the layers.
+    output_tensor: Option<&'static str>,
+    guard: LoadGuard,
     load_progress_callback: &'a mut dyn FnMut(LoadProgress),
     loaded_tensors: HashMap<String, ggml::Tensor>,
 }
-impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
+impl<R: Read + Seek> TensorLoader<LoadError> for MmapCompatibleLoader<'_, R> {
+    fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
     fn load(&mut self, name: &str) -> Result<ggml::Tensor, LoadError> {
+        // The tensors loaded so far are freed with the context when the error is returned.
+        self.guard.check(LoadStage::LoadingTensors)?;
+
         let info = self.tensors.get(name).ok_or(LoadError::UnknownTensor {
             tensor_name: String::from(name),
             path: Default::default(),
         })?;
 
-        let mut main_context = FileContext::new(
-            &self.context,
-            &mut self.file,
-            &self.path,
-            self.context.mmap.as_ref(),
-        );
-
-        let mut tensor = main_context.get_tensor(info)?;
+        let mut tensor = match self.tensor_sources[name] {
+            TensorSource::Shard(index) => {
+                let shard = &mut self.shards[index];
+                FileContext::new(
+                    &self.context,
+                    &mut shard.file,
+                    &shard.path,
+                    self.context.mmaps.get(index),
+                )
+                .get_tensor(info)?
+            }
+            TensorSource::Override(index) => {
+                let file = &mut self.overrides[index];
+                FileContext::new(&self.context, &mut file.file, &file.path, None)
+                    .get_tensor(info)?
+            }
+        };
 
         if let Some(lora_adapters) = &mut self.lora_adapters {
             for lora_adapter in lora_adapters {
@@ -661,6 +1310,15 @@ impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
             }
         }
 
+        // Only the matrices are offloaded, as the GPU is used for the matrix multiplications.
+        let offload =
+            info.n_dims == 2 && self.placement.device_of(name, self.output_tensor) == Device::Gpu;
+        if offload {
+            // SAFETY: the tensor was loaded from this context, and the model does not modify
+            // its weights.
+            unsafe { self.context.offload(&tensor) };
+        }
+
         (self.load_progress_callback)(LoadProgress::TensorLoaded {
             current_tensor: self.loaded_tensors.len(),
             tensor_count: self.tensors.len(),
@@ -675,16 +1333,16 @@ impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
     }
 }
 
-pub(crate) struct FileContext<'a> {
+pub(crate) struct FileContext<'a, R: Read + Seek> {
     context: &'a Context,
-    file: &'a mut File,
+    file: &'a mut R,
     path: &'a Path,
     mmap: Option<&'a Mmap>,
 }
-impl<'a> FileContext<'a> {
+impl<'a, R: Read + Seek> FileContext<'a, R> {
     pub(crate) fn new(
         context: &'a Context,
-        file: &'a mut File,
+        file: &'a mut R,
         path: &'a Path,
         mmap: Option<&'a Mmap>,
     ) -> Self {
@@ -711,21 +1369,59 @@ impl<'a> FileContext<'a> {
             });
         }
 
-        let mut tensor = match dims {
-            1 => self.context.new_tensor_1d(info.element_type, ne[0]),
-            2 => self.context.new_tensor_2d(info.element_type, ne[0], ne[1]),
-            3 => self
-                .context
-                .new_tensor_3d(info.element_type, ne[0], ne[1], ne[2]),
-            _ => {
+        if !(1..=3).contains(&dims) {
+            return Err(LoadError::InvariantBroken {
+                path: Some(self.path.to_owned()),
+                invariant: format!(
+                    "the tensor {name} should have between 1 and 3 dimensions, not {dims}"
+                ),
+            });
+        }
+        let element_type = loaded_element_type(info.element_type, dims);
+        let mut tensor = self
+            .context
+            .try_new_tensor(element_type, ne)
+            .map_err(|err| LoadError::InvariantBroken {
+                path: Some(self.path.to_owned()),
+                invariant: format!("the tensor {name} could not be created: {err}"),
+            })?;
+
+        if info.element_type.is_file_only() {
+            if self.mmap.is_some() {
                 return Err(LoadError::InvariantBroken {
                     path: Some(self.path.to_owned()),
                     invariant: format!(
-                        "the tensor {name} should have between 1 and 3 dimensions, not {dims}"
+                        "the {} tensor {name} cannot be memory mapped",
+                        info.element_type
                     ),
-                })
+                });
             }
-        };
+            let mut data = vec![0; info.calc_size()];
+            self.file.seek(SeekFrom::Start(info.start_offset))?;
+            self.file.read_exact(&mut data)?;
+            let data =
+                convert_file_only(&data, info.element_type, element_type).ok_or_else(|| {
+                    LoadError::TensorOutOfRange {
+                        tensor_name: name.to_owned(),
+                        element_type: info.element_type,
+                        loaded_type: element_type,
+                        path: self.path.to_owned(),
+                    }
+                })?;
+            // SAFETY: the tensor was allocated with the size of the converted data.
+            unsafe { tensor.write_data(&data) };
+            return Ok(tensor);
+        }
+
+        if info.is_legacy() {
+            let mut data = vec![0; info.calc_size()];
+            self.file.seek(SeekFrom::Start(info.start_offset))?;
+            self.file.read_exact(&mut data)?;
+            let data = ggml::legacy::upgrade(info.element_type, info.quantization_version, &data);
+            // SAFETY: the upgraded data is in the layout the tensor was allocated with.
+            unsafe { tensor.write_data(&data) };
+            return Ok(tensor);
+        }
 
         match self.mmap {
             Some(mmap) => unsafe {
@@ -745,6 +1441,81 @@ impl<'a> FileContext<'a> {
     }
 }
 
+/// Returns the element type that a tensor stored as `element_type` has once loaded.
+///
+/// `ggml` cannot compute with the [file-only](ggml::Type::is_file_only) types, so those
+/// tensors are converted: `bf16` matrices to `f16`, and vectors to `f32` like the other 1D
+/// tensors of a model. The `Q4_2` and `Q4_3` types of old files are converted to `Q8_0`,
+/// the smallest type that keeps their values almost exactly.
+pub(crate) fn loaded_element_type(element_type: ggml::Type, n_dims: usize) -> ggml::Type {
+    match element_type {
+        ggml::Type::BF16 if n_dims == 1 => ggml::Type::F32,
+        ggml::Type::BF16 => ggml::Type::F16,
+        ggml::Type::Q4_2 | ggml::Type::Q4_3 => ggml::Type::Q8_0,
+        _ => element_type,
+    }
+}
+
+/// Dequantizes little-endian data of a [file-only](ggml::Type::is_file_only) type.
+pub(crate) fn dequantize_file_only(data: &[u8], element_type: ggml::Type) -> Vec<f32> {
+    match element_type {
+        ggml::Type::BF16 => data
+            .chunks_exact(2)
+            .map(|chunk| half::bf16::from_le_bytes([chunk[0], chunk[1]]).to_f32())
+            .collect(),
+        ggml::Type::Q4_2 => ggml::legacy::dequantize_q4_2(data),
+        ggml::Type::Q4_3 => ggml::legacy::dequantize_q4_3(data),
+        _ => unreachable!("{element_type} is not a file-only type"),
+    }
+}
+
+/// Converts data of a [file-only](ggml::Type::is_file_only) type to `loaded_type`, as
+/// returned by [loaded_element_type].
+///
+/// Returns `None` if the values do not fit in `loaded_type`: `bf16` has the range of `f32`,
+/// so values beyond [half::f16::MAX] would otherwise become infinite in `f16`.
+pub(crate) fn convert_file_only(
+    data: &[u8],
+    element_type: ggml::Type,
+    loaded_type: ggml::Type,
+) -> Option<Vec<u8>> {
+    let values = dequantize_file_only(data, element_type);
+    Some(match loaded_type {
+        ggml::Type::F32 => values.into_iter().flat_map(f32::to_le_bytes).collect(),
+        ggml::Type::F16 => {
+            let max = half::f16::MAX.to_f32();
+            if values.iter().any(|v| v.is_finite() && v.abs() > max) {
+                return None;
+            }
+            values
+                .into_iter()
+                .flat_map(|v| half::f16::from_f32(v).to_le_bytes())
+                .collect()
+        }
+        ggml::Type::Q8_0 => ggml::quantize_q8_0(&values, values.len(), values.len()).output,
+        _ => unreachable!("{element_type} is not converted to {loaded_type}"),
+    })
+}
+
+/// Converts data of a [file-only](ggml::Type::is_file_only) type like [convert_file_only],
+/// but to `f32` if its values do not fit in the type given by [loaded_element_type].
+/// Returns the type of the converted data.
+pub(crate) fn convert_file_only_to_fit(
+    data: &[u8],
+    element_type: ggml::Type,
+    n_dims: usize,
+) -> (ggml::Type, Vec<u8>) {
+    let loaded_type = loaded_element_type(element_type, n_dims);
+    match convert_file_only(data, element_type, loaded_type) {
+        Some(data) => (loaded_type, data),
+        None => (
+            ggml::Type::F32,
+            convert_file_only(data, element_type, ggml::Type::F32)
+                .expect("every value fits in f32"),
+        ),
+    }
+}
+
 /// A implementation for `load_progress_callback` that outputs to `stdout`.
 pub fn load_progress_callback_stdout(progress: LoadProgress) {
     match progress {
@@ -774,12 +1545,146 @@ pub fn load_progress_callback_stdout(progress: LoadProgress) {
                 tensor_count
             );
         }
+        LoadProgress::GpuSelected { platform, device } => {
+            println!("Offloading to the GPU {device} ({platform})")
+        }
         LoadProgress::LoraApplied { name, source } => {
             println!(
                 "Patched tensor {} via LoRA from '{}'",
                 name,
-                source.file_name().unwrap().to_str().unwrap()
+                source.file_name().unwrap_or_default().to_string_lossy()
             );
         }
+        LoadProgress::ShardLoaded {
+            path,
+            index,
+            shard_count,
+        } => println!("Read shard {}/{shard_count} ({path:?})", index + 1),
+        LoadProgress::Verifying { bytes, total_bytes } => {
+            if bytes == total_bytes {
+                println!("Verified the model checksum");
+            }
+        }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bf16_is_converted_to_a_type_ggml_can_use() {
+        assert_eq!(loaded_element_type(ggml::Type::BF16, 1), ggml::Type::F32);
+        assert_eq!(loaded_element_type(ggml::Type::BF16, 2), ggml::Type::F16);
+        assert_eq!(loaded_element_type(ggml::Type::Q4_2, 2), ggml::Type::Q8_0);
+        assert_eq!(loaded_element_type(ggml::Type::Q4_0, 2), ggml::Type::Q4_0);
+
+        // 1.0 and -2.5 in bf16.
+        let data = [0x80, 0x3f, 0x20, 0xc0];
+        assert_eq!(
+            convert_file_only(&data, ggml::Type::BF16, ggml::Type::F32),
+            Some([1.0f32.to_le_bytes(), (-2.5f32).to_le_bytes()].concat())
+        );
+        assert_eq!(
+            convert_file_only(&data, ggml::Type::BF16, ggml::Type::F16),
+            Some(
+                [
+                    half::f16::from_f32(1.0).to_le_bytes(),
+                    half::f16::from_f32(-2.5).to_le_bytes()
+                ]
+                .concat()
+            )
+        );
+    }
+
+    #[test]
+    fn bf16_beyond_the_range_of_f16_is_not_made_infinite() {
+        // 1.0 and 1e5 in bf16; the latter is beyond the range of f16.
+        let data = [
+            half::bf16::from_f32(1.0).to_le_bytes(),
+            half::bf16::from_f32(1e5).to_le_bytes(),
+        ]
+        .concat();
+        assert_eq!(
+            convert_file_only(&data, ggml::Type::BF16, ggml::Type::F16),
+            None
+        );
+
+        let (element_type, converted) = convert_file_only_to_fit(&data, ggml::Type::BF16, 2);
+        assert_eq!(element_type, ggml::Type::F32);
+        let values: Vec<f32> = converted
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, [1.0, half::bf16::from_f32(1e5).to_f32()]);
+
+        // Infinities stay infinite, so they do not need `f32`.
+        let data = half::bf16::INFINITY.to_le_bytes();
+        assert_eq!(
+            convert_file_only_to_fit(&data, ggml::Type::BF16, 2).0,
+            ggml::Type::F16
+        );
+    }
+
+    #[test]
+    fn load_guard_reports_the_stage_that_timed_out() {
+        let mut params = ModelParameters {
+            load_timeout: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        assert!(LoadGuard::new(&params).check(LoadStage::Mapping).is_ok());
+
+        params.load_timeout = Some(Duration::ZERO);
+        let guard = LoadGuard::new(&params);
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(matches!(
+            guard.check(LoadStage::LoadingTensors),
+            Err(LoadError::TimedOut {
+                stage: LoadStage::LoadingTensors,
+                ..
+            })
+        ));
+
+        let token = CancellationToken::new();
+        token.cancel();
+        params.cancellation_token = Some(token);
+        assert!(matches!(
+            LoadGuard::new(&params).check(LoadStage::Verifying),
+            Err(LoadError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn override_globs_match_whole_names() {
+        let glob = glob_regex("layers.*.attention.wq.weight");
+        assert!(glob.is_match("layers.0.attention.wq.weight"));
+        assert!(glob.is_match("layers.31.attention.wq.weight"));
+        assert!(!glob.is_match("layers.0.attention.wq.weight.bias"));
+        assert!(!glob.is_match("layers_0_attention_wq_weight"));
+
+        let glob = glob_regex("output.weigh?");
+        assert!(glob.is_match("output.weight"));
+        assert!(!glob.is_match("output.weights"));
+    }
+
+    #[test]
+    fn broken_attention_hyperparameters_are_rejected() {
+        let path = Path::new("model.bin");
+        assert!(validate_attention(path, 4096, 32, Some(128)).is_ok());
+        assert!(validate_attention(path, 768, 12, None).is_ok());
+        assert!(matches!(
+            validate_attention(path, 4096, 30, None),
+            Err(LoadError::AttentionHeadsMismatch { n_head: 30, .. })
+        ));
+        assert!(matches!(
+            validate_attention(path, 4096, 0, None),
+            Err(LoadError::AttentionHeadsMismatch { .. })
+        ));
+        for n_rot in [0, 63, 256] {
+            assert!(matches!(
+                validate_attention(path, 4096, 32, Some(n_rot)),
+                Err(LoadError::InvalidRotaryDimensions { head_size: 128, .. })
+            ));
+        }
+    }
+}
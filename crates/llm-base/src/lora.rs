@@ -1,14 +1,19 @@
 use crate::{
-    loader::FileContext, model::HyperparametersWriteError, util, FileType, Hyperparameters,
-    LoadError,
+    loader::FileContext,
+    memory::{self, MemoryKind},
+    model::HyperparametersWriteError,
+    util, FileType, Hyperparameters, LoadError, Loader, Tokenizer,
 };
 
 use ggml::format::TensorLoadInfo;
 use std::{
     collections::{HashMap, HashSet},
     fs::File,
-    path::PathBuf,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
+use thiserror::Error;
 
 #[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
 /// Parameters for a [LoRA](https://arxiv.org/abs/2106.09685) adapter.
@@ -148,3 +153,123 @@ impl LoraAdapter {
             })
     }
 }
+
+/// A [LoRA](https://arxiv.org/abs/2106.09685) adapter that is applied to individual
+/// [InferenceSession](crate::InferenceSession)s, rather than patched into the weights of
+/// the model when it is loaded.
+///
+/// The adapter's low-rank matrices are kept separate from the model, and their contribution
+/// is computed during each matrix multiplication (`y = Wx + s * B(Ax)`). This lets many
+/// sessions share one copy of the model's weights while each uses a different adapter
+/// (e.g. one per persona), at the cost of some extra computation per token.
+///
+/// Use [InferenceSession::set_lora](crate::InferenceSession::set_lora) to apply an adapter
+/// to a session.
+pub struct SessionLora {
+    scaling: f32,
+    path: PathBuf,
+    /// The transposed `A` and the `B` matrices, keyed by the name of the tensor they patch.
+    pub(crate) tensors: HashMap<String, (ggml::Tensor, ggml::Tensor)>,
+    // Must be kept alive for the tensors.
+    #[cfg_attr(not(feature = "metal"), allow(dead_code))]
+    pub(crate) context: Arc<ggml::Context>,
+}
+unsafe impl Send for SessionLora {}
+unsafe impl Sync for SessionLora {}
+impl SessionLora {
+    /// Loads the LoRA adapter at `path`.
+    pub fn load(path: &Path) -> Result<Self, LoadError> {
+        let mut file =
+            File::open(crate::util::long_path(path)).map_err(|e| LoadError::OpenFileFailed {
+                source: e,
+                path: path.to_owned(),
+            })?;
+        let mut reader = BufReader::new(&file);
+        let mut loader: Loader<LoraParameters, _> =
+            Loader::new(Tokenizer::empty_embedded(), |_| {});
+        ggml::format::load(&mut reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+        let get_info = |name: String| {
+            loader
+                .tensors
+                .get(&name)
+                .cloned()
+                .ok_or(LoadError::UnknownTensor {
+                    path: path.to_owned(),
+                    tensor_name: name,
+                })
+        };
+        let targets = loader
+            .tensors
+            .keys()
+            .filter_map(|name| name.strip_suffix(".loraA"))
+            .map(|name| {
+                Ok((
+                    name.to_owned(),
+                    get_info(format!("{name}.loraA"))?,
+                    get_info(format!("{name}.loraB"))?,
+                ))
+            })
+            .collect::<Result<Vec<_>, LoadError>>()?;
+
+        // Each `A` matrix is stored twice: as loaded, and transposed.
+        let mut context_size = targets
+            .iter()
+            .map(|(_, a, b)| 2 * a.calc_absolute_size(false) + b.calc_absolute_size(false))
+            .sum::<usize>();
+        // Add 5% as ggml overhead, as in [LoraAdapter::patch].
+        context_size += context_size / 20;
+
+        let context = ggml::Context::init(context_size, true);
+        memory::track(&context, MemoryKind::ModelWeights, context_size);
+
+        let mut file_context = FileContext::new(&context, &mut file, path, None);
+        let mut graph = ggml::ComputationGraph::new(1);
+        let mut tensors = HashMap::with_capacity(targets.len());
+        for (name, a_info, b_info) in targets {
+            let a = file_context.get_tensor(&a_info)?;
+            let b = file_context.get_tensor(&b_info)?;
+
+            // `A` is transposed ahead of time so that `Ax` is a single matrix multiplication.
+            let a_t = context.op_cont(&context.op_transpose(&a));
+            graph.build_forward_expand(&a_t);
+            tensors.insert(name, (a_t, b));
+        }
+        context.graph_compute(&mut graph);
+
+        Ok(Self {
+            scaling: loader.hyperparameters.calculate_scaling(),
+            path: path.to_owned(),
+            tensors,
+            context: Arc::new(context),
+        })
+    }
+
+    /// The path the adapter was loaded from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The scaling applied to the adapter's contribution.
+    pub fn scaling(&self) -> f32 {
+        self.scaling
+    }
+}
+
+/// Errors encountered when applying a [SessionLora] to a session.
+#[derive(Error, Debug)]
+pub enum SessionLoraError {
+    /// The adapter patches a tensor that the model does not have.
+    #[error("the adapter patches the tensor {tensor_name}, which the model does not have")]
+    UnknownTensor {
+        /// The name of the tensor.
+        tensor_name: String,
+    },
+    /// The shape of the adapter's matrices does not match the tensor they patch.
+    #[error("the adapter's matrices for the tensor {tensor_name} do not match its shape")]
+    ShapeMismatch {
+        /// The name of the tensor.
+        tensor_name: String,
+    },
+}
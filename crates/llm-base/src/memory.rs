@@ -0,0 +1,215 @@
+//! Process-wide accounting of the memory used by loaded models and inference sessions.
+//!
+//! Every model and [InferenceSession](crate::InferenceSession) registers the memory it
+//! allocates here, so that applications hosting several models can see how much memory
+//! is in use with [usage], and can set a global cap with [set_limit]. When a cap is set,
+//! new sessions that would exceed it fail with [MemoryLimitExceeded] instead of
+//! allocating and risking the process being killed by the operating system.
+use std::{
+    ptr::NonNull,
+    sync::{Mutex, Weak},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The kinds of memory that are accounted for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MemoryKind {
+    /// The weights of a model. For memory-mapped models, this is the size of the mapping.
+    ModelWeights,
+    /// The key/value memory of a session.
+    KvCache,
+    /// The scratch and evaluation buffers of a session.
+    Scratch,
+}
+
+/// A snapshot of the memory in use by all loaded models and live sessions, in bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The memory used by model weights. Memory-mapped models count the size of their
+    /// mapping, even though the operating system may not have paged all of it in.
+    pub model_weights: usize,
+    /// The memory used by the key/value caches of inference sessions.
+    pub kv_cache: usize,
+    /// The memory used by the scratch and evaluation buffers of inference sessions.
+    pub scratch: usize,
+}
+impl MemoryUsage {
+    /// The total memory in use, in bytes.
+    pub fn total(&self) -> usize {
+        self.model_weights + self.kv_cache + self.scratch
+    }
+
+    fn add(&mut self, kind: MemoryKind, bytes: usize) {
+        match kind {
+            MemoryKind::ModelWeights => self.model_weights += bytes,
+            MemoryKind::KvCache => self.kv_cache += bytes,
+            MemoryKind::Scratch => self.scratch += bytes,
+        }
+    }
+}
+
+/// Returned when allocating a new session would exceed the limit set with [set_limit].
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("allocating {requested} bytes would exceed the memory limit of {limit} bytes ({in_use} bytes in use)")]
+pub struct MemoryLimitExceeded {
+    /// The number of bytes that were requested.
+    pub requested: usize,
+    /// The number of bytes in use at the time of the request.
+    pub in_use: usize,
+    /// The limit that would have been exceeded.
+    pub limit: usize,
+}
+
+/// Returns the memory currently in use by all loaded models and live sessions.
+pub fn usage() -> MemoryUsage {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.prune();
+    registry.usage()
+}
+
+/// Sets the maximum amount of memory, in bytes, that models and sessions may use in total.
+/// `None` removes the limit, which is the default.
+///
+/// The limit is checked when sessions are started; sessions that would exceed it fail
+/// with [MemoryLimitExceeded]. Loading a model is never refused, but the model's weights
+/// count towards the limit for subsequent sessions.
+pub fn set_limit(limit: Option<usize>) {
+    REGISTRY.lock().unwrap().limit = limit;
+}
+
+/// Returns the limit set with [set_limit], if any.
+pub fn limit() -> Option<usize> {
+    REGISTRY.lock().unwrap().limit
+}
+
+/// Accounts for the memory owned by the ggml context `owner` until it is freed.
+///
+/// The context is tracked through a weak reference, so the memory is considered released
+/// once the context and every tensor referring to it have been dropped.
+pub(crate) fn track(owner: &ggml::Context, kind: MemoryKind, bytes: usize) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.prune();
+    registry.allocations.push(Allocation {
+        owner: std::sync::Arc::downgrade(&owner.ptr),
+        kind,
+        bytes,
+    });
+}
+
+/// Checks that `requested` more bytes can be allocated without exceeding the limit.
+///
+/// The memory is reserved until the returned [Reservation] is dropped, so that concurrent
+/// callers cannot both pass the check and overshoot the limit together.
+pub(crate) fn reserve(
+    allocations: &[(MemoryKind, usize)],
+) -> Result<Reservation, MemoryLimitExceeded> {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.prune();
+
+    let requested: usize = allocations.iter().map(|(_, bytes)| bytes).sum();
+    let in_use = registry.usage().total();
+    if let Some(limit) = registry.limit {
+        if in_use + requested > limit {
+            return Err(MemoryLimitExceeded {
+                requested,
+                in_use,
+                limit,
+            });
+        }
+    }
+
+    for &(kind, bytes) in allocations {
+        registry.reserved.add(kind, bytes);
+    }
+    Ok(Reservation {
+        allocations: allocations.to_vec(),
+    })
+}
+
+/// Memory reserved with [reserve]. The reservation is released when this is dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Reservation {
+    allocations: Vec<(MemoryKind, usize)>,
+}
+impl Reservation {
+    /// The memory that is reserved.
+    pub(crate) fn allocations(&self) -> &[(MemoryKind, usize)] {
+        &self.allocations
+    }
+}
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock().unwrap();
+        for &(kind, bytes) in &self.allocations {
+            let reserved = &mut registry.reserved;
+            match kind {
+                MemoryKind::ModelWeights => reserved.model_weights -= bytes,
+                MemoryKind::KvCache => reserved.kv_cache -= bytes,
+                MemoryKind::Scratch => reserved.scratch -= bytes,
+            }
+        }
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    allocations: Vec::new(),
+    reserved: MemoryUsage {
+        model_weights: 0,
+        kv_cache: 0,
+        scratch: 0,
+    },
+    limit: None,
+});
+
+struct Registry {
+    /// Memory owned by ggml contexts, released when the context is freed.
+    allocations: Vec<Allocation>,
+    /// Memory held by live [Reservation]s.
+    reserved: MemoryUsage,
+    limit: Option<usize>,
+}
+impl Registry {
+    fn prune(&mut self) {
+        self.allocations
+            .retain(|allocation| allocation.owner.strong_count() > 0);
+    }
+
+    fn usage(&self) -> MemoryUsage {
+        let mut usage = self.reserved;
+        for allocation in &self.allocations {
+            usage.add(allocation.kind, allocation.bytes);
+        }
+        usage
+    }
+}
+
+struct Allocation {
+    owner: Weak<NonNull<ggml::sys::ggml_context>>,
+    kind: MemoryKind,
+    bytes: usize,
+}
+// SAFETY: the context pointer is only used to check whether the context is still alive,
+// and is never dereferenced.
+unsafe impl Send for Allocation {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_respect_the_limit() {
+        let in_use = usage().total();
+        set_limit(Some(in_use + 100));
+
+        let reservation = reserve(&[(MemoryKind::KvCache, 60)]).unwrap();
+        let err = reserve(&[(MemoryKind::Scratch, 60)]).unwrap_err();
+        assert_eq!(err.requested, 60);
+        assert_eq!(err.in_use, in_use + 60);
+
+        drop(reservation);
+        assert!(reserve(&[(MemoryKind::Scratch, 60)]).is_ok());
+        set_limit(None);
+    }
+}
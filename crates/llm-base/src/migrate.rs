@@ -0,0 +1,163 @@
+//! Implements the migration of model files from older versions of the GGML formats.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Seek, Write},
+    path::PathBuf,
+};
+
+use ggml::format::{SaveContainerType, SaveHandler, TensorLoadInfo, TensorSaveInfo};
+
+use crate::{
+    loader::convert_file_only_to_fit, FileTypeFormat, Hyperparameters, KnownModel, LoadError,
+    Loader, QuantizeError, Tokenizer,
+};
+
+/// Progress of a migration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MigrateProgress<'a> {
+    /// Hyperparameters have been loaded.
+    HyperparametersLoaded,
+    /// A tensor has been written.
+    TensorMigrated {
+        /// Name of the tensor.
+        name: &'a str,
+        /// Whether the tensor was in a legacy layout, and was upgraded.
+        upgraded: bool,
+        /// Whether the tensor was in a type that has been removed from `ggml`, and was
+        /// requantized.
+        requantized: bool,
+    },
+    /// The model has been migrated.
+    Finished {
+        /// The number of tensors that were upgraded.
+        upgraded_tensors: usize,
+        /// The number of tensors that were requantized.
+        requantized_tensors: usize,
+        /// The number of tensors in the model.
+        tensor_count: usize,
+    },
+}
+
+/// Rewrites the model in `reader` to `writer` in the GGJT v3 format, with the current
+/// quantization version.
+///
+/// Quantized tensors in the layouts of older versions (see [ggml::legacy]) are upgraded
+/// without being requantized. Tensors in the `Q4_2` and `Q4_3` types, which were removed
+/// from `ggml`, are requantized to `Q8_0`, which keeps their values almost exactly.
+/// Everything else, including the hyperparameters and the vocabulary, is kept as is.
+/// Models that are already current can be migrated too, which only changes their container.
+pub fn migrate<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    mut progress_callback: impl FnMut(MigrateProgress),
+) -> Result<(), QuantizeError> {
+    let mut loader = Loader::<M::Hyperparameters, _>::new(Tokenizer::empty_embedded(), |_| {});
+    ggml::format::load(reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, PathBuf::default()))?;
+    progress_callback(MigrateProgress::HyperparametersLoaded);
+
+    let Loader {
+        mut hyperparameters,
+        tokenizer,
+        tensors,
+        ..
+    } = loader;
+    if let Some(ft) = hyperparameters.file_type_mut() {
+        ft.quantization_version = ggml::QNT_VERSION;
+        if matches!(
+            ft.format,
+            FileTypeFormat::MostlyQ4_2 | FileTypeFormat::MostlyQ4_3
+        ) {
+            ft.format = FileTypeFormat::MostlyQ8_0;
+        }
+    }
+    let tokenizer = match tokenizer {
+        Tokenizer::Embedded(v) => v.iter().collect::<Vec<_>>(),
+        Tokenizer::HuggingFace(_) => vec![],
+    };
+
+    // Tensors are written in the order they were in.
+    let mut tensor_names: Vec<_> = tensors.values().collect();
+    tensor_names.sort_by_key(|tensor| tensor.start_offset);
+    let tensor_names: Vec<String> = tensor_names.iter().map(|t| t.name.clone()).collect();
+
+    let mut saver = MigrateSaver {
+        hyperparameters: &hyperparameters,
+        tensors: &tensors,
+        source_reader: reader,
+        progress_callback: &mut progress_callback,
+        upgraded_tensors: 0,
+        requantized_tensors: 0,
+    };
+    ggml::format::save(
+        writer,
+        &mut saver,
+        SaveContainerType::GgjtV3,
+        &tokenizer,
+        &tensor_names,
+    )
+    .map_err(|err| QuantizeError::from_format_error(err, PathBuf::default()))?;
+
+    let upgraded_tensors = saver.upgraded_tensors;
+    let requantized_tensors = saver.requantized_tensors;
+    progress_callback(MigrateProgress::Finished {
+        upgraded_tensors,
+        requantized_tensors,
+        tensor_count: tensors.len(),
+    });
+    Ok(())
+}
+
+struct MigrateSaver<'a, H: Hyperparameters, R: BufRead + Seek, F: FnMut(MigrateProgress)> {
+    hyperparameters: &'a H,
+    tensors: &'a HashMap<String, TensorLoadInfo>,
+    source_reader: &'a mut R,
+    progress_callback: &'a mut F,
+    upgraded_tensors: usize,
+    requantized_tensors: usize,
+}
+impl<H: Hyperparameters, R: BufRead + Seek, F: FnMut(MigrateProgress)> SaveHandler<QuantizeError>
+    for MigrateSaver<'_, H, R, F>
+{
+    fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), QuantizeError> {
+        self.hyperparameters
+            .write_ggml(writer)
+            .map_err(QuantizeError::HyperparametersWriteError)
+    }
+
+    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, QuantizeError> {
+        let tensor = self.tensors.get(tensor_name).expect(
+            "tensor not found; should be impossible due to handler being populated from loader",
+        );
+
+        let data = tensor.read_data(self.source_reader)?;
+        let upgraded = tensor.is_legacy();
+        let requantized = matches!(tensor.element_type, ggml::Type::Q4_2 | ggml::Type::Q4_3);
+        let mut element_type = tensor.element_type;
+        let data = if upgraded {
+            self.upgraded_tensors += 1;
+            ggml::legacy::upgrade(tensor.element_type, tensor.quantization_version, &data)
+        } else if requantized {
+            self.requantized_tensors += 1;
+            let (converted_type, converted) =
+                convert_file_only_to_fit(&data, tensor.element_type, tensor.n_dims);
+            element_type = converted_type;
+            converted
+        } else {
+            data
+        };
+        (self.progress_callback)(MigrateProgress::TensorMigrated {
+            name: tensor_name,
+            upgraded,
+            requantized,
+        });
+
+        Ok(TensorSaveInfo {
+            n_dims: tensor.n_dims,
+            dims: tensor.dims,
+            element_type,
+            data,
+        })
+    }
+}
@@ -1,6 +1,46 @@
+use std::sync::Arc;
+
 use ggml::Tensor;
 
-use crate::{InferenceSession, OutputRequest};
+use crate::{
+    diagnostics::{Diagnostic, Diagnostics},
+    model::EmbeddingTensors,
+    ContextSize, InferenceSession, KnownModel, ModelParameters, OutputRequest, TensorLoader,
+};
+
+/// Loads the output weights named by `names`, or returns `None` if they are tied to the
+/// token embeddings (see [EmbeddingTensors::are_tied]). A model file without output
+/// weights is reported to `diagnostics`.
+pub fn load_output<E: std::error::Error>(
+    tensor_loader: &mut impl TensorLoader<E>,
+    names: EmbeddingTensors,
+    diagnostics: &Diagnostics,
+) -> Result<Option<Tensor>, E> {
+    match names.output {
+        Some(output) if tensor_loader.contains(output) => tensor_loader.load(output).map(Some),
+        Some(output) => {
+            diagnostics.emit(Diagnostic::TensorFallback {
+                tensor_name: output.to_owned(),
+                fallback_name: names.input.to_owned(),
+            });
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Returns the context size in [ModelParameters] for a model with `n_layer` layers of
+/// `n_embd` elements. A size chosen to fit a memory budget is reported to the diagnostics.
+pub fn context_size(params: &ModelParameters, n_layer: usize, n_embd: usize) -> usize {
+    let context_size = params.context_size.tokens(n_layer, n_embd);
+    if let ContextSize::Auto { max_memory_bytes } = params.context_size {
+        params.diagnostics.emit(Diagnostic::ContextSizeChosen {
+            context_size,
+            max_memory_bytes,
+        });
+    }
+    context_size
+}
 
 /// Return result for just the last token
 pub fn read_last_token(
@@ -57,3 +97,55 @@ pub fn extract_embeddings(
         embeddings.copy_from_slice(&all_embeddings[n_embd * (n - 1)..]);
     }
 }
+
+/// Checks that the weights in `context` are not referenced by anything other than the
+/// model being dropped. Call this from the model's [Drop] implementation.
+///
+/// Lingering references (e.g. from a session that outlives its model) keep the weights,
+/// and any memory mapping, alive. This is reported to `diagnostics`; the weights are freed
+/// when the last reference is dropped.
+pub fn check_weights_released(context: &Arc<ggml::Context>, diagnostics: &Diagnostics) {
+    let references = Arc::strong_count(context);
+    if references <= 1 {
+        return;
+    }
+
+    diagnostics.emit(Diagnostic::WeightsStillReferenced {
+        references: references - 1,
+    });
+}
+
+/// Implementation of [KnownModel::close].
+pub(crate) fn close<M: KnownModel>(model: M) -> bool {
+    let weights = model.weights_context().map(Arc::downgrade);
+    drop(model);
+    weights.map_or(true, |weights| weights.strong_count() == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn lingering_weights_are_reported_without_panicking() {
+        let reported = Arc::new(Mutex::new(vec![]));
+        let diagnostics = Diagnostics::new({
+            let reported = reported.clone();
+            move |diagnostic| reported.lock().unwrap().push(diagnostic.clone())
+        });
+        let context = Arc::new(ggml::Context::init(1024, false));
+
+        check_weights_released(&context, &diagnostics);
+        assert!(reported.lock().unwrap().is_empty());
+
+        let session_reference = context.clone();
+        check_weights_released(&context, &diagnostics);
+        assert!(matches!(
+            reported.lock().unwrap()[..],
+            [Diagnostic::WeightsStillReferenced { references: 1 }]
+        ));
+        drop(session_reference);
+    }
+}
@@ -1,18 +1,29 @@
 //! Large language model traits and types
 
 use std::{
+    any::Any,
     error::Error,
     fmt::Debug,
     io::{BufRead, Write},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 use regex::Regex;
 use thiserror::Error;
 
 use crate::{
-    loader::TensorLoader, tokenizer::TokenId, FileType, InferenceParameters, InferenceSession,
-    InferenceSessionConfig, LoadError, LoadProgress, Tokenizer, TokenizerSource,
+    cancellation::CancellationToken,
+    convert::HfConverter,
+    diagnostics::Diagnostics,
+    inference_session::{kv_cache_size, KvLayout},
+    loader::TensorLoader,
+    memory::MemoryLimitExceeded,
+    placement::{PlacementPolicy, PlacementReport},
+    tokenizer::TokenId,
+    FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, LoadError,
+    LoadProgress, Tokenizer, TokenizerSource,
 };
 
 /// Common functions for model evaluation
@@ -50,8 +61,21 @@ pub trait KnownModel: Send + Sync {
     where
         Self: Sized;
 
+    /// Starts a new `InferenceSession` for this model, or returns an error if it would
+    /// exceed the [memory limit](crate::memory::set_limit).
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, MemoryLimitExceeded>;
+
     /// Starts a new `InferenceSession` for this model.
-    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession;
+    ///
+    /// # Panics
+    /// Panics if the session would exceed the [memory limit](crate::memory::set_limit).
+    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
+        self.try_start_session(config)
+            .unwrap_or_else(|err| panic!("failed to start session: {err}"))
+    }
 
     /// This function is called by the provided [InferenceSession]; it will use this model
     /// and the [InferenceParameters] to generate output by evaluating the `input_tokens`.
@@ -72,7 +96,7 @@ pub trait KnownModel: Send + Sync {
     fn tokenizer(&self) -> &Tokenizer;
 
     /// Get the context size (configured with [ModelParameters::context_size]) used by
-    /// this model.
+    /// this model. Sessions may use a smaller one (see [InferenceSession::context_size]).
     fn context_size(&self) -> usize;
 
     /// Get the beginning of text/beginning of string token ID, if available. This value is defined by model implementers.
@@ -81,24 +105,118 @@ pub trait KnownModel: Send + Sync {
     /// Get the end of text/end of string token ID. This value is defined by model implementers.
     fn eot_token_id(&self) -> TokenId;
 
+    /// Get the IDs of all of the tokens that end generation: [Self::eot_token_id], and the
+    /// other end-of-text and end-of-turn tokens of the vocabulary, such as `<|im_end|>`
+    /// (see [Tokenizer::end_token_ids]).
+    fn stop_token_ids(&self) -> Vec<TokenId> {
+        let mut ids = vec![self.eot_token_id()];
+        for id in self.tokenizer().end_token_ids() {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        ids
+    }
+
     /// Get the list of regexes to use to determine if a tensor in this model should be quantized.
     fn quantize_tensors() -> Vec<Regex>;
 
     /// Get the list of regexes to use to determine if a tensor in this model should not be quantized.
     fn skip_quantize_tensors() -> Vec<Regex>;
 
+    /// Returns the names of the tensors holding the token embeddings and output weights.
+    fn embedding_tensors() -> EmbeddingTensors;
+
+    /// Returns the names of tensors that, together, only model files of this architecture
+    /// have. They are used to recognize the architecture of a model file, which GGML files
+    /// do not record; see [compatibility](crate::compatibility).
+    fn distinctive_tensors() -> Vec<&'static str>;
+
     /// Returns whether the model supports deleting tokens.
     fn supports_rewind(&self) -> bool {
         // Assume we can't delete unless otherwise specified
         false
     }
+
+    /// Returns how the model lays out its key/value memory, if its entries can be moved
+    /// for [InferenceSessionConfig::kv_eviction] and
+    /// [InferenceSessionConfig::context_overflow]. This requires the model to use
+    /// [InferenceSession::position] for its positional encoding.
+    fn kv_layout(&self) -> Option<KvLayout> {
+        None
+    }
+
+    /// Returns the [Diagnostics] the model was loaded with
+    /// ([ModelParameters::diagnostics]), to which its sessions also report.
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        None
+    }
+
+    /// Returns the weight tensor named `name` in the model file, if the model has it.
+    ///
+    /// This is used to match a [SessionLora](crate::SessionLora) against the model.
+    fn tensor(&self, _name: &str) -> Option<&ggml::Tensor> {
+        None
+    }
+
+    /// Returns all of the weight tensors of the model, with their names in the model file.
+    fn tensors(&self) -> Vec<(&str, &ggml::Tensor)> {
+        vec![]
+    }
+
+    /// Returns the dimensions and quantization of the model.
+    fn architecture_info(&self) -> ArchitectureInfo;
+
+    /// Returns where each layer of the model is stored, and in which formats.
+    fn placement_report(&self) -> PlacementReport {
+        PlacementReport::from_tensors(self.tensors())
+    }
+
+    /// Returns the converter used by [convert_hf](crate::convert::convert_hf) to convert
+    /// Hugging Face checkpoints to this model, or `None` if conversion is not supported.
+    fn hf_converter() -> Option<Box<dyn HfConverter<Self::Hyperparameters>>> {
+        None
+    }
+
+    /// Returns the context holding this model's weights, if it has one.
+    ///
+    /// This is used by [KnownModel::close] to check whether the weights were freed.
+    fn weights_context(&self) -> Option<&Arc<ggml::Context>> {
+        None
+    }
+
+    /// Drops the model, returning whether its weights were actually freed (and unmapped,
+    /// for memory-mapped models).
+    ///
+    /// The weights stay alive for as long as anything else references them; for example,
+    /// a Metal-accelerated [InferenceSession] keeps the weights of its model alive until
+    /// the session is dropped.
+    fn close(self) -> bool
+    where
+        Self: Sized,
+    {
+        common::close(self)
+    }
 }
 
 /// A type-erased model to allow for interacting with a model without knowing
 /// its hyperparameters.
 pub trait Model: Send + Sync {
+    /// Starts a new `InferenceSession` for this model, or returns an error if it would
+    /// exceed the [memory limit](crate::memory::set_limit).
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, MemoryLimitExceeded>;
+
     /// Starts a new `InferenceSession` for this model.
-    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession;
+    ///
+    /// # Panics
+    /// Panics if the session would exceed the [memory limit](crate::memory::set_limit).
+    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
+        self.try_start_session(config)
+            .unwrap_or_else(|err| panic!("failed to start session: {err}"))
+    }
 
     /// This function is called by the provided [InferenceSession]; it will use this model
     /// and the [InferenceParameters] to generate output by evaluating the `input_tokens`.
@@ -116,7 +234,7 @@ pub trait Model: Send + Sync {
     fn tokenizer(&self) -> &Tokenizer;
 
     /// Get the context size (configured with [ModelParameters::context_size]) used by
-    /// this model.
+    /// this model. Sessions may use a smaller one (see [InferenceSession::context_size]).
     fn context_size(&self) -> usize;
 
     /// Get the beginning of text/beginning of string token ID, if available. This value is defined by model implementers.
@@ -125,10 +243,67 @@ pub trait Model: Send + Sync {
     /// Get the end of text/end of string token ID. This value is defined by model implementers.
     fn eot_token_id(&self) -> TokenId;
 
+    /// Get the IDs of all of the tokens that end generation. See [KnownModel::stop_token_ids].
+    fn stop_token_ids(&self) -> Vec<TokenId>;
+
     /// Returns whether the model supports deleting tokens.
     fn supports_rewind(&self) -> bool;
+
+    /// Returns how the model lays out its key/value memory, if its entries can be moved.
+    fn kv_layout(&self) -> Option<KvLayout>;
+
+    /// Returns the [Diagnostics] the model was loaded with, if it keeps them.
+    fn diagnostics(&self) -> Option<&Diagnostics>;
+
+    /// Returns the weight tensor named `name` in the model file, if the model has it.
+    fn tensor(&self, name: &str) -> Option<&ggml::Tensor>;
+
+    /// Returns the dimensions and quantization of the model.
+    fn architecture_info(&self) -> ArchitectureInfo;
+
+    /// Returns where each layer of the model is stored, and in which formats.
+    fn placement_report(&self) -> PlacementReport;
+
+    /// Drops the model, returning whether its weights were actually freed.
+    /// See [KnownModel::close].
+    fn close(self: Box<Self>) -> bool;
+
+    /// Returns the model as [Any], so that it can be downcast to its concrete type.
+    /// `downcast_ref` and `downcast` on `dyn Model` are usually more convenient.
+    fn as_any(&self) -> &dyn Any;
+
+    /// Converts the model into [Any], so that it can be downcast to its concrete type.
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send + Sync>;
+}
+impl<'a> dyn Model + 'a {
+    /// Returns whether the model is a `M`.
+    pub fn is<M: KnownModel + 'static>(&self) -> bool {
+        self.as_any().is::<M>()
+    }
+
+    /// Returns the model as a `M`, if it is one. This gives access to the APIs of a
+    /// specific architecture for models loaded without knowing their type.
+    pub fn downcast_ref<M: KnownModel + 'static>(&self) -> Option<&M> {
+        self.as_any().downcast_ref()
+    }
+
+    /// Converts the model into a `M` if it is one, or returns it unchanged otherwise.
+    pub fn downcast<M: KnownModel + 'static>(self: Box<Self>) -> Result<Box<M>, Box<Self>> {
+        if self.is::<M>() {
+            Ok(self.into_any().downcast().unwrap())
+        } else {
+            Err(self)
+        }
+    }
 }
-impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
+impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H> + 'static> Model for M {
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, MemoryLimitExceeded> {
+        KnownModel::try_start_session(self, config)
+    }
+
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         KnownModel::start_session(self, config)
     }
@@ -159,9 +334,82 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
         KnownModel::eot_token_id(self)
     }
 
+    fn stop_token_ids(&self) -> Vec<TokenId> {
+        KnownModel::stop_token_ids(self)
+    }
+
     fn supports_rewind(&self) -> bool {
         KnownModel::supports_rewind(self)
     }
+
+    fn kv_layout(&self) -> Option<KvLayout> {
+        KnownModel::kv_layout(self)
+    }
+
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        KnownModel::diagnostics(self)
+    }
+
+    fn tensor(&self, name: &str) -> Option<&ggml::Tensor> {
+        KnownModel::tensor(self, name)
+    }
+
+    fn architecture_info(&self) -> ArchitectureInfo {
+        KnownModel::architecture_info(self)
+    }
+
+    fn placement_report(&self) -> PlacementReport {
+        KnownModel::placement_report(self)
+    }
+
+    fn close(self: Box<Self>) -> bool {
+        KnownModel::close(*self)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+/// The dimensions and quantization of a model, as returned by [Model::architecture_info].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchitectureInfo {
+    /// The number of layers.
+    pub n_layer: usize,
+    /// The number of attention heads in each layer.
+    pub n_head: usize,
+    /// The size of the embeddings.
+    pub n_embd: usize,
+    /// The context size the model was trained with, if recorded in the model file.
+    pub n_ctx_train: Option<usize>,
+    /// The number of tokens in the model's vocabulary.
+    pub vocab_size: usize,
+    /// The quantization of the model's weights, if recorded in the model file.
+    pub quantization: Option<FileType>,
+    /// Whether the output weights are tied to (shared with) the token embeddings.
+    pub tied_embeddings: bool,
+}
+
+/// The names of the tensors holding a model's token embeddings and output weights (the
+/// language model head). See [KnownModel::embedding_tensors].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmbeddingTensors {
+    /// The token embeddings.
+    pub input: &'static str,
+    /// The output weights. Model files without this tensor tie the output weights to the
+    /// token embeddings. `None` if the architecture always ties them.
+    pub output: Option<&'static str>,
+}
+impl EmbeddingTensors {
+    /// Returns whether the output weights are tied to the token embeddings in a model
+    /// file that has the tensors for which `has_tensor` returns true.
+    pub fn are_tied(&self, has_tensor: impl Fn(&str) -> bool) -> bool {
+        self.output.map_or(true, |output| !has_tensor(output))
+    }
 }
 
 /// Implemented by model hyperparameters for interacting with hyperparameters
@@ -181,6 +429,15 @@ pub trait Hyperparameters: Sized + Default + Debug + PartialEq + Eq {
 
     /// Get mutable access to filetype of the model.
     fn file_type_mut(&mut self) -> Option<&mut FileType>;
+
+    /// Checks the invariants that the architecture relies on, such as the embedding size
+    /// being divisible by the number of attention heads, after the hyperparameters of the
+    /// model at `path` were read. See [crate::validate_attention].
+    ///
+    /// Files that break them would load, but produce garbage or NaNs during inference.
+    fn validate(&self, _path: &Path) -> Result<(), LoadError> {
+        Ok(())
+    }
 }
 #[derive(Error, Debug)]
 /// Reported from functions that write
@@ -202,23 +459,127 @@ pub struct ModelParameters {
     pub prefer_mmap: bool,
     /// The context size ("memory") the model should use when evaluating a prompt. A larger context
     /// consumes more resources, but produces more consistent and coherent responses.
-    pub context_size: usize,
+    ///
+    /// This is the largest context size of the sessions of the model: each session can use
+    /// a smaller one with [InferenceSessionConfig::context_size](crate::InferenceSessionConfig::context_size).
+    /// It can be a fixed number of tokens, or chosen to fit a memory budget.
+    pub context_size: ContextSize,
     /// The [LoRA](https://arxiv.org/abs/2106.09685) adapters to use when loading the model. If `None`, no adapters will be used.
     pub lora_adapters: Option<Vec<PathBuf>>,
     /// Whether to use GPU acceleration when available
     pub use_gpu: bool,
+    /// The number of layers to offload to the GPU when [Self::use_gpu] is set, starting
+    /// from the first, while the rest stay on the CPU. If `None`, all layers are offloaded.
+    ///
+    /// Offloading requires the `cublas` (CUDA) or `opencl` (CLBlast) feature. With Metal, the
+    /// whole model is always used on the GPU, as it shares the memory of the CPU.
+    pub gpu_layers: Option<usize>,
+    /// Where to store the weights of each layer when [Self::use_gpu] is set, for finer
+    /// control than [Self::gpu_layers], which it replaces if set. For example, the
+    /// attention weights can be offloaded while the feed-forward weights stay on the CPU.
+    ///
+    /// This has the same requirements as [Self::gpu_layers].
+    pub placement: Option<PlacementPolicy>,
+    /// Receives notable events while loading and using the model. Logs them by default.
+    pub diagnostics: Diagnostics,
+    /// The SHA-256 of the model file. If set, the file is hashed before it is loaded, and
+    /// loading fails with [LoadError::ChecksumMismatch](crate::LoadError::ChecksumMismatch)
+    /// if it does not match. For sharded models, this is the hash of all of the shards
+    /// one after the other, as computed by [util::model_sha256](crate::util::model_sha256).
+    ///
+    /// Hashing reads the whole file, which takes about as long as loading it without
+    /// memory mapping.
+    pub expected_sha256: Option<[u8; 32]>,
+    /// Cancels loading when cancelled. It is checked between tensors and while verifying
+    /// [Self::expected_sha256], and a cancelled load fails with
+    /// [LoadError::Cancelled](crate::LoadError::Cancelled) after freeing what it allocated.
+    pub cancellation_token: Option<CancellationToken>,
+    /// How long loading may take, e.g. to give up on a network filesystem that stalls.
+    /// Loading fails with [LoadError::TimedOut](crate::LoadError::TimedOut), with the stage
+    /// that was in progress, after freeing what it allocated.
+    ///
+    /// It is checked at the same points as [Self::cancellation_token], as well as after
+    /// reading the metadata of each file and after memory mapping them: a single read that
+    /// never returns cannot be interrupted, but the load fails as soon as it does.
+    pub load_timeout: Option<Duration>,
+    /// Tensors to replace with those of other files, as pairs of a pattern and a path. The
+    /// tensors of the file at the path whose names match the pattern replace the tensors
+    /// of the model with the same names, e.g. `("output.weight", "repaired-head.bin")`
+    /// to patch a botched conversion. Later overrides take precedence.
+    ///
+    /// The files are model files of the same architecture, holding only some of the
+    /// tensors. Patterns are globs: `*` matches any text and `?` any character, so
+    /// `layers.*.attention.wq.weight` matches that tensor in every layer. Each tensor must
+    /// have the shape of the tensor it replaces, and each pattern must match at least one.
+    ///
+    /// Models with overrides are not memory mapped.
+    pub tensor_overrides: Vec<(String, PathBuf)>,
 }
 
 impl Default for ModelParameters {
     fn default() -> Self {
         Self {
             prefer_mmap: true,
-            context_size: 2048,
+            context_size: ContextSize::default(),
             lora_adapters: None,
             use_gpu: false,
+            gpu_layers: None,
+            placement: None,
+            diagnostics: Default::default(),
+            expected_sha256: None,
+            cancellation_token: None,
+            load_timeout: None,
+            tensor_overrides: vec![],
+        }
+    }
+}
+
+/// The context size of a model. See [ModelParameters::context_size].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextSize {
+    /// A fixed number of tokens.
+    Fixed(usize),
+    /// The largest number of tokens whose key/value memory fits in `max_memory_bytes`,
+    /// which depends on the number of layers and the embedding size of the model.
+    ///
+    /// The memory is computed for the key/value types of the default
+    /// [InferenceSessionConfig](crate::InferenceSessionConfig) (`f16`); sessions with `f32`
+    /// memory need twice as much. The chosen size is reported as a
+    /// [Diagnostic::ContextSizeChosen](crate::diagnostics::Diagnostic::ContextSizeChosen).
+    Auto {
+        /// The memory the key/value memory of a session may use, in bytes.
+        max_memory_bytes: usize,
+    },
+}
+impl ContextSize {
+    /// Returns the number of tokens for a model with `n_layer` layers of `n_embd` elements.
+    pub fn tokens(&self, n_layer: usize, n_embd: usize) -> usize {
+        match *self {
+            Self::Fixed(tokens) => tokens,
+            Self::Auto { max_memory_bytes } => {
+                let config = InferenceSessionConfig::default();
+                let size = |n_ctx| kv_cache_size(&config, n_ctx, n_layer, n_embd);
+                let per_token = (size(1) - size(0)).max(1);
+                let mut tokens = max_memory_bytes.saturating_sub(size(0)) / per_token;
+                // The sizes are rounded, so make sure that the estimate fits.
+                while tokens > 0 && size(tokens) > max_memory_bytes {
+                    tokens -= 1;
+                }
+                tokens
+            }
         }
     }
 }
+impl Default for ContextSize {
+    fn default() -> Self {
+        Self::Fixed(2048)
+    }
+}
+impl From<usize> for ContextSize {
+    fn from(tokens: usize) -> Self {
+        Self::Fixed(tokens)
+    }
+}
 
 /// Used in a call to [Model::evaluate] or [InferenceSession::infer] to request
 /// information from the model. If a value is set to `Some`, the `Vec` will be
@@ -233,4 +594,45 @@ pub struct OutputRequest {
     /// that measures the relatedness of text strings. Output shape is
     /// `n_batch * n_embd`.
     pub embeddings: Option<Vec<f32>>,
+    /// Called by [InferenceSession] after every evaluation with the complete logits that the
+    /// next token is sampled from, including during [InferenceSession::infer]. See
+    /// [LogitsCallback].
+    pub logits_callback: Option<LogitsCallback>,
+}
+
+/// A callback that receives the logits after every evaluation of a session, for
+/// distillation or custom decoding without giving up the bookkeeping of [InferenceSession].
+///
+/// It is called with the tokens that were just evaluated (a prompt batch, or a generated
+/// token) and the `n_vocab` logits of the last of them, before any [LogitsProcessor](crate::LogitsProcessor) or
+/// sampler sees them. The logits are borrowed from the session without being copied. The
+/// logits of every token of a prompt batch are available through
+/// [OutputRequest::all_logits].
+///
+/// Clones share the same callback, and compare equal if they do.
+#[derive(Clone)]
+pub struct LogitsCallback(Arc<LogitsFn>);
+type LogitsFn = dyn Fn(&[TokenId], &[f32]) + Send + Sync;
+impl LogitsCallback {
+    /// Creates a callback that calls `callback` with the evaluated tokens and the logits.
+    pub fn new(callback: impl Fn(&[TokenId], &[f32]) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    pub(crate) fn call(&self, input_tokens: &[TokenId], logits: &[f32]) {
+        (self.0)(input_tokens, logits)
+    }
+}
+impl PartialEq for LogitsCallback {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(
+            Arc::as_ptr(&self.0) as *const u8,
+            Arc::as_ptr(&other.0) as *const u8,
+        )
+    }
+}
+impl Debug for LogitsCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogitsCallback").finish_non_exhaustive()
+    }
 }
@@ -0,0 +1,448 @@
+//! Higher-level building blocks that combine several inference calls to perform a task.
+use rand::SeedableRng;
+use thiserror::Error;
+
+use crate::{
+    memory::MemoryLimitExceeded,
+    text::{chunk_by_tokens, ChunkError},
+    util, InferenceError, InferenceFeedback, InferenceParameters, InferenceRequest,
+    InferenceResponse, InferenceSessionConfig, Model, OutputRequest, TokenizationError,
+};
+
+/// The placeholder that is replaced with the text to summarize in the prompts of
+/// [SummarizeOptions].
+pub const TEXT_PLACEHOLDER: &str = "{text}";
+
+/// Options for [summarize].
+#[derive(Clone, Debug)]
+pub struct SummarizeOptions {
+    /// The prompt used to summarize each chunk of the input. [TEXT_PLACEHOLDER] is
+    /// replaced with the chunk.
+    pub chunk_prompt: String,
+    /// The prompt used to merge several summaries into one. [TEXT_PLACEHOLDER] is
+    /// replaced with the summaries, separated by [SummarizeOptions::separator].
+    pub merge_prompt: String,
+    /// The text placed between summaries when they are merged.
+    pub separator: String,
+    /// The maximum number of tokens in each chunk of the input. The chunks are also
+    /// limited by the context size of the model, so this can be left as `usize::MAX`.
+    pub chunk_tokens: usize,
+    /// The maximum number of tokens generated for each summary.
+    pub max_summary_tokens: usize,
+    /// The parameters used for generation.
+    pub parameters: InferenceParameters,
+    /// The configuration of the sessions used for generation.
+    pub session_config: InferenceSessionConfig,
+    /// The seed for the random number generator. If not specified, a random seed is used.
+    pub seed: Option<u64>,
+}
+impl Default for SummarizeOptions {
+    fn default() -> Self {
+        Self {
+            chunk_prompt: "Write a concise summary of the following text.\n\n\
+                Text:\n{text}\n\nSummary:"
+                .to_owned(),
+            merge_prompt: "The following are summaries of consecutive parts of a longer \
+                document. Combine them into a single concise summary.\n\n\
+                Summaries:\n{text}\n\nCombined summary:"
+                .to_owned(),
+            separator: "\n\n".to_owned(),
+            chunk_tokens: usize::MAX,
+            max_summary_tokens: 256,
+            parameters: Default::default(),
+            session_config: Default::default(),
+            seed: None,
+        }
+    }
+}
+
+/// Errors encountered by [summarize].
+#[derive(Error, Debug)]
+pub enum SummarizeError {
+    /// The context window of the model is too small to summarize with these options.
+    ///
+    /// Each prompt must fit in the context together with at least two summaries of
+    /// [SummarizeOptions::max_summary_tokens] tokens, so that summaries can be merged.
+    #[error(
+        "the context size of {context_size} tokens is too small for the prompts and summary length"
+    )]
+    ContextTooSmall {
+        /// The context size of the model.
+        context_size: usize,
+    },
+    /// The session for a summary could not be started.
+    #[error("the session could not be started")]
+    MemoryLimitExceeded(#[from] MemoryLimitExceeded),
+    /// Tokenization failed.
+    #[error("tokenization failed")]
+    Tokenization(#[from] TokenizationError),
+    /// Inference failed.
+    #[error("inference failed")]
+    Inference(#[from] InferenceError),
+}
+
+/// Summarizes `text`, which may be much longer than the context window of `model`.
+///
+/// The text is split into chunks that fit in the context window, and each chunk is
+/// summarized. The summaries are then merged, in groups that fit in the context window,
+/// until only one summary remains.
+pub fn summarize(
+    model: &dyn Model,
+    text: &str,
+    options: SummarizeOptions,
+) -> Result<String, SummarizeError> {
+    let mut rng = match options.seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+    let tokenizer = model.tokenizer();
+    let context_size = options
+        .session_config
+        .context_size
+        .map_or(model.context_size(), |size| size.min(model.context_size()));
+
+    // The number of tokens of text that fit in `prompt`, leaving room for the summary.
+    let budget = |prompt: &str| -> Result<usize, SummarizeError> {
+        let overhead = tokenizer
+            .tokenize(&prompt.replace(TEXT_PLACEHOLDER, ""), true)?
+            .len();
+        Ok(context_size.saturating_sub(overhead + options.max_summary_tokens))
+    };
+    let chunk_budget = budget(&options.chunk_prompt)?.min(options.chunk_tokens);
+    let merge_budget = budget(&options.merge_prompt)?;
+    if chunk_budget == 0 || merge_budget < 2 * options.max_summary_tokens {
+        return Err(SummarizeError::ContextTooSmall { context_size });
+    }
+
+    let mut generate = |prompt: &str, text: &str| -> Result<String, SummarizeError> {
+        let prompt = prompt.replace(TEXT_PLACEHOLDER, text);
+        let mut session = model.try_start_session(options.session_config.clone())?;
+        let mut output = String::new();
+        session.infer::<std::convert::Infallible>(
+            model,
+            &mut rng,
+            &InferenceRequest {
+                prompt: prompt.as_str().into(),
+                parameters: &options.parameters,
+                play_back_previous_tokens: false,
+                maximum_token_count: Some(options.max_summary_tokens),
+                maximum_output_bytes: None,
+                maximum_output_chars: None,
+                stop_token_sequences: &[],
+                guardrails: &[],
+                forced_prefix: None,
+                logprobs: None,
+                early_stop: Default::default(),
+                cancellation_token: None,
+                deadline: None,
+            },
+            &mut Default::default(),
+            |response| {
+                if let InferenceResponse::InferredToken(token) = response {
+                    output.push_str(&token);
+                }
+                Ok(InferenceFeedback::Continue)
+            },
+        )?;
+        Ok(output.trim().to_owned())
+    };
+
+    let chunks = chunk_by_tokens(tokenizer, text, chunk_budget, 0).map_err(|e| match e {
+        ChunkError::Tokenization(e) => SummarizeError::Tokenization(e),
+        ChunkError::OverlapTooLarge { .. } => unreachable!("chunks do not overlap"),
+    })?;
+    let mut summaries = chunks
+        .into_iter()
+        .map(|chunk| generate(&options.chunk_prompt, chunk.text))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    while summaries.len() > 1 {
+        let lengths = summaries
+            .iter()
+            .map(|summary| Ok(tokenizer.tokenize(summary, false)?.len()))
+            .collect::<Result<Vec<_>, SummarizeError>>()?;
+        let separator_length = tokenizer.tokenize(&options.separator, false)?.len();
+
+        summaries = group_by_budget(&lengths, separator_length, merge_budget)
+            .into_iter()
+            .map(|group| match &summaries[group] {
+                [summary] => Ok(summary.clone()),
+                group => generate(&options.merge_prompt, &group.join(&options.separator)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+    }
+
+    Ok(summaries.pop().unwrap_or_default())
+}
+
+/// Splits items with the given `lengths` into consecutive groups whose total length
+/// (including a separator between items) fits in `budget`. Every group contains at
+/// least two items (except possibly the last), so that merging always makes progress.
+fn group_by_budget(
+    lengths: &[usize],
+    separator_length: usize,
+    budget: usize,
+) -> Vec<std::ops::Range<usize>> {
+    let mut groups = vec![];
+    let mut start = 0;
+    let mut total = 0;
+    for (i, &length) in lengths.iter().enumerate() {
+        let added = if i == start {
+            length
+        } else {
+            separator_length + length
+        };
+        if i - start >= 2 && total + added > budget {
+            groups.push(start..i);
+            start = i;
+            total = length;
+        } else {
+            total += added;
+        }
+    }
+    if start < lengths.len() {
+        groups.push(start..lengths.len());
+    }
+    groups
+}
+
+/// Options for [compress_prompt].
+#[derive(Clone, Debug)]
+pub struct CompressOptions {
+    /// The fraction of the tokens of the text to keep, from 0 to 1.
+    pub ratio: f32,
+    /// The parameters used to score the tokens.
+    pub parameters: InferenceParameters,
+    /// The configuration of the sessions used to score the tokens.
+    pub session_config: InferenceSessionConfig,
+}
+impl Default for CompressOptions {
+    fn default() -> Self {
+        Self {
+            ratio: 0.5,
+            parameters: Default::default(),
+            session_config: Default::default(),
+        }
+    }
+}
+
+/// A text compressed by [compress_prompt].
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompressedPrompt {
+    /// The text, without the tokens that were dropped.
+    pub text: String,
+    /// The number of tokens of the original text.
+    pub original_tokens: usize,
+    /// The number of tokens that were kept.
+    pub kept_tokens: usize,
+    /// The fraction of the information of the original text (the sum of the
+    /// self-information of its tokens, as scored by the model) that the kept tokens carry.
+    /// This measures how much was lost, from 0 (everything) to 1 (nothing).
+    pub retained_information: f32,
+}
+
+/// Errors encountered by [compress_prompt].
+#[derive(Error, Debug)]
+pub enum CompressError {
+    /// [CompressOptions::ratio] is not between 0 and 1.
+    #[error("the ratio of tokens to keep should be between 0 and 1, not {ratio}")]
+    InvalidRatio {
+        /// The ratio.
+        ratio: f32,
+    },
+    /// The session used to score the tokens could not be started.
+    #[error("the session could not be started")]
+    MemoryLimitExceeded(#[from] MemoryLimitExceeded),
+    /// Tokenization failed.
+    #[error("tokenization failed")]
+    Tokenization(#[from] TokenizationError),
+}
+
+/// Compresses `text` to about [CompressOptions::ratio] of its tokens by dropping those that
+/// carry the least information, so that long contexts (such as retrieved documents) fit in
+/// a smaller context window.
+///
+/// Each token is scored by its self-information, `-ln p(token)`, given the text before it:
+/// tokens that `model` predicts easily add little to the text and are dropped first, as in
+/// [LLMLingua](https://arxiv.org/abs/2310.05736). A small model can be used to compress
+/// prompts for a larger one. Text longer than the context window is scored in windows, each
+/// without the context of the previous ones.
+///
+/// The result is no longer fluent text, but models can usually still answer questions about
+/// it. [CompressedPrompt::retained_information] reports how much was dropped.
+pub fn compress_prompt(
+    model: &dyn Model,
+    text: &str,
+    options: CompressOptions,
+) -> Result<CompressedPrompt, CompressError> {
+    if !(0.0..=1.0).contains(&options.ratio) {
+        return Err(CompressError::InvalidRatio {
+            ratio: options.ratio,
+        });
+    }
+    let tokenizer = model.tokenizer();
+    let tokens = tokenizer.tokenize_with_offsets(text)?;
+    let context_size = options
+        .session_config
+        .context_size
+        .map_or(model.context_size(), |size| size.min(model.context_size()));
+    let n_vocab = tokenizer.len();
+    let bos = model.bot_token_id();
+
+    // The self-information of each token, or `None` for tokens that start a window
+    // without a beginning-of-text token, as nothing predicts them.
+    let mut scores = Vec::with_capacity(tokens.len());
+    let window_size = context_size.saturating_sub(1).max(1);
+    for window in tokens.chunks(window_size) {
+        let mut session = model.try_start_session(options.session_config.clone())?;
+        let input: Vec<_> = bos
+            .into_iter()
+            .chain(window.iter().map(|(token, _)| *token))
+            .collect();
+
+        let mut logits = vec![];
+        for batch in input.chunks(options.parameters.n_batch.max(1)) {
+            let mut output_request = OutputRequest {
+                all_logits: Some(vec![]),
+                ..Default::default()
+            };
+            model.evaluate(
+                &mut session,
+                &options.parameters,
+                batch,
+                &mut output_request,
+            );
+            logits.extend(output_request.all_logits.unwrap());
+        }
+
+        // The logits after each token predict the next one.
+        let predicted = &input[input.len() - window.len()..];
+        if bos.is_none() {
+            scores.push(None);
+        }
+        scores.extend(
+            predicted
+                .iter()
+                .skip(usize::from(bos.is_none()))
+                .zip(logits.chunks(n_vocab))
+                .map(|(&token, logits)| Some(-util::softmax(logits)[token as usize].ln())),
+        );
+    }
+
+    // Tokens that were produced from the same characters are kept or dropped together,
+    // so that no character is cut in half.
+    let mut units: Vec<Unit> = vec![];
+    for ((_, range), score) in tokens.iter().zip(scores) {
+        match units.last_mut() {
+            Some(unit) if range.start < unit.range.end => {
+                unit.range.end = unit.range.end.max(range.end);
+                unit.tokens += 1;
+                unit.score = unit.score.zip(score).map(|(a, b)| a + b);
+            }
+            _ => units.push(Unit {
+                range: range.clone(),
+                tokens: 1,
+                score,
+            }),
+        }
+    }
+
+    let budget = (tokens.len() as f32 * options.ratio).round() as usize;
+    let kept = select_units(&units, budget);
+
+    let mut compressed = String::new();
+    let mut kept_tokens = 0;
+    let mut kept_information = 0.0;
+    let mut total_information = 0.0;
+    for (unit, kept) in units.iter().zip(kept) {
+        let information = unit.score.unwrap_or(0.0);
+        total_information += information;
+        if kept {
+            compressed.push_str(&text[unit.range.clone()]);
+            kept_tokens += unit.tokens;
+            kept_information += information;
+        }
+    }
+
+    Ok(CompressedPrompt {
+        text: compressed,
+        original_tokens: tokens.len(),
+        kept_tokens,
+        retained_information: if total_information > 0.0 {
+            kept_information / total_information
+        } else {
+            1.0
+        },
+    })
+}
+
+/// Consecutive tokens of a text that [compress_prompt] keeps or drops together.
+struct Unit {
+    /// The byte range of the tokens in the text.
+    range: std::ops::Range<usize>,
+    /// The number of tokens.
+    tokens: usize,
+    /// The total self-information of the tokens, or `None` if they must be kept.
+    score: Option<f32>,
+}
+
+/// Returns whether to keep each of `units`, keeping those with the highest scores (and
+/// those without a score) that fit in `budget` tokens.
+fn select_units(units: &[Unit], budget: usize) -> Vec<bool> {
+    let mut kept = vec![false; units.len()];
+    let mut remaining = budget;
+    let mut order: Vec<usize> = (0..units.len()).collect();
+    order.sort_by(|&a, &b| {
+        let score = |i: usize| units[i].score.unwrap_or(f32::INFINITY);
+        score(b).total_cmp(&score(a)).then(a.cmp(&b))
+    });
+    for i in order {
+        let unit = &units[i];
+        if unit.score.is_none() || unit.tokens <= remaining {
+            kept[i] = true;
+            remaining = remaining.saturating_sub(unit.tokens);
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_fit_budget_and_always_merge() {
+        assert_eq!(
+            group_by_budget(&[10, 10, 10, 10, 10], 1, 25),
+            [0..2, 2..4, 4..5]
+        );
+        // Oversized items are still paired up.
+        assert_eq!(group_by_budget(&[30, 30, 30], 1, 25), [0..2, 2..3]);
+    }
+
+    #[test]
+    fn compression_keeps_the_most_informative_units() {
+        let unit = |tokens, score| Unit {
+            range: 0..0,
+            tokens,
+            score,
+        };
+        let units = [
+            unit(1, Some(0.5)),
+            unit(1, Some(3.0)),
+            unit(2, Some(4.0)),
+            unit(1, None),
+            unit(1, Some(2.0)),
+            unit(1, Some(1.0)),
+        ];
+        assert_eq!(
+            select_units(&units, 4),
+            [false, true, true, true, false, false]
+        );
+        // Units that do not fit are skipped in favor of smaller ones.
+        assert_eq!(
+            select_units(&units, 2),
+            [false, true, false, true, false, false]
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! Where the weights of a model are stored, and in which formats.
+use std::{collections::BTreeMap, error::Error, fmt::Display, str::FromStr};
+
+/// Where a tensor is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub enum Device {
+    /// In main memory, for use by the CPU.
+    ///
+    /// Weights used by Metal are also reported here, as they are shared with the GPU
+    /// rather than copied to it.
+    #[default]
+    Cpu,
+    /// In the memory of the GPU, where the weights were offloaded with
+    /// [ModelParameters::gpu_layers](crate::ModelParameters::gpu_layers) or
+    /// [ModelParameters::placement](crate::ModelParameters::placement).
+    Gpu,
+}
+impl FromStr for Device {
+    type Err = InvalidPlacement;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpu" => Ok(Self::Cpu),
+            "gpu" => Ok(Self::Gpu),
+            _ => Err(InvalidPlacement(s.to_owned())),
+        }
+    }
+}
+impl Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cpu => write!(f, "cpu"),
+            Self::Gpu => write!(f, "gpu"),
+        }
+    }
+}
+
+/// Where to store the weights of each layer of a model, for
+/// [ModelParameters::placement](crate::ModelParameters::placement).
+///
+/// Only the matrices are offloaded, as the GPU is used for the matrix multiplications; the
+/// rest of the computation of a layer runs on the CPU. The token embeddings always stay on
+/// the CPU, as they are only looked up, and the output weights stay with them when the
+/// model ties them to the token embeddings.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PlacementPolicy {
+    /// The devices of the weights of each layer, by layer index. The layers past the end
+    /// of the list are stored on the CPU.
+    pub layers: Vec<LayerDevices>,
+    /// The device of the output weights, which turn the output of the last layer into
+    /// logits.
+    pub output: Device,
+}
+impl PlacementPolicy {
+    /// Returns a policy that stores the first `count` layers on the GPU, like
+    /// [ModelParameters::gpu_layers](crate::ModelParameters::gpu_layers).
+    pub fn first_layers(count: usize) -> Self {
+        Self {
+            layers: vec![LayerDevices::all(Device::Gpu); count],
+            output: Device::Cpu,
+        }
+    }
+
+    /// Returns the device of the weight named `name`, where `output` is the name of the
+    /// output weights of the model, if they are not tied to the token embeddings.
+    pub(crate) fn device_of(&self, name: &str, output: Option<&str>) -> Device {
+        if Some(name) == output {
+            return self.output;
+        }
+        let Some(layer) = layer_index(name).and_then(|index| self.layers.get(index)) else {
+            return Device::Cpu;
+        };
+        let is_attention = name
+            .split(['.', '/'])
+            .any(|part| matches!(part, "attention" | "attn" | "self_attention"));
+        if is_attention {
+            layer.attention
+        } else {
+            layer.feed_forward
+        }
+    }
+}
+impl FromStr for PlacementPolicy {
+    type Err = InvalidPlacement;
+
+    /// A comma separated list of `TARGET=DEVICE`, where DEVICE is `cpu` or `gpu`, and
+    /// TARGET is `output` or a layer range (`3` or `0-15`), optionally followed by
+    /// `.attention` or `.feed_forward` to only place that part of the layers.
+    ///
+    /// For example, `0-31.attention=gpu,output=gpu` stores the attention weights of the
+    /// first 32 layers and the output weights on the GPU, and the rest on the CPU.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidPlacement(s.to_owned());
+        let mut policy = Self::default();
+        for entry in s.split(',') {
+            let (target, device) = entry.split_once('=').ok_or_else(invalid)?;
+            let device: Device = device.trim().parse().map_err(|_| invalid())?;
+            let target = target.trim();
+            if target == "output" {
+                policy.output = device;
+                continue;
+            }
+
+            let (range, part) = match target.split_once('.') {
+                Some((range, part)) => (range, Some(part)),
+                None => (target, None),
+            };
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let first: usize = first.parse().map_err(|_| invalid())?;
+            let last: usize = last.parse().map_err(|_| invalid())?;
+            if last < first {
+                return Err(invalid());
+            }
+            if policy.layers.len() <= last {
+                policy
+                    .layers
+                    .resize(last + 1, LayerDevices::all(Device::Cpu));
+            }
+            for layer in &mut policy.layers[first..=last] {
+                match part {
+                    None => *layer = LayerDevices::all(device),
+                    Some("attention") => layer.attention = device,
+                    Some("feed_forward") => layer.feed_forward = device,
+                    Some(_) => return Err(invalid()),
+                }
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// Where to store the weights of one layer of a model. See [PlacementPolicy].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerDevices {
+    /// The device of the weights of the self-attention of the layer.
+    pub attention: Device,
+    /// The device of the other weights of the layer, such as those of the feed-forward
+    /// network (MLP).
+    pub feed_forward: Device,
+}
+impl LayerDevices {
+    /// Returns the placement of a layer with all of its weights on `device`.
+    pub fn all(device: Device) -> Self {
+        Self {
+            attention: device,
+            feed_forward: device,
+        }
+    }
+}
+
+/// An error was encountered when parsing a [PlacementPolicy] or a [Device].
+#[derive(Debug)]
+pub struct InvalidPlacement(String);
+impl Display for InvalidPlacement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "should be a list of <layers>[.attention|.feed_forward]=<cpu|gpu> or output=<cpu|gpu>: {:?}",
+            self.0
+        )
+    }
+}
+impl Error for InvalidPlacement {}
+
+/// The placement of the weights of one layer of a model. See [PlacementReport].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerPlacement {
+    /// The index of the layer, or `None` for the weights outside of the repeated layers,
+    /// such as the token embeddings.
+    pub layer: Option<usize>,
+    /// Where the weights of the layer are stored. Layers with some of their weights on the
+    /// GPU are reported as [Device::Gpu].
+    pub device: Device,
+    /// The element types of the weights of the layer, with the number of bytes stored in
+    /// each type, largest first.
+    pub element_types: Vec<(ggml::Type, usize)>,
+    /// The number of bytes of the weights of the layer.
+    pub bytes: usize,
+}
+
+/// Where each layer of a model is stored, and in which formats.
+///
+/// Returned by [KnownModel::placement_report](crate::KnownModel::placement_report).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PlacementReport {
+    /// The layers of the model in order, preceded by the weights outside of the layers.
+    pub layers: Vec<LayerPlacement>,
+}
+impl PlacementReport {
+    /// Creates a report from the weights of a model, grouping them into layers by the
+    /// layer number in their names (e.g. `layers.3.attention.wq.weight`).
+    pub fn from_tensors<'a>(
+        tensors: impl IntoIterator<Item = (&'a str, &'a ggml::Tensor)>,
+    ) -> Self {
+        let mut layers: BTreeMap<Option<usize>, LayerPlacement> = BTreeMap::new();
+        for (name, tensor) in tensors {
+            let layer = layer_index(name);
+            let placement = layers.entry(layer).or_insert(LayerPlacement {
+                layer,
+                device: Device::Cpu,
+                element_types: vec![],
+                bytes: 0,
+            });
+            if tensor.backend() == ggml::accelerator::Backend::Gpu {
+                placement.device = Device::Gpu;
+            }
+            let element_type = tensor.get_type();
+            match placement
+                .element_types
+                .iter_mut()
+                .find(|(t, _)| *t == element_type)
+            {
+                Some((_, bytes)) => *bytes += tensor.nbytes(),
+                None => placement
+                    .element_types
+                    .push((element_type, tensor.nbytes())),
+            }
+            placement.bytes += tensor.nbytes();
+        }
+
+        Self {
+            layers: layers
+                .into_values()
+                .map(|mut placement| {
+                    placement
+                        .element_types
+                        .sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+                    placement
+                })
+                .collect(),
+        }
+    }
+
+    /// The number of bytes of all of the weights of the model.
+    pub fn bytes(&self) -> usize {
+        self.layers.iter().map(|layer| layer.bytes).sum()
+    }
+}
+
+/// Returns the layer number in the name of a tensor, for the naming schemes of all
+/// supported architectures (`layers.3.`, `transformer.h.3.`, `model/h3/`).
+pub(crate) fn layer_index(name: &str) -> Option<usize> {
+    name.split(['.', '/'])
+        .find_map(|part| part.strip_prefix('h').unwrap_or(part).parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layer_index_supports_all_naming_schemes() {
+        assert_eq!(layer_index("layers.12.attention.wq.weight"), Some(12));
+        assert_eq!(layer_index("transformer.h.3.attn.q_proj.weight"), Some(3));
+        assert_eq!(layer_index("model/h7/mlp/c_fc/w"), Some(7));
+        assert_eq!(
+            layer_index("transformer.blocks.0.ffn.up_proj.weight"),
+            Some(0)
+        );
+        assert_eq!(layer_index("tok_embeddings.weight"), None);
+        assert_eq!(layer_index("model/wte"), None);
+    }
+
+    #[test]
+    fn placement_policy_splits_attention_from_feed_forward() {
+        let policy: PlacementPolicy = "0-1.attention=gpu,1=gpu,output=gpu".parse().unwrap();
+        assert_eq!(
+            policy.layers,
+            [
+                LayerDevices {
+                    attention: Device::Gpu,
+                    feed_forward: Device::Cpu
+                },
+                LayerDevices::all(Device::Gpu)
+            ]
+        );
+
+        let output = Some("lm_head.weight");
+        for (name, device) in [
+            ("transformer.h.0.attn.q_proj.weight", Device::Gpu),
+            ("transformer.h.0.mlp.fc_in.weight", Device::Cpu),
+            ("model/h0/attn/c_attn/w", Device::Gpu),
+            ("gpt_neox.layers.0.mlp.dense_h_to_4h.weight", Device::Cpu),
+            ("transformer.h.1.mlp.fc_in.weight", Device::Gpu),
+            ("transformer.h.2.attn.q_proj.weight", Device::Cpu),
+            ("lm_head.weight", Device::Gpu),
+            ("transformer.wte.weight", Device::Cpu),
+        ] {
+            assert_eq!(policy.device_of(name, output), device, "{name}");
+        }
+
+        assert!("0-1.mlp=gpu".parse::<PlacementPolicy>().is_err());
+        assert!("2-1=gpu".parse::<PlacementPolicy>().is_err());
+        assert!("output=tpu".parse::<PlacementPolicy>().is_err());
+    }
+}
@@ -1,8 +1,10 @@
 //! Implements quantization of weights.
 
 use crate::{
-    loader::FileTypeFormat, model::HyperparametersWriteError, Hyperparameters, KnownModel,
-    LoadError, LoadProgress, Loader, Tokenizer,
+    convert::VocabularyChanges,
+    loader::{convert_file_only_to_fit, dequantize_file_only, loaded_element_type, FileTypeFormat},
+    model::HyperparametersWriteError,
+    Hyperparameters, KnownModel, LoadError, LoadProgress, Loader, Tokenizer,
 };
 use ggml::format::{SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo};
 use half::f16;
@@ -45,8 +47,8 @@ pub enum QuantizeProgress<'a> {
         original_size: usize,
         /// The reduced size of the tensor.
         reduced_size: usize,
-        /// The history of the quantization.
-        history: Vec<f32>,
+        /// The distribution of the quantized values of the tensor.
+        histogram: QuantizationHistogram,
     },
     /// A tensor has been skipped.
     TensorSkipped {
@@ -55,17 +57,163 @@ pub enum QuantizeProgress<'a> {
         /// The original size (in bytes) of the tensor data.
         size: usize,
     },
+    /// The vocabulary tensors were changed to match the vocabulary. Only reported by
+    /// [convert_hf](crate::convert::convert_hf).
+    VocabularyAdjusted {
+        /// What was changed.
+        changes: VocabularyChanges,
+    },
     /// A model has been quantized.
     Finished {
         /// The original size (in bytes) of the model.
         original_size: usize,
         /// The reduced size (in bytes) of the model.
         reduced_size: usize,
-        /// The history of the quantization.
-        history: Vec<f32>,
+        /// The distribution of the quantized values of all quantized tensors.
+        histogram: QuantizationHistogram,
     },
 }
 
+/// The distribution of the values of a quantized tensor among the quantization levels.
+///
+/// `ggml` sorts each quantized value into one of [Self::BUCKETS] buckets, by its
+/// quantization level: bucket `i` holds the values whose level is in the `i`-th sixteenth
+/// of the levels of the format (see [Self::bucket_bounds]). A good quantization spreads the
+/// values over the levels; values piled up in a few buckets waste precision.
+///
+/// The k-quant formats do not record a histogram, so theirs is empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QuantizationHistogram {
+    counts: [u64; Self::BUCKETS],
+}
+impl QuantizationHistogram {
+    /// The number of buckets.
+    pub const BUCKETS: usize = 16;
+
+    /// A histogram with the counts `ggml` recorded.
+    pub(crate) fn from_ggml(history: &[i64]) -> Self {
+        let mut counts = [0; Self::BUCKETS];
+        for (count, value) in counts.iter_mut().zip(history) {
+            *count = (*value).max(0) as u64;
+        }
+        Self { counts }
+    }
+
+    /// The number of values in each bucket.
+    pub fn counts(&self) -> &[u64; Self::BUCKETS] {
+        &self.counts
+    }
+
+    /// The number of values in the histogram.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Whether the histogram has no values, as for the k-quant formats.
+    pub fn is_empty(&self) -> bool {
+        self.total() == 0
+    }
+
+    /// The fraction of the values in each bucket, which sum to one unless the histogram is
+    /// empty.
+    pub fn frequencies(&self) -> [f32; Self::BUCKETS] {
+        let total = self.total().max(1) as f32;
+        self.counts.map(|count| count as f32 / total)
+    }
+
+    /// The lower (inclusive) and upper (exclusive) bounds of `bucket`, as fractions of the
+    /// range of quantization levels of the format.
+    ///
+    /// # Panics
+    /// Panics if `bucket` is not less than [Self::BUCKETS].
+    pub fn bucket_bounds(bucket: usize) -> (f32, f32) {
+        assert!(
+            bucket < Self::BUCKETS,
+            "there are {} buckets",
+            Self::BUCKETS
+        );
+        let width = 1.0 / Self::BUCKETS as f32;
+        (bucket as f32 * width, (bucket + 1) as f32 * width)
+    }
+
+    /// Adds the values of `other` to this histogram.
+    pub fn merge(&mut self, other: &Self) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts) {
+            *count += other;
+        }
+    }
+}
+
+/// What happened to a tensor during quantization. See [QuantizeReport::tensors].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TensorQuantizeStats {
+    /// The name of the tensor.
+    pub name: String,
+    /// The type of the tensor before quantization.
+    pub original_element_type: ggml::Type,
+    /// The type the tensor was saved as.
+    pub element_type: ggml::Type,
+    /// The number of elements in the tensor.
+    pub n_elements: usize,
+    /// The size (in bytes) of the tensor before quantization.
+    pub original_size: usize,
+    /// The size (in bytes) of the tensor after quantization.
+    pub reduced_size: usize,
+    /// The distribution of the quantized values, or `None` if the tensor was not quantized
+    /// (or only planned by [quantize_dry_run]).
+    pub histogram: Option<QuantizationHistogram>,
+}
+
+/// The outcome of quantizing a model, returned by [quantize] and
+/// [convert_hf](crate::convert::convert_hf), or planned by [quantize_dry_run].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QuantizeReport {
+    /// The size (in bytes) of the tensors before quantization.
+    pub original_size: usize,
+    /// The size (in bytes) of the tensors after quantization.
+    pub reduced_size: usize,
+    /// Every tensor of the model, in the order they were saved.
+    pub tensors: Vec<TensorQuantizeStats>,
+    /// The distribution of the quantized values of all quantized tensors.
+    pub histogram: QuantizationHistogram,
+}
+impl QuantizeReport {
+    /// An estimate of the memory needed for the tensor data while quantizing: tensors are
+    /// quantized one at a time, and the largest needs its original data, its values as `f32`
+    /// and its quantized data at once.
+    pub fn peak_memory(&self) -> usize {
+        self.tensors
+            .iter()
+            .map(|tensor| {
+                let converted = tensor.element_type != tensor.original_element_type
+                    && tensor.element_type.is_quantized();
+                let values_size = if converted { tensor.n_elements * 4 } else { 0 };
+                tensor.original_size + values_size + tensor.reduced_size
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Adds `tensor` to the report.
+    pub(crate) fn record(&mut self, tensor: TensorQuantizeStats) {
+        self.original_size += tensor.original_size;
+        self.reduced_size += tensor.reduced_size;
+        if let Some(histogram) = &tensor.histogram {
+            self.histogram.merge(histogram);
+        }
+        self.tensors.push(tensor);
+    }
+
+    /// The progress event for the end of quantization.
+    pub(crate) fn finished(&self) -> QuantizeProgress<'static> {
+        QuantizeProgress::Finished {
+            original_size: self.original_size,
+            reduced_size: self.reduced_size,
+            histogram: self.histogram.clone(),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 /// Errors encountered during the quantization process.
 pub enum QuantizeError {
@@ -135,7 +283,7 @@ impl QuantizeError {
     }
 }
 
-/// Quantizes a model.
+/// Quantizes a model, and returns a report of what happened to each tensor.
 pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     reader: &mut R,
     writer: &mut W,
@@ -143,7 +291,7 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     save_container_type: ggml::format::SaveContainerType,
     quantization_type: ggml::Type,
     progress_callback: impl Fn(QuantizeProgress),
-) -> Result<(), QuantizeError> {
+) -> Result<QuantizeReport, QuantizeError> {
     // Sanity check
     let quantization_target = QuantizationTarget::try_from(quantization_type).map_err(|_| {
         QuantizeError::InvalidQuantizationTarget {
@@ -206,18 +354,114 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     .map_err(|err| QuantizeError::from_format_error(err, PathBuf::default()))?;
 
     // Final report
-    let sum_all: i64 = saver.history_all.iter().sum();
-    progress_callback(QuantizeProgress::Finished {
-        original_size: saver.total_size_original,
-        reduced_size: saver.total_size_new,
-        history: saver
-            .history_all
-            .iter()
-            .map(|hist| *hist as f32 / sum_all as f32)
-            .collect(),
-    });
+    progress_callback(saver.report.finished());
 
-    Ok(())
+    Ok(saver.report)
+}
+
+/// Plans the quantization of a model without quantizing it: only the metadata of the
+/// tensors is read, and the report has the type and projected size each tensor would be
+/// saved with by [quantize]. No histograms are computed.
+pub fn quantize_dry_run<M: KnownModel, R: BufRead + Seek>(
+    reader: &mut R,
+    quantization_type: ggml::Type,
+) -> Result<QuantizeReport, QuantizeError> {
+    let quantization_target = QuantizationTarget::try_from(quantization_type).map_err(|_| {
+        QuantizeError::InvalidQuantizationTarget {
+            element_type: quantization_type,
+        }
+    })?;
+
+    let mut loader = Loader::<M::Hyperparameters, _>::new(Tokenizer::empty_embedded(), |_| {});
+    ggml::format::load(reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, PathBuf::default()))?;
+
+    let to_quantize = M::quantize_tensors();
+    let to_skip = M::skip_quantize_tensors();
+    let mut tensors: Vec<_> = loader.tensors.values().collect();
+    tensors.sort_by_key(|tensor| tensor.start_offset);
+
+    let mut report = QuantizeReport::default();
+    for tensor in tensors {
+        let quantize = should_quantize(&tensor.name, tensor, &to_quantize, &to_skip)?;
+        let element_type = saved_element_type(tensor, quantize, quantization_target);
+        report.record(TensorQuantizeStats {
+            name: tensor.name.clone(),
+            original_element_type: tensor.element_type,
+            element_type,
+            n_elements: tensor.n_elements,
+            original_size: tensor.calc_size(),
+            reduced_size: ggml::type_size(element_type) * tensor.n_elements
+                / ggml::blck_size(element_type),
+            histogram: None,
+        });
+    }
+    Ok(report)
+}
+
+/// Whether `tensor` should be quantized: only the 2D tensors that the model allows to be.
+fn should_quantize(
+    tensor_name: &str,
+    tensor: &TensorLoadInfo,
+    to_quantize: &[Regex],
+    to_skip: &[Regex],
+) -> Result<bool, QuantizeError> {
+    let quantize = tensor.n_dims == 2
+        && to_quantize.iter().any(|re| re.is_match(tensor_name))
+        && !to_skip.iter().any(|re| re.is_match(tensor_name));
+
+    if quantize
+        && !matches!(tensor.element_type, ggml::Type::F32 | ggml::Type::F16)
+        && !tensor.element_type.is_file_only()
+    {
+        return Err(QuantizeError::UnsupportedElementType {
+            element_type: tensor.element_type,
+        });
+    }
+    Ok(quantize)
+}
+
+/// The type `tensor` is saved as by [quantize]. A `bf16` tensor whose values do not fit in
+/// `f16` is saved as `f32` instead, which only shows once its data is read.
+fn saved_element_type(
+    tensor: &TensorLoadInfo,
+    quantize: bool,
+    quantization_target: QuantizationTarget,
+) -> ggml::Type {
+    if quantize {
+        quantization_target.into()
+    } else {
+        // `ggml` cannot use the file-only types, so they are converted rather than copied
+        // as-is.
+        loaded_element_type(tensor.element_type, tensor.n_dims)
+    }
+}
+
+/// Quantizes `data`, a tensor of `n_elements` elements with rows of `n_elements_0`
+/// elements, to `element_type`.
+///
+/// # Panics
+/// Panics if `element_type` is not a quantized type that can be produced.
+pub(crate) fn quantize_data(
+    element_type: ggml::Type,
+    data: &[f32],
+    n_elements: usize,
+    n_elements_0: usize,
+) -> ggml::QuantizationResult {
+    let quantize = match element_type {
+        ggml::Type::Q4_0 => ggml::quantize_q4_0,
+        ggml::Type::Q4_1 => ggml::quantize_q4_1,
+        ggml::Type::Q5_0 => ggml::quantize_q5_0,
+        ggml::Type::Q5_1 => ggml::quantize_q5_1,
+        ggml::Type::Q8_0 => ggml::quantize_q8_0,
+        ggml::Type::Q2_K => ggml::quantize_q2_k,
+        ggml::Type::Q3_K => ggml::quantize_q3_k,
+        ggml::Type::Q4_K => ggml::quantize_q4_k,
+        ggml::Type::Q5_K => ggml::quantize_q5_k,
+        ggml::Type::Q6_K => ggml::quantize_q6_k,
+        _ => panic!("cannot quantize to {element_type}"),
+    };
+    quantize(data, n_elements, n_elements_0)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -276,9 +520,7 @@ struct QuantizeSaver<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead
     progress_callback: F,
 
     // Output
-    total_size_original: usize,
-    total_size_new: usize,
-    history_all: Vec<i64>,
+    report: QuantizeReport,
 }
 impl<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek>
     QuantizeSaver<'a, F, H, R>
@@ -301,9 +543,7 @@ impl<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek>
             source_reader,
             progress_callback,
 
-            total_size_original: 0,
-            total_size_new: 0,
-            history_all: vec![0; 16],
+            report: QuantizeReport::default(),
         }
     }
 }
@@ -329,21 +569,12 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
             element_type: tensor.element_type,
         });
 
-        // Quantize only 2D tensors
-        let quantize = tensor.n_dims == 2
-            && self.to_quantize.iter().any(|re| re.is_match(tensor_name))
-            && !self.to_skip.iter().any(|re| re.is_match(tensor_name));
+        let quantize = should_quantize(tensor_name, tensor, self.to_quantize, self.to_skip)?;
+        let mut element_type = saved_element_type(tensor, quantize, self.quantization_target);
         let raw_data = tensor.read_data(self.source_reader)?;
 
-        if quantize && !matches!(tensor.element_type, ggml::Type::F32 | ggml::Type::F16) {
-            return Err(QuantizeError::UnsupportedElementType {
-                element_type: tensor.element_type,
-            });
-        }
-
-        self.total_size_original += raw_data.len();
-
-        let (element_type, data) = if quantize {
+        let original_size = raw_data.len();
+        let (data, histogram) = if quantize {
             (self.progress_callback)(QuantizeProgress::TensorQuantizing { name: tensor_name });
 
             let data_f32: Vec<f32> = match tensor.element_type {
@@ -357,52 +588,45 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
                         f16::from_bits(u16::from_le_bytes(chunk.try_into().unwrap())).to_f32()
                     })
                     .collect(),
-                _ => unreachable!(),
+                _ => dequantize_file_only(&raw_data, tensor.element_type),
             };
 
-            let result = match self.quantization_target {
-                QuantizationTarget::Q4_0 => {
-                    ggml::quantize_q4_0(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q4_1 => {
-                    ggml::quantize_q4_1(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q5_0 => {
-                    ggml::quantize_q5_0(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q5_1 => {
-                    ggml::quantize_q5_1(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                QuantizationTarget::Q8_0 => {
-                    ggml::quantize_q8_0(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-            };
+            let result = quantize_data(element_type, &data_f32, tensor.n_elements, tensor.dims[0]);
             let new_data = result.output;
-
-            let mut history_new = vec![];
-            for (i, val) in result.history.iter().enumerate() {
-                self.history_all[i] += val;
-                history_new.push(*val as f32 / tensor.n_elements as f32);
-            }
+            let histogram = QuantizationHistogram::from_ggml(&result.history);
 
             (self.progress_callback)(QuantizeProgress::TensorQuantized {
                 name: tensor_name,
-                original_size: raw_data.len(),
+                original_size,
                 reduced_size: new_data.len(),
-                history: history_new,
+                histogram: histogram.clone(),
             });
 
-            self.total_size_new += new_data.len();
-
-            (self.quantization_target.into(), new_data)
+            (new_data, Some(histogram))
         } else {
             (self.progress_callback)(QuantizeProgress::TensorSkipped {
                 name: tensor_name,
                 size: raw_data.len(),
             });
-            self.total_size_new += raw_data.len();
-            (tensor.element_type, raw_data)
+            let data = if tensor.element_type.is_file_only() {
+                let (converted_type, data) =
+                    convert_file_only_to_fit(&raw_data, tensor.element_type, tensor.n_dims);
+                element_type = converted_type;
+                data
+            } else {
+                raw_data
+            };
+            (data, None)
         };
+        self.report.record(TensorQuantizeStats {
+            name: tensor_name.to_owned(),
+            original_element_type: tensor.element_type,
+            element_type,
+            n_elements: tensor.n_elements,
+            original_size,
+            reduced_size: data.len(),
+            histogram,
+        });
 
         Ok(TensorSaveInfo {
             n_dims: tensor.n_dims,
@@ -412,3 +636,41 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_are_merged_into_the_report() {
+        let mut report = QuantizeReport::default();
+        let mut history = vec![0; QuantizationHistogram::BUCKETS];
+        history[3] = 3;
+        history[8] = 1;
+        report.record(TensorQuantizeStats {
+            name: "layers.0.attention.wq.weight".to_owned(),
+            original_element_type: ggml::Type::F32,
+            element_type: ggml::Type::Q4_0,
+            n_elements: 8,
+            original_size: 32,
+            reduced_size: 18,
+            histogram: Some(QuantizationHistogram::from_ggml(&history)),
+        });
+        report.record(TensorQuantizeStats {
+            name: "norm.weight".to_owned(),
+            original_element_type: ggml::Type::F32,
+            element_type: ggml::Type::F32,
+            n_elements: 4,
+            original_size: 16,
+            reduced_size: 16,
+            histogram: None,
+        });
+
+        assert_eq!((report.original_size, report.reduced_size), (48, 34));
+        assert_eq!(report.peak_memory(), 32 + 8 * 4 + 18);
+        assert_eq!(report.histogram.total(), 4);
+        assert_eq!(report.histogram.frequencies()[3], 0.75);
+        assert_eq!(QuantizationHistogram::bucket_bounds(8), (0.5, 0.5625));
+        assert!(QuantizationHistogram::default().is_empty());
+    }
+}
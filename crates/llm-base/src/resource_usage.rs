@@ -0,0 +1,130 @@
+//! Measurement of the resources (memory, CPU time and energy) used by the process.
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Resources used by the process while running inference. Reported as part of
+/// [InferenceStats](crate::InferenceStats).
+///
+/// Each value is `None` if it could not be measured on this platform.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResourceUsage {
+    /// The peak resident set size of the process, in bytes.
+    ///
+    /// This is a process-wide high-water mark, and includes memory used before inference
+    /// started (e.g. the model weights).
+    pub peak_rss_bytes: Option<u64>,
+    /// The CPU time spent in user mode, summed across all threads.
+    pub user_cpu_time: Option<Duration>,
+    /// The CPU time spent in kernel mode, summed across all threads.
+    pub system_cpu_time: Option<Duration>,
+    /// An estimate of the energy consumed by the CPU package(s), in joules.
+    ///
+    /// This is read from Intel RAPL counters via `/sys/class/powercap` on Linux, which are
+    /// often only readable by root. The counters are system-wide, so other processes running
+    /// at the same time will be included.
+    pub energy_joules: Option<f64>,
+}
+
+/// A snapshot of the counters used to compute a [ResourceUsage].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ResourceSnapshot {
+    user_cpu_time: Option<Duration>,
+    system_cpu_time: Option<Duration>,
+    energy_microjoules: Option<u64>,
+}
+impl ResourceSnapshot {
+    /// Takes a snapshot of the current counters.
+    pub(crate) fn now() -> Self {
+        let (user_cpu_time, system_cpu_time) = match cpu_times() {
+            Some((user, system)) => (Some(user), Some(system)),
+            None => (None, None),
+        };
+        Self {
+            user_cpu_time,
+            system_cpu_time,
+            energy_microjoules: rapl_energy_microjoules(),
+        }
+    }
+
+    /// Computes the resources used between `self` and now.
+    pub(crate) fn elapsed(&self) -> ResourceUsage {
+        let now = Self::now();
+        fn sub(later: Option<Duration>, earlier: Option<Duration>) -> Option<Duration> {
+            Some(later?.saturating_sub(earlier?))
+        }
+
+        ResourceUsage {
+            peak_rss_bytes: peak_rss_bytes(),
+            user_cpu_time: sub(now.user_cpu_time, self.user_cpu_time),
+            system_cpu_time: sub(now.system_cpu_time, self.system_cpu_time),
+            energy_joules: now
+                .energy_microjoules
+                .zip(self.energy_microjoules)
+                // The counter wraps around; ignore the measurement if it did.
+                .and_then(|(later, earlier)| later.checked_sub(earlier))
+                .map(|uj| uj as f64 / 1_000_000.0),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn rusage() -> Option<libc::rusage> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    // SAFETY: `usage` is a valid pointer to a `rusage`, which `getrusage` fills in on success.
+    let result = unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) };
+    // SAFETY: `getrusage` succeeded, so `usage` has been initialised.
+    (result == 0).then(|| unsafe { usage.assume_init() })
+}
+
+#[cfg(unix)]
+fn cpu_times() -> Option<(Duration, Duration)> {
+    fn to_duration(tv: libc::timeval) -> Duration {
+        Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
+    }
+    let usage = rusage()?;
+    Some((to_duration(usage.ru_utime), to_duration(usage.ru_stime)))
+}
+
+#[cfg(not(unix))]
+fn cpu_times() -> Option<(Duration, Duration)> {
+    None
+}
+
+#[cfg(unix)]
+fn peak_rss_bytes() -> Option<u64> {
+    let max_rss = rusage()?.ru_maxrss as u64;
+    // `ru_maxrss` is in bytes on macOS and kilobytes everywhere else.
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+#[cfg(not(unix))]
+fn peak_rss_bytes() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn rapl_energy_microjoules() -> Option<u64> {
+    // Sum the top-level package domains (`intel-rapl:0`, `intel-rapl:1`, ...), skipping
+    // subdomains such as `intel-rapl:0:0`, which are already included in their package.
+    let mut total = None;
+    for entry in std::fs::read_dir("/sys/class/powercap").ok()?.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with("intel-rapl:") || name.matches(':').count() != 1 {
+            continue;
+        }
+        let energy = std::fs::read_to_string(entry.path().join("energy_uj")).ok()?;
+        *total.get_or_insert(0) += energy.trim().parse::<u64>().ok()?;
+    }
+    total
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rapl_energy_microjoules() -> Option<u64> {
+    None
+}
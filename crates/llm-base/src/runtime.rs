@@ -0,0 +1,531 @@
+//! An optional runtime that owns a model and runs inference on dedicated worker threads.
+//!
+//! [InferenceSession](crate::InferenceSession) is blocking, and applications with a UI thread or an async executor
+//! would otherwise need to design their own threading around it. A [Runtime] spawns a fixed
+//! number of worker threads that pull requests from a shared queue. Requests are submitted
+//! through a cheap, cloneable [RuntimeHandle], and their output is delivered through a
+//! [GenerationStream], which can be consumed either as a blocking [Iterator] or from async
+//! code with [GenerationStream::next_event].
+//!
+//! The runtime is not available on WebAssembly, which does not have threads by default.
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    thread::JoinHandle,
+};
+
+use rand::SeedableRng;
+use thiserror::Error;
+
+use crate::{
+    guardrail::Guardrail, memory::MemoryLimitExceeded, InferenceError, InferenceFeedback,
+    InferenceParameters, InferenceRequest, InferenceResponse, InferenceSessionConfig,
+    InferenceStats, Model, TokenId,
+};
+
+/// Configuration for a [Runtime].
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    /// The number of worker threads. Each worker runs one generation at a time.
+    ///
+    /// Note that each generation also uses [InferenceParameters::n_threads] threads for
+    /// computation, so this should usually be kept small.
+    pub worker_count: usize,
+    /// The configuration used for the sessions created for each request.
+    pub session_config: InferenceSessionConfig,
+}
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            worker_count: 1,
+            session_config: Default::default(),
+        }
+    }
+}
+
+/// A request to generate text, submitted with [RuntimeHandle::generate].
+#[derive(Debug, Clone)]
+pub struct GenerationRequest {
+    /// The prompt to feed to the model.
+    pub prompt: String,
+    /// The parameters to use for this request.
+    pub parameters: InferenceParameters,
+    /// The maximum number of tokens to generate.
+    pub maximum_token_count: Option<usize>,
+    /// The maximum number of bytes of text to generate.
+    pub maximum_output_bytes: Option<usize>,
+    /// The maximum number of characters of text to generate.
+    pub maximum_output_chars: Option<usize>,
+    /// Sequences of token IDs that stop generation. See
+    /// [InferenceRequest::stop_token_sequences].
+    pub stop_token_sequences: Vec<Vec<TokenId>>,
+    /// Policies enforced on the generated text. See [InferenceRequest::guardrails].
+    pub guardrails: Vec<Arc<dyn Guardrail>>,
+    /// Text that the response is forced to start with. See [InferenceRequest::forced_prefix].
+    pub forced_prefix: Option<String>,
+    /// The seed for the random number generator. If not specified, a random seed is used.
+    pub seed: Option<u64>,
+    /// The maximum number of tokens that the [GenerationStream] holds before they are
+    /// consumed. When it is full, generation waits for the consumer, so that a slow consumer
+    /// (such as text-to-speech) paces generation. If not specified, generation never waits.
+    pub max_buffered_tokens: Option<usize>,
+}
+impl GenerationRequest {
+    /// Creates a request for `prompt` with the default parameters.
+    pub fn new(prompt: impl Into<String>) -> Self {
+        Self {
+            prompt: prompt.into(),
+            parameters: Default::default(),
+            maximum_token_count: None,
+            maximum_output_bytes: None,
+            maximum_output_chars: None,
+            stop_token_sequences: vec![],
+            guardrails: vec![],
+            forced_prefix: None,
+            seed: None,
+            max_buffered_tokens: None,
+        }
+    }
+}
+
+/// An event produced by a [GenerationStream].
+#[derive(Debug)]
+pub enum GenerationEvent {
+    /// A token that has been generated.
+    Token(String),
+    /// Generation has finished. This is always the last event of a successful generation.
+    Finished(InferenceStats),
+}
+
+/// Errors encountered while running a request on a [Runtime].
+#[derive(Error, Debug)]
+pub enum RuntimeError {
+    /// Inference failed.
+    #[error("inference failed")]
+    Inference(#[from] InferenceError),
+    /// The session for the request could not be started without exceeding the
+    /// [memory limit](crate::memory::set_limit).
+    #[error("the session could not be started")]
+    MemoryLimitExceeded(#[from] MemoryLimitExceeded),
+    /// The runtime was shut down before the request could complete.
+    #[error("the runtime was shut down")]
+    ShutDown,
+}
+
+/// Owns a model and runs inference requests on dedicated worker threads.
+///
+/// Dropping the runtime is equivalent to calling [Runtime::shutdown].
+pub struct Runtime {
+    handle: RuntimeHandle,
+    workers: Vec<JoinHandle<()>>,
+}
+impl Runtime {
+    /// Starts a runtime for `model`.
+    pub fn new(model: Arc<dyn Model>, config: RuntimeConfig) -> Self {
+        let (sender, receiver) = mpsc::channel::<Message>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..config.worker_count.max(1))
+            .map(|index| {
+                let model = model.clone();
+                let receiver = receiver.clone();
+                let session_config = config.session_config.clone();
+                std::thread::Builder::new()
+                    .name(format!("llm-runtime-{index}"))
+                    .spawn(move || worker(model.as_ref(), session_config, &receiver))
+                    .expect("failed to spawn runtime worker thread")
+            })
+            .collect();
+
+        Self {
+            handle: RuntimeHandle { sender },
+            workers,
+        }
+    }
+
+    /// Returns a handle that can be used to submit requests to this runtime.
+    pub fn handle(&self) -> RuntimeHandle {
+        self.handle.clone()
+    }
+
+    /// Waits for the requests submitted so far to finish, then stops the workers.
+    ///
+    /// Requests submitted after this point will return [RuntimeError::ShutDown].
+    pub fn shutdown(mut self) {
+        self.shutdown_impl();
+    }
+
+    fn shutdown_impl(&mut self) {
+        for _ in &self.workers {
+            let _ = self.handle.sender.send(Message::Shutdown);
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        self.shutdown_impl();
+    }
+}
+
+/// A cheap, cloneable handle used to submit requests to a [Runtime].
+#[derive(Clone)]
+pub struct RuntimeHandle {
+    sender: mpsc::Sender<Message>,
+}
+impl RuntimeHandle {
+    /// Submits `request` to the runtime, returning a stream of its output.
+    ///
+    /// If the runtime has been shut down, the stream will immediately yield
+    /// [RuntimeError::ShutDown].
+    pub fn generate(&self, request: GenerationRequest) -> GenerationStream {
+        let shared = Arc::new(StreamShared::default());
+        // If the runtime has shut down, the job is dropped here, which finishes the stream.
+        let _ = self.sender.send(Message::Job(Box::new(Job {
+            request,
+            stream: shared.clone(),
+        })));
+        GenerationStream { shared }
+    }
+}
+
+/// The output of a request submitted with [RuntimeHandle::generate].
+///
+/// The stream can be consumed as a blocking [Iterator], or asynchronously with
+/// [GenerationStream::next_event]. Dropping the stream cancels the request.
+pub struct GenerationStream {
+    shared: Arc<StreamShared>,
+}
+impl GenerationStream {
+    /// Returns a future that resolves to the next event, or `None` if the stream has ended.
+    pub fn next_event(&mut self) -> NextEvent<'_> {
+        NextEvent { stream: self }
+    }
+
+    /// Cancels the request. Generation will stop after the current token.
+    pub fn cancel(&self) {
+        self.shared.state.lock().unwrap().cancelled = true;
+        self.shared.condvar.notify_all();
+    }
+
+    fn poll_next(
+        &self,
+        cx: Option<&Context<'_>>,
+    ) -> Poll<Option<Result<GenerationEvent, RuntimeError>>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(event) = state.events.pop_front() {
+            // Wake up the worker if it is waiting for room in the buffer.
+            self.shared.condvar.notify_all();
+            return Poll::Ready(Some(event));
+        }
+        if state.finished {
+            return Poll::Ready(None);
+        }
+        if let Some(cx) = cx {
+            state.waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+impl Iterator for GenerationStream {
+    type Item = Result<GenerationEvent, RuntimeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(event) = state.events.pop_front() {
+                self.shared.condvar.notify_all();
+                return Some(event);
+            }
+            if state.finished {
+                return None;
+            }
+            state = self.shared.condvar.wait(state).unwrap();
+        }
+    }
+}
+impl Drop for GenerationStream {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// The future returned by [GenerationStream::next_event].
+pub struct NextEvent<'a> {
+    stream: &'a mut GenerationStream,
+}
+impl Future for NextEvent<'_> {
+    type Output = Option<Result<GenerationEvent, RuntimeError>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.stream.poll_next(Some(cx))
+    }
+}
+
+enum Message {
+    Job(Box<Job>),
+    Shutdown,
+}
+
+struct Job {
+    request: GenerationRequest,
+    stream: Arc<StreamShared>,
+}
+impl Drop for Job {
+    fn drop(&mut self) {
+        // Make sure the stream always ends, even if the job was never run (e.g. because the
+        // runtime shut down) or the worker panicked.
+        let mut state = self.stream.state.lock().unwrap();
+        if !state.finished {
+            state.events.push_back(Err(RuntimeError::ShutDown));
+            state.finished = true;
+            self.stream.notify(&mut state);
+        }
+    }
+}
+
+#[derive(Default)]
+struct StreamShared {
+    state: Mutex<StreamState>,
+    condvar: Condvar,
+}
+impl StreamShared {
+    /// Adds `event` to the stream, then waits until the stream holds fewer than
+    /// `max_buffered` events. Returns whether the stream is still wanted.
+    fn push(&self, event: GenerationEvent, max_buffered: Option<usize>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.events.push_back(Ok(event));
+        self.notify(&mut state);
+        if let Some(max_buffered) = max_buffered {
+            while state.events.len() >= max_buffered.max(1) && !state.cancelled {
+                state = self.condvar.wait(state).unwrap();
+            }
+        }
+        !state.cancelled
+    }
+
+    fn finish(&self, result: Result<GenerationEvent, RuntimeError>) {
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return;
+        }
+        state.events.push_back(result);
+        state.finished = true;
+        self.notify(&mut state);
+    }
+
+    fn notify(&self, state: &mut StreamState) {
+        self.condvar.notify_all();
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    events: VecDeque<Result<GenerationEvent, RuntimeError>>,
+    finished: bool,
+    cancelled: bool,
+    waker: Option<Waker>,
+}
+
+fn worker(
+    model: &dyn Model,
+    session_config: InferenceSessionConfig,
+    receiver: &Mutex<mpsc::Receiver<Message>>,
+) {
+    loop {
+        // Only hold the lock while waiting for a job, so that other workers can pick up
+        // requests while this one is generating.
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(Message::Job(job)) => job,
+            Ok(Message::Shutdown) | Err(_) => return,
+        };
+        let (request, stream) = (&job.request, &job.stream);
+        if stream.state.lock().unwrap().cancelled {
+            continue;
+        }
+
+        let mut rng = match request.seed {
+            Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+            None => rand::rngs::StdRng::from_entropy(),
+        };
+        let mut session = match model.try_start_session(session_config.clone()) {
+            Ok(session) => session,
+            Err(err) => {
+                stream.finish(Err(err.into()));
+                continue;
+            }
+        };
+        let guardrails = request
+            .guardrails
+            .iter()
+            .map(|guardrail| guardrail.as_ref())
+            .collect::<Vec<_>>();
+        let result = session.infer::<std::convert::Infallible>(
+            model,
+            &mut rng,
+            &InferenceRequest {
+                prompt: request.prompt.as_str().into(),
+                parameters: &request.parameters,
+                play_back_previous_tokens: false,
+                maximum_token_count: request.maximum_token_count,
+                maximum_output_bytes: request.maximum_output_bytes,
+                maximum_output_chars: request.maximum_output_chars,
+                stop_token_sequences: &request.stop_token_sequences,
+                guardrails: &guardrails,
+                forced_prefix: request.forced_prefix.as_deref(),
+                logprobs: None,
+                early_stop: Default::default(),
+                cancellation_token: None,
+                deadline: None,
+            },
+            &mut Default::default(),
+            |response| match response {
+                InferenceResponse::InferredToken(token) => Ok(
+                    if stream.push(GenerationEvent::Token(token), request.max_buffered_tokens) {
+                        InferenceFeedback::Continue
+                    } else {
+                        InferenceFeedback::Halt
+                    },
+                ),
+                _ => Ok(InferenceFeedback::Continue),
+            },
+        );
+
+        stream.finish(
+            result
+                .map(GenerationEvent::Finished)
+                .map_err(RuntimeError::from),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::MockModel;
+
+    use super::*;
+
+    fn runtime(worker_count: usize) -> Runtime {
+        let model = MockModel::new(&["Hello", ",", " world", "!"]).with_response(", world!");
+        Runtime::new(
+            Arc::new(model),
+            RuntimeConfig {
+                worker_count,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// The text of the tokens of `stream`, and whether it finished successfully.
+    fn collect(stream: GenerationStream) -> (String, bool) {
+        let mut text = String::new();
+        let mut finished = false;
+        for event in stream {
+            match event {
+                Ok(GenerationEvent::Token(token)) => text.push_str(&token),
+                Ok(GenerationEvent::Finished(_)) => finished = true,
+                Err(_) => return (text, false),
+            }
+        }
+        (text, finished)
+    }
+
+    #[test]
+    fn handles_submit_requests_to_the_workers() {
+        let runtime = runtime(2);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = runtime.handle();
+                std::thread::spawn(move || {
+                    collect(handle.generate(GenerationRequest::new("Hello")))
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), (", world!".to_string(), true));
+        }
+
+        let mut request = GenerationRequest::new("Hello");
+        request.maximum_token_count = Some(1);
+        assert_eq!(
+            collect(runtime.handle().generate(request)),
+            (",".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn shutdown_finishes_the_submitted_requests() {
+        let runtime = runtime(1);
+        let handle = runtime.handle();
+        let streams: Vec<_> = (0..3)
+            .map(|_| handle.generate(GenerationRequest::new("Hello")))
+            .collect();
+        runtime.shutdown();
+        for stream in streams {
+            assert_eq!(collect(stream), (", world!".to_string(), true));
+        }
+
+        let mut stream = handle.generate(GenerationRequest::new("Hello"));
+        assert!(matches!(stream.next(), Some(Err(RuntimeError::ShutDown))));
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn dropping_the_runtime_shuts_it_down() {
+        let handle = runtime(1).handle();
+        let mut stream = handle.generate(GenerationRequest::new("Hello"));
+        assert!(matches!(stream.next(), Some(Err(RuntimeError::ShutDown))));
+    }
+
+    #[test]
+    fn stream_yields_events_until_finished() {
+        let shared = Arc::new(StreamShared::default());
+        let mut stream = GenerationStream {
+            shared: shared.clone(),
+        };
+
+        assert!(shared.push(GenerationEvent::Token("a".to_string()), None));
+        assert!(
+            matches!(stream.poll_next(None), Poll::Ready(Some(Ok(GenerationEvent::Token(t)))) if t == "a")
+        );
+        assert!(matches!(stream.poll_next(None), Poll::Pending));
+
+        shared.finish(Err(RuntimeError::ShutDown));
+        assert!(matches!(stream.next(), Some(Err(RuntimeError::ShutDown))));
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn full_streams_wait_for_the_consumer() {
+        let shared = Arc::new(StreamShared::default());
+        let mut stream = GenerationStream {
+            shared: shared.clone(),
+        };
+
+        let producer = std::thread::spawn(move || {
+            for token in ["a", "b", "c"] {
+                assert!(shared.push(GenerationEvent::Token(token.to_string()), Some(1)));
+                // The consumer has taken the token before the producer can continue.
+                assert!(shared.state.lock().unwrap().events.is_empty());
+            }
+            shared.finish(Err(RuntimeError::ShutDown));
+        });
+        let tokens: Vec<_> = stream
+            .by_ref()
+            .filter_map(|event| match event {
+                Ok(GenerationEvent::Token(token)) => Some(token),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tokens, ["a", "b", "c"]);
+        producer.join().unwrap();
+    }
+}
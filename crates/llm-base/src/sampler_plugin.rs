@@ -0,0 +1,183 @@
+//! Samplers loaded from dynamic libraries, to experiment with sampling without recompiling
+//! `llm`.
+//!
+//! A plugin is a shared library (e.g. a Rust `cdylib`) that exports two functions with the
+//! C ABI:
+//!
+//! ```c
+//! // Returns SAMPLER_PLUGIN_ABI_VERSION.
+//! uint32_t llm_sampler_abi_version(void);
+//!
+//! // Returns the ID of the next token, given the tokens so far and the logits of the
+//! // vocabulary. `random` is drawn from the random number generator of the session, so
+//! // that a seeded generation stays reproducible.
+//! uint32_t llm_sampler_sample(
+//!     const uint32_t *previous_tokens, size_t previous_tokens_len,
+//!     const float *logits, size_t logits_len,
+//!     uint64_t random);
+//! ```
+//!
+//! The pointers are only valid during the call. The sampler can be called from several
+//! threads at once, so `llm_sampler_sample` must be thread-safe.
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use libloading::Library;
+use thiserror::Error;
+
+use crate::{samplers::Sampler, TokenId};
+
+/// The version of the C ABI of sampler plugins, which `llm_sampler_abi_version` must return.
+pub const SAMPLER_PLUGIN_ABI_VERSION: u32 = 1;
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type SampleFn = unsafe extern "C" fn(*const TokenId, usize, *const f32, usize, u64) -> TokenId;
+
+/// A [Sampler] implemented by a plugin. See the [module documentation](self).
+#[derive(Clone)]
+pub struct PluginSampler {
+    path: PathBuf,
+    sample: SampleFn,
+    // Keeps the library loaded for as long as `sample` can be called.
+    _library: Arc<Library>,
+}
+impl PluginSampler {
+    /// Loads the plugin at `path`.
+    ///
+    /// # Safety
+    ///
+    /// Loading a library runs its initialization code, and the functions it exports are
+    /// trusted to have the signatures of the [module documentation](self).
+    pub unsafe fn load(path: &Path) -> Result<Self, SamplerPluginError> {
+        let library = Library::new(path).map_err(|source| SamplerPluginError::Load {
+            path: path.to_owned(),
+            source,
+        })?;
+        let abi_version = *library
+            .get::<AbiVersionFn>(b"llm_sampler_abi_version\0")
+            .map_err(|source| SamplerPluginError::MissingSymbol {
+                path: path.to_owned(),
+                symbol: "llm_sampler_abi_version",
+                source,
+            })?;
+        let version = abi_version();
+        if version != SAMPLER_PLUGIN_ABI_VERSION {
+            return Err(SamplerPluginError::AbiVersion {
+                path: path.to_owned(),
+                version,
+            });
+        }
+        let sample = *library
+            .get::<SampleFn>(b"llm_sampler_sample\0")
+            .map_err(|source| SamplerPluginError::MissingSymbol {
+                path: path.to_owned(),
+                symbol: "llm_sampler_sample",
+                source,
+            })?;
+        Ok(Self {
+            path: path.to_owned(),
+            sample,
+            _library: Arc::new(library),
+        })
+    }
+
+    /// The path the plugin was loaded from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+impl Debug for PluginSampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PluginSampler")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+impl Sampler for PluginSampler {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        // SAFETY: the library is loaded, and the pointers are valid for the given lengths
+        // during the call.
+        let token = unsafe {
+            (self.sample)(
+                previous_tokens.as_ptr(),
+                previous_tokens.len(),
+                logits.as_ptr(),
+                logits.len(),
+                rng.next_u64(),
+            )
+        };
+        if (token as usize) < logits.len() {
+            return token;
+        }
+        let greedy = logits
+            .iter()
+            .enumerate()
+            .filter(|(_, logit)| !logit.is_nan())
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map_or(0, |(id, _)| id as TokenId);
+        log::warn!(
+            "the sampler plugin {:?} returned the token {token}, which is not in the \
+             vocabulary of {} tokens; falling back to the most likely token, {greedy}",
+            self.path,
+            logits.len()
+        );
+        greedy
+    }
+}
+
+/// Errors encountered when loading a [PluginSampler].
+#[derive(Error, Debug)]
+pub enum SamplerPluginError {
+    /// The library could not be loaded.
+    #[error("could not load the sampler plugin {path:?}")]
+    Load {
+        /// The path of the library.
+        path: PathBuf,
+        /// The underlying error.
+        #[source]
+        source: libloading::Error,
+    },
+    /// The library does not export one of the functions of the C ABI.
+    #[error("the sampler plugin {path:?} does not export `{symbol}`")]
+    MissingSymbol {
+        /// The path of the library.
+        path: PathBuf,
+        /// The name of the function.
+        symbol: &'static str,
+        /// The underlying error.
+        #[source]
+        source: libloading::Error,
+    },
+    /// The library implements another version of the C ABI.
+    #[error(
+        "the sampler plugin {path:?} implements version {version} of the plugin ABI, \
+         but version {SAMPLER_PLUGIN_ABI_VERSION} is required"
+    )]
+    AbiVersion {
+        /// The path of the library.
+        path: PathBuf,
+        /// The version that `llm_sampler_abi_version` returned.
+        version: u32,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_library_fails_to_load() {
+        let path = Path::new("/nonexistent/libsampler.so");
+        // SAFETY: the library does not exist, so no code is run.
+        let err = unsafe { PluginSampler::load(path) }.unwrap_err();
+        assert!(matches!(err, SamplerPluginError::Load { path: p, .. } if p == path));
+    }
+}
@@ -2,23 +2,107 @@
 //!
 //! You can define your own [Sampler] by implementing the trait.
 
-use std::fmt::Debug;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    sync::Arc,
+};
 
 use partial_sort::PartialSort;
 use rand::{distributions::WeightedIndex, prelude::Distribution};
 
-use crate::{TokenBias, TokenId};
+use crate::{TokenBias, TokenId, Tokenizer};
 
 /// A sampler for generation.
+///
+/// Samplers are shared between sessions, so any state they need to keep between tokens is
+/// stored in the [SamplerState] of the [InferenceSession](crate::InferenceSession) instead.
 pub trait Sampler: Debug + Send + Sync {
     /// Given the previous tokens, the logits from the most recent evaluation, and a source of randomness,
     /// sample from the logits and return the token ID.
+    ///
+    /// Stateful samplers should behave as if they were starting from a fresh [SamplerState].
     fn sample(
         &self,
         previous_tokens: &[TokenId],
         logits: &[f32],
         rng: &mut dyn rand::RngCore,
     ) -> TokenId;
+
+    /// Like [Sampler::sample], but with the state that the session has kept since the
+    /// previous token, which the sampler can update.
+    ///
+    /// This is what inference calls. The default implementation ignores the state.
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let _ = state;
+        self.sample(previous_tokens, logits, rng)
+    }
+}
+
+/// The state kept by a [Sampler] between tokens.
+///
+/// It is owned by the [InferenceSession](crate::InferenceSession), so it carries over between
+/// calls to [InferenceSession::infer](crate::InferenceSession::infer) and is saved in snapshots,
+/// which makes multi-turn generation behave like one continuous stream.
+///
+/// Each stateful sampler has its own field, so that samplers do not overwrite each other's
+/// state. Samplers defined outside this crate keep theirs in a [custom](Self::custom) slot,
+/// keyed by their name.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SamplerState {
+    /// The state of [Mirostat2], once it has sampled a token.
+    pub mirostat2: Option<Mirostat2State>,
+    generation_start: usize,
+    custom: BTreeMap<String, serde_bytes::ByteBuf>,
+}
+impl SamplerState {
+    /// The number of previous tokens that were part of the prompt: generation started at
+    /// this index. The session updates it whenever a prompt is fed.
+    pub fn generation_start(&self) -> usize {
+        self.generation_start
+    }
+
+    /// Sets the index at which generation started. See [Self::generation_start].
+    pub fn set_generation_start(&mut self, generation_start: usize) {
+        self.generation_start = generation_start;
+    }
+
+    /// The state stored by the sampler named `sampler` with [Self::set_custom], if any.
+    ///
+    /// This is for samplers defined outside this crate, which serialize their state in the
+    /// format of their choice. It is saved in snapshots along with the state of the
+    /// built-in samplers.
+    pub fn custom(&self, sampler: &str) -> Option<&[u8]> {
+        self.custom.get(sampler).map(|state| state.as_slice())
+    }
+
+    /// Stores the state of the sampler named `sampler`, replacing its previous state. See
+    /// [Self::custom]. The name should be unique to the sampler, e.g. by starting with the
+    /// name of the crate that defines it.
+    pub fn set_custom(&mut self, sampler: impl Into<String>, state: Vec<u8>) {
+        self.custom
+            .insert(sampler.into(), serde_bytes::ByteBuf::from(state));
+    }
+
+    /// Removes the state of the sampler named `sampler`, returning it.
+    pub fn remove_custom(&mut self, sampler: &str) -> Option<Vec<u8>> {
+        self.custom.remove(sampler).map(|state| state.into_vec())
+    }
+
+    /// Forgets the state of all samplers, so that they start over. The generation start is
+    /// kept.
+    pub fn clear(&mut self) {
+        *self = Self {
+            generation_start: self.generation_start,
+            ..Default::default()
+        };
+    }
 }
 
 /// Top-P Top-K sampling.
@@ -45,6 +129,19 @@ pub struct TopPTopK {
     pub bias_tokens: TokenBias,
     /// The number of tokens to consider for the repetition penalty.
     pub repetition_penalty_last_n: usize,
+    /// Whether the repetition penalty applies to the tokens of the prompt. If `false`, the
+    /// penalty window does not reach back past the [start of the generation](SamplerState::generation_start),
+    /// so the model is not punished for using words from the prompt.
+    pub penalize_prompt: bool,
+    /// If `penalize_prompt` is set, the number of tokens of the prompt, counted back from
+    /// the [start of the generation](SamplerState::generation_start), that the repetition
+    /// penalty considers, e.g. to penalize the end of the conversation but not the
+    /// question. `None` considers all of them. The window never reaches back further than
+    /// `repetition_penalty_last_n` tokens.
+    pub repetition_penalty_prompt_window: Option<usize>,
+    /// The number of tokens that top-K, top-P (and [Typical] sampling) keep at least, however
+    /// aggressive they are, so that generation does not degenerate into a loop. Usually 1.
+    pub min_keep: usize,
 }
 impl Default for TopPTopK {
     fn default() -> Self {
@@ -55,6 +152,9 @@ impl Default for TopPTopK {
             temperature: 0.80,
             bias_tokens: TokenBias::empty(),
             repetition_penalty_last_n: 512,
+            penalize_prompt: true,
+            repetition_penalty_prompt_window: None,
+            min_keep: 1,
         }
     }
 }
@@ -65,15 +165,56 @@ impl Sampler for TopPTopK {
         logits: &[f32],
         rng: &mut dyn rand::RngCore,
     ) -> TokenId {
+        self.sample_with_state(&mut SamplerState::default(), previous_tokens, logits, rng)
+    }
+
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let mut logits_id = self.top_k_logits(state, previous_tokens, logits);
+        let mut probs = softmax_sorted(&logits_id);
+        top_p_truncate(self.top_p, self.min_keep, &mut probs, &mut logits_id);
+
+        sample_or_greedy(&probs, &logits_id, rng).1
+    }
+}
+impl TopPTopK {
+    /// Applies the temperature, the repetition penalty and the token biases to `logits`,
+    /// and returns the top K of them with their token IDs, highest first.
+    fn top_k_logits(
+        &self,
+        state: &SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+    ) -> Vec<(f32, TokenId)> {
         let Self {
             top_k,
-            top_p,
             repeat_penalty,
             temperature,
             repetition_penalty_last_n,
+            penalize_prompt,
+            repetition_penalty_prompt_window,
             ..
         } = *self;
-        let bias_tokens = &self.bias_tokens;
+        let bias_tokens = self.bias_tokens.for_next_token(previous_tokens);
+
+        let mut penalty_start = previous_tokens
+            .len()
+            .saturating_sub(repetition_penalty_last_n);
+        let generation_start = state.generation_start().min(previous_tokens.len());
+        let prompt_window = if penalize_prompt {
+            repetition_penalty_prompt_window
+        } else {
+            Some(0)
+        };
+        if let Some(prompt_window) = prompt_window {
+            penalty_start = penalty_start.max(generation_start.saturating_sub(prompt_window));
+        }
+        let penalized_tokens = &previous_tokens[penalty_start..];
 
         let n_logits = logits.len();
         let mut logits_id = Vec::<(f32, TokenId)>::with_capacity(n_logits);
@@ -87,11 +228,7 @@ impl Sampler for TopPTopK {
 
                 let val = if let Some(logit_override) = bias_tokens.get(tid) {
                     logit_override
-                } else if previous_tokens[previous_tokens
-                    .len()
-                    .saturating_sub(repetition_penalty_last_n)..]
-                    .contains(&(i as TokenId))
-                {
+                } else if penalized_tokens.contains(&(i as TokenId)) {
                     // repetition penalty from CTRL paper (https://arxiv.org/abs/1909.05858)
                     // credit https://github.com/facebookresearch/llama/compare/main...shawwn:llama:main
 
@@ -110,53 +247,766 @@ impl Sampler for TopPTopK {
 
         // find the top K tokens
         {
+            let top_k = top_k.max(self.min_keep).min(logits_id.len());
             logits_id.partial_sort(top_k, |a, b| {
                 // Sort descending
                 b.0.total_cmp(&a.0)
             });
             logits_id.truncate(top_k);
         }
+        logits_id
+    }
+}
 
-        let maxl = logits_id
-            .iter()
-            .map(|x| x.0)
-            .max_by(f32::total_cmp)
-            .unwrap();
+/// Returns the probabilities of `logits_id`, which are sorted from the highest logit.
+fn softmax_sorted(logits_id: &[(f32, TokenId)]) -> Vec<f32> {
+    let Some(&(maxl, _)) = logits_id.first() else {
+        return vec![];
+    };
+    let mut probs: Vec<f32> = logits_id.iter().map(|(k, _)| (k - maxl).exp()).collect();
+    let sum: f32 = probs.iter().copied().sum();
+
+    // Normalize the probs
+    for p in probs.iter_mut() {
+        *p /= sum;
+    }
+    probs
+}
+
+/// Keeps the most likely tokens whose cumulative probability reaches `top_p`, and at least
+/// `min_keep` of them, and renormalizes their probabilities. `probs` must be sorted in
+/// descending order.
+fn top_p_truncate(
+    top_p: f32,
+    min_keep: usize,
+    probs: &mut Vec<f32>,
+    logits_id: &mut Vec<(f32, TokenId)>,
+) {
+    if top_p >= 1.0 {
+        return;
+    }
+    let mut cumsum = 0.0;
+    for i in 0..probs.len() {
+        cumsum += probs[i];
+        if cumsum >= top_p && i + 1 >= min_keep {
+            probs.truncate(i + 1);
+            logits_id.truncate(i + 1);
+            break;
+        }
+    }
+
+    cumsum = 1.0 / cumsum;
+    for p in probs.iter_mut() {
+        *p *= cumsum;
+    }
+}
+
+/// Samples one of `logits_id` with the probabilities `probs`, returning its logit and ID.
+///
+/// If the probabilities do not form a distribution, e.g. because the model produced
+/// non-finite logits, this warns and falls back to the token with the highest logit
+/// instead of panicking.
+fn sample_or_greedy(
+    probs: &[f32],
+    logits_id: &[(f32, TokenId)],
+    rng: &mut dyn rand::RngCore,
+) -> (f32, TokenId) {
+    match WeightedIndex::new(probs) {
+        Ok(dist) => logits_id[dist.sample(rng)],
+        Err(err) => {
+            let greedy = logits_id
+                .iter()
+                .copied()
+                .filter(|(logit, _)| !logit.is_nan())
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+                .unwrap_or((f32::NAN, 0));
+            log::warn!(
+                "cannot sample from the probabilities of the candidate tokens ({err}); \
+                 falling back to the most likely token, {}",
+                greedy.1
+            );
+            greedy
+        }
+    }
+}
 
-        // compute probs for the top K tokens
-        let mut probs: Vec<f32> = logits_id
+/// [Locally typical](https://arxiv.org/abs/2202.00666) sampling.
+///
+/// Instead of keeping the most likely tokens, typical sampling keeps the tokens whose
+/// surprise (`-ln p`) is closest to the entropy of the distribution, i.e. to the surprise
+/// that is expected at this point of the text, until their cumulative probability reaches
+/// `typical_p`. This avoids both the unlikely tokens that derail a text and the overly
+/// likely ones that make it loop, which reduces degenerate repetition in long generations.
+///
+/// It composes with [TopPTopK]: the temperature, repetition penalty, token biases and
+/// top-K of `base` are applied first, then typical sampling, then the top-P of `base`.
+#[derive(Clone, Debug)]
+pub struct Typical {
+    /// The cumulative probability of the most typical tokens that are kept for sampling.
+    /// `1.0` disables typical sampling.
+    pub typical_p: f32,
+    /// The sampler whose penalties and truncations are applied along with typical sampling.
+    pub base: TopPTopK,
+}
+impl Default for Typical {
+    fn default() -> Self {
+        Self {
+            typical_p: 0.95,
+            base: TopPTopK {
+                top_p: 1.0,
+                ..Default::default()
+            },
+        }
+    }
+}
+impl Sampler for Typical {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        self.sample_with_state(&mut SamplerState::default(), previous_tokens, logits, rng)
+    }
+
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let mut logits_id = self.base.top_k_logits(state, previous_tokens, logits);
+        let mut probs = softmax_sorted(&logits_id);
+
+        if self.typical_p < 1.0 {
+            let entropy: f32 = probs
+                .iter()
+                .filter(|&&p| p > 0.0)
+                .map(|&p| -p * p.ln())
+                .sum();
+            // Order the tokens by how far their surprise is from the entropy.
+            let mut order: Vec<usize> = (0..probs.len()).collect();
+            order.sort_by(|&a, &b| {
+                let distance = |i: usize| (-probs[i].ln() - entropy).abs();
+                distance(a).total_cmp(&distance(b))
+            });
+            let mut cumsum = 0.0;
+            let keep = order
+                .iter()
+                .position(|&i| {
+                    cumsum += probs[i];
+                    cumsum >= self.typical_p
+                })
+                .map_or(order.len(), |position| position + 1)
+                .max(self.base.min_keep)
+                .min(order.len());
+            order.truncate(keep);
+            let cumsum: f32 = order.iter().map(|&i| probs[i]).sum();
+            // Top-P applies to the most likely of the typical tokens.
+            order.sort_unstable();
+
+            logits_id = order.iter().map(|&i| logits_id[i]).collect();
+            probs = order.iter().map(|&i| probs[i] / cumsum).collect();
+        }
+        top_p_truncate(
+            self.base.top_p,
+            self.base.min_keep,
+            &mut probs,
+            &mut logits_id,
+        );
+
+        sample_or_greedy(&probs, &logits_id, rng).1
+    }
+}
+
+/// [Mirostat 2.0](https://arxiv.org/abs/2007.14966) sampling.
+///
+/// Instead of keeping a fixed number of tokens, Mirostat adjusts the truncation after every
+/// token so that the surprise of the generated text stays close to `tau`. The threshold it
+/// learns is kept in the session's [SamplerState], so it carries over between calls.
+#[derive(Clone, Debug)]
+pub struct Mirostat2 {
+    /// The target surprise, in bits. Lower values give more focused and coherent text.
+    pub tau: f32,
+    /// How quickly the threshold follows the observed surprise.
+    pub eta: f32,
+    /// Temperature (randomness) used for sampling. A higher number is more random.
+    pub temperature: f32,
+    /// A list of tokens to bias against in the process of generation.
+    pub bias_tokens: TokenBias,
+    /// The number of tokens that the threshold keeps at least. Usually 1.
+    pub min_keep: usize,
+}
+/// The state kept by [Mirostat2] between tokens, in [SamplerState::mirostat2].
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Mirostat2State {
+    /// The maximum surprise of the tokens that are kept, adjusted after each token so that
+    /// the surprise of the generated text approaches [Mirostat2::tau].
+    pub mu: f32,
+}
+impl Default for Mirostat2 {
+    fn default() -> Self {
+        Self {
+            tau: 5.0,
+            eta: 0.1,
+            temperature: 0.80,
+            bias_tokens: TokenBias::empty(),
+            min_keep: 1,
+        }
+    }
+}
+impl Sampler for Mirostat2 {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        self.sample_with_state(&mut SamplerState::default(), previous_tokens, logits, rng)
+    }
+
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        // The threshold starts at twice the target, as in the paper.
+        let mu = state.mirostat2.map_or(2.0 * self.tau, |state| state.mu);
+        let bias_tokens = self.bias_tokens.for_next_token(previous_tokens);
+
+        let scale = 1.0 / self.temperature;
+        let mut logits_id: Vec<(f32, TokenId)> = logits
             .iter()
-            .copied()
-            .map(|(k, _)| (k - maxl).exp())
+            .enumerate()
+            .map(|(i, &logit)| {
+                let tid = i as TokenId;
+                (bias_tokens.get(tid).unwrap_or(logit * scale), tid)
+            })
             .collect();
-        let sum: f32 = probs.iter().copied().sum();
+        logits_id.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
 
-        // Normalize the probs
+        let maxl = logits_id[0].0;
+        let mut probs: Vec<f32> = logits_id.iter().map(|(k, _)| (k - maxl).exp()).collect();
+        let sum: f32 = probs.iter().sum();
         for p in probs.iter_mut() {
             *p /= sum;
         }
 
-        // Top p sampling
-        if top_p < 1.0 {
-            let mut cumsum = 0.0;
-            for i in 0..probs.len() {
-                cumsum += probs[i];
-                if cumsum >= top_p {
-                    probs.truncate(i + 1);
-                    logits_id.truncate(i + 1);
-                    break;
+        // Drop the tokens that are more surprising than the threshold, keeping at least
+        // `min_keep`.
+        let keep = probs
+            .iter()
+            .position(|p| -p.log2() > mu)
+            .unwrap_or(probs.len())
+            .max(self.min_keep)
+            .min(probs.len());
+        probs.truncate(keep);
+        logits_id.truncate(keep);
+        let sum: f32 = probs.iter().sum();
+        for p in probs.iter_mut() {
+            *p /= sum;
+        }
+
+        let (_, token) = sample_or_greedy(&probs, &logits_id, rng);
+
+        // The threshold is left as is if the sampler fell back to the most likely token.
+        if let Some(idx) = logits_id.iter().position(|&(_, id)| id == token) {
+            let surprise = -probs[idx].log2();
+            if surprise.is_finite() {
+                state.mirostat2 = Some(Mirostat2State {
+                    mu: mu - self.eta * (surprise - self.tau),
+                });
+            }
+        }
+
+        token
+    }
+}
+
+/// The [DRY](https://github.com/oobabooga/text-generation-webui/pull/5677) ("don't repeat
+/// yourself") repetition penalty, applied before another sampler.
+///
+/// Instead of penalizing every token that occurred before, as the flat repetition penalty
+/// of [TopPTopK] does, DRY penalizes the tokens that would continue a sequence of tokens that
+/// already occurred verbatim. A token that extends a repetition of `n` tokens, where `n` is
+/// at least `allowed_length`, has `multiplier * exponent_base ^ (n - allowed_length)`
+/// subtracted from its logit, so short repetitions such as braces and indentation in code
+/// go unpunished while the penalty grows quickly for looping sentences.
+///
+/// Repetitions do not extend across the `sequence_breakers`, such as line breaks, so that
+/// the structure of a text (e.g. `Name:` at the start of lines) is not penalized.
+#[derive(Clone, Debug)]
+pub struct Dry {
+    /// The penalty for a token that extends a repetition of `allowed_length` tokens. `0.0`
+    /// disables DRY.
+    pub multiplier: f32,
+    /// How much faster than linearly the penalty grows with the length of the repetition,
+    /// called the "base" of DRY elsewhere.
+    pub exponent_base: f32,
+    /// The length of the repetitions that go unpunished.
+    pub allowed_length: usize,
+    /// The tokens that repetitions do not extend across. See [Dry::breaker_tokens].
+    pub sequence_breakers: HashSet<TokenId>,
+    /// The number of previous tokens that are searched for repetitions. `0` searches all of
+    /// them.
+    pub last_n: usize,
+    /// The sampler that samples from the penalized logits.
+    pub base: Arc<dyn Sampler>,
+}
+impl Dry {
+    /// The sequence breakers commonly used with DRY, for [Dry::breaker_tokens].
+    pub const DEFAULT_SEQUENCE_BREAKERS: [&'static str; 4] = ["\n", ":", "\"", "*"];
+
+    /// Returns the tokens of `tokenizer` whose text contains any of `breakers`, for
+    /// [Dry::sequence_breakers].
+    pub fn breaker_tokens<S: AsRef<str>>(
+        tokenizer: &Tokenizer,
+        breakers: &[S],
+    ) -> HashSet<TokenId> {
+        (0..tokenizer.len())
+            .filter(|&id| {
+                let text = tokenizer.token(id);
+                breakers.iter().any(|breaker| {
+                    let breaker = breaker.as_ref().as_bytes();
+                    !breaker.is_empty() && text.windows(breaker.len()).any(|w| w == breaker)
+                })
+            })
+            .map(|id| id as TokenId)
+            .collect()
+    }
+
+    /// Subtracts the penalty of each token that would extend a repetition from `logits`.
+    fn penalize(&self, previous_tokens: &[TokenId], logits: &mut [f32]) {
+        let start = match self.last_n {
+            0 => 0,
+            last_n => previous_tokens.len().saturating_sub(last_n),
+        };
+        let tokens = &previous_tokens[start..];
+        let Some(&last) = tokens.last() else {
+            return;
+        };
+        if self.multiplier == 0.0 || self.sequence_breakers.contains(&last) {
+            return;
+        }
+
+        // For each earlier occurrence of the last token, the repetition that ends there is
+        // extended by the token that followed it.
+        let end = tokens.len() - 1;
+        let mut longest = HashMap::<TokenId, usize>::new();
+        for i in (0..end).filter(|&i| tokens[i] == last) {
+            let length = (0..=i)
+                .take_while(|&k| {
+                    tokens[i - k] == tokens[end - k]
+                        && !self.sequence_breakers.contains(&tokens[i - k])
+                })
+                .count();
+            let next = tokens[i + 1];
+            let entry = longest.entry(next).or_default();
+            *entry = (*entry).max(length);
+        }
+
+        for (token, length) in longest {
+            if length >= self.allowed_length {
+                if let Some(logit) = logits.get_mut(token as usize) {
+                    let exponent = (length - self.allowed_length) as i32;
+                    *logit -= self.multiplier * self.exponent_base.powi(exponent);
                 }
             }
+        }
+    }
+}
+impl Default for Dry {
+    fn default() -> Self {
+        Self {
+            multiplier: 0.8,
+            exponent_base: 1.75,
+            allowed_length: 2,
+            sequence_breakers: HashSet::new(),
+            last_n: 0,
+            // DRY replaces the flat repetition penalty.
+            base: Arc::new(TopPTopK {
+                repeat_penalty: 1.0,
+                ..Default::default()
+            }),
+        }
+    }
+}
+impl Sampler for Dry {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        self.sample_with_state(&mut SamplerState::default(), previous_tokens, logits, rng)
+    }
 
-            cumsum = 1.0 / cumsum;
-            for p in probs.iter_mut() {
-                *p *= cumsum;
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let mut logits = logits.to_vec();
+        self.penalize(previous_tokens, &mut logits);
+        self.base
+            .sample_with_state(state, previous_tokens, &logits, rng)
+    }
+}
+
+/// [TopPTopK] sampling whose parameters change as the generation goes on, following a
+/// [ParameterSchedule]: for example, to start precise and get more creative, or the reverse.
+///
+/// The schedule is indexed by the number of tokens generated since the
+/// [start of the generation](SamplerState::generation_start), so it starts over with each
+/// prompt.
+#[derive(Clone, Debug)]
+pub struct Scheduled {
+    /// The parameters that the schedule adjusts.
+    pub base: TopPTopK,
+    /// How the parameters change.
+    pub schedule: ParameterSchedule,
+}
+impl Sampler for Scheduled {
+    fn sample(
+        &self,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        self.sample_with_state(&mut SamplerState::default(), previous_tokens, logits, rng)
+    }
+
+    fn sample_with_state(
+        &self,
+        state: &mut SamplerState,
+        previous_tokens: &[TokenId],
+        logits: &[f32],
+        rng: &mut dyn rand::RngCore,
+    ) -> TokenId {
+        let generated = previous_tokens
+            .len()
+            .saturating_sub(state.generation_start());
+        let mut sampler = self.base.clone();
+        self.schedule.apply(generated, &mut sampler);
+        sampler.sample_with_state(state, previous_tokens, logits, rng)
+    }
+}
+
+/// How the parameters of a [Scheduled] sampler change with the number of generated tokens.
+#[derive(Clone)]
+pub enum ParameterSchedule {
+    /// Each parameter is interpolated linearly between the keyframes that set it, in order
+    /// of [Keyframe::at], and keeps the value of the first and last of them before and after
+    /// them. Parameters that no keyframe sets keep their base value.
+    Keyframes(Vec<Keyframe>),
+    /// Adjusts the parameters for the given number of generated tokens. The parameters are
+    /// the base ones each time.
+    Custom(Arc<ScheduleFn>),
+}
+/// Adjusts the parameters of a [Scheduled] sampler. See [ParameterSchedule::Custom].
+pub type ScheduleFn = dyn Fn(usize, &mut TopPTopK) + Send + Sync;
+impl ParameterSchedule {
+    /// Adjusts `sampler` for the token after `generated` generated tokens.
+    pub fn apply(&self, generated: usize, sampler: &mut TopPTopK) {
+        match self {
+            Self::Keyframes(keyframes) => {
+                if let Some(temperature) =
+                    interpolate(keyframes, generated, |keyframe| keyframe.temperature)
+                {
+                    sampler.temperature = temperature;
+                }
+                if let Some(top_p) = interpolate(keyframes, generated, |keyframe| keyframe.top_p) {
+                    sampler.top_p = top_p;
+                }
             }
+            Self::Custom(schedule) => schedule(generated, sampler),
         }
+    }
+}
+impl Debug for ParameterSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Keyframes(keyframes) => f.debug_tuple("Keyframes").field(keyframes).finish(),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// The values of parameters at a point of a [ParameterSchedule::Keyframes] schedule.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Keyframe {
+    /// The number of generated tokens at which the parameters have these values.
+    pub at: usize,
+    /// The [temperature](TopPTopK::temperature), if this keyframe sets it.
+    pub temperature: Option<f32>,
+    /// The [top-P](TopPTopK::top_p), if this keyframe sets it.
+    pub top_p: Option<f32>,
+}
+
+/// Interpolates the parameter that `value` reads from the keyframes at `at`, if any keyframe
+/// sets it.
+fn interpolate(
+    keyframes: &[Keyframe],
+    at: usize,
+    value: impl Fn(&Keyframe) -> Option<f32>,
+) -> Option<f32> {
+    let mut points: Vec<(usize, f32)> = keyframes
+        .iter()
+        .filter_map(|keyframe| Some((keyframe.at, value(keyframe)?)))
+        .collect();
+    points.sort_by_key(|&(at, _)| at);
+    let after = points.iter().position(|&(point, _)| point > at);
+    match after {
+        None => points.last().map(|&(_, value)| value),
+        Some(0) => Some(points[0].1),
+        Some(i) => {
+            let ((start, from), (end, to)) = (points[i - 1], points[i]);
+            let t = (at - start) as f32 / (end - start) as f32;
+            Some(from + (to - from) * t)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn mirostat2_keeps_its_threshold_in_the_state() {
+        let sampler = Mirostat2 {
+            tau: 1.0,
+            eta: 0.5,
+            ..Default::default()
+        };
+        let logits = [1.0, 0.5, 0.0, -0.5];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+
+        let mut state = SamplerState::default();
+        sampler.sample_with_state(&mut state, &[], &logits, &mut rng);
+        let mu = state.mirostat2.unwrap().mu;
+        assert_ne!(mu, 2.0);
+
+        sampler.sample_with_state(&mut state, &[], &logits, &mut rng);
+        assert_ne!(state.mirostat2.unwrap().mu, mu);
+
+        state.set_generation_start(3);
+        state.clear();
+        assert_eq!(state.mirostat2, None);
+        assert_eq!(state.generation_start(), 3);
+    }
+
+    #[test]
+    fn typical_sampling_drops_the_most_and_least_likely_tokens() {
+        // The entropy is about 1.3 nats, closest to the surprise of the second and third
+        // tokens (1.6 nats).
+        let probs: [f32; 5] = [0.5, 0.2, 0.2, 0.05, 0.05];
+        let logits = probs.map(f32::ln);
+        let sampler = Typical {
+            typical_p: 0.3,
+            base: TopPTopK {
+                top_k: 5,
+                top_p: 1.0,
+                repeat_penalty: 1.0,
+                temperature: 1.0,
+                ..Default::default()
+            },
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let token = sampler.sample(&[], &logits, &mut rng);
+            assert!(token == 1 || token == 2, "{token}");
+        }
+
+        // It composes with the token biases of the base sampler.
+        let sampler = Typical {
+            base: TopPTopK {
+                bias_tokens: TokenBias::new(vec![(1, f32::NEG_INFINITY)]),
+                ..sampler.base.clone()
+            },
+            ..sampler
+        };
+        for _ in 0..20 {
+            assert_ne!(sampler.sample(&[], &logits, &mut rng), 1);
+        }
+    }
+
+    #[test]
+    fn truncation_keeps_min_keep_tokens() {
+        let logits = [1.0, 0.5, 0.0];
+        let sampler = TopPTopK {
+            top_k: 0,
+            top_p: 0.01,
+            repeat_penalty: 1.0,
+            temperature: 1.0,
+            min_keep: 2,
+            ..Default::default()
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut sampled = [false; 3];
+        for _ in 0..100 {
+            sampled[sampler.sample(&[], &logits, &mut rng) as usize] = true;
+        }
+        assert_eq!(sampled, [true, true, false]);
+    }
+
+    #[test]
+    fn non_finite_logits_fall_back_to_greedy() {
+        let logits = [0.0, f32::NAN, 1.0, f32::INFINITY];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let samplers: [&dyn Sampler; 3] = [
+            &TopPTopK::default(),
+            &Typical::default(),
+            &Mirostat2::default(),
+        ];
+        for sampler in samplers {
+            assert_eq!(sampler.sample(&[], &logits, &mut rng), 3, "{sampler:?}");
+        }
+
+        // Every token banned.
+        let sampler = TopPTopK {
+            bias_tokens: TokenBias::new((0..4).map(|id| (id, f32::NEG_INFINITY)).collect()),
+            ..Default::default()
+        };
+        sampler.sample(&[], &logits, &mut rng);
+    }
+
+    #[test]
+    fn dry_penalizes_continuing_a_repetition() {
+        let greedy = Arc::new(TopPTopK {
+            top_k: 1,
+            repeat_penalty: 1.0,
+            ..Default::default()
+        });
+        let sampler = Dry {
+            multiplier: 1.0,
+            exponent_base: 2.0,
+            allowed_length: 2,
+            base: greedy,
+            ..Default::default()
+        };
+        // Token 3 would extend a repetition of `4 0 1 2`, and is penalized by 2^(4 - 2).
+        let logits = [0.0, 0.0, 0.0, 2.5, 1.0];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let previous = [4, 0, 1, 2, 3, 4, 0, 1, 2];
+        assert_eq!(sampler.sample(&previous, &logits, &mut rng), 4);
+
+        // A repetition of 2 tokens is penalized by 1.
+        assert_eq!(sampler.sample(&previous[2..], &logits, &mut rng), 3);
+
+        // Repetitions do not extend across the breakers.
+        let sampler = Dry {
+            sequence_breakers: [0].into(),
+            ..sampler
+        };
+        assert_eq!(sampler.sample(&previous, &logits, &mut rng), 3);
+    }
+
+    #[test]
+    fn keyframes_are_interpolated() {
+        let schedule = ParameterSchedule::Keyframes(vec![
+            Keyframe {
+                at: 10,
+                temperature: Some(1.0),
+                top_p: Some(0.5),
+            },
+            Keyframe {
+                at: 0,
+                temperature: Some(0.2),
+                top_p: None,
+            },
+        ]);
+        let at = |generated| {
+            let mut sampler = TopPTopK {
+                top_p: 0.9,
+                ..Default::default()
+            };
+            schedule.apply(generated, &mut sampler);
+            (sampler.temperature, sampler.top_p)
+        };
+        assert_eq!(at(0), (0.2, 0.5));
+        assert!((at(5).0 - 0.6).abs() < 1e-6);
+        assert_eq!(at(10), (1.0, 0.5));
+        assert_eq!(at(100), (1.0, 0.5));
+    }
+
+    #[test]
+    fn repetition_penalty_can_skip_the_prompt() {
+        let sampler = TopPTopK {
+            top_k: 1,
+            repeat_penalty: 100.0,
+            temperature: 1.0,
+            penalize_prompt: false,
+            ..Default::default()
+        };
+        let logits = [1.0, 0.9];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+
+        let mut state = SamplerState::default();
+        state.set_generation_start(1);
+        assert_eq!(
+            sampler.sample_with_state(&mut state, &[0], &logits, &mut rng),
+            0
+        );
+        assert_eq!(
+            sampler.sample_with_state(&mut state, &[1, 0], &logits, &mut rng),
+            1
+        );
+
+        let sampler = TopPTopK {
+            penalize_prompt: true,
+            ..sampler
+        };
+        assert_eq!(
+            sampler.sample_with_state(&mut state, &[0], &logits, &mut rng),
+            1
+        );
+    }
+
+    #[test]
+    fn repetition_penalty_window_is_anchored_at_the_generation() {
+        let sampler = TopPTopK {
+            top_k: 1,
+            repeat_penalty: 100.0,
+            temperature: 1.0,
+            repetition_penalty_prompt_window: Some(1),
+            ..Default::default()
+        };
+        let logits = [1.0, 0.9, 0.8];
+
+        // The prompt is [0, 1]: only its last token is penalized.
+        let mut state = SamplerState::default();
+        state.set_generation_start(2);
+        let sample = |sampler: &TopPTopK, state: &mut SamplerState, tokens: &[TokenId]| {
+            sampler.sample_with_state(
+                state,
+                tokens,
+                &logits,
+                &mut rand::rngs::mock::StepRng::new(0, 0),
+            )
+        };
+        assert_eq!(sample(&sampler, &mut state, &[0, 1]), 0);
+        // The generated tokens are penalized whatever the window.
+        assert_eq!(sample(&sampler, &mut state, &[0, 1, 0]), 2);
 
-        let dist = WeightedIndex::new(&probs).expect("WeightedIndex error");
-        let idx = dist.sample(rng);
+        let whole_prompt = TopPTopK {
+            repetition_penalty_prompt_window: Some(2),
+            ..sampler.clone()
+        };
+        assert_eq!(sample(&whole_prompt, &mut state, &[0, 1]), 2);
 
-        logits_id[idx].1
+        // The window is ignored when the prompt is not penalized.
+        let no_prompt = TopPTopK {
+            penalize_prompt: false,
+            ..sampler
+        };
+        assert_eq!(sample(&no_prompt, &mut state, &[0, 1]), 0);
+        assert_eq!(sample(&no_prompt, &mut state, &[0, 1, 0]), 1);
     }
 }
@@ -0,0 +1,555 @@
+//! Chat prompt templates, which turn a system prompt and a list of messages into the
+//! prompt format a model was fine-tuned on.
+//!
+//! Rendering keeps track of which part of the prompt came from which message, so that
+//! tools can report how much of the context window each part uses. A [Conversation] keeps
+//! the messages of a chat along with pinned few-shot examples, and trims the oldest messages
+//! to fit the context window, and can be exported with [Conversation::export] to turn chats
+//! into datasets or reproductions.
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{TokenizationError, Tokenizer};
+
+/// A chat prompt format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PromptTemplate {
+    /// The ChatML format, used by many OpenAI-style fine-tunes.
+    ChatMl,
+    /// The Llama 2 chat format (`[INST] ... [/INST]`).
+    Llama2,
+    /// The Vicuna format (`USER: ... ASSISTANT: ...`).
+    Vicuna,
+    /// The Alpaca instruction format (`### Instruction: ... ### Response: ...`).
+    Alpaca,
+}
+impl PromptTemplate {
+    /// All of the supported templates.
+    pub const ALL: [PromptTemplate; 4] = [Self::ChatMl, Self::Llama2, Self::Vicuna, Self::Alpaca];
+
+    /// The name of the template, as accepted by [PromptTemplate::from_str].
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ChatMl => "chatml",
+            Self::Llama2 => "llama2",
+            Self::Vicuna => "vicuna",
+            Self::Alpaca => "alpaca",
+        }
+    }
+
+    /// Renders `system` (if any) and `messages` into a prompt, ending with the prefix
+    /// after which the model should write the assistant's reply.
+    ///
+    /// Messages with [Role::System] are rendered in the same way as `system`.
+    pub fn render(&self, system: Option<&str>, messages: &[Message]) -> RenderedPrompt {
+        self.render_with_examples(system, &[], messages)
+    }
+
+    /// Renders `system`, then the messages of the few-shot `examples`, then `messages`.
+    fn render_with_examples(
+        &self,
+        system: Option<&str>,
+        examples: &[Example],
+        messages: &[Message],
+    ) -> RenderedPrompt {
+        let system = system.map(|content| Message {
+            role: Role::System,
+            content: content.to_owned(),
+        });
+        let examples = examples.iter().enumerate().flat_map(|(index, example)| {
+            example
+                .messages
+                .iter()
+                .map(move |message| (message, Some(index)))
+        });
+
+        let mut sections = vec![];
+        let mut pending_system = None;
+        let mut user_index = 0;
+        let mut assistant_index = 0;
+        for (message, example) in system
+            .iter()
+            .map(|message| (message, None))
+            .chain(examples)
+            .chain(messages.iter().map(|message| (message, None)))
+        {
+            let mut label = match (message.role, example) {
+                (role, Some(index)) => format!("example #{} {role}", index + 1),
+                (Role::System, None) => "system".to_owned(),
+                (Role::User, None) => {
+                    user_index += 1;
+                    format!("user #{user_index}")
+                }
+                (Role::Assistant, None) => {
+                    assistant_index += 1;
+                    format!("assistant #{assistant_index}")
+                }
+            };
+            let content = &message.content;
+            let text = match (self, message.role) {
+                (Self::ChatMl, role) => {
+                    format!("<|im_start|>{role}\n{content}<|im_end|>\n")
+                }
+
+                // Llama 2 places the system prompt inside the first instruction, so it is
+                // rendered as part of the next user message.
+                (Self::Llama2, Role::System) => {
+                    pending_system = Some(format!("<<SYS>>\n{content}\n<</SYS>>\n\n"));
+                    String::new()
+                }
+                (Self::Llama2, Role::User) => {
+                    let system = match pending_system.take() {
+                        Some(system) => {
+                            label = format!("system + {label}");
+                            system
+                        }
+                        None => String::new(),
+                    };
+                    format!("[INST] {system}{content} [/INST]")
+                }
+                (Self::Llama2, Role::Assistant) => format!(" {content} </s><s>"),
+
+                (Self::Vicuna | Self::Alpaca, Role::System) => format!("{content}\n\n"),
+                (Self::Vicuna, Role::User) => format!("USER: {content}\n"),
+                (Self::Vicuna, Role::Assistant) => format!("ASSISTANT: {content}\n"),
+                (Self::Alpaca, Role::User) => format!("### Instruction:\n{content}\n\n"),
+                (Self::Alpaca, Role::Assistant) => format!("### Response:\n{content}\n\n"),
+            };
+            if !text.is_empty() {
+                sections.push(PromptSection {
+                    label,
+                    text,
+                    example,
+                });
+            }
+        }
+
+        let reply_prefix = match self {
+            Self::ChatMl => "<|im_start|>assistant\n".to_owned(),
+            // A system prompt with no user message still needs an instruction.
+            Self::Llama2 => pending_system
+                .map(|system| format!("[INST] {system}[/INST]"))
+                .unwrap_or_default(),
+            Self::Vicuna => "ASSISTANT:".to_owned(),
+            Self::Alpaca => "### Response:\n".to_owned(),
+        };
+        if !reply_prefix.is_empty() {
+            sections.push(PromptSection {
+                label: "reply prefix".to_owned(),
+                text: reply_prefix,
+                example: None,
+            });
+        }
+
+        RenderedPrompt { sections }
+    }
+}
+impl Display for PromptTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+impl FromStr for PromptTemplate {
+    type Err = UnknownPromptTemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lowercase = s.to_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|template| template.name() == lowercase)
+            .ok_or_else(|| UnknownPromptTemplateError(s.to_owned()))
+    }
+}
+
+/// Returned when a [PromptTemplate] name is not recognised.
+#[derive(Error, Debug)]
+#[error("{0} is not a supported prompt template (expected one of chatml, llama2, vicuna, alpaca)")]
+pub struct UnknownPromptTemplateError(pub String);
+
+/// The author of a [Message].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Instructions for the model.
+    System,
+    /// The user.
+    User,
+    /// The model.
+    Assistant,
+}
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::System => write!(f, "system"),
+            Role::User => write!(f, "user"),
+            Role::Assistant => write!(f, "assistant"),
+        }
+    }
+}
+
+/// A message in a conversation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    /// The author of the message.
+    pub role: Role,
+    /// The text of the message.
+    pub content: String,
+}
+
+/// A part of a [RenderedPrompt] that came from one message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PromptSection {
+    /// A description of where this section came from (e.g. `user #2`).
+    pub label: String,
+    /// The rendered text.
+    pub text: String,
+    /// The index of the [Conversation::examples] entry this section came from, if any.
+    pub example: Option<usize>,
+}
+
+/// A prompt rendered by [PromptTemplate::render].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RenderedPrompt {
+    /// The sections of the prompt, in order.
+    pub sections: Vec<PromptSection>,
+}
+impl RenderedPrompt {
+    /// The full text of the prompt.
+    pub fn text(&self) -> String {
+        self.sections.iter().map(|s| s.text.as_str()).collect()
+    }
+}
+
+/// A format that [Conversation::export] writes a conversation in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// The ChatML format, as rendered by [PromptTemplate::ChatMl], without the reply prefix.
+    ChatMl,
+    /// A JSON object with the messages in the `messages` field, as in a request to the
+    /// OpenAI chat completions API.
+    OpenAiJson,
+    /// A Markdown document with a heading for each message.
+    Markdown,
+}
+impl ExportFormat {
+    /// All of the supported formats.
+    pub const ALL: [ExportFormat; 3] = [Self::ChatMl, Self::OpenAiJson, Self::Markdown];
+
+    /// The name of the format, as accepted by [ExportFormat::from_str].
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ChatMl => "chatml",
+            Self::OpenAiJson => "openai-json",
+            Self::Markdown => "markdown",
+        }
+    }
+}
+impl Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+impl FromStr for ExportFormat {
+    type Err = UnknownExportFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lowercase = s.to_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|format| format.name() == lowercase)
+            .ok_or_else(|| UnknownExportFormatError(s.to_owned()))
+    }
+}
+
+/// Returned when an [ExportFormat] name is not recognised.
+#[derive(Error, Debug)]
+#[error("{0} is not a supported export format (expected one of chatml, openai-json, markdown)")]
+pub struct UnknownExportFormatError(pub String);
+
+/// A few-shot example: an exchange that shows the model how to reply, such as a user
+/// message and the assistant's answer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Example {
+    /// The messages of the exchange.
+    pub messages: Vec<Message>,
+}
+
+/// A conversation with a chat model: the system prompt, few-shot examples and the
+/// messages exchanged so far, rendered with a [PromptTemplate].
+///
+/// The examples are pinned after the system prompt: [Conversation::trim] only drops the
+/// oldest messages to fit the conversation in the context window. They can be replaced at
+/// any time, and [Conversation::example_tokens] reports how much of the context each one
+/// uses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conversation {
+    /// The prompt format.
+    pub template: PromptTemplate,
+    /// The system prompt.
+    pub system: Option<String>,
+    /// The few-shot examples, rendered after the system prompt and before the messages.
+    pub examples: Vec<Example>,
+    /// The messages exchanged so far.
+    pub messages: Vec<Message>,
+}
+impl Conversation {
+    /// Creates an empty conversation.
+    pub fn new(template: PromptTemplate, system: Option<String>) -> Self {
+        Self {
+            template,
+            system,
+            examples: vec![],
+            messages: vec![],
+        }
+    }
+
+    /// Adds a message to the end of the conversation.
+    pub fn push(&mut self, role: Role, content: impl Into<String>) {
+        self.messages.push(Message {
+            role,
+            content: content.into(),
+        });
+    }
+
+    /// Renders the conversation into a prompt, ending with the prefix after which the model
+    /// should write the assistant's reply. See [PromptTemplate::render].
+    pub fn render(&self) -> RenderedPrompt {
+        self.template
+            .render_with_examples(self.system.as_deref(), &self.examples, &self.messages)
+    }
+
+    /// The number of tokens of the rendered prompt, including the beginning-of-sentence
+    /// token.
+    pub fn prompt_tokens(&self, tokenizer: &Tokenizer) -> Result<usize, TokenizationError> {
+        Ok(tokenizer.tokenize(&self.render().text(), true)?.len())
+    }
+
+    /// The number of tokens used by each of [Self::examples], in order.
+    ///
+    /// Sections are tokenized separately, so the counts may be off by a token where
+    /// tokens merge across section boundaries.
+    pub fn example_tokens(&self, tokenizer: &Tokenizer) -> Result<Vec<usize>, TokenizationError> {
+        let mut counts = vec![0; self.examples.len()];
+        for section in self.render().sections {
+            if let Some(index) = section.example {
+                counts[index] += tokenizer.tokenize(&section.text, false)?.len();
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Writes the conversation in `format`: the system prompt, the messages of the
+    /// examples, then the messages exchanged so far.
+    pub fn export(&self, format: ExportFormat) -> String {
+        let system = self.system.as_ref().map(|content| Message {
+            role: Role::System,
+            content: content.clone(),
+        });
+        let messages = system
+            .iter()
+            .chain(self.examples.iter().flat_map(|example| &example.messages))
+            .chain(&self.messages);
+        match format {
+            ExportFormat::ChatMl => {
+                let mut rendered = PromptTemplate::ChatMl.render_with_examples(
+                    self.system.as_deref(),
+                    &self.examples,
+                    &self.messages,
+                );
+                rendered
+                    .sections
+                    .retain(|section| section.label != "reply prefix");
+                rendered.text()
+            }
+            ExportFormat::OpenAiJson => {
+                #[derive(Serialize)]
+                struct Export<'a> {
+                    messages: Vec<&'a Message>,
+                }
+                serde_json::to_string_pretty(&Export {
+                    messages: messages.collect(),
+                })
+                .expect("messages serialize to JSON")
+            }
+            ExportFormat::Markdown => messages
+                .map(|message| {
+                    let role = match message.role {
+                        Role::System => "System",
+                        Role::User => "User",
+                        Role::Assistant => "Assistant",
+                    };
+                    format!("### {role}\n\n{}\n\n", message.content.trim_end())
+                })
+                .collect(),
+        }
+    }
+
+    /// Drops the oldest messages until the rendered prompt is at most `max_tokens` tokens
+    /// long, returning how many were dropped. The system prompt, the examples and the last
+    /// message are always kept, so the prompt may still be longer.
+    ///
+    /// A reply whose user message was dropped is dropped with it, so that the conversation
+    /// still starts with a user message.
+    pub fn trim(
+        &mut self,
+        tokenizer: &Tokenizer,
+        max_tokens: usize,
+    ) -> Result<usize, TokenizationError> {
+        let mut dropped = 0;
+        while self.messages.len() > 1 && self.prompt_tokens(tokenizer)? > max_tokens {
+            self.messages.remove(0);
+            dropped += 1;
+            while self.messages.len() > 1 && self.messages[0].role == Role::Assistant {
+                self.messages.remove(0);
+                dropped += 1;
+            }
+        }
+        Ok(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tokenizer::EmbeddedTokenizer, TokenId};
+
+    /// A tokenizer with a token for each byte of printable ASCII and newlines, so that
+    /// text has as many tokens as bytes.
+    fn tokenizer() -> Tokenizer {
+        let mut tokenizer = EmbeddedTokenizer::default();
+        // The embedded tokenizer treats token 0 as unset.
+        tokenizer.push_token(0, b"<unk>".to_vec(), 0.0);
+        for (id, byte) in (b' '..=b'~').chain([b'\n']).enumerate() {
+            tokenizer.push_token(id as TokenId + 1, vec![byte], 0.0);
+        }
+        tokenizer.into()
+    }
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: content.to_owned(),
+        }
+    }
+
+    #[test]
+    fn trimming_keeps_the_examples() {
+        let mut conversation = Conversation::new(PromptTemplate::Vicuna, Some("Be brief.".into()));
+        conversation.examples = vec![Example {
+            messages: vec![message(Role::User, "2+2?"), message(Role::Assistant, "4")],
+        }];
+        conversation.push(Role::User, "Hi");
+        conversation.push(Role::Assistant, "Hello!");
+        conversation.push(Role::User, "3+3?");
+
+        let tokenizer = tokenizer();
+        // "USER: 2+2?\n" and "ASSISTANT: 4\n".
+        assert_eq!(conversation.example_tokens(&tokenizer).unwrap(), [11 + 13]);
+        let labels: Vec<_> = conversation
+            .render()
+            .sections
+            .into_iter()
+            .map(|s| s.label)
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                "system",
+                "example #1 user",
+                "example #1 assistant",
+                "user #1",
+                "assistant #1",
+                "user #2",
+                "reply prefix"
+            ]
+        );
+
+        let full = conversation.prompt_tokens(&tokenizer).unwrap();
+        // Dropping "Hi" also drops its reply.
+        assert_eq!(conversation.trim(&tokenizer, full - 1).unwrap(), 2);
+        assert_eq!(conversation.messages, [message(Role::User, "3+3?")]);
+        assert_eq!(
+            conversation.render().text(),
+            "Be brief.\n\nUSER: 2+2?\nASSISTANT: 4\nUSER: 3+3?\nASSISTANT:"
+        );
+
+        // The last message is never dropped.
+        assert_eq!(conversation.trim(&tokenizer, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn exports_the_conversation() {
+        let mut conversation = Conversation::new(PromptTemplate::Vicuna, Some("Be brief.".into()));
+        conversation.push(Role::User, "Hi");
+        conversation.push(Role::Assistant, "Hello!");
+
+        assert_eq!(
+            conversation.export(ExportFormat::ChatMl),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\nHello!<|im_end|>\n"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&conversation.export(ExportFormat::OpenAiJson)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Hi" },
+                { "role": "assistant", "content": "Hello!" },
+            ] })
+        );
+        assert_eq!(
+            conversation.export(ExportFormat::Markdown),
+            "### System\n\nBe brief.\n\n### User\n\nHi\n\n### Assistant\n\nHello!\n\n"
+        );
+        assert_eq!(
+            "OpenAI-JSON".parse::<ExportFormat>().unwrap(),
+            ExportFormat::OpenAiJson
+        );
+    }
+
+    #[test]
+    fn renders_llama2_with_system_prompt_in_first_instruction() {
+        let messages = [
+            Message {
+                role: Role::User,
+                content: "Hi".to_owned(),
+            },
+            Message {
+                role: Role::Assistant,
+                content: "Hello!".to_owned(),
+            },
+            Message {
+                role: Role::User,
+                content: "How are you?".to_owned(),
+            },
+        ];
+        let prompt = PromptTemplate::Llama2.render(Some("Be brief."), &messages);
+
+        assert_eq!(
+            prompt.text(),
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s><s>[INST] How are you? [/INST]"
+        );
+        let labels: Vec<_> = prompt.sections.iter().map(|s| s.label.as_str()).collect();
+        assert_eq!(labels, ["system + user #1", "assistant #1", "user #2"]);
+    }
+
+    #[test]
+    fn renders_chatml_with_reply_prefix() {
+        let messages = [Message {
+            role: Role::User,
+            content: "Hi".to_owned(),
+        }];
+        let prompt = PromptTemplate::ChatMl.render(Some("Be brief."), &messages);
+
+        assert_eq!(
+            prompt.text(),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            "ChatML".parse::<PromptTemplate>().unwrap(),
+            PromptTemplate::ChatMl
+        );
+    }
+}
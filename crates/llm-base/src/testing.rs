@@ -0,0 +1,370 @@
+//! A model with scripted outputs, to test applications without downloading weights.
+//!
+//! [MockModel] evaluates no network: it predicts the tokens of a scripted response, one
+//! after the other, followed by the end-of-text token. It goes through
+//! [InferenceSession] like any other model, so the streaming of tokens, stop sequences,
+//! limits, snapshots and errors of an application can be tested against it
+//! deterministically.
+//!
+//! ```
+//! # use llm_base::{testing::MockModel, InferenceRequest, InferenceResponse, KnownModel};
+//! let model = MockModel::new(&["Hello", ",", " world", "!"]).with_response(", world!");
+//! let mut session = model.start_session(Default::default());
+//! let mut output = String::new();
+//! session
+//!     .infer::<std::convert::Infallible>(
+//!         &model,
+//!         &mut rand::thread_rng(),
+//!         &InferenceRequest {
+//!             prompt: "Hello".into(),
+//!             ..Default::default()
+//!         },
+//!         &mut Default::default(),
+//!         |response| {
+//!             if let InferenceResponse::InferredToken(token) = response {
+//!                 output.push_str(&token);
+//!             }
+//!             Ok(llm_base::InferenceFeedback::Continue)
+//!         },
+//!     )
+//!     .unwrap();
+//! assert_eq!(output, ", world!");
+//! ```
+use std::error::Error;
+
+use regex::Regex;
+
+use crate::{
+    model::{common, HyperparametersWriteError},
+    util, ArchitectureInfo, EmbeddedTokenizer, EmbeddingTensors, FileType, Hyperparameters,
+    InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
+    ModelParameters, OutputRequest, TensorLoader, TokenId, Tokenizer,
+};
+
+/// The ID of the beginning-of-sentence token of a [MockModel].
+pub const MOCK_BOS: TokenId = 1;
+/// The ID of the end-of-text token of a [MockModel].
+pub const MOCK_EOT: TokenId = 2;
+
+/// The size of the (zeroed) embeddings of a [MockModel].
+const N_EMBD: usize = 8;
+/// The logit of the predicted token. The others are `0.0`, so that it is sampled whatever
+/// the temperature and penalties.
+const PREDICTED_LOGIT: f32 = 100.0;
+
+/// A model that predicts a scripted response. See the [module documentation](self).
+///
+/// Its vocabulary is `<unk>`, `<s>` ([MOCK_BOS]) and `</s>` ([MOCK_EOT]), followed by the
+/// tokens it is created with. Text is tokenized into the longest tokens of the
+/// vocabulary, and fails to tokenize if it contains text that no token covers.
+///
+/// After each token, the model predicts the token of the response that follows the
+/// longest part of the response that the session ends with, so the response starts over
+/// after the prompt of each [InferenceSession::infer]. Once the session ends with the
+/// whole response, it predicts [MOCK_EOT].
+pub struct MockModel {
+    hyperparameters: MockHyperparameters,
+    tokenizer: Tokenizer,
+    context_size: usize,
+    response: Vec<TokenId>,
+    non_finite_logits_at: Option<usize>,
+}
+impl MockModel {
+    /// The context size of a model that was not given one with [Self::with_context_size].
+    pub const DEFAULT_CONTEXT_SIZE: usize = 256;
+
+    /// Creates a model with the tokens of `vocabulary` after the special tokens, which
+    /// predicts [MOCK_EOT] until it is given a response.
+    pub fn new(vocabulary: &[&str]) -> Self {
+        let mut tokenizer = EmbeddedTokenizer::default();
+        for (id, token) in ["<unk>", "<s>", "</s>"]
+            .iter()
+            .chain(vocabulary)
+            .enumerate()
+        {
+            tokenizer.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+        }
+        Self::from_tokenizer(tokenizer.into(), Self::DEFAULT_CONTEXT_SIZE)
+    }
+
+    fn from_tokenizer(tokenizer: Tokenizer, context_size: usize) -> Self {
+        Self {
+            hyperparameters: MockHyperparameters {
+                n_vocab: tokenizer.len(),
+            },
+            tokenizer,
+            context_size,
+            response: vec![],
+            non_finite_logits_at: None,
+        }
+    }
+
+    /// Sets the response that the model predicts.
+    ///
+    /// # Panics
+    /// Panics if `response` cannot be tokenized with the vocabulary of the model.
+    pub fn with_response(self, response: &str) -> Self {
+        let tokens = self
+            .tokenizer
+            .tokenize(response, false)
+            .unwrap_or_else(|err| panic!("the response {response:?} does not tokenize: {err}"));
+        self.with_response_tokens(tokens.into_iter().map(|(_, id)| id).collect())
+    }
+
+    /// Sets the tokens of the response that the model predicts, e.g. to script tokens that
+    /// tokenizing text would not produce.
+    pub fn with_response_tokens(mut self, response: Vec<TokenId>) -> Self {
+        self.response = response;
+        self
+    }
+
+    /// Sets the context size of the model, e.g. to test what happens when it is full.
+    pub fn with_context_size(mut self, context_size: usize) -> Self {
+        self.context_size = context_size;
+        self
+    }
+
+    /// Makes the logits NaN when the token at `position` of the session is evaluated, so
+    /// that inference fails with
+    /// [InferenceError::NumericalError](crate::InferenceError::NumericalError).
+    pub fn with_non_finite_logits_at(mut self, position: usize) -> Self {
+        self.non_finite_logits_at = Some(position);
+        self
+    }
+
+    /// Returns the logits after `tokens`.
+    fn logits(&self, tokens: &[TokenId]) -> Vec<f32> {
+        let mut logits = vec![0.0; self.hyperparameters.n_vocab];
+        if self.non_finite_logits_at == Some(tokens.len() - 1) {
+            logits.fill(f32::NAN);
+            return logits;
+        }
+        let matched = (0..=self.response.len().min(tokens.len()))
+            .rev()
+            .find(|&len| tokens.ends_with(&self.response[..len]))
+            .unwrap_or(0);
+        let next = self.response.get(matched).copied().unwrap_or(MOCK_EOT);
+        if let Some(logit) = logits.get_mut(next as usize) {
+            *logit = PREDICTED_LOGIT;
+        }
+        logits
+    }
+}
+impl KnownModel for MockModel {
+    type Hyperparameters = MockHyperparameters;
+
+    fn new<E: Error>(
+        _hyperparameters: Self::Hyperparameters,
+        params: ModelParameters,
+        tokenizer: Tokenizer,
+        _tensor_loader: impl TensorLoader<E>,
+    ) -> Result<Self, E> {
+        let context_size = common::context_size(&params, 1, N_EMBD);
+        Ok(Self::from_tokenizer(tokenizer, context_size))
+    }
+
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, crate::memory::MemoryLimitExceeded> {
+        InferenceSession::try_new(
+            config,
+            self.context_size,
+            1,
+            N_EMBD,
+            self.hyperparameters.n_vocab,
+        )
+    }
+
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        _params: &InferenceParameters,
+        input_tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+    ) {
+        // Generated tokens are added to the session before they are evaluated, and the
+        // tokens of a prompt after.
+        let mut tokens = session.tokens[..session.n_past].to_vec();
+        let mut all_logits = vec![];
+        for &token in input_tokens {
+            tokens.push(token);
+            session.last_logits = self.logits(&tokens);
+            if output_request.all_logits.is_some() {
+                all_logits.extend_from_slice(&session.last_logits);
+            }
+        }
+        if let Some(output) = &mut output_request.all_logits {
+            *output = all_logits;
+        }
+        if let Some(embeddings) = &mut output_request.embeddings {
+            *embeddings = vec![0.0; N_EMBD];
+        }
+        session.n_past += input_tokens.len();
+    }
+
+    fn hyperparameters(&self) -> &Self::Hyperparameters {
+        &self.hyperparameters
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    fn bot_token_id(&self) -> Option<TokenId> {
+        Some(MOCK_BOS)
+    }
+
+    fn eot_token_id(&self) -> TokenId {
+        MOCK_EOT
+    }
+
+    fn architecture_info(&self) -> ArchitectureInfo {
+        ArchitectureInfo {
+            n_layer: 1,
+            n_head: 1,
+            n_embd: N_EMBD,
+            n_ctx_train: None,
+            vocab_size: self.hyperparameters.n_vocab,
+            quantization: None,
+            tied_embeddings: false,
+        }
+    }
+
+    fn quantize_tensors() -> Vec<Regex> {
+        vec![]
+    }
+
+    fn skip_quantize_tensors() -> Vec<Regex> {
+        vec![]
+    }
+
+    fn embedding_tensors() -> EmbeddingTensors {
+        EmbeddingTensors {
+            input: "tok_embeddings.weight",
+            output: None,
+        }
+    }
+
+    fn distinctive_tensors() -> Vec<&'static str> {
+        vec![]
+    }
+
+    fn supports_rewind(&self) -> bool {
+        // The predictions only depend on the tokens of the session.
+        true
+    }
+}
+
+/// The hyperparameters of a [MockModel].
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct MockHyperparameters {
+    /// The number of tokens in the vocabulary.
+    pub n_vocab: usize,
+}
+impl Hyperparameters for MockHyperparameters {
+    fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
+        Ok(Self {
+            n_vocab: util::read_i32(reader)?.try_into()?,
+        })
+    }
+
+    fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
+        util::write_i32(writer, self.n_vocab.try_into()?)?;
+        Ok(())
+    }
+
+    fn n_vocabulary(&self) -> usize {
+        self.n_vocab
+    }
+
+    fn file_type(&self) -> Option<FileType> {
+        None
+    }
+
+    fn file_type_mut(&mut self) -> Option<&mut FileType> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha12Rng;
+
+    use super::*;
+    use crate::{
+        InferenceError, InferenceFeedback, InferenceRequest, InferenceResponse, StopReason,
+    };
+
+    fn model() -> MockModel {
+        MockModel::new(&["Hello", ",", " world", "!", " "]).with_response(", world!")
+    }
+
+    /// Runs inference on `prompt`, returning the generated text and the result.
+    fn infer(
+        model: &MockModel,
+        session: &mut InferenceSession,
+        prompt: &str,
+        maximum_token_count: Option<usize>,
+    ) -> (String, Result<StopReason, InferenceError>) {
+        let mut output = String::new();
+        let result = session.infer::<Infallible>(
+            model,
+            &mut ChaCha12Rng::seed_from_u64(0),
+            &InferenceRequest {
+                prompt: prompt.into(),
+                maximum_token_count,
+                ..Default::default()
+            },
+            &mut Default::default(),
+            |response| {
+                if let InferenceResponse::InferredToken(token) = response {
+                    output.push_str(&token);
+                }
+                Ok(InferenceFeedback::Continue)
+            },
+        );
+        (output, result.map(|stats| stats.stop_reason))
+    }
+
+    #[test]
+    fn generates_the_response_then_stops() {
+        let model = model();
+        let mut session = model.start_session(Default::default());
+        let (output, result) = infer(&model, &mut session, "Hello", None);
+        assert_eq!(output, ", world!");
+        assert_eq!(result.unwrap(), StopReason::EndOfText);
+
+        // The response starts over after the next prompt.
+        let (output, result) = infer(&model, &mut session, " Hello", Some(2));
+        assert_eq!(output, ", world");
+        assert_eq!(result.unwrap(), StopReason::MaximumTokens);
+    }
+
+    #[test]
+    fn non_finite_logits_fail() {
+        let model = model().with_non_finite_logits_at(3);
+        let mut session = model.start_session(Default::default());
+        let (output, result) = infer(&model, &mut session, "Hello", None);
+        assert_eq!(output, ",");
+        assert!(matches!(
+            result,
+            Err(InferenceError::NumericalError { step: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn small_context_fills_up() {
+        let model = model().with_context_size(3);
+        let mut session = model.start_session(Default::default());
+        let (_, result) = infer(&model, &mut session, "Hello", None);
+        assert!(matches!(result, Err(InferenceError::ContextFull)));
+    }
+}
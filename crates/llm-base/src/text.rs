@@ -0,0 +1,141 @@
+//! Utilities for working with text in terms of a model's tokens.
+use std::ops::Range;
+
+use thiserror::Error;
+
+use crate::{TokenId, TokenizationError, Tokenizer};
+
+/// A chunk of text produced by [chunk_by_tokens].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextChunk<'a> {
+    /// The text of the chunk.
+    pub text: &'a str,
+    /// The byte range of the chunk in the original text.
+    pub byte_range: Range<usize>,
+    /// The range of the chunk in the tokens of the original text.
+    pub token_range: Range<usize>,
+    /// The tokens of the chunk.
+    pub tokens: Vec<TokenId>,
+}
+
+/// Errors encountered by [chunk_by_tokens].
+#[derive(Error, Debug)]
+pub enum ChunkError {
+    /// The overlap must be smaller than the chunk size, or chunking would not make progress.
+    #[error("the overlap of {overlap} tokens must be smaller than the chunk size of {max_tokens} tokens")]
+    OverlapTooLarge {
+        /// The requested maximum number of tokens per chunk.
+        max_tokens: usize,
+        /// The requested overlap.
+        overlap: usize,
+    },
+    /// Tokenization failed.
+    #[error("tokenization failed")]
+    Tokenization(#[from] TokenizationError),
+}
+
+/// Splits `text` into chunks of at most `max_tokens` tokens, as counted by `tokenizer`.
+/// Consecutive chunks share up to `overlap` tokens.
+///
+/// Chunks are only split between tokens that start on a character boundary, so each
+/// chunk is valid UTF-8 and can be sliced out of `text` with its
+/// [byte_range](TextChunk::byte_range). Text the tokenizer skips (such as whitespace
+/// for some tokenizers) is assigned to the following chunk, so that chunks without
+/// overlap cover `text` exactly. If a single character needs more than `max_tokens`
+/// tokens, its chunk exceeds the limit.
+pub fn chunk_by_tokens<'a>(
+    tokenizer: &Tokenizer,
+    text: &'a str,
+    max_tokens: usize,
+    overlap: usize,
+) -> Result<Vec<TextChunk<'a>>, ChunkError> {
+    if overlap >= max_tokens {
+        return Err(ChunkError::OverlapTooLarge {
+            max_tokens,
+            overlap,
+        });
+    }
+
+    let tokens = tokenizer.tokenize_with_offsets(text)?;
+    let token_count = tokens.len();
+
+    // The byte offset at which a chunk starting (or ending) before each token begins, or
+    // `None` if a chunk cannot be split there.
+    let boundaries: Vec<Option<usize>> = (0..=token_count)
+        .map(|i| match i {
+            0 => Some(0),
+            i if i == token_count => Some(text.len()),
+            i => {
+                let start = tokens[i].1.start;
+                (tokens[i - 1].1.end <= start && text.is_char_boundary(start)).then_some(start)
+            }
+        })
+        .collect();
+    let is_boundary = |i: usize| boundaries[i].is_some();
+
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < token_count {
+        let limit = (start + max_tokens).min(token_count);
+        let end = (start + 1..=limit)
+            .rev()
+            .find(|&i| is_boundary(i))
+            .or_else(|| (limit + 1..=token_count).find(|&i| is_boundary(i)))
+            .unwrap_or(token_count);
+
+        let byte_range = boundaries[start].unwrap()..boundaries[end].unwrap();
+        chunks.push(TextChunk {
+            text: &text[byte_range.clone()],
+            byte_range,
+            token_range: start..end,
+            tokens: tokens[start..end].iter().map(|(id, _)| *id).collect(),
+        });
+
+        if end == token_count {
+            break;
+        }
+        start = (start + 1..=end.saturating_sub(overlap))
+            .rev()
+            .find(|&i| is_boundary(i))
+            .unwrap_or(end);
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::EmbeddedTokenizer;
+
+    fn tokenizer() -> Tokenizer {
+        let mut tokenizer = EmbeddedTokenizer::default();
+        // The embedded tokenizer treats token 0 as unset.
+        tokenizer.push_token(0, b"<unk>".to_vec(), 0.0);
+        let pieces: [&[u8]; 5] = [b"a", b"b", b" ", b"\xC3", b"\xA9"];
+        for (id, piece) in pieces.into_iter().enumerate() {
+            tokenizer.push_token(id as TokenId + 1, piece.to_vec(), 0.0);
+        }
+        tokenizer.into()
+    }
+
+    #[test]
+    fn chunks_do_not_split_characters() {
+        let text = "ab é";
+        let chunks = chunk_by_tokens(&tokenizer(), text, 1, 0).unwrap();
+
+        let texts: Vec<_> = chunks.iter().map(|c| c.text).collect();
+        assert_eq!(texts, ["a", "b", " ", "é"]);
+        assert_eq!(chunks[3].byte_range, 3..5);
+        assert_eq!(chunks[3].token_range, 3..5);
+    }
+
+    #[test]
+    fn chunks_overlap() {
+        let text = "abab";
+        let chunks = chunk_by_tokens(&tokenizer(), text, 3, 1).unwrap();
+
+        let ranges: Vec<_> = chunks.iter().map(|c| c.byte_range.clone()).collect();
+        assert_eq!(ranges, [0..3, 2..4]);
+        assert!(chunk_by_tokens(&tokenizer(), text, 1, 1).is_err());
+    }
+}
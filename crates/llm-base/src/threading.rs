@@ -0,0 +1,182 @@
+//! Selection of the number of threads used to evaluate the model.
+use std::time::Duration;
+
+/// How many threads to use when evaluating the model.
+///
+/// Prompt processing evaluates many tokens at once and benefits from as many threads as
+/// there are physical cores, while single-token decoding is often faster with fewer threads
+/// due to synchronisation overhead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadCount {
+    /// Use the same number of threads for every evaluation.
+    Fixed(usize),
+    /// Use separate thread counts for prompt processing and decoding.
+    PerPhase {
+        /// The number of threads used when feeding the prompt.
+        prompt: usize,
+        /// The number of threads used when generating one token at a time.
+        decode: usize,
+    },
+    /// Use `max` threads for prompt processing, and tune the number of threads used
+    /// for decoding (between 1 and `max`) based on the measured per-token latency.
+    Auto {
+        /// The maximum number of threads to use.
+        max: usize,
+    },
+}
+impl Default for ThreadCount {
+    fn default() -> Self {
+        ThreadCount::Fixed(8)
+    }
+}
+impl From<usize> for ThreadCount {
+    fn from(value: usize) -> Self {
+        ThreadCount::Fixed(value)
+    }
+}
+
+/// Whether `ggml` can evaluate the model with more than one thread. WebAssembly only has
+/// threads with the `atomics` target feature; without it, one thread is always used.
+const SUPPORTS_THREADS: bool = cfg!(any(not(target_arch = "wasm32"), target_feature = "atomics"));
+
+/// How many decode steps to run between trying a different thread count.
+const PROBE_INTERVAL: usize = 32;
+/// How many decode steps to measure a candidate thread count for.
+const PROBE_LENGTH: usize = 4;
+/// How much faster a candidate must be to replace the current thread count.
+const IMPROVEMENT_THRESHOLD: f64 = 0.95;
+/// The weight given to new measurements in the latency moving averages.
+const EMA_ALPHA: f64 = 0.25;
+
+/// Tunes the number of decode threads for [ThreadCount::Auto] by hill-climbing on the
+/// measured per-token latency.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ThreadTuner {
+    /// Moving average of the per-token decode latency (in seconds), indexed by thread count.
+    latency: Vec<Option<f64>>,
+    /// The thread count currently believed to be the fastest.
+    current: usize,
+    /// A thread count being measured, and how many steps it has left.
+    probe: Option<(usize, usize)>,
+    /// Whether the next probe should try more threads rather than fewer.
+    probe_up: bool,
+    steps_since_probe: usize,
+    /// The thread count used for the most recent decode step, if it should be measured.
+    pending: Option<usize>,
+}
+impl ThreadTuner {
+    /// Selects the number of threads to use to evaluate `n_tokens` tokens.
+    pub(crate) fn select(&mut self, threads: ThreadCount, n_tokens: usize) -> usize {
+        self.pending = None;
+        if !SUPPORTS_THREADS {
+            return 1;
+        }
+        let is_decode = n_tokens == 1;
+        match threads {
+            ThreadCount::Fixed(n) => n.max(1),
+            ThreadCount::PerPhase { prompt, decode } => {
+                if is_decode { decode } else { prompt }.max(1)
+            }
+            ThreadCount::Auto { max } => {
+                let max = max.max(1);
+                if !is_decode {
+                    return max;
+                }
+                let n = self.select_decode(max);
+                self.pending = Some(n);
+                n
+            }
+        }
+    }
+
+    /// Records how long the evaluation selected by the last call to [Self::select] took.
+    pub(crate) fn record(&mut self, elapsed: Duration) {
+        let Some(n) = self.pending.take() else {
+            return;
+        };
+        if self.latency.len() <= n {
+            self.latency.resize(n + 1, None);
+        }
+        let sample = elapsed.as_secs_f64();
+        let average = self.latency[n].map_or(sample, |average| {
+            average * (1.0 - EMA_ALPHA) + sample * EMA_ALPHA
+        });
+        self.latency[n] = Some(average);
+
+        if let Some((candidate, remaining)) = self.probe {
+            if remaining > 1 {
+                self.probe = Some((candidate, remaining - 1));
+                return;
+            }
+
+            self.probe = None;
+            let candidate_latency = self.latency[candidate];
+            let current_latency = self.latency.get(self.current).copied().flatten();
+            match (candidate_latency, current_latency) {
+                (Some(candidate_latency), Some(current_latency))
+                    if candidate_latency < current_latency * IMPROVEMENT_THRESHOLD =>
+                {
+                    // Keep climbing in the same direction.
+                    self.current = candidate;
+                }
+                _ => self.probe_up = !self.probe_up,
+            }
+        }
+    }
+
+    fn select_decode(&mut self, max: usize) -> usize {
+        if self.current == 0 || self.current > max {
+            self.current = max;
+        }
+        if let Some((candidate, _)) = self.probe {
+            return candidate;
+        }
+
+        self.steps_since_probe += 1;
+        if self.steps_since_probe >= PROBE_INTERVAL {
+            self.steps_since_probe = 0;
+            let candidate = if self.probe_up {
+                (self.current + 1).min(max)
+            } else {
+                self.current.saturating_sub(1).max(1)
+            };
+            if candidate != self.current {
+                self.probe = Some((candidate, PROBE_LENGTH));
+                return candidate;
+            }
+            self.probe_up = !self.probe_up;
+        }
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_converges_to_fastest_decode_thread_count() {
+        // Latency is lowest at 4 threads.
+        let latency = |n: usize| Duration::from_micros(100 + 50 * (n as i64 - 4).unsigned_abs());
+
+        let mut tuner = ThreadTuner::default();
+        let threads = ThreadCount::Auto { max: 8 };
+        assert_eq!(tuner.select(threads, 16), 8);
+        for _ in 0..2000 {
+            let n = tuner.select(threads, 1);
+            tuner.record(latency(n));
+        }
+        assert_eq!(tuner.current, 4);
+    }
+
+    #[test]
+    fn per_phase_uses_decode_threads_for_single_tokens() {
+        let mut tuner = ThreadTuner::default();
+        let threads = ThreadCount::PerPhase {
+            prompt: 8,
+            decode: 3,
+        };
+        assert_eq!(tuner.select(threads, 8), 8);
+        assert_eq!(tuner.select(threads, 1), 3);
+    }
+}
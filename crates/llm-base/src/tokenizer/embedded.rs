@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range};
 
 use thiserror::Error;
 
@@ -37,7 +37,7 @@ impl EmbeddedTokenizer {
     /// # Panics
     /// - This function can panic if `id` does not correspond to the next token in the vocabulary.
     ///   That is, if there are already `n` tokens in the vocabulary, then `id` must be `n`.
-    pub(crate) fn push_token(&mut self, id: TokenId, content: Token, score: TokenScore) {
+    pub fn push_token(&mut self, id: TokenId, content: Token, score: TokenScore) {
         // These are loader invariants. If this is broken, then the loader is broken and this is a bug,
         // not an issue with the model itself.
         assert_eq!(self.id_to_token.len(), self.id_to_token_score.len());
@@ -133,6 +133,26 @@ impl EmbeddedTokenizer {
         Ok(res)
     }
 
+    /// Tokenize a `text` with this tokenizer, returning the byte range of `text` that
+    /// each token was produced from.
+    pub(crate) fn tokenize_with_offsets(
+        &self,
+        text: &str,
+    ) -> Result<Vec<(TokenId, Range<usize>)>, TokenizationError> {
+        // The pieces of this tokenizer are the exact bytes of the text, so the offsets
+        // are their running lengths.
+        let mut start = 0;
+        Ok(self
+            .tokenize(text, false)?
+            .into_iter()
+            .map(|(piece, id)| {
+                let range = start..start + piece.len();
+                start = range.end;
+                (id, range)
+            })
+            .collect())
+    }
+
     /// Decode a list `tokens` with this tokenizer.
     pub(crate) fn decode(&self, tokens: Vec<TokenId>, skip_special_tokens: bool) -> Vec<u8> {
         let mut vec = vec![];
@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 use super::{TokenId, TokenizationError};
 
 /// A Hugging Face tokenizer.
@@ -64,6 +66,30 @@ impl HuggingFaceTokenizer {
             .collect())
     }
 
+    /// Tokenize a `text` with this tokenizer, returning the byte range of `text` that
+    /// each token was produced from.
+    pub(crate) fn tokenize_with_offsets(
+        &self,
+        text: &str,
+    ) -> Result<Vec<(TokenId, Range<usize>)>, TokenizationError> {
+        let encoding = self
+            .tokenizer
+            .encode(text, false)
+            .map_err(|e| TokenizationError::TokenizationFailed { error: e })?;
+
+        Ok(encoding
+            .get_ids()
+            .iter()
+            .copied()
+            .zip(
+                encoding
+                    .get_offsets()
+                    .iter()
+                    .map(|&(start, end)| start..end),
+            )
+            .collect())
+    }
+
     /// Decode a list `tokens` with this tokenizer.
     pub(crate) fn decode(&self, tokens: Vec<TokenId>, skip_special_tokens: bool) -> Vec<u8> {
         self.tokenizer
@@ -1,6 +1,8 @@
 use std::{
+    borrow::Cow,
     error::Error,
     fmt::Display,
+    ops::Range,
     path::{Path, PathBuf},
     str::FromStr,
 };
@@ -89,7 +91,7 @@ impl TokenizerSource {
             .into(),
 
             Self::HuggingFaceTokenizerFile(path) => HuggingFaceTokenizer::new(
-                tokenizers::Tokenizer::from_file(&path)
+                tokenizers::Tokenizer::from_file(crate::util::long_path(&path))
                     .map_err(|error| TokenizerLoadError::new(path, error))?,
             )
             .into(),
@@ -105,6 +107,19 @@ impl TokenizerSource {
     }
 }
 
+/// The tokens that models use to end their output or their turn in a conversation.
+/// [Tokenizer::end_token_ids] looks them up in a vocabulary.
+pub const END_TOKENS: &[&str] = &[
+    "</s>",
+    "<|endoftext|>",
+    "<|end_of_text|>",
+    "<|im_end|>",
+    "<|eot_id|>",
+    "<|eom_id|>",
+    "<|end|>",
+    "<end_of_turn>",
+];
+
 /// Encapsulates the tokenizer for a model, and provides methods to tokenize text.
 pub enum Tokenizer {
     /// The vocabulary built-in to the model.
@@ -139,6 +154,14 @@ impl Tokenizer {
         }
     }
 
+    /// Returns the IDs of the tokens of [END_TOKENS] that are in the vocabulary.
+    pub fn end_token_ids(&self) -> Vec<TokenId> {
+        END_TOKENS
+            .iter()
+            .filter_map(|token| self.id(token.as_bytes()))
+            .collect()
+    }
+
     /// Converts a token index to the token it represents in this tokenizer.
     pub fn token(&self, idx: usize) -> Vec<u8> {
         match self {
@@ -177,6 +200,21 @@ impl Tokenizer {
         }
     }
 
+    /// Tokenize a `text` with this tokenizer, returning the byte range of `text` that
+    /// each token was produced from. No beginning-of-string token is inserted.
+    ///
+    /// The ranges are in order, but may overlap when several tokens were produced from
+    /// the same character.
+    pub(crate) fn tokenize_with_offsets(
+        &self,
+        text: &str,
+    ) -> Result<Vec<(TokenId, Range<usize>)>, TokenizationError> {
+        match self {
+            Tokenizer::Embedded(v) => v.tokenize_with_offsets(text),
+            Tokenizer::HuggingFace(v) => v.tokenize_with_offsets(text),
+        }
+    }
+
     /// Decode a list `tokens` with this tokenizer.
     pub fn decode(&self, tokens: Vec<TokenId>, bos: bool) -> Vec<u8> {
         match self {
@@ -201,6 +239,13 @@ pub enum Prompt<'a> {
     Text(&'a str),
     /// A prompt specified as tokens for this model's tokenizer.
     Tokens(&'a [TokenId]),
+    /// A prompt specified as text, in which `{{token:ID}}` is replaced with the token
+    /// `ID` without going through the tokenizer.
+    ///
+    /// This places exact control tokens (e.g. `{{token:32001}}`) in a prompt without
+    /// building the token list by hand. Text that looks like an escape but does not
+    /// contain a valid token ID is tokenized as-is.
+    WithTokenEscapes(&'a str),
 }
 impl Prompt<'_> {
     /// Converts this prompt to a list of tokens for this model's tokenizer.
@@ -219,17 +264,37 @@ impl Prompt<'_> {
                 .map(|(_, tok)| *tok)
                 .collect(),
             Self::Tokens(tokens) => tokens.to_vec(),
+            Self::WithTokenEscapes(text) => {
+                // Tokenizing nothing yields just the beginning-of-sentence token, if any.
+                let mut tokens: Vec<_> = vocab
+                    .tokenize("", beginning_of_sentence)?
+                    .into_iter()
+                    .map(|(_, tok)| tok)
+                    .collect();
+                for segment in split_token_escapes(text) {
+                    match segment {
+                        PromptSegment::Text(text) => tokens
+                            .extend(vocab.tokenize(text, false)?.into_iter().map(|(_, tok)| tok)),
+                        PromptSegment::Token(id) if (id as usize) < vocab.len() => tokens.push(id),
+                        PromptSegment::Token(id) => {
+                            return Err(TokenizationError::InvalidTokenId(id))
+                        }
+                    }
+                }
+                tokens
+            }
         })
     }
 
     /// Returns whether this prompt is empty.
     pub fn is_empty(&self) -> bool {
         match self {
-            Self::Text(text) => text.is_empty(),
+            Self::Text(text) | Self::WithTokenEscapes(text) => text.is_empty(),
             Self::Tokens(tokens) => tokens.is_empty(),
         }
     }
 }
+
 impl<'a> Default for Prompt<'a> {
     fn default() -> Self {
         Self::Text("")
@@ -256,7 +321,40 @@ impl<'a> From<&'a Vec<TokenId>> for Prompt<'a> {
     }
 }
 
-#[derive(Default, Clone, Debug, PartialEq)]
+#[derive(Debug, PartialEq)]
+enum PromptSegment<'a> {
+    Text(&'a str),
+    Token(TokenId),
+}
+
+/// Splits `text` into the text between `{{token:ID}}` escapes and the escaped tokens.
+fn split_token_escapes(mut text: &str) -> Vec<PromptSegment<'_>> {
+    const START: &str = "{{token:";
+    let mut segments = vec![];
+    let mut search_from = 0;
+    while let Some(offset) = text[search_from..].find(START) {
+        let escape_start = search_from + offset;
+        let escape = text[escape_start + START.len()..]
+            .split_once("}}")
+            .and_then(|(id, rest)| Some((id.parse().ok()?, rest)));
+        let Some((id, rest)) = escape else {
+            search_from = escape_start + START.len();
+            continue;
+        };
+        if escape_start > 0 {
+            segments.push(PromptSegment::Text(&text[..escape_start]));
+        }
+        segments.push(PromptSegment::Token(id));
+        text = rest;
+        search_from = 0;
+    }
+    if !text.is_empty() {
+        segments.push(PromptSegment::Text(text));
+    }
+    segments
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 /// A list of tokens to bias during the process of inferencing.
 ///
 /// When a biased token is encountered, the bias will be used
@@ -265,28 +363,125 @@ impl<'a> From<&'a Vec<TokenId>> for Prompt<'a> {
 /// This can be used to disable the generation of responses
 /// with specific tokens by setting their corresponding bias
 /// to -1.0.
-pub struct TokenBias(Vec<(TokenId, f32)>);
+///
+/// Text biased with [TokenBias::add_text] may take several tokens, which are only biased
+/// after the tokens before them; see [TokenBias::for_next_token].
+pub struct TokenBias {
+    tokens: Vec<(TokenId, f32)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sequences: Vec<(Vec<TokenId>, f32)>,
+}
 
 impl TokenBias {
     /// Create an empty [TokenBias].
     pub const fn empty() -> Self {
-        Self(Vec::new())
+        Self {
+            tokens: Vec::new(),
+            sequences: Vec::new(),
+        }
     }
 
     /// Create a [TokenBias] from an existing `Vec`.
     pub fn new(mut v: Vec<(TokenId, f32)>) -> Self {
         v.sort_by_cached_key(|(tid, _)| *tid);
         v.dedup_by_key(|(tid, _)| *tid);
-        Self(v)
+        Self {
+            tokens: v,
+            sequences: Vec::new(),
+        }
     }
 
     /// Retrieves the bias for a given token, if available.
+    ///
+    /// This does not include the biases of the sequences of several tokens, which depend
+    /// on the previous tokens; use [TokenBias::for_next_token] for them.
     pub fn get(&self, tid: TokenId) -> Option<f32> {
-        self.0
+        self.tokens
             .binary_search_by_key(&tid, |(tid, _)| *tid)
-            .map(|idx| self.0[idx].1)
+            .map(|idx| self.tokens[idx].1)
             .ok()
     }
+
+    /// Returns the biases of the token that follows `previous_tokens`, as a [TokenBias]
+    /// of single tokens.
+    ///
+    /// A sequence of several tokens biases its next token when `previous_tokens` ends
+    /// with the tokens before it. A negative bias only applies to the last token of the
+    /// sequence, so that banning a word does not ban the other words that start with the
+    /// same tokens. A positive bias applies to every token of the sequence, starting with
+    /// the first, so that the word is both started and completed. The biases of single
+    /// tokens take precedence.
+    pub fn for_next_token(&self, previous_tokens: &[TokenId]) -> Cow<'_, Self> {
+        if self.sequences.is_empty() {
+            return Cow::Borrowed(self);
+        }
+
+        let mut tokens = self.tokens.clone();
+        for (sequence, bias) in &self.sequences {
+            let first = if *bias < 0.0 { sequence.len() - 1 } else { 0 };
+            for k in first..sequence.len() {
+                if previous_tokens.ends_with(&sequence[..k])
+                    && !tokens.iter().any(|(id, _)| *id == sequence[k])
+                {
+                    tokens.push((sequence[k], *bias));
+                }
+            }
+        }
+        Cow::Owned(Self::new(tokens))
+    }
+
+    /// Biases `text` by token, so that biases can be given without knowing the vocabulary
+    /// of the model. Tokens and sequences that already have a bias keep it.
+    ///
+    /// `text` is tokenized as is, with a leading space and without its leading whitespace,
+    /// as words are usually tokenized differently at the start of the text and after a
+    /// space. The bias applies to the tokens of each tokenization as described in
+    /// [TokenBias::for_next_token], and to any token whose text is one of these variants.
+    ///
+    /// Fails if none of the variants can be tokenized.
+    pub fn add_text(
+        &mut self,
+        tokenizer: &Tokenizer,
+        text: &str,
+        bias: f32,
+    ) -> Result<(), TokenizationError> {
+        let trimmed = text.trim_start();
+        let spaced = format!(" {trimmed}");
+        let mut variants = vec![text, trimmed, spaced.as_str()];
+        variants.sort_unstable();
+        variants.dedup();
+
+        // A variant that cannot be tokenized is skipped, unless none can.
+        let mut sequences = vec![];
+        let mut error = None;
+        for variant in variants {
+            if variant.is_empty() {
+                continue;
+            }
+            match tokenizer.tokenize(variant, false) {
+                Ok(tokenized) if !tokenized.is_empty() => {
+                    sequences.push(tokenized.into_iter().map(|(_, id)| id).collect::<Vec<_>>())
+                }
+                Ok(_) => {}
+                Err(err) => error = Some(err),
+            }
+            sequences.extend(tokenizer.id(variant.as_bytes()).map(|id| vec![id]));
+        }
+        if let (true, Some(error)) = (sequences.is_empty(), error) {
+            return Err(error);
+        }
+        for sequence in sequences {
+            if let [id] = sequence[..] {
+                if self.get(id).is_none() {
+                    self.tokens.push((id, bias));
+                    self.tokens.sort_by_key(|(id, _)| *id);
+                }
+            } else if !self.sequences.iter().any(|(s, _)| *s == sequence) {
+                self.sequences.push((sequence, bias));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl FromStr for TokenBias {
@@ -342,6 +537,94 @@ impl Error for InvalidTokenBias {}
 
 impl std::fmt::Display for TokenBias {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.0)
+        write!(f, "{:?}", self.tokens)?;
+        if !self.sequences.is_empty() {
+            write!(f, " {:?}", self.sequences)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_escapes_are_split_from_the_text() {
+        use PromptSegment::*;
+        assert_eq!(
+            split_token_escapes("{{token:1}}Hi{{token:32001}} there{{token:x}}{{token:2}}"),
+            [
+                Token(1),
+                Text("Hi"),
+                Token(32001),
+                Text(" there{{token:x}}"),
+                Token(2)
+            ]
+        );
+        assert_eq!(split_token_escapes("no escapes"), [Text("no escapes")]);
+    }
+
+    #[test]
+    fn end_tokens_are_found_in_the_vocabulary() {
+        let mut tokenizer = EmbeddedTokenizer::default();
+        for (id, token) in ["<unk>", "</s>", "<|im_start|>", "<|im_end|>", "end"]
+            .into_iter()
+            .enumerate()
+        {
+            tokenizer.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+        }
+        assert_eq!(Tokenizer::from(tokenizer).end_token_ids(), [1, 3]);
+    }
+
+    #[test]
+    fn text_biases_cover_the_leading_space_variants() {
+        let mut tokenizer = EmbeddedTokenizer::default();
+        for (id, token) in ["<unk>", "cat", " cat", " dog", "s", "dog"]
+            .into_iter()
+            .enumerate()
+        {
+            tokenizer.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+        }
+        let tokenizer = Tokenizer::from(tokenizer);
+
+        let mut bias = TokenBias::new(vec![(3, 1.0)]);
+        bias.add_text(&tokenizer, "cat", -1.0).unwrap();
+        bias.add_text(&tokenizer, " dog", -2.0).unwrap();
+        assert_eq!(
+            bias,
+            TokenBias::new(vec![(1, -1.0), (2, -1.0), (3, 1.0), (5, -2.0)])
+        );
+    }
+
+    #[test]
+    fn text_biases_follow_the_tokens_of_the_word() {
+        let mut tokenizer = EmbeddedTokenizer::default();
+        for (id, token) in ["<unk>", "cat", " cat", "s", "dog"].into_iter().enumerate() {
+            tokenizer.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+        }
+        let tokenizer = Tokenizer::from(tokenizer);
+
+        // "cats" is "cat" + "s": banning it only bans the "s" after "cat" or " cat".
+        let mut ban = TokenBias::empty();
+        ban.add_text(&tokenizer, "cats", f32::NEG_INFINITY).unwrap();
+        assert_eq!(ban.for_next_token(&[]).into_owned(), TokenBias::empty());
+        assert_eq!(ban.for_next_token(&[4]).into_owned(), TokenBias::empty());
+        for previous in [[4, 1], [4, 2]] {
+            assert_eq!(
+                ban.for_next_token(&previous).into_owned(),
+                TokenBias::new(vec![(3, f32::NEG_INFINITY)])
+            );
+        }
+
+        // A positive bias starts the word, then completes it.
+        let mut boost = TokenBias::new(vec![(3, -1.0)]);
+        boost.add_text(&tokenizer, "cats", 2.0).unwrap();
+        assert_eq!(
+            boost.for_next_token(&[4]).into_owned(),
+            TokenBias::new(vec![(1, 2.0), (2, 2.0), (3, -1.0)])
+        );
+        assert_eq!(boost.for_next_token(&[1]).get(3), Some(-1.0));
+        assert_eq!(boost.get(1), None);
     }
 }
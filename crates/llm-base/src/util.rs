@@ -2,7 +2,9 @@
 pub use ggml::util::*;
 
 use std::{
-    io::BufRead,
+    borrow::Cow,
+    fs::File,
+    io::{BufRead, Read},
     path::{Path, PathBuf},
 };
 
@@ -22,6 +24,7 @@ macro_rules! mulf {
 }
 
 use memmap2::{Mmap, MmapAsRawDesc, MmapOptions};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
 use crate::{FileType, LoadError};
@@ -95,14 +98,15 @@ pub fn find_all_model_files(main_path: &Path) -> Result<Vec<PathBuf>, FindAllMod
             .ok_or_else(|| FindAllModelFilesError::NoParentPath {
                 path: main_path.to_owned(),
             })?;
-    if main_path_parent.to_str() == Some("") {
+    if main_path_parent.as_os_str().is_empty() {
         main_path_parent = Path::new(".");
     }
     Ok(collect_related_paths(
         main_path,
-        std::fs::read_dir(main_path_parent)?
+        std::fs::read_dir(long_path(main_path_parent))?
             .filter_map(Result::ok)
-            .map(|de| de.path()),
+            // The entries are joined to the original path, not to its long form.
+            .map(|de| main_path_parent.join(de.file_name())),
     ))
 }
 
@@ -114,28 +118,162 @@ fn collect_related_paths(
 
     let mut paths: Vec<PathBuf> = directory_paths
         .filter(|p| {
-            p.file_name()
-                .and_then(|p| p.to_str())
-                .zip(main_filename)
-                .map(|(part_filename, main_filename)| {
-                    match part_filename.strip_prefix(main_filename) {
-                        Some(suffix) => {
-                            suffix.is_empty()
-                                || (suffix
-                                    .strip_prefix('.')
-                                    .map(|s| s.parse::<usize>().is_ok())
-                                    .unwrap_or(false))
+            // Names that are not valid UTF-8 can still be loaded, but cannot have parts.
+            p.file_name() == main_path.file_name()
+                || p.file_name()
+                    .and_then(|p| p.to_str())
+                    .zip(main_filename)
+                    .map(|(part_filename, main_filename)| {
+                        match part_filename.strip_prefix(main_filename) {
+                            Some(suffix) => {
+                                suffix.is_empty()
+                                    || (suffix
+                                        .strip_prefix('.')
+                                        .map(|s| s.parse::<usize>().is_ok())
+                                        .unwrap_or(false))
+                            }
+                            None => false,
                         }
-                        None => false,
-                    }
-                })
-                .unwrap_or(false)
+                    })
+                    .unwrap_or(false)
         })
         .collect();
     paths.sort();
     paths
 }
 
+/// Returns the paths of all of the shards of a sharded model, if `path` is one of them.
+///
+/// Shards are named `<name>-<index>-of-<count><extension>`, with indices starting at 1,
+/// like `model-00001-of-00004.bin`. The index is zero-padded to the same width in all
+/// of the shards. This only looks at the name of the file: the other shards may not exist.
+pub fn shard_paths(path: &Path) -> Option<Vec<PathBuf>> {
+    let filename = path.file_name()?.to_str()?;
+    let (start, end) = filename.rsplit_once("-of-")?;
+    let (name, index) = start.rsplit_once('-')?;
+    let count_len = end.bytes().take_while(u8::is_ascii_digit).count();
+    let (count, extension) = end.split_at(count_len);
+
+    let width = index.len();
+    if !index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let index: usize = index.parse().ok()?;
+    let count_value: usize = count.parse().ok()?;
+    if index == 0 || index > count_value {
+        return None;
+    }
+
+    Some(
+        (1..=count_value)
+            .map(|i| path.with_file_name(format!("{name}-{i:0width$}-of-{count}{extension}")))
+            .collect(),
+    )
+}
+
+/// Computes the SHA-256 of the model at `path`: of all of its shards, one after the other,
+/// if it is one of the shards of a sharded model (see [shard_paths]), or of the file
+/// otherwise. This is the hash that
+/// [ModelParameters::expected_sha256](crate::ModelParameters::expected_sha256) is
+/// compared with when loading.
+pub fn model_sha256(path: &Path) -> std::io::Result<[u8; 32]> {
+    let paths = shard_paths(path).unwrap_or_else(|| vec![path.to_owned()]);
+    let mut hasher = Sha256::new();
+    for path in paths {
+        let mut file = File::open(long_path(&path))?;
+        hash_reader(&mut hasher, &mut file, |_| Ok::<_, std::io::Error>(()))?;
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Feeds all of `reader` to `hasher`, calling `on_read` with the number of bytes of each
+/// chunk read. Reading stops at the first error of `on_read`.
+pub(crate) fn hash_reader<E: From<std::io::Error>>(
+    hasher: &mut Sha256,
+    reader: &mut impl Read,
+    mut on_read: impl FnMut(u64) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.update(&buffer[..n]);
+        on_read(n as u64)?;
+    }
+}
+
+/// Returns a form of `path` that can be opened even if it is longer than `MAX_PATH`
+/// (260 characters) on Windows.
+///
+/// On Windows, the path is made absolute, normalized, and given the `\\?\` prefix, which
+/// lifts the length limit. Paths that are already verbatim, or that cannot be made absolute,
+/// are returned as they are. On other platforms, `path` is always returned as it is.
+///
+/// Use the returned path to access the file, and the original path in messages.
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    #[cfg(windows)]
+    {
+        verbatim_path(path).map_or(Cow::Borrowed(path), Cow::Owned)
+    }
+    #[cfg(not(windows))]
+    {
+        Cow::Borrowed(path)
+    }
+}
+
+#[cfg(windows)]
+fn verbatim_path(path: &Path) -> Option<PathBuf> {
+    use std::{
+        ffi::OsString,
+        path::{Component, Prefix},
+    };
+
+    let absolute = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        std::env::current_dir().ok()?.join(path)
+    };
+
+    let mut components = absolute.components();
+    let mut verbatim = OsString::from(r"\\?\");
+    match components.next()? {
+        Component::Prefix(prefix) => match prefix.kind() {
+            Prefix::Disk(disk) => verbatim.push(format!("{}:", disk as char)),
+            Prefix::UNC(server, share) => {
+                verbatim.push(r"UNC\");
+                verbatim.push(server);
+                verbatim.push(r"\");
+                verbatim.push(share);
+            }
+            // Verbatim and device paths are passed to Windows as they are.
+            _ => return None,
+        },
+        _ => return None,
+    }
+
+    // Windows does not normalize verbatim paths, so `.` and `..` are resolved here.
+    let mut parts = vec![];
+    for component in components {
+        match component {
+            Component::Normal(part) => parts.push(part),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    if parts.is_empty() {
+        verbatim.push(r"\");
+    }
+    for part in parts {
+        verbatim.push(r"\");
+        verbatim.push(part);
+    }
+    Some(verbatim.into())
+}
+
 /// mmap with MAP_POPULATE
 pub fn mmap_populate<T: MmapAsRawDesc>(file: T) -> Result<Mmap, std::io::Error> {
     unsafe { MmapOptions::new().populate().map(file) }
@@ -177,6 +315,101 @@ mod tests {
         assert_eq!(expected_paths.as_slice(), output_paths);
     }
 
+    #[test]
+    fn test_shard_paths() {
+        let expected_paths = [
+            "/models/llama-00001-of-00003.bin",
+            "/models/llama-00002-of-00003.bin",
+            "/models/llama-00003-of-00003.bin",
+        ]
+        .map(PathBuf::from);
+        assert_eq!(
+            shard_paths(Path::new("/models/llama-00002-of-00003.bin")).as_deref(),
+            Some(expected_paths.as_slice())
+        );
+        assert_eq!(shard_paths(Path::new("/models/llama.bin")), None);
+        assert_eq!(shard_paths(Path::new("/models/llama-4-of-3.bin")), None);
+    }
+
+    #[test]
+    fn test_model_sha256_hashes_all_shards() {
+        let dir = std::env::temp_dir().join(format!("llm-sha256-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("llama-1-of-2.bin"), b"first ").unwrap();
+        std::fs::write(dir.join("llama-2-of-2.bin"), b"second").unwrap();
+        std::fs::write(dir.join("llama.bin"), b"first second").unwrap();
+
+        let expected: [u8; 32] = Sha256::digest(b"first second").into();
+        assert_eq!(
+            model_sha256(&dir.join("llama-2-of-2.bin")).unwrap(),
+            expected
+        );
+        assert_eq!(model_sha256(&dir.join("llama.bin")).unwrap(), expected);
+        assert!(model_sha256(&dir.join("missing.bin")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unicode_and_long_paths() {
+        let dir = std::env::temp_dir()
+            .join(format!("llm-paths-{}", std::process::id()))
+            .join("模型 ünïcödé".repeat(12))
+            .join("ディレクトリ".repeat(10))
+            .join("ünïcödé".repeat(10));
+        assert!(dir.as_os_str().len() > 260);
+        std::fs::create_dir_all(long_path(&dir)).unwrap();
+
+        let main_path = dir.join("ラマ.bin");
+        std::fs::write(long_path(&main_path), b"model").unwrap();
+        std::fs::write(long_path(&dir.join("ラマ.bin.1")), b"part").unwrap();
+        assert_eq!(
+            find_all_model_files(&main_path).unwrap(),
+            [main_path.clone(), dir.join("ラマ.bin.1")]
+        );
+        assert_eq!(std::fs::read(long_path(&main_path)).unwrap(), b"model");
+
+        std::fs::remove_dir_all(long_path(
+            &std::env::temp_dir().join(format!("llm-paths-{}", std::process::id())),
+        ))
+        .unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_long_path_is_verbatim() {
+        assert_eq!(
+            long_path(Path::new(r"C:\models\.\old\..\llama.bin")),
+            Path::new(r"\\?\C:\models\llama.bin")
+        );
+        assert_eq!(
+            long_path(Path::new(r"\\server\share\llama.bin")),
+            Path::new(r"\\?\UNC\server\share\llama.bin")
+        );
+        assert_eq!(
+            long_path(Path::new(r"\\?\C:\models\llama.bin")),
+            Path::new(r"\\?\C:\models\llama.bin")
+        );
+        assert!(long_path(Path::new("llama.bin"))
+            .to_str()
+            .unwrap()
+            .starts_with(r"\\?\"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_paths() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let main_path = Path::new("/models").join(OsStr::from_bytes(b"ll\xFFama.bin"));
+        let directory_paths = [main_path.clone(), PathBuf::from("/models/llama.bin")];
+        assert_eq!(
+            collect_related_paths(&main_path, directory_paths.into_iter()),
+            std::slice::from_ref(&main_path)
+        );
+        assert_eq!(shard_paths(&main_path), None);
+    }
+
     #[test]
     fn test_valid_utf8() {
         let mut buffer = TokenUtf8Buffer::new();
@@ -0,0 +1,238 @@
+//! Statistics about a vocabulary, for debugging mismatches between a model and its tokenizer.
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{TokenId, Tokenizer};
+
+/// Statistics about the tokens of a [Tokenizer], computed by [VocabStats::new].
+#[derive(Clone, Debug, PartialEq)]
+pub struct VocabStats {
+    /// The number of tokens in the vocabulary.
+    pub size: usize,
+    /// The bytes that no token represents on its own, either as the byte itself or as a
+    /// byte-fallback token like `<0x0A>`. Text containing them cannot always be tokenized.
+    pub missing_bytes: Vec<u8>,
+    /// The longest tokens, longest first.
+    pub longest: Vec<(TokenId, Vec<u8>)>,
+    /// Tokens that have the same bytes as other tokens, grouped by their bytes.
+    pub duplicates: Vec<(Vec<u8>, Vec<TokenId>)>,
+    /// Tokens without any bytes, such as special tokens in some tokenizers.
+    pub empty: Vec<TokenId>,
+    /// The number of tokens of each [Script], most common first.
+    pub scripts: Vec<(Script, usize)>,
+}
+impl VocabStats {
+    /// Computes the statistics of `tokenizer`, keeping the `longest` longest tokens.
+    pub fn new(tokenizer: &Tokenizer, longest: usize) -> Self {
+        let tokens: Vec<Vec<u8>> = (0..tokenizer.len()).map(|i| tokenizer.token(i)).collect();
+
+        let mut covered = [false; 256];
+        for token in &tokens {
+            if let [byte] = token[..] {
+                covered[usize::from(byte)] = true;
+            } else if let Some(byte) = byte_fallback(token) {
+                covered[usize::from(byte)] = true;
+            }
+        }
+        let missing_bytes = (0..=255).filter(|b| !covered[usize::from(*b)]).collect();
+
+        let mut by_length: Vec<_> = tokens.iter().enumerate().collect();
+        by_length.sort_by_key(|(id, token)| (std::cmp::Reverse(token.len()), *id));
+        let longest = by_length
+            .into_iter()
+            .take(longest)
+            .map(|(id, token)| (id as TokenId, token.clone()))
+            .collect();
+
+        let mut ids_by_token: HashMap<&[u8], Vec<TokenId>> = HashMap::new();
+        for (id, token) in tokens.iter().enumerate() {
+            ids_by_token.entry(token).or_default().push(id as TokenId);
+        }
+        let empty = ids_by_token.remove(&b""[..]).unwrap_or_default();
+        let mut duplicates: Vec<_> = ids_by_token
+            .into_iter()
+            .filter(|(_, ids)| ids.len() > 1)
+            .map(|(token, ids)| (token.to_vec(), ids))
+            .collect();
+        duplicates.sort_by_key(|(_, ids)| ids[0]);
+
+        let mut script_counts: HashMap<Script, usize> = HashMap::new();
+        for token in tokens.iter().filter(|t| !t.is_empty()) {
+            *script_counts.entry(Script::of(token)).or_default() += 1;
+        }
+        let mut scripts: Vec<_> = script_counts.into_iter().collect();
+        scripts.sort_by_key(|(script, count)| (std::cmp::Reverse(*count), *script));
+
+        Self {
+            size: tokens.len(),
+            missing_bytes,
+            longest,
+            duplicates,
+            empty,
+            scripts,
+        }
+    }
+}
+
+/// Returns the byte represented by a byte-fallback token like `<0x0A>`.
+fn byte_fallback(token: &[u8]) -> Option<u8> {
+    let hex = token.strip_prefix(b"<0x")?.strip_suffix(b">")?;
+    if hex.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+/// Returns the IDs of the rows of `embeddings`, a matrix with rows of `n_embd` values, whose
+/// L2 norm is at most `threshold`.
+///
+/// Such rows are usually tokens that were never seen in training, or padding added to round
+/// up the size of the vocabulary.
+pub fn unused_embedding_rows(embeddings: &[f32], n_embd: usize, threshold: f32) -> Vec<TokenId> {
+    embeddings
+        .chunks_exact(n_embd)
+        .enumerate()
+        .filter(|(_, row)| row.iter().map(|v| v * v).sum::<f32>().sqrt() <= threshold)
+        .map(|(id, _)| id as TokenId)
+        .collect()
+}
+
+/// The writing system of a token, as determined by its first letter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Script {
+    /// Latin letters, including accented letters.
+    Latin,
+    /// Cyrillic letters.
+    Cyrillic,
+    /// Greek letters.
+    Greek,
+    /// Arabic letters.
+    Arabic,
+    /// Hebrew letters.
+    Hebrew,
+    /// Devanagari letters.
+    Devanagari,
+    /// Thai letters.
+    Thai,
+    /// Chinese characters, which are also used in Japanese and Korean.
+    Han,
+    /// Japanese hiragana and katakana.
+    Kana,
+    /// Korean hangul.
+    Hangul,
+    /// Letters of other scripts.
+    OtherLetters,
+    /// Tokens without letters that contain digits.
+    Digits,
+    /// Tokens that only contain whitespace.
+    Whitespace,
+    /// Tokens without letters or digits, such as punctuation and symbols.
+    Symbols,
+    /// Tokens that are not valid UTF-8, such as single bytes of multi-byte characters.
+    Bytes,
+}
+impl Script {
+    /// Determines the script of `token`.
+    pub fn of(token: &[u8]) -> Self {
+        let Ok(text) = std::str::from_utf8(token) else {
+            return Script::Bytes;
+        };
+        if let Some(c) = text.chars().find(|c| c.is_alphabetic()) {
+            return Script::of_letter(c);
+        }
+        // SentencePiece marks spaces with `▁`.
+        if text.chars().all(|c| c.is_whitespace() || c == '▁') {
+            Script::Whitespace
+        } else if text.chars().any(|c| c.is_numeric()) {
+            Script::Digits
+        } else {
+            Script::Symbols
+        }
+    }
+
+    fn of_letter(c: char) -> Self {
+        match u32::from(c) {
+            0x0000..=0x024F | 0x1E00..=0x1EFF => Script::Latin,
+            0x0370..=0x03FF | 0x1F00..=0x1FFF => Script::Greek,
+            0x0400..=0x052F => Script::Cyrillic,
+            0x0590..=0x05FF => Script::Hebrew,
+            0x0600..=0x06FF | 0x0750..=0x077F => Script::Arabic,
+            0x0900..=0x097F => Script::Devanagari,
+            0x0E00..=0x0E7F => Script::Thai,
+            0x1100..=0x11FF | 0xAC00..=0xD7AF => Script::Hangul,
+            0x3040..=0x30FF => Script::Kana,
+            0x4E00..=0x9FFF | 0x3400..=0x4DBF | 0x20000..=0x2A6DF => Script::Han,
+            _ => Script::OtherLetters,
+        }
+    }
+}
+impl Display for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Script::Latin => "Latin",
+            Script::Cyrillic => "Cyrillic",
+            Script::Greek => "Greek",
+            Script::Arabic => "Arabic",
+            Script::Hebrew => "Hebrew",
+            Script::Devanagari => "Devanagari",
+            Script::Thai => "Thai",
+            Script::Han => "Han",
+            Script::Kana => "Kana",
+            Script::Hangul => "Hangul",
+            Script::OtherLetters => "other letters",
+            Script::Digits => "digits",
+            Script::Whitespace => "whitespace",
+            Script::Symbols => "symbols",
+            Script::Bytes => "partial UTF-8",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::EmbeddedTokenizer;
+
+    #[test]
+    fn vocab_stats() {
+        let mut tokenizer = EmbeddedTokenizer::default();
+        let pieces: [&[u8]; 8] = [
+            b"<unk>",
+            b"<0x0A>",
+            b"a",
+            b"hello",
+            b"\xD0\xB4",
+            b"a",
+            b"\xC3",
+            b"",
+        ];
+        for (id, piece) in pieces.into_iter().enumerate() {
+            tokenizer.push_token(id as TokenId, piece.to_vec(), 0.0);
+        }
+        let stats = VocabStats::new(&tokenizer.into(), 2);
+
+        assert_eq!(stats.size, 8);
+        assert_eq!(stats.missing_bytes.len(), 256 - 3);
+        assert!(!stats.missing_bytes.contains(&b'\n'));
+        assert_eq!(
+            stats.longest,
+            [(1, b"<0x0A>".to_vec()), (0, b"<unk>".to_vec())]
+        );
+        assert_eq!(stats.duplicates, [(b"a".to_vec(), vec![2, 5])]);
+        assert_eq!(stats.empty, [7]);
+        assert_eq!(
+            stats.scripts,
+            [
+                (Script::Latin, 5),
+                (Script::Cyrillic, 1),
+                (Script::Bytes, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn unused_rows_have_a_norm_of_about_zero() {
+        let embeddings = [1.0, 0.0, 0.0, 0.0, 0.0, 1e-5, 0.5, 0.5];
+        assert_eq!(unused_embedding_rows(&embeddings, 2, 1e-4), [1, 2]);
+    }
+}
@@ -0,0 +1,405 @@
+//! Output filters and tools implemented as WebAssembly modules, run in a sandbox.
+//!
+//! Unlike native plugins, a WASM plugin can only touch its own memory: it gets no access to
+//! files, the network, the clock or the environment, and each call is limited in time (as
+//! fuel, i.e. instructions) and memory by [WasmLimits]. A plugin that exceeds them or traps
+//! fails the call, not the process.
+//!
+//! A plugin is a core WASM module that exports its `memory` and
+//! `llm_alloc(len: i32) -> i32`, which returns a buffer of `len` bytes for the host to write
+//! the input of a call into. The only function it can import is
+//! `llm.output(ptr: i32, len: i32)`, which appends the UTF-8 text at `ptr` to the output of
+//! the call; the call traps if its output grows past [WasmLimits::memory_bytes]. Then:
+//!
+//! - A [WasmFilter], a [Guardrail] on the generated text, exports
+//!   `llm_filter(ptr: i32, len: i32, end: i32) -> i32`. It is called with the text to check
+//!   and whether generation has ended, as in [Guardrail::check]; its output is the text to
+//!   pass on. It returns the number of bytes at the end of the checked text to hold back,
+//!   or -1 to halt generation.
+//! - A [WasmTool], which the application calls when the model asks for it, exports
+//!   `llm_tool(ptr: i32, len: i32) -> i32`. It is called with the input of the tool, and
+//!   returns 0 on success, in which case its output is the result of the tool.
+use std::{fmt::Debug, path::Path, sync::Mutex};
+
+use thiserror::Error;
+use wasmtime::{
+    Caller, Config, Engine, Instance, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder, TypedFunc, WasmParams, WasmResults,
+};
+
+use crate::guardrail::{Guardrail, GuardrailVerdict};
+
+/// The resources a WASM plugin may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WasmLimits {
+    /// The fuel of each call, roughly the number of WASM instructions it may execute.
+    pub fuel: u64,
+    /// The most memory the plugin may have, in bytes. The output of each call is limited
+    /// to this size too.
+    pub memory_bytes: usize,
+}
+impl Default for WasmLimits {
+    fn default() -> Self {
+        Self {
+            fuel: 100_000_000,
+            memory_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// A [Guardrail] implemented by a WASM plugin. See the [module documentation](self).
+///
+/// If the plugin fails, generation is halted without passing on the text it was checking,
+/// so that a broken filter does not let through the text it should have filtered.
+pub struct WasmFilter {
+    plugin: Mutex<Plugin>,
+    filter: TypedFunc<(i32, i32, i32), i32>,
+}
+impl WasmFilter {
+    /// Compiles the filter in `wasm`, a WASM binary.
+    pub fn new(wasm: &[u8], limits: WasmLimits) -> Result<Self, WasmPluginError> {
+        let mut plugin = Plugin::new(wasm, limits)?;
+        let filter = plugin.typed_func("llm_filter")?;
+        Ok(Self {
+            plugin: Mutex::new(plugin),
+            filter,
+        })
+    }
+
+    /// Compiles the filter in the WASM binary at `path`.
+    pub fn load(path: &Path, limits: WasmLimits) -> Result<Self, WasmPluginError> {
+        Self::new(&read(path)?, limits)
+    }
+
+    fn try_check(&self, pending: &str, end: bool) -> Result<GuardrailVerdict, WasmPluginError> {
+        let mut plugin = self.plugin.lock().unwrap();
+        let (ptr, len) = plugin.write_input(pending)?;
+        let held = self
+            .filter
+            .call(&mut plugin.store, (ptr, len, end.into()))
+            .map_err(WasmPluginError::Trapped)?;
+        let text = plugin.take_output()?;
+        match held {
+            -1 => Ok(GuardrailVerdict::Halt { text }),
+            held if held >= 0 => Ok(GuardrailVerdict::Pass {
+                text,
+                held: held as usize,
+            }),
+            code => Err(WasmPluginError::Failed { code }),
+        }
+    }
+}
+impl Debug for WasmFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmFilter").finish_non_exhaustive()
+    }
+}
+impl Guardrail for WasmFilter {
+    fn check(&self, pending: &str, end: bool) -> GuardrailVerdict {
+        self.try_check(pending, end).unwrap_or_else(|err| {
+            log::error!("the WASM filter failed, halting generation: {err}");
+            GuardrailVerdict::Halt {
+                text: String::new(),
+            }
+        })
+    }
+}
+
+/// A tool implemented by a WASM plugin, e.g. a calculator that answers the tool calls of
+/// the model. See the [module documentation](self).
+pub struct WasmTool {
+    plugin: Mutex<Plugin>,
+    tool: TypedFunc<(i32, i32), i32>,
+}
+impl WasmTool {
+    /// Compiles the tool in `wasm`, a WASM binary.
+    pub fn new(wasm: &[u8], limits: WasmLimits) -> Result<Self, WasmPluginError> {
+        let mut plugin = Plugin::new(wasm, limits)?;
+        let tool = plugin.typed_func("llm_tool")?;
+        Ok(Self {
+            plugin: Mutex::new(plugin),
+            tool,
+        })
+    }
+
+    /// Compiles the tool in the WASM binary at `path`.
+    pub fn load(path: &Path, limits: WasmLimits) -> Result<Self, WasmPluginError> {
+        Self::new(&read(path)?, limits)
+    }
+
+    /// Runs the tool on `input`, returning its output.
+    pub fn call(&self, input: &str) -> Result<String, WasmPluginError> {
+        let mut plugin = self.plugin.lock().unwrap();
+        let (ptr, len) = plugin.write_input(input)?;
+        let code = self
+            .tool
+            .call(&mut plugin.store, (ptr, len))
+            .map_err(WasmPluginError::Trapped)?;
+        let output = plugin.take_output()?;
+        match code {
+            0 => Ok(output),
+            code => Err(WasmPluginError::Failed { code }),
+        }
+    }
+}
+impl Debug for WasmTool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmTool").finish_non_exhaustive()
+    }
+}
+
+/// Errors encountered when loading or running a WASM plugin.
+#[derive(Error, Debug)]
+pub enum WasmPluginError {
+    /// The plugin could not be read.
+    #[error("could not read the WASM plugin")]
+    Read(#[from] std::io::Error),
+    /// The plugin is not a valid WASM module, or does not implement the plugin interface,
+    /// e.g. because it imports functions other than `llm.output`.
+    #[error("the WASM plugin is invalid: {0:#}")]
+    Invalid(wasmtime::Error),
+    /// The plugin trapped, ran out of fuel or memory, or passed invalid memory to the host.
+    #[error("the WASM plugin trapped: {0:#}")]
+    Trapped(wasmtime::Error),
+    /// The plugin returned an error code.
+    #[error("the WASM plugin failed with code {code}")]
+    Failed {
+        /// The code it returned.
+        code: i32,
+    },
+    /// The output of the plugin is not valid UTF-8.
+    #[error("the output of the WASM plugin is not valid UTF-8")]
+    InvalidOutput,
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, WasmPluginError> {
+    Ok(std::fs::read(crate::util::long_path(path))?)
+}
+
+/// The state the host keeps for a plugin.
+struct HostState {
+    output: Vec<u8>,
+    /// The most bytes of output a call may have.
+    output_limit: usize,
+    limits: StoreLimits,
+}
+
+/// An instantiated plugin.
+struct Plugin {
+    store: Store<HostState>,
+    instance: Instance,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    fuel: u64,
+}
+impl Plugin {
+    fn new(wasm: &[u8], limits: WasmLimits) -> Result<Self, WasmPluginError> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(WasmPluginError::Invalid)?;
+        let module = Module::new(&engine, wasm).map_err(WasmPluginError::Invalid)?;
+
+        let mut store = Store::new(
+            &engine,
+            HostState {
+                output: vec![],
+                output_limit: limits.memory_bytes,
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(limits.memory_bytes)
+                    .instances(1)
+                    .build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+
+        // The only capability of a plugin.
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap(
+                "llm",
+                "output",
+                |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> wasmtime::Result<()> {
+                    let memory = caller
+                        .get_export("memory")
+                        .and_then(|export| export.into_memory())
+                        .ok_or_else(|| wasmtime::Error::msg("the plugin exports no memory"))?;
+                    let (data, state) = memory.data_and_store_mut(&mut caller);
+                    let bytes = (ptr as u32 as usize)
+                        .checked_add(len as u32 as usize)
+                        .and_then(|end| data.get(ptr as u32 as usize..end))
+                        .ok_or_else(|| wasmtime::Error::msg("output out of bounds"))?;
+                    if state.output.len() + bytes.len() > state.output_limit {
+                        return Err(wasmtime::Error::msg("the output exceeds the memory limit"));
+                    }
+                    state.output.extend_from_slice(bytes);
+                    Ok(())
+                },
+            )
+            .map_err(WasmPluginError::Invalid)?;
+
+        // Instantiation runs the start function, if any, which needs fuel too.
+        store
+            .set_fuel(limits.fuel)
+            .map_err(WasmPluginError::Invalid)?;
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .map_err(WasmPluginError::Invalid)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| WasmPluginError::Invalid(wasmtime::Error::msg("no `memory` export")))?;
+        let alloc = instance
+            .get_typed_func(&mut store, "llm_alloc")
+            .map_err(WasmPluginError::Invalid)?;
+        Ok(Self {
+            store,
+            instance,
+            memory,
+            alloc,
+            fuel: limits.fuel,
+        })
+    }
+
+    /// The exported function `name`.
+    fn typed_func<Params: WasmParams, Results: WasmResults>(
+        &mut self,
+        name: &str,
+    ) -> Result<TypedFunc<Params, Results>, WasmPluginError> {
+        self.instance
+            .get_typed_func(&mut self.store, name)
+            .map_err(WasmPluginError::Invalid)
+    }
+
+    /// Refuels the plugin and writes `input` to a buffer it allocates, returning the
+    /// pointer and length of the buffer.
+    fn write_input(&mut self, input: &str) -> Result<(i32, i32), WasmPluginError> {
+        self.store
+            .set_fuel(self.fuel)
+            .map_err(WasmPluginError::Trapped)?;
+        self.store.data_mut().output.clear();
+        let len = i32::try_from(input.len())
+            .map_err(|_| WasmPluginError::Trapped(wasmtime::Error::msg("the input is too long")))?;
+        let ptr = self
+            .alloc
+            .call(&mut self.store, len)
+            .map_err(WasmPluginError::Trapped)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, input.as_bytes())
+            .map_err(|err| WasmPluginError::Trapped(err.into()))?;
+        Ok((ptr, len))
+    }
+
+    /// Takes the output of the last call.
+    fn take_output(&mut self) -> Result<String, WasmPluginError> {
+        let output = std::mem::take(&mut self.store.data_mut().output);
+        String::from_utf8(output).map_err(|_| WasmPluginError::InvalidOutput)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A plugin whose filter and tool echo their input, unless it starts with `!`.
+    const ECHO: &str = r#"
+        (module
+          (import "llm" "output" (func $output (param i32 i32)))
+          (memory (export "memory") 1)
+          (func (export "llm_alloc") (param i32) (result i32) (i32.const 1024))
+          (func $rejected (param $ptr i32) (param $len i32) (result i32)
+            (if (result i32) (local.get $len)
+              (then (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 33)))
+              (else (i32.const 0))))
+          (func (export "llm_filter") (param $ptr i32) (param $len i32) (param $end i32) (result i32)
+            (if (call $rejected (local.get $ptr) (local.get $len))
+              (then (return (i32.const -1))))
+            (call $output (local.get $ptr) (local.get $len))
+            (i32.const 0))
+          (func (export "llm_tool") (param $ptr i32) (param $len i32) (result i32)
+            (if (call $rejected (local.get $ptr) (local.get $len))
+              (then (return (i32.const 3))))
+            (call $output (local.get $ptr) (local.get $len))
+            (i32.const 0)))
+    "#;
+
+    fn wasm(wat: &str) -> Vec<u8> {
+        wat::parse_str(wat).unwrap()
+    }
+
+    #[test]
+    fn filters_and_tools_run_in_the_plugin() {
+        let filter = WasmFilter::new(&wasm(ECHO), WasmLimits::default()).unwrap();
+        assert_eq!(
+            filter.check("hello", false),
+            GuardrailVerdict::Pass {
+                text: "hello".to_owned(),
+                held: 0
+            }
+        );
+        assert_eq!(
+            filter.check("!no", false),
+            GuardrailVerdict::Halt {
+                text: String::new()
+            }
+        );
+
+        let tool = WasmTool::new(&wasm(ECHO), WasmLimits::default()).unwrap();
+        assert_eq!(tool.call("2 + 2").unwrap(), "2 + 2");
+        assert!(matches!(
+            tool.call("!"),
+            Err(WasmPluginError::Failed { code: 3 })
+        ));
+    }
+
+    #[test]
+    fn plugins_are_limited() {
+        // Plugins cannot import anything but `llm.output`.
+        let wasi = r#"(module (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32))))"#;
+        assert!(matches!(
+            WasmTool::new(&wasm(wasi), WasmLimits::default()),
+            Err(WasmPluginError::Invalid(_))
+        ));
+
+        // A filter that never returns runs out of fuel, which halts generation.
+        let looping = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "llm_alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "llm_filter") (param i32 i32 i32) (result i32)
+                (loop (br 0))
+                (i32.const 0)))
+        "#;
+        let limits = WasmLimits {
+            fuel: 10_000,
+            ..Default::default()
+        };
+        let filter = WasmFilter::new(&wasm(looping), limits).unwrap();
+        assert_eq!(
+            filter.check("text", false),
+            GuardrailVerdict::Halt {
+                text: String::new()
+            }
+        );
+    }
+
+    #[test]
+    fn output_is_limited() {
+        // A tool that outputs its whole memory until it is stopped.
+        let flooding = r#"
+            (module
+              (import "llm" "output" (func $output (param i32 i32)))
+              (memory (export "memory") 1)
+              (func (export "llm_alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "llm_tool") (param i32 i32) (result i32)
+                (loop (call $output (i32.const 0) (i32.const 65536)) (br 0))
+                (i32.const 0)))
+        "#;
+        let limits = WasmLimits {
+            memory_bytes: 4 * 65536,
+            ..Default::default()
+        };
+        let tool = WasmTool::new(&wasm(flooding), limits).unwrap();
+        let err = tool.call("").unwrap_err();
+        assert!(matches!(err, WasmPluginError::Trapped(_)));
+        assert!(err.to_string().contains("exceeds the memory limit"));
+    }
+}
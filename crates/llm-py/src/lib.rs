@@ -0,0 +1,429 @@
+//! Python bindings for `llm`.
+//!
+//! This exposes a small API (`Model.load`, `Model.generate`, `Model.stream`, `Model.embed`)
+//! that runs directly on top of [llm::InferenceSession], so Python users get the same
+//! inference loop and quantized kernels as Rust users. The GIL is released for the
+//! duration of loading and inference.
+//!
+//! Build a wheel with [maturin](https://github.com/PyO3/maturin) from this directory:
+//!
+//! ```text
+//! maturin develop --release
+//! ```
+#![deny(missing_docs)]
+
+use std::{
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use llm::{
+    samplers::TopPTopK, InferenceFeedback, InferenceParameters, InferenceRequest,
+    InferenceResponse, ModelArchitecture, ModelParameters, OutputRequest, TokenizerSource,
+};
+use pyo3::{create_exception, exceptions::PyException, prelude::*};
+use rand::SeedableRng;
+
+create_exception!(
+    llm_py,
+    LlmError,
+    PyException,
+    "Raised when loading a model or running inference fails."
+);
+
+fn to_py_err(err: impl std::fmt::Display) -> PyErr {
+    LlmError::new_err(err.to_string())
+}
+
+/// Settings used for `Model.generate` and `Model.stream`.
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct GenerationConfig {
+    /// The maximum number of tokens to generate. `None` generates until the end of text.
+    #[pyo3(get, set)]
+    pub max_tokens: Option<usize>,
+    /// The temperature used for sampling.
+    #[pyo3(get, set)]
+    pub temperature: f32,
+    /// The top K words by score are kept during sampling.
+    #[pyo3(get, set)]
+    pub top_k: usize,
+    /// The cumulative probability after which no more words are kept for sampling.
+    #[pyo3(get, set)]
+    pub top_p: f32,
+    /// The penalty for repeating tokens.
+    #[pyo3(get, set)]
+    pub repeat_penalty: f32,
+    /// The number of previous tokens considered for the repeat penalty.
+    #[pyo3(get, set)]
+    pub repeat_last_n: usize,
+    /// Whether the repeat penalty applies to the tokens of the prompt.
+    #[pyo3(get, set)]
+    pub penalize_prompt: bool,
+    /// The number of tokens at the end of the prompt that the repeat penalty applies to.
+    /// `None` applies it to the whole prompt.
+    #[pyo3(get, set)]
+    pub repeat_prompt_window: Option<usize>,
+    /// The seed for the random number generator. `None` uses a random seed.
+    #[pyo3(get, set)]
+    pub seed: Option<u64>,
+    /// The number of threads to use. `None` uses the library default.
+    #[pyo3(get, set)]
+    pub threads: Option<usize>,
+}
+#[pymethods]
+impl GenerationConfig {
+    #[new]
+    #[pyo3(signature = (
+        max_tokens = None,
+        temperature = 0.80,
+        top_k = 40,
+        top_p = 0.95,
+        repeat_penalty = 1.30,
+        repeat_last_n = 512,
+        penalize_prompt = true,
+        repeat_prompt_window = None,
+        seed = None,
+        threads = None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        max_tokens: Option<usize>,
+        temperature: f32,
+        top_k: usize,
+        top_p: f32,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        penalize_prompt: bool,
+        repeat_prompt_window: Option<usize>,
+        seed: Option<u64>,
+        threads: Option<usize>,
+    ) -> Self {
+        Self {
+            max_tokens,
+            temperature,
+            top_k,
+            top_p,
+            repeat_penalty,
+            repeat_last_n,
+            penalize_prompt,
+            repeat_prompt_window,
+            seed,
+            threads,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{self:?}")
+    }
+}
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self::new(None, 0.80, 40, 0.95, 1.30, 512, true, None, None, None)
+    }
+}
+impl GenerationConfig {
+    fn inference_parameters(&self) -> InferenceParameters {
+        let defaults = InferenceParameters::default();
+        InferenceParameters {
+            n_threads: self.threads.map_or(defaults.n_threads, Into::into),
+            sampler: Arc::new(TopPTopK {
+                top_k: self.top_k,
+                top_p: self.top_p,
+                repeat_penalty: self.repeat_penalty,
+                temperature: self.temperature,
+                repetition_penalty_last_n: self.repeat_last_n,
+                penalize_prompt: self.penalize_prompt,
+                repetition_penalty_prompt_window: self.repeat_prompt_window,
+                ..Default::default()
+            }),
+            ..defaults
+        }
+    }
+
+    fn rng(&self) -> rand::rngs::StdRng {
+        match self.seed {
+            Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+            None => rand::rngs::StdRng::from_entropy(),
+        }
+    }
+}
+
+/// A loaded language model.
+#[pyclass(name = "Model")]
+pub struct PyModel {
+    model: Arc<dyn llm::Model>,
+}
+#[pymethods]
+impl PyModel {
+    /// Load a GGML model from `path`.
+    ///
+    /// `architecture` is one of the architectures supported by `llm` (e.g. `"llama"`).
+    /// If `tokenizer_path` is given, the Hugging Face tokenizer at that path is used
+    /// instead of the one embedded in the model.
+    #[staticmethod]
+    #[pyo3(signature = (
+        path,
+        architecture,
+        tokenizer_path = None,
+        context_size = 2048,
+        prefer_mmap = true,
+        use_gpu = false
+    ))]
+    fn load(
+        py: Python<'_>,
+        path: PathBuf,
+        architecture: &str,
+        tokenizer_path: Option<PathBuf>,
+        context_size: usize,
+        prefer_mmap: bool,
+        use_gpu: bool,
+    ) -> PyResult<Self> {
+        let architecture: ModelArchitecture = architecture.parse().map_err(to_py_err)?;
+        let tokenizer_source = match tokenizer_path {
+            Some(path) => TokenizerSource::HuggingFaceTokenizerFile(path),
+            None => TokenizerSource::Embedded,
+        };
+        let params = ModelParameters {
+            prefer_mmap,
+            context_size: context_size.into(),
+            use_gpu,
+            ..Default::default()
+        };
+
+        let model = py
+            .allow_threads(|| {
+                llm::load_dynamic(Some(architecture), &path, tokenizer_source, params, |_| {})
+            })
+            .map_err(to_py_err)?;
+
+        Ok(Self {
+            model: Arc::from(model),
+        })
+    }
+
+    /// Generate a completion for `prompt` and return it as a single string.
+    #[pyo3(signature = (prompt, config = None))]
+    fn generate(
+        &self,
+        py: Python<'_>,
+        prompt: &str,
+        config: Option<GenerationConfig>,
+    ) -> PyResult<String> {
+        let config = config.unwrap_or_default();
+        py.allow_threads(|| {
+            let mut output = String::new();
+            generate(self.model.as_ref(), prompt, &config, |token| {
+                output.push_str(&token);
+                true
+            })
+            .map(|_| output)
+        })
+        .map_err(to_py_err)
+    }
+
+    /// Generate a completion for `prompt`, returning an iterator that yields each token as
+    /// it is produced.
+    ///
+    /// Inference runs on a background thread. Dropping the iterator stops generation.
+    #[pyo3(signature = (prompt, config = None))]
+    fn stream(&self, prompt: String, config: Option<GenerationConfig>) -> TokenStream {
+        let config = config.unwrap_or_default();
+        let model = self.model.clone();
+        let (sender, receiver) = mpsc::sync_channel(16);
+
+        thread::spawn(move || {
+            let result = generate(model.as_ref(), &prompt, &config, |token| {
+                sender.send(Ok(token)).is_ok()
+            });
+            if let Err(err) = result {
+                let _ = sender.send(Err(err.to_string()));
+            }
+        });
+
+        TokenStream {
+            receiver: Mutex::new(receiver),
+        }
+    }
+
+    /// Compute the embeddings for `text`.
+    fn embed(&self, py: Python<'_>, text: &str) -> PyResult<Vec<f32>> {
+        py.allow_threads(|| {
+            let model = self.model.as_ref();
+            let tokens = model
+                .tokenizer()
+                .tokenize(text, true)
+                .map_err(to_py_err)?
+                .into_iter()
+                .map(|(_, id)| id)
+                .collect::<Vec<_>>();
+
+            let mut session = model.start_session(Default::default());
+            let mut output_request = OutputRequest {
+                all_logits: None,
+                embeddings: Some(Vec::new()),
+                logits_callback: None,
+            };
+            model.evaluate(
+                &mut session,
+                &InferenceParameters::default(),
+                &tokens,
+                &mut output_request,
+            );
+
+            Ok(output_request.embeddings.unwrap_or_default())
+        })
+    }
+
+    /// Tokenize `text`, returning the token IDs.
+    #[pyo3(signature = (text, bos = true))]
+    fn tokenize(&self, text: &str, bos: bool) -> PyResult<Vec<u32>> {
+        Ok(self
+            .model
+            .tokenizer()
+            .tokenize(text, bos)
+            .map_err(to_py_err)?
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect())
+    }
+
+    /// The context size of the model, in tokens.
+    #[getter]
+    fn context_size(&self) -> usize {
+        self.model.context_size()
+    }
+}
+
+/// An iterator over the tokens produced by `Model.stream`.
+#[pyclass]
+pub struct TokenStream {
+    receiver: Mutex<mpsc::Receiver<Result<String, String>>>,
+}
+#[pymethods]
+impl TokenStream {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<String>> {
+        let next = py.allow_threads(|| self.receiver.lock().unwrap().recv());
+        match next {
+            Ok(Ok(token)) => Ok(Some(token)),
+            Ok(Err(err)) => Err(LlmError::new_err(err)),
+            // The inference thread has finished.
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Runs inference for `prompt`, calling `on_token` for each inferred token.
+/// Generation stops early if `on_token` returns `false`.
+fn generate(
+    model: &dyn llm::Model,
+    prompt: &str,
+    config: &GenerationConfig,
+    mut on_token: impl FnMut(String) -> bool,
+) -> Result<(), llm::InferenceError> {
+    let parameters = config.inference_parameters();
+    let mut session = model.start_session(Default::default());
+    session
+        .infer::<std::convert::Infallible>(
+            model,
+            &mut config.rng(),
+            &InferenceRequest {
+                prompt: prompt.into(),
+                parameters: &parameters,
+                play_back_previous_tokens: false,
+                maximum_token_count: config.max_tokens,
+                maximum_output_bytes: None,
+                maximum_output_chars: None,
+                stop_token_sequences: &[],
+                guardrails: &[],
+                forced_prefix: None,
+                logprobs: None,
+                early_stop: Default::default(),
+                cancellation_token: None,
+                deadline: None,
+            },
+            &mut Default::default(),
+            |response| {
+                Ok(match response {
+                    InferenceResponse::InferredToken(token) => {
+                        if on_token(token) {
+                            InferenceFeedback::Continue
+                        } else {
+                            InferenceFeedback::Halt
+                        }
+                    }
+                    _ => InferenceFeedback::Continue,
+                })
+            },
+        )
+        .map(|_| ())
+}
+
+/// The `llm_py` Python module.
+#[pymodule]
+fn llm_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyModel>()?;
+    m.add_class::<GenerationConfig>()?;
+    m.add_class::<TokenStream>()?;
+    m.add("LlmError", m.py().get_type::<LlmError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use llm::testing::MockModel;
+
+    use super::*;
+
+    fn model() -> MockModel {
+        MockModel::new(&["Hello", ",", " world", "!"]).with_response(", world!")
+    }
+
+    #[test]
+    fn generate_streams_the_response() {
+        let mut tokens = vec![];
+        generate(&model(), "Hello", &GenerationConfig::default(), |token| {
+            tokens.push(token);
+            true
+        })
+        .unwrap();
+        assert_eq!(tokens, [",", " world", "!"]);
+    }
+
+    #[test]
+    fn generate_stops_when_asked() {
+        let mut tokens = vec![];
+        generate(&model(), "Hello", &GenerationConfig::default(), |token| {
+            tokens.push(token);
+            false
+        })
+        .unwrap();
+        assert_eq!(tokens, [","]);
+    }
+
+    #[test]
+    fn generate_stops_at_max_tokens() {
+        let config = GenerationConfig {
+            max_tokens: Some(2),
+            ..Default::default()
+        };
+        let mut output = String::new();
+        generate(&model(), "Hello", &config, |token| {
+            output.push_str(&token);
+            true
+        })
+        .unwrap();
+        assert_eq!(output, ", world");
+    }
+
+    #[test]
+    fn generate_reports_untokenizable_prompts() {
+        let result = generate(&model(), "Goodbye", &GenerationConfig::default(), |_| true);
+        assert!(result.is_err());
+    }
+}
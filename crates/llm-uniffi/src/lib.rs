@@ -0,0 +1,352 @@
+//! Swift and Kotlin bindings for `llm`, for use in iOS and Android applications.
+//!
+//! This exposes a reduced API through [UniFFI](https://mozilla.github.io/uniffi-rs/):
+//! loading a model, one-shot generation with streamed tokens, chat sessions, and embeddings.
+//!
+//! See the `README.md` in this crate for instructions on building for mobile targets and
+//! generating the foreign-language sources.
+#![deny(missing_docs)]
+
+use std::{
+    cell::Cell,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+use llm::{
+    samplers::TopPTopK, InferenceFeedback, InferenceParameters, InferenceRequest,
+    InferenceResponse, InferenceSession, ModelArchitecture, ModelParameters, OutputRequest,
+    TokenizerSource,
+};
+use rand::SeedableRng;
+
+uniffi::setup_scaffolding!();
+
+/// Errors that can be returned across the FFI boundary.
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+pub enum LlmError {
+    /// The model could not be loaded.
+    #[error("failed to load model: {message}")]
+    Load {
+        /// A description of the error.
+        message: String,
+    },
+    /// Inference failed.
+    #[error("inference failed: {message}")]
+    Inference {
+        /// A description of the error.
+        message: String,
+    },
+}
+impl LlmError {
+    fn load(err: impl std::fmt::Display) -> Self {
+        Self::Load {
+            message: err.to_string(),
+        }
+    }
+
+    fn inference(err: impl std::fmt::Display) -> Self {
+        Self::Inference {
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Settings used when loading a model.
+#[derive(uniffi::Record)]
+pub struct LoadOptions {
+    /// The architecture of the model (e.g. `"llama"`).
+    pub architecture: String,
+    /// The context size of the model, in tokens.
+    #[uniffi(default = 2048)]
+    pub context_size: u32,
+    /// Whether to memory-map the model.
+    #[uniffi(default = true)]
+    pub prefer_mmap: bool,
+    /// Whether to use GPU acceleration, where available.
+    #[uniffi(default = false)]
+    pub use_gpu: bool,
+}
+
+/// Settings used for generation.
+#[derive(Clone, uniffi::Record)]
+pub struct GenerationOptions {
+    /// The maximum number of tokens to generate. `None` generates until the end of text.
+    #[uniffi(default = None)]
+    pub max_tokens: Option<u32>,
+    /// The temperature used for sampling.
+    #[uniffi(default = 0.8)]
+    pub temperature: f32,
+    /// The top K words by score are kept during sampling.
+    #[uniffi(default = 40)]
+    pub top_k: u32,
+    /// The cumulative probability after which no more words are kept for sampling.
+    #[uniffi(default = 0.95)]
+    pub top_p: f32,
+    /// The penalty for repeating tokens.
+    #[uniffi(default = 1.3)]
+    pub repeat_penalty: f32,
+    /// The seed for the random number generator. `None` uses a random seed.
+    #[uniffi(default = None)]
+    pub seed: Option<u64>,
+    /// The number of threads to use.
+    #[uniffi(default = 4)]
+    pub threads: u32,
+}
+impl GenerationOptions {
+    fn inference_parameters(&self) -> InferenceParameters {
+        InferenceParameters {
+            n_threads: (self.threads as usize).into(),
+            sampler: Arc::new(TopPTopK {
+                top_k: self.top_k as usize,
+                top_p: self.top_p,
+                repeat_penalty: self.repeat_penalty,
+                temperature: self.temperature,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn rng(&self) -> rand::rngs::StdRng {
+        match self.seed {
+            Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+            None => rand::rngs::StdRng::from_entropy(),
+        }
+    }
+}
+
+/// Settings for a [Chat].
+#[derive(uniffi::Record)]
+pub struct ChatOptions {
+    /// Text fed to the model before the first message.
+    #[uniffi(default = "")]
+    pub system_prompt: String,
+    /// The prefix for each user message. This is also used as the stop sequence.
+    #[uniffi(default = "### Human: ")]
+    pub user_prefix: String,
+    /// The prefix for each assistant message.
+    #[uniffi(default = "### Assistant:")]
+    pub assistant_prefix: String,
+}
+
+/// Receives tokens as they are generated.
+#[uniffi::export(callback_interface)]
+pub trait TokenListener: Send + Sync {
+    /// Called with each generated piece of text. Return `false` to stop generation.
+    fn on_token(&self, token: String) -> bool;
+}
+
+/// A loaded language model.
+#[derive(uniffi::Object)]
+pub struct Model {
+    model: Arc<dyn llm::Model>,
+}
+#[uniffi::export]
+impl Model {
+    /// Load the model at `path`.
+    #[uniffi::constructor]
+    pub fn load(path: String, options: LoadOptions) -> Result<Arc<Self>, LlmError> {
+        let architecture: ModelArchitecture =
+            options.architecture.parse().map_err(LlmError::load)?;
+        let params = ModelParameters {
+            prefer_mmap: options.prefer_mmap,
+            context_size: (options.context_size as usize).into(),
+            use_gpu: options.use_gpu,
+            ..Default::default()
+        };
+
+        let model = llm::load_dynamic(
+            Some(architecture),
+            path.as_ref(),
+            TokenizerSource::Embedded,
+            params,
+            |_| {},
+        )
+        .map_err(LlmError::load)?;
+
+        Ok(Arc::new(Self {
+            model: Arc::from(model),
+        }))
+    }
+
+    /// Generate a completion for `prompt`.
+    pub fn generate(&self, prompt: String, options: GenerationOptions) -> Result<String, LlmError> {
+        let mut output = String::new();
+        let mut session = self.model.start_session(Default::default());
+        infer(self.model.as_ref(), &mut session, &prompt, &options, |t| {
+            output.push_str(&t);
+            true
+        })?;
+        Ok(output)
+    }
+
+    /// Generate a completion for `prompt`, passing each token to `listener` as it is generated.
+    pub fn stream(
+        &self,
+        prompt: String,
+        options: GenerationOptions,
+        listener: Box<dyn TokenListener>,
+    ) -> Result<(), LlmError> {
+        let mut session = self.model.start_session(Default::default());
+        infer(self.model.as_ref(), &mut session, &prompt, &options, |t| {
+            listener.on_token(t)
+        })
+    }
+
+    /// Compute the embeddings for `text`.
+    pub fn embed(&self, text: String) -> Result<Vec<f32>, LlmError> {
+        let tokens = self
+            .model
+            .tokenizer()
+            .tokenize(&text, true)
+            .map_err(LlmError::inference)?
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect::<Vec<_>>();
+
+        let mut session = self.model.start_session(Default::default());
+        let mut output_request = OutputRequest {
+            all_logits: None,
+            embeddings: Some(Vec::new()),
+            logits_callback: None,
+        };
+        self.model.evaluate(
+            &mut session,
+            &InferenceParameters::default(),
+            &tokens,
+            &mut output_request,
+        );
+
+        Ok(output_request.embeddings.unwrap_or_default())
+    }
+
+    /// Start a new chat with this model.
+    pub fn start_chat(self: Arc<Self>, options: ChatOptions) -> Arc<Chat> {
+        let session = self.model.start_session(Default::default());
+        Arc::new(Chat {
+            model: self,
+            options,
+            state: Mutex::new(ChatState {
+                session,
+                fed_system_prompt: false,
+            }),
+        })
+    }
+}
+
+/// A multi-turn conversation with a [Model]. The conversation history is kept
+/// in the model's context window.
+#[derive(uniffi::Object)]
+pub struct Chat {
+    model: Arc<Model>,
+    options: ChatOptions,
+    state: Mutex<ChatState>,
+}
+struct ChatState {
+    session: InferenceSession,
+    fed_system_prompt: bool,
+}
+#[uniffi::export]
+impl Chat {
+    /// Send `message` and stream the reply to `listener`. Returns the full reply.
+    pub fn send(
+        &self,
+        message: String,
+        options: GenerationOptions,
+        listener: Box<dyn TokenListener>,
+    ) -> Result<String, LlmError> {
+        let mut state = self.state.lock().unwrap();
+        let ChatOptions {
+            system_prompt,
+            user_prefix,
+            assistant_prefix,
+        } = &self.options;
+
+        let mut prompt = String::new();
+        if !state.fed_system_prompt {
+            prompt.push_str(system_prompt);
+            state.fed_system_prompt = true;
+        }
+        prompt.push_str(&format!("{user_prefix}{message}\n{assistant_prefix}"));
+
+        let mut reply = String::new();
+        let stopped = Cell::new(false);
+        let mut callback = llm::conversation_inference_callback::<Infallible>(user_prefix, |t| {
+            reply.push_str(&t);
+            if !listener.on_token(t) {
+                stopped.set(true);
+            }
+        });
+
+        let model = self.model.model.as_ref();
+        infer_with_callback(model, &mut state.session, &prompt, &options, |r| {
+            let feedback = callback(r)?;
+            Ok(if stopped.get() {
+                InferenceFeedback::Halt
+            } else {
+                feedback
+            })
+        })?;
+        drop(callback);
+
+        Ok(reply)
+    }
+}
+
+/// Runs inference for `prompt`, calling `on_token` for each inferred token.
+/// Generation stops early if `on_token` returns `false`.
+fn infer(
+    model: &dyn llm::Model,
+    session: &mut InferenceSession,
+    prompt: &str,
+    options: &GenerationOptions,
+    mut on_token: impl FnMut(String) -> bool,
+) -> Result<(), LlmError> {
+    infer_with_callback(model, session, prompt, options, |response| {
+        Ok(match response {
+            InferenceResponse::InferredToken(token) => {
+                if on_token(token) {
+                    InferenceFeedback::Continue
+                } else {
+                    InferenceFeedback::Halt
+                }
+            }
+            _ => InferenceFeedback::Continue,
+        })
+    })
+}
+
+fn infer_with_callback(
+    model: &dyn llm::Model,
+    session: &mut InferenceSession,
+    prompt: &str,
+    options: &GenerationOptions,
+    callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, Infallible>,
+) -> Result<(), LlmError> {
+    let parameters = options.inference_parameters();
+    session
+        .infer(
+            model,
+            &mut options.rng(),
+            &InferenceRequest {
+                prompt: prompt.into(),
+                parameters: &parameters,
+                play_back_previous_tokens: false,
+                maximum_token_count: options.max_tokens.map(|n| n as usize),
+                maximum_output_bytes: None,
+                maximum_output_chars: None,
+                stop_token_sequences: &[],
+                guardrails: &[],
+                forced_prefix: None,
+                logprobs: None,
+                early_stop: Default::default(),
+                cancellation_token: None,
+                deadline: None,
+            },
+            &mut Default::default(),
+            callback,
+        )
+        .map(|_| ())
+        .map_err(LlmError::inference)
+}
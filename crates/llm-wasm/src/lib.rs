@@ -0,0 +1,206 @@
+//! WebAssembly bindings for `llm`, for running small models in the browser.
+//!
+//! The bindings are plain functions over the WebAssembly C ABI, so that they can be called
+//! from JavaScript without generated glue code. Strings and model files are passed as
+//! UTF-8 or raw bytes in the module's memory, in buffers allocated with [llm_alloc].
+//! Generated tokens are streamed to the `on_token` function that the host provides in the
+//! `llm` import module.
+//!
+//! See the `README.md` in this crate for instructions on building the module and an example
+//! of its use.
+#![deny(missing_docs)]
+
+use std::{cell::RefCell, convert::Infallible, io::Cursor, ptr, slice};
+
+use llm::{
+    InferenceFeedback, InferenceRequest, InferenceResponse, ModelArchitecture, ModelParameters,
+    TokenizerSource,
+};
+use rand::SeedableRng;
+
+#[link(wasm_import_module = "llm")]
+extern "C" {
+    /// Receives each generated piece of text, as `len` bytes of UTF-8 at `ptr`, which are
+    /// only valid during the call. Returns zero to stop generation.
+    fn on_token(ptr: *const u8, len: usize) -> u32;
+}
+
+thread_local! {
+    /// The message of the last error, read with [llm_last_error_ptr] and
+    /// [llm_last_error_len].
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+fn set_last_error(err: impl std::fmt::Display) {
+    LAST_ERROR.with(|e| *e.borrow_mut() = err.to_string());
+}
+
+/// Allocates a zeroed buffer of `len` bytes for the host to write into.
+///
+/// Buffers are freed with [llm_free], except for model files passed to [llm_load], which
+/// takes ownership of them.
+#[no_mangle]
+pub extern "C" fn llm_alloc(len: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; len].into_boxed_slice()).cast()
+}
+
+/// Frees a buffer of `len` bytes allocated with [llm_alloc].
+///
+/// # Safety
+///
+/// `ptr` must have been returned by [llm_alloc] with the same `len`, and not freed since.
+#[no_mangle]
+pub unsafe extern "C" fn llm_free(ptr: *mut u8, len: usize) {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len)));
+}
+
+/// Loads a model of `architecture` (e.g. `"llama"`) from the model file in the buffer at
+/// `model_ptr`, which must have been allocated with [llm_alloc] and is freed by this
+/// function. The model uses the tokenizer embedded in the file.
+///
+/// Returns a handle to the model, to be freed with [llm_free_model], or null if it could not
+/// be loaded; see [llm_last_error_ptr].
+///
+/// # Safety
+///
+/// `architecture_ptr` must point to `architecture_len` bytes, and `model_ptr` must have
+/// been returned by [llm_alloc] with `model_len`.
+#[no_mangle]
+pub unsafe extern "C" fn llm_load(
+    architecture_ptr: *const u8,
+    architecture_len: usize,
+    model_ptr: *mut u8,
+    model_len: usize,
+    context_size: u32,
+) -> *mut Box<dyn llm::Model> {
+    let model_file = Box::from_raw(ptr::slice_from_raw_parts_mut(model_ptr, model_len));
+    let architecture = slice::from_raw_parts(architecture_ptr, architecture_len);
+
+    let architecture = std::str::from_utf8(architecture)
+        .map_err(|err| err.to_string())
+        .and_then(|a| {
+            a.parse::<ModelArchitecture>()
+                .map_err(|err| err.to_string())
+        });
+    let architecture = match architecture {
+        Ok(architecture) => architecture,
+        Err(err) => {
+            set_last_error(err);
+            return ptr::null_mut();
+        }
+    };
+    let params = ModelParameters {
+        context_size: (context_size as usize).into(),
+        ..Default::default()
+    };
+
+    match llm::load_dynamic_from_reader(
+        Some(architecture),
+        Cursor::new(model_file),
+        TokenizerSource::Embedded,
+        params,
+        |_| {},
+    ) {
+        Ok(model) => Box::into_raw(Box::new(model)),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Frees a model returned by [llm_load].
+///
+/// # Safety
+///
+/// `model` must have been returned by [llm_load], and not freed since.
+#[no_mangle]
+pub unsafe extern "C" fn llm_free_model(model: *mut Box<dyn llm::Model>) {
+    drop(Box::from_raw(model));
+}
+
+/// Generates a completion for the UTF-8 `prompt` with a new session, passing each piece of
+/// text to the host's `on_token` as it is generated.
+///
+/// At most `max_tokens` tokens are generated, or until the end of text if it is zero. The
+/// same `seed` generates the same completion.
+///
+/// Returns zero on success, and a negative number if inference failed; see
+/// [llm_last_error_ptr].
+///
+/// # Safety
+///
+/// `model` must have been returned by [llm_load], and `prompt_ptr` must point to
+/// `prompt_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn llm_infer(
+    model: *const Box<dyn llm::Model>,
+    prompt_ptr: *const u8,
+    prompt_len: usize,
+    max_tokens: u32,
+    seed: u64,
+) -> i32 {
+    let model = (*model).as_ref();
+    let prompt = match std::str::from_utf8(slice::from_raw_parts(prompt_ptr, prompt_len)) {
+        Ok(prompt) => prompt,
+        Err(err) => {
+            set_last_error(err);
+            return -1;
+        }
+    };
+
+    let mut session = model.start_session(Default::default());
+    let result = session.infer::<Infallible>(
+        model,
+        &mut rand::rngs::StdRng::seed_from_u64(seed),
+        &InferenceRequest {
+            prompt: prompt.into(),
+            parameters: &Default::default(),
+            play_back_previous_tokens: false,
+            maximum_token_count: (max_tokens != 0).then_some(max_tokens as usize),
+            maximum_output_bytes: None,
+            maximum_output_chars: None,
+            stop_token_sequences: &[],
+            guardrails: &[],
+            forced_prefix: None,
+            logprobs: None,
+            early_stop: Default::default(),
+            cancellation_token: None,
+            deadline: None,
+        },
+        &mut Default::default(),
+        |response| {
+            Ok(match response {
+                InferenceResponse::InferredToken(token) => {
+                    if on_token(token.as_ptr(), token.len()) != 0 {
+                        InferenceFeedback::Continue
+                    } else {
+                        InferenceFeedback::Halt
+                    }
+                }
+                _ => InferenceFeedback::Continue,
+            })
+        },
+    );
+
+    match result {
+        Ok(_) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Returns a pointer to the UTF-8 message of the last error. It is valid until the next
+/// call that fails.
+#[no_mangle]
+pub extern "C" fn llm_last_error_ptr() -> *const u8 {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/// Returns the length of the message of the last error, in bytes.
+#[no_mangle]
+pub extern "C" fn llm_last_error_len() -> usize {
+    LAST_ERROR.with(|e| e.borrow().len())
+}
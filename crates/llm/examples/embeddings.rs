@@ -116,6 +116,7 @@ fn get_embeddings(
     let mut output_request = llm::OutputRequest {
         all_logits: None,
         embeddings: Some(Vec::new()),
+        logits_callback: None,
     };
     let vocab = model.tokenizer();
     let beginning_of_sentence = true;
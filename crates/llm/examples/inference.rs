@@ -64,6 +64,15 @@ fn main() {
             parameters: &llm::InferenceParameters::default(),
             play_back_previous_tokens: false,
             maximum_token_count: None,
+            maximum_output_bytes: None,
+            maximum_output_chars: None,
+            stop_token_sequences: &[],
+            guardrails: &[],
+            forced_prefix: None,
+            logprobs: None,
+            early_stop: Default::default(),
+            cancellation_token: None,
+            deadline: None,
         },
         // OutputRequest
         &mut Default::default(),
@@ -95,6 +95,15 @@ fn main() {
                             parameters: &inference_parameters,
                             play_back_previous_tokens: false,
                             maximum_token_count: None,
+                            maximum_output_bytes: None,
+                            maximum_output_chars: None,
+                            stop_token_sequences: &[],
+                            guardrails: &[],
+                            forced_prefix: None,
+                            logprobs: None,
+                            early_stop: Default::default(),
+                            cancellation_token: None,
+                            deadline: None,
                         },
                         &mut Default::default(),
                         conversation_inference_callback(&format!("{character_name}:"), print_token),
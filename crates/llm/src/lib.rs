@@ -45,6 +45,15 @@
 //!         parameters: &llm::InferenceParameters::default(),
 //!         play_back_previous_tokens: false,
 //!         maximum_token_count: None,
+//!         maximum_output_bytes: None,
+//!         maximum_output_chars: None,
+//!         stop_token_sequences: &[],
+//!         guardrails: &[],
+//!         forced_prefix: None,
+//!         logprobs: None,
+//!         early_stop: Default::default(),
+//!         cancellation_token: None,
+//!         deadline: None,
 //!     },
 //!     // llm::OutputRequest
 //!     &mut Default::default(),
@@ -70,22 +79,46 @@
 use std::{
     error::Error,
     fmt::{Debug, Display},
+    io::{Read, Seek, Write},
     path::Path,
     str::FromStr,
 };
 
 // Try not to expose too many GGML details here.
 // This is the "user-facing" API, and GGML may not always be our backend.
+#[cfg(feature = "attention-stats")]
+pub use llm_base::attention_stats;
+#[cfg(feature = "sampler-plugins")]
+pub use llm_base::sampler_plugin;
+#[cfg(feature = "testing")]
+pub use llm_base::testing;
+#[cfg(feature = "wasm-plugins")]
+pub use llm_base::wasm_plugin;
+#[cfg(not(target_arch = "wasm32"))]
+pub use llm_base::{cache, runtime};
 pub use llm_base::{
-    conversation_inference_callback, feed_prompt_callback, ggml::format as ggml_format, load,
-    load_progress_callback_stdout, quantize, samplers, ElementType, FileType, FileTypeFormat,
+    cancellation, compatibility, constraint, conversation_inference_callback, convert, diagnostics,
+    feed_prompt_callback, ggml::format as ggml_format, guardrail, json, judge, load,
+    load_from_reader, load_progress_callback_stdout, long_path, memory, migrate, model_sha256,
+    pipelines, placement, quantize, quantize_dry_run, samplers, template, text, vocab,
+    ArchitectureInfo, CancellationToken, Choice, ChooseError, ContainerType, ContextOverflowPolicy,
+    ContextSize, EarlyStop, ElementType, EmbeddingTensors, ErrorCode, FileType, FileTypeFormat,
     FormatMagic, Hyperparameters, InferenceError, InferenceFeedback, InferenceParameters,
     InferenceRequest, InferenceResponse, InferenceSession, InferenceSessionConfig,
     InferenceSnapshot, InferenceSnapshotRef, InferenceStats, InvalidTokenBias, KnownModel,
-    LoadError, LoadProgress, Loader, Model, ModelKVMemoryType, ModelParameters, OutputRequest,
-    Prompt, QuantizeError, QuantizeProgress, RewindError, Sampler, SnapshotError, TokenBias,
-    TokenId, TokenUtf8Buffer, TokenizationError, Tokenizer, TokenizerSource,
+    KvEviction, KvLayout, LoadError, LoadProgress, LoadStage, Loader, LogitsCallback,
+    LogitsProcessor, MigrateProgress, Model, ModelKVMemoryType, ModelParameters, OutputRequest,
+    Prompt, QuantizationHistogram, QuantizeError, QuantizeProgress, QuantizeReport, ResourceUsage,
+    RewindError, RngState, Sampler, SamplerHandle, SamplerState, SequenceError, SequenceId,
+    SessionLora, SessionLoraError, SnapshotError, SpeculationError, SpillError, StopReason,
+    TensorQuantizeStats, ThreadCount, TokenBias, TokenId, TokenLogprobs, TokenUtf8Buffer,
+    TokenizationError, Tokenizer, TokenizerSource, END_TOKENS, READER_PATH,
 };
+#[cfg(feature = "graph-dump")]
+pub use llm_base::{GraphDump, GraphDumpFormat};
+
+#[cfg(feature = "hf-hub")]
+pub use llm_base::hf_hub;
 
 use serde::Serialize;
 
@@ -120,6 +153,18 @@ macro_rules! define_models {
         }
 
         impl ModelArchitecture {
+            /// Returns the architecture of `model`, or `None` if it is not one of the
+            /// architectures supported by this crate.
+            pub fn of(model: &dyn Model) -> Option<Self> {
+                $(
+                    #[cfg(feature = $model_lowercase_str)]
+                    if model.is::<models::$model_pascalcase>() {
+                        return Some(Self::$model_pascalcase);
+                    }
+                )*
+                None
+            }
+
             /// Use a visitor to dispatch some code based on the model architecture.
             pub fn visit<R>(&self, visitor: &mut impl ModelArchitectureVisitor<R>) -> R {
                 match self {
@@ -257,6 +302,268 @@ pub fn load_dynamic(
     })
 }
 
+/// A helper function that loads the specified model from `reader` using an architecture
+/// specified at runtime.
+///
+/// A wrapper around [load_from_reader] that dispatches to the correct model.
+pub fn load_dynamic_from_reader<R: Read + Seek>(
+    architecture: Option<ModelArchitecture>,
+    reader: R,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError> {
+    let architecture = architecture.ok_or_else(|| LoadError::MissingModelArchitecture {
+        path: READER_PATH.into(),
+    })?;
+
+    struct LoadVisitor<R: Read + Seek, F: FnMut(LoadProgress)> {
+        // Taken by the only call to `visit`.
+        reader: Option<R>,
+        tokenizer_source: TokenizerSource,
+        params: ModelParameters,
+        load_progress_callback: F,
+    }
+    impl<R: Read + Seek, F: FnMut(LoadProgress)>
+        ModelArchitectureVisitor<Result<Box<dyn Model>, LoadError>> for LoadVisitor<R, F>
+    {
+        fn visit<M: KnownModel + 'static>(&mut self) -> Result<Box<dyn Model>, LoadError> {
+            let reader = self.reader.take().expect("the model is only loaded once");
+            Ok(Box::new(load_from_reader::<M, _>(
+                reader,
+                self.tokenizer_source.clone(),
+                self.params.clone(),
+                &mut self.load_progress_callback,
+            )?))
+        }
+    }
+
+    architecture.visit(&mut LoadVisitor {
+        reader: Some(reader),
+        tokenizer_source,
+        params,
+        load_progress_callback,
+    })
+}
+
+/// What [check_compatibility] found out about a model file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompatibilityReport {
+    /// The container format and version of the file, if it could be read.
+    pub container_type: Option<ContainerType>,
+    /// The architecture of the model, if it was recognized.
+    pub architecture: Option<ModelArchitecture>,
+    /// The file type recorded in the hyperparameters, if the architecture has one.
+    pub file_type: Option<FileType>,
+    /// The quantization version of the file, if it could be read.
+    pub quantization_version: Option<u32>,
+    /// The number of tensors of each type.
+    pub element_types: Vec<(ElementType, usize)>,
+    /// The number of tensors in the file.
+    pub tensor_count: usize,
+    /// Everything that would prevent the file from loading. Each issue's [Display]
+    /// implementation describes what can be done about it.
+    pub issues: Vec<compatibility::CompatibilityIssue>,
+    /// What would make loading the file slower, if it is compatible.
+    pub notes: Vec<compatibility::CompatibilityNote>,
+}
+impl CompatibilityReport {
+    /// Whether the file can be loaded by this build, with [Self::architecture].
+    pub fn is_compatible(&self) -> bool {
+        self.architecture.is_some() && self.issues.is_empty()
+    }
+}
+
+/// Checks whether the model file at `path` can be loaded by this build without loading
+/// it, by reading only its metadata. If `path` is a shard of a sharded model, all of the
+/// shards are checked.
+///
+/// The architecture of the model is recognized among the supported architectures, so the
+/// report can also be used to pick the architecture to pass to [load_dynamic].
+/// Only failing to open the file is an error: everything else is reported as an issue.
+pub fn check_compatibility(path: &Path) -> Result<CompatibilityReport, LoadError> {
+    use compatibility::{probe_shards, CompatibilityIssue, ProbedModelFile};
+
+    let shards = llm_base::util::shard_paths(path)
+        .unwrap_or_else(|| vec![path.to_owned()])
+        .into_iter()
+        .map(|path| {
+            if !llm_base::long_path(&path).exists() {
+                return Err(LoadError::FileDoesNotExist { path });
+            }
+            match std::fs::File::open(llm_base::long_path(&path)) {
+                Ok(file) => Ok(std::io::BufReader::new(file)),
+                Err(source) => Err(LoadError::OpenFileFailed { source, path }),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    struct ProbeVisitor<R: std::io::BufRead + Seek>(Vec<R>);
+    impl<R: std::io::BufRead + Seek>
+        ModelArchitectureVisitor<Result<ProbedModelFile, CompatibilityIssue>> for ProbeVisitor<R>
+    {
+        fn visit<M: KnownModel + 'static>(
+            &mut self,
+        ) -> Result<ProbedModelFile, CompatibilityIssue> {
+            probe_shards::<M, _>(&mut self.0)
+        }
+    }
+
+    let mut visitor = ProbeVisitor(shards);
+    let mut report = CompatibilityReport {
+        container_type: None,
+        architecture: None,
+        file_type: None,
+        quantization_version: None,
+        element_types: vec![],
+        tensor_count: 0,
+        issues: vec![],
+        notes: vec![],
+    };
+    // Probing the file as the wrong architecture usually fails, so the reasons for which
+    // it failed are only reported if no architecture could read it.
+    let mut failures = vec![];
+    for architecture in ModelArchitecture::ALL {
+        match architecture.visit(&mut visitor) {
+            Ok(probed) if probed.recognized => {
+                return Ok(CompatibilityReport {
+                    container_type: Some(probed.container_type),
+                    architecture: Some(*architecture),
+                    file_type: probed.file_type,
+                    quantization_version: Some(probed.quantization_version),
+                    element_types: probed.element_types,
+                    tensor_count: probed.tensor_count,
+                    issues: probed.issues,
+                    notes: probed.notes,
+                });
+            }
+            Ok(probed) => {
+                if report.container_type.is_none() {
+                    report.container_type = Some(probed.container_type);
+                    report.quantization_version = Some(probed.quantization_version);
+                    report.element_types = probed.element_types;
+                    report.tensor_count = probed.tensor_count;
+                }
+            }
+            Err(issue) if issue.is_format_issue() => {
+                if let CompatibilityIssue::UnsupportedContainerVersion { container_type } = issue {
+                    report.container_type = Some(container_type);
+                }
+                report.issues.push(issue);
+                return Ok(report);
+            }
+            Err(issue) => failures.push(issue),
+        }
+    }
+
+    let issue = if let Some(i) = failures
+        .iter()
+        .position(|i| matches!(i, CompatibilityIssue::Truncated))
+    {
+        // Any part of the file may be missing, including the tensors that would have been
+        // recognized.
+        Some(failures.swap_remove(i))
+    } else if report.container_type.is_some() {
+        // The file could be read, but not recognized.
+        None
+    } else {
+        // A tensor type is only read once the hyperparameters and vocabulary have been, so
+        // an unsupported one is a real issue, while an unsupported file type may have been
+        // read from the wrong field.
+        let position = |f: fn(&CompatibilityIssue) -> bool| failures.iter().position(f);
+        position(|i| matches!(i, CompatibilityIssue::UnsupportedElementType { .. }))
+            .or_else(|| position(|i| matches!(i, CompatibilityIssue::Unreadable { .. })))
+            .or_else(|| (!failures.is_empty()).then_some(0))
+            .map(|i| failures.swap_remove(i))
+    };
+    report
+        .issues
+        .push(issue.unwrap_or(CompatibilityIssue::UnknownArchitecture));
+    Ok(report)
+}
+
+/// Rewrites the model file at `src` to `dst` in the current format, upgrading tensors in the
+/// layouts of older quantization versions. See [migrate] for what is kept.
+///
+/// The architecture of the model is recognized with [check_compatibility]. Models in legacy
+/// layouts can also be loaded directly, but are upgraded every time they are loaded and
+/// cannot be memory mapped.
+pub fn migrate_model(src: &Path, dst: &Path) -> Result<(), QuantizeError> {
+    let report = check_compatibility(src)?;
+    let architecture = report
+        .architecture
+        .ok_or_else(|| LoadError::MissingModelArchitecture {
+            path: src.to_owned(),
+        })?;
+    if dst.exists() && dst.canonicalize()? == src.canonicalize()? {
+        return Err(QuantizeError::InvariantBroken {
+            path: dst.to_owned(),
+            invariant: "the model must be migrated to a different file".to_owned(),
+        });
+    }
+
+    struct MigrateVisitor<'a> {
+        src: &'a Path,
+        dst: &'a Path,
+    }
+    impl ModelArchitectureVisitor<Result<(), QuantizeError>> for MigrateVisitor<'_> {
+        fn visit<M: KnownModel + 'static>(&mut self) -> Result<(), QuantizeError> {
+            let file = std::fs::File::open(llm_base::long_path(self.src)).map_err(|source| {
+                LoadError::OpenFileFailed {
+                    source,
+                    path: self.src.to_owned(),
+                }
+            })?;
+            let mut reader = std::io::BufReader::new(file);
+            let file = std::fs::File::create(llm_base::long_path(self.dst)).map_err(|source| {
+                QuantizeError::CreateFileFailed {
+                    source,
+                    path: self.dst.to_owned(),
+                }
+            })?;
+            let mut writer = std::io::BufWriter::new(file);
+            migrate::<M, _, _>(&mut reader, &mut writer, |_| {})?;
+            writer.flush()?;
+            Ok(())
+        }
+    }
+
+    architecture.visit(&mut MigrateVisitor { src, dst })
+}
+
+/// Downloads a model file from the Hugging Face Hub into `cache_dir` (by default,
+/// [hf_hub::default_cache_dir]), unless it is already there, and loads it with [load_dynamic].
+///
+/// Interrupted downloads are resumed by the next call. `download_progress_callback` is
+/// called as the file downloads, and `load_progress_callback` as it loads.
+#[cfg(feature = "hf-hub")]
+pub fn load_from_hf(
+    architecture: Option<ModelArchitecture>,
+    file: &hf_hub::HfFile,
+    cache_dir: Option<&Path>,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    download_progress_callback: impl FnMut(hf_hub::DownloadProgress),
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, hf_hub::HfLoadError> {
+    let default_cache_dir;
+    let cache_dir = match cache_dir {
+        Some(cache_dir) => cache_dir,
+        None => {
+            default_cache_dir = hf_hub::default_cache_dir();
+            &default_cache_dir
+        }
+    };
+    let path = hf_hub::download(file, cache_dir, download_progress_callback)?;
+    Ok(load_dynamic(
+        architecture,
+        &path,
+        tokenizer_source,
+        params,
+        load_progress_callback,
+    )?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
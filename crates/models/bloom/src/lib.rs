@@ -2,11 +2,13 @@
 //! for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::sync::Arc;
+use std::{collections::HashMap, path::Path, sync::Arc};
 
 use llm_base::{
+    diagnostics::Diagnostics,
     ggml,
-    model::{common, HyperparametersWriteError},
+    memory::MemoryLimitExceeded,
+    model::{common, ArchitectureInfo, EmbeddingTensors, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceParameters, InferenceSession, InferenceSessionConfig,
     KnownModel, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
 };
@@ -18,6 +20,8 @@ use llm_base::{
 pub struct Bloom {
     // the context size ("memory") the model should use when evaluating a prompt
     context_size: usize,
+    // receives notable events, such as the weights outliving the model
+    diagnostics: Diagnostics,
 
     hyperparameters: Hyperparameters,
     tokenizer: Tokenizer,
@@ -31,18 +35,26 @@ pub struct Bloom {
     // output normalization weight & bias
     output_norm: ggml::Tensor,
     output_norm_bias: ggml::Tensor,
-    // output weight
-    output: ggml::Tensor,
+    // output weight, if not tied to `wte`
+    output: Option<ggml::Tensor>,
 
     // weights for the model
     layers: Vec<Layer>,
 
+    // the weights by their name in the model file, used to apply session LoRA adapters
+    tensors: HashMap<String, ggml::Tensor>,
+
     // must be kept alive for the model
     context: Arc<ggml::Context>,
 }
 
 unsafe impl Send for Bloom {}
 unsafe impl Sync for Bloom {}
+impl Drop for Bloom {
+    fn drop(&mut self) {
+        common::check_weights_released(&self.context, &self.diagnostics);
+    }
+}
 
 impl KnownModel for Bloom {
     type Hyperparameters = Hyperparameters;
@@ -61,7 +73,7 @@ impl KnownModel for Bloom {
         let norm_bias = tl.load("norm.bias")?;
         let output_norm = tl.load("output_norm.weight")?;
         let output_norm_bias = tl.load("output_norm.bias")?;
-        let output = tl.load("output.weight")?;
+        let output = common::load_output(&mut tl, Self::embedding_tensors(), &params.diagnostics)?;
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
@@ -89,13 +101,16 @@ impl KnownModel for Bloom {
             layers.push(layer);
         }
 
-        let (context, _) = tl.finish();
+        let (context, tensors) = tl.finish();
 
-        let ModelParameters { context_size, .. } = params;
+        let context_size =
+            common::context_size(&params, hyperparameters.n_layer, hyperparameters.n_embd);
+        let ModelParameters { diagnostics, .. } = params;
 
         Ok(Bloom {
             hyperparameters,
             context_size,
+            diagnostics,
             tokenizer,
             wte,
             norm,
@@ -104,12 +119,16 @@ impl KnownModel for Bloom {
             output_norm_bias,
             output,
             layers,
+            tensors,
             context: Arc::new(context),
         })
     }
 
-    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
-        InferenceSession::new(
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, MemoryLimitExceeded> {
+        InferenceSession::try_new(
             config,
             self.context_size,
             self.hyperparameters.n_layer,
@@ -127,8 +146,8 @@ impl KnownModel for Bloom {
     ) {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
-        let num_threads = params.n_threads;
-        let ctx_size = self.context_size;
+        let num_threads = session.thread_count(params, input_tokens.len());
+        let ctx_size = session.context_size();
 
         let Hyperparameters {
             n_vocab,
@@ -139,7 +158,7 @@ impl KnownModel for Bloom {
             file_type: _,
         } = self.hyperparameters;
 
-        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+        let outputs = session.compute(self.context.clone(), input_tokens, |mut builder| {
             let ctx0 = builder.ctx0;
             let (memory_k_size, memory_v_size) = (
                 builder.memory_k.element_size(),
@@ -172,7 +191,7 @@ impl KnownModel for Bloom {
                 );
 
                 //attention
-                current = ctx0.op_mul_mat(&self.layers[il].query_key_value, &current);
+                current = builder.mul_mat(&self.layers[il].query_key_value, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].query_key_value_b, &current),
                     &current,
@@ -297,7 +316,7 @@ impl KnownModel for Bloom {
                 );
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                current = builder.mul_mat(&self.layers[il].wo, &current);
                 current = ctx0.op_add(&ctx0.op_repeat(&self.layers[il].wo_b, &current), &current);
 
                 let input_feed_forward = ctx0.op_add(&current, &input_self_attention);
@@ -317,7 +336,7 @@ impl KnownModel for Bloom {
                     &current,
                 );
 
-                current = ctx0.op_mul_mat(&self.layers[il].w1, &current);
+                current = builder.mul_mat(&self.layers[il].w1, &current);
 
                 current = ctx0.op_add(&ctx0.op_repeat(&self.layers[il].w1_b, &current), &current);
 
@@ -325,7 +344,7 @@ impl KnownModel for Bloom {
 
                 current = ctx0.op_gelu(&current);
 
-                current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
+                current = builder.mul_mat(&self.layers[il].w2, &current);
 
                 current = ctx0.op_add(&ctx0.op_repeat(&self.layers[il].w2_b, &current), &current);
 
@@ -333,6 +352,8 @@ impl KnownModel for Bloom {
 
                 // input for next layer
                 input_layer = current;
+
+                input_layer = builder.record_layer_output(&input_layer);
             }
 
             // norm
@@ -352,7 +373,7 @@ impl KnownModel for Bloom {
             let embeddings_tensor: ggml::Tensor = input_layer.share();
 
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
+            input_layer = builder.mul_mat(self.output.as_ref().unwrap_or(&self.wte), &input_layer);
 
             (
                 gf,
@@ -389,6 +410,35 @@ impl KnownModel for Bloom {
         self.tokenizer.id("</s>".as_bytes()).unwrap()
     }
 
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
+    }
+
+    fn tensor(&self, name: &str) -> Option<&ggml::Tensor> {
+        self.tensors.get(name)
+    }
+
+    fn tensors(&self) -> Vec<(&str, &ggml::Tensor)> {
+        self.tensors.iter().map(|(n, t)| (n.as_str(), t)).collect()
+    }
+
+    fn architecture_info(&self) -> ArchitectureInfo {
+        let hp = &self.hyperparameters;
+        ArchitectureInfo {
+            n_layer: hp.n_layer,
+            n_head: hp.n_head,
+            n_embd: hp.n_embd,
+            n_ctx_train: None,
+            vocab_size: hp.n_vocab,
+            quantization: Some(hp.file_type),
+            tied_embeddings: self.output.is_none(),
+        }
+    }
+
+    fn weights_context(&self) -> Option<&Arc<ggml::Context>> {
+        Some(&self.context)
+    }
+
     fn quantize_tensors() -> Vec<Regex> {
         vec![Regex::new(".*weight").unwrap()]
     }
@@ -397,6 +447,17 @@ impl KnownModel for Bloom {
         vec![]
     }
 
+    fn embedding_tensors() -> EmbeddingTensors {
+        EmbeddingTensors {
+            input: "tok_embeddings.weight",
+            output: Some("output.weight"),
+        }
+    }
+
+    fn distinctive_tensors() -> Vec<&'static str> {
+        vec!["tok_embeddings.weight", "norm.bias"]
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -452,6 +513,10 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn validate(&self, path: &Path) -> Result<(), llm_base::LoadError> {
+        llm_base::validate_attention(path, self.n_embd, self.n_head, None)
+    }
 }
 
 struct Layer {
@@ -7,14 +7,16 @@
 //! supported. It is currently only available as a preview.
 #![deny(missing_docs)]
 
-use std::sync::Arc;
+use std::{collections::HashMap, path::Path, sync::Arc};
 
 use ggml::Tensor;
 use llm_base::{
+    diagnostics::Diagnostics,
     ggml,
-    model::{common, HyperparametersWriteError},
+    memory::MemoryLimitExceeded,
+    model::{common, ArchitectureInfo, EmbeddingTensors, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceParameters, InferenceSession, InferenceSessionConfig,
-    KnownModel, LoadError, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    KnownModel, KvLayout, LoadError, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
 };
 
 /// The Falcon model. Ref: [Technology Innovation Institute](https://huggingface.co/tiiuae)
@@ -24,6 +26,8 @@ use llm_base::{
 pub struct Falcon {
     // the context size ("memory") the model should use when evaluating a prompt
     context_size: usize,
+    // receives notable events, such as the weights outliving the model
+    diagnostics: Diagnostics,
 
     hyperparameters: Hyperparameters,
 
@@ -34,17 +38,26 @@ pub struct Falcon {
     tok_embeddings: Tensor,
     output_norm: Tensor,
     output_norm_b: Tensor,
-    lm_head: Tensor,
+    // language model head, if not tied to `tok_embeddings`
+    lm_head: Option<Tensor>,
 
     // weights for the model
     layers: Vec<Layer>,
 
+    // the weights by their name in the model file, used to apply session LoRA adapters
+    tensors: HashMap<String, ggml::Tensor>,
+
     // must be kept alive for the model
     context: Arc<ggml::Context>,
 }
 
 unsafe impl Send for Falcon {}
 unsafe impl Sync for Falcon {}
+impl Drop for Falcon {
+    fn drop(&mut self) {
+        common::check_weights_released(&self.context, &self.diagnostics);
+    }
+}
 
 impl KnownModel for Falcon {
     type Hyperparameters = Hyperparameters;
@@ -61,7 +74,7 @@ impl KnownModel for Falcon {
         let tok_embeddings = tl.load("transformer.word_embeddings.weight")?;
         let output_norm = tl.load("transformer.ln_f.weight")?;
         let output_norm_b = tl.load("transformer.ln_f.bias")?;
-        let lm_head = tl.load("lm_head.weight")?;
+        let lm_head = common::load_output(&mut tl, Self::embedding_tensors(), &params.diagnostics)?;
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
@@ -81,25 +94,32 @@ impl KnownModel for Falcon {
             layers.push(layer);
         }
 
-        let (context, _) = tl.finish();
+        let (context, tensors) = tl.finish();
 
-        let ModelParameters { context_size, .. } = params;
+        let context_size =
+            common::context_size(&params, hyperparameters.n_layer, hyperparameters.n_embd);
+        let ModelParameters { diagnostics, .. } = params;
 
         Ok(Falcon {
             hyperparameters,
             context_size,
+            diagnostics,
             tokenizer,
             tok_embeddings,
             output_norm,
             output_norm_b,
             lm_head,
             layers,
+            tensors,
             context: Arc::new(context),
         })
     }
 
-    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
-        InferenceSession::new(
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, MemoryLimitExceeded> {
+        InferenceSession::try_new(
             config,
             self.context_size,
             self.hyperparameters.n_layer,
@@ -117,8 +137,9 @@ impl KnownModel for Falcon {
     ) {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
-        let num_threads = params.n_threads;
-        let ctx_size = self.context_size;
+        let position = session.position();
+        let num_threads = session.thread_count(params, input_tokens.len());
+        let ctx_size = session.context_size();
 
         let Hyperparameters {
             n_embd,
@@ -172,7 +193,7 @@ impl KnownModel for Falcon {
                 layernorm_output = current.share();
 
                 // compute QKV
-                current = ctx0.op_mul_mat(&self.layers[il].query_key_value, &current);
+                current = builder.mul_mat(&self.layers[il].query_key_value, &current);
 
                 let fused_qkv_row_nb = (n_embd + 2 * (n_embd / n_head)) * f32_size;
 
@@ -198,8 +219,8 @@ impl KnownModel for Falcon {
                 );
 
                 // using mode = 2 for neox mode
-                qcur = ctx0.op_rope_inplace(&qcur, session_len, head_dim, 2);
-                kcur = ctx0.op_rope_inplace(&kcur, session_len, head_dim, 2);
+                qcur = ctx0.op_rope_inplace(&qcur, position, head_dim, 2);
+                kcur = ctx0.op_rope_inplace(&kcur, position, head_dim, 2);
 
                 // store key and value to memory
 
@@ -246,6 +267,7 @@ impl KnownModel for Falcon {
                 let big_kq_masked = ctx0.op_diag_mask_inf_inplace(&big_kq_scaled, session_len);
 
                 let big_kq_softmax = ctx0.op_soft_max_inplace(&big_kq_masked);
+                builder.record_attention(&mut gf, &big_kq_softmax);
 
                 let mut bigv = ctx0.op_permute(
                     &ctx0.op_reshape_3d(
@@ -274,7 +296,7 @@ impl KnownModel for Falcon {
                 );
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                current = builder.mul_mat(&self.layers[il].wo, &current);
 
                 // feed forward uses second scratch buffer
                 builder.use_scratch(Some(1));
@@ -283,14 +305,16 @@ impl KnownModel for Falcon {
                 let attn_out =
                     ctx0.op_cpy(&current, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n));
 
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_up, &inp_ff);
+                current = builder.mul_mat(&self.layers[il].ffn_up, &inp_ff);
                 current = ctx0.op_gelu(&current);
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_down, &current);
+                current = builder.mul_mat(&self.layers[il].ffn_down, &current);
 
                 current = ctx0.op_add(&current, &attn_out);
                 current = ctx0.op_add(&current, &input_layer);
 
                 input_layer = current.share();
+
+                input_layer = builder.record_layer_output(&input_layer);
             }
 
             builder.use_scratch(Some(0));
@@ -311,7 +335,10 @@ impl KnownModel for Falcon {
             builder.use_scratch(None);
 
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.lm_head, &input_layer);
+            input_layer = builder.mul_mat(
+                self.lm_head.as_ref().unwrap_or(&self.tok_embeddings),
+                &input_layer,
+            );
 
             (
                 gf,
@@ -348,6 +375,43 @@ impl KnownModel for Falcon {
         self.tokenizer.id("<|endoftext|>".as_bytes()).unwrap()
     }
 
+    fn kv_layout(&self) -> Option<KvLayout> {
+        // Falcon shares one key and one value head between all its heads.
+        Some(KvLayout {
+            width: self.hyperparameters.n_embd / self.hyperparameters.n_head,
+            transposed_values: false,
+        })
+    }
+
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
+    }
+
+    fn tensor(&self, name: &str) -> Option<&ggml::Tensor> {
+        self.tensors.get(name)
+    }
+
+    fn tensors(&self) -> Vec<(&str, &ggml::Tensor)> {
+        self.tensors.iter().map(|(n, t)| (n.as_str(), t)).collect()
+    }
+
+    fn architecture_info(&self) -> ArchitectureInfo {
+        let hp = &self.hyperparameters;
+        ArchitectureInfo {
+            n_layer: hp.n_layer,
+            n_head: hp.n_head,
+            n_embd: hp.n_embd,
+            n_ctx_train: None,
+            vocab_size: hp.n_vocab,
+            quantization: Some(hp.file_type),
+            tied_embeddings: self.lm_head.is_none(),
+        }
+    }
+
+    fn weights_context(&self) -> Option<&Arc<ggml::Context>> {
+        Some(&self.context)
+    }
+
     fn quantize_tensors() -> Vec<Regex> {
         vec![Regex::new(".*weight").unwrap()]
     }
@@ -355,6 +419,17 @@ impl KnownModel for Falcon {
     fn skip_quantize_tensors() -> Vec<Regex> {
         vec![]
     }
+
+    fn embedding_tensors() -> EmbeddingTensors {
+        EmbeddingTensors {
+            input: "transformer.word_embeddings.weight",
+            output: Some("lm_head.weight"),
+        }
+    }
+
+    fn distinctive_tensors() -> Vec<&'static str> {
+        vec!["transformer.word_embeddings.weight"]
+    }
 }
 
 /// Falcon [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -405,6 +480,10 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn validate(&self, path: &Path) -> Result<(), LoadError> {
+        llm_base::validate_attention(path, self.n_embd, self.n_head, None)
+    }
 }
 
 struct Layer {
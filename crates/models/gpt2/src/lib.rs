@@ -1,12 +1,14 @@
 //! An implementation of [GPT-2](https://huggingface.co/docs/transformers/model_doc/gpt2) for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::sync::Arc;
+use std::{collections::HashMap, path::Path, sync::Arc};
 
 use ggml::Tensor;
 use llm_base::{
+    diagnostics::Diagnostics,
     ggml,
-    model::{common, HyperparametersWriteError},
+    memory::MemoryLimitExceeded,
+    model::{common, ArchitectureInfo, EmbeddingTensors, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceParameters, InferenceSession, InferenceSessionConfig,
     KnownModel, LoadError, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
 };
@@ -18,6 +20,8 @@ use llm_base::{
 pub struct Gpt2 {
     // the context size ("memory") the model should use when evaluating a prompt
     context_size: usize,
+    // receives notable events, such as the weights outliving the model
+    diagnostics: Diagnostics,
 
     hyperparameters: Hyperparameters,
     tokenizer: Tokenizer,
@@ -38,12 +42,20 @@ pub struct Gpt2 {
     // weights for the model
     layers: Vec<Layer>,
 
+    // the weights by their name in the model file, used to apply session LoRA adapters
+    tensors: HashMap<String, ggml::Tensor>,
+
     // must be kept alive for the model
     context: Arc<ggml::Context>,
 }
 
 unsafe impl Send for Gpt2 {}
 unsafe impl Sync for Gpt2 {}
+impl Drop for Gpt2 {
+    fn drop(&mut self) {
+        common::check_weights_released(&self.context, &self.diagnostics);
+    }
+}
 
 impl KnownModel for Gpt2 {
     type Hyperparameters = Hyperparameters;
@@ -64,7 +76,7 @@ impl KnownModel for Gpt2 {
 
         // GPT-2's language model head is optional; if it is not present,
         // the `wte` tensor is used instead.
-        let lm_head = tl.load("model/lm_head").ok();
+        let lm_head = common::load_output(&mut tl, Self::embedding_tensors(), &params.diagnostics)?;
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
@@ -86,13 +98,16 @@ impl KnownModel for Gpt2 {
             layers.push(layer);
         }
 
-        let (context, _) = tl.finish();
+        let (context, tensors) = tl.finish();
 
-        let ModelParameters { context_size, .. } = params;
+        let context_size =
+            common::context_size(&params, hyperparameters.n_layer, hyperparameters.n_embd);
+        let ModelParameters { diagnostics, .. } = params;
 
         Ok(Gpt2 {
             hyperparameters,
             context_size,
+            diagnostics,
             tokenizer,
             layers,
             ln_f_g,
@@ -100,12 +115,16 @@ impl KnownModel for Gpt2 {
             wte,
             wpe,
             lm_head,
+            tensors,
             context: Arc::new(context),
         })
     }
 
-    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
-        InferenceSession::new(
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, MemoryLimitExceeded> {
+        InferenceSession::try_new(
             config,
             self.context_size,
             self.hyperparameters.n_layer,
@@ -123,8 +142,8 @@ impl KnownModel for Gpt2 {
     ) {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
-        let num_threads = params.n_threads;
-        let ctx_size = self.context_size;
+        let num_threads = session.thread_count(params, input_tokens.len());
+        let ctx_size = session.context_size();
 
         let Hyperparameters {
             n_embd,
@@ -164,7 +183,7 @@ impl KnownModel for Gpt2 {
                 );
 
                 // attn
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_attn_w, &current);
+                current = builder.mul_mat(&self.layers[il].c_attn_attn_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_attn_attn_b, &current),
                     &current,
@@ -256,7 +275,7 @@ impl KnownModel for Gpt2 {
                 );
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+                current = builder.mul_mat(&self.layers[il].c_attn_proj_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_attn_proj_b, &current),
                     &current,
@@ -278,7 +297,7 @@ impl KnownModel for Gpt2 {
                 );
 
                 // feed-forward fully connected
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_fc_w, &current);
+                current = builder.mul_mat(&self.layers[il].c_mlp_fc_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_mlp_fc_b, &current),
                     &current,
@@ -288,7 +307,7 @@ impl KnownModel for Gpt2 {
                 current = ctx0.op_gelu(&current);
 
                 // feed-forward projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &current);
+                current = builder.mul_mat(&self.layers[il].c_mlp_proj_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_mlp_proj_b, &current),
                     &current,
@@ -296,6 +315,8 @@ impl KnownModel for Gpt2 {
 
                 // input for next layer
                 input_layer = ctx0.op_add(&current, &ff_in);
+
+                input_layer = builder.record_layer_output(&input_layer);
             }
 
             builder.use_scratch(Some(0));
@@ -312,7 +333,7 @@ impl KnownModel for Gpt2 {
             let embeddings_tensor: ggml::Tensor = input_layer.share();
 
             let head = self.lm_head.as_ref().unwrap_or(&self.wte);
-            input_layer = ctx0.op_mul_mat(head, &input_layer);
+            input_layer = builder.mul_mat(head, &input_layer);
 
             (
                 gf,
@@ -349,6 +370,35 @@ impl KnownModel for Gpt2 {
         self.tokenizer.id("<|endoftext|>".as_bytes()).unwrap()
     }
 
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
+    }
+
+    fn tensor(&self, name: &str) -> Option<&ggml::Tensor> {
+        self.tensors.get(name)
+    }
+
+    fn tensors(&self) -> Vec<(&str, &ggml::Tensor)> {
+        self.tensors.iter().map(|(n, t)| (n.as_str(), t)).collect()
+    }
+
+    fn architecture_info(&self) -> ArchitectureInfo {
+        let hp = &self.hyperparameters;
+        ArchitectureInfo {
+            n_layer: hp.n_layer,
+            n_head: hp.n_head,
+            n_embd: hp.n_embd,
+            n_ctx_train: Some(hp.n_ctx),
+            vocab_size: hp.n_vocab,
+            quantization: Some(hp.file_type),
+            tied_embeddings: self.lm_head.is_none(),
+        }
+    }
+
+    fn weights_context(&self) -> Option<&Arc<ggml::Context>> {
+        Some(&self.context)
+    }
+
     fn quantize_tensors() -> Vec<Regex> {
         [
             "model/wte",
@@ -366,6 +416,17 @@ impl KnownModel for Gpt2 {
     fn skip_quantize_tensors() -> Vec<Regex> {
         vec![]
     }
+
+    fn embedding_tensors() -> EmbeddingTensors {
+        EmbeddingTensors {
+            input: "model/wte",
+            output: Some("model/lm_head"),
+        }
+    }
+
+    fn distinctive_tensors() -> Vec<&'static str> {
+        vec!["model/wte"]
+    }
 }
 
 /// GPT-2 [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -433,6 +494,10 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn validate(&self, path: &Path) -> Result<(), LoadError> {
+        llm_base::validate_attention(path, self.n_embd, self.n_head, None)
+    }
 }
 
 struct Layer {
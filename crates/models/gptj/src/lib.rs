@@ -1,14 +1,17 @@
 //! An implementation of [GPT-J](https://huggingface.co/docs/transformers/model_doc/gptj) for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::{error::Error, sync::Arc};
+use std::{collections::HashMap, error::Error, path::Path, sync::Arc};
 
 use ggml::Tensor;
 use llm_base::{
+    diagnostics::Diagnostics,
     ggml,
-    model::{common, HyperparametersWriteError},
+    memory::MemoryLimitExceeded,
+    model::{common, ArchitectureInfo, EmbeddingTensors, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceParameters, InferenceSession, InferenceSessionConfig,
-    KnownModel, LoadError, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    KnownModel, KvLayout, LoadError, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId,
+    Tokenizer,
 };
 
 /// The GPT-J model. Ref: [GitHub](https://github.com/kingoflolz/mesh-transformer-jax/#gpt-j-6b)
@@ -18,6 +21,8 @@ use llm_base::{
 pub struct GptJ {
     // the context size ("memory") the model should use when evaluating a prompt
     context_size: usize,
+    // receives notable events, such as the weights outliving the model
+    diagnostics: Diagnostics,
 
     hyperparameters: Hyperparameters,
     tokenizer: Tokenizer,
@@ -28,19 +33,27 @@ pub struct GptJ {
     ln_f_b: Tensor,
     // weighted token embeddings
     wte: Tensor,
-    // language model head gain & bias
-    lmh_g: Tensor,
-    lmh_b: Tensor,
+    // language model head gain & bias, if not tied to `wte`
+    lmh_g: Option<Tensor>,
+    lmh_b: Option<Tensor>,
 
     // weights for the model
     layers: Vec<Layer>,
 
+    // the weights by their name in the model file, used to apply session LoRA adapters
+    tensors: HashMap<String, ggml::Tensor>,
+
     // must be kept alive for the model
     context: Arc<ggml::Context>,
 }
 
 unsafe impl Send for GptJ {}
 unsafe impl Sync for GptJ {}
+impl Drop for GptJ {
+    fn drop(&mut self) {
+        common::check_weights_released(&self.context, &self.diagnostics);
+    }
+}
 
 impl KnownModel for GptJ {
     type Hyperparameters = Hyperparameters;
@@ -60,8 +73,12 @@ impl KnownModel for GptJ {
         let wte = tl.load("transformer.wte.weight")?;
         let ln_f_g = tl.load("transformer.ln_f.weight")?;
         let ln_f_b = tl.load("transformer.ln_f.bias")?;
-        let lmh_g = tl.load("lm_head.weight")?;
-        let lmh_b = tl.load("lm_head.bias")?;
+        let lmh_g = common::load_output(&mut tl, Self::embedding_tensors(), &params.diagnostics)?;
+        let lmh_b = if tl.contains("lm_head.bias") {
+            Some(tl.load("lm_head.bias")?)
+        } else {
+            None
+        };
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
@@ -81,13 +98,16 @@ impl KnownModel for GptJ {
             layers.push(layer);
         }
 
-        let (context, _) = tl.finish();
+        let (context, tensors) = tl.finish();
 
-        let ModelParameters { context_size, .. } = params;
+        let context_size =
+            common::context_size(&params, hyperparameters.n_layer, hyperparameters.n_embd);
+        let ModelParameters { diagnostics, .. } = params;
 
         Ok(GptJ {
             hyperparameters,
             context_size,
+            diagnostics,
             tokenizer,
             ln_f_g,
             ln_f_b,
@@ -95,12 +115,16 @@ impl KnownModel for GptJ {
             lmh_g,
             lmh_b,
             layers,
+            tensors,
             context: Arc::new(context),
         })
     }
 
-    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
-        InferenceSession::new(
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, MemoryLimitExceeded> {
+        InferenceSession::try_new(
             config,
             self.context_size,
             self.hyperparameters.n_layer,
@@ -118,8 +142,9 @@ impl KnownModel for GptJ {
     ) {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
-        let num_threads = params.n_threads;
-        let ctx_size = self.context_size;
+        let position = session.position();
+        let num_threads = session.thread_count(params, input_tokens.len());
+        let ctx_size = session.context_size();
 
         let Hyperparameters {
             n_embd,
@@ -130,7 +155,7 @@ impl KnownModel for GptJ {
             ..
         } = self.hyperparameters;
 
-        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+        let outputs = session.compute(self.context.clone(), input_tokens, |mut builder| {
             let ctx0 = builder.ctx0;
             let (memory_k_size, memory_v_size) = (
                 builder.memory_k.element_size(),
@@ -154,30 +179,30 @@ impl KnownModel for GptJ {
                 // self-attention
                 let qcur = ctx0.op_rope_inplace(
                     &ctx0.op_reshape_3d(
-                        &ctx0.op_mul_mat(&self.layers[il].c_attn_q_proj_w, &current),
+                        &builder.mul_mat(&self.layers[il].c_attn_q_proj_w, &current),
                         n_embd / n_head,
                         n_head,
                         input_len,
                     ),
-                    session_len,
+                    position,
                     n_rot,
                     0,
                 );
                 let kcur = ctx0.op_rope_inplace(
                     &ctx0.op_reshape_3d(
-                        &ctx0.op_mul_mat(&self.layers[il].c_attn_k_proj_w, &current),
+                        &builder.mul_mat(&self.layers[il].c_attn_k_proj_w, &current),
                         n_embd / n_head,
                         n_head,
                         input_len,
                     ),
-                    session_len,
+                    position,
                     n_rot,
                     0,
                 );
 
                 // self-attention store key and value to memory
                 let vcur =
-                    ctx0.op_transpose(&ctx0.op_mul_mat(&self.layers[il].c_attn_v_proj_w, &current));
+                    ctx0.op_transpose(&builder.mul_mat(&self.layers[il].c_attn_v_proj_w, &current));
 
                 let k = ctx0.op_view_1d(
                     builder.memory_k,
@@ -217,6 +242,7 @@ impl KnownModel for GptJ {
 
                 let kq_masked = ctx0.op_diag_mask_inf_inplace(&kq_scaled, session_len);
                 let kq_softmax = ctx0.op_soft_max_inplace(&kq_masked);
+                builder.record_attention(&mut gf, &kq_softmax);
 
                 let big_v = ctx0.op_view_3d(
                     builder.memory_v,
@@ -237,12 +263,12 @@ impl KnownModel for GptJ {
                 );
 
                 // self-attention projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+                current = builder.mul_mat(&self.layers[il].c_attn_proj_w, &current);
 
                 // feed-forward
                 let ff_in = current.share();
 
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_fc_w, &input_sa);
+                current = builder.mul_mat(&self.layers[il].c_mlp_fc_w, &input_sa);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_mlp_fc_b, &current),
                     &current,
@@ -251,7 +277,7 @@ impl KnownModel for GptJ {
                 current = ctx0.op_gelu(&current);
 
                 // feed-forward projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &current);
+                current = builder.mul_mat(&self.layers[il].c_mlp_proj_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_mlp_proj_b, &current),
                     &current,
@@ -261,6 +287,8 @@ impl KnownModel for GptJ {
 
                 // input for next layer
                 input_layer = ctx0.op_add(&current, &input_layer);
+
+                input_layer = builder.record_layer_output(&input_layer);
             }
 
             // norm
@@ -273,8 +301,10 @@ impl KnownModel for GptJ {
             let embeddings_tensor: ggml::Tensor = input_layer.share();
 
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
-            input_layer = ctx0.op_add(&ctx0.op_repeat(&self.lmh_b, &input_layer), &input_layer);
+            input_layer = builder.mul_mat(self.lmh_g.as_ref().unwrap_or(&self.wte), &input_layer);
+            if let Some(lmh_b) = &self.lmh_b {
+                input_layer = ctx0.op_add(&ctx0.op_repeat(lmh_b, &input_layer), &input_layer);
+            }
 
             (
                 gf,
@@ -311,6 +341,35 @@ impl KnownModel for GptJ {
         self.tokenizer.id("<|endoftext|>".as_bytes()).unwrap()
     }
 
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
+    }
+
+    fn tensor(&self, name: &str) -> Option<&ggml::Tensor> {
+        self.tensors.get(name)
+    }
+
+    fn tensors(&self) -> Vec<(&str, &ggml::Tensor)> {
+        self.tensors.iter().map(|(n, t)| (n.as_str(), t)).collect()
+    }
+
+    fn architecture_info(&self) -> ArchitectureInfo {
+        let hp = &self.hyperparameters;
+        ArchitectureInfo {
+            n_layer: hp.n_layer,
+            n_head: hp.n_head,
+            n_embd: hp.n_embd,
+            n_ctx_train: Some(hp.n_ctx),
+            vocab_size: hp.n_vocab,
+            quantization: Some(hp.file_type),
+            tied_embeddings: self.lmh_g.is_none(),
+        }
+    }
+
+    fn weights_context(&self) -> Option<&Arc<ggml::Context>> {
+        Some(&self.context)
+    }
+
     fn quantize_tensors() -> Vec<Regex> {
         vec![Regex::new(".*weight").unwrap()]
     }
@@ -319,9 +378,27 @@ impl KnownModel for GptJ {
         vec![]
     }
 
+    fn embedding_tensors() -> EmbeddingTensors {
+        EmbeddingTensors {
+            input: "transformer.wte.weight",
+            output: Some("lm_head.weight"),
+        }
+    }
+
+    fn distinctive_tensors() -> Vec<&'static str> {
+        vec!["transformer.wte.weight", "lm_head.bias"]
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn kv_layout(&self) -> Option<KvLayout> {
+        Some(KvLayout {
+            width: self.hyperparameters.n_embd,
+            transposed_values: true,
+        })
+    }
 }
 
 /// GPT-J [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -392,6 +469,10 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn validate(&self, path: &Path) -> Result<(), LoadError> {
+        llm_base::validate_attention(path, self.n_embd, self.n_head, Some(self.n_rot))
+    }
 }
 
 struct Layer {
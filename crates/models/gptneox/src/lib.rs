@@ -2,14 +2,17 @@
 //! This crate also supports the [RedPajama](https://www.together.xyz/blog/redpajama) GPT-NeoX model.
 #![deny(missing_docs)]
 
-use std::{error::Error, sync::Arc};
+use std::{collections::HashMap, error::Error, path::Path, sync::Arc};
 
 use ggml::Tensor;
 use llm_base::{
+    diagnostics::Diagnostics,
     ggml,
-    model::{common, HyperparametersWriteError},
+    memory::MemoryLimitExceeded,
+    model::{common, ArchitectureInfo, EmbeddingTensors, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceParameters, InferenceSession, InferenceSessionConfig,
-    KnownModel, LoadError, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    KnownModel, KvLayout, LoadError, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId,
+    Tokenizer,
 };
 
 /// The GPT-NeoX model. Ref: [GitHub](https://github.com/EleutherAI/gpt-neox)
@@ -19,6 +22,8 @@ use llm_base::{
 pub struct GptNeoX {
     // the context size ("memory") the model should use when evaluating a prompt
     context_size: usize,
+    // receives notable events, such as the weights outliving the model
+    diagnostics: Diagnostics,
 
     hyperparameters: Hyperparameters,
     tokenizer: Tokenizer,
@@ -29,18 +34,26 @@ pub struct GptNeoX {
     ln_f_b: Tensor,
     // weight token embeddings
     wte: Tensor,
-    // language model head gain
-    lmh_g: Tensor,
+    // language model head gain, if not tied to `wte`
+    lmh_g: Option<Tensor>,
 
     // weights for the model
     layers: Vec<Layer>,
 
+    // the weights by their name in the model file, used to apply session LoRA adapters
+    tensors: HashMap<String, ggml::Tensor>,
+
     // must be kept alive for the model
     context: Arc<ggml::Context>,
 }
 
 unsafe impl Send for GptNeoX {}
 unsafe impl Sync for GptNeoX {}
+impl Drop for GptNeoX {
+    fn drop(&mut self) {
+        common::check_weights_released(&self.context, &self.diagnostics);
+    }
+}
 
 impl KnownModel for GptNeoX {
     type Hyperparameters = Hyperparameters;
@@ -60,7 +73,7 @@ impl KnownModel for GptNeoX {
         let wte = tl.load("gpt_neox.embed_in.weight")?;
         let ln_f_g = tl.load("gpt_neox.final_layer_norm.weight")?;
         let ln_f_b = tl.load("gpt_neox.final_layer_norm.bias")?;
-        let lmh_g = tl.load("embed_out.weight")?;
+        let lmh_g = common::load_output(&mut tl, Self::embedding_tensors(), &params.diagnostics)?;
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
@@ -95,25 +108,32 @@ impl KnownModel for GptNeoX {
             layers.push(layer);
         }
 
-        let (context, _) = tl.finish();
+        let (context, tensors) = tl.finish();
 
-        let ModelParameters { context_size, .. } = params;
+        let context_size =
+            common::context_size(&params, hyperparameters.n_layer, hyperparameters.n_embd);
+        let ModelParameters { diagnostics, .. } = params;
 
         Ok(GptNeoX {
             hyperparameters,
             context_size,
+            diagnostics,
             tokenizer,
             ln_f_g,
             ln_f_b,
             wte,
             lmh_g,
             layers,
+            tensors,
             context: Arc::new(context),
         })
     }
 
-    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
-        InferenceSession::new(
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, MemoryLimitExceeded> {
+        InferenceSession::try_new(
             config,
             self.context_size,
             self.hyperparameters.n_layer,
@@ -133,8 +153,9 @@ impl KnownModel for GptNeoX {
     ) {
         let n = input_tokens.len();
         let n_past = session.n_past;
-        let n_threads = params.n_threads;
-        let n_ctx = self.context_size;
+        let position = session.position();
+        let n_threads = session.thread_count(params, input_tokens.len());
+        let n_ctx = session.context_size();
 
         let Hyperparameters {
             n_embd,
@@ -169,7 +190,7 @@ impl KnownModel for GptNeoX {
                 );
 
                 // self-attention compute QKV
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_attn_w, &current);
+                current = builder.mul_mat(&self.layers[il].c_attn_attn_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_attn_attn_b, &current),
                     &current,
@@ -198,8 +219,8 @@ impl KnownModel for GptNeoX {
                 ));
 
                 // self-attention using mode = 2 for GPT-NeoX mode
-                qcur = ctx0.op_rope_inplace(&qcur, n_past, n_rot, 2);
-                kcur = ctx0.op_rope_inplace(&kcur, n_past, n_rot, 2);
+                qcur = ctx0.op_rope_inplace(&qcur, position, n_rot, 2);
+                kcur = ctx0.op_rope_inplace(&kcur, position, n_rot, 2);
 
                 // store key and value to memory
                 vcur = ctx0.op_transpose(&ctx0.op_reshape_2d(&vcur, n_embd, n));
@@ -251,6 +272,7 @@ impl KnownModel for GptNeoX {
 
                 // KQ = soft_max(KQ_masked)
                 let KQ_softmax = ctx0.op_soft_max_inplace(&KQ_masked);
+                builder.record_attention(&mut gf, &KQ_softmax);
 
                 // V_trans = Vmem.view(n_embd/n_head, n_head, n_past + N).permute(1, 2, 0, 3).contiguous()
                 let V = ctx0.op_view_3d(
@@ -272,7 +294,7 @@ impl KnownModel for GptNeoX {
                 current = ctx0.op_cpy(&KQV_merged, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n));
 
                 // self-attention projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+                current = builder.mul_mat(&self.layers[il].c_attn_proj_w, &current);
                 current = ctx0.op_add(
                     &ctx0.op_repeat(&self.layers[il].c_attn_proj_b, &current),
                     &current,
@@ -284,7 +306,12 @@ impl KnownModel for GptNeoX {
                 let feedforward_input: Tensor;
                 if !use_parallel_residual {
                     feedforward_input = ctx0.op_add(&current, &input_layer);
-                    current = feed_forward_network(ctx0, &self.layers[il], &feedforward_input);
+                    current = feed_forward_network(
+                        ctx0,
+                        &|weight, input| builder.mul_mat(weight, input),
+                        &self.layers[il],
+                        &feedforward_input,
+                    );
                     // input for next layer
                     input_layer = ctx0.op_add(&current, &feedforward_input);
                 } else {
@@ -293,7 +320,12 @@ impl KnownModel for GptNeoX {
 
                     // this is independent of the self-attention result, so it could be done in parallel to the self-attention
                     // note here we pass inpL instead of cur
-                    current = feed_forward_network(ctx0, &self.layers[il], &input_layer);
+                    current = feed_forward_network(
+                        ctx0,
+                        &|weight, input| builder.mul_mat(weight, input),
+                        &self.layers[il],
+                        &input_layer,
+                    );
 
                     // layer input + FF
                     current = ctx0.op_add(&current, &feedforward_input);
@@ -301,6 +333,8 @@ impl KnownModel for GptNeoX {
                     // input for next layer
                     input_layer = ctx0.op_add(&current, &input_layer);
                 }
+
+                input_layer = builder.record_layer_output(&input_layer);
             }
 
             // use the first scratch for the norm
@@ -320,7 +354,7 @@ impl KnownModel for GptNeoX {
             ctx0.use_scratch(None);
 
             // apply language model head
-            input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
+            input_layer = builder.mul_mat(self.lmh_g.as_ref().unwrap_or(&self.wte), &input_layer);
 
             (
                 gf,
@@ -357,6 +391,35 @@ impl KnownModel for GptNeoX {
         self.tokenizer.id("<|endoftext|>".as_bytes()).unwrap()
     }
 
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
+    }
+
+    fn tensor(&self, name: &str) -> Option<&ggml::Tensor> {
+        self.tensors.get(name)
+    }
+
+    fn tensors(&self) -> Vec<(&str, &ggml::Tensor)> {
+        self.tensors.iter().map(|(n, t)| (n.as_str(), t)).collect()
+    }
+
+    fn architecture_info(&self) -> ArchitectureInfo {
+        let hp = &self.hyperparameters;
+        ArchitectureInfo {
+            n_layer: hp.n_layer,
+            n_head: hp.n_head,
+            n_embd: hp.n_embd,
+            n_ctx_train: Some(hp.n_ctx),
+            vocab_size: hp.n_vocab,
+            quantization: Some(hp.file_type),
+            tied_embeddings: self.lmh_g.is_none(),
+        }
+    }
+
+    fn weights_context(&self) -> Option<&Arc<ggml::Context>> {
+        Some(&self.context)
+    }
+
     fn quantize_tensors() -> Vec<Regex> {
         vec![Regex::new(".*weight").unwrap()]
     }
@@ -365,9 +428,27 @@ impl KnownModel for GptNeoX {
         vec![]
     }
 
+    fn embedding_tensors() -> EmbeddingTensors {
+        EmbeddingTensors {
+            input: "gpt_neox.embed_in.weight",
+            output: Some("embed_out.weight"),
+        }
+    }
+
+    fn distinctive_tensors() -> Vec<&'static str> {
+        vec!["gpt_neox.embed_in.weight"]
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn kv_layout(&self) -> Option<KvLayout> {
+        Some(KvLayout {
+            width: self.hyperparameters.n_embd,
+            transposed_values: true,
+        })
+    }
 }
 
 /// GPT-NeoX [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -444,6 +525,10 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn validate(&self, path: &Path) -> Result<(), LoadError> {
+        llm_base::validate_attention(path, self.n_embd, self.n_head, Some(self.n_rot))
+    }
 }
 
 struct Layer {
@@ -470,7 +555,12 @@ struct Layer {
     c_mlp_proj_b: Tensor,
 }
 
-fn feed_forward_network(context: &ggml::Context, layer: &Layer, input: &Tensor) -> Tensor {
+fn feed_forward_network(
+    context: &ggml::Context,
+    mul_mat: &dyn Fn(&Tensor, &Tensor) -> Tensor,
+    layer: &Layer,
+    input: &Tensor,
+) -> Tensor {
     let mut current = context.op_norm(input);
 
     //gain and bias
@@ -480,7 +570,7 @@ fn feed_forward_network(context: &ggml::Context, layer: &Layer, input: &Tensor)
     );
 
     // apply weights
-    current = context.op_mul_mat(&layer.c_mlp_fc_w, &current);
+    current = mul_mat(&layer.c_mlp_fc_w, &current);
 
     // apply bias
     current = context.op_add(&context.op_repeat(&layer.c_mlp_fc_b, &current), &current);
@@ -490,7 +580,7 @@ fn feed_forward_network(context: &ggml::Context, layer: &Layer, input: &Tensor)
 
     // projection
     // cur = proj_w*cur + proj_b
-    current = context.op_mul_mat(&layer.c_mlp_proj_w, &current);
+    current = mul_mat(&layer.c_mlp_proj_w, &current);
 
     current = context.op_add(&context.op_repeat(&layer.c_mlp_proj_b, &current), &current);
 
@@ -1,13 +1,17 @@
 //! An implementation of [LLaMA](https://huggingface.co/docs/transformers/model_doc/llama) for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::{error::Error, sync::Arc};
+use std::{collections::HashMap, error::Error, path::Path, sync::Arc};
 
 use llm_base::{
+    convert::{ConvertError, HfConfig, HfConverter, HfTensor},
+    diagnostics::Diagnostics,
     ggml,
-    model::{common, HyperparametersWriteError},
+    memory::MemoryLimitExceeded,
+    model::{common, ArchitectureInfo, EmbeddingTensors, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceParameters, InferenceSession, InferenceSessionConfig,
-    KnownModel, LoadError, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    KnownModel, KvLayout, LoadError, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId,
+    Tokenizer,
 };
 
 /// The LLaMA model. Ref: [Introducing LLaMA](https://ai.facebook.com/blog/large-language-model-llama-meta-ai/)
@@ -17,6 +21,8 @@ use llm_base::{
 pub struct Llama {
     // the context size ("memory") the model should use when evaluating a prompt
     context_size: usize,
+    // receives notable events, such as the weights outliving the model
+    diagnostics: Diagnostics,
 
     hyperparameters: Hyperparameters,
     tokenizer: Tokenizer,
@@ -26,18 +32,26 @@ pub struct Llama {
     wte: ggml::Tensor,
     // normalization
     norm: ggml::Tensor,
-    // output weight
-    output: ggml::Tensor,
+    // output weight, if not tied to `wte`
+    output: Option<ggml::Tensor>,
 
     // weights for the model
     layers: Vec<Layer>,
 
+    // the weights by their name in the model file, used to apply session LoRA adapters
+    tensors: HashMap<String, ggml::Tensor>,
+
     // must be kept alive for the model
     context: Arc<ggml::Context>,
 }
 
 unsafe impl Send for Llama {}
 unsafe impl Sync for Llama {}
+impl Drop for Llama {
+    fn drop(&mut self) {
+        common::check_weights_released(&self.context, &self.diagnostics);
+    }
+}
 
 impl KnownModel for Llama {
     type Hyperparameters = Hyperparameters;
@@ -53,7 +67,7 @@ impl KnownModel for Llama {
         // model-global weights
         let wte = tl.load("tok_embeddings.weight")?;
         let norm = tl.load("norm.weight")?;
-        let output = tl.load("output.weight")?;
+        let output = common::load_output(&mut tl, Self::embedding_tensors(), &params.diagnostics)?;
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
@@ -72,25 +86,32 @@ impl KnownModel for Llama {
             layers.push(layer);
         }
 
-        let (context, _tensors) = tl.finish();
+        let (context, tensors) = tl.finish();
 
-        let ModelParameters { context_size, .. } = params;
+        let context_size =
+            common::context_size(&params, hyperparameters.n_layer, hyperparameters.n_embd);
+        let ModelParameters { diagnostics, .. } = params;
 
         Ok(Self {
             hyperparameters,
             context_size,
+            diagnostics,
             tokenizer,
             wte,
             norm,
             output,
             layers,
+            tensors,
             context: Arc::new(context),
         })
     }
 
     /// Starts a new `InferenceSession` for this model.
-    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
-        InferenceSession::new(
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, MemoryLimitExceeded> {
+        InferenceSession::try_new(
             config,
             self.context_size,
             self.hyperparameters.n_layer,
@@ -108,8 +129,9 @@ impl KnownModel for Llama {
     ) {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
-        let num_threads = params.n_threads;
-        let ctx_size = self.context_size;
+        let position = session.position();
+        let num_threads = session.thread_count(params, input_tokens.len());
+        let ctx_size = session.context_size();
 
         let Hyperparameters {
             n_vocab,
@@ -151,24 +173,24 @@ impl KnownModel for Llama {
                 // compute Q and K and RoPE them
                 let q_current = ctx0.op_rope_inplace(
                     &ctx0.op_reshape_3d(
-                        &ctx0.op_mul_mat(&self.layers[il].wq, &current),
+                        &builder.mul_mat(&self.layers[il].wq, &current),
                         n_embd / n_head,
                         n_head,
                         input_len,
                     ),
-                    session_len,
+                    position,
                     n_rot,
                     0,
                 );
                 ggml::set_name(&q_current, "Qcur");
                 let k_current = ctx0.op_rope_inplace(
                     &ctx0.op_reshape_3d(
-                        &ctx0.op_mul_mat(&self.layers[il].wk, &current),
+                        &builder.mul_mat(&self.layers[il].wk, &current),
                         n_embd / n_head,
                         n_head,
                         input_len,
                     ),
-                    session_len,
+                    position,
                     n_rot,
                     0,
                 );
@@ -177,7 +199,7 @@ impl KnownModel for Llama {
                 // store key and value to memory
                 // compute the transposed [N, n_embd] V matrix
                 let v_current = ctx0.op_transpose(&ctx0.op_reshape_2d(
-                    &ctx0.op_mul_mat(&self.layers[il].wv, &current),
+                    &builder.mul_mat(&self.layers[il].wv, &current),
                     n_embd,
                     input_len,
                 ));
@@ -235,6 +257,7 @@ impl KnownModel for Llama {
                 // KQ = soft_max(KQ_masked)
                 let k_q_soft_max = ctx0.op_soft_max_inplace(&k_q_masked);
                 ggml::set_name(&k_q_soft_max, "KQ_soft_max");
+                builder.record_attention(&mut gf, &k_q_soft_max);
 
                 // split cached V into n_head heads
                 let v = ctx0.op_view_3d(
@@ -263,7 +286,7 @@ impl KnownModel for Llama {
                 ggml::set_name(&current, "KQV_merged_contiguous");
 
                 // projection (no bias)
-                current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                current = builder.mul_mat(&self.layers[il].wo, &current);
 
                 builder.use_scratch(Some(1));
 
@@ -276,21 +299,23 @@ impl KnownModel for Llama {
                 // cur = cur*ffn_norm(broadcasted)
                 current = ctx0.op_mul(&current, &self.layers[il].ffn_norm);
 
-                let tmp = ctx0.op_mul_mat(&self.layers[il].w3, &current);
+                let tmp = builder.mul_mat(&self.layers[il].w3, &current);
 
-                current = ctx0.op_mul_mat(&self.layers[il].w1, &current);
+                current = builder.mul_mat(&self.layers[il].w1, &current);
 
                 // SILU activation
                 current = ctx0.op_silu(&current);
 
                 current = ctx0.op_mul(&current, &tmp);
 
-                current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
+                current = builder.mul_mat(&self.layers[il].w2, &current);
 
                 current = ctx0.op_add(&current, &input_feed_forward);
 
                 // input for next layer
                 input_layer = current;
+
+                input_layer = builder.record_layer_output(&input_layer);
             }
             builder.use_scratch(Some(0));
 
@@ -303,7 +328,7 @@ impl KnownModel for Llama {
             let embedding_result: ggml::Tensor = input_layer.share();
 
             // lm_head
-            input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
+            input_layer = builder.mul_mat(self.output.as_ref().unwrap_or(&self.wte), &input_layer);
 
             ctx0.use_scratch(None);
             (
@@ -341,6 +366,35 @@ impl KnownModel for Llama {
         self.tokenizer.id("</s>".as_bytes()).unwrap_or(2)
     }
 
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
+    }
+
+    fn tensor(&self, name: &str) -> Option<&ggml::Tensor> {
+        self.tensors.get(name)
+    }
+
+    fn tensors(&self) -> Vec<(&str, &ggml::Tensor)> {
+        self.tensors.iter().map(|(n, t)| (n.as_str(), t)).collect()
+    }
+
+    fn architecture_info(&self) -> ArchitectureInfo {
+        let hp = &self.hyperparameters;
+        ArchitectureInfo {
+            n_layer: hp.n_layer,
+            n_head: hp.n_head,
+            n_embd: hp.n_embd,
+            n_ctx_train: None,
+            vocab_size: hp.n_vocab,
+            quantization: Some(hp.file_type),
+            tied_embeddings: self.output.is_none(),
+        }
+    }
+
+    fn weights_context(&self) -> Option<&Arc<ggml::Context>> {
+        Some(&self.context)
+    }
+
     fn quantize_tensors() -> Vec<Regex> {
         vec![Regex::new(".*weight").unwrap()]
     }
@@ -349,9 +403,31 @@ impl KnownModel for Llama {
         vec![]
     }
 
+    fn embedding_tensors() -> EmbeddingTensors {
+        EmbeddingTensors {
+            input: "tok_embeddings.weight",
+            output: Some("output.weight"),
+        }
+    }
+
+    fn distinctive_tensors() -> Vec<&'static str> {
+        vec!["tok_embeddings.weight", "layers.0.attention.wq.weight"]
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn kv_layout(&self) -> Option<KvLayout> {
+        Some(KvLayout {
+            width: self.hyperparameters.n_embd,
+            transposed_values: true,
+        })
+    }
+
+    fn hf_converter() -> Option<Box<dyn HfConverter<Self::Hyperparameters>>> {
+        Some(Box::new(HfLlamaConverter))
+    }
 }
 
 /// LLaMA [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -408,6 +484,123 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn validate(&self, path: &Path) -> Result<(), LoadError> {
+        llm_base::validate_attention(path, self.n_embd, self.n_head, Some(self.n_rot))
+    }
+}
+
+/// Converts Hugging Face `LlamaForCausalLM` checkpoints.
+struct HfLlamaConverter;
+impl HfConverter<Hyperparameters> for HfLlamaConverter {
+    fn hyperparameters(
+        &self,
+        config: &HfConfig,
+        n_vocab: usize,
+        file_type: FileType,
+    ) -> Result<Hyperparameters, ConvertError> {
+        let n_embd = config.usize("hidden_size")?;
+        let n_head = config.usize("num_attention_heads")?;
+        if let Some(n_head_kv) = config.optional_usize("num_key_value_heads")? {
+            if n_head_kv != n_head {
+                return Err(ConvertError::UnsupportedConfig {
+                    reason: format!(
+                        "grouped-query attention ({n_head_kv} key-value heads for {n_head} heads)"
+                    ),
+                });
+            }
+        }
+
+        // n_mult is only used to derive the feed-forward size, which is read from the
+        // tensors when loading, so a mismatch is harmless.
+        let n_ff = config.usize("intermediate_size")?;
+        // `usize::div_ceil` is newer than the minimum supported Rust version.
+        #[allow(clippy::manual_div_ceil)]
+        let n_mult = (1..=8192)
+            .rev()
+            .find(|n_mult| (2 * (4 * n_embd) / 3 + n_mult - 1) / n_mult * n_mult == n_ff)
+            .unwrap_or(256);
+
+        Ok(Hyperparameters {
+            n_vocab,
+            n_embd,
+            n_mult,
+            n_head,
+            n_layer: config.usize("num_hidden_layers")?,
+            n_rot: n_embd / n_head,
+            file_type,
+        })
+    }
+
+    fn tensor_name(&self, hf_name: &str) -> Option<String> {
+        let name = match hf_name {
+            "model.embed_tokens.weight" => "tok_embeddings.weight".to_owned(),
+            "model.norm.weight" => "norm.weight".to_owned(),
+            "lm_head.weight" => "output.weight".to_owned(),
+            _ => {
+                let (layer, name) = hf_name.strip_prefix("model.layers.")?.split_once('.')?;
+                let name = match name {
+                    "input_layernorm.weight" => "attention_norm.weight",
+                    "self_attn.q_proj.weight" => "attention.wq.weight",
+                    "self_attn.k_proj.weight" => "attention.wk.weight",
+                    "self_attn.v_proj.weight" => "attention.wv.weight",
+                    "self_attn.o_proj.weight" => "attention.wo.weight",
+                    "post_attention_layernorm.weight" => "ffn_norm.weight",
+                    "mlp.gate_proj.weight" => "feed_forward.w1.weight",
+                    "mlp.down_proj.weight" => "feed_forward.w2.weight",
+                    "mlp.up_proj.weight" => "feed_forward.w3.weight",
+                    // e.g. the precomputed rotary frequencies
+                    _ => return None,
+                };
+                format!("layers.{layer}.{name}")
+            }
+        };
+        Some(name)
+    }
+
+    fn vocabulary_tensors(&self) -> &[&str] {
+        &["tok_embeddings.weight", "output.weight"]
+    }
+
+    fn transform(
+        &self,
+        hyperparameters: &Hyperparameters,
+        name: &str,
+        tensor: &mut HfTensor,
+    ) -> Result<(), ConvertError> {
+        if !(name.ends_with("attention.wq.weight") || name.ends_with("attention.wk.weight")) {
+            return Ok(());
+        }
+
+        // Hugging Face stores the two halves of the rotary dimensions of each head one
+        // after the other, while GGML expects them interleaved.
+        let n_head = hyperparameters.n_head;
+        let &[rows, cols] = tensor.shape.as_slice() else {
+            return Err(ConvertError::UnsupportedConfig {
+                reason: format!("{name} is not a matrix"),
+            });
+        };
+        if rows % (2 * n_head) != 0 {
+            return Err(ConvertError::UnsupportedConfig {
+                reason: format!(
+                    "{name} has {rows} rows, which cannot be split into {n_head} heads"
+                ),
+            });
+        }
+        let head_rows = rows / n_head;
+        let half = head_rows / 2;
+
+        let mut data = vec![0.0; tensor.data.len()];
+        for head in 0..n_head {
+            for (i, j) in (0..2).flat_map(|i| (0..half).map(move |j| (i, j))) {
+                let from = (head * head_rows + i * half + j) * cols;
+                let to = (head * head_rows + 2 * j + i) * cols;
+                data[to..to + cols].copy_from_slice(&tensor.data[from..from + cols]);
+            }
+        }
+        tensor.data = data;
+        Ok(())
+    }
 }
 
 struct Layer {
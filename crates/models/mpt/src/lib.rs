@@ -1,12 +1,14 @@
 //! An implementation of [MPT](https://huggingface.co/mosaicml) for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::sync::Arc;
+use std::{collections::HashMap, path::Path, sync::Arc};
 
 use ggml::Tensor;
 use llm_base::{
+    diagnostics::Diagnostics,
     ggml::{self},
-    model::{common, HyperparametersWriteError},
+    memory::MemoryLimitExceeded,
+    model::{common, ArchitectureInfo, EmbeddingTensors, HyperparametersWriteError},
     util, FileType, GraphOutputs, InferenceParameters, InferenceSession, InferenceSessionConfig,
     KnownModel, LoadError, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
 };
@@ -18,6 +20,8 @@ use llm_base::{
 pub struct Mpt {
     // the context size ("memory") the model should use when evaluating a prompt
     context_size: usize,
+    // receives notable events, such as the weights outliving the model
+    diagnostics: Diagnostics,
 
     hyperparameters: Hyperparameters,
     tokenizer: Tokenizer,
@@ -31,12 +35,20 @@ pub struct Mpt {
     // weights for the model
     layers: Vec<Layer>,
 
+    // the weights by their name in the model file, used to apply session LoRA adapters
+    tensors: HashMap<String, ggml::Tensor>,
+
     // must be kept alive for the model
     context: Arc<ggml::Context>,
 }
 
 unsafe impl Send for Mpt {}
 unsafe impl Sync for Mpt {}
+impl Drop for Mpt {
+    fn drop(&mut self) {
+        common::check_weights_released(&self.context, &self.diagnostics);
+    }
+}
 
 impl KnownModel for Mpt {
     type Hyperparameters = Hyperparameters;
@@ -70,23 +82,30 @@ impl KnownModel for Mpt {
             layers.push(layer);
         }
 
-        let (context, _) = tl.finish();
+        let (context, tensors) = tl.finish();
 
-        let ModelParameters { context_size, .. } = params;
+        let context_size =
+            common::context_size(&params, hyperparameters.n_layer, hyperparameters.n_embd);
+        let ModelParameters { diagnostics, .. } = params;
 
         Ok(Mpt {
             hyperparameters,
             context_size,
+            diagnostics,
             tokenizer,
             wte,
             norm,
             layers,
+            tensors,
             context: Arc::new(context),
         })
     }
 
-    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
-        InferenceSession::new(
+    fn try_start_session(
+        &self,
+        config: InferenceSessionConfig,
+    ) -> Result<InferenceSession, MemoryLimitExceeded> {
+        InferenceSession::try_new(
             config,
             self.context_size,
             self.hyperparameters.n_layer,
@@ -104,8 +123,8 @@ impl KnownModel for Mpt {
     ) {
         let n = input_tokens.len();
         let session_len = session.n_past;
-        let num_threads = params.n_threads;
-        let ctx_size = self.context_size;
+        let num_threads = session.thread_count(params, input_tokens.len());
+        let ctx_size = session.context_size();
 
         let Hyperparameters {
             n_embd,
@@ -139,7 +158,7 @@ impl KnownModel for Mpt {
                     &current,
                 );
 
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_wqkv_weight, &current);
+                current = builder.mul_mat(&self.layers[il].c_attn_wqkv_weight, &current);
 
                 let nb = current.get_nb()[1];
                 let qcur = ctx0.op_view_2d(&current, (n_embd, n), nb, 0);
@@ -219,7 +238,7 @@ impl KnownModel for Mpt {
 
                 current = ctx0.op_cpy(&kqv_merged, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n));
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].c_attn_out_proj_weight, &current);
+                current = builder.mul_mat(&self.layers[il].c_attn_out_proj_weight, &current);
 
                 input_layer = ctx0.op_add(&input_layer, &current);
 
@@ -232,14 +251,16 @@ impl KnownModel for Mpt {
                     &current,
                 );
 
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_up_proj, &current);
+                current = builder.mul_mat(&self.layers[il].ffn_up_proj, &current);
 
                 current = ctx0.op_gelu(&current);
 
                 // projection
-                current = ctx0.op_mul_mat(&self.layers[il].ffn_down_proj, &current);
+                current = builder.mul_mat(&self.layers[il].ffn_down_proj, &current);
 
                 input_layer = ctx0.op_add(&input_layer, &current);
+
+                input_layer = builder.record_layer_output(&input_layer);
             }
 
             //use scratch buffer 0 for the rest
@@ -254,7 +275,7 @@ impl KnownModel for Mpt {
             // disable scratch buffer for last layer
             ctx0.use_scratch(None);
             // output embedding weight tied to input embedding
-            input_layer = ctx0.op_mul_mat(&self.wte, &input_layer);
+            input_layer = builder.mul_mat(&self.wte, &input_layer);
 
             (
                 gf,
@@ -291,6 +312,35 @@ impl KnownModel for Mpt {
         self.tokenizer.id("<|endoftext|>".as_bytes()).unwrap()
     }
 
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        Some(&self.diagnostics)
+    }
+
+    fn tensor(&self, name: &str) -> Option<&ggml::Tensor> {
+        self.tensors.get(name)
+    }
+
+    fn tensors(&self) -> Vec<(&str, &ggml::Tensor)> {
+        self.tensors.iter().map(|(n, t)| (n.as_str(), t)).collect()
+    }
+
+    fn architecture_info(&self) -> ArchitectureInfo {
+        let hp = &self.hyperparameters;
+        ArchitectureInfo {
+            n_layer: hp.n_layer,
+            n_head: hp.n_head,
+            n_embd: hp.n_embd,
+            n_ctx_train: Some(hp.max_seq_len),
+            vocab_size: hp.n_vocab,
+            quantization: Some(hp.file_type),
+            tied_embeddings: true,
+        }
+    }
+
+    fn weights_context(&self) -> Option<&Arc<ggml::Context>> {
+        Some(&self.context)
+    }
+
     fn quantize_tensors() -> Vec<Regex> {
         vec![Regex::new(".*weight").unwrap()]
     }
@@ -299,6 +349,17 @@ impl KnownModel for Mpt {
         vec![]
     }
 
+    fn embedding_tensors() -> EmbeddingTensors {
+        EmbeddingTensors {
+            input: "transformer.wte.weight",
+            output: None,
+        }
+    }
+
+    fn distinctive_tensors() -> Vec<&'static str> {
+        vec!["transformer.wte.weight", "transformer.norm_f.weight"]
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -365,6 +426,10 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn validate(&self, path: &Path) -> Result<(), LoadError> {
+        llm_base::validate_attention(path, self.n_embd, self.n_head, None)
+    }
 }
 
 struct Layer {